@@ -33,6 +33,20 @@ pub struct AlephCli {
     /// with `--no-backup`, but note that that limits crash recoverability.
     #[clap(long, value_name = "PATH", group = "backup")]
     backup_path: Option<PathBuf>,
+
+    /// Log all the consensus traffic at the debug level of the `aleph-party` target.
+    ///
+    /// Meant for debugging only, it produces a lot of output.
+    #[clap(long)]
+    record_consensus: bool,
+
+    /// Only follow the consensus, without proposing any data or sending anything to the other
+    /// members.
+    ///
+    /// Meant for monitoring and indexing. The node still holds its place in the committee, the
+    /// other members treat it as down.
+    #[clap(long)]
+    observer: bool,
 }
 
 impl AlephCli {
@@ -55,4 +69,12 @@ impl AlephCli {
     pub fn no_backup(&self) -> bool {
         self.no_backup
     }
+
+    pub fn record_consensus(&self) -> bool {
+        self.record_consensus
+    }
+
+    pub fn observer(&self) -> bool {
+        self.observer
+    }
 }
@@ -1,6 +1,8 @@
-use futures::channel::mpsc;
+use std::path::PathBuf;
+
+use futures::channel::{mpsc, oneshot};
 use jsonrpsee::{
-    core::{error::Error as JsonRpseeError, RpcResult},
+    core::{async_trait, error::Error as JsonRpseeError, RpcResult},
     proc_macros::rpc,
     types::error::{CallError, ErrorObject},
 };
@@ -15,6 +17,15 @@ pub enum Error {
     /// Provided block range couldn't be resolved to a list of blocks.
     #[error("Node is not fully functional: {}", .0)]
     FailedJustificationSend(String),
+    /// The validator network of the node cannot be adjusted.
+    #[error("{0}")]
+    NetworkAdminUnavailable(String),
+    /// The ordered data cannot be exported by this node.
+    #[error("{0}")]
+    OrderedDataExportUnavailable(String),
+    /// Exporting the ordered data failed.
+    #[error("{0}")]
+    OrderedDataExportFailed(String),
 }
 
 // Base code for all system errors.
@@ -23,6 +34,12 @@ const BASE_ERROR: i32 = 2000;
 const MALFORMATTED_JUSTIFICATION_ARG_ERROR: i32 = BASE_ERROR + 1;
 // AlephNodeApiServer is failed to send JustificationNotification.
 const FAILED_JUSTIFICATION_SEND_ERROR: i32 = BASE_ERROR + 2;
+// The validator network of the node cannot be adjusted.
+const NETWORK_ADMIN_UNAVAILABLE_ERROR: i32 = BASE_ERROR + 3;
+// The ordered data cannot be exported by this node.
+const ORDERED_DATA_EXPORT_UNAVAILABLE_ERROR: i32 = BASE_ERROR + 4;
+// Exporting the ordered data failed.
+const ORDERED_DATA_EXPORT_FAILED_ERROR: i32 = BASE_ERROR + 5;
 
 impl From<Error> for JsonRpseeError {
     fn from(e: Error) -> Self {
@@ -37,6 +54,21 @@ impl From<Error> for JsonRpseeError {
                 e,
                 None::<()>,
             )),
+            Error::NetworkAdminUnavailable(e) => CallError::Custom(ErrorObject::owned(
+                NETWORK_ADMIN_UNAVAILABLE_ERROR,
+                e,
+                None::<()>,
+            )),
+            Error::OrderedDataExportUnavailable(e) => CallError::Custom(ErrorObject::owned(
+                ORDERED_DATA_EXPORT_UNAVAILABLE_ERROR,
+                e,
+                None::<()>,
+            )),
+            Error::OrderedDataExportFailed(e) => CallError::Custom(ErrorObject::owned(
+                ORDERED_DATA_EXPORT_FAILED_ERROR,
+                e,
+                None::<()>,
+            )),
         }
         .into()
     }
@@ -53,9 +85,39 @@ pub trait AlephNodeApi<Hash, Number> {
         hash: Hash,
         number: Number,
     ) -> RpcResult<()>;
+
+    /// Announce the given addresses to the other validators instead of the ones the node started
+    /// with. Only available on validator nodes, and only as an unsafe call.
+    #[method(name = "alephNode_updateValidatorAddresses")]
+    fn aleph_node_update_validator_addresses(&self, addresses: Vec<String>) -> RpcResult<()>;
+
+    /// Reconnect to the given validator, e.g. during its rolling restart, after delivering the
+    /// data already sent to it. Only available on validator nodes, and only as an unsafe call.
+    #[method(name = "alephNode_recycleValidatorPeer")]
+    fn aleph_node_recycle_validator_peer(&self, peer_id: AuthorityId) -> RpcResult<()>;
+
+    /// Hold back the data sent to the given validator without disconnecting, e.g. during its
+    /// maintenance. Only available on validator nodes, and only as an unsafe call.
+    #[method(name = "alephNode_pauseValidatorPeer")]
+    fn aleph_node_pause_validator_peer(&self, peer_id: AuthorityId) -> RpcResult<()>;
+
+    /// Send out the data held back for the given validator, in order, and stop holding it back.
+    /// Only available on validator nodes, and only as an unsafe call.
+    #[method(name = "alephNode_resumeValidatorPeer")]
+    fn aleph_node_resume_validator_peer(&self, peer_id: AuthorityId) -> RpcResult<()>;
+
+    /// Write the data ordered in the current session to the file at the given path on the node,
+    /// replacing it. Returns how many items were written. Only available on validator nodes, and
+    /// only as an unsafe call.
+    #[method(name = "alephNode_exportOrderedData")]
+    async fn aleph_node_export_ordered_data(&self, path: String) -> RpcResult<usize>;
 }
 
-use finality_aleph::{AlephJustification, JustificationNotification};
+use aleph_primitives::AuthorityId;
+use finality_aleph::{
+    AlephJustification, ExportRequest, JustificationNotification, NetworkAdminCommand,
+};
+use sc_rpc_api::DenyUnsafe;
 use sp_api::BlockT;
 use sp_runtime::traits::NumberFor;
 
@@ -67,6 +129,9 @@ where
     NumberFor<B>: Serialize + for<'de> serde::Deserialize<'de>,
 {
     import_justification_tx: mpsc::UnboundedSender<JustificationNotification<B>>,
+    network_admin_tx: Option<mpsc::UnboundedSender<NetworkAdminCommand>>,
+    ordered_data_exports_tx: Option<mpsc::UnboundedSender<ExportRequest>>,
+    deny_unsafe: DenyUnsafe,
 }
 
 impl<B> AlephNode<B>
@@ -77,13 +142,33 @@ where
 {
     pub fn new(
         import_justification_tx: mpsc::UnboundedSender<JustificationNotification<B>>,
+        network_admin_tx: Option<mpsc::UnboundedSender<NetworkAdminCommand>>,
+        ordered_data_exports_tx: Option<mpsc::UnboundedSender<ExportRequest>>,
+        deny_unsafe: DenyUnsafe,
     ) -> Self {
         AlephNode {
             import_justification_tx,
+            network_admin_tx,
+            ordered_data_exports_tx,
+            deny_unsafe,
         }
     }
+
+    fn send_network_admin_command(&self, command: NetworkAdminCommand) -> RpcResult<()> {
+        self.deny_unsafe.check_if_safe()?;
+        let network_admin_tx = self.network_admin_tx.as_ref().ok_or_else(|| {
+            Error::NetworkAdminUnavailable("Not a validator node, no validator network".into())
+        })?;
+        network_admin_tx.unbounded_send(command).map_err(|_| {
+            Error::NetworkAdminUnavailable(
+                "AlephNodeApiServer failed to send NetworkAdminCommand via its channel".into(),
+            )
+            .into()
+        })
+    }
 }
 
+#[async_trait]
 impl<B> AlephNodeApiServer<B::Hash, NumberFor<B>> for AlephNode<B>
 where
     B: BlockT,
@@ -116,4 +201,44 @@ where
                 .into()
             })
     }
+
+    fn aleph_node_update_validator_addresses(&self, addresses: Vec<String>) -> RpcResult<()> {
+        self.send_network_admin_command(NetworkAdminCommand::UpdateAddresses(addresses))
+    }
+
+    fn aleph_node_recycle_validator_peer(&self, peer_id: AuthorityId) -> RpcResult<()> {
+        self.send_network_admin_command(NetworkAdminCommand::RecyclePeer(peer_id))
+    }
+
+    fn aleph_node_pause_validator_peer(&self, peer_id: AuthorityId) -> RpcResult<()> {
+        self.send_network_admin_command(NetworkAdminCommand::PausePeer(peer_id))
+    }
+
+    fn aleph_node_resume_validator_peer(&self, peer_id: AuthorityId) -> RpcResult<()> {
+        self.send_network_admin_command(NetworkAdminCommand::ResumePeer(peer_id))
+    }
+
+    async fn aleph_node_export_ordered_data(&self, path: String) -> RpcResult<usize> {
+        self.deny_unsafe.check_if_safe()?;
+        let ordered_data_exports_tx = self.ordered_data_exports_tx.as_ref().ok_or_else(|| {
+            Error::OrderedDataExportUnavailable("Not a validator node, no ordered data".into())
+        })?;
+        let (result, exported) = oneshot::channel();
+        ordered_data_exports_tx
+            .unbounded_send(ExportRequest {
+                path: PathBuf::from(path),
+                result,
+            })
+            .map_err(|_| {
+                Error::OrderedDataExportUnavailable(
+                    "AlephNodeApiServer failed to send ExportRequest via its channel".into(),
+                )
+            })?;
+        let exported = exported.await.map_err(|_| {
+            Error::OrderedDataExportUnavailable(
+                "AlephNodeApiServer got no result of the ExportRequest".into(),
+            )
+        })?;
+        exported.map_err(|e| Error::OrderedDataExportFailed(e.to_string()).into())
+    }
 }
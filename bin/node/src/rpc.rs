@@ -8,7 +8,7 @@
 use std::sync::Arc;
 
 use aleph_runtime::{opaque::Block, AccountId, Balance, BlockNumber, Hash, Index};
-use finality_aleph::JustificationNotification;
+use finality_aleph::{ExportRequest, JustificationNotification, NetworkAdminCommand};
 use futures::channel::mpsc;
 use jsonrpsee::RpcModule;
 pub use sc_rpc_api::DenyUnsafe;
@@ -26,6 +26,10 @@ pub struct FullDeps<B: BlockT, C, P> {
     /// Whether to deny unsafe calls
     pub deny_unsafe: DenyUnsafe,
     pub import_justification_tx: mpsc::UnboundedSender<JustificationNotification<B>>,
+    /// Where to send commands adjusting the validator network, only present on validator nodes.
+    pub network_admin_tx: Option<mpsc::UnboundedSender<NetworkAdminCommand>>,
+    /// Where to send requests to export the ordered data, only present on validator nodes.
+    pub ordered_data_exports_tx: Option<mpsc::UnboundedSender<ExportRequest>>,
 }
 
 /// Instantiate all full RPC extensions.
@@ -53,6 +57,8 @@ where
         pool,
         deny_unsafe,
         import_justification_tx,
+        network_admin_tx,
+        ordered_data_exports_tx,
     } = deps;
 
     module.merge(System::new(client.clone(), pool, deny_unsafe).into_rpc())?;
@@ -62,7 +68,15 @@ where
     module.merge(Contracts::new(client).into_rpc())?;
 
     use crate::aleph_node_rpc::{AlephNode, AlephNodeApiServer};
-    module.merge(AlephNode::new(import_justification_tx).into_rpc())?;
+    module.merge(
+        AlephNode::new(
+            import_justification_tx,
+            network_admin_tx,
+            ordered_data_exports_tx,
+            deny_unsafe,
+        )
+        .into_rpc(),
+    )?;
 
     Ok(module)
 }
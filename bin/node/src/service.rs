@@ -8,8 +8,9 @@ use std::{
 use aleph_primitives::AlephSessionApi;
 use aleph_runtime::{self, opaque::Block, RuntimeApi, MAX_BLOCK_SIZE};
 use finality_aleph::{
-    run_nonvalidator_node, run_validator_node, AlephBlockImport, AlephConfig,
-    JustificationNotification, Metrics, MillisecsPerBlock, Protocol, SessionPeriod,
+    run_nonvalidator_node, run_validator_node, AlephBlockImport, AlephConfig, ExportRequest,
+    JustificationNotification, Metrics, MillisecsPerBlock, NetworkAdminCommand, Protocol,
+    SessionPeriod,
 };
 use futures::channel::mpsc;
 use log::warn;
@@ -184,6 +185,8 @@ fn setup(
     client: Arc<FullClient>,
     telemetry: &mut Option<Telemetry>,
     import_justification_tx: mpsc::UnboundedSender<JustificationNotification<Block>>,
+    network_admin_tx: Option<mpsc::UnboundedSender<NetworkAdminCommand>>,
+    ordered_data_exports_tx: Option<mpsc::UnboundedSender<ExportRequest>>,
 ) -> Result<
     (
         RpcHandlers,
@@ -222,6 +225,8 @@ fn setup(
                 pool: pool.clone(),
                 deny_unsafe,
                 import_justification_tx: import_justification_tx.clone(),
+                network_admin_tx: network_admin_tx.clone(),
+                ordered_data_exports_tx: ordered_data_exports_tx.clone(),
             };
 
             Ok(crate::rpc::create_full(deps)?)
@@ -291,6 +296,8 @@ pub fn new_authority(
     let backoff_authoring_blocks: Option<()> = None;
     let prometheus_registry = config.prometheus_registry().cloned();
 
+    let (network_admin_tx, network_admin_rx) = mpsc::unbounded();
+    let (ordered_data_exports_tx, ordered_data_exports_rx) = mpsc::unbounded();
     let (_rpc_handlers, network, network_starter) = setup(
         config,
         backend,
@@ -301,6 +308,8 @@ pub fn new_authority(
         client.clone(),
         &mut telemetry,
         justification_tx,
+        Some(network_admin_tx),
+        Some(ordered_data_exports_tx),
     )?;
 
     let mut proposer_factory = sc_basic_authorship::ProposerFactory::new(
@@ -367,6 +376,11 @@ pub fn new_authority(
         backup_saving_path: backup_path,
         external_addresses: aleph_config.external_addresses(),
         validator_port: aleph_config.validator_port(),
+        registry: prometheus_registry,
+        ordered_data_exports: Some(ordered_data_exports_rx),
+        record_consensus: aleph_config.record_consensus(),
+        observer: aleph_config.observer(),
+        network_admin: Some(network_admin_rx),
     };
     task_manager.spawn_essential_handle().spawn_blocking(
         "aleph",
@@ -402,6 +416,7 @@ pub fn new_full(
             .path(),
     );
 
+    let prometheus_registry = config.prometheus_registry().cloned();
     let (_rpc_handlers, network, network_starter) = setup(
         config,
         backend,
@@ -412,6 +427,8 @@ pub fn new_full(
         client.clone(),
         &mut telemetry,
         justification_tx,
+        None,
+        None,
     )?;
 
     let session_period = SessionPeriod(
@@ -442,6 +459,11 @@ pub fn new_full(
         backup_saving_path: backup_path,
         external_addresses: aleph_config.external_addresses(),
         validator_port: aleph_config.validator_port(),
+        registry: prometheus_registry,
+        ordered_data_exports: None,
+        record_consensus: aleph_config.record_consensus(),
+        observer: aleph_config.observer(),
+        network_admin: None,
     };
 
     task_manager.spawn_essential_handle().spawn_blocking(
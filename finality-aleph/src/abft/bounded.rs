@@ -0,0 +1,95 @@
+use codec::Encode;
+use log::warn;
+use sp_runtime::traits::Block;
+
+use crate::data_io::AlephData;
+
+/// The biggest encoded size of the data we hand to the consensus. Honest data holds at most
+/// `MAX_DATA_BRANCH_LEN` hashes, so it is way smaller than this.
+pub const MAX_DATA_SIZE: usize = 1024;
+
+/// Guards the consensus from data sources that produce oversized data, which would inflate the
+/// units and the bandwidth they take. Such data is not proposed at all.
+pub struct BoundedDataProvider<P> {
+    inner: P,
+    max_size: usize,
+}
+
+impl<P> BoundedDataProvider<P> {
+    pub fn new(inner: P, max_size: usize) -> Self {
+        BoundedDataProvider { inner, max_size }
+    }
+
+    fn bound<B: Block>(&self, data: Option<AlephData<B>>) -> Option<AlephData<B>> {
+        data.filter(|data| {
+            let size = data.encoded_size();
+            if size > self.max_size {
+                warn!(target: "aleph-data-store", "Not proposing data of size {}, above the limit of {}: {:?}", size, self.max_size, data);
+                return false;
+            }
+            true
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl<B, P> current_aleph_bft::DataProvider<AlephData<B>> for BoundedDataProvider<P>
+where
+    B: Block,
+    P: current_aleph_bft::DataProvider<AlephData<B>>,
+{
+    async fn get_data(&mut self) -> Option<AlephData<B>> {
+        let data = self.inner.get_data().await;
+        self.bound(data)
+    }
+}
+
+#[async_trait::async_trait]
+impl<B, P> legacy_aleph_bft::DataProvider<AlephData<B>> for BoundedDataProvider<P>
+where
+    B: Block,
+    P: legacy_aleph_bft::DataProvider<AlephData<B>>,
+{
+    async fn get_data(&mut self) -> Option<AlephData<B>> {
+        let data = self.inner.get_data().await;
+        self.bound(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use current_aleph_bft::DataProvider;
+    use substrate_test_runtime_client::runtime::Block;
+
+    use super::{BoundedDataProvider, MAX_DATA_SIZE};
+    use crate::data_io::{AlephData, UnvalidatedAlephProposal, MAX_DATA_BRANCH_LEN};
+
+    struct FixedProvider(AlephData<Block>);
+
+    #[async_trait::async_trait]
+    impl DataProvider<AlephData<Block>> for FixedProvider {
+        async fn get_data(&mut self) -> Option<AlephData<Block>> {
+            Some(self.0.clone())
+        }
+    }
+
+    fn data_with_branch_of(len: usize) -> AlephData<Block> {
+        AlephData {
+            head_proposal: UnvalidatedAlephProposal::new(vec![Default::default(); len], len as u64),
+        }
+    }
+
+    #[tokio::test]
+    async fn passes_honest_data_through() {
+        let data = data_with_branch_of(MAX_DATA_BRANCH_LEN);
+        let mut provider = BoundedDataProvider::new(FixedProvider(data.clone()), MAX_DATA_SIZE);
+        assert_eq!(provider.get_data().await, Some(data));
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_data() {
+        let data = data_with_branch_of(100);
+        let mut provider = BoundedDataProvider::new(FixedProvider(data), MAX_DATA_SIZE);
+        assert_eq!(provider.get_data().await, None);
+    }
+}
@@ -1,8 +1,20 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    fmt::{Display, Error as FmtError, Formatter},
+    sync::Arc,
+    time::Duration,
+};
 
-use crate::{NodeIndex, SessionId, UnitCreationDelay};
+use futures::{future, Future, FutureExt};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    abft::SpawnHandleT, oneshot, party::manager::Task, NodeIndex, SessionId, UnitCreationDelay,
+};
 
 const MAX_ROUNDS: u16 = 7000;
+const REBROADCAST_INTERVAL_PER_MEMBER: Duration = Duration::from_millis(150);
+const MIN_REBROADCAST_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_REBROADCAST_INTERVAL: Duration = Duration::from_secs(60);
 
 fn exponential_slowdown(
     t: usize,
@@ -44,6 +56,133 @@ pub struct DelayConfig {
     pub unit_creation_delay: DelaySchedule,
 }
 
+/// The bounds of the interval between rebroadcasts of a unit, for a session with the given number
+/// of members.
+///
+/// Every member rebroadcasts its units to all the others, so the rebroadcast traffic grows
+/// quadratically with the committee size. To keep the bandwidth used by a single node bounded,
+/// the lower bound grows linearly with the number of members, by 150ms per member, and is clamped
+/// to between 5s and 60s. The upper bound is a third longer than the lower one. This gives the old
+/// fixed 15-20s window for a committee of 100 members.
+pub fn rebroadcast_window(n_members: usize) -> (Duration, Duration) {
+    let min = REBROADCAST_INTERVAL_PER_MEMBER
+        .saturating_mul(n_members.try_into().unwrap_or(u32::MAX))
+        .clamp(MIN_REBROADCAST_INTERVAL, MAX_REBROADCAST_INTERVAL);
+    (min, min + min / 3)
+}
+
+/// The delays used in production for a session with the given number of members, with units
+/// created according to `unit_creation_delay`.
+pub fn default_delay_config(
+    n_members: usize,
+    unit_creation_delay: UnitCreationDelay,
+) -> DelayConfig {
+    let (unit_rebroadcast_interval_min, unit_rebroadcast_interval_max) =
+        rebroadcast_window(n_members);
+    DelayConfig {
+        tick_interval: Duration::from_millis(100),
+        requests_interval: Duration::from_millis(3000),
+        unit_rebroadcast_interval_min,
+        unit_rebroadcast_interval_max,
+        unit_creation_delay: unit_creation_delay_fn(unit_creation_delay),
+    }
+}
+
+/// Pins the interval between rebroadcasts of a unit to a single value from the window of the
+/// config, drawn using the seed and the index of the member. Otherwise the consensus draws a fresh
+/// interval every time. The order of events still depends on timing, so it does not become
+/// reproducible. Only meant for tests.
+pub fn seed_delay_config(delay_config: DelayConfig, seed: u64, node_id: NodeIndex) -> DelayConfig {
+    let mut rng = StdRng::seed_from_u64(seed.wrapping_add(node_id.0 as u64));
+    let interval = rng.gen_range(
+        delay_config.unit_rebroadcast_interval_min..=delay_config.unit_rebroadcast_interval_max,
+    );
+    DelayConfig {
+        unit_rebroadcast_interval_min: interval,
+        unit_rebroadcast_interval_max: interval,
+        ..delay_config
+    }
+}
+
+/// Why the weights of committee members cannot be used.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WeightsError {
+    /// There has to be exactly one weight per member.
+    WrongLength { n_members: usize, weights: usize },
+    /// A member without weight would never count towards a quorum.
+    ZeroWeight(NodeIndex),
+    /// The consensus derives its quorums from the number of members, so it cannot handle members
+    /// of differing weights yet.
+    Unequal,
+}
+
+impl Display for WeightsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        use WeightsError::*;
+        match self {
+            WrongLength { n_members, weights } => write!(
+                f,
+                "got {} weights for a committee of {} members",
+                weights, n_members
+            ),
+            ZeroWeight(node_id) => write!(f, "member {:?} has zero weight", node_id),
+            Unequal => write!(
+                f,
+                "members of differing weights are not supported, quorums only depend on the number of members"
+            ),
+        }
+    }
+}
+
+/// Checks whether the weights can be used for a committee of the given size. No weights at all
+/// means all the members weigh the same.
+pub fn check_weights(n_members: usize, weights: Option<&[u64]>) -> Result<(), WeightsError> {
+    let weights = match weights {
+        Some(weights) => weights,
+        None => return Ok(()),
+    };
+    if weights.len() != n_members {
+        return Err(WeightsError::WrongLength {
+            n_members,
+            weights: weights.len(),
+        });
+    }
+    if let Some(node_id) = weights.iter().position(|weight| *weight == 0) {
+        return Err(WeightsError::ZeroWeight(NodeIndex(node_id)));
+    }
+    match weights.windows(2).all(|pair| pair[0] == pair[1]) {
+        true => Ok(()),
+        false => Err(WeightsError::Unequal),
+    }
+}
+
+/// The essential task of a member could not be spawned, the spawner dropped it without running it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpawnError {
+    pub name: &'static str,
+}
+
+impl Display for SpawnError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        write!(f, "failed to spawn the essential task {}", self.name)
+    }
+}
+
+/// Spawns the essential task of a member, failing if the spawner refused it right away.
+pub fn spawn_member_task<S: SpawnHandleT>(
+    spawn_handle: &S,
+    name: &'static str,
+    task: impl Future<Output = ()> + Send + 'static,
+    stop: oneshot::Sender<()>,
+) -> Result<Task, SpawnError> {
+    let mut handle = spawn_handle.spawn_essential(name, task);
+    match (&mut handle).now_or_never() {
+        Some(Err(())) => Err(SpawnError { name }),
+        Some(Ok(())) => Ok(Task::new(Box::pin(future::ready(Ok(()))), stop)),
+        None => Ok(Task::new(handle, stop)),
+    }
+}
+
 pub struct AlephConfig {
     delay_config: DelayConfig,
     n_members: usize,
@@ -118,3 +257,91 @@ impl From<AlephConfig> for legacy_aleph_bft::Config {
         aleph_config
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{pin::Pin, time::Duration};
+
+    use futures::{future, Future};
+
+    use super::{
+        default_delay_config, rebroadcast_window, seed_delay_config, spawn_member_task, SpawnError,
+    };
+    use crate::{abft::SpawnHandleT, oneshot, NodeIndex, UnitCreationDelay};
+
+    /// Refuses to run anything, like a spawner of a node that is shutting down.
+    struct FailingSpawner;
+
+    impl SpawnHandleT for FailingSpawner {
+        fn spawn(&self, _name: &'static str, _task: impl Future<Output = ()> + Send + 'static) {}
+
+        fn spawn_essential(
+            &self,
+            _name: &'static str,
+            _task: impl Future<Output = ()> + Send + 'static,
+        ) -> Pin<Box<dyn Future<Output = Result<(), ()>> + Send>> {
+            Box::pin(future::ready(Err(())))
+        }
+    }
+
+    #[test]
+    fn reports_member_tasks_that_failed_to_spawn() {
+        let (stop, _exit) = oneshot::channel();
+        let result = spawn_member_task(&FailingSpawner, "member", future::ready(()), stop);
+        assert_eq!(result.err(), Some(SpawnError { name: "member" }));
+    }
+
+    #[test]
+    fn seeding_pins_rebroadcast_interval_within_window() {
+        let (min, max) = rebroadcast_window(100);
+        let seeded = |seed, node_id| {
+            let config = seed_delay_config(
+                default_delay_config(100, UnitCreationDelay(200)),
+                seed,
+                NodeIndex(node_id),
+            );
+            assert_eq!(
+                config.unit_rebroadcast_interval_min,
+                config.unit_rebroadcast_interval_max
+            );
+            config.unit_rebroadcast_interval_min
+        };
+        for node_id in 0..10 {
+            let interval = seeded(2137, node_id);
+            assert!(interval >= min && interval <= max);
+            assert_eq!(interval, seeded(2137, node_id));
+        }
+        let intervals: Vec<_> = (0..10).map(|node_id| seeded(2137, node_id)).collect();
+        assert!(intervals.iter().any(|interval| *interval != intervals[0]));
+    }
+
+    #[test]
+    fn rebroadcast_window_grows_with_committee() {
+        let mut previous = rebroadcast_window(0);
+        for n_members in 1..2000 {
+            let current = rebroadcast_window(n_members);
+            assert!(current.0 >= previous.0);
+            assert!(current.1 >= previous.1);
+            assert!(current.0 < current.1);
+            previous = current;
+        }
+    }
+
+    #[test]
+    fn rebroadcast_window_is_clamped() {
+        let smallest = Duration::from_secs(5);
+        assert_eq!(rebroadcast_window(1), (smallest, smallest + smallest / 3));
+        assert_eq!(
+            rebroadcast_window(100),
+            (Duration::from_secs(15), Duration::from_secs(20))
+        );
+        assert_eq!(
+            rebroadcast_window(100_000),
+            (Duration::from_secs(60), Duration::from_secs(80))
+        );
+        assert_eq!(
+            rebroadcast_window(usize::MAX),
+            (Duration::from_secs(60), Duration::from_secs(80))
+        );
+    }
+}
@@ -1,5 +1,3 @@
-use std::time::Duration;
-
 use current_aleph_bft::{Config, LocalIO, Terminator};
 use log::debug;
 use sp_blockchain::HeaderBackend;
@@ -7,15 +5,20 @@ use sp_runtime::traits::Block;
 
 use crate::{
     abft::{
-        common::{unit_creation_delay_fn, AlephConfig, DelayConfig},
-        NetworkWrapper, SpawnHandleT,
+        common::{
+            check_weights, default_delay_config, seed_delay_config, spawn_member_task, AlephConfig,
+            DelayConfig, SpawnError, WeightsError,
+        },
+        quorum::QuorumMonitor,
+        stall::{track_progress, StallMonitor},
+        NetworkWrapper,
     },
     crypto::Signature,
     data_io::{AlephData, OrderedDataInterpreter},
-    network::DataNetwork,
+    network::{DataNetwork, ReceiveOnlyNetwork},
     oneshot,
     party::{
-        backup::ABFTBackup,
+        backup::{sync_stopped, ABFTBackup, SharedSaver},
         manager::{SubtaskCommon, Task},
     },
     CurrentNetworkData, Hasher, Keychain, NodeIndex, SessionId, SignatureSet, UnitCreationDelay,
@@ -24,6 +27,24 @@ use crate::{
 /// Version of the current abft
 pub const VERSION: u32 = 1;
 
+/// Finishes when the member task is stopped, or when the parent terminator exits, if there is
+/// one. Returns whether it was the parent.
+async fn stop_requested(exit: oneshot::Receiver<()>, parent: Option<&mut Terminator>) -> bool {
+    match parent {
+        Some(parent) => tokio::select! {
+            _ = exit => false,
+            _ = parent.get_exit() => true,
+        },
+        None => {
+            let _ = exit.await;
+            false
+        }
+    }
+}
+
+/// Runs the member of the session. With a parent terminator, the member terminates together with
+/// it, on top of when the returned task is stopped.
+#[allow(clippy::too_many_arguments)]
 pub fn run_member<
     B: Block,
     C: HeaderBackend<B> + Send + 'static,
@@ -39,49 +60,803 @@ pub fn run_member<
     data_provider: impl current_aleph_bft::DataProvider<AlephData<B>> + Send + 'static,
     ordered_data_interpreter: OrderedDataInterpreter<B, C>,
     backup: ABFTBackup,
-) -> Task {
+    stall_monitor: Option<StallMonitor>,
+    quorum_monitor: Option<QuorumMonitor>,
+    parent_terminator: Option<&mut Terminator>,
+) -> Result<Task, SpawnError> {
     let SubtaskCommon {
         spawn_handle,
         session_id,
     } = subtask_common;
     let (stop, exit) = oneshot::channel();
-    let member_terminator = Terminator::create_root(exit, "member");
-    let local_io = LocalIO::new(data_provider, ordered_data_interpreter, backup.0, backup.1);
+    let mut parent = parent_terminator.map(|parent| parent.add_offspring_connection("member"));
+    let (finalization_handler, watch_progress) = track_progress(
+        ordered_data_interpreter,
+        stall_monitor,
+        SessionId(session_id),
+    );
+    let network = network.with_quorum_watch(
+        quorum_monitor.map(|monitor| monitor.watch(SessionId(session_id), config.n_members.0)),
+    );
+    let (saver, loader) = backup;
+    let saver = SharedSaver::new(saver);
+    let local_io = LocalIO::new(data_provider, finalization_handler, saver.clone(), loader);
 
     let task = {
         let spawn_handle = spawn_handle.clone();
         async move {
             debug!(target: "aleph-party", "Running the member task for {:?}", session_id);
-            current_aleph_bft::run_session(
+            let (member_stop, member_exit) = oneshot::channel();
+            let session = current_aleph_bft::run_session(
                 config,
                 local_io,
                 network,
                 multikeychain,
                 spawn_handle,
-                member_terminator,
-            )
-            .await;
+                Terminator::create_root(member_exit, "member"),
+            );
+            // Watching the progress never finishes, so this only waits for the session.
+            let session = async {
+                tokio::select! {
+                    _ = session => (),
+                    _ = watch_progress => (),
+                }
+            };
+            tokio::pin!(session);
+            let stopped_by_parent = tokio::select! {
+                _ = &mut session => false,
+                stopped_by_parent = stop_requested(exit, parent.as_mut()) => {
+                    let _ = member_stop.send(());
+                    session.await;
+                    stopped_by_parent
+                }
+            };
+            sync_stopped(saver, session_id).await;
+            // Only a parent that is terminating waits for us, otherwise it learns we are gone
+            // when the connection is dropped.
+            if let (true, Some(parent)) = (stopped_by_parent, parent) {
+                parent.terminate_sync().await;
+            }
             debug!(target: "aleph-party", "Member task stopped for {:?}", session_id);
         }
     };
 
-    let handle = spawn_handle.spawn_essential("aleph/consensus_session_member", task);
-    Task::new(handle, stop)
+    spawn_member_task(&spawn_handle, "aleph/consensus_session_member", task, stop)
+}
+
+/// Provides no data at all, for members that are not supposed to propose anything.
+struct NoDataProvider;
+
+#[async_trait::async_trait]
+impl<B: Block> current_aleph_bft::DataProvider<AlephData<B>> for NoDataProvider {
+    async fn get_data(&mut self) -> Option<AlephData<B>> {
+        None
+    }
+}
+
+/// Runs the member of the session as an observer, which follows the consensus and gets the
+/// ordered data, but proposes no data and sends nothing to the other members, so none of its
+/// units ever reach them. It still takes up its index in the committee, so the others treat it
+/// as a member that is down.
+pub fn run_observer<
+    B: Block,
+    C: HeaderBackend<B> + Send + 'static,
+    ADN: DataNetwork<CurrentNetworkData<B>> + 'static,
+>(
+    subtask_common: SubtaskCommon,
+    multikeychain: Keychain,
+    config: Config,
+    network: ADN,
+    ordered_data_interpreter: OrderedDataInterpreter<B, C>,
+    backup: ABFTBackup,
+    parent_terminator: Option<&mut Terminator>,
+) -> Result<Task, SpawnError> {
+    run_member(
+        subtask_common,
+        multikeychain,
+        config,
+        ReceiveOnlyNetwork::<CurrentNetworkData<B>, _>::new(network).into(),
+        NoDataProvider,
+        ordered_data_interpreter,
+        backup,
+        None,
+        None,
+        parent_terminator,
+    )
 }
 
+/// Creates the config used in production. The weights of the members, if given, have to be
+/// listed in the order of their indices, no weights meaning all the members weigh the same.
+/// The weights do not influence the quorum thresholds: AlephBFT derives them from the number of
+/// members alone, and its config has nowhere to put weights. So they are only checked, equal
+/// weights giving the same config as no weights and differing ones getting rejected, rather than
+/// being silently treated as equal. Weighted quorums need support in AlephBFT first.
+/// A seed only pins the interval between rebroadcasts of a unit, the ordering still depends on
+/// the timing of the network and is not reproducible between runs. Production runs should never
+/// use one.
 pub fn create_aleph_config(
     n_members: usize,
     node_id: NodeIndex,
     session_id: SessionId,
     unit_creation_delay: UnitCreationDelay,
-) -> Config {
-    let delay_config = DelayConfig {
-        tick_interval: Duration::from_millis(100),
-        requests_interval: Duration::from_millis(3000),
-        unit_rebroadcast_interval_min: Duration::from_millis(15000),
-        unit_rebroadcast_interval_max: Duration::from_millis(20000),
-        unit_creation_delay: unit_creation_delay_fn(unit_creation_delay),
+    weights: Option<Vec<u64>>,
+    seed: Option<u64>,
+) -> Result<Config, WeightsError> {
+    check_weights(n_members, weights.as_deref())?;
+    let delay_config = default_delay_config(n_members, unit_creation_delay);
+    let delay_config = match seed {
+        Some(seed) => seed_delay_config(delay_config, seed, node_id),
+        None => delay_config,
     };
+    Ok(create_aleph_config_with_delays(
+        n_members,
+        node_id,
+        session_id,
+        delay_config,
+    ))
+}
 
+/// Creates the config like `create_aleph_config`, but with all the delays provided explicitly.
+pub fn create_aleph_config_with_delays(
+    n_members: usize,
+    node_id: NodeIndex,
+    session_id: SessionId,
+    delay_config: DelayConfig,
+) -> Config {
     AlephConfig::new(delay_config, n_members, node_id, session_id).into()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Write},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use current_aleph_bft::Terminator;
+    use futures::{
+        channel::mpsc::{self, UnboundedReceiver},
+        StreamExt,
+    };
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use sc_service::TaskManager;
+    use substrate_test_runtime_client::{
+        runtime::Block, DefaultTestClientBuilderExt, TestClientBuilder, TestClientBuilderExt,
+    };
+    use tokio::{
+        runtime::Handle,
+        time::{sleep, timeout},
+    };
+
+    use super::{create_aleph_config, create_aleph_config_with_delays, run_member, run_observer};
+    use crate::{
+        abft::{
+            common::{seed_delay_config, DelayConfig, WeightsError},
+            CurrentNetworkData, QuorumMonitor, QuorumReached, StallMonitor,
+        },
+        data_io::{AlephData, OrderedDataInterpreter},
+        network::{
+            mock::{crypto_basics, fully_connected_mock},
+            Direction, RecordingNetwork,
+        },
+        oneshot,
+        party::{
+            backup::{BackupSaver, Loader, Saver},
+            manager::{SubtaskCommon, Task},
+        },
+        testing::{client_chain_builder::ClientChainBuilder, mocks::aleph_data_from_blocks},
+        BlockHashNum, Keychain, NodeIndex, SessionBoundaries, SessionId, SessionPeriod,
+        UnitCreationDelay,
+    };
+
+    const NODES_N: usize = 4;
+    const BLOCKS_N: usize = 7;
+    const SEED: u64 = 2137;
+    const SESSION_PERIOD: u32 = 100;
+    const FINALIZATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+    type TestNetworkData = CurrentNetworkData<Block>;
+
+    /// Drops everything saved, remembering whether it was synced.
+    struct SyncRecorder(Arc<AtomicBool>);
+
+    impl Write for SyncRecorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl BackupSaver for SyncRecorder {
+        fn sync(&mut self) -> io::Result<()> {
+            self.0.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    /// Proposes randomly chosen prefixes of a known branch.
+    struct RandomPrefixProvider {
+        blocks: Vec<Block>,
+        rng: StdRng,
+    }
+
+    #[async_trait::async_trait]
+    impl current_aleph_bft::DataProvider<AlephData<Block>> for RandomPrefixProvider {
+        async fn get_data(&mut self) -> Option<AlephData<Block>> {
+            let len = self.rng.gen_range(1..=self.blocks.len());
+            Some(aleph_data_from_blocks(self.blocks[..len].to_vec()))
+        }
+    }
+
+    fn fast_delay_config() -> DelayConfig {
+        DelayConfig {
+            tick_interval: Duration::from_millis(10),
+            requests_interval: Duration::from_millis(50),
+            unit_rebroadcast_interval_min: Duration::from_millis(500),
+            unit_rebroadcast_interval_max: Duration::from_millis(600),
+            unit_creation_delay: Arc::new(|_| Duration::from_millis(10)),
+        }
+    }
+
+    async fn finalized_up_to(
+        mut blocks_to_finalize: UnboundedReceiver<BlockHashNum<Block>>,
+        last: BlockHashNum<Block>,
+    ) -> Vec<BlockHashNum<Block>> {
+        let mut finalized = Vec::new();
+        while let Some(block) = blocks_to_finalize.next().await {
+            let done = block == last;
+            finalized.push(block);
+            if done {
+                break;
+            }
+        }
+        finalized
+    }
+
+    /// Runs all the members until they finalize the whole branch, returning what every one of them
+    /// finalized and the last block of the branch. Seeded members rebroadcast units at pinned
+    /// intervals. The isolated member, if any, runs cut off from everyone else and is not waited
+    /// for, unless it gets reconnected after the given time.
+    async fn finalize_branch(
+        seed: Option<u64>,
+        isolated: Option<NodeIndex>,
+        reconnect_after: Option<Duration>,
+    ) -> (Vec<Vec<BlockHashNum<Block>>>, BlockHashNum<Block>) {
+        let task_manager = TaskManager::new(Handle::current(), None).unwrap();
+        let client = Arc::new(TestClientBuilder::new().build());
+        let mut chain_builder =
+            ClientChainBuilder::new(client.clone(), Arc::new(TestClientBuilder::new().build()));
+        let blocks = chain_builder
+            .initialize_single_branch_and_import(BLOCKS_N)
+            .await;
+        let last = blocks.last().expect("there are some blocks").header.clone();
+        let last: BlockHashNum<Block> = (last.hash(), last.number).into();
+        let session_id = SessionId(0);
+        let session_boundaries = SessionBoundaries::new(session_id, SessionPeriod(SESSION_PERIOD));
+
+        let (authorities, verifier) = crypto_basics(NODES_N).await;
+        let (networks, links) = fully_connected_mock::<TestNetworkData>(NODES_N);
+        if let Some(isolated) = isolated {
+            links.isolate(isolated);
+            if let Some(reconnect_after) = reconnect_after {
+                tokio::spawn(async move {
+                    sleep(reconnect_after).await;
+                    for other in 0..NODES_N {
+                        links.restore(isolated, NodeIndex(other));
+                    }
+                });
+            }
+        }
+        let mut tasks = Vec::new();
+        let mut outputs = Vec::new();
+        for ((node_id, pen), network) in authorities.into_iter().zip(networks) {
+            let (blocks_to_finalize_tx, blocks_to_finalize_rx) = mpsc::unbounded();
+            let interpreter = OrderedDataInterpreter::new(
+                blocks_to_finalize_tx,
+                client.clone(),
+                session_boundaries.clone(),
+            );
+            let data_provider = RandomPrefixProvider {
+                blocks: blocks.clone(),
+                rng: StdRng::seed_from_u64(SEED + node_id.0 as u64),
+            };
+            let delay_config = match seed {
+                Some(seed) => seed_delay_config(fast_delay_config(), seed, node_id),
+                None => fast_delay_config(),
+            };
+            let config =
+                create_aleph_config_with_delays(NODES_N, node_id, session_id, delay_config);
+            tasks.push(
+                run_member(
+                    SubtaskCommon {
+                        spawn_handle: task_manager.spawn_handle().into(),
+                        session_id: session_id.0,
+                    },
+                    Keychain::new(node_id, verifier.clone(), pen),
+                    config,
+                    network.into(),
+                    data_provider,
+                    interpreter,
+                    (Box::new(std::io::sink()), Box::new(std::io::empty())),
+                    None,
+                    None,
+                    None,
+                )
+                .expect("member task spawns"),
+            );
+            if isolated != Some(node_id) || reconnect_after.is_some() {
+                outputs.push(finalized_up_to(blocks_to_finalize_rx, last.clone()));
+            }
+        }
+
+        let outputs = timeout(FINALIZATION_TIMEOUT, futures::future::join_all(outputs))
+            .await
+            .expect("all members should finalize the whole branch");
+        for task in tasks {
+            task.stop().await.expect("member should stop cleanly");
+        }
+        (outputs, last)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn members_finalize_the_same_blocks() {
+        let (outputs, last) = finalize_branch(None, None, None).await;
+        assert!(outputs[0].ends_with(&[last]));
+        for output in &outputs[1..] {
+            assert_eq!(output, &outputs[0]);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn seeded_members_finalize_the_same_blocks() {
+        // the seed does not make the ordering reproducible between runs, it only must not get in
+        // the way of the members agreeing within one
+        let (outputs, last) = finalize_branch(Some(SEED), None, None).await;
+        assert!(outputs[0].ends_with(&[last]));
+        for output in &outputs[1..] {
+            assert_eq!(output, &outputs[0]);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn members_finalize_despite_partitioned_member() {
+        let (outputs, last) = finalize_branch(None, Some(NodeIndex(NODES_N - 1)), None).await;
+        assert_eq!(outputs.len(), NODES_N - 1);
+        assert!(outputs[0].ends_with(&[last]));
+        for output in &outputs[1..] {
+            assert_eq!(output, &outputs[0]);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn partitioned_member_catches_up_once_reconnected() {
+        let (outputs, last) = finalize_branch(
+            None,
+            Some(NodeIndex(NODES_N - 1)),
+            Some(Duration::from_secs(1)),
+        )
+        .await;
+        assert_eq!(outputs.len(), NODES_N);
+        assert!(outputs[0].ends_with(&[last]));
+        for output in &outputs[1..] {
+            assert_eq!(output, &outputs[0]);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn observer_follows_without_sending() {
+        let task_manager = TaskManager::new(Handle::current(), None).unwrap();
+        let client = Arc::new(TestClientBuilder::new().build());
+        let mut chain_builder =
+            ClientChainBuilder::new(client.clone(), Arc::new(TestClientBuilder::new().build()));
+        let blocks = chain_builder
+            .initialize_single_branch_and_import(BLOCKS_N)
+            .await;
+        let last = blocks.last().expect("there are some blocks").header.clone();
+        let last: BlockHashNum<Block> = (last.hash(), last.number).into();
+        let session_id = SessionId(0);
+        let session_boundaries = SessionBoundaries::new(session_id, SessionPeriod(SESSION_PERIOD));
+        let observer_id = NodeIndex(NODES_N - 1);
+        let (records_sink, mut records) = mpsc::unbounded();
+
+        let (authorities, verifier) = crypto_basics(NODES_N).await;
+        let (networks, _links) = fully_connected_mock::<TestNetworkData>(NODES_N);
+        let mut tasks = Vec::new();
+        let mut outputs = Vec::new();
+        for ((node_id, pen), network) in authorities.into_iter().zip(networks) {
+            let (blocks_to_finalize_tx, blocks_to_finalize_rx) = mpsc::unbounded();
+            let interpreter = OrderedDataInterpreter::new(
+                blocks_to_finalize_tx,
+                client.clone(),
+                session_boundaries.clone(),
+            );
+            let subtask_common = SubtaskCommon {
+                spawn_handle: task_manager.spawn_handle().into(),
+                session_id: session_id.0,
+            };
+            let keychain = Keychain::new(node_id, verifier.clone(), pen);
+            let config =
+                create_aleph_config_with_delays(NODES_N, node_id, session_id, fast_delay_config());
+            let backup = (
+                Box::new(std::io::sink()) as Saver,
+                Box::new(std::io::empty()) as Loader,
+            );
+            let task = match node_id == observer_id {
+                true => run_observer(
+                    subtask_common,
+                    keychain,
+                    config,
+                    RecordingNetwork::new(network, Some(records_sink.clone())),
+                    interpreter,
+                    backup,
+                    None,
+                ),
+                false => run_member(
+                    subtask_common,
+                    keychain,
+                    config,
+                    network.into(),
+                    RandomPrefixProvider {
+                        blocks: blocks.clone(),
+                        rng: StdRng::seed_from_u64(SEED + node_id.0 as u64),
+                    },
+                    interpreter,
+                    backup,
+                    None,
+                    None,
+                    None,
+                ),
+            };
+            tasks.push(task.expect("member task spawns"));
+            outputs.push(finalized_up_to(blocks_to_finalize_rx, last.clone()));
+        }
+
+        let outputs = timeout(FINALIZATION_TIMEOUT, futures::future::join_all(outputs))
+            .await
+            .expect("the observer should finalize the whole branch with the members");
+        for task in tasks {
+            task.stop().await.expect("member should stop cleanly");
+        }
+        assert!(outputs[0].ends_with(&[last]));
+        for output in &outputs[1..] {
+            assert_eq!(output, &outputs[0]);
+        }
+        let mut received = 0;
+        while let Ok(Some(record)) = records.try_next() {
+            assert_eq!(record.direction, Direction::Received);
+            received += 1;
+        }
+        assert!(received > 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reports_stall_without_enough_peers() {
+        let task_manager = TaskManager::new(Handle::current(), None).unwrap();
+        let client = Arc::new(TestClientBuilder::new().build());
+        let mut chain_builder =
+            ClientChainBuilder::new(client.clone(), Arc::new(TestClientBuilder::new().build()));
+        let blocks = chain_builder
+            .initialize_single_branch_and_import(BLOCKS_N)
+            .await;
+        let session_id = SessionId(0);
+        let session_boundaries = SessionBoundaries::new(session_id, SessionPeriod(SESSION_PERIOD));
+        let stall_window = Duration::from_millis(500);
+        let (stalled_tx, mut stalled) = mpsc::unbounded();
+
+        let (authorities, verifier) = crypto_basics(NODES_N).await;
+        // Only half of the members run, which is not enough to order anything.
+        let mut tasks = Vec::new();
+        let mut blocks_to_finalize = Vec::new();
+        for ((node_id, pen), network) in authorities
+            .into_iter()
+            .zip(fully_connected_mock::<TestNetworkData>(NODES_N).0)
+            .take(NODES_N / 2)
+        {
+            let (blocks_to_finalize_tx, blocks_to_finalize_rx) = mpsc::unbounded();
+            let interpreter = OrderedDataInterpreter::new(
+                blocks_to_finalize_tx,
+                client.clone(),
+                session_boundaries.clone(),
+            );
+            let data_provider = RandomPrefixProvider {
+                blocks: blocks.clone(),
+                rng: StdRng::seed_from_u64(SEED + node_id.0 as u64),
+            };
+            let config =
+                create_aleph_config_with_delays(NODES_N, node_id, session_id, fast_delay_config());
+            tasks.push(
+                run_member(
+                    SubtaskCommon {
+                        spawn_handle: task_manager.spawn_handle().into(),
+                        session_id: session_id.0,
+                    },
+                    Keychain::new(node_id, verifier.clone(), pen),
+                    config,
+                    network.into(),
+                    data_provider,
+                    interpreter,
+                    (Box::new(std::io::sink()), Box::new(std::io::empty())),
+                    Some(StallMonitor::new(stall_window, stalled_tx.clone())),
+                    None,
+                    None,
+                )
+                .expect("member task spawns"),
+            );
+            blocks_to_finalize.push(blocks_to_finalize_rx);
+        }
+
+        for _ in 0..NODES_N / 2 {
+            let event = timeout(FINALIZATION_TIMEOUT, stalled.next())
+                .await
+                .expect("the stall should be reported")
+                .expect("members should still be running");
+            assert_eq!(event.session_id, session_id);
+            assert!(event.elapsed >= stall_window);
+        }
+        // the members keep running after the report
+        for mut finalized in blocks_to_finalize {
+            assert!(finalized.try_next().is_err());
+        }
+        for task in tasks {
+            task.stop().await.expect("member should stop cleanly");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reports_quorum_with_threshold_peers() {
+        let task_manager = TaskManager::new(Handle::current(), None).unwrap();
+        let client = Arc::new(TestClientBuilder::new().build());
+        let mut chain_builder =
+            ClientChainBuilder::new(client.clone(), Arc::new(TestClientBuilder::new().build()));
+        let blocks = chain_builder
+            .initialize_single_branch_and_import(BLOCKS_N)
+            .await;
+        let session_id = SessionId(0);
+        let session_boundaries = SessionBoundaries::new(session_id, SessionPeriod(SESSION_PERIOD));
+        let (quorum_tx, mut quorum) = mpsc::unbounded();
+
+        let (authorities, verifier) = crypto_basics(NODES_N).await;
+        // With four members, each running member is connected to exactly the two peers it needs.
+        let running = NODES_N - 1;
+        let mut tasks = Vec::new();
+        let mut blocks_to_finalize = Vec::new();
+        for ((node_id, pen), network) in authorities
+            .into_iter()
+            .zip(fully_connected_mock::<TestNetworkData>(NODES_N).0)
+            .take(running)
+        {
+            let (blocks_to_finalize_tx, blocks_to_finalize_rx) = mpsc::unbounded();
+            let interpreter = OrderedDataInterpreter::new(
+                blocks_to_finalize_tx,
+                client.clone(),
+                session_boundaries.clone(),
+            );
+            let data_provider = RandomPrefixProvider {
+                blocks: blocks.clone(),
+                rng: StdRng::seed_from_u64(SEED + node_id.0 as u64),
+            };
+            let config =
+                create_aleph_config_with_delays(NODES_N, node_id, session_id, fast_delay_config());
+            tasks.push(
+                run_member(
+                    SubtaskCommon {
+                        spawn_handle: task_manager.spawn_handle().into(),
+                        session_id: session_id.0,
+                    },
+                    Keychain::new(node_id, verifier.clone(), pen),
+                    config,
+                    network.into(),
+                    data_provider,
+                    interpreter,
+                    (Box::new(std::io::sink()), Box::new(std::io::empty())),
+                    None,
+                    Some(QuorumMonitor::new(quorum_tx.clone())),
+                    None,
+                )
+                .expect("member task spawns"),
+            );
+            blocks_to_finalize.push(blocks_to_finalize_rx);
+        }
+
+        for _ in 0..running {
+            let event = timeout(FINALIZATION_TIMEOUT, quorum.next())
+                .await
+                .expect("the quorum should be reported")
+                .expect("members should still be running");
+            assert_eq!(event, QuorumReached { session_id });
+        }
+        for task in tasks {
+            task.stop().await.expect("member should stop cleanly");
+        }
+        // every member reports only once
+        assert!(quorum.try_next().is_err());
+    }
+
+    /// Runs a single member, which is not enough to order anything, saving to the given saver.
+    async fn run_lonely_member(
+        task_manager: &TaskManager,
+        saver: Saver,
+        parent_terminator: Option<&mut Terminator>,
+    ) -> Task {
+        let client = Arc::new(TestClientBuilder::new().build());
+        let mut chain_builder =
+            ClientChainBuilder::new(client.clone(), Arc::new(TestClientBuilder::new().build()));
+        let blocks = chain_builder
+            .initialize_single_branch_and_import(BLOCKS_N)
+            .await;
+        let session_id = SessionId(0);
+        let session_boundaries = SessionBoundaries::new(session_id, SessionPeriod(SESSION_PERIOD));
+        let (authorities, verifier) = crypto_basics(NODES_N).await;
+        let ((node_id, pen), network) = authorities
+            .into_iter()
+            .zip(fully_connected_mock::<TestNetworkData>(NODES_N).0)
+            .next()
+            .expect("there are some members");
+        let (blocks_to_finalize_tx, _blocks_to_finalize_rx) = mpsc::unbounded();
+        let interpreter =
+            OrderedDataInterpreter::new(blocks_to_finalize_tx, client, session_boundaries);
+        let data_provider = RandomPrefixProvider {
+            blocks,
+            rng: StdRng::seed_from_u64(SEED),
+        };
+        let config =
+            create_aleph_config_with_delays(NODES_N, node_id, session_id, fast_delay_config());
+        run_member(
+            SubtaskCommon {
+                spawn_handle: task_manager.spawn_handle().into(),
+                session_id: session_id.0,
+            },
+            Keychain::new(node_id, verifier, pen),
+            config,
+            network.into(),
+            data_provider,
+            interpreter,
+            (saver, Box::new(std::io::empty())),
+            None,
+            None,
+            parent_terminator,
+        )
+        .expect("member task spawns")
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn syncs_backup_before_stopping() {
+        let task_manager = TaskManager::new(Handle::current(), None).unwrap();
+        let synced = Arc::new(AtomicBool::new(false));
+        let task =
+            run_lonely_member(&task_manager, Box::new(SyncRecorder(synced.clone())), None).await;
+
+        assert!(!synced.load(Ordering::SeqCst));
+        task.stop().await.expect("member should stop cleanly");
+        assert!(synced.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stops_with_parent_terminator() {
+        let task_manager = TaskManager::new(Handle::current(), None).unwrap();
+        let (stop_parent, parent_exit) = oneshot::channel();
+        let mut parent = Terminator::create_root(parent_exit, "parent");
+        let synced = Arc::new(AtomicBool::new(false));
+        let mut task = run_lonely_member(
+            &task_manager,
+            Box::new(SyncRecorder(synced.clone())),
+            Some(&mut parent),
+        )
+        .await;
+
+        stop_parent.send(()).expect("parent should listen");
+        timeout(FINALIZATION_TIMEOUT, parent.terminate_sync())
+            .await
+            .expect("the member should terminate with the parent");
+        timeout(FINALIZATION_TIMEOUT, task.stopped())
+            .await
+            .expect("the member task should finish")
+            .expect("member should stop cleanly");
+        assert!(synced.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn equal_weights_give_default_config() {
+        let unit_creation_delay = UnitCreationDelay(200);
+        let default = create_aleph_config(
+            NODES_N,
+            NodeIndex(1),
+            SessionId(43),
+            unit_creation_delay,
+            None,
+            None,
+        )
+        .expect("should accept no weights");
+        for weights in [vec![1; NODES_N], vec![2137; NODES_N]] {
+            let config = create_aleph_config(
+                NODES_N,
+                NodeIndex(1),
+                SessionId(43),
+                unit_creation_delay,
+                Some(weights),
+                None,
+            )
+            .expect("should accept equal weights");
+            assert_eq!(config.n_members, default.n_members);
+            assert_eq!(config.node_ix, default.node_ix);
+            assert_eq!(config.session_id, default.session_id);
+            assert_eq!(config.max_round, default.max_round);
+            assert_eq!(
+                config.delay_config.unit_rebroadcast_interval_min,
+                default.delay_config.unit_rebroadcast_interval_min
+            );
+        }
+    }
+
+    #[test]
+    fn seed_pins_rebroadcast_interval() {
+        let unit_creation_delay = UnitCreationDelay(200);
+        let create = |seed| {
+            create_aleph_config(
+                NODES_N,
+                NodeIndex(1),
+                SessionId(43),
+                unit_creation_delay,
+                None,
+                seed,
+            )
+            .expect("should accept no weights")
+            .delay_config
+        };
+        let default = create(None);
+        let seeded = create(Some(SEED));
+        assert_eq!(
+            seeded.unit_rebroadcast_interval_min,
+            seeded.unit_rebroadcast_interval_max
+        );
+        assert!(
+            seeded.unit_rebroadcast_interval_min >= default.unit_rebroadcast_interval_min
+                && seeded.unit_rebroadcast_interval_max <= default.unit_rebroadcast_interval_max
+        );
+        assert_eq!(
+            create(Some(SEED)).unit_rebroadcast_interval_min,
+            seeded.unit_rebroadcast_interval_min
+        );
+    }
+
+    #[test]
+    fn rejects_unusable_weights() {
+        let create = |weights| {
+            create_aleph_config(
+                NODES_N,
+                NodeIndex(1),
+                SessionId(43),
+                UnitCreationDelay(200),
+                Some(weights),
+                None,
+            )
+            .map(|_| ())
+        };
+        assert_eq!(
+            create(vec![1; NODES_N - 1]),
+            Err(WeightsError::WrongLength {
+                n_members: NODES_N,
+                weights: NODES_N - 1,
+            })
+        );
+        assert_eq!(
+            create(vec![1; NODES_N + 1]),
+            Err(WeightsError::WrongLength {
+                n_members: NODES_N,
+                weights: NODES_N + 1,
+            })
+        );
+        assert_eq!(
+            create(vec![1, 1, 0, 1]),
+            Err(WeightsError::ZeroWeight(NodeIndex(2)))
+        );
+        assert_eq!(create(vec![1, 2, 1, 1]), Err(WeightsError::Unequal));
+    }
+}
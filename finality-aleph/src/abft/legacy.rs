@@ -1,5 +1,3 @@
-use std::time::Duration;
-
 use legacy_aleph_bft::{Config, LocalIO};
 use log::debug;
 use sp_blockchain::HeaderBackend;
@@ -7,14 +5,18 @@ use sp_runtime::traits::Block;
 
 use crate::{
     abft::{
-        common::{unit_creation_delay_fn, AlephConfig, DelayConfig},
-        NetworkWrapper, SpawnHandleT,
+        common::{
+            default_delay_config, seed_delay_config, spawn_member_task, AlephConfig, SpawnError,
+        },
+        quorum::QuorumMonitor,
+        stall::{track_progress, StallMonitor},
+        NetworkWrapper,
     },
     data_io::{AlephData, OrderedDataInterpreter},
     network::DataNetwork,
     oneshot,
     party::{
-        backup::ABFTBackup,
+        backup::{sync_stopped, ABFTBackup, SharedSaver},
         manager::{SubtaskCommon, Task},
     },
     Keychain, LegacyNetworkData, NodeIndex, SessionId, UnitCreationDelay,
@@ -23,6 +25,7 @@ use crate::{
 /// Version of the legacy abft
 pub const VERSION: u32 = 0;
 
+#[allow(clippy::too_many_arguments)]
 pub fn run_member<
     B: Block,
     C: HeaderBackend<B> + Send + 'static,
@@ -35,48 +38,65 @@ pub fn run_member<
     data_provider: impl legacy_aleph_bft::DataProvider<AlephData<B>> + Send + 'static,
     ordered_data_interpreter: OrderedDataInterpreter<B, C>,
     backup: ABFTBackup,
-) -> Task {
+    stall_monitor: Option<StallMonitor>,
+    quorum_monitor: Option<QuorumMonitor>,
+) -> Result<Task, SpawnError> {
     let SubtaskCommon {
         spawn_handle,
         session_id,
     } = subtask_common;
     let (stop, exit) = oneshot::channel();
-    let local_io = LocalIO::new(data_provider, ordered_data_interpreter, backup.0, backup.1);
+    let (finalization_handler, watch_progress) = track_progress(
+        ordered_data_interpreter,
+        stall_monitor,
+        SessionId(session_id),
+    );
+    let network = network.with_quorum_watch(
+        quorum_monitor.map(|monitor| monitor.watch(SessionId(session_id), config.n_members.0)),
+    );
+    let (saver, loader) = backup;
+    let saver = SharedSaver::new(saver);
+    let local_io = LocalIO::new(data_provider, finalization_handler, saver.clone(), loader);
 
     let task = {
         let spawn_handle = spawn_handle.clone();
         async move {
             debug!(target: "aleph-party", "Running the member task for {:?}", session_id);
-            legacy_aleph_bft::run_session(
+            let session = legacy_aleph_bft::run_session(
                 config,
                 local_io,
                 network,
                 multikeychain,
                 spawn_handle,
                 exit,
-            )
-            .await;
+            );
+            // Watching the progress never finishes, so this only waits for the session.
+            tokio::select! {
+                _ = session => (),
+                _ = watch_progress => (),
+            }
+            sync_stopped(saver, session_id).await;
             debug!(target: "aleph-party", "Member task stopped for {:?}", session_id);
         }
     };
 
-    let handle = spawn_handle.spawn_essential("aleph/consensus_session_member", task);
-    Task::new(handle, stop)
+    spawn_member_task(&spawn_handle, "aleph/consensus_session_member", task, stop)
 }
 
+/// Creates the config used in production. A seed only pins the interval between rebroadcasts of a
+/// unit, the ordering still depends on the timing of the network and is not reproducible between
+/// runs. Production runs should never use one.
 pub fn create_aleph_config(
     n_members: usize,
     node_id: NodeIndex,
     session_id: SessionId,
     unit_creation_delay: UnitCreationDelay,
+    seed: Option<u64>,
 ) -> Config {
-    let delay_config = DelayConfig {
-        tick_interval: Duration::from_millis(100),
-        requests_interval: Duration::from_millis(3000),
-        unit_rebroadcast_interval_min: Duration::from_millis(15000),
-        unit_rebroadcast_interval_max: Duration::from_millis(20000),
-        unit_creation_delay: unit_creation_delay_fn(unit_creation_delay),
+    let delay_config = default_delay_config(n_members, unit_creation_delay);
+    let delay_config = match seed {
+        Some(seed) => seed_delay_config(delay_config, seed, node_id),
+        None => delay_config,
     };
-
     AlephConfig::new(delay_config, n_members, node_id, session_id).into()
 }
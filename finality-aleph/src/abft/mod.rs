@@ -6,28 +6,35 @@
 //! by numbers. Whenever we upgrade to next version of abft we need to increment and mark each version
 //! version accordingly.
 
+mod bounded;
 mod common;
 mod crypto;
 mod current;
 mod legacy;
 mod network;
+mod quorum;
+mod stall;
 mod traits;
 mod types;
 
 use std::fmt::Debug;
 
 use aleph_bft_crypto::{PartialMultisignature, Signature};
+pub use bounded::{BoundedDataProvider, MAX_DATA_SIZE};
 use codec::{Decode, Encode};
+pub use common::SpawnError;
 pub use crypto::Keychain;
 pub use current::{
     create_aleph_config as current_create_aleph_config, run_member as run_current_member,
-    VERSION as CURRENT_VERSION,
+    run_observer as run_current_observer, VERSION as CURRENT_VERSION,
 };
 pub use legacy::{
     create_aleph_config as legacy_create_aleph_config, run_member as run_legacy_member,
     VERSION as LEGACY_VERSION,
 };
 pub use network::{CurrentNetworkData, LegacyNetworkData, NetworkWrapper};
+pub use quorum::{QuorumMonitor, QuorumReached};
+pub use stall::{SessionStalled, StallMonitor, DEFAULT_STALL_WINDOW};
 pub use traits::{Hash, SpawnHandle, SpawnHandleT, Wrapper as HashWrapper};
 pub use types::{NodeCount, NodeIndex, Recipient};
 
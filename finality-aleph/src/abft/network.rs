@@ -4,7 +4,7 @@ use log::warn;
 use sp_runtime::traits::Block;
 
 use crate::{
-    abft::SignatureSet,
+    abft::{quorum::QuorumWatch, SignatureSet},
     crypto::Signature,
     data_io::{AlephData, AlephNetworkMessage},
     network::{Data, DataNetwork},
@@ -36,6 +36,7 @@ impl<B: Block> AlephNetworkMessage<B>
 /// A wrapper needed only because of type system theoretical constraints. Sadness.
 pub struct NetworkWrapper<D: Data, DN: DataNetwork<D>> {
     inner: DN,
+    quorum: Option<QuorumWatch>,
     _phantom: PhantomData<D>,
 }
 
@@ -43,12 +44,27 @@ impl<D: Data, DN: DataNetwork<D>> From<DN> for NetworkWrapper<D, DN> {
     fn from(inner: DN) -> Self {
         NetworkWrapper {
             inner,
+            quorum: None,
             _phantom: PhantomData,
         }
     }
 }
 
 impl<D: Data, DN: DataNetwork<D>> NetworkWrapper<D, DN> {
+    /// Returns the wrapper reporting to the watch once enough peers are connected.
+    pub fn with_quorum_watch(self, quorum: Option<QuorumWatch>) -> Self {
+        NetworkWrapper { quorum, ..self }
+    }
+
+    /// The peers are checked whenever the member waits for data, which it does all the time.
+    fn check_quorum(&mut self) {
+        if let Some(quorum) = &self.quorum {
+            if quorum.check(self.inner.peers().len()) {
+                self.quorum = None;
+            }
+        }
+    }
+
     fn send<R>(&self, data: D, recipient: R)
     where
         R: Into<Recipient>,
@@ -59,6 +75,7 @@ impl<D: Data, DN: DataNetwork<D>> NetworkWrapper<D, DN> {
     }
 
     async fn next_event(&mut self) -> Option<D> {
+        self.check_quorum();
         self.inner.next().await
     }
 }
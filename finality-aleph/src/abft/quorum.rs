@@ -0,0 +1,93 @@
+use futures::channel::mpsc;
+use log::debug;
+
+use crate::SessionId;
+
+/// Reported when enough members of a session are connected for its consensus to make progress.
+/// This does not mean anything got ordered yet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuorumReached {
+    pub session_id: SessionId,
+}
+
+/// Where to report sessions reaching a quorum of connected members.
+#[derive(Clone)]
+pub struct QuorumMonitor {
+    events: mpsc::UnboundedSender<QuorumReached>,
+}
+
+impl QuorumMonitor {
+    pub fn new(events: mpsc::UnboundedSender<QuorumReached>) -> Self {
+        QuorumMonitor { events }
+    }
+
+    /// Starts watching a session with the given number of members.
+    pub fn watch(self, session_id: SessionId, n_members: usize) -> QuorumWatch {
+        QuorumWatch {
+            session_id,
+            threshold: peers_for_quorum(n_members),
+            events: self.events,
+        }
+    }
+}
+
+/// How many peers have to be connected, so that together with us they make up more than two
+/// thirds of the members.
+fn peers_for_quorum(n_members: usize) -> usize {
+    (n_members - n_members.saturating_sub(1) / 3).saturating_sub(1)
+}
+
+/// Reports the quorum of a single session, at most once.
+pub struct QuorumWatch {
+    session_id: SessionId,
+    threshold: usize,
+    events: mpsc::UnboundedSender<QuorumReached>,
+}
+
+impl QuorumWatch {
+    /// Reports the quorum if enough peers are connected. Returns whether the watch is done, after
+    /// which it should not be checked anymore.
+    pub fn check(&self, connected_peers: usize) -> bool {
+        if connected_peers < self.threshold {
+            return false;
+        }
+        let event = QuorumReached {
+            session_id: self.session_id,
+        };
+        if self.events.unbounded_send(event).is_err() {
+            debug!(target: "aleph-party", "Nobody listens for sessions reaching quorum, not reporting it for {:?}.", self.session_id);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc;
+
+    use super::{peers_for_quorum, QuorumMonitor, QuorumReached};
+    use crate::SessionId;
+
+    #[test]
+    fn needs_more_than_two_thirds_with_us() {
+        assert_eq!(peers_for_quorum(1), 0);
+        assert_eq!(peers_for_quorum(4), 2);
+        assert_eq!(peers_for_quorum(6), 3);
+        assert_eq!(peers_for_quorum(7), 4);
+        assert_eq!(peers_for_quorum(10), 6);
+    }
+
+    #[test]
+    fn reports_once_threshold_is_connected() {
+        let (events_tx, mut events) = mpsc::unbounded();
+        let session_id = SessionId(43);
+        let watch = QuorumMonitor::new(events_tx).watch(session_id, 7);
+        assert!(!watch.check(3));
+        assert!(events.try_next().is_err());
+        assert!(watch.check(4));
+        assert_eq!(
+            events.try_next().unwrap(),
+            Some(QuorumReached { session_id })
+        );
+    }
+}
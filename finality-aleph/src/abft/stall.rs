@@ -0,0 +1,166 @@
+use std::{sync::Arc, time::Instant};
+
+use futures::{channel::mpsc, future::pending};
+use log::debug;
+use parking_lot::Mutex;
+use tokio::time::{sleep, Duration};
+
+use crate::SessionId;
+
+/// How long a session can order nothing before it is considered stalled, by default.
+pub const DEFAULT_STALL_WINDOW: Duration = Duration::from_secs(60);
+
+/// Reported when the consensus of a session did not order any data for a while.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionStalled {
+    pub session_id: SessionId,
+    /// How long ago the session last ordered anything, or started if it never did.
+    pub elapsed: Duration,
+}
+
+/// Where to report stalled sessions and after how long without progress.
+#[derive(Clone)]
+pub struct StallMonitor {
+    window: Duration,
+    events: mpsc::UnboundedSender<SessionStalled>,
+}
+
+impl StallMonitor {
+    pub fn new(window: Duration, events: mpsc::UnboundedSender<SessionStalled>) -> Self {
+        StallMonitor { window, events }
+    }
+
+    /// Watches the progress of the session, reporting it as stalled once per window for as long as
+    /// nothing is ordered. Never finishes, so that it can be raced against the session itself.
+    async fn watch(self, session_id: SessionId, last_progress: Arc<Mutex<Instant>>) {
+        loop {
+            let elapsed = last_progress.lock().elapsed();
+            if elapsed < self.window {
+                sleep(self.window - elapsed).await;
+                continue;
+            }
+            let event = SessionStalled {
+                session_id,
+                elapsed,
+            };
+            if self.events.unbounded_send(event).is_err() {
+                debug!(target: "aleph-party", "Nobody listens for stalled sessions, stopping the monitor for {:?}.", session_id);
+                return pending().await;
+            }
+            sleep(self.window).await;
+        }
+    }
+}
+
+/// Passes the ordered data to the wrapped finalization handler, noting when the session last
+/// made progress.
+pub struct ProgressTracker<FH> {
+    inner: FH,
+    last_progress: Arc<Mutex<Instant>>,
+}
+
+impl<FH> ProgressTracker<FH> {
+    fn note_progress(&self) {
+        *self.last_progress.lock() = Instant::now();
+    }
+}
+
+/// Wraps the finalization handler so that its progress is watched by the monitor, if any.
+/// The returned future never finishes.
+pub fn track_progress<FH>(
+    finalization_handler: FH,
+    monitor: Option<StallMonitor>,
+    session_id: SessionId,
+) -> (ProgressTracker<FH>, impl futures::Future<Output = ()>) {
+    let last_progress = Arc::new(Mutex::new(Instant::now()));
+    let tracker = ProgressTracker {
+        inner: finalization_handler,
+        last_progress: last_progress.clone(),
+    };
+    let watch = async move {
+        match monitor {
+            Some(monitor) => monitor.watch(session_id, last_progress).await,
+            None => pending().await,
+        }
+    };
+    (tracker, watch)
+}
+
+impl<D, FH: current_aleph_bft::FinalizationHandler<D>> current_aleph_bft::FinalizationHandler<D>
+    for ProgressTracker<FH>
+{
+    fn data_finalized(&mut self, data: D) {
+        self.note_progress();
+        self.inner.data_finalized(data)
+    }
+}
+
+impl<D, FH: legacy_aleph_bft::FinalizationHandler<D>> legacy_aleph_bft::FinalizationHandler<D>
+    for ProgressTracker<FH>
+{
+    fn data_finalized(&mut self, data: D) {
+        self.note_progress();
+        self.inner.data_finalized(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{channel::mpsc, StreamExt};
+    use tokio::time::{timeout, Duration};
+
+    use super::{track_progress, StallMonitor};
+    use crate::SessionId;
+
+    struct Counter(usize);
+
+    impl current_aleph_bft::FinalizationHandler<u32> for Counter {
+        fn data_finalized(&mut self, _: u32) {
+            self.0 += 1;
+        }
+    }
+
+    #[tokio::test]
+    async fn quiet_while_making_progress() {
+        let window = Duration::from_millis(100);
+        let (events_tx, mut events) = mpsc::unbounded();
+        let (mut tracker, watch) = track_progress(
+            Counter(0),
+            Some(StallMonitor::new(window, events_tx)),
+            SessionId(43),
+        );
+        let progress = async {
+            for data in 0..20 {
+                current_aleph_bft::FinalizationHandler::data_finalized(&mut tracker, data);
+                tokio::time::sleep(window / 4).await;
+            }
+        };
+        tokio::select! {
+            _ = watch => panic!("the monitor should never finish"),
+            _ = progress => (),
+        }
+        assert_eq!(tracker.inner.0, 20);
+        // the monitor is gone, and it reported nothing
+        assert!(events.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn reports_repeatedly_while_stalled() {
+        let window = Duration::from_millis(50);
+        let (events_tx, mut events) = mpsc::unbounded();
+        let (_tracker, watch) = track_progress(
+            Counter(0),
+            Some(StallMonitor::new(window, events_tx)),
+            SessionId(43),
+        );
+        tokio::spawn(watch);
+        for _ in 0..2 {
+            let event = timeout(Duration::from_secs(5), events.next())
+                .await
+                .expect("should report the stall")
+                .expect("should not close the channel");
+            assert_eq!(event.session_id, SessionId(43));
+            assert!(event.elapsed >= window);
+        }
+    }
+}
@@ -9,8 +9,9 @@ use crate::{
     data_io::{
         chain_info::{AuxFinalizationChainInfoProvider, CachedChainInfoProvider},
         status_provider::get_proposal_status,
-        AlephData, ChainInfoProvider,
+        AlephData, ChainInfoProvider, OrderedDataLog,
     },
+    metrics::OrderedDataMetrics,
     mpsc::TrySendError,
     BlockHashNum, SessionBoundaries,
 };
@@ -26,6 +27,8 @@ pub struct OrderedDataInterpreter<B: BlockT, C: HeaderBackend<B>> {
     chain_info_provider: InterpretersChainInfoProvider<B, C>,
     last_finalized_by_aleph: BlockHashNum<B>,
     session_boundaries: SessionBoundaries<B>,
+    metrics: Option<OrderedDataMetrics>,
+    log: Option<OrderedDataLog<B>>,
 }
 
 fn get_last_block_prev_session<B: BlockT, C: HeaderBackend<B>>(
@@ -64,6 +67,24 @@ impl<B: BlockT, C: HeaderBackend<B>> OrderedDataInterpreter<B, C> {
             chain_info_provider,
             last_finalized_by_aleph,
             session_boundaries,
+            metrics: None,
+            log: None,
+        }
+    }
+
+    /// Returns the interpreter counting all the data it gets in the metrics.
+    pub fn with_metrics(self, metrics: OrderedDataMetrics) -> Self {
+        OrderedDataInterpreter {
+            metrics: Some(metrics),
+            ..self
+        }
+    }
+
+    /// Returns the interpreter appending all the data it gets to the log.
+    pub fn with_log(self, log: OrderedDataLog<B>) -> Self {
+        OrderedDataInterpreter {
+            log: Some(log),
+            ..self
         }
     }
 
@@ -113,6 +134,12 @@ impl<B: BlockT, C: HeaderBackend<B>> OrderedDataInterpreter<B, C> {
     }
 
     pub fn data_finalized(&mut self, data: AlephData<B>) {
+        if let Some(metrics) = &self.metrics {
+            metrics.report_ordered();
+        }
+        if let Some(log) = &self.log {
+            log.record(data.clone());
+        }
         for block in self.blocks_to_finalize_from_data(data) {
             self.set_last_finalized(block.clone());
             self.chain_info_provider()
@@ -124,3 +151,44 @@ impl<B: BlockT, C: HeaderBackend<B>> OrderedDataInterpreter<B, C> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::channel::mpsc;
+    use prometheus_endpoint::Registry;
+    use substrate_test_runtime_client::{
+        DefaultTestClientBuilderExt, TestClientBuilder, TestClientBuilderExt,
+    };
+
+    use super::OrderedDataInterpreter;
+    use crate::{
+        metrics::OrderedDataMetrics,
+        testing::{client_chain_builder::ClientChainBuilder, mocks::aleph_data_from_blocks},
+        SessionBoundaries, SessionId, SessionPeriod,
+    };
+
+    #[tokio::test]
+    async fn counts_ordered_data() {
+        let client = Arc::new(TestClientBuilder::new().build());
+        let mut chain_builder =
+            ClientChainBuilder::new(client.clone(), Arc::new(TestClientBuilder::new().build()));
+        let blocks = chain_builder.initialize_single_branch_and_import(3).await;
+        let metrics = OrderedDataMetrics::register(&Registry::new()).unwrap();
+        let (blocks_to_finalize_tx, _blocks_to_finalize) = mpsc::unbounded();
+        let mut interpreter = OrderedDataInterpreter::new(
+            blocks_to_finalize_tx,
+            client,
+            SessionBoundaries::new(SessionId(0), SessionPeriod(100)),
+        )
+        .with_metrics(metrics.clone());
+        assert_eq!(metrics.ordered_total(), 0);
+        assert_eq!(metrics.seconds_since_last_ordered(), None);
+        for len in 1..=blocks.len() {
+            interpreter.data_finalized(aleph_data_from_blocks(blocks[..len].to_vec()));
+            assert_eq!(metrics.ordered_total(), len as u64);
+        }
+        assert_eq!(metrics.seconds_since_last_ordered(), Some(0));
+    }
+}
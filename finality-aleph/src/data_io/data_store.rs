@@ -26,7 +26,7 @@ use crate::{
         status_provider::get_proposal_status,
         AlephNetworkMessage,
     },
-    network::{ComponentNetwork, DataNetwork, ReceiverComponent, RequestBlocks, SimpleNetwork},
+    network::{ComponentNetwork, ReceiverComponent, RequestBlocks, SimpleNetwork},
     BlockHashNum, SessionBoundaries,
 };
 
@@ -210,7 +210,10 @@ where
         block_requester: RB,
         config: DataStoreConfig,
         component_network: N,
-    ) -> (Self, impl DataNetwork<Message>) {
+    ) -> (
+        Self,
+        SimpleNetwork<Message, mpsc::UnboundedReceiver<Message>, N::S>,
+    ) {
         let (messages_for_aleph, messages_from_data_store) = mpsc::unbounded();
         let (messages_to_network, messages_from_network) = component_network.into();
         let status = client.info();
@@ -0,0 +1,309 @@
+use std::{
+    fmt, fs,
+    fs::File,
+    io,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use codec::{Decode, Encode};
+use futures::{
+    channel::{mpsc, oneshot},
+    StreamExt,
+};
+use log::{info, warn};
+use parking_lot::Mutex;
+use sp_runtime::traits::Block as BlockT;
+use tokio::task;
+
+use crate::{data_io::AlephData, SessionId};
+
+const EXPORT_MAGIC: [u8; 4] = *b"ALOD";
+const PARTIAL_EXPORT_SUFFIX: &str = ".partial";
+
+/// Written at the start of every export, so that a file in an unknown format is never read as
+/// ordered data.
+#[derive(Debug, PartialEq, Eq, Encode, Decode)]
+struct ExportHeader {
+    magic: [u8; 4],
+    session_id: SessionId,
+}
+
+/// Reasons for which exporting or reading back the ordered data might fail.
+#[derive(Debug)]
+pub enum ExportError {
+    /// No session was started yet, so there is nothing to export.
+    NoSession,
+    IOError(io::Error),
+    /// The file does not start with the expected magic bytes.
+    BadMagic,
+    /// The file ends in the middle of the header or of an item.
+    Truncated,
+    /// The item with the given index could not be decoded.
+    Undecodable(usize),
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportError::NoSession => write!(f, "no session to export"),
+            ExportError::IOError(err) => write!(f, "IO error: {}", err),
+            ExportError::BadMagic => write!(f, "not an ordered data export"),
+            ExportError::Truncated => write!(f, "export truncated"),
+            ExportError::Undecodable(index) => write!(f, "item {} could not be decoded", index),
+        }
+    }
+}
+
+impl From<io::Error> for ExportError {
+    fn from(err: io::Error) -> Self {
+        Self::IOError(err)
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+struct SessionLog<B: BlockT> {
+    session_id: SessionId,
+    data: Vec<AlephData<B>>,
+}
+
+/// Everything the interpreter output in the latest session it was started for, in order.
+/// Cloning is cheap and all the clones share the same log.
+#[derive(Clone)]
+pub struct OrderedDataLog<B: BlockT> {
+    session: Arc<Mutex<Option<SessionLog<B>>>>,
+}
+
+impl<B: BlockT> Default for OrderedDataLog<B> {
+    fn default() -> Self {
+        OrderedDataLog {
+            session: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl<B: BlockT> OrderedDataLog<B> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forgets the data of the previous session and starts logging the given one.
+    pub fn start_session(&self, session_id: SessionId) {
+        *self.session.lock() = Some(SessionLog {
+            session_id,
+            data: Vec::new(),
+        });
+    }
+
+    /// Appends the data to the log of the current session, if any was started.
+    pub fn record(&self, data: AlephData<B>) {
+        if let Some(session) = self.session.lock().as_mut() {
+            session.data.push(data);
+        }
+    }
+
+    /// Writes the log of the current session to the file at the given path and returns how many
+    /// items it contained. The log is taken as a whole at once and the file is only put in place
+    /// once fully written and synced, so a reader never sees a torn export.
+    ///
+    /// The format is the SCALE encoded header, followed by every item encoded with SCALE and
+    /// prefixed with its length as a little endian `u32`.
+    pub fn export(&self, path: &Path) -> Result<usize, ExportError> {
+        let (session_id, data) = match self.session.lock().as_ref() {
+            Some(session) => (session.session_id, session.data.clone()),
+            None => return Err(ExportError::NoSession),
+        };
+        let mut partial_path = path.as_os_str().to_owned();
+        partial_path.push(PARTIAL_EXPORT_SUFFIX);
+        let partial_path = PathBuf::from(partial_path);
+        let mut file = File::create(&partial_path)?;
+        file.write_all(
+            &ExportHeader {
+                magic: EXPORT_MAGIC,
+                session_id,
+            }
+            .encode(),
+        )?;
+        for item in &data {
+            let encoded = item.encode();
+            file.write_all(&(encoded.len() as u32).to_le_bytes())?;
+            file.write_all(&encoded)?;
+        }
+        file.flush()?;
+        file.sync_all()?;
+        fs::rename(&partial_path, path)?;
+        // the rename only survives a crash once the directory holding the file is synced too
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(directory)?.sync_all()?;
+        Ok(data.len())
+    }
+}
+
+/// Reads back an export made by [`OrderedDataLog::export`].
+pub fn read_export<B: BlockT>(path: &Path) -> Result<(SessionId, Vec<AlephData<B>>), ExportError> {
+    let contents = fs::read(path)?;
+    let mut rest = contents.as_slice();
+    let header = ExportHeader::decode(&mut rest).map_err(|_| ExportError::Truncated)?;
+    if header.magic != EXPORT_MAGIC {
+        return Err(ExportError::BadMagic);
+    }
+    let mut data = Vec::new();
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return Err(ExportError::Truncated);
+        }
+        let (len, tail) = rest.split_at(4);
+        let len = u32::from_le_bytes(len.try_into().expect("we took exactly 4 bytes")) as usize;
+        if tail.len() < len {
+            return Err(ExportError::Truncated);
+        }
+        let (mut item, tail) = tail.split_at(len);
+        let item =
+            AlephData::<B>::decode(&mut item).map_err(|_| ExportError::Undecodable(data.len()))?;
+        data.push(item);
+        rest = tail;
+    }
+    Ok((header.session_id, data))
+}
+
+/// A command to export the ordered data of the current session to the file at the path. The
+/// number of exported items, or the reason of the failure, is sent back.
+pub struct ExportRequest {
+    pub path: PathBuf,
+    pub result: oneshot::Sender<Result<usize, ExportError>>,
+}
+
+/// Exports the log whenever requested, until nobody can request exports anymore.
+pub async fn serve_exports<B: BlockT>(
+    log: OrderedDataLog<B>,
+    mut requests: mpsc::UnboundedReceiver<ExportRequest>,
+) {
+    while let Some(ExportRequest { path, result }) = requests.next().await {
+        let exporting_log = log.clone();
+        let exporting_path = path.clone();
+        let exported = task::spawn_blocking(move || exporting_log.export(&exporting_path))
+            .await
+            .unwrap_or_else(|e| Err(io::Error::new(io::ErrorKind::Other, e).into()));
+        match &exported {
+            Ok(items) => {
+                info!(target: "aleph-party", "Exported {} ordered items to {:?}", items, path)
+            }
+            Err(e) => {
+                warn!(target: "aleph-party", "Could not export ordered data to {:?}: {}", path, e)
+            }
+        }
+        // The export is done either way, whether anyone still waits for the result or not.
+        let _ = result.send(exported);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf, sync::Arc};
+
+    use futures::channel::{mpsc, oneshot};
+    use substrate_test_runtime_client::{
+        runtime::Block, DefaultTestClientBuilderExt, TestClientBuilder, TestClientBuilderExt,
+    };
+
+    use super::{read_export, serve_exports, ExportError, ExportRequest, OrderedDataLog};
+    use crate::{
+        data_io::OrderedDataInterpreter,
+        testing::{client_chain_builder::ClientChainBuilder, mocks::aleph_data_from_blocks},
+        SessionBoundaries, SessionId, SessionPeriod,
+    };
+
+    fn export_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "aleph-ordered-data-{}-{}",
+            std::process::id(),
+            name
+        ))
+    }
+
+    #[tokio::test]
+    async fn exports_ordered_data_in_order() {
+        let client = Arc::new(TestClientBuilder::new().build());
+        let mut chain_builder =
+            ClientChainBuilder::new(client.clone(), Arc::new(TestClientBuilder::new().build()));
+        let blocks = chain_builder.initialize_single_branch_and_import(3).await;
+        let log = OrderedDataLog::new();
+        log.start_session(SessionId(0));
+        let (blocks_to_finalize_tx, _blocks_to_finalize) = mpsc::unbounded();
+        let mut interpreter = OrderedDataInterpreter::new(
+            blocks_to_finalize_tx,
+            client,
+            SessionBoundaries::new(SessionId(0), SessionPeriod(100)),
+        )
+        .with_log(log.clone());
+        let ordered: Vec<_> = (1..=blocks.len())
+            .map(|len| aleph_data_from_blocks(blocks[..len].to_vec()))
+            .collect();
+        for data in &ordered {
+            interpreter.data_finalized(data.clone());
+        }
+
+        let path = export_path("in-order");
+        assert_eq!(log.export(&path).expect("should export"), ordered.len());
+        let (session_id, exported) = read_export(&path).expect("should read back");
+        fs::remove_file(&path).expect("should clean up");
+        assert_eq!(session_id, SessionId(0));
+        assert_eq!(exported, ordered);
+    }
+
+    #[test]
+    fn nothing_to_export_before_any_session() {
+        let log = OrderedDataLog::<Block>::new();
+        let path = export_path("no-session");
+        assert!(matches!(log.export(&path), Err(ExportError::NoSession)));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn rejects_truncated_export() {
+        let log = OrderedDataLog::<Block>::new();
+        log.start_session(SessionId(7));
+        let path = export_path("truncated");
+        log.export(&path).expect("should export");
+        let contents = fs::read(&path).expect("should read");
+        fs::write(&path, &contents[..contents.len() - 1]).expect("should write");
+        let result = read_export::<Block>(&path);
+        fs::remove_file(&path).expect("should clean up");
+        assert!(matches!(result, Err(ExportError::Truncated)));
+    }
+
+    #[tokio::test]
+    async fn exports_on_request() {
+        let log = OrderedDataLog::<Block>::new();
+        log.start_session(SessionId(7));
+        let (requests_for_exporter, requests) = mpsc::unbounded();
+        let exporter = tokio::spawn(serve_exports(log, requests));
+
+        let path = export_path("on-request");
+        let (result, exported) = oneshot::channel();
+        requests_for_exporter
+            .unbounded_send(ExportRequest {
+                path: path.clone(),
+                result,
+            })
+            .expect("exporter should be running");
+        let exported = exported.await.expect("should get the result");
+        let read_back = read_export::<Block>(&path);
+        fs::remove_file(&path).expect("should clean up");
+        assert_eq!(exported.expect("should export"), 0);
+        assert_eq!(
+            read_back.expect("should read back"),
+            (SessionId(7), Vec::new())
+        );
+
+        drop(requests_for_exporter);
+        exporter
+            .await
+            .expect("should stop once nobody can request exports");
+    }
+}
@@ -10,6 +10,7 @@ mod chain_info;
 mod data_interpreter;
 mod data_provider;
 mod data_store;
+mod export;
 mod proposal;
 mod status_provider;
 
@@ -17,6 +18,7 @@ pub use chain_info::ChainInfoProvider;
 pub use data_interpreter::OrderedDataInterpreter;
 pub use data_provider::{ChainTracker, DataProvider};
 pub use data_store::{DataStore, DataStoreConfig};
+pub use export::{read_export, serve_exports, ExportError, ExportRequest, OrderedDataLog};
 pub use proposal::UnvalidatedAlephProposal;
 
 // Maximum number of blocks above the last finalized allowed in an AlephBFT proposal.
@@ -8,6 +8,7 @@ use futures::{
     channel::{mpsc, oneshot},
     Future,
 };
+use prometheus_endpoint::Registry;
 use sc_client_api::{backend::Backend, BlockchainEvents, Finalizer, LockImportRun, TransactionFor};
 use sc_consensus::BlockImport;
 use sc_network::{ExHashT, NetworkService};
@@ -51,10 +52,11 @@ mod validator_network;
 
 pub use abft::{Keychain, NodeCount, NodeIndex, Recipient, SignatureSet, SpawnHandle};
 pub use aleph_primitives::{AuthorityId, AuthorityPair, AuthoritySignature};
+pub use data_io::{read_export, ExportError, ExportRequest};
 pub use import::AlephBlockImport;
 pub use justification::{AlephJustification, JustificationNotification};
 pub use network::Protocol;
-pub use nodes::{run_nonvalidator_node, run_validator_node};
+pub use nodes::{run_nonvalidator_node, run_validator_node, NetworkAdminCommand};
 pub use session::SessionPeriod;
 
 pub use crate::metrics::Metrics;
@@ -269,4 +271,14 @@ pub struct AlephConfig<B: Block, H: ExHashT, C, SC> {
     pub backup_saving_path: Option<PathBuf>,
     pub external_addresses: Vec<String>,
     pub validator_port: u16,
+    pub registry: Option<Registry>,
+    /// Where commands to export the data ordered in the current session come from, if exporting
+    /// is enabled at all.
+    pub ordered_data_exports: Option<mpsc::UnboundedReceiver<ExportRequest>>,
+    /// Whether to log all the consensus traffic, for debugging.
+    pub record_consensus: bool,
+    /// Whether to only follow the consensus, without proposing data or sending anything.
+    pub observer: bool,
+    /// Where commands adjusting the validator network come from, if it can be adjusted at all.
+    pub network_admin: Option<mpsc::UnboundedReceiver<NetworkAdminCommand>>,
 }
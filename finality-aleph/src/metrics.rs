@@ -2,13 +2,13 @@ use std::{
     collections::HashMap,
     fmt::Debug,
     hash::Hash,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use log::{trace, warn};
 use lru::LruCache;
 use parking_lot::Mutex;
-use prometheus_endpoint::{register, Gauge, PrometheusError, Registry, U64};
+use prometheus_endpoint::{register, Counter, Gauge, PrometheusError, Registry, U64};
 use sc_service::Arc;
 
 // How many entries (block hash + timestamp) we keep in memory per one checkpoint type.
@@ -123,6 +123,61 @@ impl<H: Key> Metrics<H> {
     }
 }
 
+/// Counts the data ordered by the consensus, to tell whether it still makes progress. Cloning is
+/// cheap and all the clones report to the same counters.
+#[derive(Clone)]
+pub struct OrderedDataMetrics {
+    ordered_total: Counter<U64>,
+    last_ordered_timestamp: Gauge<U64>,
+    last_ordered: Arc<Mutex<Option<Instant>>>,
+}
+
+impl OrderedDataMetrics {
+    pub fn register(registry: &Registry) -> Result<Self, PrometheusError> {
+        Ok(OrderedDataMetrics {
+            ordered_total: register(
+                Counter::new(
+                    "aleph_ordered_data_total",
+                    "Data items ordered by the consensus",
+                )?,
+                registry,
+            )?,
+            // The time since the last ordering is computed from this on the dashboards, a gauge
+            // holding it directly would only be as fresh as the last ordering.
+            last_ordered_timestamp: register(
+                Gauge::new(
+                    "aleph_last_ordered_data_timestamp",
+                    "When the consensus last ordered data, in seconds since the unix epoch",
+                )?,
+                registry,
+            )?,
+            last_ordered: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// Report a data item being ordered.
+    pub fn report_ordered(&self) {
+        self.ordered_total.inc();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |timestamp| timestamp.as_secs());
+        self.last_ordered_timestamp.set(timestamp);
+        *self.last_ordered.lock() = Some(Instant::now());
+    }
+
+    /// How many data items were ordered so far.
+    pub fn ordered_total(&self) -> u64 {
+        self.ordered_total.get()
+    }
+
+    /// How long ago the last data item was ordered, if any was.
+    pub fn seconds_since_last_ordered(&self) -> Option<u64> {
+        self.last_ordered
+            .lock()
+            .map(|last_ordered| last_ordered.elapsed().as_secs())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::cmp::min;
@@ -1,16 +1,18 @@
-use std::{fmt::Display, marker::PhantomData};
+use std::{fmt::Display, marker::PhantomData, sync::Arc};
 
-use futures::{channel::mpsc, StreamExt};
+use futures::{channel::mpsc, lock::Mutex, StreamExt};
 use log::warn;
 
 use crate::{
     network::{Data, DataNetwork, SendError},
-    Recipient,
+    NodeIndex, Recipient,
 };
 
 /// For sending arbitrary messages.
 pub trait Sender<D: Data>: Sync + Send + Clone {
     fn send(&self, data: D, recipient: Recipient) -> Result<(), SendError>;
+    /// The nodes we can currently send data to, in increasing order.
+    fn peers(&self) -> Vec<NodeIndex>;
 }
 
 #[derive(Clone)]
@@ -34,6 +36,10 @@ impl<D: Data, S: Sender<D>, IntoD: Data + Into<D>> Sender<IntoD> for MapSender<D
     fn send(&self, data: IntoD, recipient: Recipient) -> Result<(), SendError> {
         self.sender.send(data.into(), recipient)
     }
+
+    fn peers(&self) -> Vec<NodeIndex> {
+        self.sender.peers()
+    }
 }
 
 /// For receiving arbitrary messages.
@@ -101,6 +107,10 @@ impl<D: Data, N: NetworkExt<D>> DataNetwork<D> for N {
     async fn next(&mut self) -> Option<D> {
         self.as_mut().next().await
     }
+
+    fn peers(&self) -> Vec<NodeIndex> {
+        self.as_ref().peers()
+    }
 }
 
 pub trait NetworkMap<D: Data, IntoD: Data>: Network<D> {
@@ -127,6 +137,11 @@ impl<D: Data> Sender<D> for mpsc::UnboundedSender<(D, Recipient)> {
         self.unbounded_send((data, recipient))
             .map_err(|_| SendError::SendFailed)
     }
+
+    /// A bare channel knows nothing about the nodes behind it.
+    fn peers(&self) -> Vec<NodeIndex> {
+        Vec::new()
+    }
 }
 
 #[async_trait::async_trait]
@@ -136,6 +151,39 @@ impl<D: Data> Receiver<D> for mpsc::UnboundedReceiver<D> {
     }
 }
 
+/// A receiver that can be cloned, with all the clones taking from the same inner receiver, so
+/// that it can be handed over to a new user once the previous one is gone. Meant for one user at
+/// a time, others wait until the current one stops waiting for data.
+pub struct SharedReceiver<D, R> {
+    receiver: Arc<Mutex<R>>,
+    _phantom: PhantomData<D>,
+}
+
+impl<D: Data, R: Receiver<D>> SharedReceiver<D, R> {
+    pub fn new(receiver: R) -> Self {
+        SharedReceiver {
+            receiver: Arc::new(Mutex::new(receiver)),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<D, R> Clone for SharedReceiver<D, R> {
+    fn clone(&self) -> Self {
+        SharedReceiver {
+            receiver: self.receiver.clone(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<D: Data, R: Receiver<D>> Receiver<D> for SharedReceiver<D, R> {
+    async fn next(&mut self) -> Option<D> {
+        self.receiver.lock().await.next().await
+    }
+}
+
 pub struct SimpleNetwork<D: Data, R: Receiver<D>, S: Sender<D>> {
     receiver: R,
     sender: S,
@@ -182,13 +230,13 @@ mod tests {
         StreamExt,
     };
 
-    use super::{DataNetwork, NetworkMap, Receiver, Sender};
+    use super::{DataNetwork, NetworkMap, Receiver, Sender, SharedReceiver};
     use crate::{
         network::{
             component::{Network, ReceiverMap, SenderMap},
             Data, SendError,
         },
-        Recipient,
+        NodeIndex, Recipient,
     };
 
     #[tokio::test]
@@ -201,6 +249,19 @@ mod tests {
         assert_eq!(Some(val), received);
     }
 
+    #[tokio::test]
+    async fn shared_receiver_clones_take_from_the_same_receiver() {
+        let (sender, receiver) = mpsc::unbounded();
+        let mut first = SharedReceiver::new(receiver);
+        let mut second = first.clone();
+
+        sender.unbounded_send(1u64).unwrap();
+        sender.unbounded_send(2u64).unwrap();
+        assert_eq!(first.next().await, Some(1));
+        drop(first);
+        assert_eq!(second.next().await, Some(2));
+    }
+
     #[derive(Decode, Encode, Clone, PartialEq, Eq, Debug, Copy)]
     enum FromType {
         A,
@@ -262,6 +323,10 @@ mod tests {
                 .unbounded_send(data)
                 .map_err(|_| SendError::SendFailed)
         }
+
+        fn peers(&self) -> Vec<NodeIndex> {
+            Vec::new()
+        }
     }
 
     struct TestReceiver<D>(UnboundedReceiver<D>);
@@ -0,0 +1,144 @@
+use std::marker::PhantomData;
+
+use prometheus_endpoint::{register, CounterVec, Opts, PrometheusError, Registry, U64};
+
+use crate::{
+    network::{Data, DataNetwork, SendError},
+    NodeIndex, Recipient,
+};
+
+/// Counts the data sent to each of the nodes, labelled with their indices, so that the traffic
+/// can be attributed to specific validators. Cloning is cheap and all the clones report to the
+/// same counters.
+#[derive(Clone)]
+pub struct SendMetrics {
+    sent: CounterVec<U64>,
+}
+
+impl SendMetrics {
+    pub fn register(registry: &Registry) -> Result<Self, PrometheusError> {
+        Ok(SendMetrics {
+            sent: register(
+                CounterVec::new(
+                    Opts::new(
+                        "aleph_network_data_sent",
+                        "Data sent by the consensus, by the index of the receiving node",
+                    ),
+                    &["node"],
+                )?,
+                registry,
+            )?,
+        })
+    }
+
+    fn report_sent(&self, node_id: NodeIndex) {
+        self.sent.with_label_values(&[&node_id.0.to_string()]).inc();
+    }
+
+    /// How much data was sent to the node so far.
+    pub fn sent(&self, node_id: NodeIndex) -> u64 {
+        self.sent.with_label_values(&[&node_id.0.to_string()]).get()
+    }
+}
+
+/// A network passing everything to the inner network, and additionally counting the data sent to
+/// each of the nodes in the metrics, if they are provided. Data sent to everyone counts for every
+/// node we can currently send to.
+pub struct CountingNetwork<D: Data, DN: DataNetwork<D>> {
+    inner: DN,
+    metrics: Option<SendMetrics>,
+    _phantom: PhantomData<D>,
+}
+
+impl<D: Data, DN: DataNetwork<D>> CountingNetwork<D, DN> {
+    /// Wrap the network, without metrics nothing gets counted.
+    pub fn new(inner: DN, metrics: Option<SendMetrics>) -> Self {
+        CountingNetwork {
+            inner,
+            metrics,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<D: Data, DN: DataNetwork<D>> DataNetwork<D> for CountingNetwork<D, DN> {
+    fn send(&self, data: D, recipient: Recipient) -> Result<(), SendError> {
+        if let Some(metrics) = &self.metrics {
+            match &recipient {
+                Recipient::Node(node_id) => metrics.report_sent(*node_id),
+                Recipient::Everyone => {
+                    for node_id in self.inner.peers() {
+                        metrics.report_sent(node_id);
+                    }
+                }
+            }
+        }
+        self.inner.send(data, recipient)
+    }
+
+    async fn next(&mut self) -> Option<D> {
+        self.inner.next().await
+    }
+
+    fn peers(&self) -> Vec<NodeIndex> {
+        self.inner.peers()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{channel::mpsc, StreamExt};
+    use prometheus_endpoint::Registry;
+
+    use super::{CountingNetwork, SendMetrics};
+    use crate::{
+        network::{DataNetwork, SendError},
+        NodeIndex, Recipient,
+    };
+
+    struct TestNetwork {
+        sent: mpsc::UnboundedSender<(u64, Recipient)>,
+        peers: Vec<NodeIndex>,
+    }
+
+    #[async_trait::async_trait]
+    impl DataNetwork<u64> for TestNetwork {
+        fn send(&self, data: u64, recipient: Recipient) -> Result<(), SendError> {
+            self.sent
+                .unbounded_send((data, recipient))
+                .map_err(|_| SendError::SendFailed)
+        }
+
+        async fn next(&mut self) -> Option<u64> {
+            None
+        }
+
+        fn peers(&self) -> Vec<NodeIndex> {
+            self.peers.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_data_sent_to_each_peer() {
+        let (sent, mut sent_from_network) = mpsc::unbounded();
+        let peers = vec![NodeIndex(1), NodeIndex(2), NodeIndex(3)];
+        let metrics = SendMetrics::register(&Registry::new()).expect("should register");
+        let network = CountingNetwork::new(TestNetwork { sent, peers }, Some(metrics.clone()));
+
+        for recipient in [
+            Recipient::Node(NodeIndex(1)),
+            Recipient::Node(NodeIndex(1)),
+            Recipient::Node(NodeIndex(2)),
+            Recipient::Everyone,
+        ] {
+            network.send(43, recipient.clone()).expect("should send");
+            assert_eq!(sent_from_network.next().await, Some((43, recipient)));
+        }
+
+        assert_eq!(metrics.sent(NodeIndex(1)), 3);
+        assert_eq!(metrics.sent(NodeIndex(2)), 2);
+        assert_eq!(metrics.sent(NodeIndex(3)), 1);
+        assert_eq!(metrics.sent(NodeIndex(4)), 0);
+    }
+}
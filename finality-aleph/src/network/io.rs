@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use futures::channel::mpsc;
 
 use crate::network::{
@@ -5,12 +7,13 @@ use crate::network::{
     SessionManagerIO,
 };
 
-type NetworkServiceIO<D, M> = NetworkIo<NetworkData<D, M>, M>;
+/// The network service only ever sees data shared by the connection manager.
+type NetworkServiceIO<D, M> = NetworkIo<NetworkData<Arc<D>, M>, M>;
 
 pub fn setup<D: Data, M: Multiaddress + 'static>() -> (
     ConnectionManagerIO<D, M>,
     NetworkServiceIO<D, M>,
-    SessionManagerIO<D>,
+    SessionManagerIO<D, M>,
 ) {
     // Prepare and start the network
     let (commands_for_network, commands_from_io) = mpsc::unbounded();
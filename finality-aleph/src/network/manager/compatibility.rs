@@ -6,22 +6,73 @@ use std::{
 use codec::{Decode, Encode, Error as CodecError, Input as CodecInput};
 use log::warn;
 
-use crate::network::{
-    manager::{DiscoveryMessage, NetworkData},
-    Data, Multiaddress,
+use crate::{
+    crypto::Signature,
+    network::{
+        manager::{ControlMessage, DiscoveryMessage, LegacyAuthData, NetworkData},
+        Data, Multiaddress,
+    },
 };
 
 type Version = u16;
 type ByteCount = u16;
 
+/// Authentications without sequence numbers.
+const LEGACY_VERSION: Version = 1;
+/// The version of the authentications we create.
+const CURRENT_VERSION: Version = 2;
+/// Control messages, which older versions ignore as coming from the future.
+const CONTROL_VERSION: Version = 3;
+
 // We allow sending authentications of size up to 16KiB, that should be enough.
 const MAX_AUTHENTICATION_SIZE: u16 = 16 * 1024;
 
+/// Discovery messages as sent in version 1, with authentications lacking sequence numbers.
+#[derive(Clone, Debug, PartialEq, Eq, Encode, Decode)]
+enum LegacyDiscoveryMessage<M: Multiaddress> {
+    AuthenticationBroadcast((LegacyAuthData<M>, Signature)),
+    Authentication((LegacyAuthData<M>, Signature)),
+}
+
+impl<M: Multiaddress> From<LegacyDiscoveryMessage<M>> for DiscoveryMessage<M> {
+    fn from(message: LegacyDiscoveryMessage<M>) -> Self {
+        use LegacyDiscoveryMessage::*;
+        match message {
+            AuthenticationBroadcast((auth_data, signature)) => {
+                DiscoveryMessage::AuthenticationBroadcast((auth_data.into(), signature))
+            }
+            Authentication((auth_data, signature)) => {
+                DiscoveryMessage::Authentication((auth_data.into(), signature))
+            }
+        }
+    }
+}
+
+impl<M: Multiaddress> From<&DiscoveryMessage<M>> for LegacyDiscoveryMessage<M> {
+    fn from(message: &DiscoveryMessage<M>) -> Self {
+        use DiscoveryMessage::*;
+        match message {
+            AuthenticationBroadcast((auth_data, signature)) => {
+                LegacyDiscoveryMessage::AuthenticationBroadcast((
+                    auth_data.to_legacy(),
+                    signature.clone(),
+                ))
+            }
+            Authentication((auth_data, signature)) => {
+                LegacyDiscoveryMessage::Authentication((auth_data.to_legacy(), signature.clone()))
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum VersionedAuthentication<M: Multiaddress> {
     // Most likely from the future.
     Other(Version, Vec<u8>),
+    // Authentications without sequence numbers, which get the legacy one.
     V1(DiscoveryMessage<M>),
+    V2(DiscoveryMessage<M>),
+    V3(ControlMessage),
 }
 
 impl<D: Data, M: Multiaddress> TryInto<NetworkData<D, M>> for VersionedAuthentication<M> {
@@ -30,7 +81,8 @@ impl<D: Data, M: Multiaddress> TryInto<NetworkData<D, M>> for VersionedAuthentic
     fn try_into(self) -> Result<NetworkData<D, M>, Self::Error> {
         use VersionedAuthentication::*;
         match self {
-            V1(message) => Ok(NetworkData::Meta(message)),
+            V1(message) | V2(message) => Ok(NetworkData::Meta(message)),
+            V3(message) => Ok(NetworkData::Control(message)),
             Other(v, _) => Err(Error::UnknownVersion(v)),
         }
     }
@@ -38,7 +90,41 @@ impl<D: Data, M: Multiaddress> TryInto<NetworkData<D, M>> for VersionedAuthentic
 
 impl<M: Multiaddress> From<DiscoveryMessage<M>> for VersionedAuthentication<M> {
     fn from(message: DiscoveryMessage<M>) -> VersionedAuthentication<M> {
-        VersionedAuthentication::V1(message)
+        // Legacy authentications are passed on in the format their creators understand.
+        let is_legacy = match &message {
+            DiscoveryMessage::AuthenticationBroadcast((auth_data, _))
+            | DiscoveryMessage::Authentication((auth_data, _)) => auth_data.is_legacy(),
+        };
+        match is_legacy {
+            true => VersionedAuthentication::V1(message),
+            false => VersionedAuthentication::V2(message),
+        }
+    }
+}
+
+impl<M: Multiaddress> From<ControlMessage> for VersionedAuthentication<M> {
+    fn from(message: ControlMessage) -> VersionedAuthentication<M> {
+        VersionedAuthentication::V3(message)
+    }
+}
+
+/// Decodes an authentication we are able to use, otherwise tells apart the ones we do not
+/// understand, most likely because of a version skew, from the ones that are just broken.
+pub fn decode_authentication<D: Data, M: Multiaddress>(
+    bytes: &[u8],
+) -> Result<NetworkData<D, M>, Error> {
+    match VersionedAuthentication::<M>::decode(&mut &bytes[..]) {
+        Ok(authentication) => authentication.try_into(),
+        Err(_) => match Version::decode(&mut &bytes[..]) {
+            Ok(version)
+                if version != LEGACY_VERSION
+                    && version != CURRENT_VERSION
+                    && version != CONTROL_VERSION =>
+            {
+                Err(Error::UnknownVersion(version))
+            }
+            _ => Err(Error::MalformedPayload),
+        },
     }
 }
 
@@ -81,7 +167,9 @@ impl<M: Multiaddress> Encode for VersionedAuthentication<M> {
             + byte_count_size
             + match self {
                 Other(_, payload) => payload.len(),
-                V1(data) => data.size_hint(),
+                V1(data) => LegacyDiscoveryMessage::from(data).size_hint(),
+                V2(data) => data.size_hint(),
+                V3(message) => message.size_hint(),
             }
     }
 
@@ -89,7 +177,11 @@ impl<M: Multiaddress> Encode for VersionedAuthentication<M> {
         use VersionedAuthentication::*;
         match self {
             Other(version, payload) => encode_with_version(*version, payload),
-            V1(data) => encode_with_version(1, &data.encode()),
+            V1(data) => {
+                encode_with_version(LEGACY_VERSION, &LegacyDiscoveryMessage::from(data).encode())
+            }
+            V2(data) => encode_with_version(CURRENT_VERSION, &data.encode()),
+            V3(message) => encode_with_version(CONTROL_VERSION, &message.encode()),
         }
     }
 }
@@ -100,7 +192,9 @@ impl<M: Multiaddress> Decode for VersionedAuthentication<M> {
         let version = Version::decode(input)?;
         let num_bytes = ByteCount::decode(input)?;
         match version {
-            1 => Ok(V1(DiscoveryMessage::decode(input)?)),
+            LEGACY_VERSION => Ok(V1(LegacyDiscoveryMessage::decode(input)?.into())),
+            CURRENT_VERSION => Ok(V2(DiscoveryMessage::decode(input)?)),
+            CONTROL_VERSION => Ok(V3(ControlMessage::decode(input)?)),
             _ => {
                 if num_bytes > MAX_AUTHENTICATION_SIZE {
                     Err("Authentication has unknown version and is encoded as more than 16KiB.")?;
@@ -116,6 +210,7 @@ impl<M: Multiaddress> Decode for VersionedAuthentication<M> {
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Error {
     UnknownVersion(Version),
+    MalformedPayload,
 }
 
 impl Display for Error {
@@ -125,6 +220,7 @@ impl Display for Error {
             UnknownVersion(version) => {
                 write!(f, "Authentication has unknown version {}", version)
             }
+            MalformedPayload => write!(f, "Authentication is malformed"),
         }
     }
 }
@@ -133,10 +229,13 @@ impl Display for Error {
 mod test {
     use codec::{Decode, Encode};
 
-    use super::{DiscoveryMessage, VersionedAuthentication};
+    use super::{decode_authentication, DiscoveryMessage, Error, VersionedAuthentication};
     use crate::{
         network::{
-            manager::{compatibility::MAX_AUTHENTICATION_SIZE, SessionHandler},
+            manager::{
+                compatibility::{CONTROL_VERSION, MAX_AUTHENTICATION_SIZE},
+                AuthData, NetworkData, SessionHandler,
+            },
             mock::{crypto_basics, MockMultiaddress, MockNetworkIdentity},
             NetworkIdentity,
         },
@@ -144,7 +243,7 @@ mod test {
     };
 
     #[tokio::test]
-    async fn correctly_decodes_v1() {
+    async fn correctly_decodes_v2() {
         let crypto_basics = crypto_basics(1).await;
         let handler = SessionHandler::new(
             Some(crypto_basics.0[0].clone()),
@@ -154,12 +253,66 @@ mod test {
         )
         .await
         .unwrap();
-        let authentication_v1 = VersionedAuthentication::V1(DiscoveryMessage::Authentication(
+        let authentication_v2 = VersionedAuthentication::V2(DiscoveryMessage::Authentication(
             handler.authentication().unwrap(),
         ));
+        let encoded = authentication_v2.encode();
+        let decoded = VersionedAuthentication::decode(&mut encoded.as_slice());
+        assert_eq!(decoded, Ok(authentication_v2))
+    }
+
+    #[tokio::test]
+    async fn decodes_v1_with_legacy_sequence() {
+        let crypto_basics = crypto_basics(1).await;
+        let handler = SessionHandler::new(
+            Some(crypto_basics.0[0].clone()),
+            crypto_basics.1.clone(),
+            SessionId(43),
+            MockNetworkIdentity::new().identity().0,
+        )
+        .await
+        .unwrap();
+        let (auth_data, signature) = handler.authentication().unwrap();
+        let legacy_auth_data: AuthData<MockMultiaddress> = auth_data.to_legacy().into();
+        let message = DiscoveryMessage::AuthenticationBroadcast((legacy_auth_data, signature));
+        let authentication_v1 = VersionedAuthentication::from(message.clone());
+        assert_eq!(
+            authentication_v1,
+            VersionedAuthentication::V1(message.clone())
+        );
         let encoded = authentication_v1.encode();
+        assert_eq!(encoded[..2], 1u16.encode()[..]);
         let decoded = VersionedAuthentication::decode(&mut encoded.as_slice());
-        assert_eq!(decoded, Ok(authentication_v1))
+        assert_eq!(decoded, Ok(authentication_v1));
+        assert_eq!(
+            decode_authentication::<i32, MockMultiaddress>(&encoded),
+            Ok(NetworkData::Meta(message))
+        );
+    }
+
+    #[tokio::test]
+    async fn decodes_v3_as_control() {
+        let crypto_basics = crypto_basics(1).await;
+        let handler = SessionHandler::<MockMultiaddress>::new(
+            Some(crypto_basics.0[0].clone()),
+            crypto_basics.1.clone(),
+            SessionId(43),
+            MockNetworkIdentity::new().identity().0,
+        )
+        .await
+        .unwrap();
+        let leaving = handler.leaving().await.unwrap();
+        let control = VersionedAuthentication::<MockMultiaddress>::from(leaving.clone());
+        let encoded = control.encode();
+        assert_eq!(encoded[..2], 3u16.encode()[..]);
+        assert_eq!(
+            VersionedAuthentication::decode(&mut encoded.as_slice()),
+            Ok(control)
+        );
+        assert_eq!(
+            decode_authentication::<i32, MockMultiaddress>(&encoded),
+            Ok(NetworkData::Control(leaving))
+        );
     }
 
     #[tokio::test]
@@ -206,4 +359,53 @@ mod test {
         let decoded = VersionedAuthentication::<MockMultiaddress>::decode(&mut other.as_slice());
         assert!(decoded.is_err());
     }
+
+    #[tokio::test]
+    async fn reports_unknown_version() {
+        let mut future = 42u16.encode();
+        future.append(&mut 2u16.encode());
+        future.append(&mut vec![21, 37]);
+        let decoded = decode_authentication::<i32, MockMultiaddress>(&future);
+        assert_eq!(decoded, Err(Error::UnknownVersion(42)));
+        // even if we cannot even read the whole payload
+        let decoded = decode_authentication::<i32, MockMultiaddress>(&future[..5]);
+        assert_eq!(decoded, Err(Error::UnknownVersion(42)));
+    }
+
+    #[tokio::test]
+    async fn reports_malformed_control_payload() {
+        let mut broken = CONTROL_VERSION.encode();
+        broken.append(&mut 2u16.encode());
+        broken.append(&mut vec![21, 37]);
+        let decoded = decode_authentication::<i32, MockMultiaddress>(&broken);
+        assert_eq!(decoded, Err(Error::MalformedPayload));
+    }
+
+    #[tokio::test]
+    async fn reports_malformed_payload() {
+        let crypto_basics = crypto_basics(1).await;
+        let handler = SessionHandler::new(
+            Some(crypto_basics.0[0].clone()),
+            crypto_basics.1.clone(),
+            SessionId(43),
+            MockNetworkIdentity::new().identity().0,
+        )
+        .await
+        .unwrap();
+        let message = DiscoveryMessage::Authentication(handler.authentication().unwrap());
+        let encoded = VersionedAuthentication::V2(message.clone()).encode();
+        assert_eq!(
+            decode_authentication::<i32, MockMultiaddress>(&encoded),
+            Ok(NetworkData::Meta(message))
+        );
+        let truncated = &encoded[..encoded.len() - 1];
+        assert_eq!(
+            decode_authentication::<i32, MockMultiaddress>(truncated),
+            Err(Error::MalformedPayload)
+        );
+        assert_eq!(
+            decode_authentication::<i32, MockMultiaddress>(&[2]),
+            Err(Error::MalformedPayload)
+        );
+    }
 }
@@ -49,6 +49,22 @@ impl<PID: PeerId> Connections<PID> {
         }
         result
     }
+
+    /// Assume we no longer need to be connected to the peer for the given session.
+    /// Returns the peer if we no longer have any reason to be connected to it.
+    pub fn remove_peer(&mut self, session_id: SessionId, peer: &PID) -> HashSet<PID> {
+        let mut result = HashSet::new();
+        if let Some(peers) = self.peers_by_session.get_mut(&session_id) {
+            peers.remove(peer);
+        }
+        if let Some(sessions) = self.associated_sessions.get_mut(peer) {
+            if sessions.remove(&session_id) && sessions.is_empty() {
+                self.associated_sessions.remove(peer);
+                result.insert(peer.clone());
+            }
+        }
+        result
+    }
 }
 
 #[cfg(test)]
@@ -84,6 +100,24 @@ mod tests {
         assert!(to_remove.is_empty());
     }
 
+    #[test]
+    fn removes_peer_leaving_its_last_session() {
+        let session_id = SessionId(43);
+        let other_session_id = SessionId(2137);
+        let peer_ids = random_peer_ids(2);
+        let leaving = peer_ids.iter().next().expect("there are peers").clone();
+        let mut connections = Connections::new();
+        connections.add_peers(session_id, peer_ids.clone());
+        connections.add_peers(other_session_id, [leaving.clone()]);
+        assert!(connections.remove_peer(session_id, &leaving).is_empty());
+        let to_remove = connections.remove_peer(other_session_id, &leaving);
+        assert_eq!(to_remove, HashSet::from([leaving.clone()]));
+        // the peer is gone only from the sessions it left
+        let mut remaining = peer_ids;
+        remaining.remove(&leaving);
+        assert_eq!(connections.remove_session(session_id), remaining);
+    }
+
     #[test]
     fn removes_peer_only_after_all_sessions_pass() {
         let start = 43;
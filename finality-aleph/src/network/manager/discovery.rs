@@ -1,4 +1,5 @@
 use std::{
+    cmp::{max, min},
     collections::HashMap,
     marker::PhantomData,
     time::{Duration, Instant},
@@ -6,11 +7,13 @@ use std::{
 
 use codec::{Decode, Encode};
 use log::{debug, info, trace, warn};
+use lru::LruCache;
+use rand::{thread_rng, Rng};
 
 use crate::{
     network::{
         manager::{Authentication, SessionHandler},
-        DataCommand, Multiaddress, Protocol,
+        AddressPolicy, DataCommand, Multiaddress, Protocol,
     },
     NodeIndex, SessionId,
 };
@@ -33,10 +36,113 @@ impl<M: Multiaddress> DiscoveryMessage<M> {
     }
 }
 
+/// The bucket of a single authority in a single session. A token is represented by a full
+/// interval worth of credit, so the bucket never holds more than one.
+struct Bucket {
+    credit: Duration,
+    last_refill: Instant,
+    sequence: u64,
+}
+
+/// A token bucket per authority and session, refilled with one token per interval.
+/// Authentications with a sequence number higher than the last rebroadcast one are let through
+/// regardless of the tokens.
+struct RebroadcastLimiter {
+    interval: Duration,
+    buckets: HashMap<(NodeIndex, SessionId), Bucket>,
+}
+
+impl RebroadcastLimiter {
+    fn new(interval: Duration) -> Self {
+        RebroadcastLimiter {
+            interval,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Whether the authentication with the given sequence number should be rebroadcast at the
+    /// given moment, if so it uses up the token.
+    fn allow(&mut self, key: (NodeIndex, SessionId), sequence: u64, now: Instant) -> bool {
+        let bucket = match self.buckets.get_mut(&key) {
+            Some(bucket) => bucket,
+            None => {
+                self.buckets.insert(
+                    key,
+                    Bucket {
+                        credit: Duration::ZERO,
+                        last_refill: now,
+                        sequence,
+                    },
+                );
+                return true;
+            }
+        };
+        bucket.credit = min(
+            bucket.credit + now.saturating_duration_since(bucket.last_refill),
+            self.interval,
+        );
+        bucket.last_refill = max(bucket.last_refill, now);
+        if sequence > bucket.sequence {
+            bucket.sequence = sequence;
+            bucket.credit = Duration::ZERO;
+            return true;
+        }
+        if bucket.credit >= self.interval {
+            bucket.credit = Duration::ZERO;
+            return true;
+        }
+        false
+    }
+
+    /// Drops the buckets of the authorities and sessions for which `keep` returns false.
+    fn retain(&mut self, mut keep: impl FnMut(&(NodeIndex, SessionId)) -> bool) {
+        self.buckets.retain(|key, _| keep(key));
+    }
+}
+
+/// How often we announce our own authentication, regardless of what other nodes send us, so that
+/// nodes which missed the earlier announcements eventually learn our addresses.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AnnouncementSchedule {
+    /// The average time between announcements.
+    pub interval: Duration,
+    /// Every interval is randomly changed by up to this fraction, so that the nodes do not all
+    /// announce at once.
+    pub jitter: f64,
+}
+
+impl AnnouncementSchedule {
+    fn next_after(&self, now: Instant) -> Instant {
+        let jitter = match self.jitter > 0.0 {
+            true => thread_rng().gen_range(-self.jitter..=self.jitter),
+            false => 0.0,
+        };
+        now + Duration::from_secs_f64((self.interval.as_secs_f64() * (1.0 + jitter)).max(0.0))
+    }
+}
+
+/// What discovery knows, enough to restore it later without learning everything anew: the
+/// freshest authentication of every authority in every session, with the sequence number within
+/// it telling how fresh it is.
+#[derive(Clone, Debug, PartialEq, Eq, Encode, Decode)]
+pub struct DiscoveryState<M: Multiaddress> {
+    pub authentications: Vec<Authentication<M>>,
+}
+
+/// How many recently verified authentications are remembered per session, by default.
+pub const DEFAULT_DEDUP_CAPACITY: usize = 256;
+
 /// Handles creating and responding to discovery messages.
 pub struct Discovery<M: Multiaddress> {
-    cooldown: Duration,
-    last_broadcast: HashMap<NodeIndex, Instant>,
+    address_policy: AddressPolicy,
+    rebroadcast_limiter: RebroadcastLimiter,
+    /// The freshest accepted authentication of every authority in every session. Entries of
+    /// authorities that are no longer part of the session are pruned, and everything goes away
+    /// together with the discovery once the session ends.
+    known: HashMap<(NodeIndex, SessionId), Authentication<M>>,
+    announcements: Option<(AnnouncementSchedule, Instant)>,
+    /// Recently verified authentications, exact repeats of them need not be verified again.
+    verified: LruCache<Authentication<M>, ()>,
     _phantom: PhantomData<M>,
 }
 
@@ -65,15 +171,81 @@ fn response<M: Multiaddress>(
 }
 
 impl<M: Multiaddress> Discovery<M> {
-    /// Create a new discovery handler with the given response/broadcast cooldown.
-    pub fn new(cooldown: Duration) -> Self {
+    /// Create a new discovery handler rebroadcasting authentications of every authority at most
+    /// once per cooldown, unless they are fresher, and accepting addresses according to the policy.
+    pub fn new(cooldown: Duration, address_policy: AddressPolicy) -> Self {
         Discovery {
-            cooldown,
-            last_broadcast: HashMap::new(),
+            address_policy,
+            rebroadcast_limiter: RebroadcastLimiter::new(cooldown),
+            known: HashMap::new(),
+            announcements: None,
+            verified: LruCache::new(DEFAULT_DEDUP_CAPACITY),
             _phantom: PhantomData,
         }
     }
 
+    /// Returns the discovery handler remembering at most the given number of recently verified
+    /// authentications.
+    pub fn with_dedup_capacity(self, capacity: usize) -> Self {
+        Discovery {
+            verified: LruCache::new(capacity),
+            ..self
+        }
+    }
+
+    /// Forgets all the verified authentications, needed whenever the handler might start judging
+    /// them differently, e.g. after its verifier changes.
+    pub fn forget_verified(&mut self) {
+        self.verified.clear();
+    }
+
+    /// Forgets the authentications of the nodes the handler no longer accepts, e.g. because they
+    /// left the committee of the session, so that they are neither kept nor snapshotted. Their
+    /// rebroadcast limits, and the limits of any other session, are dropped as well.
+    pub fn forget_departed(&mut self, handler: &SessionHandler<M>) {
+        self.known
+            .retain(|(node_id, _), _| handler.peer_id(node_id).is_some());
+        let session_id = handler.session_id();
+        self.rebroadcast_limiter.retain(|(node_id, session)| {
+            *session == session_id && handler.peer_id(node_id).is_some()
+        });
+    }
+
+    /// Returns the discovery handler additionally announcing our authentication according to the
+    /// schedule, with the first announcement one interval after now.
+    pub fn with_announcements(self, schedule: AnnouncementSchedule, now: Instant) -> Self {
+        Discovery {
+            announcements: Some((schedule, schedule.next_after(now))),
+            ..self
+        }
+    }
+
+    /// When the next scheduled announcement is due, if they are scheduled at all.
+    pub fn next_announcement(&self) -> Option<Instant> {
+        self.announcements.map(|(_, next)| next)
+    }
+
+    /// Returns the announcement of our authentication if it is due at the given moment, and
+    /// schedules the next one.
+    pub fn scheduled_announcements(
+        &mut self,
+        handler: &SessionHandler<M>,
+        now: Instant,
+    ) -> Vec<DiscoveryCommand<M>> {
+        let (schedule, next) = match &mut self.announcements {
+            Some((schedule, next)) if *next <= now => (schedule, next),
+            _ => return Vec::new(),
+        };
+        *next = schedule.next_after(now);
+        match handler.authentication() {
+            Some(authentication) => {
+                trace!(target: "aleph-network", "Announcing our authentication in session {}.", handler.session_id().0);
+                vec![authentication_broadcast(authentication)]
+            }
+            None => Vec::new(),
+        }
+    }
+
     /// Returns messages that should be sent as part of authority discovery at this moment.
     pub fn discover_authorities(
         &mut self,
@@ -90,23 +262,103 @@ impl<M: Multiaddress> Discovery<M> {
         vec![authentication_broadcast(authentication)]
     }
 
+    /// The sequence number of the freshest authentication of the creator of the given one.
+    fn known_sequence(&self, authentication: &Authentication<M>) -> Option<u64> {
+        let auth_data = &authentication.0;
+        self.known
+            .get(&(auth_data.creator(), auth_data.session()))
+            .map(|(known, _)| known.sequence())
+    }
+
+    /// Whether the authentication is at least as fresh as any we know from its creator. Repeats
+    /// of the freshest one are still handled, so that they can be passed on after the cooldown.
+    fn is_fresh(&self, authentication: &Authentication<M>) -> bool {
+        match self.known_sequence(authentication) {
+            Some(sequence) => authentication.0.sequence() >= sequence,
+            None => true,
+        }
+    }
+
+    /// Checks the authentication using the handler, unless an identical one has been verified
+    /// recently.
+    fn verify(
+        &mut self,
+        authentication: &Authentication<M>,
+        handler: &mut SessionHandler<M>,
+    ) -> bool {
+        if self.verified.get(authentication).is_some() {
+            trace!(target: "aleph-network", "Skipping verification of repeated authentication {:?}.", authentication);
+            return true;
+        }
+        if !handler.handle_authentication(authentication.clone()) {
+            return false;
+        }
+        self.verified.put(authentication.clone(), ());
+        true
+    }
+
     /// Checks the authentication using the handler and returns the addresses we should be
-    /// connected to if the authentication is correct.
+    /// connected to if the authentication is correct, not older than any seen before, and
+    /// contains addresses accepted by the policy.
+    ///
+    /// The authentication is only kept, and thus passed on, if the policy accepts all of its
+    /// addresses, since the signature does not allow dropping the rest.
     fn handle_authentication(
         &mut self,
         authentication: Authentication<M>,
         handler: &mut SessionHandler<M>,
     ) -> Vec<M> {
-        if !handler.handle_authentication(authentication.clone()) {
+        if !self.is_fresh(&authentication) {
+            trace!(target: "aleph-network", "Ignoring stale authentication {:?}.", authentication);
+            return Vec::new();
+        }
+        let auth_data = &authentication.0;
+        if auth_data.exceeds_address_limit(&self.address_policy) {
+            warn!(target: "aleph-network", "Rejecting authentication from node {:?} in session {:?}: it carries {} addresses, more than the allowed {}.", auth_data.creator(), auth_data.session(), auth_data.addresses().len(), self.address_policy.max_addresses);
+            return Vec::new();
+        }
+        let addresses = auth_data.validate_addresses(&self.address_policy);
+        if addresses.is_empty() {
+            warn!(target: "aleph-network", "Rejecting authentication from node {:?} in session {:?}: none of the addresses {:?} are acceptable.", auth_data.creator(), auth_data.session(), auth_data.addresses());
+            return Vec::new();
+        }
+        if !self.verify(&authentication, handler) {
             return Vec::new();
         }
-        authentication.0.addresses()
+        let auth_data = &authentication.0;
+        match addresses.len() == auth_data.addresses().len() {
+            true => {
+                self.known
+                    .insert((auth_data.creator(), auth_data.session()), authentication);
+            }
+            false => {
+                debug!(target: "aleph-network", "Not keeping authentication from node {:?} in session {:?}, some of the addresses {:?} are not acceptable.", auth_data.creator(), auth_data.session(), auth_data.addresses())
+            }
+        }
+        addresses
     }
 
-    fn should_rebroadcast(&self, node_id: &NodeIndex) -> bool {
-        match self.last_broadcast.get(node_id) {
-            Some(instant) => Instant::now() > *instant + self.cooldown,
-            None => true,
+    /// Whether exactly this authentication is the one we keep for its creator.
+    fn is_kept(&self, authentication: &Authentication<M>) -> bool {
+        let auth_data = &authentication.0;
+        self.known.get(&(auth_data.creator(), auth_data.session())) == Some(authentication)
+    }
+
+    fn respond(
+        &self,
+        authentication: &Authentication<M>,
+        handler: &SessionHandler<M>,
+    ) -> Vec<DiscoveryCommand<M>> {
+        match handler.peer_id(&authentication.0.creator()) {
+            Some(peer_id) => handler
+                .authentication()
+                .map(|handler_authentication| response(handler_authentication, peer_id))
+                .into_iter()
+                .collect(),
+            None => {
+                warn!(target: "aleph-network", "Id of correctly authenticated peer not present.");
+                Vec::new()
+            }
         }
     }
 
@@ -120,26 +372,45 @@ impl<M: Multiaddress> Discovery<M> {
         if addresses.is_empty() {
             return (Vec::new(), Vec::new());
         }
-        let node_id = authentication.0.creator();
-        let mut messages = Vec::new();
-        match handler.peer_id(&node_id) {
-            Some(peer_id) => {
-                if let Some(handler_authentication) = handler.authentication() {
-                    messages.push(response(handler_authentication, peer_id));
-                }
-            }
-            None => {
-                warn!(target: "aleph-network", "Id of correctly authenticated peer not present.")
-            }
+        let mut messages = self.respond(&authentication, handler);
+        if !self.is_kept(&authentication) {
+            return (addresses, messages);
         }
-        if self.should_rebroadcast(&node_id) {
+        let auth_data = &authentication.0;
+        if self.rebroadcast_limiter.allow(
+            (auth_data.creator(), auth_data.session()),
+            auth_data.sequence(),
+            Instant::now(),
+        ) {
             trace!(target: "aleph-network", "Rebroadcasting {:?}.", authentication);
-            self.last_broadcast.insert(node_id, Instant::now());
             messages.push(authentication_broadcast(authentication));
         }
         (addresses, messages)
     }
 
+    /// A snapshot of the freshest authentications we know, ordered by session and creator.
+    pub fn state(&self) -> DiscoveryState<M> {
+        let mut authentications: Vec<_> = self.known.values().cloned().collect();
+        authentications
+            .sort_by_key(|(auth_data, _)| (auth_data.session().0, auth_data.creator().0));
+        DiscoveryState { authentications }
+    }
+
+    /// Restores the authentications from the snapshot as if they just arrived, so they get
+    /// verified by the handler again and the ones staler than what we already know are ignored.
+    /// Returns the addresses we should be connected to because of them.
+    pub fn load_state(
+        &mut self,
+        state: DiscoveryState<M>,
+        handler: &mut SessionHandler<M>,
+    ) -> Vec<M> {
+        state
+            .authentications
+            .into_iter()
+            .flat_map(|authentication| self.handle_authentication(authentication, handler))
+            .collect()
+    }
+
     /// Analyzes the provided message and returns all the new multiaddresses we should
     /// be connected to if we want to stay connected to the committee and any messages
     /// that we should send as a result of it.
@@ -163,18 +434,24 @@ impl<M: Multiaddress> Discovery<M> {
 
 #[cfg(test)]
 mod tests {
-    use std::{thread::sleep, time::Duration};
+    use std::{
+        thread::sleep,
+        time::{Duration, Instant},
+    };
 
-    use codec::Encode;
+    use codec::{Decode, Encode};
 
-    use super::{Discovery, DiscoveryMessage};
+    use super::{
+        AnnouncementSchedule, Discovery, DiscoveryCommand, DiscoveryMessage, DiscoveryState,
+        RebroadcastLimiter,
+    };
     use crate::{
         network::{
-            manager::SessionHandler,
-            mock::{crypto_basics, MockMultiaddress, MockPeerId},
-            DataCommand,
+            manager::{Authentication, SessionHandler},
+            mock::{crypto_basics, MockMultiaddress, MockNetworkIdentity, MockPeerId},
+            AddressPolicy, DataCommand, NetworkIdentity,
         },
-        SessionId,
+        NodeIndex, SessionId,
     };
 
     const NUM_NODES: u8 = 7;
@@ -216,7 +493,7 @@ mod tests {
         .await
         .unwrap();
         (
-            Discovery::new(Duration::from_millis(MS_COOLDOWN)),
+            Discovery::new(Duration::from_millis(MS_COOLDOWN), AddressPolicy::default()),
             handlers,
             non_validator,
         )
@@ -230,6 +507,50 @@ mod tests {
         build_number(NUM_NODES).await
     }
 
+    /// Returns a discovery, a handler of the first node, and two authentications of the second
+    /// node, the latter being fresher than the former.
+    async fn build_with_stale_and_fresh() -> (
+        Discovery<MockMultiaddress>,
+        SessionHandler<MockMultiaddress>,
+        Authentication<MockMultiaddress>,
+        Authentication<MockMultiaddress>,
+    ) {
+        let crypto_basics = crypto_basics(NUM_NODES.into()).await;
+        let handler = SessionHandler::new(
+            Some(crypto_basics.0[0].clone()),
+            crypto_basics.1.clone(),
+            SessionId(43),
+            MockNetworkIdentity::new().identity().0,
+        )
+        .await
+        .unwrap();
+        let addresses = MockNetworkIdentity::new().identity().0;
+        let mut other_handler = SessionHandler::new(
+            Some(crypto_basics.0[1].clone()),
+            crypto_basics.1.clone(),
+            SessionId(43),
+            addresses.clone(),
+        )
+        .await
+        .unwrap();
+        let stale = other_handler.authentication().unwrap();
+        other_handler
+            .update(
+                Some(crypto_basics.0[1].clone()),
+                crypto_basics.1.clone(),
+                addresses,
+            )
+            .await
+            .unwrap();
+        let fresh = other_handler.authentication().unwrap();
+        (
+            Discovery::new(Duration::from_millis(MS_COOLDOWN), AddressPolicy::default()),
+            handler,
+            stale,
+            fresh,
+        )
+    }
+
     #[tokio::test]
     async fn broadcasts_when_clueless() {
         for num_nodes in 2..NUM_NODES {
@@ -248,6 +569,73 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn announces_on_schedule() {
+        let (discovery, handlers, _) = build().await;
+        let handler = &handlers[0];
+        let interval = Duration::from_secs(10);
+        let start = Instant::now();
+        let mut discovery = discovery.with_announcements(
+            AnnouncementSchedule {
+                interval,
+                jitter: 0.0,
+            },
+            start,
+        );
+        let broadcast = (
+            DiscoveryMessage::AuthenticationBroadcast(handler.authentication().unwrap()),
+            DataCommand::Broadcast,
+        );
+        for i in 1..4 {
+            let due = start + interval * i;
+            assert_eq!(discovery.next_announcement(), Some(due));
+            assert!(discovery
+                .scheduled_announcements(handler, due - Duration::from_millis(1))
+                .is_empty());
+            assert_eq!(
+                discovery.scheduled_announcements(handler, due),
+                vec![broadcast.clone()]
+            );
+            assert!(discovery.scheduled_announcements(handler, due).is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn jitters_announcements_within_bounds() {
+        let (discovery, handlers, _) = build().await;
+        let handler = &handlers[0];
+        let start = Instant::now();
+        let mut discovery = discovery.with_announcements(
+            AnnouncementSchedule {
+                interval: Duration::from_secs(10),
+                jitter: 0.5,
+            },
+            start,
+        );
+        let mut last = start;
+        for _ in 0..10 {
+            let next = discovery.next_announcement().unwrap();
+            let interval = next.duration_since(last);
+            assert!(
+                (Duration::from_secs(5)..=Duration::from_secs(15)).contains(&interval),
+                "interval {:?} out of bounds",
+                interval
+            );
+            assert_eq!(discovery.scheduled_announcements(handler, next).len(), 1);
+            last = next;
+        }
+    }
+
+    #[tokio::test]
+    async fn does_not_announce_without_schedule() {
+        let (mut discovery, handlers, _) = build().await;
+        assert_eq!(discovery.next_announcement(), None);
+        let far_future = Instant::now() + Duration::from_secs(3600);
+        assert!(discovery
+            .scheduled_announcements(&handlers[0], far_future)
+            .is_empty());
+    }
+
     #[tokio::test]
     async fn non_validator_discover_authorities_returns_empty_vector() {
         let (mut discovery, _, non_validator) = build().await;
@@ -276,6 +664,45 @@ mod tests {
             ) if *authentication == handler.authentication().unwrap())));
     }
 
+    #[tokio::test]
+    async fn restores_state_into_fresh_discovery() {
+        let (mut discovery, mut handlers, mut non_validator) = build().await;
+        let authentications: Vec<_> = handlers[1..]
+            .iter()
+            .map(|handler| handler.authentication().unwrap())
+            .collect();
+        let handler = &mut handlers[0];
+        let mut expected_addresses = Vec::new();
+        for authentication in authentications.iter().rev() {
+            let (addresses, _) = discovery.handle_message(
+                DiscoveryMessage::AuthenticationBroadcast(authentication.clone()),
+                handler,
+            );
+            expected_addresses.extend(addresses);
+        }
+        let state = discovery.state();
+        assert_eq!(
+            state,
+            DiscoveryState {
+                authentications: authentications.clone()
+            }
+        );
+        let decoded = DiscoveryState::decode(&mut &state.encode()[..]).expect("should decode");
+        assert_eq!(decoded, state);
+
+        let mut restored =
+            Discovery::new(Duration::from_millis(MS_COOLDOWN), AddressPolicy::default());
+        let mut restored_addresses = restored.load_state(decoded, &mut non_validator);
+        assert_eq!(restored.state(), state);
+        restored_addresses.sort_by_key(|address| address.encode());
+        expected_addresses.sort_by_key(|address| address.encode());
+        assert_eq!(restored_addresses, expected_addresses);
+        for authentication in authentications {
+            let node_id = authentication.0.creator();
+            assert!(non_validator.peer_id(&node_id).is_some());
+        }
+    }
+
     #[tokio::test]
     async fn non_validators_rebroadcasts_responds() {
         let (mut discovery, handlers, mut non_validator) = build().await;
@@ -307,6 +734,77 @@ mod tests {
         assert!(commands.is_empty());
     }
 
+    fn rebroadcasts(
+        commands: &[DiscoveryCommand<MockMultiaddress>],
+        authentication: &Authentication<MockMultiaddress>,
+    ) -> usize {
+        commands
+            .iter()
+            .filter(|command| {
+                matches!(command, (
+                DiscoveryMessage::AuthenticationBroadcast(rebroadcast_authentication),
+                DataCommand::Broadcast,
+            ) if rebroadcast_authentication == authentication)
+            })
+            .count()
+    }
+
+    #[tokio::test]
+    async fn rebroadcasts_fresher_authentication_immediately() {
+        let (mut discovery, mut handler, stale, fresh) = build_with_stale_and_fresh().await;
+        let (_, commands) = discovery.handle_message(
+            DiscoveryMessage::AuthenticationBroadcast(stale.clone()),
+            &mut handler,
+        );
+        assert_eq!(rebroadcasts(&commands, &stale), 1);
+        let (addresses, commands) = discovery.handle_message(
+            DiscoveryMessage::AuthenticationBroadcast(fresh.clone()),
+            &mut handler,
+        );
+        assert_eq!(addresses.len(), fresh.0.addresses().len());
+        assert_eq!(addresses[0].encode(), fresh.0.addresses()[0].encode());
+        assert_eq!(commands.len(), 2);
+        assert_eq!(rebroadcasts(&commands, &fresh), 1);
+        assert!(commands.iter().any(|command| matches!(command, (
+                DiscoveryMessage::Authentication(authentication),
+                DataCommand::SendTo(_, _),
+            ) if *authentication == handler.authentication().unwrap())));
+    }
+
+    #[tokio::test]
+    async fn coalesces_repeated_broadcasts() {
+        let (mut discovery, mut handlers, _) = build().await;
+        let authentication = handlers[1].authentication().unwrap();
+        let handler = &mut handlers[0];
+        let mut all_commands = Vec::new();
+        for _ in 0..50 {
+            let (_, commands) = discovery.handle_message(
+                DiscoveryMessage::AuthenticationBroadcast(authentication.clone()),
+                handler,
+            );
+            all_commands.extend(commands);
+        }
+        assert_eq!(rebroadcasts(&all_commands, &authentication), 1);
+    }
+
+    #[tokio::test]
+    async fn limits_rebroadcasts_per_authority() {
+        let (mut discovery, mut handlers, _) = build().await;
+        let first = handlers[1].authentication().unwrap();
+        let second = handlers[2].authentication().unwrap();
+        let handler = &mut handlers[0];
+        let mut all_commands = Vec::new();
+        for authentication in [&first, &second, &first, &second] {
+            let (_, commands) = discovery.handle_message(
+                DiscoveryMessage::AuthenticationBroadcast(authentication.clone()),
+                handler,
+            );
+            all_commands.extend(commands);
+        }
+        assert_eq!(rebroadcasts(&all_commands, &first), 1);
+        assert_eq!(rebroadcasts(&all_commands, &second), 1);
+    }
+
     #[tokio::test]
     async fn does_not_rebroadcast_quickly_but_still_responds() {
         let (mut discovery, mut handlers, _) = build().await;
@@ -353,6 +851,144 @@ mod tests {
             ) if rebroadcast_authentication == &authentication)));
     }
 
+    #[tokio::test]
+    async fn forgets_departed_authorities() {
+        let (mut discovery, mut handlers, _) = build().await;
+        let authentication = handlers[1].authentication().unwrap();
+        discovery.handle_message(
+            DiscoveryMessage::AuthenticationBroadcast(authentication.clone()),
+            &mut handlers[0],
+        );
+        discovery.forget_departed(&handlers[0]);
+        assert_eq!(discovery.state().authentications, vec![authentication]);
+        assert_eq!(discovery.rebroadcast_limiter.buckets.len(), 1);
+        // this handler knows no peers, as if they all left
+        let (_, unaware_handler, _, _) = build_with_stale_and_fresh().await;
+        discovery.forget_departed(&unaware_handler);
+        assert!(discovery.state().authentications.is_empty());
+        assert!(discovery.rebroadcast_limiter.buckets.is_empty());
+    }
+
+    #[tokio::test]
+    async fn verifies_repeated_authentication_once() {
+        let (mut discovery, mut handlers, _) = build().await;
+        let authentication = handlers[1].authentication().unwrap();
+        discovery.handle_message(
+            DiscoveryMessage::AuthenticationBroadcast(authentication.clone()),
+            &mut handlers[0],
+        );
+        // this handler knows different keys, so it would reject the authentication if asked
+        let (_, mut rejecting_handler, _, _) = build_with_stale_and_fresh().await;
+        sleep(Duration::from_millis(MS_COOLDOWN + 5));
+        let (_, commands) = discovery.handle_message(
+            DiscoveryMessage::AuthenticationBroadcast(authentication.clone()),
+            &mut rejecting_handler,
+        );
+        assert_eq!(rebroadcasts(&commands, &authentication), 1);
+        discovery.forget_verified();
+        sleep(Duration::from_millis(MS_COOLDOWN + 5));
+        let (_, commands) = discovery.handle_message(
+            DiscoveryMessage::AuthenticationBroadcast(authentication),
+            &mut rejecting_handler,
+        );
+        assert!(commands.is_empty());
+    }
+
+    #[tokio::test]
+    async fn verifies_modified_authentication_again() {
+        let (mut discovery, mut handlers, _) = build().await;
+        let authentication = handlers[1].authentication().unwrap();
+        let (_, signature) = handlers[2].authentication().unwrap();
+        let modified_authentication = (authentication.0.clone(), signature);
+        let handler = &mut handlers[0];
+        discovery.handle_message(
+            DiscoveryMessage::AuthenticationBroadcast(authentication),
+            handler,
+        );
+        sleep(Duration::from_millis(MS_COOLDOWN + 5));
+        let (addresses, commands) = discovery.handle_message(
+            DiscoveryMessage::AuthenticationBroadcast(modified_authentication),
+            handler,
+        );
+        assert!(addresses.is_empty());
+        assert!(commands.is_empty());
+    }
+
+    #[tokio::test]
+    async fn verifies_every_repeat_without_dedup() {
+        let (discovery, mut handlers, _) = build().await;
+        let mut discovery = discovery.with_dedup_capacity(0);
+        let authentication = handlers[1].authentication().unwrap();
+        discovery.handle_message(
+            DiscoveryMessage::AuthenticationBroadcast(authentication.clone()),
+            &mut handlers[0],
+        );
+        let (_, mut rejecting_handler, _, _) = build_with_stale_and_fresh().await;
+        sleep(Duration::from_millis(MS_COOLDOWN + 5));
+        let (_, commands) = discovery.handle_message(
+            DiscoveryMessage::AuthenticationBroadcast(authentication),
+            &mut rejecting_handler,
+        );
+        assert!(commands.is_empty());
+    }
+
+    #[test]
+    fn limiter_refills_one_token_per_interval() {
+        let interval = Duration::from_secs(10);
+        let mut limiter = RebroadcastLimiter::new(interval);
+        let key = (NodeIndex(1), SessionId(43));
+        let now = Instant::now();
+        assert!(limiter.allow(key, 7, now));
+        assert!(!limiter.allow(key, 7, now + interval / 2));
+        assert!(!limiter.allow(key, 7, now + interval - Duration::from_millis(1)));
+        assert!(limiter.allow(key, 7, now + interval));
+        assert!(!limiter.allow(key, 7, now + interval));
+        // tokens do not accumulate above one
+        assert!(limiter.allow(key, 7, now + interval * 10));
+        assert!(!limiter.allow(key, 7, now + interval * 10));
+        // other sessions are limited separately
+        assert!(limiter.allow((NodeIndex(1), SessionId(44)), 7, now + interval * 10));
+    }
+
+    #[test]
+    fn limiter_lets_fresher_sequence_through() {
+        let mut limiter = RebroadcastLimiter::new(Duration::from_secs(10));
+        let key = (NodeIndex(1), SessionId(43));
+        let now = Instant::now();
+        assert!(limiter.allow(key, 7, now));
+        assert!(limiter.allow(key, 8, now));
+        assert!(!limiter.allow(key, 8, now));
+        assert!(!limiter.allow(key, 7, now));
+        assert!(limiter.allow(key, 9, now));
+    }
+
+    #[tokio::test]
+    async fn ignores_stale_authentication_delivered_out_of_order() {
+        let (mut discovery, mut handler, stale, fresh) = build_with_stale_and_fresh().await;
+        let (addresses, _) =
+            discovery.handle_message(DiscoveryMessage::Authentication(fresh), &mut handler);
+        assert!(!addresses.is_empty());
+        let (addresses, commands) = discovery.handle_message(
+            DiscoveryMessage::AuthenticationBroadcast(stale),
+            &mut handler,
+        );
+        assert!(addresses.is_empty());
+        assert!(commands.is_empty());
+    }
+
+    #[tokio::test]
+    async fn accepts_fresh_authentication_delivered_in_order() {
+        let (mut discovery, mut handler, stale, fresh) = build_with_stale_and_fresh().await;
+        let (addresses, _) =
+            discovery.handle_message(DiscoveryMessage::Authentication(stale), &mut handler);
+        assert!(!addresses.is_empty());
+        let (addresses, _) = discovery.handle_message(
+            DiscoveryMessage::Authentication(fresh.clone()),
+            &mut handler,
+        );
+        assert_eq!(addresses, fresh.0.addresses());
+    }
+
     #[tokio::test]
     async fn accepts_correct_authentications() {
         let (mut discovery, mut handlers, _) = build().await;
@@ -381,4 +1017,101 @@ mod tests {
         assert!(addresses.is_empty());
         assert!(commands.is_empty());
     }
+
+    async fn authentication_with_addresses(
+        addresses: Vec<MockMultiaddress>,
+    ) -> (
+        Discovery<MockMultiaddress>,
+        SessionHandler<MockMultiaddress>,
+        Authentication<MockMultiaddress>,
+    ) {
+        let crypto_basics = crypto_basics(NUM_NODES.into()).await;
+        let handler = SessionHandler::new(
+            Some(crypto_basics.0[0].clone()),
+            crypto_basics.1.clone(),
+            SessionId(43),
+            MockNetworkIdentity::new().identity().0,
+        )
+        .await
+        .unwrap();
+        let authentication = SessionHandler::new(
+            Some(crypto_basics.0[1].clone()),
+            crypto_basics.1.clone(),
+            SessionId(43),
+            addresses,
+        )
+        .await
+        .unwrap()
+        .authentication()
+        .unwrap();
+        (
+            Discovery::new(Duration::from_millis(MS_COOLDOWN), AddressPolicy::default()),
+            handler,
+            authentication,
+        )
+    }
+
+    #[tokio::test]
+    async fn accepts_only_routable_addresses() {
+        let peer_id = MockPeerId::random();
+        let routable = MockMultiaddress::random_with_id(peer_id);
+        let addresses = vec![
+            MockMultiaddress::unroutable_with_id(peer_id),
+            routable.clone(),
+            MockMultiaddress::unroutable_with_id(peer_id),
+        ];
+        let (mut discovery, mut handler, authentication) =
+            authentication_with_addresses(addresses).await;
+        let (addresses, commands) = discovery.handle_message(
+            DiscoveryMessage::AuthenticationBroadcast(authentication.clone()),
+            &mut handler,
+        );
+        assert_eq!(addresses, vec![routable]);
+        // only responded to, the unroutable addresses are not passed on
+        assert_eq!(commands.len(), 1);
+        assert_eq!(rebroadcasts(&commands, &authentication), 0);
+        assert!(discovery.state().authentications.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_authentication_above_address_limit() {
+        let peer_id = MockPeerId::random();
+        let addresses: Vec<_> = (0..20)
+            .map(|_| MockMultiaddress::random_with_id(peer_id))
+            .collect();
+        let (discovery, mut handler, authentication) =
+            authentication_with_addresses(addresses).await;
+        let mut discovery = Discovery {
+            address_policy: AddressPolicy {
+                max_addresses: 4,
+                ..AddressPolicy::default()
+            },
+            ..discovery
+        };
+        let (accepted, commands) = discovery.handle_message(
+            DiscoveryMessage::AuthenticationBroadcast(authentication),
+            &mut handler,
+        );
+        assert!(accepted.is_empty());
+        assert!(commands.is_empty());
+        assert!(discovery.state().authentications.is_empty());
+        assert!(handler.peer_id(&NodeIndex(1)).is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_authentication_without_routable_addresses() {
+        let peer_id = MockPeerId::random();
+        let addresses = vec![
+            MockMultiaddress::unroutable_with_id(peer_id),
+            MockMultiaddress::unroutable_with_id(peer_id),
+        ];
+        let (mut discovery, mut handler, authentication) =
+            authentication_with_addresses(addresses).await;
+        let (addresses, commands) = discovery.handle_message(
+            DiscoveryMessage::AuthenticationBroadcast(authentication),
+            &mut handler,
+        );
+        assert!(addresses.is_empty());
+        assert!(commands.is_empty());
+    }
 }
@@ -0,0 +1,157 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::SessionId;
+
+/// How many inbound messages of a single session can be processed in a row, if messages of other
+/// sessions are waiting. All the sessions get the same share.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InboundShare {
+    pub quantum: usize,
+}
+
+impl Default for InboundShare {
+    /// Taking turns message by message, so that no session can delay the others by more than
+    /// a single message.
+    fn default() -> Self {
+        InboundShare { quantum: 1 }
+    }
+}
+
+impl InboundShare {
+    fn quantum(&self) -> usize {
+        // A zero quantum would never let anything through.
+        self.quantum.max(1)
+    }
+}
+
+/// A queue per session, drained round-robin, so that a flood of messages in one session does not
+/// starve the others.
+pub struct SessionQueues<T> {
+    queues: HashMap<SessionId, VecDeque<T>>,
+    /// Sessions with nonempty queues, in the order of their turns.
+    turns: VecDeque<SessionId>,
+    share: InboundShare,
+    taken: usize,
+}
+
+impl<T> SessionQueues<T> {
+    pub fn new(share: InboundShare) -> Self {
+        SessionQueues {
+            queues: HashMap::new(),
+            turns: VecDeque::new(),
+            share,
+            taken: 0,
+        }
+    }
+
+    pub fn push(&mut self, session_id: SessionId, item: T) {
+        let queue = self.queues.entry(session_id).or_default();
+        if queue.is_empty() {
+            self.turns.push_back(session_id);
+        }
+        queue.push_back(item);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.turns.is_empty()
+    }
+
+    /// Takes the next item of the session whose turn it is, passing the turn on once the session
+    /// used up its share or has nothing more waiting.
+    pub fn pop(&mut self) -> Option<(SessionId, T)> {
+        let session_id = *self.turns.front()?;
+        let queue = self
+            .queues
+            .get_mut(&session_id)
+            .expect("sessions with turns have queues");
+        let item = queue
+            .pop_front()
+            .expect("sessions with turns have nonempty queues");
+        self.taken += 1;
+        if queue.is_empty() {
+            self.queues.remove(&session_id);
+            self.turns.pop_front();
+            self.taken = 0;
+        } else if self.taken >= self.share.quantum() {
+            self.turns.rotate_left(1);
+            self.taken = 0;
+        }
+        Some((session_id, item))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InboundShare, SessionQueues};
+    use crate::SessionId;
+
+    fn drain(queues: &mut SessionQueues<u32>) -> Vec<(SessionId, u32)> {
+        std::iter::from_fn(|| queues.pop()).collect()
+    }
+
+    #[test]
+    fn keeps_order_within_session() {
+        let mut queues = SessionQueues::new(InboundShare::default());
+        for item in 0..20 {
+            queues.push(SessionId(43), item);
+        }
+        let drained: Vec<_> = drain(&mut queues)
+            .into_iter()
+            .map(|(_, item)| item)
+            .collect();
+        assert_eq!(drained, (0..20).collect::<Vec<_>>());
+        assert!(queues.is_empty());
+    }
+
+    #[test]
+    fn takes_turns_according_to_share() {
+        let mut queues = SessionQueues::new(InboundShare { quantum: 2 });
+        for item in 0..4 {
+            queues.push(SessionId(43), item);
+        }
+        for item in 10..13 {
+            queues.push(SessionId(44), item);
+        }
+        let drained: Vec<_> = drain(&mut queues)
+            .into_iter()
+            .map(|(_, item)| item)
+            .collect();
+        assert_eq!(drained, vec![0, 1, 10, 11, 2, 3, 12]);
+    }
+
+    #[test]
+    fn trickle_progresses_during_flood() {
+        let share = InboundShare::default();
+        let mut queues = SessionQueues::new(share);
+        for item in 0..10_000 {
+            queues.push(SessionId(43), item);
+        }
+        for round in 0..100 {
+            // the trickle arrives while the flood is being processed
+            queues.push(SessionId(44), u32::MAX);
+            let position = std::iter::from_fn(|| queues.pop())
+                .position(|(session_id, _)| session_id == SessionId(44))
+                .expect("the trickle should get through");
+            assert!(
+                position <= share.quantum,
+                "round {}: waited {}",
+                round,
+                position
+            );
+        }
+        assert!(!queues.is_empty());
+    }
+
+    #[test]
+    fn zero_share_does_not_starve() {
+        let mut queues = SessionQueues::new(InboundShare { quantum: 0 });
+        queues.push(SessionId(43), 1);
+        queues.push(SessionId(44), 2);
+        queues.push(SessionId(43), 3);
+        let drained: Vec<_> = drain(&mut queues)
+            .into_iter()
+            .map(|(_, item)| item)
+            .collect();
+        assert_eq!(drained, vec![1, 2, 3]);
+    }
+}
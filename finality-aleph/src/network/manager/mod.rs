@@ -1,25 +1,37 @@
-use codec::{Decode, Encode};
+use std::fmt::{Display, Error as FmtError, Formatter};
+
+use codec::{Decode, Encode, EncodeLike, Error as CodecError, Input, Output};
+use sp_core::hashing::blake2_256;
 
 use crate::{
     crypto::Signature,
-    network::{Data, Multiaddress},
+    network::{AddressPolicy, Data, Multiaddress},
     NodeIndex, SessionId,
 };
 
 mod compatibility;
 mod connections;
 mod discovery;
+mod fair_queue;
+mod priority;
 mod service;
 mod session;
 
-pub use compatibility::VersionedAuthentication;
+pub use compatibility::{decode_authentication, VersionedAuthentication};
 use connections::Connections;
-pub use discovery::{Discovery, DiscoveryMessage};
+pub use discovery::{
+    AnnouncementSchedule, Discovery, DiscoveryMessage, DiscoveryState, DEFAULT_DEDUP_CAPACITY,
+};
+pub use fair_queue::{InboundShare, SessionQueues};
+pub use priority::{Priority, PriorityQueue, PriorityWeights};
 pub use service::{
-    Config as ConnectionManagerConfig, Service as ConnectionManager, SessionCommand,
-    IO as ConnectionIO,
+    Config as ConnectionManagerConfig, ConfigBuilder as ConnectionManagerConfigBuilder,
+    ConfigError as ConnectionManagerConfigError, IsConnected, Service as ConnectionManager,
+    SessionCommand, SessionPeers, IO as ConnectionIO,
+};
+pub use session::{
+    check_addresses, AuthError, Handler as SessionHandler, HandlerError as SessionHandlerError,
 };
-pub use session::{Handler as SessionHandler, HandlerError as SessionHandlerError};
 /// Data validators use to authenticate themselves for a single session
 /// and disseminate their addresses.
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Encode, Decode)]
@@ -27,6 +39,7 @@ pub struct AuthData<M: Multiaddress> {
     addresses: Vec<M>,
     node_id: NodeIndex,
     session_id: SessionId,
+    sequence: u64,
 }
 
 impl<M: Multiaddress> AuthData<M> {
@@ -41,23 +54,647 @@ impl<M: Multiaddress> AuthData<M> {
     pub fn addresses(&self) -> Vec<M> {
         self.addresses.clone()
     }
+
+    /// Whether there are more addresses than the policy allows.
+    pub fn exceeds_address_limit(&self, policy: &AddressPolicy) -> bool {
+        self.addresses.len() > policy.max_addresses
+    }
+
+    /// Returns the addresses accepted by the policy, none at all if there are more of them than
+    /// it allows. Only meaningful once the signature has been verified.
+    pub fn validate_addresses(&self, policy: &AddressPolicy) -> Vec<M> {
+        if self.exceeds_address_limit(policy) {
+            return Vec::new();
+        }
+        self.addresses
+            .iter()
+            .filter(|address| policy.accepts(*address))
+            .cloned()
+            .collect()
+    }
+
+    /// Grows with every new authentication created by the same node for the same session,
+    /// so that stale ones can be recognized.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Whether this came from a node still using authentications without sequence numbers.
+    pub fn is_legacy(&self) -> bool {
+        self.sequence == LEGACY_SEQUENCE
+    }
+
+    /// The form of this data older nodes know, without the sequence number.
+    pub fn to_legacy(&self) -> LegacyAuthData<M> {
+        LegacyAuthData {
+            addresses: self.addresses.clone(),
+            node_id: self.node_id,
+            session_id: self.session_id,
+        }
+    }
+
+    /// The bytes the signature is made over. Legacy data was signed without the sequence number.
+    pub fn signed_payload(&self) -> Vec<u8> {
+        match self.is_legacy() {
+            true => self.to_legacy().encode(),
+            false => self.encode(),
+        }
+    }
+}
+
+/// The sequence number assigned to authentications created before they contained one, lower
+/// than any sequence number we create, so that any new authentication supersedes them.
+pub const LEGACY_SEQUENCE: u64 = 0;
+
+/// Data validators used to authenticate themselves before sequence numbers were introduced.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Encode, Decode)]
+pub struct LegacyAuthData<M: Multiaddress> {
+    addresses: Vec<M>,
+    node_id: NodeIndex,
+    session_id: SessionId,
+}
+
+impl<M: Multiaddress> From<LegacyAuthData<M>> for AuthData<M> {
+    fn from(legacy: LegacyAuthData<M>) -> Self {
+        let LegacyAuthData {
+            addresses,
+            node_id,
+            session_id,
+        } = legacy;
+        AuthData {
+            addresses,
+            node_id,
+            session_id,
+            sequence: LEGACY_SEQUENCE,
+        }
+    }
 }
 
 /// A full authentication, consisting of a signed AuthData.
 pub type Authentication<M> = (AuthData<M>, Signature);
 
+/// A channel within a session, keeping different kinds of traffic apart.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Encode, Decode)]
+pub enum Channel {
+    /// The channel of all the data not explicitly sent through another one.
+    #[default]
+    Main,
+    /// Intended for requests, e.g. for missing units, so that they do not wait behind the units.
+    Requests,
+}
+
+/// Makes the checksums of the data of a session differ from those of other sessions and chains.
+/// It is derived from public data, so the checksums only catch data ending up in the wrong session
+/// by mistake, they do not authenticate anything. The connections are what authenticates peers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChecksumKey([u8; 32]);
+
+impl ChecksumKey {
+    /// The key of the session derived from the seed, e.g. the genesis hash of the chain.
+    pub fn new(seed: &[u8], session_id: SessionId) -> Self {
+        ChecksumKey(blake2_256(&(seed, session_id).encode()))
+    }
+}
+
+/// A checksum of data within a session, catching data delivered to the wrong session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Encode, Decode)]
+pub struct SessionChecksum([u8; 32]);
+
 /// Data inside session, sent to validator network.
-pub type DataInSession<D> = (D, SessionId);
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DataInSession<D: Data> {
+    pub data: D,
+    pub session_id: SessionId,
+    pub channel: Channel,
+    /// Only present if the peers agreed on checksums.
+    pub checksum: Option<SessionChecksum>,
+}
+
+impl<D: Data> DataInSession<D> {
+    /// Data in the main channel of the session.
+    pub fn new(data: D, session_id: SessionId) -> Self {
+        DataInSession {
+            data,
+            session_id,
+            channel: Channel::Main,
+            checksum: None,
+        }
+    }
+
+    fn compute_checksum(&self, key: &ChecksumKey) -> SessionChecksum {
+        SessionChecksum(blake2_256(
+            &(key.0, &self.data, self.session_id, self.channel).encode(),
+        ))
+    }
+
+    /// The same data, with a checksum computed using the key.
+    pub fn with_checksum(mut self, key: &ChecksumKey) -> Self {
+        self.checksum = Some(self.compute_checksum(key));
+        self
+    }
+
+    /// Whether the data carries a checksum computed using the key. Data without any checksum
+    /// never matches.
+    pub fn checksum_matches(&self, key: &ChecksumKey) -> bool {
+        self.checksum == Some(self.compute_checksum(key))
+    }
+}
+
+impl<D: Data> Encode for DataInSession<D> {
+    fn size_hint(&self) -> usize {
+        let channel_size = match (self.channel, self.checksum) {
+            (Channel::Main, None) => 0,
+            (channel, _) => channel.size_hint(),
+        };
+        let checksum_size = self.checksum.map_or(0, |checksum| checksum.size_hint());
+        self.data.size_hint() + self.session_id.size_hint() + channel_size + checksum_size
+    }
+
+    /// The main channel is not encoded at all, so that its data is encoded the same way as before
+    /// channels were introduced. The checksum, if any, comes after the channel, which then has to be
+    /// encoded explicitly.
+    fn encode_to<T: Output + ?Sized>(&self, dest: &mut T) {
+        self.data.encode_to(dest);
+        self.session_id.encode_to(dest);
+        if self.channel != Channel::Main || self.checksum.is_some() {
+            self.channel.encode_to(dest);
+        }
+        if let Some(checksum) = self.checksum {
+            checksum.encode_to(dest);
+        }
+    }
+}
+
+impl<D: Data> EncodeLike for DataInSession<D> {}
+
+impl<D: Data> Decode for DataInSession<D> {
+    /// The channel and the checksum are optional, so this has to be decoded on its own, rather than
+    /// as a part of a bigger structure.
+    fn decode<I: Input>(input: &mut I) -> Result<Self, CodecError> {
+        let data = D::decode(input)?;
+        let session_id = SessionId::decode(input)?;
+        let channel = match input.read_byte() {
+            Ok(tag) => Channel::decode(&mut [tag].as_slice())?,
+            Err(_) => return Ok(DataInSession::new(data, session_id)),
+        };
+        let checksum = match input.read_byte() {
+            Ok(first) => {
+                let mut checksum = [first; 32];
+                input.read(&mut checksum[1..])?;
+                Some(SessionChecksum(checksum))
+            }
+            Err(_) => None,
+        };
+        Ok(DataInSession {
+            data,
+            session_id,
+            channel,
+            checksum,
+        })
+    }
+}
 
 impl<D: Data, M: Multiaddress> From<DataInSession<D>> for NetworkData<D, M> {
     fn from(data: DataInSession<D>) -> Self {
-        NetworkData::Data(data.0, data.1)
+        NetworkData::Data(data.data, data.session_id, data.channel)
     }
 }
 
-/// The data that should be sent to the network service.
+/// Signed payloads of control messages start with this, so that they never match the payload of
+/// an authentication, or of anything else signed with the same keys.
+const LEAVING_CONTEXT: &[u8] = b"aleph-leaving-session";
+
+/// The node with the index stops taking part in the session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Encode, Decode)]
+pub struct Leaving {
+    pub session_id: SessionId,
+    pub node_id: NodeIndex,
+}
+
+impl Leaving {
+    /// The bytes the signature is made over.
+    pub fn signed_payload(&self) -> Vec<u8> {
+        (LEAVING_CONTEXT, self).encode()
+    }
+}
+
+/// Coordination between the connection managers of the peers, outside of discovery.
 #[derive(Clone, Debug, PartialEq, Eq, Encode, Decode)]
+pub enum ControlMessage {
+    /// The node stops taking part in the session, so there is no point in routing any more data
+    /// of the session to it. Signed by the node, so that nobody else can cut it off.
+    LeavingSession(Leaving, Signature),
+}
+
+impl ControlMessage {
+    pub fn session_id(&self) -> SessionId {
+        use ControlMessage::*;
+        match self {
+            LeavingSession(leaving, _) => leaving.session_id,
+        }
+    }
+}
+
+/// The data that should be sent to the network service.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum NetworkData<D: Data, M: Multiaddress> {
     Meta(DiscoveryMessage<M>),
-    Data(D, SessionId),
+    Data(D, SessionId, Channel),
+    Control(ControlMessage),
+    /// A variant introduced in a version we do not know, with its tag and the remaining bytes.
+    /// Only ever created when decoding, so that such messages can be ignored, rather than
+    /// failing to decode.
+    Unknown(u8, Vec<u8>),
+}
+
+const META_TAG: u8 = 0;
+const DATA_TAG: u8 = 1;
+/// Data outside of the main channel uses a separate tag, so that older versions ignore it as an
+/// unknown variant, instead of mistaking it for main channel data.
+const CHANNEL_DATA_TAG: u8 = 2;
+const CONTROL_TAG: u8 = 3;
+
+impl<D: Data, M: Multiaddress> Encode for NetworkData<D, M> {
+    fn size_hint(&self) -> usize {
+        self.encoded_size()
+    }
+
+    fn encode_to<T: Output + ?Sized>(&self, dest: &mut T) {
+        use NetworkData::*;
+        match self {
+            Meta(message) => {
+                dest.push_byte(META_TAG);
+                message.encode_to(dest);
+            }
+            Data(data, session_id, Channel::Main) => {
+                dest.push_byte(DATA_TAG);
+                data.encode_to(dest);
+                session_id.encode_to(dest);
+            }
+            Data(data, session_id, channel) => {
+                dest.push_byte(CHANNEL_DATA_TAG);
+                channel.encode_to(dest);
+                data.encode_to(dest);
+                session_id.encode_to(dest);
+            }
+            Control(message) => {
+                dest.push_byte(CONTROL_TAG);
+                message.encode_to(dest);
+            }
+            Unknown(tag, payload) => {
+                dest.push_byte(*tag);
+                dest.write(payload);
+            }
+        }
+    }
+}
+
+impl<D: Data, M: Multiaddress> EncodeLike for NetworkData<D, M> {}
+
+/// Reads everything that is left in the input.
+fn read_remaining<I: Input>(input: &mut I) -> Result<Vec<u8>, CodecError> {
+    match input.remaining_len()? {
+        Some(len) => {
+            let mut result = vec![0; len];
+            input.read(&mut result)?;
+            Ok(result)
+        }
+        None => {
+            let mut result = Vec::new();
+            while let Ok(byte) = input.read_byte() {
+                result.push(byte);
+            }
+            Ok(result)
+        }
+    }
+}
+
+impl<D: Data, M: Multiaddress> Decode for NetworkData<D, M> {
+    /// Unknown variants consume the whole remaining input, so this has to be decoded on its own,
+    /// rather than as a part of a bigger structure.
+    fn decode<I: Input>(input: &mut I) -> Result<Self, CodecError> {
+        use NetworkData::*;
+        match input.read_byte()? {
+            META_TAG => Ok(Meta(DiscoveryMessage::decode(input)?)),
+            DATA_TAG => Ok(Data(
+                D::decode(input)?,
+                SessionId::decode(input)?,
+                Channel::Main,
+            )),
+            CHANNEL_DATA_TAG => {
+                let channel = Channel::decode(input)?;
+                Ok(Data(D::decode(input)?, SessionId::decode(input)?, channel))
+            }
+            CONTROL_TAG => Ok(Control(ControlMessage::decode(input)?)),
+            tag => Ok(Unknown(tag, read_remaining(input)?)),
+        }
+    }
+}
+
+/// Why raw bytes are not valid network data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The bytes do not encode network data at all.
+    Malformed,
+    /// The network data was followed by this many more bytes.
+    TrailingBytes(usize),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        use DecodeError::*;
+        match self {
+            Malformed => write!(f, "malformed network data"),
+            TrailingBytes(count) => write!(f, "{} bytes after the network data", count),
+        }
+    }
+}
+
+/// Decodes network data from exactly the given bytes. Never panics, whatever the input, so it
+/// can be fuzzed on its own.
+pub fn decode_network_data<D: Data, M: Multiaddress>(
+    bytes: &[u8],
+) -> Result<NetworkData<D, M>, DecodeError> {
+    let mut input = bytes;
+    let data = NetworkData::decode(&mut input).map_err(|_| DecodeError::Malformed)?;
+    match input.len() {
+        0 => Ok(data),
+        count => Err(DecodeError::TrailingBytes(count)),
+    }
+}
+
+fn hint_or_encoded_size<T: Encode>(item: &T) -> usize {
+    // The default size hint is zero, in which case we have no choice but to encode.
+    match item.size_hint() {
+        0 => item.encoded_size(),
+        hint => hint,
+    }
+}
+
+impl<D: Data, M: Multiaddress> NetworkData<D, M> {
+    /// An estimate of the length of the encoding, computed without encoding whenever the
+    /// contents provide size hints.
+    pub fn encoded_size(&self) -> usize {
+        use NetworkData::*;
+        // One byte for the variant index.
+        1 + match self {
+            Meta(message) => hint_or_encoded_size(message),
+            Data(data, session_id, Channel::Main) => {
+                hint_or_encoded_size(data) + session_id.size_hint()
+            }
+            Data(data, session_id, channel) => {
+                channel.size_hint() + hint_or_encoded_size(data) + session_id.size_hint()
+            }
+            Control(message) => hint_or_encoded_size(message),
+            Unknown(_, payload) => payload.len(),
+        }
+    }
+
+    /// Discovery and control should not wait behind session data, otherwise a busy node could
+    /// become unreachable.
+    pub fn priority(&self) -> Priority {
+        use NetworkData::*;
+        match self {
+            Meta(_) | Control(_) => Priority::High,
+            Data(_, _, _) | Unknown(_, _) => Priority::Normal,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use codec::{Decode, Encode};
+    use rand::{thread_rng, Rng};
+
+    use super::{
+        decode_network_data, Channel, ChecksumKey, ControlMessage, DataInSession, DecodeError,
+        DiscoveryMessage, Leaving, NetworkData,
+    };
+    use crate::{
+        network::{
+            manager::SessionHandler,
+            mock::{crypto_basics, MockMultiaddress, MockNetworkIdentity},
+            NetworkIdentity,
+        },
+        NodeIndex, SessionId,
+    };
+
+    fn assert_close_estimate(data: NetworkData<Vec<u64>, MockMultiaddress>) {
+        let real = data.encode().len();
+        let estimate = data.encoded_size();
+        assert!(
+            estimate.abs_diff(real) <= real / 10,
+            "estimate {} too far from {}",
+            estimate,
+            real
+        );
+    }
+
+    #[test]
+    fn estimates_data_size() {
+        assert_close_estimate(NetworkData::Data(
+            vec![2137; 1000],
+            SessionId(43),
+            Channel::Main,
+        ));
+        assert_close_estimate(NetworkData::Data(Vec::new(), SessionId(43), Channel::Main));
+        assert_close_estimate(NetworkData::Data(
+            vec![2137; 1000],
+            SessionId(43),
+            Channel::Requests,
+        ));
+    }
+
+    #[tokio::test]
+    async fn estimates_meta_size() {
+        let crypto_basics = crypto_basics(1).await;
+        let handler = SessionHandler::new(
+            Some(crypto_basics.0[0].clone()),
+            crypto_basics.1.clone(),
+            SessionId(43),
+            MockNetworkIdentity::new().identity().0,
+        )
+        .await
+        .unwrap();
+        assert_close_estimate(NetworkData::Meta(
+            DiscoveryMessage::AuthenticationBroadcast(handler.authentication().unwrap()),
+        ));
+    }
+
+    #[test]
+    fn decodes_unknown_variant() {
+        let mut encoded = vec![7];
+        encoded.append(&mut (2137u32, SessionId(43)).encode());
+        let decoded = NetworkData::<Vec<u64>, MockMultiaddress>::decode(&mut encoded.as_slice())
+            .expect("should decode");
+        assert_eq!(decoded, NetworkData::Unknown(7, encoded[1..].to_vec()));
+        assert_eq!(decoded.encode(), encoded);
+    }
+
+    #[tokio::test]
+    async fn known_variants_roundtrip() {
+        let crypto_basics = crypto_basics(1).await;
+        let handler = SessionHandler::new(
+            Some(crypto_basics.0[0].clone()),
+            crypto_basics.1.clone(),
+            SessionId(43),
+            MockNetworkIdentity::new().identity().0,
+        )
+        .await
+        .unwrap();
+        let (_, signature) = handler.authentication().unwrap();
+        let leaving = Leaving {
+            session_id: SessionId(43),
+            node_id: NodeIndex(7),
+        };
+        let messages: Vec<NetworkData<Vec<u64>, MockMultiaddress>> = vec![
+            NetworkData::Meta(DiscoveryMessage::AuthenticationBroadcast(
+                handler.authentication().unwrap(),
+            )),
+            NetworkData::Data(vec![2137; 10], SessionId(43), Channel::Main),
+            NetworkData::Data(vec![2137; 10], SessionId(43), Channel::Requests),
+            NetworkData::Control(ControlMessage::LeavingSession(leaving, signature)),
+        ];
+        for message in messages {
+            let encoded = message.encode();
+            assert_eq!(NetworkData::decode(&mut encoded.as_slice()), Ok(message));
+        }
+    }
+
+    async fn valid_messages() -> Vec<NetworkData<Vec<u64>, MockMultiaddress>> {
+        let crypto_basics = crypto_basics(1).await;
+        let handler = SessionHandler::new(
+            Some(crypto_basics.0[0].clone()),
+            crypto_basics.1.clone(),
+            SessionId(43),
+            MockNetworkIdentity::new().identity().0,
+        )
+        .await
+        .unwrap();
+        let (_, signature) = handler.authentication().unwrap();
+        let leaving = Leaving {
+            session_id: SessionId(43),
+            node_id: NodeIndex(7),
+        };
+        vec![
+            NetworkData::Meta(DiscoveryMessage::AuthenticationBroadcast(
+                handler.authentication().unwrap(),
+            )),
+            NetworkData::Data(vec![2137; 10], SessionId(43), Channel::Main),
+            NetworkData::Data(Vec::new(), SessionId(43), Channel::Main),
+            NetworkData::Data(Vec::new(), SessionId(43), Channel::Requests),
+            NetworkData::Control(ControlMessage::LeavingSession(leaving, signature)),
+            NetworkData::Unknown(7, vec![1, 2, 3]),
+        ]
+    }
+
+    #[tokio::test]
+    async fn decodes_valid_messages() {
+        for message in valid_messages().await {
+            assert_eq!(decode_network_data(&message.encode()), Ok(message));
+        }
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut encoded = NetworkData::<Vec<u64>, MockMultiaddress>::Data(
+            vec![2137],
+            SessionId(43),
+            Channel::Main,
+        )
+        .encode();
+        encoded.extend_from_slice(&[0, 0, 0]);
+        assert_eq!(
+            decode_network_data::<Vec<u64>, MockMultiaddress>(&encoded),
+            Err(DecodeError::TrailingBytes(3))
+        );
+    }
+
+    #[test]
+    fn encodes_main_channel_data_as_before_channels() {
+        let mut expected = vec![1];
+        expected.append(&mut (vec![2137u64], SessionId(43)).encode());
+        let data = DataInSession::new(vec![2137u64], SessionId(43));
+        assert_eq!(data.encode(), expected[1..]);
+        assert_eq!(DataInSession::decode(&mut &expected[1..]), Ok(data.clone()));
+        let data: NetworkData<_, MockMultiaddress> = data.into();
+        assert_eq!(data.encode(), expected);
+    }
+
+    #[test]
+    fn data_in_session_roundtrips() {
+        for channel in [Channel::Main, Channel::Requests] {
+            let data = DataInSession {
+                data: vec![2137u64],
+                session_id: SessionId(43),
+                channel,
+                checksum: None,
+            };
+            let encoded = data.encode();
+            assert_eq!(
+                DataInSession::decode(&mut encoded.as_slice()),
+                Ok(data.clone())
+            );
+            let checked = data.with_checksum(&ChecksumKey::new(b"seed", SessionId(43)));
+            let encoded = checked.encode();
+            assert_eq!(DataInSession::decode(&mut encoded.as_slice()), Ok(checked));
+        }
+    }
+
+    #[test]
+    fn matches_valid_session_checksum() {
+        let key = ChecksumKey::new(b"seed", SessionId(43));
+        let data = DataInSession::new(vec![2137u64], SessionId(43)).with_checksum(&key);
+        assert!(data.checksum_matches(&key));
+    }
+
+    #[test]
+    fn rejects_mismatched_session_checksum() {
+        let key = ChecksumKey::new(b"seed", SessionId(43));
+        let unchecked = DataInSession::new(vec![2137u64], SessionId(43));
+        assert!(!unchecked.checksum_matches(&key));
+        // checksummed within a different session, but delivered to this one
+        let mut misrouted = unchecked
+            .clone()
+            .with_checksum(&ChecksumKey::new(b"seed", SessionId(44)));
+        assert!(!misrouted.checksum_matches(&key));
+        misrouted.checksum = unchecked.with_checksum(&key).checksum;
+        misrouted.data = vec![2138u64];
+        assert!(!misrouted.checksum_matches(&key));
+    }
+
+    #[test]
+    fn rejects_empty_input() {
+        assert_eq!(
+            decode_network_data::<Vec<u64>, MockMultiaddress>(&[]),
+            Err(DecodeError::Malformed)
+        );
+    }
+
+    #[tokio::test]
+    async fn survives_arbitrary_input() {
+        let mut rng = thread_rng();
+        for _ in 0..10_000 {
+            let length = rng.gen_range(0..256);
+            let mut bytes: Vec<u8> = (0..length).map(|_| rng.gen()).collect();
+            // known tags make it much more likely to get deep into decoding
+            if let Some(tag) = bytes.first_mut() {
+                *tag %= 3;
+            }
+            let _ = decode_network_data::<Vec<u64>, MockMultiaddress>(&bytes);
+        }
+        // mangled valid messages are the most likely to trip decoding up
+        for message in valid_messages().await {
+            let encoded = message.encode();
+            for length in 0..encoded.len() {
+                let _ = decode_network_data::<Vec<u64>, MockMultiaddress>(&encoded[..length]);
+            }
+            for _ in 0..1_000 {
+                let mut mangled = encoded.clone();
+                let position = rng.gen_range(0..mangled.len());
+                mangled[position] = rng.gen();
+                let _ = decode_network_data::<Vec<u64>, MockMultiaddress>(&mangled);
+            }
+        }
+    }
 }
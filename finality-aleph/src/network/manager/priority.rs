@@ -0,0 +1,156 @@
+use std::collections::VecDeque;
+
+/// How urgently a message should be sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    High,
+    Normal,
+}
+
+impl Priority {
+    fn other(&self) -> Self {
+        match self {
+            Priority::High => Priority::Normal,
+            Priority::Normal => Priority::High,
+        }
+    }
+}
+
+/// How many messages of a given priority can be taken in a row, if messages of the other
+/// priority are waiting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PriorityWeights {
+    pub high: usize,
+    pub normal: usize,
+}
+
+impl Default for PriorityWeights {
+    /// High priority messages are rare, so they can take a big share without starving the rest,
+    /// while under heavy load they still wait for at most a handful of normal ones.
+    fn default() -> Self {
+        PriorityWeights { high: 4, normal: 8 }
+    }
+}
+
+impl PriorityWeights {
+    fn weight(&self, priority: Priority) -> usize {
+        let weight = match priority {
+            Priority::High => self.high,
+            Priority::Normal => self.normal,
+        };
+        // A zero weight would starve the queue completely.
+        weight.max(1)
+    }
+}
+
+/// Two queues, one per priority, drained with weighted fairness.
+pub struct PriorityQueue<T> {
+    high: VecDeque<T>,
+    normal: VecDeque<T>,
+    weights: PriorityWeights,
+    current: Priority,
+    taken: usize,
+}
+
+impl<T> PriorityQueue<T> {
+    pub fn new(weights: PriorityWeights) -> Self {
+        PriorityQueue {
+            high: VecDeque::new(),
+            normal: VecDeque::new(),
+            weights,
+            current: Priority::High,
+            taken: 0,
+        }
+    }
+
+    fn queue(&mut self, priority: Priority) -> &mut VecDeque<T> {
+        match priority {
+            Priority::High => &mut self.high,
+            Priority::Normal => &mut self.normal,
+        }
+    }
+
+    pub fn push(&mut self, item: T, priority: Priority) {
+        self.queue(priority).push_back(item);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.high.is_empty() && self.normal.is_empty()
+    }
+
+    /// Takes the next item, switching to the other queue whenever the current one is empty or
+    /// used up its weight.
+    pub fn pop(&mut self) -> Option<T> {
+        // At most two switches are needed to find a nonempty queue with some weight left.
+        for _ in 0..3 {
+            if self.taken < self.weights.weight(self.current) {
+                let current = self.current;
+                if let Some(item) = self.queue(current).pop_front() {
+                    self.taken += 1;
+                    return Some(item);
+                }
+            }
+            self.current = self.current.other();
+            self.taken = 0;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Priority, PriorityQueue, PriorityWeights};
+
+    fn drain(queue: &mut PriorityQueue<u32>) -> Vec<u32> {
+        std::iter::from_fn(|| queue.pop()).collect()
+    }
+
+    #[test]
+    fn keeps_order_within_priority() {
+        let mut queue = PriorityQueue::new(PriorityWeights::default());
+        for item in 0..20 {
+            queue.push(item, Priority::Normal);
+        }
+        assert_eq!(drain(&mut queue), (0..20).collect::<Vec<_>>());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn interleaves_according_to_weights() {
+        let mut queue = PriorityQueue::new(PriorityWeights { high: 1, normal: 2 });
+        for item in 0..4 {
+            queue.push(item, Priority::Normal);
+        }
+        for item in 10..13 {
+            queue.push(item, Priority::High);
+        }
+        assert_eq!(drain(&mut queue), vec![10, 0, 1, 11, 2, 3, 12]);
+    }
+
+    #[test]
+    fn high_priority_gets_through_data_flood() {
+        let weights = PriorityWeights::default();
+        let mut queue = PriorityQueue::new(weights);
+        for item in 0..10_000 {
+            queue.push(item, Priority::Normal);
+        }
+        // take some data, so that the high priority message arrives in the middle of a flood
+        for _ in 0..3 {
+            queue.pop();
+        }
+        queue.push(u32::MAX, Priority::High);
+        let position = std::iter::from_fn(|| queue.pop())
+            .position(|item| item == u32::MAX)
+            .expect("the high priority item should be there");
+        assert!(position <= weights.normal);
+    }
+
+    #[test]
+    fn zero_weight_does_not_starve() {
+        let mut queue = PriorityQueue::new(PriorityWeights { high: 0, normal: 0 });
+        queue.push(1, Priority::Normal);
+        queue.push(2, Priority::High);
+        queue.push(3, Priority::Normal);
+        assert_eq!(drain(&mut queue), vec![2, 1, 3]);
+    }
+}
@@ -1,14 +1,20 @@
 use std::{
     cmp,
-    collections::{HashMap, HashSet},
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    fmt::{Display, Error as FmtError, Formatter},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
 use futures::{
     channel::{mpsc, oneshot},
-    StreamExt,
+    future, StreamExt,
 };
 use log::{debug, info, trace, warn};
+use parking_lot::Mutex;
 use tokio::time::{self, Instant};
 
 use crate::{
@@ -16,32 +22,134 @@ use crate::{
     crypto::{AuthorityPen, AuthorityVerifier},
     network::{
         manager::{
-            Connections, Discovery, DiscoveryMessage, NetworkData, SessionHandler,
-            SessionHandlerError,
+            check_addresses, AnnouncementSchedule, Channel, Connections, ControlMessage, Discovery,
+            DiscoveryMessage, DiscoveryState, InboundShare, Leaving, NetworkData, SessionHandler,
+            SessionHandlerError, SessionQueues, DEFAULT_DEDUP_CAPACITY,
         },
-        ConnectionCommand, Data, DataCommand, Multiaddress, NetworkIdentity, Protocol,
+        AddressPolicy, ConnectionCommand, Data, DataCommand, Multiaddress, NetworkIdentity,
+        Protocol,
     },
-    MillisecsPerBlock, NodeIndex, SessionId, SessionPeriod, STATUS_REPORT_INTERVAL,
+    MillisecsPerBlock, NodeCount, NodeIndex, SessionId, SessionPeriod, STATUS_REPORT_INTERVAL,
 };
 
 /// Commands for manipulating sessions, stopping them and starting both validator and non-validator
-/// sessions.
-pub enum SessionCommand<D: Data> {
+/// sessions, as well as changing the addresses we announce in them.
+pub enum SessionCommand<D: Data, M: Multiaddress> {
     StartValidator(
         SessionId,
         AuthorityVerifier,
         NodeIndex,
         AuthorityPen,
-        Option<oneshot::Sender<mpsc::UnboundedReceiver<D>>>,
+        Option<oneshot::Sender<(mpsc::UnboundedReceiver<D>, SessionPeers)>>,
     ),
     StartNonvalidator(SessionId, AuthorityVerifier),
-    Stop(SessionId),
+    /// Start authenticating and connecting to the validators of an upcoming session, so that the
+    /// connections already exist when the session starts. They are kept alive by the heartbeats
+    /// of the validator network until then.
+    Prepare(SessionId, AuthorityVerifier, NodeIndex, AuthorityPen),
+    /// Stop the session. When draining, the session stops accepting data immediately, but the
+    /// connections to its peers are kept for a while, so that the data already sent can reach
+    /// them.
+    Stop {
+        session_id: SessionId,
+        drain: bool,
+    },
+    /// Announce the given addresses instead of the ones from the network identity, in all the
+    /// current and future sessions.
+    UpdateAddresses(Vec<M>),
+    /// Replace the authorities of a running session, e.g. after an emergency rotation. Their
+    /// number has to stay the same.
+    UpdateAuthorities(SessionId, AuthorityVerifier),
+    /// Reconnect to the peer, e.g. after it restarted. The data already sent to it is flushed
+    /// before the old connections are dropped.
+    Recycle(M::PeerId),
+    /// Hold back the data sent to the peer, e.g. for maintenance, without disconnecting from it.
+    Pause(M::PeerId),
+    /// Send out the data held back for the peer, in order, and stop holding it back.
+    Resume(M::PeerId),
+    /// Deliver the data of a started validator session that arrives through the channel to a new
+    /// receiver, instead of the previous one. Data that arrived before is not delivered.
+    OpenChannel(
+        SessionId,
+        Channel,
+        oneshot::Sender<(mpsc::UnboundedReceiver<D>, SessionPeers)>,
+    ),
+}
+
+/// Tells whether we currently have a working connection with the peer, as opposed to only
+/// knowing its addresses.
+pub type IsConnected<PID> = Arc<dyn Fn(&PID) -> bool + Send + Sync>;
+
+type Reachable = Box<dyn Fn() -> bool + Send>;
+
+/// The nodes of a session we can currently send data to, kept up to date by the connection
+/// manager for the user of the session.
+#[derive(Clone, Default)]
+pub struct SessionPeers {
+    peers: Arc<Mutex<Vec<(NodeIndex, Reachable)>>>,
+    node_count: Arc<AtomicUsize>,
+}
+
+impl SessionPeers {
+    /// The nodes we can currently send data to, in increasing order. These are the authenticated
+    /// nodes of the session the network is connected to right now.
+    pub fn get(&self) -> Vec<NodeIndex> {
+        self.peers
+            .lock()
+            .iter()
+            .filter(|(_, reachable)| reachable())
+            .map(|(node_id, _)| *node_id)
+            .collect()
+    }
+
+    /// How many nodes the session authenticates, whether we can send data to them or not.
+    pub fn node_count(&self) -> NodeCount {
+        NodeCount(self.node_count.load(Ordering::Relaxed))
+    }
+
+    fn set_node_count(&self, node_count: NodeCount) {
+        self.node_count.store(node_count.0, Ordering::Relaxed);
+    }
+
+    fn set(&self, peers: impl IntoIterator<Item = (NodeIndex, Reachable)>) {
+        let mut peers: Vec<_> = peers.into_iter().collect();
+        peers.sort_by_key(|(node_id, _)| *node_id);
+        *self.peers.lock() = peers;
+    }
 }
 
 struct Session<D: Data, M: Multiaddress> {
     handler: SessionHandler<M>,
     discovery: Discovery<M>,
-    data_for_user: Option<mpsc::UnboundedSender<D>>,
+    /// Only validators receive any data, in the channels they opened.
+    data_for_user: HashMap<Channel, mpsc::UnboundedSender<D>>,
+    peers: SessionPeers,
+    /// Without it every authenticated node counts as reachable.
+    is_connected: Option<IsConnected<M::PeerId>>,
+}
+
+impl<D: Data, M: Multiaddress> Session<D, M> {
+    fn refresh_peers(&self) {
+        self.peers.set_node_count(self.handler.node_count());
+        self.peers
+            .set(self.handler.peers().into_iter().map(|(node_id, peer_id)| {
+                let reachable: Reachable = match &self.is_connected {
+                    Some(is_connected) => {
+                        let is_connected = is_connected.clone();
+                        Box::new(move || is_connected(&peer_id))
+                    }
+                    None => Box::new(|| true),
+                };
+                (node_id, reachable)
+            }));
+    }
+
+    /// Delivers the data of the channel to a new receiver, instead of the previous one.
+    fn open_channel(&mut self, channel: Channel) -> mpsc::UnboundedReceiver<D> {
+        let (data_for_user, data_from_network) = mpsc::unbounded();
+        self.data_for_user.insert(channel, data_for_user);
+        data_from_network
+    }
 }
 
 #[derive(Clone)]
@@ -73,12 +181,28 @@ impl PreSession {
     }
 }
 
+/// How long the connections of a draining session are kept, by default.
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+/// Data of at most this many sessions that did not start yet is staged at once, by default.
+const DEFAULT_MAX_STAGED_SESSIONS: usize = 4;
+
 /// Configuration for the session manager service. Controls how often the maintenance and
-/// rebroadcasts are triggerred. Also controls when maintenance starts.
+/// rebroadcasts are triggerred. Also controls when maintenance starts, which addresses of
+/// other nodes are accepted, how inbound messages of different sessions share the processing,
+/// for how long stopped sessions are drained, whether we announce ourselves on a schedule, how
+/// many verified authentications every session remembers and how much data arriving before its
+/// session starts is held, for how many sessions.
 pub struct Config {
     discovery_cooldown: Duration,
     maintenance_period: Duration,
     initial_delay: Duration,
+    address_policy: AddressPolicy,
+    inbound_share: InboundShare,
+    drain_timeout: Duration,
+    announcement_schedule: Option<AnnouncementSchedule>,
+    dedup_capacity: usize,
+    staging_capacity: usize,
+    max_staged_sessions: usize,
 }
 
 impl Config {
@@ -91,6 +215,75 @@ impl Config {
             discovery_cooldown,
             maintenance_period,
             initial_delay,
+            address_policy: AddressPolicy::default(),
+            inbound_share: InboundShare::default(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            announcement_schedule: None,
+            dedup_capacity: DEFAULT_DEDUP_CAPACITY,
+            staging_capacity: 0,
+            max_staged_sessions: DEFAULT_MAX_STAGED_SESSIONS,
+        }
+    }
+
+    /// Returns the configuration with addresses of other nodes filtered according to the policy.
+    pub fn with_address_policy(self, address_policy: AddressPolicy) -> Self {
+        Config {
+            address_policy,
+            ..self
+        }
+    }
+
+    /// Returns the configuration with inbound messages of every session processed according to
+    /// the share.
+    pub fn with_inbound_share(self, inbound_share: InboundShare) -> Self {
+        Config {
+            inbound_share,
+            ..self
+        }
+    }
+
+    /// Returns the configuration with connections of draining sessions kept for the timeout.
+    pub fn with_drain_timeout(self, drain_timeout: Duration) -> Self {
+        Config {
+            drain_timeout,
+            ..self
+        }
+    }
+
+    /// Returns the configuration with our authentication additionally announced in every session
+    /// according to the schedule. Only the builder sets it, as it checks the schedule first.
+    fn with_announcement_schedule(self, announcement_schedule: AnnouncementSchedule) -> Self {
+        Config {
+            announcement_schedule: Some(announcement_schedule),
+            ..self
+        }
+    }
+
+    /// Returns the configuration with every session remembering at most the given number of
+    /// recently verified authentications, so that their exact repeats are not verified again.
+    pub fn with_dedup_capacity(self, dedup_capacity: usize) -> Self {
+        Config {
+            dedup_capacity,
+            ..self
+        }
+    }
+
+    /// Returns the configuration with at most the given number of messages of every session that
+    /// did not start yet held until it starts, the oldest ones dropped first. Nothing is held by
+    /// default, all such data gets dropped.
+    pub fn with_staging_capacity(self, staging_capacity: usize) -> Self {
+        Config {
+            staging_capacity,
+            ..self
+        }
+    }
+
+    /// Returns the configuration with data of at most the given number of sessions that did not
+    /// start yet held at once. Data of the session staged for the longest is dropped first.
+    pub fn with_max_staged_sessions(self, max_staged_sessions: usize) -> Self {
+        Config {
+            max_staged_sessions,
+            ..self
         }
     }
 
@@ -110,7 +303,167 @@ impl Config {
     }
 }
 
-type MessageForNetwork<D, M> = (NetworkData<D, M>, DataCommand<<M as Multiaddress>::PeerId>);
+/// Settings of the connection manager that contradict each other or cannot work at all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// Maintenance has to run periodically.
+    ZeroMaintenancePeriod,
+    /// Authentications would be rebroadcast in response to every single message.
+    ZeroDiscoveryCooldown,
+    /// Our authentication would be announced all the time.
+    ZeroAnnouncementInterval,
+    /// The announcement intervals would not be jittered within their bounds, the jitter has to be
+    /// at least zero and below one.
+    AnnouncementJitterOutOfRange,
+    /// The first maintenance would come later than the regular ones.
+    InitialDelayTooLong {
+        initial_delay: Duration,
+        maintenance_period: Duration,
+    },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        use ConfigError::*;
+        match self {
+            ZeroMaintenancePeriod => write!(f, "maintenance period cannot be zero"),
+            ZeroDiscoveryCooldown => write!(f, "discovery cooldown cannot be zero"),
+            ZeroAnnouncementInterval => write!(f, "announcement interval cannot be zero"),
+            AnnouncementJitterOutOfRange => {
+                write!(f, "announcement jitter has to be at least 0 and below 1")
+            }
+            InitialDelayTooLong {
+                initial_delay,
+                maintenance_period,
+            } => write!(
+                f,
+                "initial delay of {}ms is longer than the maintenance period of {}ms",
+                initial_delay.as_millis(),
+                maintenance_period.as_millis()
+            ),
+        }
+    }
+}
+
+/// Builds a configuration for the session manager service, checking that the settings are
+/// consistent.
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    /// Starts with the configuration that triggers maintenance about 5 times per session.
+    pub fn new(session_period: &SessionPeriod, millisecs_per_block: &MillisecsPerBlock) -> Self {
+        ConfigBuilder {
+            config: Config::with_session_period(session_period, millisecs_per_block),
+        }
+    }
+
+    pub fn discovery_cooldown(mut self, discovery_cooldown: Duration) -> Self {
+        self.config.discovery_cooldown = discovery_cooldown;
+        self
+    }
+
+    pub fn maintenance_period(mut self, maintenance_period: Duration) -> Self {
+        self.config.maintenance_period = maintenance_period;
+        self
+    }
+
+    pub fn initial_delay(mut self, initial_delay: Duration) -> Self {
+        self.config.initial_delay = initial_delay;
+        self
+    }
+
+    pub fn address_policy(self, address_policy: AddressPolicy) -> Self {
+        ConfigBuilder {
+            config: self.config.with_address_policy(address_policy),
+        }
+    }
+
+    pub fn inbound_share(self, inbound_share: InboundShare) -> Self {
+        ConfigBuilder {
+            config: self.config.with_inbound_share(inbound_share),
+        }
+    }
+
+    pub fn drain_timeout(self, drain_timeout: Duration) -> Self {
+        ConfigBuilder {
+            config: self.config.with_drain_timeout(drain_timeout),
+        }
+    }
+
+    pub fn announcement_schedule(self, announcement_schedule: AnnouncementSchedule) -> Self {
+        ConfigBuilder {
+            config: self
+                .config
+                .with_announcement_schedule(announcement_schedule),
+        }
+    }
+
+    pub fn dedup_capacity(self, dedup_capacity: usize) -> Self {
+        ConfigBuilder {
+            config: self.config.with_dedup_capacity(dedup_capacity),
+        }
+    }
+
+    pub fn staging_capacity(self, staging_capacity: usize) -> Self {
+        ConfigBuilder {
+            config: self.config.with_staging_capacity(staging_capacity),
+        }
+    }
+
+    pub fn max_staged_sessions(self, max_staged_sessions: usize) -> Self {
+        ConfigBuilder {
+            config: self.config.with_max_staged_sessions(max_staged_sessions),
+        }
+    }
+
+    /// Returns the configuration, unless some of the settings are inconsistent.
+    pub fn build(self) -> Result<Config, ConfigError> {
+        let config = self.config;
+        if config.maintenance_period.is_zero() {
+            return Err(ConfigError::ZeroMaintenancePeriod);
+        }
+        if config.discovery_cooldown.is_zero() {
+            return Err(ConfigError::ZeroDiscoveryCooldown);
+        }
+        if let Some(schedule) = config.announcement_schedule {
+            if schedule.interval.is_zero() {
+                return Err(ConfigError::ZeroAnnouncementInterval);
+            }
+            // also rejects NaN
+            if !(0.0..1.0).contains(&schedule.jitter) {
+                return Err(ConfigError::AnnouncementJitterOutOfRange);
+            }
+        }
+        if config.initial_delay > config.maintenance_period {
+            return Err(ConfigError::InitialDelayTooLong {
+                initial_delay: config.initial_delay,
+                maintenance_period: config.maintenance_period,
+            });
+        }
+        Ok(config)
+    }
+}
+
+/// User data is shared between all the peers it is sent to, so sending it to many of them only
+/// clones the pointer. Shared data encodes exactly like the data itself, so every connection can
+/// encode it on its own without copying it first.
+type MessageForNetwork<D, M> = (
+    NetworkData<Arc<D>, M>,
+    DataCommand<<M as Multiaddress>::PeerId>,
+);
+
+/// Takes the data out of the pointer, cloning it only if it is still shared.
+fn unshare<D: Data>(data: Arc<D>) -> D {
+    Arc::try_unwrap(data).unwrap_or_else(|data| data.as_ref().clone())
+}
+
+/// Data that arrived before its session started, oldest first.
+struct Staged<D: Data> {
+    since: Instant,
+    data: VecDeque<(Channel, D)>,
+}
 
 pub struct ServiceActions<D: Data, M: Multiaddress> {
     maybe_command: Option<ConnectionCommand<M>>,
@@ -141,11 +494,27 @@ pub struct Service<NI: NetworkIdentity, D: Data> {
     sessions: HashMap<SessionId, Session<D, NI::Multiaddress>>,
     to_retry: Vec<(
         PreSession,
-        Option<oneshot::Sender<mpsc::UnboundedReceiver<D>>>,
+        Option<oneshot::Sender<(mpsc::UnboundedReceiver<D>, SessionPeers)>>,
     )>,
     discovery_cooldown: Duration,
     maintenance_period: Duration,
     initial_delay: Duration,
+    address_policy: AddressPolicy,
+    inbound_share: InboundShare,
+    drain_timeout: Duration,
+    announcement_schedule: Option<AnnouncementSchedule>,
+    dedup_capacity: usize,
+    /// Stopped sessions whose connections are kept until the deadline.
+    draining: HashMap<SessionId, Instant>,
+    /// What discovery knew in the draining sessions, restored if they start again.
+    discovery_snapshots: HashMap<SessionId, DiscoveryState<NI::Multiaddress>>,
+    announced_addresses: Option<Vec<NI::Multiaddress>>,
+    /// How much data arrived for sessions in which we are not a validator.
+    mismatched_data: usize,
+    staging_capacity: usize,
+    max_staged_sessions: usize,
+    staged: HashMap<SessionId, Staged<D>>,
+    is_connected: Option<IsConnected<<NI::Multiaddress as Multiaddress>::PeerId>>,
 }
 
 impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
@@ -155,6 +524,13 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
             discovery_cooldown,
             maintenance_period,
             initial_delay,
+            address_policy,
+            inbound_share,
+            drain_timeout,
+            announcement_schedule,
+            dedup_capacity,
+            staging_capacity,
+            max_staged_sessions,
         } = config;
         Service {
             network_identity,
@@ -164,6 +540,31 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
             discovery_cooldown,
             maintenance_period,
             initial_delay,
+            address_policy,
+            inbound_share,
+            drain_timeout,
+            announcement_schedule,
+            dedup_capacity,
+            draining: HashMap::new(),
+            discovery_snapshots: HashMap::new(),
+            announced_addresses: None,
+            mismatched_data: 0,
+            staging_capacity,
+            max_staged_sessions,
+            staged: HashMap::new(),
+            is_connected: None,
+        }
+    }
+
+    /// Returns the service reporting only the authenticated nodes the check considers connected
+    /// as the peers of the sessions, rather than all of them.
+    pub fn with_connectivity(
+        self,
+        is_connected: IsConnected<<NI::Multiaddress as Multiaddress>::PeerId>,
+    ) -> Self {
+        Service {
+            is_connected: Some(is_connected),
+            ..self
         }
     }
 
@@ -176,16 +577,114 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
         }
     }
 
+    /// Stops handling any data of the session, but keeps the connections to its peers.
+    fn stop_session(&mut self, session_id: SessionId) {
+        if let Some(session) = self.sessions.remove(&session_id) {
+            // the user might still hold the peers for a while
+            session.peers.set(Vec::new());
+        }
+        self.staged.remove(&session_id);
+        self.to_retry
+            .retain(|(pre_session, _)| pre_session.session_id() != session_id);
+    }
+
+    /// Tells everyone we leave the session, if we are a validator in it, so that they stop routing
+    /// its data to us.
+    async fn announce_leaving(
+        &self,
+        session_id: &SessionId,
+    ) -> Vec<MessageForNetwork<D, NI::Multiaddress>> {
+        let leaving = match self.sessions.get(session_id) {
+            Some(session) => session.handler.leaving().await,
+            None => None,
+        };
+        leaving
+            .map(|message| (NetworkData::Control(message), DataCommand::Broadcast))
+            .into_iter()
+            .collect()
+    }
+
     fn finish_session(
         &mut self,
         session_id: SessionId,
     ) -> Option<ConnectionCommand<NI::Multiaddress>> {
-        self.sessions.remove(&session_id);
-        self.to_retry
-            .retain(|(pre_session, _)| pre_session.session_id() != session_id);
+        self.stop_session(session_id);
+        self.draining.remove(&session_id);
+        self.discovery_snapshots.remove(&session_id);
         Self::delete_reserved(self.connections.remove_session(session_id))
     }
 
+    fn drain_session(&mut self, session_id: SessionId) {
+        if let Some(session) = self.sessions.get(&session_id) {
+            self.discovery_snapshots
+                .insert(session_id, session.discovery.state());
+        }
+        self.stop_session(session_id);
+        self.draining
+            .entry(session_id)
+            .or_insert_with(|| Instant::now() + self.drain_timeout);
+    }
+
+    /// The command reconnecting to the peer, using the addresses it authenticated with in any of
+    /// the sessions we are a validator in.
+    fn recycle(&self, peer_id: &NI::PeerId) -> Option<ConnectionCommand<NI::Multiaddress>> {
+        let addresses: HashSet<_> = self
+            .sessions
+            .values()
+            .filter(|session| session.handler.is_validator())
+            .flat_map(|session| {
+                session
+                    .handler
+                    .peer_addresses(peer_id, &self.address_policy)
+            })
+            .collect();
+        match addresses.is_empty() {
+            true => {
+                debug!(target: "aleph-network", "Not recycling connections to unknown peer {:?}.", peer_id);
+                None
+            }
+            false => Some(ConnectionCommand::Recycle(addresses)),
+        }
+    }
+
+    /// The sessions we currently handle or keep the connections of, in increasing order. Draining
+    /// sessions are included until they finish, sessions waiting for a retry are not.
+    pub fn active_sessions(&self) -> Vec<SessionId> {
+        let sessions: BTreeSet<_> = self
+            .sessions
+            .keys()
+            .chain(self.draining.keys())
+            .copied()
+            .collect();
+        sessions.into_iter().collect()
+    }
+
+    /// When the next draining session should be finished, if any is draining.
+    pub fn next_drain_deadline(&self) -> Option<Instant> {
+        self.draining.values().min().copied()
+    }
+
+    /// Finishes all the draining sessions whose deadline passed.
+    /// Returns a command possibly changing what we should stay connected to.
+    pub fn finish_drained(&mut self, now: Instant) -> ServiceActions<D, NI::Multiaddress> {
+        let drained: Vec<_> = self
+            .draining
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(session_id, _)| *session_id)
+            .collect();
+        let mut to_remove = HashSet::new();
+        for session_id in drained {
+            self.draining.remove(&session_id);
+            self.discovery_snapshots.remove(&session_id);
+            to_remove.extend(self.connections.remove_session(session_id));
+        }
+        ServiceActions {
+            maybe_command: Self::delete_reserved(to_remove),
+            data: Vec::new(),
+        }
+    }
+
     fn network_message(
         (message, command): (DiscoveryMessage<NI::Multiaddress>, DataCommand<NI::PeerId>),
     ) -> MessageForNetwork<D, NI::Multiaddress> {
@@ -210,6 +709,64 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
         }
     }
 
+    /// Returns the discovery handler for a new session. Sessions in which we are not a validator
+    /// get the schedule as well, in case we become one later, they just never announce anything.
+    fn new_discovery(&self) -> Discovery<NI::Multiaddress> {
+        let discovery = Discovery::new(self.discovery_cooldown, self.address_policy)
+            .with_dedup_capacity(self.dedup_capacity);
+        match self.announcement_schedule {
+            Some(schedule) => discovery.with_announcements(schedule, Instant::now().into_std()),
+            None => discovery,
+        }
+    }
+
+    /// Restores what discovery knew in the session before it was stopped, if it is still
+    /// draining, so that the addresses of the peers need not be learned anew. Returns the
+    /// restored addresses we should be connected to.
+    ///
+    /// The snapshots are only kept in memory, so this covers sessions restarted while the node
+    /// runs, after a node restart the addresses are learned anew.
+    fn restore_discovery(
+        &mut self,
+        session_id: SessionId,
+        discovery: &mut Discovery<NI::Multiaddress>,
+        handler: &mut SessionHandler<NI::Multiaddress>,
+    ) -> Vec<NI::Multiaddress> {
+        if !self.draining.contains_key(&session_id) {
+            return Vec::new();
+        }
+        match self.discovery_snapshots.remove(&session_id) {
+            Some(state) => {
+                let addresses = discovery.load_state(state, handler);
+                debug!(target: "aleph-network", "Restored {} addresses known in session {:?}.", addresses.len(), session_id);
+                addresses
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// When the next scheduled announcement of our authentication is due, if any.
+    pub fn next_announcement(&self) -> Option<Instant> {
+        self.sessions
+            .values()
+            .filter_map(|session| session.discovery.next_announcement())
+            .min()
+            .map(Instant::from_std)
+    }
+
+    /// Returns the announcements of our authentication due at the given moment.
+    pub fn announce(&mut self, now: Instant) -> Vec<MessageForNetwork<D, NI::Multiaddress>> {
+        self.sessions
+            .values_mut()
+            .flat_map(
+                |Session {
+                     handler, discovery, ..
+                 }| { discovery.scheduled_announcements(handler, now.into_std()) },
+            )
+            .map(Self::network_message)
+            .collect()
+    }
+
     /// Returns all the network messages that should be sent as part of discovery at this moment.
     pub fn discovery(&mut self) -> Vec<MessageForNetwork<D, NI::Multiaddress>> {
         let mut result = Vec::new();
@@ -223,9 +780,15 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
     fn addresses(&self) -> Vec<NI::Multiaddress> {
         let (addresses, peer_id) = self.network_identity.identity();
         debug!(target: "aleph-network", "Got addresses:\n{:?}\n and peer_id:{:?}", addresses, peer_id);
+        let addresses = match &self.announced_addresses {
+            Some(announced_addresses) => announced_addresses.clone(),
+            None => addresses,
+        };
+        // other nodes would ignore the addresses above the limit anyway
         addresses
             .into_iter()
             .filter_map(|address| address.add_matching_peer_id(peer_id.clone()))
+            .take(self.address_policy.max_addresses)
             .collect()
     }
 
@@ -235,8 +798,9 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
         addresses: Vec<NI::Multiaddress>,
     ) -> Result<
         (
-            Vec<MessageForNetwork<D, NI::Multiaddress>>,
+            ServiceActions<D, NI::Multiaddress>,
             mpsc::UnboundedReceiver<D>,
+            SessionPeers,
         ),
         SessionHandlerError,
     > {
@@ -246,20 +810,44 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
             node_id,
             pen,
         } = pre_session;
-        let handler =
+        let mut handler =
             SessionHandler::new(Some((node_id, pen)), verifier, session_id, addresses).await?;
-        let discovery = Discovery::new(self.discovery_cooldown);
-        let (data_for_user, data_from_network) = mpsc::unbounded();
-        let data_for_user = Some(data_for_user);
-        self.sessions.insert(
-            session_id,
-            Session {
-                handler,
-                discovery,
-                data_for_user,
+        let mut discovery = self.new_discovery();
+        let restored = self.restore_discovery(session_id, &mut discovery, &mut handler);
+        // a restarted session keeps the connections it was draining
+        self.draining.remove(&session_id);
+        let maybe_command = match !restored.is_empty() && handler.is_validator() {
+            true => {
+                self.connections.add_peers(
+                    session_id,
+                    restored.iter().flat_map(|address| address.get_peer_id()),
+                );
+                Some(ConnectionCommand::AddReserved(
+                    restored.into_iter().collect(),
+                ))
+            }
+            false => None,
+        };
+        let peers = SessionPeers::default();
+        let mut session = Session {
+            handler,
+            discovery,
+            data_for_user: HashMap::new(),
+            peers: peers.clone(),
+            is_connected: self.is_connected.clone(),
+        };
+        let data_from_network = session.open_channel(Channel::Main);
+        session.refresh_peers();
+        self.sessions.insert(session_id, session);
+        self.flush_staged(&session_id);
+        Ok((
+            ServiceActions {
+                maybe_command,
+                data: self.discover_authorities(&session_id),
             },
-        );
-        Ok((self.discover_authorities(&session_id), data_from_network))
+            data_from_network,
+            peers,
+        ))
     }
 
     async fn update_validator_session(
@@ -269,6 +857,7 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
         (
             ServiceActions<D, NI::Multiaddress>,
             mpsc::UnboundedReceiver<D>,
+            SessionPeers,
         ),
         SessionHandlerError,
     > {
@@ -276,15 +865,7 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
         let session = match self.sessions.get_mut(&pre_session.session_id) {
             Some(session) => session,
             None => {
-                let (data, data_from_network) =
-                    self.start_validator_session(pre_session, addresses).await?;
-                return Ok((
-                    ServiceActions {
-                        maybe_command: None,
-                        data,
-                    },
-                    data_from_network,
-                ));
+                return self.start_validator_session(pre_session, addresses).await;
             }
         };
         let PreValidatorSession {
@@ -300,6 +881,8 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
             .iter()
             .flat_map(|address| address.get_peer_id())
             .collect();
+        session.discovery.forget_verified();
+        session.discovery.forget_departed(&session.handler);
         let maybe_command = Self::delete_reserved(
             self.connections
                 .remove_session(session_id)
@@ -307,8 +890,9 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
                 .cloned()
                 .collect(),
         );
-        let (data_for_user, data_from_network) = mpsc::unbounded();
-        session.data_for_user = Some(data_for_user);
+        let data_from_network = session.open_channel(Channel::Main);
+        session.refresh_peers();
+        let peers = session.peers.clone();
         self.connections.add_peers(session_id, peers_to_stay);
         Ok((
             ServiceActions {
@@ -316,18 +900,19 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
                 data: self.discover_authorities(&session_id),
             },
             data_from_network,
+            peers,
         ))
     }
 
     async fn handle_validator_presession(
         &mut self,
         pre_session: PreValidatorSession,
-        result_for_user: Option<oneshot::Sender<mpsc::UnboundedReceiver<D>>>,
+        result_for_user: Option<oneshot::Sender<(mpsc::UnboundedReceiver<D>, SessionPeers)>>,
     ) -> Result<ServiceActions<D, NI::Multiaddress>, SessionHandlerError> {
         match self.update_validator_session(pre_session.clone()).await {
-            Ok((actions, data_from_network)) => {
+            Ok((actions, data_from_network, peers)) => {
                 if let Some(result_for_user) = result_for_user {
-                    if result_for_user.send(data_from_network).is_err() {
+                    if result_for_user.send((data_from_network, peers)).is_err() {
                         warn!(target: "aleph-network", "Failed to send started session.")
                     }
                 }
@@ -350,14 +935,21 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
             session_id,
             verifier,
         } = pre_session;
-        let handler = SessionHandler::new(None, verifier, session_id, addresses).await?;
-        let discovery = Discovery::new(self.discovery_cooldown);
+        let mut handler = SessionHandler::new(None, verifier, session_id, addresses).await?;
+        let mut discovery = self.new_discovery();
+        self.restore_discovery(session_id, &mut discovery, &mut handler);
+        self.draining.remove(&session_id);
+        if let Some(staged) = self.staged.remove(&session_id) {
+            self.mismatched_data += staged.data.len();
+        }
         self.sessions.insert(
             session_id,
             Session {
                 handler,
                 discovery,
-                data_for_user: None,
+                data_for_user: HashMap::new(),
+                peers: SessionPeers::default(),
+                is_connected: self.is_connected.clone(),
             },
         );
         Ok(())
@@ -380,6 +972,8 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
             .handler
             .update(None, pre_session.verifier, addresses)
             .await?;
+        session.discovery.forget_verified();
+        session.discovery.forget_departed(&session.handler);
         Ok(())
     }
 
@@ -396,12 +990,85 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
             })
     }
 
+    /// Starts announcing the given addresses in all sessions, a new authentication is broadcast in
+    /// every session we are a validator in. The addresses are only used if they are usable, and
+    /// then in all the sessions at once, otherwise nothing changes.
+    async fn update_addresses(
+        &mut self,
+        addresses: Vec<NI::Multiaddress>,
+    ) -> Result<ServiceActions<D, NI::Multiaddress>, SessionHandlerError> {
+        let previous_addresses = self.announced_addresses.replace(addresses);
+        let addresses = self.addresses();
+        if let Err(e) = check_addresses(&addresses) {
+            self.announced_addresses = previous_addresses;
+            return Err(e);
+        }
+        let mut data = Vec::new();
+        let sessions: Vec<_> = self.sessions.keys().cloned().collect();
+        for session_id in sessions {
+            if let Some(session) = self.sessions.get_mut(&session_id) {
+                // cannot fail, the addresses were checked above and the keys stay the same
+                session.handler.update_addresses(addresses.clone()).await?;
+            }
+            data.append(&mut self.discover_authorities(&session_id));
+        }
+        Ok(ServiceActions {
+            maybe_command: None,
+            data,
+        })
+    }
+
+    /// Replaces the authorities of the session, dropping the connections to the ones that departed
+    /// and starting discovery of the new ones.
+    async fn update_authorities(
+        &mut self,
+        session_id: SessionId,
+        verifier: AuthorityVerifier,
+    ) -> Result<ServiceActions<D, NI::Multiaddress>, SessionHandlerError> {
+        let addresses = self.addresses();
+        let session = match self.sessions.get_mut(&session_id) {
+            Some(session) => session,
+            None => {
+                debug!(target: "aleph-network", "Not updating the authorities of unknown session {:?}.", session_id);
+                return Ok(ServiceActions::noop());
+            }
+        };
+        let peers_to_stay: HashSet<_> = session
+            .handler
+            .update_authorities(verifier, addresses)
+            .await?
+            .iter()
+            .flat_map(|address| address.get_peer_id())
+            .collect();
+        session.discovery.forget_verified();
+        session.discovery.forget_departed(&session.handler);
+        session.refresh_peers();
+        let maybe_command = match session.handler.is_validator() {
+            true => {
+                let to_remove = self
+                    .connections
+                    .remove_session(session_id)
+                    .difference(&peers_to_stay)
+                    .cloned()
+                    .collect();
+                self.connections.add_peers(session_id, peers_to_stay);
+                Self::delete_reserved(to_remove)
+            }
+            false => None,
+        };
+        info!(target: "aleph-network", "Updated the authorities of session {:?}.", session_id);
+        Ok(ServiceActions {
+            maybe_command,
+            data: self.discover_authorities(&session_id),
+        })
+    }
+
     /// Handle a session command.
     /// Returns a command possibly changing what we should stay connected to and a list of data to
     /// be sent over the network.
     pub async fn on_command(
         &mut self,
-        command: SessionCommand<D>,
+        command: SessionCommand<D, NI::Multiaddress>,
     ) -> Result<ServiceActions<D, NI::Multiaddress>, SessionHandlerError> {
         use SessionCommand::*;
         match command {
@@ -415,6 +1082,15 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
                 self.handle_validator_presession(pre_session, result_for_user)
                     .await
             }
+            Prepare(session_id, verifier, node_id, pen) => {
+                let pre_session = PreValidatorSession {
+                    session_id,
+                    verifier,
+                    node_id,
+                    pen,
+                };
+                self.handle_validator_presession(pre_session, None).await
+            }
             StartNonvalidator(session_id, verifier) => {
                 let pre_session = PreNonvalidatorSession {
                     session_id,
@@ -423,10 +1099,65 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
                 self.handle_nonvalidator_presession(pre_session).await?;
                 Ok(ServiceActions::noop())
             }
-            Stop(session_id) => Ok(ServiceActions {
-                maybe_command: self.finish_session(session_id),
+            Stop {
+                session_id,
+                drain: false,
+            } => {
+                let data = self.announce_leaving(&session_id).await;
+                Ok(ServiceActions {
+                    maybe_command: self.finish_session(session_id),
+                    data,
+                })
+            }
+            Stop {
+                session_id,
+                drain: true,
+            } => {
+                let data = self.announce_leaving(&session_id).await;
+                self.drain_session(session_id);
+                Ok(ServiceActions {
+                    maybe_command: None,
+                    data,
+                })
+            }
+            UpdateAddresses(addresses) => self.update_addresses(addresses).await,
+            UpdateAuthorities(session_id, verifier) => {
+                self.update_authorities(session_id, verifier).await
+            }
+            Recycle(peer_id) => Ok(ServiceActions {
+                maybe_command: self.recycle(&peer_id),
+                data: Vec::new(),
+            }),
+            Pause(peer_id) => Ok(ServiceActions {
+                maybe_command: Some(ConnectionCommand::Pause(peer_id)),
+                data: Vec::new(),
+            }),
+            Resume(peer_id) => Ok(ServiceActions {
+                maybe_command: Some(ConnectionCommand::Resume(peer_id)),
                 data: Vec::new(),
             }),
+            OpenChannel(session_id, channel, result_for_user) => {
+                // only validators receive data, dropping the sender tells the user it failed
+                match self
+                    .sessions
+                    .get_mut(&session_id)
+                    .filter(|session| !session.data_for_user.is_empty())
+                {
+                    Some(session) => {
+                        let data_from_network = session.open_channel(channel);
+                        if result_for_user
+                            .send((data_from_network, session.peers.clone()))
+                            .is_err()
+                        {
+                            warn!(target: "aleph-network", "Failed to send opened channel.")
+                        }
+                    }
+                    None => {
+                        debug!(target: "aleph-network", "Not opening channel {:?} in session {:?}, in which we are not a validator.", channel, session_id)
+                    }
+                }
+                Ok(ServiceActions::noop())
+            }
         }
     }
 
@@ -436,6 +1167,7 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
         &self,
         message: D,
         session_id: SessionId,
+        channel: Channel,
         recipient: Recipient,
     ) -> Vec<MessageForNetwork<D, NI::Multiaddress>> {
         if let Some(handler) = self
@@ -443,7 +1175,7 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
             .get(&session_id)
             .map(|session| &session.handler)
         {
-            let to_send = NetworkData::Data(message, session_id);
+            let to_send = NetworkData::Data(Arc::new(message), session_id, channel);
             match recipient {
                 Recipient::Everyone => (0..handler.node_count().0)
                     .map(NodeIndex)
@@ -480,10 +1212,12 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
     ) -> ServiceActions<D, NI::Multiaddress> {
         let session_id = message.session_id();
         match self.sessions.get_mut(&session_id) {
-            Some(Session {
-                handler, discovery, ..
-            }) => {
-                let (addresses, responses) = discovery.handle_message(message, handler);
+            Some(session) => {
+                let (addresses, responses) = session
+                    .discovery
+                    .handle_message(message, &mut session.handler);
+                session.refresh_peers();
+                let handler = &session.handler;
                 let maybe_command = match !addresses.is_empty() && handler.is_validator() {
                     true => {
                         debug!(target: "aleph-network", "Adding addresses for session {:?} to reserved: {:?}", session_id, addresses);
@@ -509,18 +1243,145 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
         }
     }
 
-    /// Sends the data to the identified session.
-    pub fn send_session_data(&self, session_id: &SessionId, data: D) -> Result<(), Error> {
-        match self
-            .sessions
-            .get(session_id)
-            .and_then(|session| session.data_for_user.as_ref())
-        {
-            Some(data_for_user) => data_for_user
-                .unbounded_send(data)
-                .map_err(|_| Error::UserSend),
-            None => Err(Error::NoSession),
-        }
+    /// Handle a control message from a peer.
+    /// Returns a command possibly changing what we should stay connected to.
+    pub fn on_control_message(
+        &mut self,
+        message: ControlMessage,
+    ) -> ServiceActions<D, NI::Multiaddress> {
+        use ControlMessage::*;
+        match message {
+            LeavingSession(leaving, signature) => {
+                let Leaving {
+                    session_id,
+                    node_id,
+                } = leaving;
+                let session = match self.sessions.get_mut(&session_id) {
+                    Some(session) => session,
+                    None => {
+                        debug!(target: "aleph-network", "Node {:?} left unknown session {:?}.", node_id, session_id);
+                        return ServiceActions::noop();
+                    }
+                };
+                if !session.handler.verify_leaving(&leaving, &signature) {
+                    debug!(target: "aleph-network", "Ignoring a badly signed leave of node {:?} in session {:?}.", node_id, session_id);
+                    return ServiceActions::noop();
+                }
+                let peer_id = match session.handler.forget_peer(&node_id) {
+                    Some(peer_id) => peer_id,
+                    None => return ServiceActions::noop(),
+                };
+                session.refresh_peers();
+                debug!(target: "aleph-network", "Node {:?} left session {:?}, no longer routing to {:?}.", node_id, session_id, peer_id);
+                ServiceActions {
+                    maybe_command: Self::delete_reserved(
+                        self.connections.remove_peer(session_id, &peer_id),
+                    ),
+                    data: Vec::new(),
+                }
+            }
+        }
+    }
+
+    /// Sends the data to the identified session, through the given channel.
+    /// Nobody should send us data in sessions in which we are not a validator, so such data gets
+    /// dropped and counted, unless it is late data of a draining session.
+    pub fn send_session_data(
+        &mut self,
+        session_id: &SessionId,
+        channel: Channel,
+        data: D,
+    ) -> Result<(), Error> {
+        let session = match self
+            .sessions
+            .get(session_id)
+            .filter(|session| !session.data_for_user.is_empty())
+        {
+            Some(session) => session,
+            None => {
+                if self.staging_capacity > 0
+                    && self.max_staged_sessions > 0
+                    && self.expects_session(session_id)
+                {
+                    self.stage(*session_id, channel, data);
+                    return Ok(());
+                }
+                if !self.draining.contains_key(session_id) {
+                    self.mismatched_data += 1;
+                    debug!(target: "aleph-network", "Dropping data for session {:?}, in which we are not a validator.", session_id);
+                }
+                return Err(Error::NoSession);
+            }
+        };
+        match session.data_for_user.get(&channel) {
+            Some(data_for_user) => data_for_user
+                .unbounded_send(data)
+                .map_err(|_| Error::UserSend),
+            None => Err(Error::NoSession),
+        }
+    }
+
+    /// Whether the session did not start yet, but we know it is about to. That is the case for
+    /// sessions whose start failed and is to be retried, and for the session right after the
+    /// latest one we know of. Before we know of any session nothing is expected, so peers cannot
+    /// make us hold data of arbitrary sessions.
+    fn expects_session(&self, session_id: &SessionId) -> bool {
+        if self.sessions.contains_key(session_id) || self.draining.contains_key(session_id) {
+            return false;
+        }
+        let retried = self
+            .to_retry
+            .iter()
+            .map(|(pre_session, _)| pre_session.session_id());
+        if retried.clone().any(|retried| &retried == session_id) {
+            return true;
+        }
+        self.sessions
+            .keys()
+            .chain(self.draining.keys())
+            .copied()
+            .chain(retried)
+            .map(|known| known.0)
+            .max()
+            .map_or(false, |latest| latest.checked_add(1) == Some(session_id.0))
+    }
+
+    /// Holds the data of a session that did not start yet, dropping its oldest data beyond the
+    /// capacity. Staging data of one session too many drops all the data of the session staged
+    /// for the longest.
+    fn stage(&mut self, session_id: SessionId, channel: Channel, data: D) {
+        if !self.staged.contains_key(&session_id) && self.staged.len() >= self.max_staged_sessions {
+            let oldest = self
+                .staged
+                .iter()
+                .min_by_key(|(_, staged)| staged.since)
+                .map(|(session_id, _)| *session_id);
+            if let Some(oldest) = oldest {
+                debug!(target: "aleph-network", "Dropping the data staged for session {:?}, it never started.", oldest);
+                self.staged.remove(&oldest);
+            }
+        }
+        let staged = self.staged.entry(session_id).or_insert_with(|| Staged {
+            since: Instant::now(),
+            data: VecDeque::new(),
+        });
+        if staged.data.len() >= self.staging_capacity {
+            staged.data.pop_front();
+        }
+        staged.data.push_back((channel, data));
+    }
+
+    /// Delivers the data that arrived before the session started to the channels it opened.
+    fn flush_staged(&mut self, session_id: &SessionId) {
+        let staged = match self.staged.remove(session_id) {
+            Some(staged) => staged,
+            None => return,
+        };
+        for (channel, data) in staged.data {
+            if let Err(e) = self.send_session_data(session_id, channel, data) {
+                trace!(target: "aleph-network", "Failed to deliver staged data in session {:?}: {:?}.", session_id, e);
+            }
+        }
     }
 
     /// Retries starting a validator session the user requested, but which failed to start
@@ -548,6 +1409,15 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
     pub fn status_report(&self) {
         let mut status = String::from("Connection Manager status report: ");
 
+        let active: Vec<_> = self
+            .active_sessions()
+            .iter()
+            .map(|session_id| session_id.0)
+            .collect();
+        if !active.is_empty() {
+            status.push_str(&format!("active sessions: {:?}; ", active));
+        }
+
         let mut authenticated: Vec<_> = self
             .sessions
             .iter()
@@ -617,7 +1487,18 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
             status.push_str(&format!("missing authorities: {}; ", missing_status));
         }
 
-        if !authenticated.is_empty() || !missing.is_empty() {
+        if self.mismatched_data > 0 {
+            status.push_str(&format!(
+                "data dropped for sessions we are not a validator in: {}; ",
+                self.mismatched_data
+            ));
+        }
+
+        if !active.is_empty()
+            || !authenticated.is_empty()
+            || !missing.is_empty()
+            || self.mismatched_data > 0
+        {
             info!(target: "aleph-network", "{}", status);
         }
     }
@@ -627,9 +1508,9 @@ impl<NI: NetworkIdentity, D: Data> Service<NI, D> {
 pub struct IO<D: Data, M: Multiaddress> {
     commands_for_network: mpsc::UnboundedSender<ConnectionCommand<M>>,
     messages_for_network: mpsc::UnboundedSender<MessageForNetwork<D, M>>,
-    commands_from_user: mpsc::UnboundedReceiver<SessionCommand<D>>,
-    messages_from_user: mpsc::UnboundedReceiver<(D, SessionId, Recipient)>,
-    messages_from_network: mpsc::UnboundedReceiver<NetworkData<D, M>>,
+    commands_from_user: mpsc::UnboundedReceiver<SessionCommand<D, M>>,
+    messages_from_user: mpsc::UnboundedReceiver<(D, SessionId, Channel, Recipient)>,
+    messages_from_network: mpsc::UnboundedReceiver<NetworkData<Arc<D>, M>>,
 }
 
 /// Errors that can happen during the network service operations.
@@ -639,7 +1520,8 @@ pub enum Error {
     CommandSend,
     /// Should never be fatal.
     UserSend,
-    /// Should never be fatal.
+    /// Should never be fatal. Also returned when the session does not receive data in the
+    /// channel.
     NoSession,
     CommandsChannel,
     MessageChannel,
@@ -650,9 +1532,9 @@ impl<D: Data, M: Multiaddress> IO<D, M> {
     pub fn new(
         commands_for_network: mpsc::UnboundedSender<ConnectionCommand<M>>,
         messages_for_network: mpsc::UnboundedSender<MessageForNetwork<D, M>>,
-        commands_from_user: mpsc::UnboundedReceiver<SessionCommand<D>>,
-        messages_from_user: mpsc::UnboundedReceiver<(D, SessionId, Recipient)>,
-        messages_from_network: mpsc::UnboundedReceiver<NetworkData<D, M>>,
+        commands_from_user: mpsc::UnboundedReceiver<SessionCommand<D, M>>,
+        messages_from_user: mpsc::UnboundedReceiver<(D, SessionId, Channel, Recipient)>,
+        messages_from_network: mpsc::UnboundedReceiver<NetworkData<Arc<D>, M>>,
     ) -> IO<D, M> {
         IO {
             commands_for_network,
@@ -675,6 +1557,21 @@ impl<D: Data, M: Multiaddress> IO<D, M> {
             .map_err(|_| Error::CommandSend)
     }
 
+    /// Sends out all the user data that is already waiting.
+    fn flush_user_messages<NI: NetworkIdentity<Multiaddress = M, PeerId = M::PeerId>>(
+        &mut self,
+        service: &Service<NI, D>,
+    ) -> Result<(), Error> {
+        while let Ok(Some((message, session_id, channel, recipient))) =
+            self.messages_from_user.try_next()
+        {
+            for message in service.on_user_message(message, session_id, channel, recipient) {
+                self.send_data(message)?;
+            }
+        }
+        Ok(())
+    }
+
     fn send(
         &self,
         ServiceActions {
@@ -694,15 +1591,51 @@ impl<D: Data, M: Multiaddress> IO<D, M> {
     fn on_network_message<NI: NetworkIdentity<Multiaddress = M, PeerId = M::PeerId>>(
         &self,
         service: &mut Service<NI, D>,
-        message: NetworkData<D, M>,
+        message: NetworkData<Arc<D>, M>,
     ) -> Result<(), Error> {
         use NetworkData::*;
-        match message {
+        let result = match message {
             Meta(message) => self.send(service.on_discovery_message(message)),
-            Data(data, session_id) => service.send_session_data(&session_id, data),
+            Data(data, session_id, channel) => {
+                service.send_session_data(&session_id, channel, unshare(data))
+            }
+            Control(message) => self.send(service.on_control_message(message)),
+            Unknown(tag, _) => {
+                trace!(target: "aleph-network", "Ignoring network data of unknown type {}.", tag);
+                Ok(())
+            }
+        };
+        match result {
+            Err(Error::UserSend) => {
+                trace!(target: "aleph-network", "Failed to send to user in session.");
+                Ok(())
+            }
+            Err(Error::NoSession) => {
+                trace!(target: "aleph-network", "Received message for unknown session or channel.");
+                Ok(())
+            }
+            result => result,
         }
     }
 
+    /// Queues the message in its session, messages without one are handled immediately.
+    fn queue_network_message<NI: NetworkIdentity<Multiaddress = M, PeerId = M::PeerId>>(
+        &self,
+        service: &mut Service<NI, D>,
+        inbound: &mut SessionQueues<NetworkData<Arc<D>, M>>,
+        message: NetworkData<Arc<D>, M>,
+    ) -> Result<(), Error> {
+        use NetworkData::*;
+        let session_id = match &message {
+            Meta(message) => message.session_id(),
+            Data(_, session_id, _) => *session_id,
+            Control(message) => message.session_id(),
+            Unknown(_, _) => return self.on_network_message(service, message),
+        };
+        inbound.push(session_id, message);
+        Ok(())
+    }
+
     /// Run the connection manager service with this IO.
     pub async fn run<NI: NetworkIdentity<Multiaddress = M, PeerId = M::PeerId>>(
         mut self,
@@ -716,15 +1649,26 @@ impl<D: Data, M: Multiaddress> IO<D, M> {
         );
 
         let mut status_ticker = time::interval(STATUS_REPORT_INTERVAL);
+        // Everything that already arrived is queued per session and processed in turns, so that
+        // a flood in one session does not delay the others.
+        let mut inbound = SessionQueues::new(service.inbound_share);
         loop {
             trace!(target: "aleph-network", "Manager Loop started a next iteration");
+            let drain_deadline = service.next_drain_deadline();
+            let announcement = service.next_announcement();
             tokio::select! {
                 maybe_command = self.commands_from_user.next() => {
                     trace!(target: "aleph-network", "Manager received a command from user");
                     match maybe_command {
-                        Some(command) => match service.on_command(command).await {
-                            Ok(to_send) => self.send(to_send)?,
-                            Err(e) => warn!(target: "aleph-network", "Failed to update handler: {:?}", e),
+                        Some(command) => {
+                            if matches!(command, SessionCommand::Stop { drain: true, .. } | SessionCommand::Recycle(_)) {
+                                // the data sent before stopping or reconnecting should still reach the peers
+                                self.flush_user_messages(&service)?;
+                            }
+                            match service.on_command(command).await {
+                                Ok(to_send) => self.send(to_send)?,
+                                Err(e) => warn!(target: "aleph-network", "Failed to update handler: {:?}", e),
+                            }
                         },
                         None => return Err(Error::CommandsChannel),
                     }
@@ -732,7 +1676,7 @@ impl<D: Data, M: Multiaddress> IO<D, M> {
                 maybe_message = self.messages_from_user.next() => {
                     trace!(target: "aleph-network", "Manager received a message from user");
                     match maybe_message {
-                        Some((message, session_id, recipient)) => for message in service.on_user_message(message, session_id, recipient) {
+                        Some((message, session_id, channel, recipient)) => for message in service.on_user_message(message, session_id, channel, recipient) {
                             self.send_data(message)?;
                         },
                         None => return Err(Error::MessageChannel),
@@ -741,16 +1685,20 @@ impl<D: Data, M: Multiaddress> IO<D, M> {
                 maybe_message = self.messages_from_network.next() => {
                     trace!(target: "aleph-network", "Manager received a message from network");
                     match maybe_message {
-                        Some(message) => if let Err(e) = self.on_network_message(&mut service, message) {
-                            match e {
-                                Error::UserSend => trace!(target: "aleph-network", "Failed to send to user in session."),
-                                Error::NoSession => trace!(target: "aleph-network", "Received message for unknown session."),
-                                _ => return Err(e),
+                        Some(message) => {
+                            self.queue_network_message(&mut service, &mut inbound, message)?;
+                            while let Ok(Some(message)) = self.messages_from_network.try_next() {
+                                self.queue_network_message(&mut service, &mut inbound, message)?;
                             }
                         },
                         None => return Err(Error::NetworkChannel),
                     }
                 },
+                _ = future::ready(()), if !inbound.is_empty() => {
+                    if let Some((_, message)) = inbound.pop() {
+                        self.on_network_message(&mut service, message)?;
+                    }
+                },
                 _ = maintenance.tick() => {
                     debug!(target: "aleph-network", "Manager starts maintenence");
                     match service.retry_session_start().await {
@@ -761,6 +1709,16 @@ impl<D: Data, M: Multiaddress> IO<D, M> {
                         self.send_data(to_send)?;
                     }
                 },
+                _ = time::sleep_until(drain_deadline.unwrap_or_else(Instant::now)), if drain_deadline.is_some() => {
+                    debug!(target: "aleph-network", "Manager finishes drained sessions");
+                    self.send(service.finish_drained(Instant::now()))?;
+                },
+                _ = time::sleep_until(announcement.unwrap_or_else(Instant::now)), if announcement.is_some() => {
+                    trace!(target: "aleph-network", "Manager announces our authentications");
+                    for to_send in service.announce(Instant::now()) {
+                        self.send_data(to_send)?;
+                    }
+                },
                 _ = status_ticker.tick() => {
                     service.status_report();
                 }
@@ -771,24 +1729,38 @@ impl<D: Data, M: Multiaddress> IO<D, M> {
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::{
+        collections::HashSet,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
 
+    use codec::{Decode, Encode};
     use futures::{channel::oneshot, StreamExt};
+    use parking_lot::Mutex;
 
-    use super::{Config, Error, Service, ServiceActions, SessionCommand};
+    use super::{
+        AnnouncementSchedule, Config, ConfigBuilder, ConfigError, Error, Service, ServiceActions,
+        SessionCommand,
+    };
     use crate::{
+        crypto::AuthorityVerifier,
         network::{
-            manager::{DiscoveryMessage, NetworkData},
-            mock::{crypto_basics, MockNetworkIdentity},
-            ConnectionCommand, DataCommand, Protocol,
+            manager::{Channel, ControlMessage, DiscoveryMessage, Leaving, NetworkData},
+            mock::{crypto_basics, MockMultiaddress, MockNetworkIdentity, MockPeerId},
+            AddressPolicy, ConnectionCommand, DataCommand, Multiaddress, NetworkIdentity, Protocol,
         },
-        Recipient, SessionId,
+        MillisecsPerBlock, NodeCount, NodeIndex, Recipient, SessionId, SessionPeriod,
     };
 
     const NUM_NODES: usize = 7;
     const MAINTENANCE_PERIOD: Duration = Duration::from_secs(120);
     const DISCOVERY_PERIOD: Duration = Duration::from_secs(60);
     const INITIAL_DELAY: Duration = Duration::from_secs(5);
+    const DRAIN_MARGIN: Duration = Duration::from_millis(10);
 
     fn build() -> Service<MockNetworkIdentity, i32> {
         Service::new(
@@ -797,6 +1769,93 @@ mod tests {
         )
     }
 
+    fn builder() -> ConfigBuilder {
+        ConfigBuilder::new(&SessionPeriod(900), &MillisecsPerBlock(1000))
+    }
+
+    #[test]
+    fn builds_default_config() {
+        let config = builder().build().expect("defaults should be consistent");
+        let default = Config::with_session_period(&SessionPeriod(900), &MillisecsPerBlock(1000));
+        assert_eq!(config.discovery_cooldown, default.discovery_cooldown);
+        assert_eq!(config.maintenance_period, default.maintenance_period);
+        assert_eq!(config.initial_delay, default.initial_delay);
+    }
+
+    #[test]
+    fn rejects_zero_maintenance_period() {
+        assert_eq!(
+            builder()
+                .maintenance_period(Duration::ZERO)
+                .initial_delay(Duration::ZERO)
+                .build()
+                .err(),
+            Some(ConfigError::ZeroMaintenancePeriod)
+        );
+    }
+
+    #[test]
+    fn rejects_zero_discovery_cooldown() {
+        assert_eq!(
+            builder().discovery_cooldown(Duration::ZERO).build().err(),
+            Some(ConfigError::ZeroDiscoveryCooldown)
+        );
+    }
+
+    #[test]
+    fn rejects_zero_announcement_interval() {
+        assert_eq!(
+            builder()
+                .announcement_schedule(AnnouncementSchedule {
+                    interval: Duration::ZERO,
+                    jitter: 0.0,
+                })
+                .build()
+                .err(),
+            Some(ConfigError::ZeroAnnouncementInterval)
+        );
+    }
+
+    #[test]
+    fn rejects_announcement_jitter_out_of_range() {
+        for jitter in [-0.1, 1.0, 1.5, f64::NAN] {
+            assert_eq!(
+                builder()
+                    .announcement_schedule(AnnouncementSchedule {
+                        interval: Duration::from_secs(10),
+                        jitter,
+                    })
+                    .build()
+                    .err(),
+                Some(ConfigError::AnnouncementJitterOutOfRange),
+                "jitter {} should be rejected",
+                jitter
+            );
+        }
+        assert!(builder()
+            .announcement_schedule(AnnouncementSchedule {
+                interval: Duration::from_secs(10),
+                jitter: 0.99,
+            })
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn rejects_initial_delay_longer_than_maintenance_period() {
+        assert_eq!(
+            builder()
+                .maintenance_period(Duration::from_secs(10))
+                .initial_delay(Duration::from_secs(11))
+                .build()
+                .err(),
+            Some(ConfigError::InitialDelayTooLong {
+                initial_delay: Duration::from_secs(11),
+                maintenance_period: Duration::from_secs(10),
+            })
+        );
+    }
+
     #[tokio::test]
     async fn starts_nonvalidator_session() {
         let mut service = build();
@@ -812,7 +1871,7 @@ mod tests {
         assert!(maybe_command.is_none());
         assert!(data.is_empty());
         assert_eq!(
-            service.send_session_data(&session_id, -43),
+            service.send_session_data(&session_id, Channel::Main, -43),
             Err(Error::NoSession)
         );
     }
@@ -843,7 +1902,160 @@ mod tests {
             .iter()
             .all(|(_, command)| command == &DataCommand::Broadcast));
         let _data_from_network = result_from_service.await.unwrap();
-        assert_eq!(service.send_session_data(&session_id, -43), Ok(()));
+        assert_eq!(
+            service.send_session_data(&session_id, Channel::Main, -43),
+            Ok(())
+        );
+    }
+
+    #[tokio::test]
+    async fn announces_validator_sessions_on_schedule() {
+        let interval = Duration::from_secs(10);
+        let mut service = Service::<_, i32>::new(
+            MockNetworkIdentity::new(),
+            Config::new(MAINTENANCE_PERIOD, DISCOVERY_PERIOD, INITIAL_DELAY)
+                .with_announcement_schedule(AnnouncementSchedule {
+                    interval,
+                    jitter: 0.0,
+                }),
+        );
+        assert!(service.next_announcement().is_none());
+        let (validator_data, verifier) = crypto_basics(NUM_NODES).await;
+        let (node_id, pen) = validator_data[0].clone();
+        service
+            .on_command(SessionCommand::StartValidator(
+                SessionId(43),
+                verifier.clone(),
+                node_id,
+                pen,
+                None,
+            ))
+            .await
+            .unwrap();
+        service
+            .on_command(SessionCommand::StartNonvalidator(SessionId(44), verifier))
+            .await
+            .unwrap();
+        let due = service.next_announcement().expect("should be scheduled");
+        assert!(service.announce(due - DRAIN_MARGIN).is_empty());
+        let data = service.announce(due);
+        // only the session in which we are a validator is announced
+        assert_eq!(data.len(), 1);
+        assert!(matches!(
+            data[0],
+            (
+                NetworkData::Meta(DiscoveryMessage::AuthenticationBroadcast(_)),
+                DataCommand::Broadcast
+            )
+        ));
+        assert!(service.announce(due).is_empty());
+        assert_eq!(service.announce(due + interval).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn drops_data_for_mismatched_session() {
+        let mut service = build();
+        let (validator_data, verifier) = crypto_basics(NUM_NODES).await;
+        let (node_id, pen) = validator_data[0].clone();
+        let session_id = SessionId(43);
+        let (result_for_user, result_from_service) = oneshot::channel();
+        service
+            .on_command(SessionCommand::StartValidator(
+                session_id,
+                verifier,
+                node_id,
+                pen,
+                Some(result_for_user),
+            ))
+            .await
+            .unwrap();
+        let (mut data_from_network, _) = result_from_service.await.unwrap();
+        assert_eq!(
+            service.send_session_data(&SessionId(44), Channel::Main, -43),
+            Err(Error::NoSession)
+        );
+        assert_eq!(service.mismatched_data, 1);
+        assert_eq!(
+            service.send_session_data(&session_id, Channel::Main, 43),
+            Ok(())
+        );
+        // only the data of the right session reaches the user
+        assert_eq!(data_from_network.next().await, Some(43));
+        assert!(data_from_network.try_next().is_err());
+        assert_eq!(service.mismatched_data, 1);
+    }
+
+    #[tokio::test]
+    async fn delivers_data_staged_before_session_start() {
+        let mut service = Service::new(
+            MockNetworkIdentity::new(),
+            Config::new(MAINTENANCE_PERIOD, DISCOVERY_PERIOD, INITIAL_DELAY)
+                .with_staging_capacity(2),
+        );
+        let (validator_data, verifier) = crypto_basics(NUM_NODES).await;
+        let (node_id, pen) = validator_data[0].clone();
+        let session_id = SessionId(43);
+        service
+            .on_command(SessionCommand::StartNonvalidator(
+                SessionId(42),
+                verifier.clone(),
+            ))
+            .await
+            .unwrap();
+        for data in [41, 42, 43] {
+            assert_eq!(
+                service.send_session_data(&session_id, Channel::Main, data),
+                Ok(())
+            );
+        }
+        let (result_for_user, result_from_service) = oneshot::channel();
+        service
+            .on_command(SessionCommand::StartValidator(
+                session_id,
+                verifier,
+                node_id,
+                pen,
+                Some(result_for_user),
+            ))
+            .await
+            .unwrap();
+        let (mut data_from_network, _) = result_from_service.await.unwrap();
+        // the oldest data did not fit
+        assert_eq!(data_from_network.next().await, Some(42));
+        assert_eq!(data_from_network.next().await, Some(43));
+        assert!(data_from_network.try_next().is_err());
+        assert_eq!(service.mismatched_data, 0);
+    }
+
+    #[tokio::test]
+    async fn does_not_stage_data_of_unexpected_sessions() {
+        let mut service = Service::<_, i32>::new(
+            MockNetworkIdentity::new(),
+            Config::new(MAINTENANCE_PERIOD, DISCOVERY_PERIOD, INITIAL_DELAY)
+                .with_staging_capacity(2),
+        );
+        let (_, verifier) = crypto_basics(NUM_NODES).await;
+        // before any session is known, no session is expected
+        assert_eq!(
+            service.send_session_data(&SessionId(43), Channel::Main, 43),
+            Err(Error::NoSession)
+        );
+        service
+            .on_command(SessionCommand::StartNonvalidator(SessionId(42), verifier))
+            .await
+            .unwrap();
+        // only the session right after the latest one is
+        assert_eq!(
+            service.send_session_data(&SessionId(45), Channel::Main, 45),
+            Err(Error::NoSession)
+        );
+        assert_eq!(
+            service.send_session_data(&SessionId(43), Channel::Main, 43),
+            Ok(())
+        );
+        assert!(service.staged.contains_key(&SessionId(43)));
+        assert!(!service.staged.contains_key(&SessionId(45)));
+        assert_eq!(service.mismatched_data, 2);
     }
 
     #[tokio::test]
@@ -871,27 +2083,38 @@ mod tests {
         assert!(data
             .iter()
             .all(|(_, command)| command == &DataCommand::Broadcast));
-        assert_eq!(service.send_session_data(&session_id, -43), Ok(()));
-        let mut data_from_network = result_from_service.await.unwrap();
+        assert_eq!(
+            service.send_session_data(&session_id, Channel::Main, -43),
+            Ok(())
+        );
+        let (mut data_from_network, _) = result_from_service.await.unwrap();
         assert_eq!(data_from_network.next().await, Some(-43));
         let ServiceActions {
             maybe_command,
             data,
         } = service
-            .on_command(SessionCommand::Stop(session_id))
+            .on_command(SessionCommand::Stop {
+                session_id,
+                drain: false,
+            })
             .await
             .unwrap();
         assert!(maybe_command.is_none());
-        assert!(data.is_empty());
+        // everyone gets told we left
+        assert!(matches!(
+            &data[..],
+            [(NetworkData::Control(ControlMessage::LeavingSession(leaving, _)), DataCommand::Broadcast)]
+                if leaving.session_id == session_id && leaving.node_id == node_id
+        ));
         assert_eq!(
-            service.send_session_data(&session_id, -43),
+            service.send_session_data(&session_id, Channel::Main, -43),
             Err(Error::NoSession)
         );
         assert!(data_from_network.next().await.is_none());
     }
 
     #[tokio::test]
-    async fn handles_broadcast() {
+    async fn drains_stopped_session() {
         let mut service = build();
         let (validator_data, verifier) = crypto_basics(NUM_NODES).await;
         let (node_id, pen) = validator_data[0].clone();
@@ -906,9 +2129,8 @@ mod tests {
             ))
             .await
             .unwrap();
-        let mut other_service = build();
         let (node_id, pen) = validator_data[1].clone();
-        let ServiceActions { data, .. } = other_service
+        let ServiceActions { data, .. } = build()
             .on_command(SessionCommand::StartValidator(
                 session_id, verifier, node_id, pen, None,
             ))
@@ -918,31 +2140,54 @@ mod tests {
             (NetworkData::Meta(broadcast), DataCommand::Broadcast) => broadcast,
             _ => panic!("Expected discovery massage broadcast, got: {:?}", data[0]),
         };
-        let addresses = match &broadcast {
-            DiscoveryMessage::AuthenticationBroadcast((auth_data, _)) => auth_data.addresses(),
+        let peer_id = match &broadcast {
+            DiscoveryMessage::AuthenticationBroadcast((auth_data, _)) => auth_data.addresses()[0]
+                .get_peer_id()
+                .expect("addresses should have peer ids"),
             _ => panic!("Expected an authentication broadcast, got {:?}", broadcast),
         };
+        service.on_discovery_message(broadcast);
+
         let ServiceActions {
             maybe_command,
             data,
-        } = service.on_discovery_message(broadcast);
+        } = service
+            .on_command(SessionCommand::Stop {
+                session_id,
+                drain: true,
+            })
+            .await
+            .unwrap();
+        assert!(maybe_command.is_none());
+        assert!(matches!(
+            &data[..],
+            [(NetworkData::Control(ControlMessage::LeavingSession(leaving, _)), DataCommand::Broadcast)]
+                if leaving.session_id == session_id && leaving.node_id == NodeIndex(0)
+        ));
+        // no more data is accepted
+        assert!(service
+            .on_user_message(2137, session_id, Channel::Main, Recipient::Everyone)
+            .is_empty());
+        assert_eq!(
+            service.send_session_data(&session_id, Channel::Main, -43),
+            Err(Error::NoSession)
+        );
+
+        let deadline = service
+            .next_drain_deadline()
+            .expect("the session should be draining");
+        let ServiceActions { maybe_command, .. } = service.finish_drained(deadline - DRAIN_MARGIN);
+        assert!(maybe_command.is_none());
+        let ServiceActions { maybe_command, .. } = service.finish_drained(deadline);
         assert_eq!(
             maybe_command,
-            Some(ConnectionCommand::AddReserved(
-                addresses.into_iter().collect()
-            ))
+            Some(ConnectionCommand::DelReserved(HashSet::from([peer_id])))
         );
-        assert_eq!(data.len(), 2);
-        assert!(data
-            .iter()
-            .any(|(_, command)| command == &DataCommand::Broadcast));
-        assert!(data
-            .iter()
-            .any(|(_, command)| matches!(command, &DataCommand::SendTo(_, _))));
+        assert!(service.next_drain_deadline().is_none());
     }
 
     #[tokio::test]
-    async fn sends_user_data() {
+    async fn reserves_restored_addresses_when_drained_session_restarts() {
         let mut service = build();
         let (validator_data, verifier) = crypto_basics(NUM_NODES).await;
         let (node_id, pen) = validator_data[0].clone();
@@ -952,16 +2197,19 @@ mod tests {
                 session_id,
                 verifier.clone(),
                 node_id,
-                pen,
+                pen.clone(),
                 None,
             ))
             .await
             .unwrap();
-        let mut other_service = build();
-        let (node_id, pen) = validator_data[1].clone();
-        let ServiceActions { data, .. } = other_service
+        let (other_node_id, other_pen) = validator_data[1].clone();
+        let ServiceActions { data, .. } = build()
             .on_command(SessionCommand::StartValidator(
-                session_id, verifier, node_id, pen, None,
+                session_id,
+                verifier.clone(),
+                other_node_id,
+                other_pen,
+                None,
             ))
             .await
             .unwrap();
@@ -969,14 +2217,887 @@ mod tests {
             (NetworkData::Meta(broadcast), DataCommand::Broadcast) => broadcast,
             _ => panic!("Expected discovery massage broadcast, got: {:?}", data[0]),
         };
-        service.on_discovery_message(broadcast);
-        let messages = service.on_user_message(2137, session_id, Recipient::Everyone);
-        assert_eq!(messages.len(), 1);
+        let addresses = match &broadcast {
+            DiscoveryMessage::AuthenticationBroadcast((auth_data, _)) => auth_data.addresses(),
+            _ => panic!("Expected an authentication broadcast, got {:?}", broadcast),
+        };
+        service.on_discovery_message(broadcast);
+        service
+            .on_command(SessionCommand::Stop {
+                session_id,
+                drain: true,
+            })
+            .await
+            .unwrap();
+
+        let ServiceActions { maybe_command, .. } = service
+            .on_command(SessionCommand::StartValidator(
+                session_id, verifier, node_id, pen, None,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            maybe_command,
+            Some(ConnectionCommand::AddReserved(
+                addresses.into_iter().collect()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn forgets_discovery_of_session_restarted_after_drain() {
+        let mut service = build();
+        let (validator_data, verifier) = crypto_basics(NUM_NODES).await;
+        let (node_id, pen) = validator_data[0].clone();
+        let session_id = SessionId(43);
+        service
+            .on_command(SessionCommand::StartValidator(
+                session_id,
+                verifier.clone(),
+                node_id,
+                pen.clone(),
+                None,
+            ))
+            .await
+            .unwrap();
+        let (other_node_id, other_pen) = validator_data[1].clone();
+        let ServiceActions { data, .. } = build()
+            .on_command(SessionCommand::StartValidator(
+                session_id,
+                verifier.clone(),
+                other_node_id,
+                other_pen,
+                None,
+            ))
+            .await
+            .unwrap();
+        let broadcast = match data[0].clone() {
+            (NetworkData::Meta(broadcast), DataCommand::Broadcast) => broadcast,
+            _ => panic!("Expected discovery massage broadcast, got: {:?}", data[0]),
+        };
+        service.on_discovery_message(broadcast);
+        service
+            .on_command(SessionCommand::Stop {
+                session_id,
+                drain: true,
+            })
+            .await
+            .unwrap();
+        let deadline = service
+            .next_drain_deadline()
+            .expect("the session should be draining");
+        service.finish_drained(deadline);
+        assert!(service.discovery_snapshots.is_empty());
+
+        let ServiceActions { maybe_command, .. } = service
+            .on_command(SessionCommand::StartValidator(
+                session_id, verifier, node_id, pen, None,
+            ))
+            .await
+            .unwrap();
+        assert!(maybe_command.is_none());
+    }
+
+    #[tokio::test]
+    async fn reports_active_sessions() {
+        let mut service = build();
+        let (validator_data, verifier) = crypto_basics(NUM_NODES).await;
+        let (node_id, pen) = validator_data[0].clone();
+        let session_ids = [SessionId(43), SessionId(44)];
+        for session_id in session_ids {
+            service
+                .on_command(SessionCommand::StartValidator(
+                    session_id,
+                    verifier.clone(),
+                    node_id,
+                    pen.clone(),
+                    None,
+                ))
+                .await
+                .unwrap();
+        }
+        assert_eq!(service.active_sessions(), session_ids.to_vec());
+
+        service
+            .on_command(SessionCommand::Stop {
+                session_id: session_ids[0],
+                drain: false,
+            })
+            .await
+            .unwrap();
+        assert_eq!(service.active_sessions(), vec![session_ids[1]]);
+
+        // draining sessions are still active until they finish
+        service
+            .on_command(SessionCommand::Stop {
+                session_id: session_ids[1],
+                drain: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!(service.active_sessions(), vec![session_ids[1]]);
+        let deadline = service
+            .next_drain_deadline()
+            .expect("the session should be draining");
+        service.finish_drained(deadline);
+        assert!(service.active_sessions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn recycles_known_peer() {
+        let mut service = build();
+        let (validator_data, verifier) = crypto_basics(NUM_NODES).await;
+        let (node_id, pen) = validator_data[0].clone();
+        let session_id = SessionId(43);
+        service
+            .on_command(SessionCommand::StartValidator(
+                session_id,
+                verifier.clone(),
+                node_id,
+                pen,
+                None,
+            ))
+            .await
+            .unwrap();
+        let (node_id, pen) = validator_data[1].clone();
+        let ServiceActions { data, .. } = build()
+            .on_command(SessionCommand::StartValidator(
+                session_id, verifier, node_id, pen, None,
+            ))
+            .await
+            .unwrap();
+        let broadcast = match data[0].clone() {
+            (NetworkData::Meta(broadcast), DataCommand::Broadcast) => broadcast,
+            _ => panic!("Expected discovery massage broadcast, got: {:?}", data[0]),
+        };
+        let addresses = match &broadcast {
+            DiscoveryMessage::AuthenticationBroadcast((auth_data, _)) => auth_data.addresses(),
+            _ => panic!("Expected an authentication broadcast, got {:?}", broadcast),
+        };
+        let peer_id = addresses[0]
+            .get_peer_id()
+            .expect("addresses should have peer ids");
+
+        // nothing to do before the peer authenticates
+        let ServiceActions { maybe_command, .. } = service
+            .on_command(SessionCommand::Recycle(peer_id.clone()))
+            .await
+            .unwrap();
+        assert!(maybe_command.is_none());
+
+        service.on_discovery_message(broadcast);
+        let ServiceActions {
+            maybe_command,
+            data,
+        } = service
+            .on_command(SessionCommand::Recycle(peer_id))
+            .await
+            .unwrap();
+        assert_eq!(
+            maybe_command,
+            Some(ConnectionCommand::Recycle(addresses.into_iter().collect()))
+        );
+        assert!(data.is_empty());
+    }
+
+    #[tokio::test]
+    async fn routes_data_to_sessions() {
+        let mut service = build();
+        let (validator_data, verifier) = crypto_basics(NUM_NODES).await;
+        let (node_id, pen) = validator_data[0].clone();
+        let session_ids = [SessionId(43), SessionId(44)];
+        let mut data_from_network = Vec::new();
+        for session_id in session_ids {
+            let (result_for_user, result_from_service) = oneshot::channel();
+            service
+                .on_command(SessionCommand::StartValidator(
+                    session_id,
+                    verifier.clone(),
+                    node_id,
+                    pen.clone(),
+                    Some(result_for_user),
+                ))
+                .await
+                .unwrap();
+            data_from_network.push(result_from_service.await.unwrap().0);
+        }
+        assert_eq!(
+            service.send_session_data(&session_ids[1], Channel::Main, -44),
+            Ok(())
+        );
+        assert_eq!(
+            service.send_session_data(&session_ids[0], Channel::Main, -43),
+            Ok(())
+        );
+        assert_eq!(
+            service.send_session_data(&session_ids[1], Channel::Main, 44),
+            Ok(())
+        );
+        assert_eq!(data_from_network[0].next().await, Some(-43));
+        assert_eq!(data_from_network[1].next().await, Some(-44));
+        assert_eq!(data_from_network[1].next().await, Some(44));
+        assert!(data_from_network[0].try_next().is_err());
+        assert!(data_from_network[1].try_next().is_err());
+    }
+
+    #[tokio::test]
+    async fn routes_data_to_channels() {
+        let mut service = build();
+        let (validator_data, verifier) = crypto_basics(NUM_NODES).await;
+        let (node_id, pen) = validator_data[0].clone();
+        let session_id = SessionId(43);
+        let (result_for_user, result_from_service) = oneshot::channel();
+        service
+            .on_command(SessionCommand::StartValidator(
+                session_id,
+                verifier,
+                node_id,
+                pen,
+                Some(result_for_user),
+            ))
+            .await
+            .unwrap();
+        let (mut main_data, _) = result_from_service.await.unwrap();
+        assert_eq!(
+            service.send_session_data(&session_id, Channel::Requests, -43),
+            Err(Error::NoSession)
+        );
+        let (result_for_user, result_from_service) = oneshot::channel();
+        service
+            .on_command(SessionCommand::OpenChannel(
+                session_id,
+                Channel::Requests,
+                result_for_user,
+            ))
+            .await
+            .unwrap();
+        let (mut request_data, _) = result_from_service.await.unwrap();
+        assert_eq!(
+            service.send_session_data(&session_id, Channel::Requests, -43),
+            Ok(())
+        );
+        assert_eq!(
+            service.send_session_data(&session_id, Channel::Main, 43),
+            Ok(())
+        );
+        assert_eq!(main_data.next().await, Some(43));
+        assert_eq!(request_data.next().await, Some(-43));
+        assert!(main_data.try_next().is_err());
+        assert!(request_data.try_next().is_err());
+
+        // channels only exist in the sessions we are a validator in
+        let (result_for_user, result_from_service) = oneshot::channel();
+        service
+            .on_command(SessionCommand::OpenChannel(
+                SessionId(44),
+                Channel::Requests,
+                result_for_user,
+            ))
+            .await
+            .unwrap();
+        assert!(result_from_service.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn handles_broadcast() {
+        let mut service = build();
+        let (validator_data, verifier) = crypto_basics(NUM_NODES).await;
+        let (node_id, pen) = validator_data[0].clone();
+        let session_id = SessionId(43);
+        service
+            .on_command(SessionCommand::StartValidator(
+                session_id,
+                verifier.clone(),
+                node_id,
+                pen,
+                None,
+            ))
+            .await
+            .unwrap();
+        let mut other_service = build();
+        let (node_id, pen) = validator_data[1].clone();
+        let ServiceActions { data, .. } = other_service
+            .on_command(SessionCommand::StartValidator(
+                session_id, verifier, node_id, pen, None,
+            ))
+            .await
+            .unwrap();
+        let broadcast = match data[0].clone() {
+            (NetworkData::Meta(broadcast), DataCommand::Broadcast) => broadcast,
+            _ => panic!("Expected discovery massage broadcast, got: {:?}", data[0]),
+        };
+        let addresses = match &broadcast {
+            DiscoveryMessage::AuthenticationBroadcast((auth_data, _)) => auth_data.addresses(),
+            _ => panic!("Expected an authentication broadcast, got {:?}", broadcast),
+        };
+        let ServiceActions {
+            maybe_command,
+            data,
+        } = service.on_discovery_message(broadcast);
+        assert_eq!(
+            maybe_command,
+            Some(ConnectionCommand::AddReserved(
+                addresses.into_iter().collect()
+            ))
+        );
+        assert_eq!(data.len(), 2);
+        assert!(data
+            .iter()
+            .any(|(_, command)| command == &DataCommand::Broadcast));
+        assert!(data
+            .iter()
+            .any(|(_, command)| matches!(command, &DataCommand::SendTo(_, _))));
+    }
+
+    #[tokio::test]
+    async fn prepared_session_keeps_connections_on_start() {
+        let mut service = build();
+        let (validator_data, verifier) = crypto_basics(NUM_NODES).await;
+        let (node_id, pen) = validator_data[0].clone();
+        let session_id = SessionId(43);
+        service
+            .on_command(SessionCommand::Prepare(
+                session_id,
+                verifier.clone(),
+                node_id,
+                pen.clone(),
+            ))
+            .await
+            .unwrap();
+        let (other_node_id, other_pen) = validator_data[1].clone();
+        let ServiceActions { data, .. } = build()
+            .on_command(SessionCommand::Prepare(
+                session_id,
+                verifier.clone(),
+                other_node_id,
+                other_pen,
+            ))
+            .await
+            .unwrap();
+        let broadcast = match data[0].clone() {
+            (NetworkData::Meta(broadcast), DataCommand::Broadcast) => broadcast,
+            _ => panic!("Expected discovery massage broadcast, got: {:?}", data[0]),
+        };
+        let ServiceActions { maybe_command, .. } = service.on_discovery_message(broadcast);
+        assert!(matches!(
+            maybe_command,
+            Some(ConnectionCommand::AddReserved(_))
+        ));
+
+        let (result_for_user, result_from_service) = oneshot::channel();
+        let ServiceActions { maybe_command, .. } = service
+            .on_command(SessionCommand::StartValidator(
+                session_id,
+                verifier,
+                node_id,
+                pen,
+                Some(result_for_user),
+            ))
+            .await
+            .unwrap();
+        // the connection made while preparing is neither dropped nor made anew
+        assert!(maybe_command.is_none());
+        let (_data_from_network, peers) = result_from_service.await.unwrap();
+        assert_eq!(peers.get(), vec![other_node_id]);
+    }
+
+    #[tokio::test]
+    async fn tracks_session_peers() {
+        let mut service = build();
+        let (validator_data, verifier) = crypto_basics(NUM_NODES).await;
+        let (node_id, pen) = validator_data[0].clone();
+        let session_id = SessionId(43);
+        let (result_for_user, result_from_service) = oneshot::channel();
+        service
+            .on_command(SessionCommand::StartValidator(
+                session_id,
+                verifier.clone(),
+                node_id,
+                pen,
+                Some(result_for_user),
+            ))
+            .await
+            .unwrap();
+        let (_data_from_network, peers) = result_from_service.await.unwrap();
+        assert!(peers.get().is_empty());
+        assert_eq!(peers.node_count(), NodeCount(NUM_NODES));
+        for (node_id, pen) in validator_data[1..3].iter().cloned().rev() {
+            let ServiceActions { data, .. } = build()
+                .on_command(SessionCommand::StartValidator(
+                    session_id,
+                    verifier.clone(),
+                    node_id,
+                    pen,
+                    None,
+                ))
+                .await
+                .unwrap();
+            let broadcast = match data[0].clone() {
+                (NetworkData::Meta(broadcast), DataCommand::Broadcast) => broadcast,
+                _ => panic!("Expected discovery massage broadcast, got: {:?}", data[0]),
+            };
+            service.on_discovery_message(broadcast);
+        }
+        assert_eq!(peers.get(), vec![NodeIndex(1), NodeIndex(2)]);
+        service
+            .on_command(SessionCommand::Stop {
+                session_id,
+                drain: false,
+            })
+            .await
+            .unwrap();
+        assert!(peers.get().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reports_only_connected_session_peers() {
+        let connected = Arc::new(Mutex::new(HashSet::new()));
+        let is_connected = {
+            let connected = connected.clone();
+            Arc::new(move |peer_id: &MockPeerId| connected.lock().contains(peer_id))
+        };
+        let mut service = build().with_connectivity(is_connected);
+        let (validator_data, verifier) = crypto_basics(NUM_NODES).await;
+        let (node_id, pen) = validator_data[0].clone();
+        let session_id = SessionId(43);
+        let (result_for_user, result_from_service) = oneshot::channel();
+        service
+            .on_command(SessionCommand::StartValidator(
+                session_id,
+                verifier.clone(),
+                node_id,
+                pen,
+                Some(result_for_user),
+            ))
+            .await
+            .unwrap();
+        let (_data_from_network, peers) = result_from_service.await.unwrap();
+        let (node_id, pen) = validator_data[1].clone();
+        let ServiceActions { data, .. } = build()
+            .on_command(SessionCommand::StartValidator(
+                session_id,
+                verifier.clone(),
+                node_id,
+                pen,
+                None,
+            ))
+            .await
+            .unwrap();
+        let broadcast = match data[0].clone() {
+            (NetworkData::Meta(broadcast), DataCommand::Broadcast) => broadcast,
+            _ => panic!("Expected discovery massage broadcast, got: {:?}", data[0]),
+        };
+        service.on_discovery_message(broadcast);
+        // authenticated, but not connected yet
+        assert!(peers.get().is_empty());
+        let peer_id = service
+            .sessions
+            .get(&session_id)
+            .and_then(|session| session.handler.peer_id(&NodeIndex(1)))
+            .expect("the peer should be authenticated");
+        connected.lock().insert(peer_id.clone());
+        assert_eq!(peers.get(), vec![NodeIndex(1)]);
+        connected.lock().remove(&peer_id);
+        assert!(peers.get().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stops_routing_to_peer_leaving_session() {
+        let mut service = build();
+        let (validator_data, verifier) = crypto_basics(NUM_NODES).await;
+        let (node_id, pen) = validator_data[0].clone();
+        let session_id = SessionId(43);
+        let (result_for_user, result_from_service) = oneshot::channel();
+        service
+            .on_command(SessionCommand::StartValidator(
+                session_id,
+                verifier.clone(),
+                node_id,
+                pen,
+                Some(result_for_user),
+            ))
+            .await
+            .unwrap();
+        let (_data_from_network, peers) = result_from_service.await.unwrap();
+        let mut peer_ids = Vec::new();
+        let mut other_services = Vec::new();
+        for (node_id, pen) in validator_data[1..3].iter().cloned() {
+            let mut other_service = build();
+            peer_ids.push(other_service.network_identity.identity().1);
+            let ServiceActions { data, .. } = other_service
+                .on_command(SessionCommand::StartValidator(
+                    session_id,
+                    verifier.clone(),
+                    node_id,
+                    pen,
+                    None,
+                ))
+                .await
+                .unwrap();
+            let broadcast = match data[0].clone() {
+                (NetworkData::Meta(broadcast), DataCommand::Broadcast) => broadcast,
+                _ => panic!("Expected discovery massage broadcast, got: {:?}", data[0]),
+            };
+            service.on_discovery_message(broadcast);
+            other_services.push(other_service);
+        }
+        assert_eq!(peers.get(), vec![NodeIndex(1), NodeIndex(2)]);
+
+        // nobody can make others stop routing to a node, except the node itself
+        let leaving = Leaving {
+            session_id,
+            node_id: NodeIndex(1),
+        };
+        let forged = validator_data[2].1.sign(&leaving.signed_payload()).await;
+        let ServiceActions { maybe_command, .. } =
+            service.on_control_message(ControlMessage::LeavingSession(leaving, forged));
+        assert!(maybe_command.is_none());
+        assert_eq!(peers.get(), vec![NodeIndex(1), NodeIndex(2)]);
+
+        let ServiceActions { data, .. } = other_services[0]
+            .on_command(SessionCommand::Stop {
+                session_id,
+                drain: false,
+            })
+            .await
+            .unwrap();
+        let leaving = match data.as_slice() {
+            [(NetworkData::Control(leaving), DataCommand::Broadcast)] => leaving.clone(),
+            _ => panic!("Expected a leaving broadcast, got: {:?}", data),
+        };
+        let ServiceActions {
+            maybe_command,
+            data,
+        } = service.on_control_message(leaving.clone());
+        assert!(data.is_empty());
+        assert_eq!(
+            maybe_command,
+            Some(ConnectionCommand::DelReserved(HashSet::from([peer_ids[0]])))
+        );
+        assert_eq!(peers.get(), vec![NodeIndex(2)]);
+        // the data of the session is no longer routed to the peer that left
+        let messages =
+            service.on_user_message(2137, session_id, Channel::Main, Recipient::Everyone);
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(
+            &messages[0],
+            (NetworkData::Data(_, _, Channel::Main), DataCommand::SendTo(peer_id, _)) if peer_id == &peer_ids[1]
+        ));
+        // leaving again changes nothing
+        let ServiceActions { maybe_command, .. } = service.on_control_message(leaving);
+        assert!(maybe_command.is_none());
+    }
+
+    #[tokio::test]
+    async fn updates_connections_when_authority_swapped() {
+        let mut service = build();
+        let (validator_data, verifier) = crypto_basics(NUM_NODES).await;
+        let (node_id, pen) = validator_data[0].clone();
+        let session_id = SessionId(43);
+        let (result_for_user, result_from_service) = oneshot::channel();
+        service
+            .on_command(SessionCommand::StartValidator(
+                session_id,
+                verifier.clone(),
+                node_id,
+                pen,
+                Some(result_for_user),
+            ))
+            .await
+            .unwrap();
+        let (_data_from_network, peers) = result_from_service.await.unwrap();
+        let mut peer_ids = Vec::new();
+        for (node_id, pen) in validator_data[1..3].iter().cloned() {
+            let mut other_service = build();
+            peer_ids.push(other_service.network_identity.identity().1);
+            let ServiceActions { data, .. } = other_service
+                .on_command(SessionCommand::StartValidator(
+                    session_id,
+                    verifier.clone(),
+                    node_id,
+                    pen,
+                    None,
+                ))
+                .await
+                .unwrap();
+            let broadcast = match data[0].clone() {
+                (NetworkData::Meta(broadcast), DataCommand::Broadcast) => broadcast,
+                _ => panic!("Expected discovery massage broadcast, got: {:?}", data[0]),
+            };
+            service.on_discovery_message(broadcast);
+        }
+        assert_eq!(peers.get(), vec![NodeIndex(1), NodeIndex(2)]);
+
+        // the authority of node 2 gets replaced
+        let (new_validator_data, _) = crypto_basics(NUM_NODES).await;
+        let (new_node_id, new_pen) = new_validator_data[2].clone();
+        let mut authorities: Vec<_> = validator_data
+            .iter()
+            .map(|(_, pen)| pen.authority_id())
+            .collect();
+        authorities[new_node_id.0] = new_pen.authority_id();
+        let new_verifier = AuthorityVerifier::new(authorities);
+        let ServiceActions {
+            maybe_command,
+            data,
+        } = service
+            .on_command(SessionCommand::UpdateAuthorities(
+                session_id,
+                new_verifier.clone(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            maybe_command,
+            Some(ConnectionCommand::DelReserved(HashSet::from([peer_ids[1]])))
+        );
+        assert_eq!(peers.get(), vec![NodeIndex(1)]);
+        // the new authority gets discovered
+        assert!(matches!(
+            data.as_slice(),
+            [(NetworkData::Meta(_), DataCommand::Broadcast)]
+        ));
+        let mut new_service = build();
+        let new_peer_id = new_service.network_identity.identity().1;
+        let ServiceActions { data, .. } = new_service
+            .on_command(SessionCommand::StartValidator(
+                session_id,
+                new_verifier,
+                new_node_id,
+                new_pen,
+                None,
+            ))
+            .await
+            .unwrap();
+        let broadcast = match data[0].clone() {
+            (NetworkData::Meta(broadcast), DataCommand::Broadcast) => broadcast,
+            _ => panic!("Expected discovery massage broadcast, got: {:?}", data[0]),
+        };
+        let ServiceActions { maybe_command, .. } = service.on_discovery_message(broadcast);
+        assert!(matches!(
+            maybe_command,
+            Some(ConnectionCommand::AddReserved(addresses))
+                if addresses.iter().all(|address| address.get_peer_id() == Some(new_peer_id))
+        ));
+        assert_eq!(peers.get(), vec![NodeIndex(1), NodeIndex(2)]);
+
+        // the consensus of the session cannot follow a change in the number of authorities
+        let (_, bigger_verifier) = crypto_basics(NUM_NODES + 1).await;
+        assert!(service
+            .on_command(SessionCommand::UpdateAuthorities(
+                session_id,
+                bigger_verifier
+            ))
+            .await
+            .is_err());
+        assert_eq!(peers.get(), vec![NodeIndex(1), NodeIndex(2)]);
+        assert_eq!(peers.node_count(), NodeCount(NUM_NODES));
+    }
+
+    #[tokio::test]
+    async fn sends_user_data() {
+        let mut service = build();
+        let (validator_data, verifier) = crypto_basics(NUM_NODES).await;
+        let (node_id, pen) = validator_data[0].clone();
+        let session_id = SessionId(43);
+        service
+            .on_command(SessionCommand::StartValidator(
+                session_id,
+                verifier.clone(),
+                node_id,
+                pen,
+                None,
+            ))
+            .await
+            .unwrap();
+        let mut other_service = build();
+        let (node_id, pen) = validator_data[1].clone();
+        let ServiceActions { data, .. } = other_service
+            .on_command(SessionCommand::StartValidator(
+                session_id, verifier, node_id, pen, None,
+            ))
+            .await
+            .unwrap();
+        let broadcast = match data[0].clone() {
+            (NetworkData::Meta(broadcast), DataCommand::Broadcast) => broadcast,
+            _ => panic!("Expected discovery massage broadcast, got: {:?}", data[0]),
+        };
+        service.on_discovery_message(broadcast);
+        let messages =
+            service.on_user_message(2137, session_id, Channel::Main, Recipient::Everyone);
+        assert_eq!(messages.len(), 1);
         let (network_data, data_command) = &messages[0];
         assert!(matches!(
             data_command,
             DataCommand::SendTo(_, Protocol::Validator)
         ));
-        assert_eq!(network_data, &NetworkData::Data(2137, session_id));
+        assert_eq!(
+            network_data,
+            &NetworkData::Data(Arc::new(2137), session_id, Channel::Main)
+        );
+    }
+
+    static PAYLOAD_CLONES: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Debug, PartialEq, Eq, Encode, Decode)]
+    struct CloneCounted(Vec<u8>);
+
+    impl Clone for CloneCounted {
+        fn clone(&self) -> Self {
+            PAYLOAD_CLONES.fetch_add(1, Ordering::SeqCst);
+            CloneCounted(self.0.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn broadcasts_user_data_without_copying_it() {
+        let mut service: Service<_, CloneCounted> = Service::new(
+            MockNetworkIdentity::new(),
+            Config::new(MAINTENANCE_PERIOD, DISCOVERY_PERIOD, INITIAL_DELAY),
+        );
+        let (validator_data, verifier) = crypto_basics(NUM_NODES).await;
+        let session_id = SessionId(43);
+        let (node_id, pen) = validator_data[0].clone();
+        service
+            .on_command(SessionCommand::StartValidator(
+                session_id,
+                verifier.clone(),
+                node_id,
+                pen,
+                None,
+            ))
+            .await
+            .unwrap();
+        for (node_id, pen) in validator_data[1..3].iter().cloned() {
+            let mut other_service = build();
+            let ServiceActions { data, .. } = other_service
+                .on_command(SessionCommand::StartValidator(
+                    session_id,
+                    verifier.clone(),
+                    node_id,
+                    pen,
+                    None,
+                ))
+                .await
+                .unwrap();
+            let broadcast = match data[0].clone() {
+                (NetworkData::Meta(broadcast), DataCommand::Broadcast) => broadcast,
+                _ => panic!("Expected discovery massage broadcast, got: {:?}", data[0]),
+            };
+            service.on_discovery_message(broadcast);
+        }
+        let payload = CloneCounted(vec![7; 1 << 20]);
+        let messages = service.on_user_message(
+            payload.clone(),
+            session_id,
+            Channel::Main,
+            Recipient::Everyone,
+        );
+        assert_eq!(PAYLOAD_CLONES.load(Ordering::SeqCst), 1);
+        assert_eq!(messages.len(), 2);
+        let shared: Vec<_> = messages
+            .into_iter()
+            .map(|message| match message {
+                (NetworkData::Data(data, _, Channel::Main), _) => data,
+                message => panic!("Expected user data, got: {:?}", message),
+            })
+            .collect();
+        assert!(Arc::ptr_eq(&shared[0], &shared[1]));
+        assert_eq!(shared[0].as_ref(), &payload);
+        assert_eq!(shared[0].encode(), payload.encode());
+        assert_eq!(PAYLOAD_CLONES.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn broadcasts_updated_addresses() {
+        let mut service = build();
+        let (validator_data, verifier) = crypto_basics(NUM_NODES).await;
+        let (node_id, pen) = validator_data[0].clone();
+        let session_id = SessionId(43);
+        let ServiceActions { data, .. } = service
+            .on_command(SessionCommand::StartValidator(
+                session_id, verifier, node_id, pen, None,
+            ))
+            .await
+            .unwrap();
+        let old_sequence = match &data[0] {
+            (
+                NetworkData::Meta(DiscoveryMessage::AuthenticationBroadcast((auth_data, _))),
+                DataCommand::Broadcast,
+            ) => auth_data.sequence(),
+            _ => panic!("Expected an authentication broadcast, got: {:?}", data[0]),
+        };
+        let (_, peer_id) = service.network_identity.identity();
+        let new_addresses: Vec<_> = (0..2)
+            .map(|_| MockMultiaddress::random_with_id(peer_id))
+            .collect();
+        let ServiceActions {
+            maybe_command,
+            data,
+        } = service
+            .on_command(SessionCommand::UpdateAddresses(new_addresses.clone()))
+            .await
+            .unwrap();
+        assert!(maybe_command.is_none());
+        assert_eq!(data.len(), 1);
+        match &data[0] {
+            (
+                NetworkData::Meta(DiscoveryMessage::AuthenticationBroadcast((auth_data, _))),
+                DataCommand::Broadcast,
+            ) => {
+                assert_eq!(auth_data.addresses(), new_addresses);
+                assert!(auth_data.sequence() > old_sequence);
+            }
+            _ => panic!("Expected an authentication broadcast, got: {:?}", data[0]),
+        }
+        // addresses of some other peer cannot be announced
+        let foreign_addresses = vec![MockMultiaddress::random_with_id(MockPeerId::random())];
+        assert!(service
+            .on_command(SessionCommand::UpdateAddresses(foreign_addresses))
+            .await
+            .is_err());
+        // and the failed update changes nothing
+        assert_eq!(service.addresses(), new_addresses);
+    }
+
+    #[tokio::test]
+    async fn announces_at_most_max_addresses() {
+        let max_addresses = 4;
+        let mut service = Service::<_, i32>::new(
+            MockNetworkIdentity::new(),
+            Config::new(MAINTENANCE_PERIOD, DISCOVERY_PERIOD, INITIAL_DELAY).with_address_policy(
+                AddressPolicy {
+                    max_addresses,
+                    ..AddressPolicy::default()
+                },
+            ),
+        );
+        let (_, peer_id) = service.network_identity.identity();
+        let addresses: Vec<_> = (0..20)
+            .map(|_| MockMultiaddress::random_with_id(peer_id))
+            .collect();
+        service
+            .on_command(SessionCommand::UpdateAddresses(addresses.clone()))
+            .await
+            .unwrap();
+        let (validator_data, verifier) = crypto_basics(NUM_NODES).await;
+        let (node_id, pen) = validator_data[0].clone();
+        let ServiceActions { data, .. } = service
+            .on_command(SessionCommand::StartValidator(
+                SessionId(43),
+                verifier,
+                node_id,
+                pen,
+                None,
+            ))
+            .await
+            .unwrap();
+        match &data[0] {
+            (
+                NetworkData::Meta(DiscoveryMessage::AuthenticationBroadcast((auth_data, _))),
+                DataCommand::Broadcast,
+            ) => assert_eq!(auth_data.addresses(), addresses[..max_addresses].to_vec()),
+            _ => panic!("Expected an authentication broadcast, got: {:?}", data[0]),
+        }
     }
 }
@@ -1,13 +1,17 @@
-use std::collections::HashMap;
+use std::{
+    cmp::max,
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use codec::Encode;
+use log::debug;
 
 use crate::{
     abft::NodeCount,
-    crypto::{AuthorityPen, AuthorityVerifier},
+    crypto::{AuthorityPen, AuthorityVerifier, Signature},
     network::{
-        manager::{AuthData, Authentication},
-        Multiaddress, PeerId,
+        manager::{AuthData, Authentication, ControlMessage, Leaving, LEGACY_SEQUENCE},
+        AddressPolicy, Multiaddress, PeerId,
     },
     NodeIndex, SessionId,
 };
@@ -38,6 +42,7 @@ pub struct Handler<M: Multiaddress> {
     own_peer_id: M::PeerId,
     authority_index_and_pen: Option<(NodeIndex, AuthorityPen)>,
     authority_verifier: AuthorityVerifier,
+    sequence: u64,
 }
 
 #[derive(Debug)]
@@ -50,6 +55,20 @@ pub enum HandlerError {
     /// or the addresses contain multiple libp2p PeerIds.
     NoP2pAddresses,
     MultiplePeerIds,
+    /// Returned when the authorities of a session are replaced by a set of a different size,
+    /// which the consensus of a running session cannot follow.
+    NodeCountChange,
+}
+
+/// Reasons for rejecting an authentication.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    /// The authentication is for a different session.
+    WrongSession { expected: SessionId, got: SessionId },
+    /// The creator of the authentication is not a member of the session.
+    UnknownNode(NodeIndex),
+    /// The signature does not match the data and the creator.
+    BadSignature,
 }
 
 enum CommonPeerId<PID: PeerId> {
@@ -102,10 +121,32 @@ fn retrieve_peer_id<M: Multiaddress>(addresses: &[M]) -> Result<M::PeerId, Handl
     get_common_peer_id(addresses).ok_or(HandlerError::MultiplePeerIds)
 }
 
+/// Checks whether the addresses can be used for creating authentications, exactly like creating
+/// or updating a handler with them would.
+pub fn check_addresses<M: Multiaddress>(addresses: &[M]) -> Result<(), HandlerError> {
+    let addresses: Vec<_> = addresses
+        .iter()
+        .filter(|address| address.get_peer_id().is_some())
+        .cloned()
+        .collect();
+    retrieve_peer_id(&addresses).map(|_| ())
+}
+
+/// The lowest sequence number for a new authentication. Based on the current time, so that
+/// authentications created after a restart are still newer than the ones from before it.
+fn minimal_sequence() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+        .max(LEGACY_SEQUENCE + 1)
+}
+
 async fn construct_session_info<M: Multiaddress>(
     authority_index_and_pen: &Option<(NodeIndex, AuthorityPen)>,
     session_id: SessionId,
     addresses: Vec<M>,
+    sequence: u64,
 ) -> Result<(SessionInfo<M>, M::PeerId), HandlerError> {
     let addresses: Vec<_> = addresses
         .into_iter()
@@ -118,8 +159,9 @@ async fn construct_session_info<M: Multiaddress>(
             addresses,
             node_id: *node_index,
             session_id,
+            sequence,
         };
-        let signature = authority_pen.sign(&auth_data.encode()).await;
+        let signature = authority_pen.sign(&auth_data.signed_payload()).await;
         return Ok((SessionInfo::OwnAuthentication((auth_data, signature)), peer));
     }
     Ok((SessionInfo::SessionId(session_id), peer))
@@ -133,9 +175,27 @@ impl<M: Multiaddress> Handler<M> {
         authority_verifier: AuthorityVerifier,
         session_id: SessionId,
         addresses: Vec<M>,
+    ) -> Result<Handler<M>, HandlerError> {
+        Self::new_with_sequence(
+            authority_index_and_pen,
+            authority_verifier,
+            session_id,
+            addresses,
+            minimal_sequence(),
+        )
+        .await
+    }
+
+    async fn new_with_sequence(
+        authority_index_and_pen: Option<(NodeIndex, AuthorityPen)>,
+        authority_verifier: AuthorityVerifier,
+        session_id: SessionId,
+        addresses: Vec<M>,
+        sequence: u64,
     ) -> Result<Handler<M>, HandlerError> {
         let (session_info, own_peer_id) =
-            construct_session_info(&authority_index_and_pen, session_id, addresses).await?;
+            construct_session_info(&authority_index_and_pen, session_id, addresses, sequence)
+                .await?;
         Ok(Handler {
             peers_by_node: HashMap::new(),
             authentications: HashMap::new(),
@@ -143,6 +203,7 @@ impl<M: Multiaddress> Handler<M> {
             authority_index_and_pen,
             authority_verifier,
             own_peer_id,
+            sequence,
         })
     }
 
@@ -173,7 +234,28 @@ impl<M: Multiaddress> Handler<M> {
         }
     }
 
+    /// Returns a signed message announcing that we leave the session, if we are a validator in it.
+    pub async fn leaving(&self) -> Option<ControlMessage> {
+        let (node_id, authority_pen) = self.authority_index_and_pen.as_ref()?;
+        let leaving = Leaving {
+            session_id: self.session_id(),
+            node_id: *node_id,
+        };
+        let signature = authority_pen.sign(&leaving.signed_payload()).await;
+        Some(ControlMessage::LeavingSession(leaving, signature))
+    }
+
+    /// Checks whether the node left this session, i.e. whether the message is for this session and
+    /// correctly signed by the node that leaves.
+    pub fn verify_leaving(&self, leaving: &Leaving, signature: &Signature) -> bool {
+        leaving.session_id == self.session_id()
+            && self
+                .authority_verifier
+                .verify(&leaving.signed_payload(), signature, leaving.node_id)
+    }
+
     /// Returns a vector of indices of nodes for which the handler has no authentication.
+    /// Our own index is never reported, so an empty result means the whole committee is known.
     pub fn missing_nodes(&self) -> Vec<NodeIndex> {
         let node_count = self.node_count().0;
         if self.peers_by_node.len() + 1 == node_count {
@@ -187,13 +269,39 @@ impl<M: Multiaddress> Handler<M> {
             .collect()
     }
 
+    /// Checks whether the authentication is for this session and correctly signed by one of its
+    /// members, without using it in any way.
+    pub fn verify_authentication(
+        &self,
+        authentication: &Authentication<M>,
+    ) -> Result<(), AuthError> {
+        let (auth_data, signature) = authentication;
+        if auth_data.session_id != self.session_id() {
+            return Err(AuthError::WrongSession {
+                expected: self.session_id(),
+                got: auth_data.session_id,
+            });
+        }
+        if auth_data.node_id.0 >= self.node_count().0 {
+            return Err(AuthError::UnknownNode(auth_data.node_id));
+        }
+        if !self.authority_verifier.verify(
+            &auth_data.signed_payload(),
+            signature,
+            auth_data.node_id,
+        ) {
+            return Err(AuthError::BadSignature);
+        }
+        Ok(())
+    }
+
     /// Verifies the authentication, uses it to update mappings, and returns whether we should
     /// remain connected to the multiaddresses.
     pub fn handle_authentication(&mut self, authentication: Authentication<M>) -> bool {
         if authentication.0.session_id != self.session_id() {
             return false;
         }
-        let (auth_data, signature) = &authentication;
+        let (auth_data, _) = &authentication;
 
         // The auth is completely useless if it doesn't have a consistent PeerId.
         let peer_id = match get_common_peer_id(&auth_data.addresses) {
@@ -203,14 +311,14 @@ impl<M: Multiaddress> Handler<M> {
         if peer_id == self.own_peer_id {
             return false;
         }
-        if !self
-            .authority_verifier
-            .verify(&auth_data.encode(), signature, auth_data.node_id)
-        {
-            // This might be an authentication for a key that has been changed, but we are not yet
-            // aware of the change.
-            if let Some(auth_pair) = self.authentications.get_mut(&peer_id) {
-                auth_pair.1 = Some(authentication.clone());
+        if let Err(e) = self.verify_authentication(&authentication) {
+            debug!(target: "aleph-network", "Rejecting authentication from node {:?}: {:?}.", auth_data.node_id, e);
+            if e == AuthError::BadSignature {
+                // This might be an authentication for a key that has been changed, but we are
+                // not yet aware of the change.
+                if let Some(auth_pair) = self.authentications.get_mut(&peer_id) {
+                    auth_pair.1 = Some(authentication.clone());
+                }
             }
             return false;
         }
@@ -225,16 +333,63 @@ impl<M: Multiaddress> Handler<M> {
         self.peers_by_node.get(node_id).cloned()
     }
 
+    /// Forgets the authentication of the node, e.g. because it left the session. Returns its
+    /// PeerId, if it was known.
+    pub fn forget_peer(&mut self, node_id: &NodeIndex) -> Option<M::PeerId> {
+        let peer_id = self.peers_by_node.remove(node_id)?;
+        self.authentications.remove(&peer_id);
+        Some(peer_id)
+    }
+
     /// Returns maping from NodeIndex to PeerId
     pub fn peers(&self) -> HashMap<NodeIndex, M::PeerId> {
         self.peers_by_node.clone()
     }
 
+    /// Returns the addresses of the peer from its authentication in this session, filtered
+    /// according to the policy. Empty if the peer is unknown.
+    pub fn peer_addresses(&self, peer_id: &M::PeerId, policy: &AddressPolicy) -> Vec<M> {
+        self.authentications
+            .get(peer_id)
+            .map(|((auth_data, _), _)| auth_data.validate_addresses(policy))
+            .unwrap_or_default()
+    }
+
+    /// Updates only the set of own addresses, keeping the keychain.
+    /// Own authentication will be regenerated with a higher sequence number.
+    pub async fn update_addresses(&mut self, addresses: Vec<M>) -> Result<Vec<M>, HandlerError> {
+        self.update(
+            self.authority_index_and_pen.clone(),
+            self.authority_verifier.clone(),
+            addresses,
+        )
+        .await
+    }
+
+    /// Replaces the authorities of the session, keeping our own keychain. Their number cannot
+    /// change, as the consensus of a running session depends on it.
+    /// If successful returns a set of addresses that we should be connected to, like `update`.
+    pub async fn update_authorities(
+        &mut self,
+        authority_verifier: AuthorityVerifier,
+        addresses: Vec<M>,
+    ) -> Result<Vec<M>, HandlerError> {
+        if authority_verifier.node_count() != self.node_count() {
+            return Err(HandlerError::NodeCountChange);
+        }
+        self.update(
+            self.authority_index_and_pen.clone(),
+            authority_verifier,
+            addresses,
+        )
+        .await
+    }
+
     /// Updates the handler with the given keychain and set of own addresses.
     /// Returns an error if the set of addresses is not valid.
     /// All authentications will be rechecked, invalid ones purged and cached ones that turn out to
     /// now be valid canonalized.
-    /// Own authentication will be regenerated.
+    /// Own authentication will be regenerated with a higher sequence number.
     /// If successful returns a set of addresses that we should be connected to.
     pub async fn update(
         &mut self,
@@ -248,11 +403,12 @@ impl<M: Multiaddress> Handler<M> {
 
         let authentications = self.authentications.clone();
 
-        *self = Handler::new(
+        *self = Handler::new_with_sequence(
             authority_index_and_pen,
             authority_verifier,
             self.session_id(),
             addresses,
+            max(self.sequence + 1, minimal_sequence()),
         )
         .await?;
 
@@ -272,9 +428,12 @@ impl<M: Multiaddress> Handler<M> {
 
 #[cfg(test)]
 mod tests {
-    use super::{get_common_peer_id, Handler, HandlerError};
+    use codec::Encode;
+
+    use super::{get_common_peer_id, AuthError, Handler, HandlerError};
     use crate::{
         network::{
+            manager::Authentication,
             mock::{crypto_basics, MockMultiaddress, MockNetworkIdentity, MockPeerId},
             NetworkIdentity,
         },
@@ -438,6 +597,55 @@ mod tests {
         ));
     }
 
+    #[tokio::test]
+    async fn fails_to_update_authorities_changing_node_count() {
+        let (_, bigger_verifier) = crypto_basics(NUM_NODES + 1).await;
+        let mut crypto_basics = crypto_basics(NUM_NODES).await;
+        let addresses = MockNetworkIdentity::new().identity().0;
+        let mut handler0 = Handler::new(
+            Some(crypto_basics.0.pop().unwrap()),
+            crypto_basics.1.clone(),
+            SessionId(43),
+            addresses.clone(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            handler0
+                .update_authorities(bigger_verifier, addresses.clone())
+                .await,
+            Err(HandlerError::NodeCountChange)
+        ));
+        assert!(handler0
+            .update_authorities(crypto_basics.1, addresses)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn update_increases_sequence() {
+        let mut crypto_basics = crypto_basics(NUM_NODES).await;
+        let addresses = MockNetworkIdentity::new().identity().0;
+        let mut handler0 = Handler::new(
+            Some(crypto_basics.0.pop().unwrap()),
+            crypto_basics.1.clone(),
+            SessionId(43),
+            addresses.clone(),
+        )
+        .await
+        .unwrap();
+        let old_sequence = handler0.authentication().unwrap().0.sequence();
+        handler0
+            .update(
+                Some(crypto_basics.0.pop().unwrap()),
+                crypto_basics.1.clone(),
+                addresses,
+            )
+            .await
+            .unwrap();
+        assert!(handler0.authentication().unwrap().0.sequence() > old_sequence);
+    }
+
     #[tokio::test]
     async fn does_not_keep_own_peer_id_or_authentication() {
         let mut crypto_basics = crypto_basics(NUM_NODES).await;
@@ -497,6 +705,34 @@ mod tests {
         assert_eq!(handler0.peer_id(&NodeIndex(1)), peer_id1);
     }
 
+    #[tokio::test]
+    async fn accepts_legacy_authentication() {
+        let crypto_basics = crypto_basics(NUM_NODES).await;
+        let mut handler0 = Handler::new(
+            Some(crypto_basics.0[0].clone()),
+            crypto_basics.1.clone(),
+            SessionId(43),
+            MockNetworkIdentity::new().identity().0,
+        )
+        .await
+        .unwrap();
+        let handler1 = Handler::new(
+            Some(crypto_basics.0[1].clone()),
+            crypto_basics.1.clone(),
+            SessionId(43),
+            MockNetworkIdentity::new().identity().0,
+        )
+        .await
+        .unwrap();
+        let legacy_auth_data = handler1.authentication().unwrap().0.to_legacy();
+        let signature = crypto_basics.0[1].1.sign(&legacy_auth_data.encode()).await;
+        let legacy_authentication: Authentication<MockMultiaddress> =
+            (legacy_auth_data.into(), signature);
+        assert!(legacy_authentication.0.is_legacy());
+        assert!(handler0.handle_authentication(legacy_authentication));
+        assert!(handler0.peer_id(&NodeIndex(1)).is_some());
+    }
+
     #[tokio::test]
     async fn non_validator_accepts_correct_authentication() {
         let crypto_basics = crypto_basics(NUM_NODES).await;
@@ -526,6 +762,33 @@ mod tests {
         assert_eq!(handler0.peer_id(&NodeIndex(1)), peer_id1);
     }
 
+    #[tokio::test]
+    async fn misses_only_unauthenticated_nodes() {
+        let crypto_basics = crypto_basics(NUM_NODES).await;
+        let mut handler3 = Handler::new(
+            Some(crypto_basics.0[3].clone()),
+            crypto_basics.1.clone(),
+            SessionId(43),
+            MockNetworkIdentity::new().identity().0,
+        )
+        .await
+        .unwrap();
+        for node_id in [1, 4, 6] {
+            let handler = Handler::new(
+                Some(crypto_basics.0[node_id].clone()),
+                crypto_basics.1.clone(),
+                SessionId(43),
+                MockNetworkIdentity::new().identity().0,
+            )
+            .await
+            .unwrap();
+            assert!(handler3.handle_authentication(handler.authentication().unwrap()));
+        }
+        let missing_nodes = handler3.missing_nodes();
+        let expected_missing: Vec<_> = [0, 2, 5].into_iter().map(NodeIndex).collect();
+        assert_eq!(missing_nodes, expected_missing);
+    }
+
     #[tokio::test]
     async fn ignores_badly_signed_authentication() {
         let crypto_basics = crypto_basics(NUM_NODES).await;
@@ -578,6 +841,74 @@ mod tests {
         assert_eq!(missing_nodes, expected_missing);
     }
 
+    async fn two_handlers(
+        session_ids: (SessionId, SessionId),
+    ) -> (Handler<MockMultiaddress>, Handler<MockMultiaddress>) {
+        let crypto_basics = crypto_basics(NUM_NODES).await;
+        let handler0 = Handler::new(
+            Some(crypto_basics.0[0].clone()),
+            crypto_basics.1.clone(),
+            session_ids.0,
+            MockNetworkIdentity::new().identity().0,
+        )
+        .await
+        .unwrap();
+        let handler1 = Handler::new(
+            Some(crypto_basics.0[1].clone()),
+            crypto_basics.1.clone(),
+            session_ids.1,
+            MockNetworkIdentity::new().identity().0,
+        )
+        .await
+        .unwrap();
+        (handler0, handler1)
+    }
+
+    #[tokio::test]
+    async fn verifies_correct_authentication_without_using_it() {
+        let (handler0, handler1) = two_handlers((SessionId(43), SessionId(43))).await;
+        assert_eq!(
+            handler0.verify_authentication(&handler1.authentication().unwrap()),
+            Ok(())
+        );
+        let expected_missing: Vec<_> = (1..NUM_NODES).map(NodeIndex).collect();
+        assert_eq!(handler0.missing_nodes(), expected_missing);
+    }
+
+    #[tokio::test]
+    async fn verification_rejects_wrong_session() {
+        let (handler0, handler1) = two_handlers((SessionId(43), SessionId(44))).await;
+        assert_eq!(
+            handler0.verify_authentication(&handler1.authentication().unwrap()),
+            Err(AuthError::WrongSession {
+                expected: SessionId(43),
+                got: SessionId(44),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn verification_rejects_unknown_node() {
+        let (handler0, handler1) = two_handlers((SessionId(43), SessionId(43))).await;
+        let mut authentication = handler1.authentication().unwrap();
+        authentication.0.node_id = NodeIndex(NUM_NODES);
+        assert_eq!(
+            handler0.verify_authentication(&authentication),
+            Err(AuthError::UnknownNode(NodeIndex(NUM_NODES)))
+        );
+    }
+
+    #[tokio::test]
+    async fn verification_rejects_bad_signature() {
+        let (handler0, handler1) = two_handlers((SessionId(43), SessionId(43))).await;
+        let mut authentication = handler1.authentication().unwrap();
+        authentication.1 = handler0.authentication().unwrap().1;
+        assert_eq!(
+            handler0.verify_authentication(&authentication),
+            Err(AuthError::BadSignature)
+        );
+    }
+
     #[tokio::test]
     async fn ignores_own_authentication() {
         let awaited_crypto_basics = crypto_basics(NUM_NODES).await;
@@ -18,10 +18,11 @@ use sp_keystore::{testing::KeyStore, CryptoStore};
 use crate::{
     crypto::{AuthorityPen, AuthorityVerifier},
     network::{
-        manager::NetworkData, ConnectionCommand, DataCommand, Event, EventStream, Multiaddress,
-        Network, NetworkIdentity, NetworkSender, NetworkServiceIO as NetworkIO, PeerId, Protocol,
+        manager::NetworkData, AddressScope, ConnectionCommand, Data, DataCommand, DataNetwork,
+        Event, EventStream, Multiaddress, Network, NetworkIdentity, NetworkSender,
+        NetworkServiceIO as NetworkIO, PeerId, Protocol, SendError,
     },
-    AuthorityId, NodeIndex,
+    AuthorityId, NodeIndex, Recipient,
 };
 
 #[derive(PartialEq, Eq, Copy, Clone, Debug, Hash, Encode, Decode)]
@@ -44,6 +45,7 @@ impl PeerId for MockPeerId {}
 pub struct MockMultiaddress {
     peer_id: Option<MockPeerId>,
     address: u32,
+    unroutable: bool,
 }
 
 impl MockMultiaddress {
@@ -51,6 +53,14 @@ impl MockMultiaddress {
         MockMultiaddress {
             peer_id: Some(peer_id),
             address: random(),
+            unroutable: false,
+        }
+    }
+
+    pub fn unroutable_with_id(peer_id: MockPeerId) -> Self {
+        MockMultiaddress {
+            unroutable: true,
+            ..Self::random_with_id(peer_id)
         }
     }
 }
@@ -74,6 +84,13 @@ impl Multiaddress for MockMultiaddress {
             }
         }
     }
+
+    fn scope(&self) -> AddressScope {
+        match self.unroutable {
+            true => AddressScope::Unroutable,
+            false => AddressScope::Global,
+        }
+    }
 }
 
 pub struct MockNetworkIdentity {
@@ -331,3 +348,114 @@ pub async fn crypto_basics(
     }
     (result, AuthorityVerifier::new(auth_ids))
 }
+
+/// The links between the nodes of an in-memory network, shared by all of them. All the links are
+/// up initially, cutting some of them simulates a partition.
+#[derive(Clone)]
+pub struct MockLinks {
+    nodes_n: usize,
+    cut: Arc<Mutex<HashSet<(usize, usize)>>>,
+}
+
+impl MockLinks {
+    fn new(nodes_n: usize) -> Self {
+        MockLinks {
+            nodes_n,
+            cut: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    fn link(first: NodeIndex, second: NodeIndex) -> (usize, usize) {
+        (first.0.min(second.0), first.0.max(second.0))
+    }
+
+    /// Cuts the link between the nodes, the data sent over it in either direction gets lost.
+    pub fn cut(&self, first: NodeIndex, second: NodeIndex) {
+        self.cut.lock().insert(Self::link(first, second));
+    }
+
+    /// Brings the link between the nodes back up.
+    pub fn restore(&self, first: NodeIndex, second: NodeIndex) {
+        self.cut.lock().remove(&Self::link(first, second));
+    }
+
+    /// Cuts all the links of the node, so that it can neither send to nor receive from anyone.
+    pub fn isolate(&self, node: NodeIndex) {
+        for other in 0..self.nodes_n {
+            self.cut(node, NodeIndex(other));
+        }
+    }
+
+    pub fn is_up(&self, first: NodeIndex, second: NodeIndex) -> bool {
+        !self.cut.lock().contains(&Self::link(first, second))
+    }
+}
+
+/// A node of an in-memory network, passing the data directly to the other nodes, as long as the
+/// links to them are up.
+pub struct MockDataNetwork<D: Data> {
+    index: NodeIndex,
+    peers: Vec<mpsc::UnboundedSender<D>>,
+    incoming: mpsc::UnboundedReceiver<D>,
+    links: MockLinks,
+}
+
+impl<D: Data> MockDataNetwork<D> {
+    fn send_to(&self, data: D, index: usize) -> Result<(), SendError> {
+        if !self.links.is_up(self.index, NodeIndex(index)) {
+            return Ok(());
+        }
+        self.peers
+            .get(index)
+            .ok_or(SendError::SendFailed)?
+            .unbounded_send(data)
+            .map_err(|_| SendError::SendFailed)
+    }
+}
+
+#[async_trait]
+impl<D: Data> DataNetwork<D> for MockDataNetwork<D> {
+    fn send(&self, data: D, recipient: Recipient) -> Result<(), SendError> {
+        match recipient {
+            Recipient::Node(index) => self.send_to(data, index.0),
+            Recipient::Everyone => (0..self.peers.len())
+                .filter(|index| *index != self.index.0)
+                .try_for_each(|index| self.send_to(data.clone(), index)),
+        }
+    }
+
+    async fn next(&mut self) -> Option<D> {
+        self.incoming.next().await
+    }
+
+    fn peers(&self) -> Vec<NodeIndex> {
+        // a node that stopped dropped its incoming channel
+        (0..self.peers.len())
+            .map(NodeIndex)
+            .filter(|index| {
+                *index != self.index
+                    && !self.peers[index.0].is_closed()
+                    && self.links.is_up(self.index, *index)
+            })
+            .collect()
+    }
+}
+
+/// An in-memory network of the given number of nodes, all connected to each other, with the
+/// links between them that can be cut later. The networks are returned in the order of the node
+/// indices.
+pub fn fully_connected_mock<D: Data>(nodes_n: usize) -> (Vec<MockDataNetwork<D>>, MockLinks) {
+    let links = MockLinks::new(nodes_n);
+    let (peers, incomings): (Vec<_>, Vec<_>) = (0..nodes_n).map(|_| mpsc::unbounded()).unzip();
+    let networks = incomings
+        .into_iter()
+        .enumerate()
+        .map(|(index, incoming)| MockDataNetwork {
+            index: NodeIndex(index),
+            peers: peers.clone(),
+            incoming,
+            links: links.clone(),
+        })
+        .collect();
+    (networks, links)
+}
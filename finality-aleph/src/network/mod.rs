@@ -2,6 +2,7 @@ use std::{
     collections::HashSet,
     fmt::{Debug, Display},
     hash::Hash,
+    net::IpAddr,
 };
 
 use async_trait::async_trait;
@@ -10,13 +11,16 @@ use codec::Codec;
 use sp_api::NumberFor;
 use sp_runtime::traits::Block;
 
-use crate::abft::Recipient;
+use crate::{abft::Recipient, NodeIndex};
 
 mod component;
+mod counting;
 mod io;
 mod manager;
 #[cfg(test)]
 pub mod mock;
+mod receive_only;
+mod recording;
 mod service;
 mod session;
 mod split;
@@ -24,26 +28,94 @@ mod split;
 pub use component::{
     Network as ComponentNetwork, NetworkExt as ComponentNetworkExt,
     NetworkMap as ComponentNetworkMap, Receiver as ReceiverComponent, Sender as SenderComponent,
-    SimpleNetwork,
+    SharedReceiver, SimpleNetwork,
 };
+pub use counting::{CountingNetwork, SendMetrics};
 pub use io::setup as setup_io;
-use manager::SessionCommand;
 pub use manager::{
-    ConnectionIO as ConnectionManagerIO, ConnectionManager, ConnectionManagerConfig,
+    decode_network_data, AnnouncementSchedule, Channel, ConnectionIO as ConnectionManagerIO,
+    ConnectionManager, ConnectionManagerConfig, ConnectionManagerConfigBuilder,
+    ConnectionManagerConfigError, DecodeError as NetworkDataDecodeError, IsConnected,
+    PriorityWeights,
 };
+use manager::{SessionCommand, SessionPeers};
+pub use receive_only::ReceiveOnlyNetwork;
+pub use recording::{Direction, Record, RecordingNetwork};
 pub use service::{Service, IO as NetworkServiceIO};
 pub use session::{Manager as SessionManager, ManagerError, Sender, IO as SessionManagerIO};
 pub use split::{split, Split};
 #[cfg(test)]
 pub mod testing {
     pub use super::manager::{
-        Authentication, DataInSession, DiscoveryMessage, NetworkData, SessionHandler,
-        VersionedAuthentication,
+        AuthData, Authentication, Channel, ControlMessage, DataInSession, DiscoveryMessage,
+        Leaving, NetworkData, SessionHandler, VersionedAuthentication,
     };
 }
 
 /// Represents the id of an arbitrary node.
-pub trait PeerId: PartialEq + Eq + Clone + Debug + Display + Hash + Codec + Send {}
+pub trait PeerId: PartialEq + Eq + Clone + Debug + Display + Hash + Codec + Send + 'static {}
+
+/// How widely an address can be reached, from the narrowest to the widest.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Hash)]
+pub enum AddressScope {
+    /// Cannot be dialed at all, e.g. an unspecified IP or a zero port.
+    Unroutable,
+    /// Only reachable from the same machine.
+    Loopback,
+    /// Only reachable within a private network.
+    Local,
+    /// Not obviously restricted, this includes all DNS names.
+    Global,
+}
+
+impl From<IpAddr> for AddressScope {
+    fn from(ip: IpAddr) -> Self {
+        use AddressScope::*;
+        match ip {
+            IpAddr::V4(ip) if ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast() => {
+                Unroutable
+            }
+            IpAddr::V4(ip) if ip.is_loopback() => Loopback,
+            IpAddr::V4(ip) if ip.is_private() || ip.is_link_local() => Local,
+            IpAddr::V6(ip) if ip.is_unspecified() || ip.is_multicast() => Unroutable,
+            IpAddr::V6(ip) if ip.is_loopback() => Loopback,
+            // Unique local (fc00::/7) and link local (fe80::/10) addresses.
+            IpAddr::V6(ip) if ip.segments()[0] & 0xfe00 == 0xfc00 => Local,
+            IpAddr::V6(ip) if ip.segments()[0] & 0xffc0 == 0xfe80 => Local,
+            _ => Global,
+        }
+    }
+}
+
+/// How many addresses a single authentication can carry by default.
+const DEFAULT_MAX_ADDRESSES: usize = 16;
+
+/// Decides which addresses received from other nodes are worth dialing.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct AddressPolicy {
+    /// Addresses with a narrower scope are dropped.
+    pub min_scope: AddressScope,
+    /// Authentications carrying more addresses than this are rejected as a whole.
+    /// Our own authentications never carry more either.
+    pub max_addresses: usize,
+}
+
+impl AddressPolicy {
+    pub fn accepts<M: Multiaddress>(&self, address: &M) -> bool {
+        address.scope() >= self.min_scope
+    }
+}
+
+impl Default for AddressPolicy {
+    /// Only drops addresses that cannot be dialed at all, since local deployments rely on
+    /// loopback and private addresses.
+    fn default() -> Self {
+        AddressPolicy {
+            min_scope: AddressScope::Loopback,
+            max_addresses: DEFAULT_MAX_ADDRESSES,
+        }
+    }
+}
 
 /// Represents the address of an arbitrary node.
 pub trait Multiaddress: Debug + Hash + Codec + Clone + Eq + Send + Sync {
@@ -54,6 +126,9 @@ pub trait Multiaddress: Debug + Hash + Codec + Clone + Eq + Send + Sync {
 
     /// Returns the address extended by the peer id, unless it already contained another peer id.
     fn add_matching_peer_id(self, peer_id: Self::PeerId) -> Option<Self>;
+
+    /// Returns how widely the address can be reached.
+    fn scope(&self) -> AddressScope;
 }
 
 /// The Generic protocol is used for validator discovery.
@@ -84,7 +159,7 @@ pub enum Event<M: Multiaddress> {
     Disconnected(M::PeerId),
     StreamOpened(M::PeerId, Protocol),
     StreamClosed(M::PeerId, Protocol),
-    Messages(Vec<(Protocol, Bytes)>),
+    Messages(M::PeerId, Vec<(Protocol, Bytes)>),
 }
 
 #[async_trait]
@@ -159,6 +234,13 @@ pub enum DataCommand<PID: PeerId> {
 pub enum ConnectionCommand<M: Multiaddress> {
     AddReserved(HashSet<M>),
     DelReserved(HashSet<M::PeerId>),
+    /// Drop the connections to the peers of these addresses, after sending out what is already
+    /// queued for them, and connect to them anew.
+    Recycle(HashSet<M>),
+    /// Hold back the data sent to the peer, but stay connected.
+    Pause(M::PeerId),
+    /// Send out the data held back for the peer and stop holding it back.
+    Resume(M::PeerId),
 }
 
 /// Returned when something went wrong when sending data using a DataNetwork.
@@ -177,4 +259,6 @@ impl<D: Clone + Codec + Send + Sync + 'static> Data for D {}
 pub trait DataNetwork<D: Data>: Send + Sync {
     fn send(&self, data: D, recipient: Recipient) -> Result<(), SendError>;
     async fn next(&mut self) -> Option<D>;
+    /// The nodes we can currently send data to, in increasing order.
+    fn peers(&self) -> Vec<NodeIndex>;
 }
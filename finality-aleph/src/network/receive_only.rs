@@ -0,0 +1,93 @@
+use std::marker::PhantomData;
+
+use log::trace;
+
+use crate::{
+    network::{Data, DataNetwork, SendError},
+    NodeIndex, Recipient,
+};
+
+/// A network passing on everything received by the inner network, but dropping everything sent
+/// through it, so that its user can follow what the others do without affecting them.
+pub struct ReceiveOnlyNetwork<D: Data, DN: DataNetwork<D>> {
+    inner: DN,
+    _phantom: PhantomData<D>,
+}
+
+impl<D: Data, DN: DataNetwork<D>> ReceiveOnlyNetwork<D, DN> {
+    pub fn new(inner: DN) -> Self {
+        ReceiveOnlyNetwork {
+            inner,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<D: Data, DN: DataNetwork<D>> DataNetwork<D> for ReceiveOnlyNetwork<D, DN> {
+    fn send(&self, _: D, recipient: Recipient) -> Result<(), SendError> {
+        trace!(target: "aleph-network", "Dropping data for {:?}, the network is receive only.", recipient);
+        Ok(())
+    }
+
+    async fn next(&mut self) -> Option<D> {
+        self.inner.next().await
+    }
+
+    fn peers(&self) -> Vec<NodeIndex> {
+        self.inner.peers()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{channel::mpsc, StreamExt};
+
+    use super::ReceiveOnlyNetwork;
+    use crate::{
+        network::{DataNetwork, SendError},
+        NodeIndex, Recipient,
+    };
+
+    struct TestNetwork {
+        sent: mpsc::UnboundedSender<(u64, Recipient)>,
+        to_receive: mpsc::UnboundedReceiver<u64>,
+    }
+
+    #[async_trait::async_trait]
+    impl DataNetwork<u64> for TestNetwork {
+        fn send(&self, data: u64, recipient: Recipient) -> Result<(), SendError> {
+            self.sent
+                .unbounded_send((data, recipient))
+                .map_err(|_| SendError::SendFailed)
+        }
+
+        async fn next(&mut self) -> Option<u64> {
+            self.to_receive.next().await
+        }
+
+        fn peers(&self) -> Vec<NodeIndex> {
+            vec![NodeIndex(1)]
+        }
+    }
+
+    #[tokio::test]
+    async fn receives_without_sending() {
+        let (sent, mut sent_from_network) = mpsc::unbounded();
+        let (for_network, to_receive) = mpsc::unbounded();
+        let mut network = ReceiveOnlyNetwork::new(TestNetwork { sent, to_receive });
+
+        network
+            .send(43, Recipient::Node(NodeIndex(1)))
+            .expect("should pretend to send");
+        network
+            .send(44, Recipient::Everyone)
+            .expect("should pretend to send");
+        for_network.unbounded_send(2137).expect("should send");
+        assert_eq!(network.next().await, Some(2137));
+        assert_eq!(network.peers(), vec![NodeIndex(1)]);
+
+        drop(network);
+        assert_eq!(sent_from_network.next().await, None);
+    }
+}
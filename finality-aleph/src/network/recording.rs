@@ -0,0 +1,153 @@
+use std::time::SystemTime;
+
+use futures::channel::mpsc;
+use log::trace;
+
+use crate::{
+    network::{Data, DataNetwork, SendError},
+    NodeIndex, Recipient,
+};
+
+/// Which way the recorded data went.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Direction {
+    Sent(Recipient),
+    Received,
+}
+
+/// A single piece of data that went through a recording network.
+#[derive(Debug, Clone)]
+pub struct Record<D: Data> {
+    pub direction: Direction,
+    pub timestamp: SystemTime,
+    pub data: D,
+}
+
+/// A network passing everything to the inner network, and additionally recording all the sent
+/// and received data in the sink, if one is provided. Useful for debugging.
+pub struct RecordingNetwork<D: Data, DN: DataNetwork<D>> {
+    inner: DN,
+    sink: Option<mpsc::UnboundedSender<Record<D>>>,
+}
+
+impl<D: Data, DN: DataNetwork<D>> RecordingNetwork<D, DN> {
+    /// Wrap the network, without a sink nothing gets recorded.
+    pub fn new(inner: DN, sink: Option<mpsc::UnboundedSender<Record<D>>>) -> Self {
+        RecordingNetwork { inner, sink }
+    }
+
+    fn record(&self, direction: Direction, data: &D) {
+        if let Some(sink) = &self.sink {
+            let record = Record {
+                direction,
+                timestamp: SystemTime::now(),
+                data: data.clone(),
+            };
+            if sink.unbounded_send(record).is_err() {
+                trace!(target: "aleph-network", "Recording sink closed, data not recorded.");
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<D: Data, DN: DataNetwork<D>> DataNetwork<D> for RecordingNetwork<D, DN> {
+    fn send(&self, data: D, recipient: Recipient) -> Result<(), SendError> {
+        if self.sink.is_some() {
+            self.record(Direction::Sent(recipient.clone()), &data);
+        }
+        self.inner.send(data, recipient)
+    }
+
+    async fn next(&mut self) -> Option<D> {
+        let data = self.inner.next().await?;
+        self.record(Direction::Received, &data);
+        Some(data)
+    }
+
+    fn peers(&self) -> Vec<NodeIndex> {
+        self.inner.peers()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{channel::mpsc, StreamExt};
+
+    use super::{Direction, RecordingNetwork};
+    use crate::{
+        network::{DataNetwork, SendError},
+        NodeIndex, Recipient,
+    };
+
+    struct TestNetwork {
+        sent: mpsc::UnboundedSender<(u64, Recipient)>,
+        to_receive: mpsc::UnboundedReceiver<u64>,
+    }
+
+    #[async_trait::async_trait]
+    impl DataNetwork<u64> for TestNetwork {
+        fn send(&self, data: u64, recipient: Recipient) -> Result<(), SendError> {
+            self.sent
+                .unbounded_send((data, recipient))
+                .map_err(|_| SendError::SendFailed)
+        }
+
+        async fn next(&mut self) -> Option<u64> {
+            self.to_receive.next().await
+        }
+
+        fn peers(&self) -> Vec<NodeIndex> {
+            Vec::new()
+        }
+    }
+
+    #[tokio::test]
+    async fn records_what_flows_through() {
+        let (sent, mut sent_from_network) = mpsc::unbounded();
+        let (for_network, to_receive) = mpsc::unbounded();
+        let (sink, mut records) = mpsc::unbounded();
+        let mut network = RecordingNetwork::new(TestNetwork { sent, to_receive }, Some(sink));
+
+        network
+            .send(43, Recipient::Node(NodeIndex(7)))
+            .expect("should send");
+        network.send(44, Recipient::Everyone).expect("should send");
+        for_network.unbounded_send(2137).expect("should send");
+        assert_eq!(network.next().await, Some(2137));
+
+        assert_eq!(
+            sent_from_network.next().await,
+            Some((43, Recipient::Node(NodeIndex(7))))
+        );
+        assert_eq!(
+            sent_from_network.next().await,
+            Some((44, Recipient::Everyone))
+        );
+        let expected = [
+            (43, Direction::Sent(Recipient::Node(NodeIndex(7)))),
+            (44, Direction::Sent(Recipient::Everyone)),
+            (2137, Direction::Received),
+        ];
+        for (data, direction) in expected {
+            let record = records.next().await.expect("should be recorded");
+            assert_eq!(record.data, data);
+            assert_eq!(record.direction, direction);
+        }
+    }
+
+    #[tokio::test]
+    async fn passes_everything_without_sink() {
+        let (sent, mut sent_from_network) = mpsc::unbounded();
+        let (for_network, to_receive) = mpsc::unbounded();
+        let mut network = RecordingNetwork::new(TestNetwork { sent, to_receive }, None);
+
+        network.send(43, Recipient::Everyone).expect("should send");
+        for_network.unbounded_send(2137).expect("should send");
+        assert_eq!(network.next().await, Some(2137));
+        assert_eq!(
+            sent_from_network.next().await,
+            Some((43, Recipient::Everyone))
+        );
+    }
+}
@@ -2,12 +2,14 @@ use std::{
     collections::{HashMap, HashSet},
     future::Future,
     iter,
+    sync::Arc,
 };
 
 use aleph_primitives::AuthorityId;
-use codec::{Decode, Encode};
+use codec::Encode;
 use futures::{channel::mpsc, StreamExt};
 use log::{debug, error, info, trace, warn};
+use parking_lot::Mutex;
 use sc_service::SpawnTaskHandle;
 use sc_utils::mpsc::{tracing_unbounded, TracingUnboundedReceiver, TracingUnboundedSender};
 use tokio::time;
@@ -15,11 +17,16 @@ use tokio::time;
 use super::manager::DataInSession;
 use crate::{
     network::{
-        manager::{NetworkData, VersionedAuthentication},
-        ConnectionCommand, Data, DataCommand, Event, EventStream, Multiaddress, Network,
-        NetworkSender, Protocol,
+        manager::{
+            decode_authentication, decode_network_data, ChecksumKey, NetworkData, PriorityQueue,
+            PriorityWeights, VersionedAuthentication,
+        },
+        ConnectionCommand, Data, DataCommand, Event, EventStream, IsConnected, Multiaddress,
+        Network, NetworkSender, Protocol,
+    },
+    validator_network::{
+        Capabilities, Metrics as ValidatorNetworkMetrics, Network as ValidatorNetwork,
     },
-    validator_network::Network as ValidatorNetwork,
     STATUS_REPORT_INTERVAL,
 };
 
@@ -44,14 +51,18 @@ pub struct Service<
     network: N,
     validator_network: VN,
     messages_from_user: mpsc::UnboundedReceiver<MessageFromUser<D, A>>,
+    // Messages from the user waiting to be sent, so that discovery does not wait behind data.
+    messages_to_send: PriorityQueue<MessageFromUser<D, A>>,
     messages_for_user: mpsc::UnboundedSender<NetworkData<D, A>>,
     commands_from_manager: mpsc::UnboundedReceiver<ConnectionCommand<A>>,
     // In future these legacy senders and receiver will be removed
-    legacy_messages_from_user: mpsc::UnboundedReceiver<(LD, DataCommand<N::PeerId>)>,
-    legacy_messages_for_user: mpsc::UnboundedSender<LD>,
+    legacy_messages_from_user:
+        mpsc::UnboundedReceiver<(NetworkData<LD, N::Multiaddress>, DataCommand<N::PeerId>)>,
+    legacy_messages_for_user: mpsc::UnboundedSender<NetworkData<LD, N::Multiaddress>>,
     legacy_commands_from_manager: mpsc::UnboundedReceiver<ConnectionCommand<N::Multiaddress>>,
     legacy_generic_connected_peers: HashSet<N::PeerId>,
-    legacy_validator_connected_peers: HashSet<N::PeerId>,
+    // Shared with the legacy connection manager, which only reports these as reachable.
+    legacy_validator_connected_peers: Arc<Mutex<HashSet<N::PeerId>>>,
     authentication_connected_peers: HashSet<N::PeerId>,
     // For now we need to use `Vec<u8>` here.
     // This is needed for backward compatibility with old network.
@@ -61,6 +72,9 @@ pub struct Service<
     legacy_validator_peer_senders: HashMap<N::PeerId, TracingUnboundedSender<Vec<u8>>>,
     authentication_peer_senders: HashMap<N::PeerId, TracingUnboundedSender<Vec<u8>>>,
     spawn_handle: SpawnTaskHandle,
+    // The seed the keys of the checksums of sessions are derived from, no checksums without it.
+    checksum_seed: Option<Vec<u8>>,
+    metrics: Option<ValidatorNetworkMetrics>,
 }
 
 /// Input/output channels for the network service.
@@ -109,12 +123,14 @@ impl<
         validator_network: VN,
         spawn_handle: SpawnTaskHandle,
         io: IO<NetworkData<D, A>, A>,
-        legacy_io: IO<LD, N::Multiaddress>,
+        legacy_io: IO<NetworkData<LD, N::Multiaddress>, N::Multiaddress>,
+        priority_weights: PriorityWeights,
     ) -> Service<N, D, LD, A, VN> {
         Service {
             network,
             validator_network,
             messages_from_user: io.messages_from_user,
+            messages_to_send: PriorityQueue::new(priority_weights),
             messages_for_user: io.messages_for_user,
             commands_from_manager: io.commands_from_manager,
             legacy_messages_from_user: legacy_io.messages_from_user,
@@ -122,14 +138,36 @@ impl<
             legacy_commands_from_manager: legacy_io.commands_from_manager,
             spawn_handle,
             legacy_generic_connected_peers: HashSet::new(),
-            legacy_validator_connected_peers: HashSet::new(),
+            legacy_validator_connected_peers: Arc::new(Mutex::new(HashSet::new())),
             authentication_connected_peers: HashSet::new(),
             legacy_generic_peer_senders: HashMap::new(),
             legacy_validator_peer_senders: HashMap::new(),
             authentication_peer_senders: HashMap::new(),
+            checksum_seed: None,
+            metrics: None,
         }
     }
 
+    /// Adds checksums derived from the seed to the data sent to the peers that agreed on them,
+    /// and drops the received data whose checksums do not match, or are missing even though the
+    /// connection the data arrived on agreed on them, reporting it to the metrics. The seed is public, so this only catches data sent to the wrong session.
+    pub fn with_session_checksums(
+        mut self,
+        seed: Vec<u8>,
+        metrics: Option<ValidatorNetworkMetrics>,
+    ) -> Self {
+        self.checksum_seed = Some(seed);
+        self.metrics = metrics;
+        self
+    }
+
+    /// Tells whether the legacy network currently has a validator stream open with the peer,
+    /// kept up to date while the service is running.
+    pub fn legacy_connectivity(&self) -> IsConnected<N::PeerId> {
+        let connected_peers = self.legacy_validator_connected_peers.clone();
+        Arc::new(move |peer_id: &N::PeerId| connected_peers.lock().contains(peer_id))
+    }
+
     fn get_sender(
         &mut self,
         peer: &N::PeerId,
@@ -250,7 +288,9 @@ impl<
                     Protocol::Validator => {
                         let (tx, rx) =
                             tracing_unbounded("mpsc_notification_stream_legacy_validator");
-                        self.legacy_validator_connected_peers.insert(peer.clone());
+                        self.legacy_validator_connected_peers
+                            .lock()
+                            .insert(peer.clone());
                         self.legacy_validator_peer_senders.insert(peer.clone(), tx);
                         rx
                     }
@@ -275,7 +315,7 @@ impl<
                         self.legacy_generic_peer_senders.remove(&peer);
                     }
                     Protocol::Validator => {
-                        self.legacy_validator_connected_peers.remove(&peer);
+                        self.legacy_validator_connected_peers.lock().remove(&peer);
                         self.legacy_validator_peer_senders.remove(&peer);
                     }
                     Protocol::Authentication => {
@@ -284,10 +324,10 @@ impl<
                     }
                 }
             }
-            Messages(messages) => {
+            Messages(peer, messages) => {
                 for (protocol, data) in messages.into_iter() {
                     match protocol {
-                        Protocol::Generic => match LD::decode(&mut &data[..]) {
+                        Protocol::Generic => match decode_network_data(&data[..]) {
                             Ok(data) => self
                                 .legacy_messages_for_user
                                 .unbounded_send(data)
@@ -296,7 +336,7 @@ impl<
                                 warn!(target: "aleph-network", "Error decoding legacy generic protocol message: {}", e)
                             }
                         },
-                        Protocol::Validator => match LD::decode(&mut &data[..]) {
+                        Protocol::Validator => match decode_network_data(&data[..]) {
                             Ok(data) => self
                                 .legacy_messages_for_user
                                 .unbounded_send(data)
@@ -305,22 +345,15 @@ impl<
                                 warn!(target: "aleph-network", "Error decoding legacy validator protocol message: {}", e)
                             }
                         },
-                        Protocol::Authentication => {
-                            match VersionedAuthentication::<A>::decode(&mut &data[..])
-                                .map(|a| a.try_into())
-                            {
-                                Ok(Ok(data)) => self
-                                    .messages_for_user
-                                    .unbounded_send(data)
-                                    .map_err(|_| SendToUserError::LatestSender)?,
-                                Ok(Err(e)) => {
-                                    warn!(target: "aleph-network", "Error decoding authentication protocol message: {}", e)
-                                }
-                                Err(e) => {
-                                    warn!(target: "aleph-network", "Error decoding authentication protocol message: {}", e)
-                                }
+                        Protocol::Authentication => match decode_authentication(&data[..]) {
+                            Ok(data) => self
+                                .messages_for_user
+                                .unbounded_send(data)
+                                .map_err(|_| SendToUserError::LatestSender)?,
+                            Err(e) => {
+                                debug!(target: "aleph-network", "Dropping authentication from peer {:?}: {}", peer, e)
                             }
-                        }
+                        },
                     };
                 }
             }
@@ -331,10 +364,43 @@ impl<
     fn handle_validator_network_data(
         &mut self,
         data: DataInSession<D>,
+        sender: AuthorityId,
+        capabilities: Capabilities,
     ) -> Result<(), mpsc::TrySendError<NetworkData<D, A>>> {
+        if let Some(seed) = &self.checksum_seed {
+            // A present checksum is always verified, but it is only required if the connection
+            // the data arrived on agreed on checksums. The peer sends over its own connection,
+            // which might have agreed on less than ours, or not yet at all.
+            let expected =
+                data.checksum.is_some() || capabilities.contains(Capabilities::SESSION_CHECKSUM);
+            if expected && !data.checksum_matches(&ChecksumKey::new(seed, data.session_id)) {
+                warn!(target: "aleph-network", "Dropping data from {} with a missing or mismatched checksum for session {:?}.", sender, data.session_id);
+                if let Some(metrics) = &self.metrics {
+                    metrics.report_misrouted();
+                }
+                return Ok(());
+            }
+        }
         self.messages_for_user.unbounded_send(data.into())
     }
 
+    /// Whether the peer agreed on checksums over our connection to it.
+    fn agreed_on_checksums(&self, peer: &AuthorityId) -> bool {
+        self.validator_network
+            .capabilities(peer)
+            .map_or(false, |capabilities| {
+                capabilities.contains(Capabilities::SESSION_CHECKSUM)
+            })
+    }
+
+    /// The data, with a checksum if we can compute it and the peer agreed on it.
+    fn maybe_with_checksum(&self, data: DataInSession<D>, peer: &AuthorityId) -> DataInSession<D> {
+        match (&self.checksum_seed, self.agreed_on_checksums(peer)) {
+            (Some(seed), true) => data.with_checksum(&ChecksumKey::new(seed, data.session_id)),
+            _ => data,
+        }
+    }
+
     fn on_manager_command(&mut self, command: ConnectionCommand<A>) {
         use ConnectionCommand::*;
         match command {
@@ -350,6 +416,19 @@ impl<
                     self.validator_network.remove_connection(peer);
                 }
             }
+            Recycle(addresses) => {
+                let peers: HashSet<_> = addresses
+                    .iter()
+                    .filter_map(|multi| multi.get_peer_id())
+                    .collect();
+                // removing lets the outgoing connections send out what they have queued first
+                for peer in peers {
+                    self.validator_network.remove_connection(peer);
+                }
+                self.on_manager_command(AddReserved(addresses));
+            }
+            Pause(peer) => self.validator_network.pause(peer),
+            Resume(peer) => self.validator_network.resume(peer),
         }
     }
 
@@ -361,6 +440,27 @@ impl<
                 self.network.add_reserved(addresses, Protocol::Validator);
             }
             DelReserved(peers) => self.network.remove_reserved(peers, Protocol::Validator),
+            Recycle(addresses) => {
+                let peers = addresses
+                    .iter()
+                    .filter_map(|multi| multi.get_peer_id())
+                    .collect();
+                self.network.remove_reserved(peers, Protocol::Validator);
+                self.network.add_reserved(addresses, Protocol::Validator);
+            }
+            Pause(_) | Resume(_) => {
+                // The legacy network does not let us hold back data, so this is ignored.
+            }
+        }
+    }
+
+    /// Queues the message together with all the others that are already waiting in the channel.
+    fn queue_user_messages(&mut self, message: MessageFromUser<D, A>) {
+        let mut message = Some(message);
+        while let Some((data, command)) = message {
+            let priority = data.priority();
+            self.messages_to_send.push((data, command), priority);
+            message = self.messages_from_user.try_next().ok().flatten();
         }
     }
 
@@ -377,19 +477,46 @@ impl<
                     }
                 }
             }
-            NetworkData::Data(data, session) => {
+            NetworkData::Data(data, session_id, channel) => {
                 match command {
                     Broadcast => {
                         // We ignore this for now. AlephBFT does not broadcast data.
                     }
-                    SendTo(peer, _) => self.validator_network.send((data, session), peer),
+                    SendTo(peer, _) => {
+                        let data = DataInSession {
+                            data,
+                            session_id,
+                            channel,
+                            checksum: None,
+                        };
+                        self.validator_network
+                            .send(self.maybe_with_checksum(data, &peer), peer)
+                    }
                 }
             }
+            NetworkData::Control(message) => {
+                // Control messages are signed, so they can go through the same gossip as the
+                // authentications.
+                let data: VersionedAuthentication<A> = message.into();
+                match command {
+                    Broadcast => self.broadcast(data.encode(), Protocol::Authentication),
+                    SendTo(_, _) => {
+                        // We ignore this for now. Control messages are only ever broadcast.
+                    }
+                }
+            }
+            NetworkData::Unknown(_, _) => {
+                // We never create these, they only exist so that newer messages can be ignored.
+            }
         }
     }
 
     /// This will be removed in the future
-    fn legacy_on_user_message(&mut self, data: LD, command: DataCommand<N::PeerId>) {
+    fn legacy_on_user_message(
+        &mut self,
+        data: NetworkData<LD, N::Multiaddress>,
+        command: DataCommand<N::PeerId>,
+    ) {
         use DataCommand::*;
         match command {
             Broadcast => self.broadcast(data.encode(), Protocol::Generic),
@@ -414,15 +541,15 @@ impl<
             self.legacy_generic_connected_peers.len()
         ));
 
-        let peer_ids = self
-            .legacy_validator_connected_peers
+        let legacy_validator_connected_peers = self.legacy_validator_connected_peers.lock();
+        let peer_ids = legacy_validator_connected_peers
             .iter()
             .map(|peer_id| format!("{}", peer_id))
             .collect::<Vec<_>>()
             .join(", ");
         status.push_str(&format!(
             "validator connected peers - {:?} [{}]; ",
-            self.legacy_validator_connected_peers.len(),
+            legacy_validator_connected_peers.len(),
             peer_ids,
         ));
 
@@ -449,7 +576,7 @@ impl<
                     }
                 },
                 maybe_data = self.validator_network.next() => match maybe_data {
-                    Some(data) => if let Err(e) = self.handle_validator_network_data(data) {
+                    Some((data, sender, capabilities)) => if let Err(e) = self.handle_validator_network_data(data, sender, capabilities) {
                         error!(target: "aleph-network", "Cannot forward messages to user: {:?}", e);
                         return;
                     },
@@ -458,12 +585,17 @@ impl<
                     }
                 },
                 maybe_message = self.messages_from_user.next() => match maybe_message {
-                    Some((data, command)) => self.on_user_message(data, command),
+                    Some(message) => self.queue_user_messages(message),
                     None => {
                         error!(target: "aleph-network", "User message stream ended.");
                         return;
                     }
                 },
+                _ = async {}, if !self.messages_to_send.is_empty() => {
+                    if let Some((data, command)) = self.messages_to_send.pop() {
+                        self.on_user_message(data, command);
+                    }
+                },
                 maybe_command = self.commands_from_manager.next() => match maybe_command {
                     Some(command) => self.on_manager_command(command),
                     None => {
@@ -505,7 +637,7 @@ mod tests {
     use super::{ConnectionCommand, DataCommand, Service};
     use crate::{
         network::{
-            manager::DataInSession,
+            manager::{Channel, ChecksumKey, DataInSession, PriorityWeights},
             mock::{
                 MockData, MockEvent, MockIO, MockMultiaddress as LegacyMockMultiaddress,
                 MockNetwork, MockNetworkIdentity, MockPeerId, MockSenderError,
@@ -517,6 +649,7 @@ mod tests {
         testing::mocks::validator_network::{
             MockMultiaddress, MockNetwork as MockValidatorNetwork,
         },
+        validator_network::Capabilities,
     };
 
     pub struct TestData {
@@ -531,6 +664,15 @@ mod tests {
 
     impl TestData {
         async fn prepare() -> Self {
+            Self::prepare_with_session_checksums(None, None).await
+        }
+
+        /// With the seed, the data gets checksums for all the peers, as long as they agreed on
+        /// the capabilities including them.
+        async fn prepare_with_session_checksums(
+            seed: Option<Vec<u8>>,
+            capabilities: Option<Capabilities>,
+        ) -> Self {
             let task_manager = TaskManager::new(Handle::current(), None).unwrap();
 
             // Prepare communication with service
@@ -538,14 +680,20 @@ mod tests {
             // Prepare service
             let (event_stream_oneshot_tx, event_stream_oneshot_rx) = oneshot::channel();
             let network = MockNetwork::new(event_stream_oneshot_tx);
-            let validator_network = MockValidatorNetwork::new("addr").await;
+            let mut validator_network = MockValidatorNetwork::new("addr").await;
+            validator_network.capabilities = capabilities;
             let service = Service::new(
                 network.clone(),
                 validator_network.clone(),
                 task_manager.spawn_handle(),
                 io,
                 legacy_io,
+                PriorityWeights::default(),
             );
+            let service = match seed {
+                Some(seed) => service.with_session_checksums(seed, None),
+                None => service,
+            };
             let (exit_tx, exit_rx) = oneshot::channel();
             let task_handle = async move {
                 tokio::select! {
@@ -591,7 +739,7 @@ mod tests {
     }
 
     fn message(i: u8) -> NetworkData<MockData, LegacyMockMultiaddress> {
-        NetworkData::Data(vec![i, i + 1, i + 2], SessionId(1))
+        NetworkData::Data(vec![i, i + 1, i + 2], SessionId(1), Channel::Main)
     }
 
     #[tokio::test]
@@ -1308,10 +1456,10 @@ mod tests {
 
         let message = message(1);
 
-        test_data.network.emit_event(MockEvent::Messages(vec![(
-            Protocol::Validator,
-            NetworkData::encode(&message).into(),
-        )]));
+        test_data.network.emit_event(MockEvent::Messages(
+            MockPeerId::random(),
+            vec![(Protocol::Validator, NetworkData::encode(&message).into())],
+        ));
 
         assert_eq!(
             test_data
@@ -1326,6 +1474,33 @@ mod tests {
         test_data.cleanup().await
     }
 
+    #[tokio::test]
+    async fn test_notification_with_trailing_bytes_dropped() {
+        let mut test_data = TestData::prepare().await;
+
+        let mut padded = NetworkData::encode(&message(1));
+        padded.push(0);
+        test_data.network.emit_event(MockEvent::Messages(
+            MockPeerId::random(),
+            vec![
+                (Protocol::Validator, padded.into()),
+                (Protocol::Validator, NetworkData::encode(&message(2)).into()),
+            ],
+        ));
+
+        assert_eq!(
+            test_data
+                .mock_io
+                .legacy_messages_from_user
+                .next()
+                .await
+                .expect("Should receive message"),
+            message(2)
+        );
+
+        test_data.cleanup().await
+    }
+
     #[tokio::test]
     async fn test_command_add_reserved() {
         let mut test_data = TestData::prepare().await;
@@ -1383,4 +1558,196 @@ mod tests {
 
         test_data.cleanup().await
     }
+
+    #[tokio::test]
+    async fn test_command_pause_and_resume() {
+        let mut test_data = TestData::prepare().await;
+
+        let (_, peer_id) = test_data.validator_network.identity();
+
+        test_data
+            .mock_io
+            .commands_for_manager
+            .unbounded_send(ConnectionCommand::Pause(peer_id.clone()))
+            .unwrap();
+        assert_eq!(
+            test_data
+                .validator_network
+                .pause
+                .next()
+                .await
+                .expect("Should receive message"),
+            peer_id
+        );
+
+        test_data
+            .mock_io
+            .commands_for_manager
+            .unbounded_send(ConnectionCommand::Resume(peer_id.clone()))
+            .unwrap();
+        assert_eq!(
+            test_data
+                .validator_network
+                .resume
+                .next()
+                .await
+                .expect("Should receive message"),
+            peer_id
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_adds_checksums_for_peers_agreeing_on_them() {
+        let mut test_data = TestData::prepare_with_session_checksums(
+            Some(b"seed".to_vec()),
+            Some(Capabilities::supported()),
+        )
+        .await;
+        let (_, peer_id) = test_data.validator_network.identity();
+
+        test_data
+            .mock_io
+            .messages_for_user
+            .unbounded_send((
+                NetworkData::Data(vec![1, 2, 3], SessionId(1), Channel::Main),
+                DataCommand::SendTo(peer_id.clone(), Protocol::Validator),
+            ))
+            .unwrap();
+        let (data, recipient) = test_data
+            .validator_network
+            .send
+            .next()
+            .await
+            .expect("Should receive message");
+        assert_eq!(recipient, peer_id);
+        assert!(data.checksum_matches(&ChecksumKey::new(b"seed", SessionId(1))));
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_forwards_data_with_valid_session_checksum() {
+        let mut test_data = TestData::prepare_with_session_checksums(
+            Some(b"seed".to_vec()),
+            Some(Capabilities::supported()),
+        )
+        .await;
+        let key = ChecksumKey::new(b"seed", SessionId(1));
+        let (_, peer_id) = test_data.validator_network.identity();
+
+        test_data.validator_network.next.send((
+            DataInSession::new(vec![1, 2, 3], SessionId(1)).with_checksum(&key),
+            peer_id,
+            Capabilities::supported(),
+        ));
+        assert_eq!(
+            test_data
+                .mock_io
+                .messages_from_user
+                .next()
+                .await
+                .expect("Should receive message"),
+            NetworkData::Data(vec![1, 2, 3], SessionId(1), Channel::Main)
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_drops_data_with_mismatched_session_checksum() {
+        let mut test_data = TestData::prepare_with_session_checksums(
+            Some(b"seed".to_vec()),
+            Some(Capabilities::supported()),
+        )
+        .await;
+        let key = ChecksumKey::new(b"seed", SessionId(1));
+        let other_key = ChecksumKey::new(b"seed", SessionId(2));
+        let (_, peer_id) = test_data.validator_network.identity();
+
+        // checksummed for another session, e.g. by a routing mistake
+        test_data.validator_network.next.send((
+            DataInSession::new(vec![1, 2, 3], SessionId(1)).with_checksum(&other_key),
+            peer_id.clone(),
+            Capabilities::supported(),
+        ));
+        test_data.validator_network.next.send((
+            DataInSession::new(vec![4, 5, 6], SessionId(1)).with_checksum(&key),
+            peer_id,
+            Capabilities::supported(),
+        ));
+        assert_eq!(
+            test_data
+                .mock_io
+                .messages_from_user
+                .next()
+                .await
+                .expect("Should receive message"),
+            NetworkData::Data(vec![4, 5, 6], SessionId(1), Channel::Main)
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_drops_data_without_checksum_from_connections_agreeing_on_them() {
+        let mut test_data = TestData::prepare_with_session_checksums(
+            Some(b"seed".to_vec()),
+            Some(Capabilities::supported()),
+        )
+        .await;
+        let key = ChecksumKey::new(b"seed", SessionId(1));
+        let (_, peer_id) = test_data.validator_network.identity();
+
+        test_data.validator_network.next.send((
+            DataInSession::new(vec![1, 2, 3], SessionId(1)),
+            peer_id.clone(),
+            Capabilities::supported(),
+        ));
+        test_data.validator_network.next.send((
+            DataInSession::new(vec![4, 5, 6], SessionId(1)).with_checksum(&key),
+            peer_id,
+            Capabilities::supported(),
+        ));
+        assert_eq!(
+            test_data
+                .mock_io
+                .messages_from_user
+                .next()
+                .await
+                .expect("Should receive message"),
+            NetworkData::Data(vec![4, 5, 6], SessionId(1), Channel::Main)
+        );
+
+        test_data.cleanup().await
+    }
+
+    #[tokio::test]
+    async fn test_forwards_data_without_checksum_from_connections_not_agreeing_on_them() {
+        // we agreed on checksums over our connection, but the peer sends over its own
+        let mut test_data = TestData::prepare_with_session_checksums(
+            Some(b"seed".to_vec()),
+            Some(Capabilities::supported()),
+        )
+        .await;
+        let (_, peer_id) = test_data.validator_network.identity();
+
+        test_data.validator_network.next.send((
+            DataInSession::new(vec![1, 2, 3], SessionId(1)),
+            peer_id,
+            Capabilities::default(),
+        ));
+        assert_eq!(
+            test_data
+                .mock_io
+                .messages_from_user
+                .next()
+                .await
+                .expect("Should receive message"),
+            NetworkData::Data(vec![1, 2, 3], SessionId(1), Channel::Main)
+        );
+
+        test_data.cleanup().await
+    }
 }
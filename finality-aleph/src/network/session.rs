@@ -1,30 +1,54 @@
+use std::collections::BTreeSet;
+
 use futures::channel::{mpsc, oneshot};
 
 use super::SimpleNetwork;
 use crate::{
     abft::Recipient,
     crypto::{AuthorityPen, AuthorityVerifier},
-    network::{Data, ReceiverComponent, SendError, SenderComponent, SessionCommand},
-    NodeIndex, SessionId,
+    network::{
+        Channel, Data, Multiaddress, ReceiverComponent, SendError, SenderComponent, SessionCommand,
+        SessionPeers,
+    },
+    NodeCount, NodeIndex, SessionId,
 };
 
-/// Sends data within a single session.
+/// Sends data within a single session, through a single channel.
 #[derive(Clone)]
 pub struct Sender<D: Data> {
     session_id: SessionId,
-    messages_for_network: mpsc::UnboundedSender<(D, SessionId, Recipient)>,
-    legacy_messages_for_network: mpsc::UnboundedSender<(D, SessionId, Recipient)>,
+    channel: Channel,
+    messages_for_network: mpsc::UnboundedSender<(D, SessionId, Channel, Recipient)>,
+    legacy_messages_for_network: mpsc::UnboundedSender<(D, SessionId, Channel, Recipient)>,
+    peers: SessionPeers,
+    legacy_peers: SessionPeers,
 }
 
 impl<D: Data> SenderComponent<D> for Sender<D> {
     fn send(&self, data: D, recipient: Recipient) -> Result<(), SendError> {
         self.messages_for_network
-            .unbounded_send((data.clone(), self.session_id, recipient.clone()))
+            .unbounded_send((
+                data.clone(),
+                self.session_id,
+                self.channel,
+                recipient.clone(),
+            ))
             .map_err(|_| SendError::SendFailed)?;
         self.legacy_messages_for_network
-            .unbounded_send((data, self.session_id, recipient))
+            .unbounded_send((data, self.session_id, self.channel, recipient))
             .map_err(|_| SendError::SendFailed)
     }
+
+    /// The data is sent through both networks, so reaching a node through either is enough.
+    fn peers(&self) -> Vec<NodeIndex> {
+        let peers: BTreeSet<_> = self
+            .peers
+            .get()
+            .into_iter()
+            .chain(self.legacy_peers.get())
+            .collect();
+        peers.into_iter().collect()
+    }
 }
 
 pub struct Receiver<D: Data> {
@@ -42,15 +66,16 @@ impl<D: Data> ReceiverComponent<D> for Receiver<D> {
     }
 }
 
-/// Sends and receives data within a single session.
+/// Sends and receives data within a single session, through a single channel.
 type Network<D> = SimpleNetwork<D, Receiver<D>, Sender<D>>;
 
 /// Manages sessions for which the network should be active.
-pub struct Manager<D: Data> {
-    commands_for_service: mpsc::UnboundedSender<SessionCommand<D>>,
-    messages_for_service: mpsc::UnboundedSender<(D, SessionId, Recipient)>,
-    legacy_commands_for_service: mpsc::UnboundedSender<SessionCommand<D>>,
-    legacy_messages_for_service: mpsc::UnboundedSender<(D, SessionId, Recipient)>,
+#[derive(Clone)]
+pub struct Manager<D: Data, M: Multiaddress, LM: Multiaddress> {
+    commands_for_service: mpsc::UnboundedSender<SessionCommand<D, M>>,
+    messages_for_service: mpsc::UnboundedSender<(D, SessionId, Channel, Recipient)>,
+    legacy_commands_for_service: mpsc::UnboundedSender<SessionCommand<D, LM>>,
+    legacy_messages_for_service: mpsc::UnboundedSender<(D, SessionId, Channel, Recipient)>,
 }
 
 /// What went wrong during a session management operation.
@@ -60,15 +85,15 @@ pub enum ManagerError {
     NetworkReceiveFailed,
 }
 
-pub struct IO<D: Data> {
-    pub commands_for_service: mpsc::UnboundedSender<SessionCommand<D>>,
-    pub messages_for_service: mpsc::UnboundedSender<(D, SessionId, Recipient)>,
+pub struct IO<D: Data, M: Multiaddress> {
+    pub commands_for_service: mpsc::UnboundedSender<SessionCommand<D, M>>,
+    pub messages_for_service: mpsc::UnboundedSender<(D, SessionId, Channel, Recipient)>,
 }
 
-impl<D: Data> IO<D> {
+impl<D: Data, M: Multiaddress> IO<D, M> {
     pub fn new(
-        commands_for_service: mpsc::UnboundedSender<SessionCommand<D>>,
-        messages_for_service: mpsc::UnboundedSender<(D, SessionId, Recipient)>,
+        commands_for_service: mpsc::UnboundedSender<SessionCommand<D, M>>,
+        messages_for_service: mpsc::UnboundedSender<(D, SessionId, Channel, Recipient)>,
     ) -> Self {
         IO {
             commands_for_service,
@@ -77,9 +102,9 @@ impl<D: Data> IO<D> {
     }
 }
 
-impl<D: Data> Manager<D> {
+impl<D: Data, M: Multiaddress, LM: Multiaddress> Manager<D, M, LM> {
     /// Create a new manager with the given channels to the service.
-    pub fn new(io: IO<D>, legacy_io: IO<D>) -> Self {
+    pub fn new(io: IO<D, M>, legacy_io: IO<D, LM>) -> Self {
         Manager {
             commands_for_service: io.commands_for_service,
             messages_for_service: io.messages_for_service,
@@ -108,14 +133,14 @@ impl<D: Data> Manager<D> {
 
     /// Start participating or update the information about the given session where you are a
     /// validator. Returns a session network to be used for sending and receiving data within the
-    /// session.
+    /// session, through the main channel, together with how many nodes the session authenticates.
     pub async fn start_validator_session(
         &self,
         session_id: SessionId,
         verifier: AuthorityVerifier,
         node_id: NodeIndex,
         pen: AuthorityPen,
-    ) -> Result<Network<D>, ManagerError> {
+    ) -> Result<(Network<D>, NodeCount), ManagerError> {
         let (result_for_us, result_from_service) = oneshot::channel();
         self.commands_for_service
             .unbounded_send(SessionCommand::StartValidator(
@@ -138,16 +163,69 @@ impl<D: Data> Manager<D> {
             ))
             .map_err(|_| ManagerError::CommandSendFailed)?;
 
-        let data_from_network = result_from_service
+        let (data_from_network, peers) = result_from_service
             .await
             .map_err(|_| ManagerError::NetworkReceiveFailed)?;
         let messages_for_network = self.messages_for_service.clone();
 
-        let legacy_data_from_network = legacy_result_from_service
+        let (legacy_data_from_network, legacy_peers) = legacy_result_from_service
             .await
             .map_err(|_| ManagerError::NetworkReceiveFailed)?;
         let legacy_messages_for_network = self.legacy_messages_for_service.clone();
 
+        let node_count = peers.node_count();
+        Ok((
+            Network::new(
+                Receiver {
+                    data_from_network,
+                    legacy_data_from_network,
+                },
+                Sender {
+                    session_id,
+                    channel: Channel::Main,
+                    messages_for_network,
+                    legacy_messages_for_network,
+                    peers,
+                    legacy_peers,
+                },
+            ),
+            node_count,
+        ))
+    }
+
+    /// Returns a network sending and receiving data through the given channel of a session in
+    /// which you are a validator, e.g. to keep some kind of traffic apart from the main one. The
+    /// session has to be started first. Opening a channel again replaces its previous network.
+    /// Nodes predating channels drop the data of all the channels but the main one.
+    pub async fn open_channel(
+        &self,
+        session_id: SessionId,
+        channel: Channel,
+    ) -> Result<Network<D>, ManagerError> {
+        let (result_for_us, result_from_service) = oneshot::channel();
+        self.commands_for_service
+            .unbounded_send(SessionCommand::OpenChannel(
+                session_id,
+                channel,
+                result_for_us,
+            ))
+            .map_err(|_| ManagerError::CommandSendFailed)?;
+        let (legacy_result_for_us, legacy_result_from_service) = oneshot::channel();
+        self.legacy_commands_for_service
+            .unbounded_send(SessionCommand::OpenChannel(
+                session_id,
+                channel,
+                legacy_result_for_us,
+            ))
+            .map_err(|_| ManagerError::CommandSendFailed)?;
+
+        let (data_from_network, peers) = result_from_service
+            .await
+            .map_err(|_| ManagerError::NetworkReceiveFailed)?;
+        let (legacy_data_from_network, legacy_peers) = legacy_result_from_service
+            .await
+            .map_err(|_| ManagerError::NetworkReceiveFailed)?;
+
         Ok(Network::new(
             Receiver {
                 data_from_network,
@@ -155,15 +233,18 @@ impl<D: Data> Manager<D> {
             },
             Sender {
                 session_id,
-                messages_for_network,
-                legacy_messages_for_network,
+                channel,
+                messages_for_network: self.messages_for_service.clone(),
+                legacy_messages_for_network: self.legacy_messages_for_service.clone(),
+                peers,
+                legacy_peers,
             },
         ))
     }
 
-    /// Start participating or update the information about the given session where you are a
-    /// validator. Used for early starts when you don't yet need the returned network, but would
-    /// like to start discovery.
+    /// Prepare the given upcoming session where you will be a validator, by starting discovery and
+    /// connecting to the other validators ahead of time. Used for early starts when you don't yet
+    /// need the network of the session.
     pub fn early_start_validator_session(
         &self,
         session_id: SessionId,
@@ -172,28 +253,76 @@ impl<D: Data> Manager<D> {
         pen: AuthorityPen,
     ) -> Result<(), ManagerError> {
         self.commands_for_service
-            .unbounded_send(SessionCommand::StartValidator(
+            .unbounded_send(SessionCommand::Prepare(
                 session_id,
                 verifier.clone(),
                 node_id,
                 pen.clone(),
-                None,
             ))
             .map_err(|_| ManagerError::CommandSendFailed)?;
         self.legacy_commands_for_service
-            .unbounded_send(SessionCommand::StartValidator(
-                session_id, verifier, node_id, pen, None,
-            ))
+            .unbounded_send(SessionCommand::Prepare(session_id, verifier, node_id, pen))
             .map_err(|_| ManagerError::CommandSendFailed)
     }
 
-    /// Stop participating in the given session.
-    pub fn stop_session(&self, session_id: SessionId) -> Result<(), ManagerError> {
+    /// Stop participating in the given session. When draining, the data already sent in the
+    /// session still gets delivered to the peers before disconnecting from them.
+    pub fn stop_session(&self, session_id: SessionId, drain: bool) -> Result<(), ManagerError> {
         self.commands_for_service
-            .unbounded_send(SessionCommand::Stop(session_id))
+            .unbounded_send(SessionCommand::Stop { session_id, drain })
             .map_err(|_| ManagerError::CommandSendFailed)?;
         self.legacy_commands_for_service
-            .unbounded_send(SessionCommand::Stop(session_id))
+            .unbounded_send(SessionCommand::Stop { session_id, drain })
+            .map_err(|_| ManagerError::CommandSendFailed)
+    }
+
+    /// Replace the authorities of the given running session, e.g. after an emergency rotation.
+    /// Connections to the departed authorities get dropped and the new ones are discovered.
+    pub fn update_authorities(
+        &self,
+        session_id: SessionId,
+        verifier: AuthorityVerifier,
+    ) -> Result<(), ManagerError> {
+        self.commands_for_service
+            .unbounded_send(SessionCommand::UpdateAuthorities(
+                session_id,
+                verifier.clone(),
+            ))
+            .map_err(|_| ManagerError::CommandSendFailed)?;
+        self.legacy_commands_for_service
+            .unbounded_send(SessionCommand::UpdateAuthorities(session_id, verifier))
+            .map_err(|_| ManagerError::CommandSendFailed)
+    }
+
+    /// Announce the given addresses in all the sessions from now on. Only the validator network is
+    /// affected, the legacy network learns its addresses on its own.
+    pub fn update_addresses(&self, addresses: Vec<M>) -> Result<(), ManagerError> {
+        self.commands_for_service
+            .unbounded_send(SessionCommand::UpdateAddresses(addresses))
+            .map_err(|_| ManagerError::CommandSendFailed)
+    }
+
+    /// Reconnect to the peer, e.g. during its rolling restart. The data already sent to it gets
+    /// delivered before the old connections are dropped. Only the validator network is affected,
+    /// the legacy network identifies peers differently.
+    pub fn recycle_peer(&self, peer_id: M::PeerId) -> Result<(), ManagerError> {
+        self.commands_for_service
+            .unbounded_send(SessionCommand::Recycle(peer_id))
+            .map_err(|_| ManagerError::CommandSendFailed)
+    }
+
+    /// Hold back the data sent to the peer without disconnecting from it, e.g. during its
+    /// maintenance. Only the validator network is affected, like when recycling.
+    pub fn pause_peer(&self, peer_id: M::PeerId) -> Result<(), ManagerError> {
+        self.commands_for_service
+            .unbounded_send(SessionCommand::Pause(peer_id))
+            .map_err(|_| ManagerError::CommandSendFailed)
+    }
+
+    /// Send out the data held back for the peer, in order, and stop holding it back.
+    pub fn resume_peer(&self, peer_id: M::PeerId) -> Result<(), ManagerError> {
+        self.commands_for_service
+            .unbounded_send(SessionCommand::Resume(peer_id))
             .map_err(|_| ManagerError::CommandSendFailed)
     }
 }
@@ -12,7 +12,7 @@ use crate::{
         ComponentNetwork, ComponentNetworkExt, Data, ReceiverComponent, SendError, SenderComponent,
         SimpleNetwork,
     },
-    Recipient, Version, Versioned,
+    NodeIndex, Recipient, Version, Versioned,
 };
 
 /// Used for routing data through split networks.
@@ -85,6 +85,10 @@ where
     fn send(&self, data: Conv::From, recipient: Recipient) -> Result<(), SendError> {
         self.sender.send(Conv::convert(data), recipient)
     }
+
+    fn peers(&self) -> Vec<NodeIndex> {
+        self.sender.peers()
+    }
 }
 
 type LeftSender<LeftData, RightData, S> =
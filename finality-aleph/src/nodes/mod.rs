@@ -32,6 +32,22 @@ use crate::{
 /// Max amount of tries we can not update a finalized block number before we will clear requests queue
 const MAX_ATTEMPTS: u32 = 5;
 
+/// Commands adjusting the validator network of a running node, e.g. issued by its operator.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NetworkAdminCommand {
+    /// Announce these addresses to the other validators instead of the ones the node started
+    /// with. Ignored if they are unusable.
+    UpdateAddresses(Vec<String>),
+    /// Reconnect to the validator, e.g. during its rolling restart. The data already sent to it
+    /// gets delivered first.
+    RecyclePeer(AuthorityId),
+    /// Hold back the data sent to the validator without disconnecting, e.g. during its
+    /// maintenance.
+    PausePeer(AuthorityId),
+    /// Send out the data held back for the validator, in order, and stop holding it back.
+    ResumePeer(AuthorityId),
+}
+
 struct JustificationVerifier {
     authority_verifier: AuthorityVerifier,
     emergency_signer: Option<AuthorityId>,
@@ -1,31 +1,57 @@
-use std::marker::PhantomData;
+use std::{marker::PhantomData, sync::Arc, time::Duration};
 
+use aleph_primitives::AuthorityId;
 use bip39::{Language, Mnemonic, MnemonicType};
-use futures::channel::oneshot;
-use log::{debug, error};
-use sc_client_api::Backend;
+use futures::{
+    channel::{mpsc, oneshot},
+    StreamExt,
+};
+use log::{debug, error, info, warn};
+use sc_client_api::{Backend, HeaderBackend};
 use sc_network::ExHashT;
 use sp_consensus::SelectChain;
 use sp_runtime::traits::Block;
 
 use crate::{
+    abft::{QuorumMonitor, StallMonitor, DEFAULT_STALL_WINDOW},
     crypto::AuthorityPen,
+    data_io::{serve_exports, OrderedDataLog},
+    metrics::OrderedDataMetrics,
     network::{
-        setup_io, ConnectionManager, ConnectionManagerConfig, Service as NetworkService,
-        SessionManager,
+        setup_io, AnnouncementSchedule, ConnectionManager, ConnectionManagerConfigBuilder,
+        PriorityWeights, SendMetrics, Service as NetworkService, SessionManager,
     },
-    nodes::{setup_justification_handler, JustificationParams},
+    nodes::{setup_justification_handler, JustificationParams, NetworkAdminCommand},
     party::{
         impls::{ChainStateImpl, SessionInfoImpl},
-        manager::NodeSessionManagerImpl,
+        manager::{NodeSessionManagerImpl, RestartPolicy},
         ConsensusParty, ConsensusPartyParams,
     },
     session_map::{AuthorityProviderImpl, FinalityNotificatorImpl, SessionMapUpdater},
-    tcp_network::new_tcp_network,
-    validator_network::{Service, KEY_TYPE},
+    tcp_network::{new_tcp_network, SystemResolver, TcpConfig, TcpMultiaddress},
+    validator_network::{
+        BlacklistConfig, ChainIdentity, Codec, ConnectionState, HandshakeConfig, HeartbeatConfig,
+        Metrics as ValidatorNetworkMetrics, ReceiveConfig, ReconnectPolicy,
+        SendChannelConfigBuilder, Service, KEY_TYPE,
+    },
     AlephConfig,
 };
 
+/// How many messages of a session arriving before we start it are held until we do.
+const EARLY_DATA_CAPACITY: usize = 64;
+/// Data arriving early is held for at most this many sessions at once.
+const EARLY_DATA_SESSIONS: usize = 2;
+/// How often the state of the connections with other validators is logged.
+const VALIDATOR_NETWORK_STATUS_INTERVAL: Duration = Duration::from_secs(60);
+/// How often, on average, we announce our authentication in every session.
+const ANNOUNCEMENT_INTERVAL: Duration = Duration::from_secs(60);
+/// How much the announcement intervals vary, so that the validators do not all announce at once.
+const ANNOUNCEMENT_JITTER: f64 = 0.2;
+/// How often the round trip time to the other validators is probed.
+const VALIDATOR_PROBE_INTERVAL: Duration = Duration::from_secs(30);
+/// How long messages to other validators wait for more to be written together with them.
+const VALIDATOR_COALESCE_WINDOW: Duration = Duration::from_millis(5);
+
 pub async fn run_validator_node<B, H, C, BE, SC>(aleph_config: AlephConfig<B, H, C, SC>)
 where
     B: Block,
@@ -48,10 +74,44 @@ where
         justification_rx,
         backup_saving_path,
         external_addresses,
+        ordered_data_exports,
+        record_consensus,
+        observer,
+        network_admin,
         validator_port,
+        registry,
         ..
     } = aleph_config;
 
+    let connection_manager_config =
+        match ConnectionManagerConfigBuilder::new(&session_period, &millisecs_per_block)
+            .staging_capacity(EARLY_DATA_CAPACITY)
+            .max_staged_sessions(EARLY_DATA_SESSIONS)
+            .announcement_schedule(AnnouncementSchedule {
+                interval: ANNOUNCEMENT_INTERVAL,
+                jitter: ANNOUNCEMENT_JITTER,
+            })
+            .build()
+        {
+            Ok(config) => config,
+            Err(e) => {
+                error!(target: "aleph-party", "Invalid connection manager configuration: {}.", e);
+                return;
+            }
+        };
+    let legacy_connection_manager_config = match ConnectionManagerConfigBuilder::new(
+        &session_period,
+        &millisecs_per_block,
+    )
+    .build()
+    {
+        Ok(config) => config,
+        Err(e) => {
+            error!(target: "aleph-party", "Invalid legacy connection manager configuration: {}.", e);
+            return;
+        }
+    };
+
     // We generate the phrase manually to only save the key in RAM, we don't want to have these
     // relatively low-importance keys getting spammed around the absolutely crucial Aleph keys.
     // The interface of `ed25519_generate_new` only allows to save in RAM by providing a mnemonic.
@@ -70,20 +130,65 @@ where
         ("0.0.0.0", validator_port),
         external_addresses,
         validator_peer_id.into(),
+        TcpConfig::default(),
+        SystemResolver,
     )
     .await
     .expect("we should have working networking");
+    let validator_network_metrics = registry.as_ref().and_then(|registry| {
+        ValidatorNetworkMetrics::register(registry)
+            .map_err(|e| {
+                warn!(target: "aleph-party", "Failed to register validator network metrics: {:?}", e);
+            })
+            .ok()
+    });
     let (validator_network_service, validator_network) = Service::new(
         dialer,
         listener,
         network_authority_pen,
         spawn_handle.clone(),
+        HeartbeatConfig {
+            probe_interval: Some(VALIDATOR_PROBE_INTERVAL),
+            ..HeartbeatConfig::default()
+        },
+        HandshakeConfig {
+            chain: ChainIdentity::new(client.info().genesis_hash.as_ref()),
+            ..HandshakeConfig::default()
+        },
+        ReceiveConfig::default(),
+        SendChannelConfigBuilder::new()
+            .coalesce_window(VALIDATOR_COALESCE_WINDOW)
+            .build()
+            .expect("the send buffer configuration is valid"),
+        Codec::default(),
+        ReconnectPolicy::default(),
+        BlacklistConfig::default(),
+        validator_network_metrics.clone(),
     );
+    let validator_network_status = validator_network_service.status_handle();
+    let validator_connected_peers = validator_network_service.connected_peers();
     let (_validator_network_exit, exit) = oneshot::channel();
     spawn_handle.spawn("aleph/validator_network", None, async move {
         debug!(target: "aleph-party", "Validator network has started.");
         validator_network_service.run(exit).await
     });
+    spawn_handle.spawn("aleph/validator_network_status", None, async move {
+        loop {
+            tokio::time::sleep(VALIDATOR_NETWORK_STATUS_INTERVAL).await;
+            let status = match validator_network_status.status().await {
+                Some(status) => status,
+                None => return,
+            };
+            let connected = status
+                .iter()
+                .filter(|peer| peer.outgoing == ConnectionState::Connected)
+                .count();
+            info!(target: "aleph-party", "Validator network connected to {} out of {} peers.", connected, status.len());
+            for peer in status {
+                debug!(target: "aleph-party", "Validator network peer status: {:?}.", peer);
+            }
+        }
+    });
 
     let block_requester = network.clone();
     let map_updater = SessionMapUpdater::<_, _, B>::new(
@@ -108,12 +213,27 @@ where
         });
 
     let (connection_io, network_io, session_io) = setup_io();
+    let (legacy_connection_io, legacy_network_io, legacy_session_io) = setup_io();
 
-    let connection_manager = ConnectionManager::new(
-        network_identity,
-        ConnectionManagerConfig::with_session_period(&session_period, &millisecs_per_block),
+    let network_service = NetworkService::new(
+        network.clone(),
+        validator_network,
+        spawn_handle.clone(),
+        network_io,
+        legacy_network_io,
+        PriorityWeights::default(),
+    )
+    // every node of the chain knows the genesis, so this only catches data sent to the wrong session
+    .with_session_checksums(
+        client.info().genesis_hash.as_ref().to_vec(),
+        validator_network_metrics,
     );
 
+    let connection_manager = ConnectionManager::new(network_identity, connection_manager_config)
+        .with_connectivity(Arc::new(move |peer_id: &AuthorityId| {
+            validator_connected_peers.contains(peer_id)
+        }));
+
     let connection_manager_task = async move {
         connection_io
             .run(connection_manager)
@@ -121,12 +241,9 @@ where
             .expect("Failed to run connection manager")
     };
 
-    let (legacy_connection_io, legacy_network_io, legacy_session_io) = setup_io();
-
-    let legacy_connection_manager = ConnectionManager::new(
-        network.clone(),
-        ConnectionManagerConfig::with_session_period(&session_period, &millisecs_per_block),
-    );
+    let legacy_connection_manager =
+        ConnectionManager::new(network.clone(), legacy_connection_manager_config)
+            .with_connectivity(network_service.legacy_connectivity());
 
     let legacy_connection_manager_task = async move {
         legacy_connection_io
@@ -136,14 +253,37 @@ where
     };
 
     let session_manager = SessionManager::new(session_io, legacy_session_io);
-    let network = NetworkService::new(
-        network.clone(),
-        validator_network,
-        spawn_handle.clone(),
-        network_io,
-        legacy_network_io,
-    );
-    let network_task = async move { network.run().await };
+    if let Some(mut commands) = network_admin {
+        let session_manager = session_manager.clone();
+        let network_admin_task = async move {
+            while let Some(command) = commands.next().await {
+                info!(target: "aleph-party", "Adjusting the validator network: {:?}.", command);
+                let result = match command {
+                    NetworkAdminCommand::UpdateAddresses(addresses) => session_manager
+                        .update_addresses(
+                            addresses
+                                .into_iter()
+                                .map(|address| {
+                                    TcpMultiaddress::new(validator_peer_id.into(), address)
+                                })
+                                .collect(),
+                        ),
+                    NetworkAdminCommand::RecyclePeer(peer_id) => {
+                        session_manager.recycle_peer(peer_id)
+                    }
+                    NetworkAdminCommand::PausePeer(peer_id) => session_manager.pause_peer(peer_id),
+                    NetworkAdminCommand::ResumePeer(peer_id) => {
+                        session_manager.resume_peer(peer_id)
+                    }
+                };
+                if let Err(e) = result {
+                    warn!(target: "aleph-party", "Failed to adjust the validator network: {:?}.", e);
+                }
+            }
+        };
+        spawn_handle.spawn("aleph/network_admin", None, network_admin_task);
+    }
+    let network_task = async move { network_service.run().await };
 
     spawn_handle.spawn("aleph/justification_handler", None, handler_task);
     debug!(target: "aleph-party", "JustificationHandler has started.");
@@ -157,26 +297,102 @@ where
     spawn_handle.spawn("aleph/network", None, network_task);
     debug!(target: "aleph-party", "Network has started.");
 
+    let (stalled_sessions_tx, mut stalled_sessions) = mpsc::unbounded();
+    let stall_reporter_task = async move {
+        while let Some(stalled) = stalled_sessions.next().await {
+            warn!(target: "aleph-party", "Session {:?} ordered nothing for {:?}, some peers might be missing.", stalled.session_id, stalled.elapsed);
+        }
+    };
+    spawn_handle.spawn("aleph/stall_reporter", None, stall_reporter_task);
+
+    let (quorum_sessions_tx, mut quorum_sessions) = mpsc::unbounded();
+    let quorum_reporter_task = async move {
+        while let Some(reached) = quorum_sessions.next().await {
+            info!(target: "aleph-party", "Session {:?} has enough peers connected to make progress.", reached.session_id);
+        }
+    };
+    spawn_handle.spawn("aleph/quorum_reporter", None, quorum_reporter_task);
+
+    let ordered_data_metrics = registry.as_ref().and_then(|registry| {
+        OrderedDataMetrics::register(registry)
+            .map_err(|e| {
+                warn!(target: "aleph-party", "Failed to register ordered data metrics: {:?}", e);
+            })
+            .ok()
+    });
+    let ordered_data_log = ordered_data_exports.map(|requests| {
+        let log = OrderedDataLog::new();
+        spawn_handle.spawn(
+            "aleph/ordered_data_exporter",
+            None,
+            serve_exports(log.clone(), requests),
+        );
+        log
+    });
+    let session_manager = NodeSessionManagerImpl::new(
+        client.clone(),
+        select_chain,
+        session_period,
+        unit_creation_delay,
+        authority_justification_tx,
+        block_requester.clone(),
+        metrics,
+        spawn_handle.into(),
+        session_manager,
+        keystore,
+    )
+    .with_stall_monitor(StallMonitor::new(DEFAULT_STALL_WINDOW, stalled_sessions_tx))
+    .with_quorum_monitor(QuorumMonitor::new(quorum_sessions_tx));
+    let session_manager = match ordered_data_metrics {
+        Some(metrics) => session_manager.with_ordered_data_metrics(metrics),
+        None => session_manager,
+    };
+    let session_manager = match ordered_data_log {
+        Some(log) => session_manager.with_ordered_data_log(log),
+        None => session_manager,
+    };
+    let session_manager = match &backup_saving_path {
+        Some(path) => session_manager.with_member_restarts(path.clone(), RestartPolicy::default()),
+        None => session_manager,
+    };
+    let session_manager = match observer {
+        true => session_manager.with_observer(),
+        false => session_manager,
+    };
+    let session_manager = match record_consensus {
+        true => {
+            let (consensus_records_tx, mut consensus_records) = mpsc::unbounded();
+            let consensus_recorder_task = async move {
+                while let Some(record) = consensus_records.next().await {
+                    debug!(target: "aleph-party", "Consensus data {:?} at {:?}: {:?}", record.direction, record.timestamp, record.data);
+                }
+            };
+            spawn_handle.spawn("aleph/consensus_recorder", None, consensus_recorder_task);
+            session_manager.with_consensus_recorder(consensus_records_tx)
+        }
+        false => session_manager,
+    };
+    let send_metrics = registry.as_ref().and_then(|registry| {
+        SendMetrics::register(registry)
+            .map_err(|e| {
+                warn!(target: "aleph-party", "Failed to register consensus send metrics: {:?}", e);
+            })
+            .ok()
+    });
+    let session_manager = match send_metrics {
+        Some(metrics) => session_manager.with_send_metrics(metrics),
+        None => session_manager,
+    };
+
     let party = ConsensusParty::new(ConsensusPartyParams {
         session_authorities,
-        sync_state: block_requester.clone(),
+        sync_state: block_requester,
         backup_saving_path,
         chain_state: ChainStateImpl {
-            client: client.clone(),
+            client,
             _phantom: PhantomData,
         },
-        session_manager: NodeSessionManagerImpl::new(
-            client,
-            select_chain,
-            session_period,
-            unit_creation_delay,
-            authority_justification_tx,
-            block_requester,
-            metrics,
-            spawn_handle.into(),
-            session_manager,
-            keystore,
-        ),
+        session_manager,
         _phantom: PhantomData,
         session_info: SessionInfoImpl::new(session_period),
     });
@@ -5,16 +5,93 @@ use std::{
     io::{Cursor, Read, Write},
     path::{Path, PathBuf},
     str::FromStr,
+    sync::Arc,
+    time::Duration,
 };
 
+use codec::{Decode, Encode};
 use log::{debug, warn};
+use parking_lot::Mutex;
+use tokio::{task, time::timeout};
+
+use crate::abft::CURRENT_VERSION;
 
 const BACKUP_FILE_EXTENSION: &str = ".abfts";
+const BACKUP_MAGIC: [u8; 4] = *b"ABFT";
+/// How long a stopping member waits for its backup to reach the disk.
+pub const SYNC_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Written at the start of every backup file, so that we never feed data in an unknown format
+/// into AlephBFT.
+#[derive(Debug, PartialEq, Eq, Encode, Decode)]
+struct BackupHeader {
+    magic: [u8; 4],
+    version: u32,
+    session_id: u32,
+}
+
+impl BackupHeader {
+    fn new(session_id: u32) -> Self {
+        BackupHeader {
+            magic: BACKUP_MAGIC,
+            version: CURRENT_VERSION,
+            session_id,
+        }
+    }
+}
+
+/// Reasons for which a backup file header might be rejected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The file ends in the middle of the header.
+    Truncated,
+    /// The backup was made with a different version of AlephBFT.
+    WrongVersion(u32),
+    /// The backup was made for a different session.
+    WrongSession(u32),
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::Truncated => write!(f, "header truncated"),
+            HeaderError::WrongVersion(version) => write!(
+                f,
+                "made with AlephBFT version {}, expected {}",
+                version, CURRENT_VERSION
+            ),
+            HeaderError::WrongSession(session_id) => {
+                write!(f, "made for session {}", session_id)
+            }
+        }
+    }
+}
+
+/// Checks the header at the start of the data and returns the rest of it.
+///
+/// Files written before headers were introduced are returned whole, they are recognized by not
+/// starting with the magic bytes. This also covers empty files, left behind if the node crashed
+/// right after creating one.
+fn strip_header(data: &[u8], session_id: u32) -> Result<&[u8], HeaderError> {
+    if !data.starts_with(&BACKUP_MAGIC) {
+        return Ok(data);
+    }
+    let mut rest = data;
+    let header = BackupHeader::decode(&mut rest).map_err(|_| HeaderError::Truncated)?;
+    if header.version != CURRENT_VERSION {
+        return Err(HeaderError::WrongVersion(header.version));
+    }
+    if header.session_id != session_id {
+        return Err(HeaderError::WrongSession(header.session_id));
+    }
+    Ok(rest)
+}
 
 #[derive(Debug)]
 pub enum BackupLoadError {
     BackupIncomplete(Vec<usize>),
     IOError(io::Error),
+    InvalidHeader(usize, HeaderError),
 }
 
 impl fmt::Display for BackupLoadError {
@@ -30,6 +107,9 @@ impl fmt::Display for BackupLoadError {
             BackupLoadError::IOError(err) => {
                 write!(f, "Backup could not be loaded because of IO error: {}", err)
             }
+            BackupLoadError::InvalidHeader(index, err) => {
+                write!(f, "Backup for run numbered {} is invalid: {}", index, err)
+            }
         }
     }
 }
@@ -42,10 +122,75 @@ impl From<io::Error> for BackupLoadError {
 
 impl std::error::Error for BackupLoadError {}
 
-pub type Saver = Box<dyn Write + Send + Sync>;
+/// Something the backup can be saved to.
+pub trait BackupSaver: Write + Send + Sync {
+    /// Flushes everything written so far and waits until it is durably stored.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl BackupSaver for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.sync_all()
+    }
+}
+
+impl BackupSaver for io::Sink {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub type Saver = Box<dyn BackupSaver>;
 pub type Loader = Box<dyn Read + Send + Sync>;
 pub type ABFTBackup = (Saver, Loader);
 
+/// A saver that can be cloned, so that it can still be synced after being handed over to
+/// AlephBFT. All the clones write to the same place.
+#[derive(Clone)]
+pub struct SharedSaver(Arc<Mutex<Saver>>);
+
+impl SharedSaver {
+    pub fn new(saver: Saver) -> Self {
+        SharedSaver(Arc::new(Mutex::new(saver)))
+    }
+}
+
+impl Write for SharedSaver {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().flush()
+    }
+}
+
+/// Syncs the saver, giving up after the timeout, so that a slow disk cannot hold a stopping
+/// member forever. The sync itself keeps going in the background after the timeout.
+pub async fn sync(saver: SharedSaver, sync_timeout: Duration) -> io::Result<()> {
+    let syncing = task::spawn_blocking(move || saver.0.lock().sync());
+    match timeout(sync_timeout, syncing).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => Err(io::Error::new(io::ErrorKind::Other, e)),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "backup sync timed out",
+        )),
+    }
+}
+
+/// Syncs the backup of a member that stopped, so that nothing it saved is lost on a clean
+/// shutdown. Failures are only logged, as there is nothing more a stopped member can do.
+pub async fn sync_stopped(saver: SharedSaver, session_id: u32) {
+    match sync(saver, SYNC_TIMEOUT).await {
+        Ok(()) => debug!(target: "aleph-party", "Synced backup for session {}", session_id),
+        Err(e) => {
+            warn!(target: "aleph-party", "Could not sync backup for session {}: {}", session_id, e)
+        }
+    }
+}
+
 /// Find all `*.abfts` files at `session_path` and return their indexes sorted, if all are present.
 fn get_session_backup_idxs(session_path: &Path) -> Result<Vec<usize>, BackupLoadError> {
     fs::create_dir_all(&session_path)?;
@@ -61,12 +206,20 @@ fn get_session_backup_idxs(session_path: &Path) -> Result<Vec<usize>, BackupLoad
     Ok(session_backups)
 }
 
-/// Load session backup at path `session_path` from all `session_idxs`.
-fn load_backup(session_path: &Path, session_idxs: &[usize]) -> Result<Loader, BackupLoadError> {
+/// Load session backup at path `session_path` from all `session_idxs`, checking their headers.
+fn load_backup(
+    session_path: &Path,
+    session_idxs: &[usize],
+    session_id: u32,
+) -> Result<Loader, BackupLoadError> {
     let mut buffer = Vec::new();
     for index in session_idxs.iter() {
         let load_path = session_path.join(format!("{}{}", index, BACKUP_FILE_EXTENSION));
-        File::open(load_path)?.read_to_end(&mut buffer)?;
+        let mut file_buffer = Vec::new();
+        File::open(load_path)?.read_to_end(&mut file_buffer)?;
+        let data = strip_header(&file_buffer, session_id)
+            .map_err(|e| BackupLoadError::InvalidHeader(*index, e))?;
+        buffer.extend_from_slice(data);
     }
     Ok(Box::new(Cursor::new(buffer)))
 }
@@ -109,11 +262,13 @@ pub fn rotate(
 
     let session_backup_idxs = get_session_backup_idxs(&session_path)?;
 
-    let backup_loader = load_backup(&session_path, &session_backup_idxs)?;
+    let backup_loader = load_backup(&session_path, &session_backup_idxs, session_id)?;
 
     let next_backup_path = get_next_path(&session_path, &session_backup_idxs);
     debug!(target: "aleph-party", "Loaded backup for session {:?}. Creating new backup file at {:?}", session_id, next_backup_path);
-    let backup_saver = Box::new(File::create(next_backup_path)?);
+    let mut backup_saver = Box::new(File::create(next_backup_path)?);
+    backup_saver.write_all(&BackupHeader::new(session_id).encode())?;
+    backup_saver.flush()?;
 
     debug!(target: "aleph-party", "Backup rotation done for session {:?}", session_id);
     Ok((backup_saver, backup_loader))
@@ -143,3 +298,124 @@ pub fn remove(path: Option<PathBuf>, session_id: u32) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Write},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use codec::Encode;
+
+    use super::{
+        strip_header, sync, BackupHeader, BackupSaver, HeaderError, SharedSaver, CURRENT_VERSION,
+    };
+
+    /// Remembers whether it was synced, taking the given time to sync.
+    struct SlowSaver {
+        delay: Duration,
+        synced: Arc<AtomicBool>,
+    }
+
+    impl Write for SlowSaver {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl BackupSaver for SlowSaver {
+        fn sync(&mut self) -> io::Result<()> {
+            thread::sleep(self.delay);
+            self.synced.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn slow_saver(delay: Duration) -> (SharedSaver, Arc<AtomicBool>) {
+        let synced = Arc::new(AtomicBool::new(false));
+        let saver = SlowSaver {
+            delay,
+            synced: synced.clone(),
+        };
+        (SharedSaver::new(Box::new(saver)), synced)
+    }
+
+    #[tokio::test]
+    async fn syncs_shared_saver() {
+        let (mut saver, synced) = slow_saver(Duration::ZERO);
+        saver.write_all(&[21, 37]).expect("saving should work");
+        sync(saver, Duration::from_secs(1))
+            .await
+            .expect("sync should succeed");
+        assert!(synced.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_slow_sync() {
+        let (saver, synced) = slow_saver(Duration::from_secs(1));
+        let err = sync(saver, Duration::from_millis(10))
+            .await
+            .expect_err("sync should time out");
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(!synced.load(Ordering::SeqCst));
+    }
+
+    fn with_header(header: BackupHeader, data: &[u8]) -> Vec<u8> {
+        let mut result = header.encode();
+        result.extend_from_slice(data);
+        result
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let data = with_header(BackupHeader::new(43), &[21, 37]);
+        assert_eq!(strip_header(&data, 43), Ok(&[21, 37][..]));
+        let data = with_header(BackupHeader::new(43), &[]);
+        assert_eq!(strip_header(&data, 43), Ok(&[][..]));
+    }
+
+    #[test]
+    fn rejects_wrong_version() {
+        let header = BackupHeader {
+            version: CURRENT_VERSION + 1,
+            ..BackupHeader::new(43)
+        };
+        let data = with_header(header, &[21, 37]);
+        assert_eq!(
+            strip_header(&data, 43),
+            Err(HeaderError::WrongVersion(CURRENT_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_session() {
+        let data = with_header(BackupHeader::new(43), &[21, 37]);
+        assert_eq!(strip_header(&data, 44), Err(HeaderError::WrongSession(43)));
+    }
+
+    #[test]
+    fn accepts_legacy_data_without_header() {
+        assert_eq!(strip_header(&[21, 37], 43), Ok(&[21, 37][..]));
+        assert_eq!(strip_header(&[21; 32], 43), Ok(&[21; 32][..]));
+        assert_eq!(strip_header(&[], 43), Ok(&[][..]));
+    }
+
+    #[test]
+    fn rejects_truncated_header() {
+        let data = with_header(BackupHeader::new(43), &[]);
+        assert_eq!(
+            strip_header(&data[..data.len() - 1], 43),
+            Err(HeaderError::Truncated)
+        );
+    }
+}
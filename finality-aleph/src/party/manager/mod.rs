@@ -1,4 +1,10 @@
-use std::{collections::HashSet, fmt::Debug, marker::PhantomData, sync::Arc};
+use std::{
+    collections::HashSet,
+    fmt::{Debug, Display, Error as FmtError, Formatter},
+    marker::PhantomData,
+    path::PathBuf,
+    sync::Arc,
+};
 
 use aleph_primitives::{AlephSessionApi, KEY_TYPE};
 use async_trait::async_trait;
@@ -15,18 +21,25 @@ use sp_runtime::{
 use crate::{
     abft::{
         current_create_aleph_config, legacy_create_aleph_config, run_current_member,
-        run_legacy_member, SpawnHandle, SpawnHandleT,
+        run_current_observer, run_legacy_member, BoundedDataProvider, CurrentNetworkData,
+        QuorumMonitor, SpawnError, SpawnHandle, SpawnHandleT, StallMonitor, MAX_DATA_SIZE,
     },
     crypto::{AuthorityPen, AuthorityVerifier},
-    data_io::{ChainTracker, DataStore, OrderedDataInterpreter},
+    data_io::{ChainTracker, DataStore, OrderedDataInterpreter, OrderedDataLog},
+    metrics::OrderedDataMetrics,
     mpsc,
     network::{
-        split, ComponentNetworkMap, ManagerError, RequestBlocks, Sender, SessionManager,
+        split, ComponentNetworkMap, CountingNetwork, ManagerError, ReceiveOnlyNetwork, Record,
+        RecordingNetwork, RequestBlocks, SendMetrics, Sender, SessionManager, SharedReceiver,
         SimpleNetwork,
     },
     party::{
-        backup::ABFTBackup, manager::aggregator::AggregatorVersion, traits::NodeSessionManager,
+        backup::{self, ABFTBackup},
+        manager::aggregator::AggregatorVersion,
+        traits::NodeSessionManager,
     },
+    substrate_network::Multiaddress as SubstrateMultiaddress,
+    tcp_network::TcpMultiaddress,
     AuthorityId, CurrentRmcNetworkData, JustificationNotification, Keychain, LegacyRmcNetworkData,
     Metrics, NodeIndex, SessionBoundaries, SessionId, SessionPeriod, UnitCreationDelay,
     VersionedNetworkData,
@@ -36,9 +49,11 @@ mod aggregator;
 mod authority;
 mod chain_tracker;
 mod data_store;
+mod supervisor;
 mod task;
 
 pub use authority::{SubtaskCommon, Subtasks, Task as AuthorityTask};
+pub use supervisor::{supervise_member, RestartPolicy};
 pub use task::{Handle, Task};
 
 use crate::{
@@ -67,13 +82,16 @@ where
     N: ComponentNetwork<VersionedNetworkData<B>> + 'static,
 {
     n_members: usize,
+    /// How many authorities the network of the session authenticates.
+    network_members: usize,
     node_id: NodeIndex,
     session_id: SessionId,
     data_network: N,
     session_boundaries: SessionBoundaries<B>,
     subtask_common: SubtaskCommon,
     data_provider: DataProvider<B>,
-    ordered_data_interpreter: OrderedDataInterpreter<B, C>,
+    /// Creates the interpreter for every run of the member, the restarted ones included.
+    new_interpreter: Box<dyn Fn() -> OrderedDataInterpreter<B, C> + Send>,
     aggregator_io: aggregator::IO<B>,
     multikeychain: Keychain,
     exit_rx: oneshot::Receiver<()>,
@@ -98,8 +116,26 @@ where
     block_requester: RB,
     metrics: Option<Metrics<<B::Header as Header>::Hash>>,
     spawn_handle: SpawnHandle,
-    session_manager: SessionManager<VersionedNetworkData<B>>,
+    session_manager:
+        SessionManager<VersionedNetworkData<B>, TcpMultiaddress, SubstrateMultiaddress>,
     keystore: Arc<dyn CryptoStore>,
+    /// Where to record all the consensus traffic of the current version, for debugging.
+    consensus_recorder: Option<mpsc::UnboundedSender<Record<CurrentNetworkData<B>>>>,
+    /// Whether to only follow the current version of the consensus, for monitoring, without
+    /// proposing any data or sending any units.
+    observer: bool,
+    /// Where to report sessions that stopped making progress.
+    stall_monitor: Option<StallMonitor>,
+    /// Where to report sessions that got enough peers connected to make progress.
+    quorum_monitor: Option<QuorumMonitor>,
+    /// Where to count the data ordered in all the sessions.
+    ordered_data_metrics: Option<OrderedDataMetrics>,
+    /// Where to keep the data ordered in the latest session, so that it can be exported.
+    ordered_data_log: Option<OrderedDataLog<B>>,
+    /// Where to count the consensus data sent to each of the nodes.
+    send_metrics: Option<SendMetrics>,
+    /// Where the backups are saved and how to restart the members that failed, if at all.
+    member_restarts: Option<(PathBuf, RestartPolicy)>,
     _phantom: PhantomData<BE>,
 }
 
@@ -122,7 +158,11 @@ where
         block_requester: RB,
         metrics: Option<Metrics<<B::Header as Header>::Hash>>,
         spawn_handle: SpawnHandle,
-        session_manager: SessionManager<VersionedNetworkData<B>>,
+        session_manager: SessionManager<
+            VersionedNetworkData<B>,
+            TcpMultiaddress,
+            SubstrateMultiaddress,
+        >,
         keystore: Arc<dyn CryptoStore>,
     ) -> Self {
         Self {
@@ -136,23 +176,136 @@ where
             spawn_handle,
             session_manager,
             keystore,
+            consensus_recorder: None,
+            observer: false,
+            stall_monitor: None,
+            quorum_monitor: None,
+            ordered_data_metrics: None,
+            ordered_data_log: None,
+            send_metrics: None,
+            member_restarts: None,
             _phantom: PhantomData,
         }
     }
 
+    /// Returns the manager reporting sessions that make no progress to the monitor.
+    pub fn with_stall_monitor(self, stall_monitor: StallMonitor) -> Self {
+        NodeSessionManagerImpl {
+            stall_monitor: Some(stall_monitor),
+            ..self
+        }
+    }
+
+    /// Returns the manager reporting sessions that reach a quorum of connected peers to the
+    /// monitor.
+    pub fn with_quorum_monitor(self, quorum_monitor: QuorumMonitor) -> Self {
+        NodeSessionManagerImpl {
+            quorum_monitor: Some(quorum_monitor),
+            ..self
+        }
+    }
+
+    /// Returns the manager counting the data ordered in all the sessions in the metrics.
+    pub fn with_ordered_data_metrics(self, ordered_data_metrics: OrderedDataMetrics) -> Self {
+        NodeSessionManagerImpl {
+            ordered_data_metrics: Some(ordered_data_metrics),
+            ..self
+        }
+    }
+
+    /// Returns the manager keeping the data ordered in the latest session in the log.
+    pub fn with_ordered_data_log(self, ordered_data_log: OrderedDataLog<B>) -> Self {
+        NodeSessionManagerImpl {
+            ordered_data_log: Some(ordered_data_log),
+            ..self
+        }
+    }
+
+    /// Returns the manager following the consensus of the current version as an observer, which
+    /// gets the ordered data and collects the justification signatures of the members, but
+    /// proposes no data and sends nothing to them.
+    pub fn with_observer(self) -> Self {
+        NodeSessionManagerImpl {
+            observer: true,
+            ..self
+        }
+    }
+
+    /// Returns the manager recording all the consensus traffic of the current version in the sink.
+    pub fn with_consensus_recorder(
+        self,
+        consensus_recorder: mpsc::UnboundedSender<Record<CurrentNetworkData<B>>>,
+    ) -> Self {
+        NodeSessionManagerImpl {
+            consensus_recorder: Some(consensus_recorder),
+            ..self
+        }
+    }
+
+    /// Returns the manager counting the consensus data sent to each of the nodes in the metrics.
+    pub fn with_send_metrics(self, send_metrics: SendMetrics) -> Self {
+        NodeSessionManagerImpl {
+            send_metrics: Some(send_metrics),
+            ..self
+        }
+    }
+
+    /// Returns the manager restarting the members that failed according to the policy, each time
+    /// from their backup saved at the path. Without the backups a restarted member would not know
+    /// the units it created before and would fork them, so members are not restarted otherwise.
+    pub fn with_member_restarts(
+        self,
+        backup_saving_path: PathBuf,
+        restart_policy: RestartPolicy,
+    ) -> Self {
+        NodeSessionManagerImpl {
+            member_restarts: Some((backup_saving_path, restart_policy)),
+            ..self
+        }
+    }
+
+    /// Runs the member under a supervisor, restarting it if the manager is configured to. The
+    /// first run gets the given backup, every later one loads what all the previous runs saved.
+    fn supervised_member(
+        &self,
+        subtask_common: SubtaskCommon,
+        backup: ABFTBackup,
+        start_member: impl FnMut(ABFTBackup) -> Result<Task, SpawnError> + Send + 'static,
+    ) -> Result<Task, SpawnError> {
+        let session_id = subtask_common.session_id;
+        let (backup_saving_path, policy) = match &self.member_restarts {
+            Some((path, policy)) => (Some(path.clone()), *policy),
+            None => (
+                None,
+                RestartPolicy {
+                    max_restarts: 0,
+                    ..RestartPolicy::default()
+                },
+            ),
+        };
+        supervise_member(
+            subtask_common,
+            policy,
+            backup,
+            move || backup::rotate(backup_saving_path.clone(), session_id),
+            start_member,
+        )
+    }
+
     fn legacy_subtasks<N: ComponentNetwork<VersionedNetworkData<B>> + 'static>(
         &self,
         params: SubtasksParams<C, SC, B, N, BE>,
-    ) -> Subtasks {
+    ) -> Result<Subtasks, SessionManagerError> {
         let SubtasksParams {
             n_members,
+            network_members,
             node_id,
             session_id,
             data_network,
             session_boundaries,
             subtask_common,
             data_provider,
-            ordered_data_interpreter,
+            new_interpreter,
             aggregator_io,
             multikeychain,
             exit_rx,
@@ -160,8 +313,14 @@ where
             chain_tracker,
             ..
         } = params;
-        let consensus_config =
-            legacy_create_aleph_config(n_members, node_id, session_id, self.unit_creation_delay);
+        let consensus_config = legacy_create_aleph_config(
+            n_members,
+            node_id,
+            session_id,
+            self.unit_creation_delay,
+            None,
+        );
+        check_members(consensus_config.n_members.0, network_members)?;
         let data_network = data_network.map();
 
         let (unfiltered_aleph_network, rmc_network) =
@@ -173,17 +332,39 @@ where
             Default::default(),
             unfiltered_aleph_network,
         );
-        Subtasks::new(
+        // Every run of the member gets a network of its own, all of them receiving from the
+        // same data store.
+        let (sender, receiver) = ComponentNetwork::into(aleph_network);
+        let receiver = SharedReceiver::new(receiver);
+        let send_metrics = self.send_metrics.clone();
+        let new_network = move || {
+            CountingNetwork::new(
+                SimpleNetwork::new(receiver.clone(), sender.clone()),
+                send_metrics.clone(),
+            )
+        };
+        let start_member = {
+            let subtask_common = subtask_common.clone();
+            let multikeychain = multikeychain.clone();
+            let stall_monitor = self.stall_monitor.clone();
+            let quorum_monitor = self.quorum_monitor.clone();
+            move |backup| {
+                run_legacy_member(
+                    subtask_common.clone(),
+                    multikeychain.clone(),
+                    consensus_config.clone(),
+                    new_network().into(),
+                    BoundedDataProvider::new(data_provider.clone(), MAX_DATA_SIZE),
+                    new_interpreter(),
+                    backup,
+                    stall_monitor.clone(),
+                    quorum_monitor.clone(),
+                )
+            }
+        };
+        Ok(Subtasks::new(
             exit_rx,
-            run_legacy_member(
-                subtask_common.clone(),
-                multikeychain.clone(),
-                consensus_config,
-                aleph_network.into(),
-                data_provider,
-                ordered_data_interpreter,
-                backup,
-            ),
+            self.supervised_member(subtask_common.clone(), backup, start_member)?,
             aggregator::task(
                 subtask_common.clone(),
                 self.client.clone(),
@@ -195,22 +376,23 @@ where
             ),
             chain_tracker::task(subtask_common.clone(), chain_tracker),
             data_store::task(subtask_common, data_store),
-        )
+        ))
     }
 
     fn current_subtasks<N: ComponentNetwork<VersionedNetworkData<B>> + 'static>(
         &self,
         params: SubtasksParams<C, SC, B, N, BE>,
-    ) -> Subtasks {
+    ) -> Result<Subtasks, SessionManagerError> {
         let SubtasksParams {
             n_members,
+            network_members,
             node_id,
             session_id,
             data_network,
             session_boundaries,
             subtask_common,
             data_provider,
-            ordered_data_interpreter,
+            new_interpreter,
             aggregator_io,
             multikeychain,
             exit_rx,
@@ -218,8 +400,16 @@ where
             chain_tracker,
             ..
         } = params;
-        let consensus_config =
-            current_create_aleph_config(n_members, node_id, session_id, self.unit_creation_delay);
+        let consensus_config = current_create_aleph_config(
+            n_members,
+            node_id,
+            session_id,
+            self.unit_creation_delay,
+            None,
+            None,
+        )
+        .expect("members of equal weight are always supported");
+        check_members(consensus_config.n_members.0, network_members)?;
         let data_network = data_network.map();
 
         let (unfiltered_aleph_network, rmc_network) =
@@ -231,18 +421,61 @@ where
             Default::default(),
             unfiltered_aleph_network,
         );
-        Subtasks::new(
-            exit_rx,
-            run_current_member(
+        // Every run of the member gets a network of its own, all of them receiving from the
+        // same data store.
+        let (sender, receiver) = ComponentNetwork::into(aleph_network);
+        let receiver = SharedReceiver::new(receiver);
+        let consensus_recorder = self.consensus_recorder.clone();
+        let send_metrics = self.send_metrics.clone();
+        let new_network = move || {
+            let network = SimpleNetwork::new(receiver.clone(), sender.clone());
+            let network = RecordingNetwork::new(network, consensus_recorder.clone());
+            CountingNetwork::new(network, send_metrics.clone())
+        };
+        let start_member = {
+            let subtask_common = subtask_common.clone();
+            let multikeychain = multikeychain.clone();
+            let stall_monitor = self.stall_monitor.clone();
+            let quorum_monitor = self.quorum_monitor.clone();
+            let observer = self.observer;
+            move |backup| match observer {
+                true => run_current_observer(
+                    subtask_common.clone(),
+                    multikeychain.clone(),
+                    consensus_config.clone(),
+                    new_network(),
+                    new_interpreter(),
+                    backup,
+                    None,
+                ),
+                false => run_current_member(
+                    subtask_common.clone(),
+                    multikeychain.clone(),
+                    consensus_config.clone(),
+                    new_network().into(),
+                    BoundedDataProvider::new(data_provider.clone(), MAX_DATA_SIZE),
+                    new_interpreter(),
+                    backup,
+                    stall_monitor.clone(),
+                    quorum_monitor.clone(),
+                    None,
+                ),
+            }
+        };
+        let aggregator = match self.observer {
+            // Observers collect the signatures of the members without sharing theirs.
+            true => aggregator::task(
                 subtask_common.clone(),
-                multikeychain.clone(),
-                consensus_config,
-                aleph_network.into(),
-                data_provider,
-                ordered_data_interpreter,
-                backup,
+                self.client.clone(),
+                aggregator_io,
+                session_boundaries,
+                self.metrics.clone(),
+                multikeychain,
+                AggregatorVersion::<_, LegacyNetworkType<B>>::Current(ReceiveOnlyNetwork::new(
+                    rmc_network,
+                )),
             ),
-            aggregator::task(
+            false => aggregator::task(
                 subtask_common.clone(),
                 self.client.clone(),
                 aggregator_io,
@@ -251,9 +484,14 @@ where
                 multikeychain,
                 AggregatorVersion::<_, LegacyNetworkType<B>>::Current(rmc_network),
             ),
+        };
+        Ok(Subtasks::new(
+            exit_rx,
+            self.supervised_member(subtask_common.clone(), backup, start_member)?,
+            aggregator,
             chain_tracker::task(subtask_common.clone(), chain_tracker),
             data_store::task(subtask_common, data_store),
-        )
+        ))
     }
 
     async fn spawn_subtasks(
@@ -263,7 +501,7 @@ where
         node_id: NodeIndex,
         exit_rx: oneshot::Receiver<()>,
         backup: ABFTBackup,
-    ) -> Subtasks {
+    ) -> Result<Subtasks, SessionManagerError> {
         debug!(target: "afa", "Authority task {:?}", session_id);
 
         let authority_verifier = AuthorityVerifier::new(authorities.to_vec());
@@ -285,11 +523,32 @@ where
             self.metrics.clone(),
         );
 
-        let ordered_data_interpreter = OrderedDataInterpreter::<B, C>::new(
-            blocks_for_aggregator,
-            self.client.clone(),
-            session_boundaries.clone(),
-        );
+        let new_interpreter = {
+            let client = self.client.clone();
+            let session_boundaries = session_boundaries.clone();
+            let ordered_data_metrics = self.ordered_data_metrics.clone();
+            let ordered_data_log = self.ordered_data_log.clone();
+            move || {
+                let ordered_data_interpreter = OrderedDataInterpreter::<B, C>::new(
+                    blocks_for_aggregator.clone(),
+                    client.clone(),
+                    session_boundaries.clone(),
+                );
+                let ordered_data_interpreter = match &ordered_data_metrics {
+                    Some(metrics) => ordered_data_interpreter.with_metrics(metrics.clone()),
+                    None => ordered_data_interpreter,
+                };
+                match &ordered_data_log {
+                    // A restarted member orders everything from the start of the session again,
+                    // so the log starts anew with it.
+                    Some(log) => {
+                        log.start_session(session_id);
+                        ordered_data_interpreter.with_log(log.clone())
+                    }
+                    None => ordered_data_interpreter,
+                }
+            }
+        };
 
         let subtask_common = SubtaskCommon {
             spawn_handle: self.spawn_handle.clone(),
@@ -300,7 +559,7 @@ where
             justifications_for_chain: self.authority_justification_tx.clone(),
         };
 
-        let data_network = self
+        let (data_network, network_members) = self
             .session_manager
             .start_validator_session(session_id, authority_verifier, node_id, authority_pen)
             .await
@@ -312,13 +571,14 @@ where
 
         let params = SubtasksParams {
             n_members: authorities.len(),
+            network_members: network_members.0,
             node_id,
             session_id,
             data_network,
             session_boundaries,
             subtask_common,
             data_provider,
-            ordered_data_interpreter,
+            new_interpreter: Box::new(new_interpreter),
             aggregator_io,
             multikeychain,
             exit_rx,
@@ -349,6 +609,44 @@ where
 pub enum SessionManagerError {
     NotAuthority,
     ManagerError(ManagerError),
+    /// The consensus was configured for a different number of members than the network of the
+    /// session authenticates.
+    MembersMismatch {
+        consensus: usize,
+        network: usize,
+    },
+    Spawn(SpawnError),
+}
+
+impl From<SpawnError> for SessionManagerError {
+    fn from(e: SpawnError) -> Self {
+        SessionManagerError::Spawn(e)
+    }
+}
+
+impl Display for SessionManagerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        use SessionManagerError::*;
+        match self {
+            NotAuthority => write!(f, "not an authority in the session"),
+            ManagerError(e) => write!(f, "network session manager failed: {:?}", e),
+            MembersMismatch { consensus, network } => write!(
+                f,
+                "consensus configured for {} members, but the network authenticates {}",
+                consensus, network
+            ),
+            Spawn(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Checks that the consensus is configured for exactly the members the network of the session
+/// knows about, running it otherwise would break it silently.
+fn check_members(consensus: usize, network: usize) -> Result<(), SessionManagerError> {
+    match consensus == network {
+        true => Ok(()),
+        false => Err(SessionManagerError::MembersMismatch { consensus, network }),
+    }
 }
 
 #[async_trait]
@@ -369,13 +667,13 @@ where
         node_id: NodeIndex,
         backup: ABFTBackup,
         authorities: &[AuthorityId],
-    ) -> AuthorityTask {
+    ) -> Result<AuthorityTask, Self::Error> {
         let (exit, exit_rx) = futures::channel::oneshot::channel();
         let subtasks = self
             .spawn_subtasks(session, authorities, node_id, exit_rx, backup)
-            .await;
+            .await?;
 
-        AuthorityTask::new(
+        Ok(AuthorityTask::new(
             self.spawn_handle
                 .spawn_essential("aleph/session_authority", async move {
                     if subtasks.wait_completion().await.is_err() {
@@ -384,7 +682,7 @@ where
                 }),
             node_id,
             exit,
-        )
+        ))
     }
 
     async fn early_start_validator_session(
@@ -418,9 +716,21 @@ where
             .map_err(SessionManagerError::ManagerError)
     }
 
+    fn update_authorities(
+        &self,
+        session: SessionId,
+        authorities: &[AuthorityId],
+    ) -> Result<(), Self::Error> {
+        let authority_verifier = AuthorityVerifier::new(authorities.to_vec());
+
+        self.session_manager
+            .update_authorities(session, authority_verifier)
+            .map_err(SessionManagerError::ManagerError)
+    }
+
     fn stop_session(&self, session: SessionId) -> Result<(), Self::Error> {
         self.session_manager
-            .stop_session(session)
+            .stop_session(session, true)
             .map_err(SessionManagerError::ManagerError)
     }
 
@@ -439,3 +749,20 @@ where
             .map(|id| id.into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{check_members, SessionManagerError};
+
+    #[test]
+    fn rejects_mismatched_members() {
+        assert!(check_members(4, 4).is_ok());
+        assert!(matches!(
+            check_members(4, 5),
+            Err(SessionManagerError::MembersMismatch {
+                consensus: 4,
+                network: 5,
+            })
+        ));
+    }
+}
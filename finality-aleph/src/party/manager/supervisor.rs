@@ -0,0 +1,263 @@
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use log::{debug, error, warn};
+use tokio::time::sleep;
+
+use crate::{
+    abft::SpawnError,
+    party::{
+        backup::{ABFTBackup, BackupLoadError},
+        manager::{SubtaskCommon, Task},
+    },
+};
+
+/// How a member that failed gets restarted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RestartPolicy {
+    /// How many times the member can be restarted before we give up on it.
+    pub max_restarts: usize,
+    /// How long to wait before the first restart, doubled before every next one.
+    pub initial_backoff: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            max_restarts: 3,
+            initial_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Starts the member and then restarts it whenever it fails, until it finishes cleanly or fails
+/// more times than the policy allows. Every restart gets the backup loaded anew, so it resumes
+/// where the failed run stopped, without losing any ordered data. Only starting the first run can
+/// fail right away, a restart that cannot even be started is not retried, as the spawner refuses
+/// tasks only when the node is shutting down.
+pub fn supervise_member(
+    subtask_common: SubtaskCommon,
+    policy: RestartPolicy,
+    backup: ABFTBackup,
+    mut load_backup: impl FnMut() -> Result<ABFTBackup, BackupLoadError> + Send + 'static,
+    mut start_member: impl FnMut(ABFTBackup) -> Result<Task, SpawnError> + Send + 'static,
+) -> Result<Task, SpawnError> {
+    let SubtaskCommon {
+        spawn_handle,
+        session_id,
+    } = subtask_common;
+    let (stop, mut exit) = oneshot::channel();
+    let mut member = start_member(backup)?;
+
+    let task = async move {
+        let mut restarts = 0;
+        let mut backoff = policy.initial_backoff;
+        loop {
+            tokio::select! {
+                result = member.stopped() => match result {
+                    Ok(()) => {
+                        debug!(target: "aleph-party", "Member of session {:?} finished.", session_id);
+                        return Ok(());
+                    }
+                    Err(()) if restarts < policy.max_restarts => {
+                        warn!(target: "aleph-party", "Member of session {:?} failed, restarting it in {:?}.", session_id, backoff);
+                    }
+                    Err(()) => {
+                        error!(target: "aleph-party", "Member of session {:?} failed {} times, giving up.", session_id, restarts + 1);
+                        return Err(());
+                    }
+                },
+                _ = &mut exit => return member.stop().await,
+            }
+            tokio::select! {
+                _ = sleep(backoff) => (),
+                _ = &mut exit => return Ok(()),
+            }
+            restarts += 1;
+            backoff = backoff.saturating_mul(2);
+            let backup = match load_backup() {
+                Ok(backup) => backup,
+                Err(e) => {
+                    error!(target: "aleph-party", "Failed to load the backup for member of session {:?}: {}", session_id, e);
+                    return Err(());
+                }
+            };
+            member = match start_member(backup) {
+                Ok(member) => member,
+                Err(e) => {
+                    error!(target: "aleph-party", "Failed to restart member of session {:?}: {}", session_id, e);
+                    return Err(());
+                }
+            };
+        }
+    };
+
+    let handle = spawn_handle.spawn_essential_with_result("aleph/member_supervisor", task);
+    Ok(Task::new(handle, stop))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Cursor, Read, Write},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use futures::{
+        channel::{mpsc, oneshot},
+        StreamExt,
+    };
+    use parking_lot::Mutex;
+    use sc_service::TaskManager;
+    use tokio::runtime::Handle;
+
+    use super::{supervise_member, RestartPolicy};
+    use crate::{
+        abft::{SpawnError, SpawnHandle},
+        party::{
+            backup::{ABFTBackup, BackupSaver},
+            manager::{SubtaskCommon, Task},
+        },
+    };
+
+    const SESSION_ID: u32 = 43;
+    const POLICY: RestartPolicy = RestartPolicy {
+        max_restarts: 2,
+        initial_backoff: Duration::from_millis(10),
+    };
+
+    /// Keeps everything saved in memory, so that it can be loaded again.
+    #[derive(Clone, Default)]
+    struct MemoryBackup(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for MemoryBackup {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl BackupSaver for MemoryBackup {
+        fn sync(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl MemoryBackup {
+        fn rotate(&self) -> ABFTBackup {
+            let saved = self.0.lock().clone();
+            (Box::new(self.clone()), Box::new(Cursor::new(saved)))
+        }
+    }
+
+    /// Starts members that save a unit to the backup and fail, until the given number of them
+    /// failed. Reports what each of them loaded from the backup.
+    fn failing_members(
+        spawn_handle: SpawnHandle,
+        failures: usize,
+        loaded: mpsc::UnboundedSender<Vec<u8>>,
+    ) -> impl FnMut(ABFTBackup) -> Result<Task, SpawnError> + Send + 'static {
+        let mut started = 0;
+        move |(mut saver, mut loader)| {
+            let mut backup = Vec::new();
+            loader
+                .read_to_end(&mut backup)
+                .expect("memory backup should load");
+            loaded.unbounded_send(backup).expect("test should listen");
+            let fail = started < failures;
+            started += 1;
+            let (stop, exit) = oneshot::channel();
+            let task = async move {
+                if fail {
+                    saver.write_all(b"unit").expect("memory backup should save");
+                    return Err(());
+                }
+                let _ = exit.await;
+                Ok(())
+            };
+            let handle = spawn_handle.spawn_essential_with_result("member", task);
+            Ok(Task::new(handle, stop))
+        }
+    }
+
+    #[tokio::test]
+    async fn restarts_failed_member_from_backup() {
+        let task_manager = TaskManager::new(Handle::current(), None).unwrap();
+        let spawn_handle: SpawnHandle = task_manager.spawn_handle().into();
+        let backup = MemoryBackup::default();
+        let (loaded_tx, mut loaded) = mpsc::unbounded();
+        let supervisor = supervise_member(
+            SubtaskCommon {
+                spawn_handle: spawn_handle.clone(),
+                session_id: SESSION_ID,
+            },
+            POLICY,
+            backup.rotate(),
+            move || Ok(backup.rotate()),
+            failing_members(spawn_handle, 1, loaded_tx),
+        )
+        .expect("member should start");
+
+        assert_eq!(loaded.next().await, Some(Vec::new()));
+        // the restarted member sees what the failed one saved
+        assert_eq!(loaded.next().await, Some(b"unit".to_vec()));
+        assert_eq!(supervisor.stop().await, Ok(()));
+        assert_eq!(loaded.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_too_many_failures() {
+        let task_manager = TaskManager::new(Handle::current(), None).unwrap();
+        let spawn_handle: SpawnHandle = task_manager.spawn_handle().into();
+        let backup = MemoryBackup::default();
+        let (loaded_tx, loaded) = mpsc::unbounded();
+        let mut supervisor = supervise_member(
+            SubtaskCommon {
+                spawn_handle: spawn_handle.clone(),
+                session_id: SESSION_ID,
+            },
+            POLICY,
+            backup.rotate(),
+            move || Ok(backup.rotate()),
+            failing_members(spawn_handle, usize::MAX, loaded_tx),
+        )
+        .expect("member should start");
+
+        assert_eq!(supervisor.stopped().await, Err(()));
+        let loaded: Vec<_> = loaded.collect().await;
+        assert_eq!(loaded.len(), POLICY.max_restarts + 1);
+        assert_eq!(loaded.last(), Some(&b"unit".repeat(POLICY.max_restarts)));
+    }
+
+    #[tokio::test]
+    async fn does_not_restart_member_finishing_cleanly() {
+        let task_manager = TaskManager::new(Handle::current(), None).unwrap();
+        let spawn_handle: SpawnHandle = task_manager.spawn_handle().into();
+        let backup = MemoryBackup::default();
+        let mut starts = 0;
+        let mut supervisor = supervise_member(
+            SubtaskCommon {
+                spawn_handle: spawn_handle.clone(),
+                session_id: SESSION_ID,
+            },
+            POLICY,
+            backup.rotate(),
+            move || Ok(backup.rotate()),
+            move |_| {
+                starts += 1;
+                assert_eq!(starts, 1, "a member that finished should not be restarted");
+                let (stop, _) = oneshot::channel();
+                let handle = spawn_handle.spawn_essential_with_result("member", async { Ok(()) });
+                Ok(Task::new(handle, stop))
+            },
+        )
+        .expect("member should start");
+
+        assert_eq!(supervisor.stopped().await, Ok(()));
+    }
+}
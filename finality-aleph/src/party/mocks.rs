@@ -1,6 +1,7 @@
 use std::{
     collections::HashSet,
     hash::Hash,
+    io::{Cursor, Error as IoError, ErrorKind, Result as IoResult, Write},
     sync::{Arc, Mutex},
 };
 
@@ -9,7 +10,7 @@ use async_trait::async_trait;
 use crate::{
     oneshot,
     party::{
-        backup::ABFTBackup,
+        backup::{ABFTBackup, BackupSaver, Loader, Saver},
         manager::AuthorityTask,
         traits::{Block, ChainState, NodeSessionManager, SessionInfo, SyncState},
     },
@@ -87,6 +88,7 @@ pub struct MockNodeSessionManager {
     pub validator_session_started: AMutex<HashSet<SessionId>>,
     pub session_stopped: AMutex<HashSet<SessionId>>,
     pub session_early_started: AMutex<HashSet<SessionId>>,
+    pub authorities_updated: AMutex<HashSet<SessionId>>,
     pub node_id: AMutex<Option<AuthorityId>>,
 }
 
@@ -97,6 +99,7 @@ impl MockNodeSessionManager {
             validator_session_started: Default::default(),
             session_stopped: Default::default(),
             session_early_started: Default::default(),
+            authorities_updated: Default::default(),
             node_id: Default::default(),
         }
     }
@@ -121,13 +124,13 @@ impl NodeSessionManager for Arc<MockNodeSessionManager> {
         node_id: NodeIndex,
         _backup: ABFTBackup,
         _authorities: &[AuthorityId],
-    ) -> AuthorityTask {
+    ) -> Result<AuthorityTask, Self::Error> {
         self.insert(self.validator_session_started.clone(), session);
 
         let (exit, _) = oneshot::channel();
         let handle = async { Ok(()) };
 
-        AuthorityTask::new(Box::pin(handle), node_id, exit)
+        Ok(AuthorityTask::new(Box::pin(handle), node_id, exit))
     }
 
     async fn early_start_validator_session(
@@ -150,6 +153,16 @@ impl NodeSessionManager for Arc<MockNodeSessionManager> {
         Ok(())
     }
 
+    fn update_authorities(
+        &self,
+        session: SessionId,
+        _authorities: &[AuthorityId],
+    ) -> Result<(), Self::Error> {
+        self.insert(self.authorities_updated.clone(), session);
+
+        Ok(())
+    }
+
     fn stop_session(&self, session: SessionId) -> Result<(), Self::Error> {
         self.insert(self.session_stopped.clone(), session);
 
@@ -195,3 +208,94 @@ impl SessionInfo<SimpleBlock> for MockSessionInfo {
         session_id.0 * self.session_period
     }
 }
+
+/// A backup kept in memory, holding at most `capacity` bytes. All the clones share the same
+/// bytes, so whatever got written through one of them can be read again through another.
+#[derive(Clone)]
+pub struct InMemoryBackup {
+    data: AMutex<Vec<u8>>,
+    capacity: usize,
+}
+
+impl InMemoryBackup {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            data: Default::default(),
+            capacity,
+        }
+    }
+
+    /// A saver appending to the shared bytes and a loader reading the bytes written so far.
+    pub fn backup(&self) -> ABFTBackup {
+        let saver: Saver = Box::new(self.clone());
+        let loader: Loader = Box::new(Cursor::new(self.bytes()));
+        (saver, loader)
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        self.data.lock().unwrap().clone()
+    }
+}
+
+impl Write for InMemoryBackup {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let mut data = self.data.lock().unwrap();
+        if data.len() + buf.len() > self.capacity {
+            return Err(IoError::new(ErrorKind::Other, "in-memory backup full"));
+        }
+        data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl BackupSaver for InMemoryBackup {
+    fn sync(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use codec::Encode;
+
+    use super::InMemoryBackup;
+
+    #[test]
+    fn backup_can_be_read_back() {
+        let backup = InMemoryBackup::new(1024);
+        let (mut saver, mut loader) = backup.backup();
+        let mut read = Vec::new();
+        loader.read_to_end(&mut read).unwrap();
+        assert!(read.is_empty());
+
+        let units: Vec<_> = (0..10u64)
+            .map(|unit| (unit, vec![unit; 3]).encode())
+            .collect();
+        for unit in &units {
+            saver.write_all(unit).unwrap();
+        }
+        saver.flush().unwrap();
+        let expected = units.concat();
+        assert_eq!(backup.bytes(), expected);
+
+        let (_, mut loader) = backup.backup();
+        let mut read = Vec::new();
+        loader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn backup_is_bounded() {
+        let backup = InMemoryBackup::new(4);
+        let (mut saver, _) = backup.backup();
+        saver.write_all(&[1, 2, 3]).unwrap();
+        assert!(saver.write_all(&[4, 5]).is_err());
+        assert_eq!(backup.bytes(), vec![1, 2, 3]);
+    }
+}
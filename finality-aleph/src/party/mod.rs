@@ -6,11 +6,11 @@ use tokio::{task::spawn_blocking, time::sleep};
 
 use crate::{
     party::{
-        manager::{Handle, SubtaskCommon as AuthoritySubtaskCommon, Task},
+        manager::{AuthorityTask, Handle, SubtaskCommon as AuthoritySubtaskCommon, Task},
         traits::{Block, ChainState, NodeSessionManager, SessionInfo, SyncState},
     },
     session_map::ReadOnlySessionMap,
-    SessionId,
+    AuthorityId, NodeIndex, SessionId,
 };
 
 pub(crate) mod backup;
@@ -120,24 +120,26 @@ where
             Ok(authority_data) => authority_data,
         };
         let authorities = authority_data.authorities();
+        // The latest authorities of the session we know of, even if we could not follow them.
+        let mut known_authorities = authorities.clone();
 
         trace!(target: "aleph-party", "Authority data for session {:?}: {:?}", session_id, authorities);
-        let mut maybe_authority_task = if let Some(node_id) =
-            self.session_manager.node_idx(authorities).await
-        {
+        let node_id = self.session_manager.node_idx(authorities).await;
+        let mut maybe_authority_task = if let Some(node_id) = node_id {
             match backup::rotate(self.backup_saving_path.clone(), session_id.0) {
                 Ok(backup) => {
                     debug!(target: "aleph-party", "Running session {:?} as authority id {:?}", session_id, node_id);
-                    Some(
-                        self.session_manager
-                            .spawn_authority_task_for_session(
-                                session_id,
-                                node_id,
-                                backup,
-                                authorities,
-                            )
-                            .await,
-                    )
+                    match self
+                        .session_manager
+                        .spawn_authority_task_for_session(session_id, node_id, backup, authorities)
+                        .await
+                    {
+                        Ok(task) => Some(task),
+                        Err(e) => {
+                            error!(target: "aleph-party", "Failed to start the authority task for session {:?}, not taking part in the consensus: {:?}", session_id, e);
+                            None
+                        }
+                    }
                 }
                 Err(err) => {
                     error!(
@@ -173,6 +175,18 @@ where
                         debug!(target: "aleph-party", "Terminating session {:?}", session_id);
                         break;
                     }
+                    if let Some(authority_data) = self.session_authorities.get(session_id).await {
+                        if *authority_data.authorities() != known_authorities {
+                            known_authorities = authority_data.authorities().clone();
+                            self.update_authorities(
+                                session_id,
+                                node_id,
+                                authorities.len(),
+                                &known_authorities,
+                                &mut maybe_authority_task,
+                            ).await;
+                        }
+                    }
                     check_session_status = Delay::new(SESSION_STATUS_CHECK_PERIOD);
                 },
                 Some(next_session_authority_data) = async {
@@ -236,6 +250,61 @@ where
         }
     }
 
+    /// Follows a change of the authorities of the running session, e.g. after an emergency
+    /// rotation. The consensus cannot follow a change of their number or of our index among
+    /// them, so such changes are ignored. Otherwise the network starts using the new authorities
+    /// and the authority task, if any, gets restarted with them from its backup.
+    async fn update_authorities(
+        &mut self,
+        session_id: SessionId,
+        node_id: Option<NodeIndex>,
+        n_members: usize,
+        authorities: &[AuthorityId],
+        maybe_authority_task: &mut Option<AuthorityTask>,
+    ) {
+        if authorities.len() != n_members {
+            warn!(target: "aleph-party", "Not following the change of the number of authorities of session {:?} from {} to {}.", session_id, n_members, authorities.len());
+            return;
+        }
+        if self.session_manager.node_idx(authorities).await != node_id {
+            warn!(target: "aleph-party", "Not following the change of the authorities of session {:?} moving us from index {:?}.", session_id, node_id);
+            return;
+        }
+        if let Err(e) = self
+            .session_manager
+            .update_authorities(session_id, authorities)
+        {
+            warn!(target: "aleph-party", "Failed to update the authorities of session {:?}: {:?}", session_id, e);
+            return;
+        }
+        info!(target: "aleph-party", "Following the new authorities of session {:?}.", session_id);
+        let (task, node_id) = match (maybe_authority_task.take(), node_id) {
+            (Some(task), Some(node_id)) => (task, node_id),
+            _ => return,
+        };
+        debug!(target: "aleph-party", "Restarting the authority task with the new authorities.");
+        if task.stop().await.is_err() {
+            warn!(target: "aleph-party", "Authority task did not stop silently");
+        }
+        let backup = match backup::rotate(self.backup_saving_path.clone(), session_id.0) {
+            Ok(backup) => backup,
+            Err(err) => {
+                error!(target: "aleph-party", "Error loading the backup of session {:?}, not taking part in the consensus anymore: {}", session_id, err);
+                return;
+            }
+        };
+        match self
+            .session_manager
+            .spawn_authority_task_for_session(session_id, node_id, backup, authorities)
+            .await
+        {
+            Ok(task) => *maybe_authority_task = Some(task),
+            Err(e) => {
+                error!(target: "aleph-party", "Failed to restart the authority task for session {:?}, not taking part in the consensus anymore: {:?}", session_id, e);
+            }
+        }
+    }
+
     pub async fn run(mut self) {
         let starting_session = self.catch_up().await;
         for curr_id in starting_session.0.. {
@@ -745,4 +814,87 @@ mod tests {
             .run_for_n_blocks(SESSION_PERIOD)
             .await;
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn party_follows_authorities_change_in_running_session() {
+        let (test, party) = PartyTest::new(SessionPeriod(SESSION_PERIOD));
+
+        let authorities: Vec<_> = (0..10)
+            .map(|id| UintAuthorityId(id).to_public_key())
+            .collect();
+        let mut rotated_authorities = authorities.clone();
+        rotated_authorities[5] = UintAuthorityId(10).to_public_key();
+
+        let state = PartyState {
+            validator_started: vec![SessionId(0)],
+            early_started: vec![],
+            stopped: vec![],
+            non_validator_started: vec![],
+        };
+
+        let test = test
+            .set_authorities_for_session_at_block(0, authorities, SessionId(0))
+            .set_authorities_for_session_at_block(10, rotated_authorities, SessionId(0))
+            .set_node_id_for_session_at_block(0, Some(UintAuthorityId(0).to_public_key()))
+            .expect_session_states_at_block(9, state)
+            .run_party(party)
+            .run_for_n_blocks(15)
+            .await;
+        sleep(SESSION_STATUS_CHECK_PERIOD + Duration::from_millis(100)).await;
+
+        assert_eq!(
+            *test
+                .controller
+                .node_session_manager
+                .authorities_updated
+                .lock()
+                .unwrap(),
+            HashSet::from([SessionId(0)])
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn party_ignores_incompatible_authorities_change() {
+        let (test, party) = PartyTest::new(SessionPeriod(SESSION_PERIOD));
+
+        let authorities: Vec<_> = (0..10)
+            .map(|id| UintAuthorityId(id).to_public_key())
+            .collect();
+        let mut moving_us = authorities.clone();
+        moving_us.swap(0, 1);
+
+        let state_1 = PartyState {
+            validator_started: vec![SessionId(0)],
+            early_started: vec![],
+            stopped: vec![],
+            non_validator_started: vec![],
+        };
+
+        let state_2 = PartyState {
+            validator_started: vec![SessionId(0)],
+            early_started: vec![],
+            stopped: vec![],
+            non_validator_started: vec![],
+        };
+
+        let test = test
+            .set_authorities_for_session_at_block(0, authorities.clone(), SessionId(0))
+            .set_authorities_for_session_at_block(10, authorities[1..].to_vec(), SessionId(0))
+            .set_authorities_for_session_at_block(15, moving_us, SessionId(0))
+            .set_node_id_for_session_at_block(0, Some(UintAuthorityId(0).to_public_key()))
+            .expect_session_states_at_block(9, state_1)
+            .expect_session_states_at_block(15, state_2)
+            .run_party(party)
+            .run_for_n_blocks(20)
+            .await;
+        sleep(SESSION_STATUS_CHECK_PERIOD + Duration::from_millis(100)).await;
+
+        assert!(test
+            .controller
+            .node_session_manager
+            .authorities_updated
+            .lock()
+            .unwrap()
+            .is_empty());
+    }
 }
@@ -43,7 +43,7 @@ pub trait NodeSessionManager {
         node_id: NodeIndex,
         backup: ABFTBackup,
         authorities: &[AuthorityId],
-    ) -> AuthorityTask;
+    ) -> Result<AuthorityTask, Self::Error>;
 
     /// Prepare validator session.
     async fn early_start_validator_session(
@@ -59,6 +59,14 @@ pub trait NodeSessionManager {
         authorities: &[AuthorityId],
     ) -> Result<(), Self::Error>;
 
+    /// Replaces the authorities of the running session. Their number and our index among them
+    /// have to stay the same.
+    fn update_authorities(
+        &self,
+        session: SessionId,
+        authorities: &[AuthorityId],
+    ) -> Result<(), Self::Error>;
+
     /// Terminates the session.
     fn stop_session(&self, session: SessionId) -> Result<(), Self::Error>;
 
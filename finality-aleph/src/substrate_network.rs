@@ -1,7 +1,7 @@
-use std::{borrow::Cow, collections::HashSet, fmt, iter, pin::Pin, sync::Arc};
+use std::{borrow::Cow, collections::HashSet, fmt, iter, net::IpAddr, pin::Pin, sync::Arc};
 
 use async_trait::async_trait;
-use codec::{Decode, Encode};
+use codec::{Compact, Decode, Encode};
 use futures::stream::{Stream, StreamExt};
 use log::error;
 use sc_network::{
@@ -12,8 +12,8 @@ use sp_api::NumberFor;
 use sp_runtime::traits::Block;
 
 use crate::network::{
-    Event, EventStream, Multiaddress as MultiaddressT, Network, NetworkIdentity, NetworkSender,
-    PeerId as PeerIdT, Protocol, RequestBlocks,
+    AddressScope, Event, EventStream, Multiaddress as MultiaddressT, Network, NetworkIdentity,
+    NetworkSender, PeerId as PeerIdT, Protocol, RequestBlocks,
 };
 
 impl<B: Block, H: ExHashT> RequestBlocks<B> for Arc<NetworkService<B, H>> {
@@ -114,6 +114,11 @@ impl From<Multiaddress> for Multiaddr {
 }
 
 impl Encode for Multiaddress {
+    fn size_hint(&self) -> usize {
+        let length = self.0.as_ref().len();
+        Compact(length as u32).size_hint() + length
+    }
+
     fn using_encoded<R, F: FnOnce(&[u8]) -> R>(&self, f: F) -> R {
         self.0.to_vec().using_encoded(f)
     }
@@ -186,6 +191,21 @@ impl MultiaddressT for Multiaddress {
             }
         }
     }
+
+    fn scope(&self) -> AddressScope {
+        use MultiaddressProtocol::*;
+        let mut protocols = self.0.iter();
+        let scope = match protocols.next() {
+            Some(Ip4(ip)) => IpAddr::V4(ip).into(),
+            Some(Ip6(ip)) => IpAddr::V6(ip).into(),
+            Some(Dns(_)) | Some(Dns4(_)) | Some(Dns6(_)) | Some(Dnsaddr(_)) => AddressScope::Global,
+            _ => AddressScope::Unroutable,
+        };
+        match protocols.next() {
+            Some(Tcp(0)) | Some(Udp(0)) => AddressScope::Unroutable,
+            _ => scope,
+        }
+    }
 }
 
 /// Name of the network protocol used by Aleph Zero. This is how messages
@@ -309,8 +329,9 @@ impl EventStream<Multiaddress> for NetworkEventStream {
                             Err(_) => continue,
                         }
                     }
-                    NotificationsReceived { messages, .. } => {
+                    NotificationsReceived { remote, messages } => {
                         return Some(Messages(
+                            remote.into(),
                             messages
                                 .into_iter()
                                 .filter_map(|(protocol, data)| {
@@ -396,7 +417,14 @@ mod tests {
     use codec::{Decode, Encode};
 
     use super::Multiaddress;
-    use crate::network::Multiaddress as _;
+    use crate::{
+        network::{
+            mock::crypto_basics,
+            testing::{Authentication, SessionHandler},
+            AddressPolicy, AddressScope, Multiaddress as _,
+        },
+        SessionId,
+    };
 
     fn address(text: &str) -> Multiaddress {
         Multiaddress(text.parse().unwrap())
@@ -466,4 +494,89 @@ mod tests {
             multiaddr,
         );
     }
+
+    #[test]
+    fn size_hint_is_exact() {
+        let address = address(
+            "/dns4/example.com/tcp/30333/p2p/12D3KooWRkGLz4YbVmrsWK75VjFTs8NvaBu42xhAmQaP4KeJpw1L",
+        );
+        assert_eq!(address.size_hint(), address.encode().len());
+    }
+
+    #[test]
+    fn recognizes_address_scopes() {
+        use AddressScope::*;
+        let cases = [
+            ("/ip4/0.0.0.0/tcp/30333", Unroutable),
+            ("/ip4/1.2.3.4/tcp/0", Unroutable),
+            ("/ip4/127.0.0.1/tcp/30333", Loopback),
+            ("/ip4/192.168.1.1/tcp/30333", Local),
+            ("/ip4/1.2.3.4/tcp/30333", Global),
+            ("/ip6/::/tcp/30333", Unroutable),
+            ("/ip6/::1/tcp/30333", Loopback),
+            ("/ip6/fd00::1/tcp/30333", Local),
+            ("/ip6/2001:db8::1/tcp/30333", Global),
+            ("/dns4/example.com/tcp/30333", Global),
+            ("/memory/1", Unroutable),
+        ];
+        for (text, scope) in cases {
+            assert_eq!(address(text).scope(), scope, "wrong scope of {}", text);
+        }
+    }
+
+    #[test]
+    fn policy_filters_mixed_addresses() {
+        let policy = AddressPolicy {
+            min_scope: AddressScope::Local,
+            ..AddressPolicy::default()
+        };
+        let accepted: Vec<_> = [
+            "/ip4/0.0.0.0/tcp/30333",
+            "/ip4/127.0.0.1/tcp/30333",
+            "/ip4/10.0.0.1/tcp/30333",
+            "/ip4/1.2.3.4/tcp/30333",
+        ]
+        .into_iter()
+        .map(address)
+        .filter(|address| policy.accepts(address))
+        .collect();
+        assert_eq!(
+            accepted,
+            vec![
+                address("/ip4/10.0.0.1/tcp/30333"),
+                address("/ip4/1.2.3.4/tcp/30333")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn auth_data_roundtrips_mixed_addresses() {
+        let (mut authorities, verifier) = crypto_basics(1).await;
+        let addresses: Vec<_> = [
+            "/ip4/1.2.3.4/tcp/30333/p2p/12D3KooWRkGLz4YbVmrsWK75VjFTs8NvaBu42xhAmQaP4KeJpw1L",
+            "/ip6/2001:db8::1/tcp/30333/p2p/12D3KooWRkGLz4YbVmrsWK75VjFTs8NvaBu42xhAmQaP4KeJpw1L",
+            "/dns/validator.example.com/tcp/30333/p2p/12D3KooWRkGLz4YbVmrsWK75VjFTs8NvaBu42xhAmQaP4KeJpw1L",
+            "/dns6/validator.example.com/tcp/30333/p2p/12D3KooWRkGLz4YbVmrsWK75VjFTs8NvaBu42xhAmQaP4KeJpw1L",
+        ]
+        .into_iter()
+        .map(address)
+        .collect();
+        let handler = SessionHandler::new(
+            authorities.pop(),
+            verifier,
+            SessionId(43),
+            addresses.clone(),
+        )
+        .await
+        .unwrap();
+        let authentication = handler.authentication().unwrap();
+        let decoded = Authentication::<Multiaddress>::decode(&mut &authentication.encode()[..])
+            .expect("should decode");
+        assert_eq!(decoded, authentication);
+        assert_eq!(decoded.0.addresses(), addresses);
+        assert_eq!(
+            decoded.0.validate_addresses(&AddressPolicy::default()),
+            addresses
+        );
+    }
 }
@@ -1,15 +1,24 @@
-use std::{io::Result as IoResult, net::ToSocketAddrs as _};
+use std::{
+    collections::HashMap,
+    io::Result as IoResult,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use aleph_primitives::AuthorityId;
 use codec::{Decode, Encode};
-use log::info;
+use log::{debug, info};
+use parking_lot::Mutex;
+use socket2::{SockRef, TcpKeepalive};
 use tokio::net::{
+    lookup_host,
     tcp::{OwnedReadHalf, OwnedWriteHalf},
     TcpListener, TcpStream, ToSocketAddrs,
 };
 
 use crate::{
-    network::{Multiaddress, NetworkIdentity, PeerId},
+    network::{AddressScope, Multiaddress, NetworkIdentity, PeerId},
     validator_network::{Dialer, Listener, Splittable},
 };
 
@@ -23,16 +32,59 @@ impl Splittable for TcpStream {
     }
 }
 
+/// Socket options of the validator connections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TcpConfig {
+    /// Whether to send small messages right away, instead of waiting to batch them.
+    pub nodelay: bool,
+    /// How long the connection has to be idle before the OS starts checking whether the peer is
+    /// still there, if at all.
+    pub keepalive: Option<Duration>,
+    /// How long the resolved host names are remembered before resolving them again.
+    pub resolution_ttl: Duration,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        TcpConfig {
+            nodelay: true,
+            keepalive: None,
+            resolution_ttl: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Applies the socket options to a freshly established connection. Failing to do so is not
+/// fatal, the connection works anyway.
+fn configure(stream: &TcpStream, config: TcpConfig) {
+    if stream.set_linger(None).is_err() {
+        info!(target: "validator-network", "stream.set_linger(None) failed.");
+    };
+    if let Err(e) = stream.set_nodelay(config.nodelay) {
+        info!(target: "validator-network", "stream.set_nodelay({}) failed: {}.", config.nodelay, e);
+    }
+    if let Some(keepalive) = config.keepalive {
+        let keepalive = TcpKeepalive::new().with_time(keepalive);
+        if let Err(e) = SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+            info!(target: "validator-network", "Setting the keepalive failed: {}.", e);
+        }
+    }
+}
+
+/// Accepts connections, applying the socket options to them.
+struct TcpConfiguredListener {
+    listener: TcpListener,
+    config: TcpConfig,
+}
+
 #[async_trait::async_trait]
-impl Listener for TcpListener {
+impl Listener for TcpConfiguredListener {
     type Connection = TcpStream;
     type Error = std::io::Error;
 
     async fn accept(&mut self) -> Result<Self::Connection, Self::Error> {
-        let stream = TcpListener::accept(self).await.map(|(stream, _)| stream)?;
-        if stream.set_linger(None).is_err() {
-            info!(target: "validator-network", "stream.set_linger(None) failed.");
-        };
+        let (stream, _) = self.listener.accept().await?;
+        configure(&stream, self.config);
         Ok(stream)
     }
 }
@@ -46,6 +98,13 @@ pub struct TcpMultiaddress {
     address: String,
 }
 
+impl TcpMultiaddress {
+    /// The address, in any form the resolver understands, of the peer.
+    pub fn new(peer_id: AuthorityId, address: String) -> Self {
+        TcpMultiaddress { peer_id, address }
+    }
+}
+
 impl Multiaddress for TcpMultiaddress {
     type PeerId = AuthorityId;
 
@@ -59,13 +118,125 @@ impl Multiaddress for TcpMultiaddress {
             false => None,
         }
     }
+
+    fn scope(&self) -> AddressScope {
+        if let Ok(address) = self.address.parse::<SocketAddr>() {
+            return match address.port() {
+                0 => AddressScope::Unroutable,
+                _ => address.ip().into(),
+            };
+        }
+        // Otherwise it should be a host name with a port, which we cannot check without resolving.
+        // IPv6 addresses have to be in brackets to be told apart from the port.
+        match self.address.rsplit_once(':') {
+            Some((host, port))
+                if !host.is_empty()
+                    && !host.contains(':')
+                    && matches!(port.parse::<u16>(), Ok(port) if port != 0) =>
+            {
+                AddressScope::Global
+            }
+            _ => AddressScope::Unroutable,
+        }
+    }
+}
+
+/// Translates host names with ports, such as `validator.example.com:30333`, into socket addresses
+/// that can be dialed.
+#[async_trait::async_trait]
+pub trait Resolver: Clone + Send + Sync + 'static {
+    async fn resolve(&self, host: &str) -> IoResult<Vec<SocketAddr>>;
 }
 
+/// Resolves host names using the operating system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+#[async_trait::async_trait]
+impl Resolver for SystemResolver {
+    async fn resolve(&self, host: &str) -> IoResult<Vec<SocketAddr>> {
+        Ok(lookup_host(host).await?.collect())
+    }
+}
+
+/// Remembers the successful resolutions for the configured time, shared by all the clones.
 #[derive(Clone)]
-struct TcpDialer;
+struct CachingResolver<R: Resolver> {
+    resolver: R,
+    ttl: Duration,
+    cache: Arc<Mutex<HashMap<String, (Vec<SocketAddr>, Instant)>>>,
+}
+
+impl<R: Resolver> CachingResolver<R> {
+    fn new(resolver: R, ttl: Duration) -> Self {
+        CachingResolver {
+            resolver,
+            ttl,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Socket addresses are used as they are, only host names get resolved.
+    async fn resolve(&self, address: &str) -> IoResult<Vec<SocketAddr>> {
+        if let Ok(socket_address) = address.parse::<SocketAddr>() {
+            return Ok(vec![socket_address]);
+        }
+        let cached = self.cache.lock().get(address).cloned();
+        if let Some((socket_addresses, resolved_at)) = cached {
+            if resolved_at.elapsed() < self.ttl {
+                return Ok(socket_addresses);
+            }
+        }
+        let socket_addresses = self.resolver.resolve(address).await?;
+        self.cache.lock().insert(
+            address.to_string(),
+            (socket_addresses.clone(), Instant::now()),
+        );
+        Ok(socket_addresses)
+    }
+}
+
+/// Resolves the addresses keeping the order in which they were announced, so that the ones
+/// the peer lists first are also dialed first. Addresses that fail to resolve are skipped.
+async fn resolve<R: Resolver>(
+    resolver: &CachingResolver<R>,
+    addresses: Vec<TcpMultiaddress>,
+) -> Vec<SocketAddr> {
+    let mut resolved = Vec::new();
+    for address in addresses {
+        match resolver.resolve(&address.address).await {
+            Ok(socket_addresses) => {
+                for socket_address in socket_addresses {
+                    if !resolved.contains(&socket_address) {
+                        resolved.push(socket_address);
+                    }
+                }
+            }
+            Err(e) => {
+                debug!(target: "validator-network", "Failed to resolve address {}: {}.", address.address, e)
+            }
+        }
+    }
+    resolved
+}
+
+#[derive(Clone)]
+struct TcpDialer<R: Resolver> {
+    config: TcpConfig,
+    resolver: CachingResolver<R>,
+}
+
+impl<R: Resolver> TcpDialer<R> {
+    fn new(config: TcpConfig, resolver: R) -> Self {
+        TcpDialer {
+            config,
+            resolver: CachingResolver::new(resolver, config.resolution_ttl),
+        }
+    }
+}
 
 #[async_trait::async_trait]
-impl Dialer<TcpMultiaddress> for TcpDialer {
+impl<R: Resolver> Dialer<TcpMultiaddress> for TcpDialer<R> {
     type Connection = TcpStream;
     type Error = std::io::Error;
 
@@ -73,15 +244,10 @@ impl Dialer<TcpMultiaddress> for TcpDialer {
         &mut self,
         addresses: Vec<TcpMultiaddress>,
     ) -> Result<Self::Connection, Self::Error> {
-        let parsed_addresses: Vec<_> = addresses
-            .into_iter()
-            .filter_map(|address| address.address.to_socket_addrs().ok())
-            .flatten()
-            .collect();
-        let stream = TcpStream::connect(&parsed_addresses[..]).await?;
-        if stream.set_linger(None).is_err() {
-            info!(target: "validator-network", "stream.set_linger(None) failed.");
-        };
+        // Tries the addresses one by one, returning the first connection that succeeds.
+        let resolved_addresses = resolve(&self.resolver, addresses).await;
+        let stream = TcpStream::connect(&resolved_addresses[..]).await?;
+        configure(&stream, self.config);
         Ok(stream)
     }
 }
@@ -101,11 +267,14 @@ impl NetworkIdentity for TcpNetworkIdentity {
 }
 
 /// Create a new tcp network, including an identity that can be used for constructing
-/// authentications for other peers.
-pub async fn new_tcp_network<A: ToSocketAddrs>(
+/// authentications for other peers. All the connections get the configured socket options, the
+/// host names of peers get translated by the resolver before dialing.
+pub async fn new_tcp_network<A: ToSocketAddrs, R: Resolver>(
     listening_addresses: A,
     external_addresses: Vec<String>,
     peer_id: AuthorityId,
+    config: TcpConfig,
+    resolver: R,
 ) -> IoResult<(
     impl Dialer<TcpMultiaddress>,
     impl Listener,
@@ -115,12 +284,296 @@ pub async fn new_tcp_network<A: ToSocketAddrs>(
     let identity = TcpNetworkIdentity {
         addresses: external_addresses
             .into_iter()
-            .map(|address| TcpMultiaddress {
-                peer_id: peer_id.clone(),
-                address,
-            })
+            .map(|address| TcpMultiaddress::new(peer_id.clone(), address))
             .collect(),
         peer_id,
     };
-    Ok((TcpDialer {}, listener, identity))
+    Ok((
+        TcpDialer::new(config, resolver),
+        TcpConfiguredListener { listener, config },
+        identity,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{Error as IoError, ErrorKind, Result as IoResult},
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use codec::{Decode, Encode};
+    use socket2::SockRef;
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        time::{timeout, Duration},
+    };
+
+    use super::{
+        resolve, CachingResolver, Resolver, SystemResolver, TcpConfig, TcpConfiguredListener,
+        TcpDialer, TcpMultiaddress,
+    };
+    use crate::{
+        network::{
+            mock::crypto_basics,
+            testing::{Authentication, SessionHandler},
+            AddressPolicy, AddressScope, Multiaddress,
+        },
+        validator_network::{Dialer, Listener},
+        SessionId,
+    };
+
+    async fn addresses(texts: &[&str]) -> Vec<TcpMultiaddress> {
+        let (mut authorities, _) = crypto_basics(1).await;
+        let peer_id = authorities.pop().unwrap().1.authority_id();
+        texts
+            .iter()
+            .map(|text| TcpMultiaddress {
+                peer_id: peer_id.clone(),
+                address: text.to_string(),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn recognizes_address_scopes() {
+        use AddressScope::*;
+        let cases = [
+            ("1.2.3.4:0", Unroutable),
+            ("127.0.0.1:30333", Loopback),
+            ("1.2.3.4:30333", Global),
+            ("[::1]:30333", Loopback),
+            ("[fd00::1]:30333", Local),
+            ("[2001:db8::1]:30333", Global),
+            ("validator.example.com:30333", Global),
+            ("validator.example.com:0", Unroutable),
+            // ambiguous without brackets
+            ("2001:db8::1:30333", Unroutable),
+        ];
+        let texts: Vec<_> = cases.iter().map(|(text, _)| *text).collect();
+        for (address, (text, scope)) in addresses(&texts).await.into_iter().zip(cases) {
+            assert_eq!(address.scope(), scope, "wrong scope of {}", text);
+        }
+    }
+
+    #[tokio::test]
+    async fn auth_data_roundtrips_mixed_addresses() {
+        let (mut authorities, verifier) = crypto_basics(1).await;
+        let authority = authorities.pop().unwrap();
+        let addresses: Vec<_> = [
+            "1.2.3.4:30333",
+            "[2001:db8::1]:30333",
+            "validator.example.com:30333",
+        ]
+        .iter()
+        .map(|text| TcpMultiaddress {
+            peer_id: authority.1.authority_id(),
+            address: text.to_string(),
+        })
+        .collect();
+        let handler =
+            SessionHandler::new(Some(authority), verifier, SessionId(43), addresses.clone())
+                .await
+                .unwrap();
+        let authentication = handler.authentication().unwrap();
+        let decoded = Authentication::<TcpMultiaddress>::decode(&mut &authentication.encode()[..])
+            .expect("should decode");
+        assert_eq!(decoded, authentication);
+        assert_eq!(decoded.0.addresses(), addresses);
+        assert_eq!(
+            decoded.0.validate_addresses(&AddressPolicy::default()),
+            addresses
+        );
+    }
+
+    #[tokio::test]
+    async fn resolves_in_announced_order() {
+        let resolved = resolve(
+            &CachingResolver::new(SystemResolver, Duration::from_secs(60)),
+            addresses(&[
+                "[::1]:30333",
+                "127.0.0.1:30334",
+                "not an address",
+                "localhost:30335",
+                "127.0.0.1:30334",
+            ])
+            .await,
+        )
+        .await;
+        let expected_prefix: [SocketAddr; 2] = [
+            "[::1]:30333".parse().unwrap(),
+            "127.0.0.1:30334".parse().unwrap(),
+        ];
+        assert!(resolved.starts_with(&expected_prefix));
+        assert!(resolved.len() > 2);
+        assert!(resolved[2..].iter().all(|address| address.port() == 30335));
+    }
+
+    #[tokio::test]
+    async fn dials_first_reachable_address() {
+        let closed_port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let texts = [
+            format!("127.0.0.1:{}", closed_port),
+            format!("127.0.0.1:{}", first.local_addr().unwrap().port()),
+            format!("127.0.0.1:{}", second.local_addr().unwrap().port()),
+        ];
+        let texts: Vec<_> = texts.iter().map(String::as_str).collect();
+        let _connection = TcpDialer::new(TcpConfig::default(), SystemResolver)
+            .connect(addresses(&texts).await)
+            .await
+            .expect("should connect");
+        timeout(Duration::from_secs(5), first.accept())
+            .await
+            .expect("the first reachable address should be dialed")
+            .unwrap();
+        assert!(timeout(Duration::from_millis(100), second.accept())
+            .await
+            .is_err());
+    }
+
+    /// Resolves the configured host names only, counting the resolutions.
+    #[derive(Clone)]
+    struct MockResolver {
+        hosts: Arc<HashMap<String, Vec<SocketAddr>>>,
+        resolutions: Arc<AtomicUsize>,
+    }
+
+    impl MockResolver {
+        fn new(hosts: Vec<(&str, Vec<SocketAddr>)>) -> Self {
+            MockResolver {
+                hosts: Arc::new(
+                    hosts
+                        .into_iter()
+                        .map(|(host, socket_addresses)| (host.to_string(), socket_addresses))
+                        .collect(),
+                ),
+                resolutions: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+
+        fn resolutions(&self) -> usize {
+            self.resolutions.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Resolver for MockResolver {
+        async fn resolve(&self, host: &str) -> IoResult<Vec<SocketAddr>> {
+            self.resolutions.fetch_add(1, Ordering::SeqCst);
+            self.hosts
+                .get(host)
+                .cloned()
+                .ok_or_else(|| IoError::new(ErrorKind::NotFound, "unknown host"))
+        }
+    }
+
+    #[tokio::test]
+    async fn expands_host_names_skipping_failures() {
+        let endpoints: Vec<SocketAddr> = vec![
+            "10.0.0.1:30333".parse().unwrap(),
+            "10.0.0.2:30333".parse().unwrap(),
+        ];
+        let resolver = MockResolver::new(vec![("validator.example.com:30333", endpoints.clone())]);
+        let resolved = resolve(
+            &CachingResolver::new(resolver.clone(), Duration::from_secs(60)),
+            addresses(&[
+                "unknown.example.com:30333",
+                "validator.example.com:30333",
+                "1.2.3.4:30333",
+            ])
+            .await,
+        )
+        .await;
+        let mut expected = endpoints;
+        expected.push("1.2.3.4:30333".parse().unwrap());
+        assert_eq!(resolved, expected);
+        // socket addresses do not need resolving
+        assert_eq!(resolver.resolutions(), 2);
+    }
+
+    #[tokio::test]
+    async fn caches_resolutions_for_ttl() {
+        let resolver = MockResolver::new(vec![(
+            "validator.example.com:30333",
+            vec!["10.0.0.1:30333".parse().unwrap()],
+        )]);
+        let addresses = addresses(&["validator.example.com:30333"]).await;
+        let caching_resolver = CachingResolver::new(resolver.clone(), Duration::from_secs(60));
+        resolve(&caching_resolver, addresses.clone()).await;
+        resolve(&caching_resolver.clone(), addresses.clone()).await;
+        assert_eq!(resolver.resolutions(), 1);
+
+        let caching_resolver = CachingResolver::new(resolver.clone(), Duration::ZERO);
+        resolve(&caching_resolver, addresses.clone()).await;
+        resolve(&caching_resolver, addresses).await;
+        assert_eq!(resolver.resolutions(), 3);
+    }
+
+    #[tokio::test]
+    async fn dials_resolved_host_names() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let resolver = MockResolver::new(vec![(
+            "validator.example.com:30333",
+            vec![listener.local_addr().unwrap()],
+        )]);
+        let _connection = TcpDialer::new(TcpConfig::default(), resolver)
+            .connect(addresses(&["validator.example.com:30333"]).await)
+            .await
+            .expect("should connect");
+        timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .expect("the resolved address should be dialed")
+            .unwrap();
+    }
+
+    async fn connect_with(config: TcpConfig) -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("127.0.0.1:{}", listener.local_addr().unwrap().port());
+        let mut listener = TcpConfiguredListener { listener, config };
+        let dialed = TcpDialer::new(config, SystemResolver)
+            .connect(addresses(&[&address]).await)
+            .await
+            .expect("should connect");
+        let accepted = timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .expect("should accept")
+            .unwrap();
+        (dialed, accepted)
+    }
+
+    #[tokio::test]
+    async fn applies_socket_options_to_connections() {
+        let config = TcpConfig {
+            nodelay: true,
+            keepalive: Some(Duration::from_secs(30)),
+            ..TcpConfig::default()
+        };
+        let (dialed, accepted) = connect_with(config).await;
+        for stream in [dialed, accepted] {
+            assert!(stream.nodelay().unwrap());
+            assert!(SockRef::from(&stream).keepalive().unwrap());
+        }
+        let config = TcpConfig {
+            nodelay: false,
+            keepalive: None,
+            ..TcpConfig::default()
+        };
+        let (dialed, accepted) = connect_with(config).await;
+        for stream in [dialed, accepted] {
+            assert!(!stream.nodelay().unwrap());
+            assert!(!SockRef::from(&stream).keepalive().unwrap());
+        }
+    }
 }
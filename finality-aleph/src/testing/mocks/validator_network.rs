@@ -4,8 +4,8 @@ use aleph_primitives::{AuthorityId, KEY_TYPE};
 use sp_keystore::{testing::KeyStore, CryptoStore};
 
 use crate::{
-    network::{mock::Channel, Data, Multiaddress, NetworkIdentity},
-    validator_network::Network,
+    network::{mock::Channel, AddressScope, Data, Multiaddress, NetworkIdentity},
+    validator_network::{Capabilities, Network},
 };
 
 pub type MockMultiaddress = (AuthorityId, String);
@@ -23,14 +23,22 @@ impl Multiaddress for MockMultiaddress {
             false => None,
         }
     }
+
+    fn scope(&self) -> AddressScope {
+        AddressScope::Global
+    }
 }
 
 #[derive(Clone)]
 pub struct MockNetwork<D: Data> {
     pub add_connection: Channel<(AuthorityId, Vec<MockMultiaddress>)>,
     pub remove_connection: Channel<AuthorityId>,
+    pub pause: Channel<AuthorityId>,
+    pub resume: Channel<AuthorityId>,
     pub send: Channel<(D, AuthorityId)>,
-    pub next: Channel<D>,
+    pub next: Channel<(D, AuthorityId, Capabilities)>,
+    /// What all the peers agreed on, if anything.
+    pub capabilities: Option<Capabilities>,
     id: AuthorityId,
     addresses: Vec<MockMultiaddress>,
 }
@@ -45,11 +53,23 @@ impl<D: Data> Network<MockMultiaddress, D> for MockNetwork<D> {
         self.remove_connection.send(peer);
     }
 
+    fn pause(&mut self, peer: AuthorityId) {
+        self.pause.send(peer);
+    }
+
+    fn resume(&mut self, peer: AuthorityId) {
+        self.resume.send(peer);
+    }
+
+    fn capabilities(&self, _peer: &AuthorityId) -> Option<Capabilities> {
+        self.capabilities
+    }
+
     fn send(&self, data: D, recipient: AuthorityId) {
         self.send.send((data, recipient));
     }
 
-    async fn next(&mut self) -> Option<D> {
+    async fn next(&mut self) -> Option<(D, AuthorityId, Capabilities)> {
         self.next.next().await
     }
 }
@@ -75,8 +95,11 @@ impl<D: Data> MockNetwork<D> {
         MockNetwork {
             add_connection: Channel::new(),
             remove_connection: Channel::new(),
+            pause: Channel::new(),
+            resume: Channel::new(),
             send: Channel::new(),
             next: Channel::new(),
+            capabilities: None,
             addresses,
             id,
         }
@@ -86,6 +109,8 @@ impl<D: Data> MockNetwork<D> {
     pub async fn _close_channels(self) {
         assert!(self.add_connection.close().await.is_none());
         assert!(self.remove_connection.close().await.is_none());
+        assert!(self.pause.close().await.is_none());
+        assert!(self.resume.close().await.is_none());
         assert!(self.send.close().await.is_none());
         assert!(self.next.close().await.is_none());
     }
@@ -3,3 +3,4 @@ mod data_store;
 mod justification;
 pub mod mocks;
 mod network;
+mod wire_format;
@@ -1,13 +1,18 @@
 use std::{
     collections::{HashMap, HashSet},
     iter::FromIterator,
+    sync::Arc,
     time::Duration,
 };
 
 use codec::{Decode, Encode};
 use futures::channel::oneshot;
 use sc_service::TaskManager;
-use tokio::{runtime::Handle, task::JoinHandle, time::timeout};
+use tokio::{
+    runtime::Handle,
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
 
 use crate::{
     crypto::{AuthorityPen, AuthorityVerifier},
@@ -17,11 +22,16 @@ use crate::{
             MockPeerId,
         },
         setup_io,
-        testing::{Authentication, DataInSession, DiscoveryMessage, NetworkData, SessionHandler},
-        ConnectionManager, ConnectionManagerConfig, DataNetwork, NetworkIdentity, Protocol,
-        Service as NetworkService, SessionManager,
+        testing::{
+            Authentication, Channel, ControlMessage, DataInSession, DiscoveryMessage, NetworkData,
+            SessionHandler, VersionedAuthentication,
+        },
+        ConnectionManager, ConnectionManagerConfig, DataNetwork, NetworkIdentity, PriorityWeights,
+        Protocol, Service as NetworkService, SessionManager,
+    },
+    testing::mocks::validator_network::{
+        MockMultiaddress as MockValidatorMultiaddress, MockNetwork as MockValidatorNetwork,
     },
-    testing::mocks::validator_network::MockNetwork as MockValidatorNetwork,
     MillisecsPerBlock, NodeIndex, Recipient, SessionId, SessionPeriod,
 };
 
@@ -65,9 +75,9 @@ type MockNetworkData = NetworkData<MockData, MockMultiaddress>;
 struct TestData {
     pub authorities: Vec<Authority>,
     pub authority_verifier: AuthorityVerifier,
-    pub session_manager: SessionManager<MockData>,
+    pub session_manager: SessionManager<MockData, MockMultiaddress, MockMultiaddress>,
     pub network: MockNetwork,
-    pub _validator_network: MockValidatorNetwork<DataInSession<MockData>>,
+    pub validator_network: MockValidatorNetwork<DataInSession<Arc<MockData>>>,
     network_manager_exit_tx: oneshot::Sender<()>,
     legacy_network_manager_exit_tx: oneshot::Sender<()>,
     network_service_exit_tx: oneshot::Sender<()>,
@@ -122,6 +132,7 @@ async fn prepare_one_session_test_data() -> TestData {
         task_manager.spawn_handle(),
         network_io,
         legacy_network_io,
+        PriorityWeights::default(),
     );
 
     let network_manager_task = async move {
@@ -157,7 +168,7 @@ async fn prepare_one_session_test_data() -> TestData {
         authority_verifier,
         session_manager,
         network,
-        _validator_network: validator_network,
+        validator_network,
         network_manager_exit_tx,
         legacy_network_manager_exit_tx,
         network_service_exit_tx,
@@ -187,6 +198,7 @@ impl TestData {
                 self.authorities[node_id].pen(),
             )
             .await
+            .map(|(data_network, _)| data_network)
             .expect("Failed to start validator session!")
     }
 
@@ -272,17 +284,46 @@ impl TestData {
             self.connect_identity_to_network(authority.peer_id(), Protocol::Generic);
             self.connect_identity_to_network(authority.peer_id(), Protocol::Validator);
 
-            self.network.emit_event(MockEvent::Messages(vec![(
-                Protocol::Generic,
-                MockNetworkData::Meta(DiscoveryMessage::AuthenticationBroadcast(
-                    handler.authentication().unwrap(),
-                ))
-                .encode()
-                .into(),
-            )]));
+            self.network.emit_event(MockEvent::Messages(
+                authority.peer_id(),
+                vec![(
+                    Protocol::Generic,
+                    MockNetworkData::Meta(DiscoveryMessage::AuthenticationBroadcast(
+                        handler.authentication().unwrap(),
+                    ))
+                    .encode()
+                    .into(),
+                )],
+            ));
         }
     }
 
+    /// Authenticates the authority to the connection manager of the validator network, returning
+    /// the address it authenticated with.
+    async fn connect_validator_network_authority(
+        &mut self,
+        node_id: usize,
+        session_id: u32,
+    ) -> MockValidatorMultiaddress {
+        let authority = &self.authorities[node_id];
+        let address = (authority.pen().authority_id(), String::from("address"));
+        let handler = SessionHandler::new(
+            Some((NodeIndex(node_id), authority.pen())),
+            self.authority_verifier.clone(),
+            SessionId(session_id),
+            vec![address.clone()],
+        )
+        .await
+        .unwrap();
+        let authentication: VersionedAuthentication<MockValidatorMultiaddress> =
+            DiscoveryMessage::AuthenticationBroadcast(handler.authentication().unwrap()).into();
+        self.network.emit_event(MockEvent::Messages(
+            authority.peer_id(),
+            vec![(Protocol::Authentication, authentication.encode().into())],
+        ));
+        address
+    }
+
     async fn start_session(&mut self, session_id: u32) -> impl DataNetwork<MockData> {
         let data_network = self.start_validator_session(0, session_id).await;
         self.connect_session_authorities(session_id).await;
@@ -300,6 +341,7 @@ impl TestData {
 
     fn emit_notifications_received(&mut self, messages: Vec<MockNetworkData>) {
         self.network.emit_event(MockEvent::Messages(
+            MockPeerId::random(),
             messages
                 .iter()
                 .map(|m| (Protocol::Generic, m.encode().into()))
@@ -396,8 +438,12 @@ impl TestData {
     )> {
         loop {
             match self.next_sent(p).await {
-                Some((MockNetworkData::Data(data, session_id), peer_id, protocol)) => {
-                    return Some((MockNetworkData::Data(data, session_id), peer_id, protocol))
+                Some((MockNetworkData::Data(data, session_id, channel), peer_id, protocol)) => {
+                    return Some((
+                        MockNetworkData::Data(data, session_id, channel),
+                        peer_id,
+                        protocol,
+                    ))
                 }
                 None => return None,
                 _ => {}
@@ -414,7 +460,7 @@ impl TestData {
         self.network_service_handle.await.unwrap();
         while let Some((data, peer_id, protocol)) = self.network.send_message.try_next().await {
             if protocol == Protocol::Validator {
-                if let Ok(MockNetworkData::Data(data, session_id)) =
+                if let Ok(MockNetworkData::Data(data, session_id, _)) =
                     MockNetworkData::decode(&mut data.as_slice())
                 {
                     panic!("No Data messages to validators should be sent during cleanup. All data messages should be handled before.\
@@ -479,14 +525,17 @@ async fn test_sends_authentication_on_receiving_broadcast() {
     let sending_peer = test_data.authorities[1].clone();
     test_data.connect_identity_to_network(sending_peer.peer_id(), Protocol::Generic);
 
-    test_data.network.emit_event(MockEvent::Messages(vec![(
-        Protocol::Generic,
-        MockNetworkData::Meta(DiscoveryMessage::AuthenticationBroadcast(
-            sending_peer_handler.authentication().unwrap(),
-        ))
-        .encode()
-        .into(),
-    )]));
+    test_data.network.emit_event(MockEvent::Messages(
+        sending_peer.peer_id(),
+        vec![(
+            Protocol::Generic,
+            MockNetworkData::Meta(DiscoveryMessage::AuthenticationBroadcast(
+                sending_peer_handler.authentication().unwrap(),
+            ))
+            .encode()
+            .into(),
+        )],
+    ));
 
     assert_eq!(
         timeout(DEFAULT_TIMEOUT, test_data.network.add_reserved.next())
@@ -532,14 +581,17 @@ async fn test_forwards_authentication_broadcast() {
         test_data.connect_identity_to_network(authority.peer_id(), Protocol::Generic);
     }
 
-    test_data.network.emit_event(MockEvent::Messages(vec![(
-        Protocol::Generic,
-        MockNetworkData::Meta(DiscoveryMessage::AuthenticationBroadcast(
-            sending_peer_handler.authentication().unwrap(),
-        ))
-        .encode()
-        .into(),
-    )]));
+    test_data.network.emit_event(MockEvent::Messages(
+        sending_peer.peer_id(),
+        vec![(
+            Protocol::Generic,
+            MockNetworkData::Meta(DiscoveryMessage::AuthenticationBroadcast(
+                sending_peer_handler.authentication().unwrap(),
+            ))
+            .encode()
+            .into(),
+        )],
+    ));
 
     assert_eq!(
         timeout(DEFAULT_TIMEOUT, test_data.network.add_reserved.next())
@@ -599,6 +651,7 @@ async fn test_connects_to_others() {
     test_data.emit_notifications_received(vec![MockNetworkData::Data(
         data.clone(),
         SessionId(session_id),
+        Channel::Main,
     )]);
     assert_eq!(
         timeout(DEFAULT_TIMEOUT, data_network.next()).await,
@@ -631,6 +684,7 @@ async fn test_connects_to_others_early_validator() {
     test_data.emit_notifications_received(vec![MockNetworkData::Data(
         data.clone(),
         SessionId(session_id),
+        Channel::Main,
     )]);
     assert_eq!(
         timeout(DEFAULT_TIMEOUT, data_network.next()).await,
@@ -649,7 +703,7 @@ async fn test_stops_session() {
 
     test_data
         .session_manager
-        .stop_session(SessionId(session_id))
+        .stop_session(SessionId(session_id), false)
         .unwrap();
     assert_eq!(
         timeout(DEFAULT_TIMEOUT, test_data.network.remove_reserved.next())
@@ -673,6 +727,237 @@ async fn test_stops_session() {
     test_data.cleanup().await;
 }
 
+async fn wait_for_peers(data_network: &impl DataNetwork<MockData>, expected: Vec<NodeIndex>) {
+    timeout(DEFAULT_TIMEOUT, async {
+        while data_network.peers() != expected {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("peers should reach the expected state");
+}
+
+#[tokio::test]
+async fn test_lists_session_peers() {
+    let session_id = 43;
+    let mut test_data = prepare_one_session_test_data().await;
+    let data_network = test_data.start_validator_session(0, session_id).await;
+    assert!(data_network.peers().is_empty());
+
+    test_data.connect_session_authorities(session_id).await;
+    test_data.check_sends_add_reserved_node().await;
+    wait_for_peers(&data_network, vec![NodeIndex(1), NodeIndex(2)]).await;
+
+    test_data
+        .session_manager
+        .stop_session(SessionId(session_id), false)
+        .unwrap();
+    wait_for_peers(&data_network, Vec::new()).await;
+    test_data.cleanup().await;
+}
+
+#[tokio::test]
+async fn test_drains_stopped_session() {
+    let session_id = 43;
+    let mut test_data = prepare_one_session_test_data().await;
+    let data_network = test_data.start_session(session_id).await;
+
+    let mut expected_data = HashSet::new();
+    for item in 0..10 {
+        data_network
+            .send(vec![item], Recipient::Everyone)
+            .expect("Should send");
+        for authority in test_data.authorities.iter().skip(1) {
+            expected_data.insert((vec![item], authority.peer_id()));
+        }
+    }
+    test_data
+        .session_manager
+        .stop_session(SessionId(session_id), true)
+        .unwrap();
+    assert_eq!(
+        timeout(DEFAULT_TIMEOUT, test_data.network.remove_reserved.next())
+            .await
+            .ok()
+            .flatten(),
+        Some((
+            HashSet::from_iter(test_data.authorities.iter().skip(1).map(|a| a.peer_id())),
+            Protocol::Validator
+        ))
+    );
+
+    // everything should have been sent before disconnecting
+    let mut sent_data = HashSet::new();
+    while let Some((data, peer_id, protocol)) = test_data.network.send_message.try_next().await {
+        if protocol != Protocol::Validator {
+            continue;
+        }
+        if let Ok(MockNetworkData::Data(data, sent_session_id, _)) =
+            MockNetworkData::decode(&mut data.as_slice())
+        {
+            assert_eq!(sent_session_id, SessionId(session_id));
+            sent_data.insert((data, peer_id));
+        }
+    }
+    assert_eq!(sent_data, expected_data);
+    test_data.cleanup().await;
+}
+
+#[tokio::test]
+async fn test_recycles_peer() {
+    let session_id = 43;
+    let mut test_data = prepare_one_session_test_data().await;
+    let data_network = test_data.start_validator_session(0, session_id).await;
+    let address = test_data
+        .connect_validator_network_authority(1, session_id)
+        .await;
+    let peer_id = address.0.clone();
+    assert_eq!(
+        timeout(
+            DEFAULT_TIMEOUT,
+            test_data.validator_network.add_connection.next()
+        )
+        .await
+        .ok()
+        .flatten(),
+        Some((peer_id.clone(), vec![address.clone()]))
+    );
+
+    data_network
+        .send(vec![1], Recipient::Node(NodeIndex(1)))
+        .expect("Should send");
+    test_data
+        .session_manager
+        .recycle_peer(peer_id.clone())
+        .unwrap();
+    data_network
+        .send(vec![2], Recipient::Node(NodeIndex(1)))
+        .expect("Should send");
+
+    // the data sent before recycling goes out before the old connection is dropped
+    assert_eq!(
+        timeout(DEFAULT_TIMEOUT, test_data.validator_network.send.next())
+            .await
+            .ok()
+            .flatten(),
+        Some((
+            DataInSession::new(Arc::new(vec![1]), SessionId(session_id)),
+            peer_id.clone()
+        ))
+    );
+    assert_eq!(
+        timeout(
+            DEFAULT_TIMEOUT,
+            test_data.validator_network.remove_connection.next()
+        )
+        .await
+        .ok()
+        .flatten(),
+        Some(peer_id.clone())
+    );
+    assert_eq!(
+        timeout(
+            DEFAULT_TIMEOUT,
+            test_data.validator_network.add_connection.next()
+        )
+        .await
+        .ok()
+        .flatten(),
+        Some((peer_id.clone(), vec![address]))
+    );
+    // and the data keeps flowing afterwards
+    assert_eq!(
+        timeout(DEFAULT_TIMEOUT, test_data.validator_network.send.next())
+            .await
+            .ok()
+            .flatten(),
+        Some((
+            DataInSession::new(Arc::new(vec![2]), SessionId(session_id)),
+            peer_id
+        ))
+    );
+    test_data.cleanup().await;
+}
+
+#[tokio::test]
+async fn test_announces_and_handles_leaving_session() {
+    let session_id = 43;
+    let mut test_data = prepare_one_session_test_data().await;
+    let _data_network = test_data.start_validator_session(0, session_id).await;
+    let address = test_data
+        .connect_validator_network_authority(1, session_id)
+        .await;
+    let peer_id = address.0.clone();
+    assert_eq!(
+        timeout(
+            DEFAULT_TIMEOUT,
+            test_data.validator_network.add_connection.next()
+        )
+        .await
+        .ok()
+        .flatten(),
+        Some((peer_id.clone(), vec![address.clone()]))
+    );
+
+    // the peer tells us it leaves, so we stop connecting to it
+    let leaving = SessionHandler::new(
+        Some((NodeIndex(1), test_data.authorities[1].pen())),
+        test_data.authority_verifier.clone(),
+        SessionId(session_id),
+        vec![address],
+    )
+    .await
+    .unwrap()
+    .leaving()
+    .await
+    .expect("validators can leave");
+    let control: VersionedAuthentication<MockValidatorMultiaddress> = leaving.into();
+    test_data.network.emit_event(MockEvent::Messages(
+        test_data.authorities[1].peer_id(),
+        vec![(Protocol::Authentication, control.encode().into())],
+    ));
+    assert_eq!(
+        timeout(
+            DEFAULT_TIMEOUT,
+            test_data.validator_network.remove_connection.next()
+        )
+        .await
+        .ok()
+        .flatten(),
+        Some(peer_id)
+    );
+
+    // and we tell the others when we leave
+    let gossip_peer = test_data.authorities[2].peer_id();
+    test_data.connect_identity_to_network(gossip_peer, Protocol::Authentication);
+    test_data
+        .session_manager
+        .stop_session(SessionId(session_id), false)
+        .unwrap();
+    let leaving = timeout(DEFAULT_TIMEOUT, async {
+        loop {
+            match test_data.network.send_message.next().await {
+                Some((data, peer_id, Protocol::Authentication)) => {
+                    if let Ok(VersionedAuthentication::<MockValidatorMultiaddress>::V3(
+                        ControlMessage::LeavingSession(leaving, _),
+                    )) = VersionedAuthentication::decode(&mut data.as_slice())
+                    {
+                        assert_eq!(peer_id, gossip_peer);
+                        return leaving;
+                    }
+                }
+                Some(_) => {}
+                None => panic!("the network should keep running"),
+            }
+        }
+    })
+    .await
+    .expect("should announce leaving");
+    assert_eq!(leaving.session_id, SessionId(session_id));
+    assert_eq!(leaving.node_id, NodeIndex(0));
+    test_data.cleanup().await;
+}
+
 #[tokio::test]
 async fn test_receives_data_in_correct_session() {
     let session_id_1 = 42;
@@ -687,12 +972,12 @@ async fn test_receives_data_in_correct_session() {
     let data_2_1 = vec![7, 8, 9];
     let data_2_2 = vec![10, 11, 12];
     test_data.emit_notifications_received(vec![
-        MockNetworkData::Data(data_1_1.clone(), SessionId(session_id_1)),
-        MockNetworkData::Data(data_2_1.clone(), SessionId(session_id_2)),
+        MockNetworkData::Data(data_1_1.clone(), SessionId(session_id_1), Channel::Main),
+        MockNetworkData::Data(data_2_1.clone(), SessionId(session_id_2), Channel::Main),
     ]);
     test_data.emit_notifications_received(vec![
-        MockNetworkData::Data(data_2_2.clone(), SessionId(session_id_2)),
-        MockNetworkData::Data(data_1_2.clone(), SessionId(session_id_1)),
+        MockNetworkData::Data(data_2_2.clone(), SessionId(session_id_2), Channel::Main),
+        MockNetworkData::Data(data_1_2.clone(), SessionId(session_id_1), Channel::Main),
     ]);
 
     assert_eq!(
@@ -724,6 +1009,51 @@ async fn test_receives_data_in_correct_session() {
     );
 }
 
+#[tokio::test]
+async fn test_keeps_channels_apart() {
+    let session_id = 42;
+    let mut test_data = prepare_one_session_test_data().await;
+    let mut main_network = test_data.start_session(session_id).await;
+    let mut requests_network = test_data
+        .session_manager
+        .open_channel(SessionId(session_id), Channel::Requests)
+        .await
+        .expect("Failed to open channel!");
+
+    requests_network
+        .send(vec![1, 2, 3], Recipient::Node(NodeIndex(1)))
+        .expect("Should send");
+    match timeout(
+        DEFAULT_TIMEOUT,
+        test_data.next_sent_data_message(Protocol::Validator),
+    )
+    .await
+    .expect("Should send data")
+    {
+        Some((MockNetworkData::Data(data, _, channel), peer_id, _)) => {
+            assert_eq!(data, vec![1, 2, 3]);
+            assert_eq!(channel, Channel::Requests);
+            assert_eq!(peer_id, test_data.authorities[1].peer_id());
+        }
+        other => panic!("Expected data, got {:?}", other),
+    }
+
+    test_data.emit_notifications_received(vec![
+        MockNetworkData::Data(vec![4, 5, 6], SessionId(session_id), Channel::Requests),
+        MockNetworkData::Data(vec![7, 8, 9], SessionId(session_id), Channel::Main),
+    ]);
+    assert_eq!(
+        timeout(DEFAULT_TIMEOUT, requests_network.next()).await,
+        Ok(Some(vec![4, 5, 6]))
+    );
+    assert_eq!(
+        timeout(DEFAULT_TIMEOUT, main_network.next()).await,
+        Ok(Some(vec![7, 8, 9]))
+    );
+
+    test_data.cleanup().await;
+}
+
 #[tokio::test]
 async fn test_sends_data_to_correct_session() {
     let session_id_1 = 42;
@@ -758,7 +1088,7 @@ async fn test_sends_data_to_correct_session() {
 
     let mut sent_data = HashSet::new();
     while sent_data.len() < 2 * (NODES_N - 1) {
-        if let Some((MockNetworkData::Data(data, session_id), peer_id, _)) = timeout(
+        if let Some((MockNetworkData::Data(data, session_id, _), peer_id, _)) = timeout(
             DEFAULT_TIMEOUT,
             test_data.next_sent_data_message(Protocol::Validator),
         )
@@ -808,7 +1138,7 @@ async fn test_broadcasts_data_to_correct_session() {
 
     let mut sent_data = HashSet::new();
     while sent_data.len() < 2 * (NODES_N - 1) {
-        if let Some((MockNetworkData::Data(data, session_id), peer_id, _)) = timeout(
+        if let Some((MockNetworkData::Data(data, session_id, _), peer_id, _)) = timeout(
             DEFAULT_TIMEOUT,
             test_data.next_sent_data_message(Protocol::Validator),
         )
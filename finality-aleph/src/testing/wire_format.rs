@@ -0,0 +1,245 @@
+//! Golden vectors of the encodings of the messages exchanged between nodes, so that changing how
+//! any of them is encoded cannot go unnoticed. Nodes running different versions have to keep
+//! understanding each other, so when one of these tests fails, first make sure the change is
+//! intentional and that older nodes can still handle the new encoding, e.g. by introducing a new
+//! version or variant. Only then update the affected vectors below, so that the break is visible
+//! in the review.
+
+use std::fmt::Debug;
+
+use aleph_primitives::{AuthorityId, AuthoritySignature};
+use codec::{Decode, Encode};
+use sp_core::ed25519;
+
+use crate::{
+    crypto::Signature,
+    network::testing::{
+        AuthData, Authentication, Channel, ControlMessage, DiscoveryMessage, Leaving, NetworkData,
+        VersionedAuthentication,
+    },
+    testing::mocks::validator_network::MockMultiaddress,
+    validator_network::{
+        testing::{Challenge, Response},
+        ChainIdentity,
+    },
+    NodeIndex, SessionId,
+};
+
+type TestNetworkData = NetworkData<Vec<u8>, MockMultiaddress>;
+
+fn address() -> MockMultiaddress {
+    (
+        AuthorityId::from(ed25519::Public::from_raw([1; 32])),
+        String::from("10.0.0.1:30343"),
+    )
+}
+
+fn signature() -> Signature {
+    AuthoritySignature::from(ed25519::Signature::from_raw([2; 64])).into()
+}
+
+/// The address encodes the same way as the addresses of the validator network.
+fn address_golden() -> Vec<u8> {
+    [
+        // the peer id
+        &[1; 32][..],
+        // the length of the address, compact encoded
+        &[56],
+        b"10.0.0.1:30343",
+    ]
+    .concat()
+}
+
+/// The authentication data as sent in version 1, without the sequence number.
+fn legacy_auth_data_golden() -> Vec<u8> {
+    [
+        // the number of addresses, compact encoded
+        &[4][..],
+        &address_golden(),
+        // the node index, as a u64
+        &[3, 0, 0, 0, 0, 0, 0, 0],
+        // the session id, as a u32
+        &[43, 0, 0, 0],
+    ]
+    .concat()
+}
+
+fn auth_data_golden() -> Vec<u8> {
+    [
+        &legacy_auth_data_golden()[..],
+        // the sequence number, 1000 as a u64
+        &[232, 3, 0, 0, 0, 0, 0, 0],
+    ]
+    .concat()
+}
+
+fn authentication_golden() -> Vec<u8> {
+    [&auth_data_golden()[..], &[2; 64]].concat()
+}
+
+/// The fields of the authentication data are private, so it can only be obtained by decoding.
+fn authentication() -> Authentication<MockMultiaddress> {
+    Authentication::decode(&mut &authentication_golden()[..]).expect("should decode")
+}
+
+/// An authentication from a node that does not know about sequence numbers.
+fn legacy_authentication() -> Authentication<MockMultiaddress> {
+    let golden = [
+        &legacy_auth_data_golden()[..],
+        // the legacy sequence number
+        &[0; 8],
+        &[2; 64],
+    ]
+    .concat();
+    Authentication::decode(&mut &golden[..]).expect("should decode")
+}
+
+fn leaving() -> ControlMessage {
+    ControlMessage::LeavingSession(
+        Leaving {
+            session_id: SessionId(43),
+            node_id: NodeIndex(3),
+        },
+        signature(),
+    )
+}
+
+fn leaving_golden() -> Vec<u8> {
+    [
+        &[0, 43, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0][..],
+        // the signature
+        &[2; 64],
+    ]
+    .concat()
+}
+
+/// Versioned authentications start with the version and the length of the rest, both as a u16.
+fn versioned_golden(version: u16, payload: &[u8]) -> Vec<u8> {
+    [
+        &version.to_le_bytes()[..],
+        &(payload.len() as u16).to_le_bytes(),
+        payload,
+    ]
+    .concat()
+}
+
+fn assert_golden<T: Encode + Decode + Debug + PartialEq>(value: &T, golden: &[u8]) {
+    assert_eq!(value.encode(), golden, "encoding of {:?} changed", value);
+    assert_eq!(
+        &T::decode(&mut &golden[..]).expect("golden vector should decode"),
+        value
+    );
+}
+
+#[test]
+fn auth_data_encoding_is_stable() {
+    let golden = auth_data_golden();
+    let auth_data = AuthData::<MockMultiaddress>::decode(&mut &golden[..]).expect("should decode");
+    assert_eq!(auth_data.addresses(), vec![address()]);
+    assert_eq!(auth_data.creator(), NodeIndex(3));
+    assert_eq!(auth_data.session(), SessionId(43));
+    assert_eq!(auth_data.sequence(), 1000);
+    assert_eq!(auth_data.encode(), golden);
+}
+
+#[test]
+fn authentication_encoding_is_stable() {
+    let (auth_data, authentication_signature) = authentication();
+    assert_eq!(auth_data.encode(), auth_data_golden());
+    assert_eq!(authentication_signature, signature());
+    assert_golden(&authentication(), &authentication_golden());
+}
+
+#[test]
+fn discovery_message_encoding_is_stable() {
+    assert_golden(
+        &DiscoveryMessage::AuthenticationBroadcast(authentication()),
+        &[&[0][..], &authentication_golden()].concat(),
+    );
+    assert_golden(
+        &DiscoveryMessage::Authentication(authentication()),
+        &[&[1][..], &authentication_golden()].concat(),
+    );
+}
+
+#[test]
+fn network_data_encoding_is_stable() {
+    let cases: Vec<(TestNetworkData, Vec<u8>)> = vec![
+        (
+            NetworkData::Meta(DiscoveryMessage::Authentication(authentication())),
+            [&[0, 1][..], &authentication_golden()].concat(),
+        ),
+        (
+            NetworkData::Data(vec![7, 8, 9], SessionId(43), Channel::Main),
+            vec![1, 12, 7, 8, 9, 43, 0, 0, 0],
+        ),
+        (
+            NetworkData::Data(vec![7, 8, 9], SessionId(43), Channel::Requests),
+            vec![2, 1, 12, 7, 8, 9, 43, 0, 0, 0],
+        ),
+        (
+            NetworkData::Control(leaving()),
+            [&[3][..], &leaving_golden()].concat(),
+        ),
+    ];
+    for (data, golden) in cases {
+        assert_golden(&data, &golden);
+    }
+}
+
+#[test]
+fn versioned_authentication_encoding_is_stable() {
+    let cases: Vec<(VersionedAuthentication<MockMultiaddress>, Vec<u8>)> = vec![
+        // nodes that do not know about sequence numbers send the authentication data without them
+        (
+            VersionedAuthentication::V1(DiscoveryMessage::AuthenticationBroadcast(
+                legacy_authentication(),
+            )),
+            versioned_golden(
+                1,
+                &[&[0][..], &legacy_auth_data_golden(), &[2; 64]].concat(),
+            ),
+        ),
+        (
+            VersionedAuthentication::V2(DiscoveryMessage::Authentication(authentication())),
+            versioned_golden(2, &[&[1][..], &authentication_golden()].concat()),
+        ),
+        (
+            VersionedAuthentication::V3(leaving()),
+            versioned_golden(3, &leaving_golden()),
+        ),
+        (
+            VersionedAuthentication::Other(4, vec![7, 8, 9]),
+            vec![4, 0, 3, 0, 7, 8, 9],
+        ),
+    ];
+    for (authentication, golden) in cases {
+        assert_golden(&authentication, &golden);
+    }
+}
+
+#[test]
+fn handshake_encoding_is_stable() {
+    let id = AuthorityId::from(ed25519::Public::from_raw([1; 32]));
+    assert_golden(
+        &Challenge {
+            id: id.clone(),
+            nonce: [5; 32],
+        },
+        &[[1; 32], [5; 32]].concat(),
+    );
+    assert_golden(
+        &Response {
+            id,
+            signature: signature(),
+        },
+        &[&[1; 32][..], &[2; 64]].concat(),
+    );
+    // sent separately, right after the challenge or the response, in the newer protocols
+    assert_golden(
+        &ChainIdentity {
+            genesis_hash: [6; 32],
+        },
+        &[6; 32],
+    );
+}
@@ -0,0 +1,201 @@
+use std::{collections::HashMap, time::Instant};
+
+use aleph_primitives::AuthorityId;
+use tokio::time::Duration;
+
+/// When to stop talking to a misbehaving peer, and for how long.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlacklistConfig {
+    /// After this many misbehaviours the peer gets blacklisted.
+    pub threshold: u32,
+    /// How long the peer stays blacklisted.
+    pub ttl: Duration,
+}
+
+impl Default for BlacklistConfig {
+    fn default() -> Self {
+        BlacklistConfig {
+            threshold: 3,
+            ttl: Duration::from_secs(600),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Record {
+    failures: u32,
+    banned_until: Option<Instant>,
+}
+
+/// Counts the misbehaviours of peers, blacklisting them temporarily once they misbehave too often.
+pub struct Blacklist {
+    config: BlacklistConfig,
+    records: HashMap<AuthorityId, Record>,
+}
+
+impl Blacklist {
+    pub fn new(config: BlacklistConfig) -> Self {
+        Blacklist {
+            config,
+            records: HashMap::new(),
+        }
+    }
+
+    /// Notes a misbehaviour of the peer. Returns how long the peer is blacklisted for, if this
+    /// misbehaviour got it blacklisted.
+    pub fn failed(&mut self, peer_id: &AuthorityId) -> Option<Duration> {
+        self.failed_at(peer_id, Instant::now())
+    }
+
+    fn failed_at(&mut self, peer_id: &AuthorityId, now: Instant) -> Option<Duration> {
+        // A zero threshold would blacklist everyone forever.
+        let threshold = self.config.threshold.max(1);
+        let record = self.records.entry(peer_id.clone()).or_default();
+        record.failures = record.failures.saturating_add(1);
+        if record.failures < threshold {
+            return None;
+        }
+        // The peer starts with a clean slate once the penalty is over.
+        record.failures = 0;
+        record.banned_until = Some(now + self.config.ttl);
+        Some(self.config.ttl)
+    }
+
+    /// Forgets the misbehaviours of a peer we are no longer interested in, unless it is
+    /// blacklisted, so that it cannot clear its penalty by leaving and coming back.
+    pub fn forget(&mut self, peer_id: &AuthorityId) {
+        if self.banned_for(peer_id).is_none() {
+            self.records.remove(peer_id);
+        }
+    }
+
+    /// Drops the records of peers that served their penalty and did not misbehave since.
+    pub fn prune(&mut self) {
+        self.prune_at(Instant::now())
+    }
+
+    fn prune_at(&mut self, now: Instant) {
+        self.records.retain(|_, record| {
+            record.failures > 0 || record.banned_until.map_or(false, |until| now < until)
+        });
+    }
+
+    /// How much longer the peer stays blacklisted, `None` if it is not blacklisted.
+    pub fn banned_for(&self, peer_id: &AuthorityId) -> Option<Duration> {
+        self.banned_for_at(peer_id, Instant::now())
+    }
+
+    fn banned_for_at(&self, peer_id: &AuthorityId, now: Instant) -> Option<Duration> {
+        let banned_until = self.records.get(peer_id)?.banned_until?;
+        match now < banned_until {
+            true => Some(banned_until - now),
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use tokio::time::Duration;
+
+    use super::{Blacklist, BlacklistConfig};
+    use crate::validator_network::mock::keys;
+
+    fn config() -> BlacklistConfig {
+        BlacklistConfig {
+            threshold: 3,
+            ttl: Duration::from_secs(60),
+        }
+    }
+
+    #[tokio::test]
+    async fn skips_peer_until_ttl_elapses() {
+        let (peer_id, _) = keys().await;
+        let mut blacklist = Blacklist::new(config());
+        let now = Instant::now();
+        assert_eq!(blacklist.failed_at(&peer_id, now), None);
+        assert_eq!(blacklist.failed_at(&peer_id, now), None);
+        assert_eq!(blacklist.banned_for_at(&peer_id, now), None);
+        assert_eq!(
+            blacklist.failed_at(&peer_id, now),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            blacklist.banned_for_at(&peer_id, now + Duration::from_secs(59)),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(
+            blacklist.banned_for_at(&peer_id, now + Duration::from_secs(60)),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn counts_failures_per_peer() {
+        let (peer_a, _) = keys().await;
+        let (peer_b, _) = keys().await;
+        let mut blacklist = Blacklist::new(config());
+        let now = Instant::now();
+        for _ in 0..2 {
+            assert_eq!(blacklist.failed_at(&peer_a, now), None);
+            assert_eq!(blacklist.failed_at(&peer_b, now), None);
+        }
+        assert!(blacklist.failed_at(&peer_a, now).is_some());
+        assert!(blacklist.banned_for_at(&peer_a, now).is_some());
+        assert_eq!(blacklist.banned_for_at(&peer_b, now), None);
+    }
+
+    #[tokio::test]
+    async fn starts_counting_anew_after_penalty() {
+        let (peer_id, _) = keys().await;
+        let mut blacklist = Blacklist::new(config());
+        let now = Instant::now();
+        for _ in 0..3 {
+            blacklist.failed_at(&peer_id, now);
+        }
+        let later = now + Duration::from_secs(60);
+        assert_eq!(blacklist.banned_for_at(&peer_id, later), None);
+        assert_eq!(blacklist.failed_at(&peer_id, later), None);
+        assert_eq!(blacklist.banned_for_at(&peer_id, later), None);
+    }
+
+    #[tokio::test]
+    async fn prunes_only_peers_without_pending_penalties() {
+        let (clean, _) = keys().await;
+        let (failing, _) = keys().await;
+        let (banned, _) = keys().await;
+        let mut blacklist = Blacklist::new(config());
+        let now = Instant::now();
+        for _ in 0..3 {
+            blacklist.failed_at(&clean, now);
+            blacklist.failed_at(&banned, now);
+        }
+        let later = now + Duration::from_secs(60);
+        blacklist.failed_at(&banned, later);
+        blacklist.failed_at(&banned, later);
+        blacklist.failed_at(&banned, later);
+        blacklist.failed_at(&failing, later);
+        blacklist.prune_at(later);
+        assert!(!blacklist.records.contains_key(&clean));
+        assert!(blacklist.records.contains_key(&failing));
+        assert!(blacklist.records.contains_key(&banned));
+
+        blacklist.forget(&failing);
+        blacklist.forget(&banned);
+        assert!(!blacklist.records.contains_key(&failing));
+        assert!(blacklist.records.contains_key(&banned));
+    }
+
+    #[tokio::test]
+    async fn zero_threshold_blacklists_on_first_failure() {
+        let (peer_id, _) = keys().await;
+        let mut blacklist = Blacklist::new(BlacklistConfig {
+            threshold: 0,
+            ..config()
+        });
+        let now = Instant::now();
+        assert!(blacklist.failed_at(&peer_id, now).is_some());
+    }
+}
@@ -0,0 +1,28 @@
+use std::time::Instant;
+
+use tokio::time::{sleep, Duration};
+
+/// A source of time, so that the timing sensitive code can be driven by virtual time in tests.
+#[async_trait::async_trait]
+pub trait Clock: Clone + Send + Sync + 'static {
+    /// The current moment.
+    fn now(&self) -> Instant;
+
+    /// Finishes after the given amount of time passed.
+    async fn sleep(&self, duration: Duration);
+}
+
+/// The real clock, using tokio timers.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioClock;
+
+#[async_trait::async_trait]
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        sleep(duration).await
+    }
+}
@@ -3,17 +3,99 @@ use std::fmt::{Display, Error as FmtError, Formatter};
 use aleph_primitives::AuthorityId;
 use codec::{Decode, Encode};
 use rand::Rng;
-use tokio::time::{timeout, Duration};
+use sp_core::hashing::blake2_256;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::{timeout, Duration},
+};
 
 use crate::{
     crypto::{verify, AuthorityPen, Signature},
     validator_network::{
-        io::{receive_data, send_data, ReceiveError, SendError},
+        io::{receive_data, send_data_with_timeout, Codec, ReceiveError, SendError},
         Splittable,
     },
 };
 
-pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_INCOMING_HANDSHAKES: usize = 32;
+/// Without an explicit limit, all the peers get dialed in about this many rounds.
+const OUTGOING_DIAL_ROUNDS: usize = 3;
+
+/// Limits on the initial exchange over a new connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HandshakeConfig {
+    /// How long we wait for every step of the handshake before dropping the connection.
+    pub timeout: Duration,
+    /// How many incoming connections can be handshaking at the same time. Further ones wait
+    /// for their turn.
+    pub max_incoming: usize,
+    /// How long an incoming connection can wait for its turn to handshake before being dropped.
+    pub incoming_queue_timeout: Duration,
+    /// How many outgoing connections can be dialed at the same time. Further dials wait for
+    /// their turn, in order. Without a limit given, it follows the number of peers we are to stay
+    /// connected to, see `outgoing_dial_limit`.
+    pub max_outgoing: Option<usize>,
+    /// The network we are a part of, peers from other ones are rejected during the handshake.
+    /// Only protocols carrying the chain identity can check it, peers using older ones are on an
+    /// unknown chain and are not rejected.
+    pub chain: ChainIdentity,
+    /// How long a single write can block, e.g. because the peer stopped reading, before the
+    /// handshake fails. Should match the write timeout of the data sent afterwards.
+    pub write_timeout: Option<Duration>,
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> Self {
+        HandshakeConfig {
+            timeout: HANDSHAKE_TIMEOUT,
+            max_incoming: MAX_INCOMING_HANDSHAKES,
+            incoming_queue_timeout: HANDSHAKE_TIMEOUT,
+            max_outgoing: None,
+            chain: ChainIdentity::default(),
+            write_timeout: None,
+        }
+    }
+}
+
+impl HandshakeConfig {
+    /// How many outgoing connections can be dialed at the same time when staying connected to
+    /// the given number of peers. Unless limited explicitly, the whole committee gets dialed in
+    /// a few rounds, whatever its size.
+    pub fn outgoing_dial_limit(&self, peers: usize) -> usize {
+        self.max_outgoing
+            .unwrap_or((peers + OUTGOING_DIAL_ROUNDS - 1) / OUTGOING_DIAL_ROUNDS)
+            .max(1)
+    }
+}
+
+/// Identifies the network a node is a part of, so that nodes of different chains, e.g. sharing
+/// a testnet environment, never exchange any data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Encode, Decode)]
+pub struct ChainIdentity {
+    /// The hash of the genesis block of the chain.
+    pub genesis_hash: [u8; 32],
+}
+
+impl ChainIdentity {
+    /// Genesis hashes of any length other than 32 bytes are hashed to fit.
+    pub fn new(genesis_hash: &[u8]) -> Self {
+        let genesis_hash = genesis_hash
+            .try_into()
+            .unwrap_or_else(|_| blake2_256(genesis_hash));
+        ChainIdentity { genesis_hash }
+    }
+}
+
+impl Display for ChainIdentity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        write!(f, "genesis 0x")?;
+        for byte in self.genesis_hash {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
 
 /// Handshake error.
 #[derive(Debug)]
@@ -22,10 +104,21 @@ pub enum HandshakeError {
     SendError(SendError),
     /// Receive error.
     ReceiveError(ReceiveError),
-    /// Signature error.
-    SignatureError,
-    /// Challenge contains invalid peer id.
-    ChallengeError(AuthorityId, AuthorityId),
+    /// The peer did not sign the challenge we sent them, e.g. replaying a response captured
+    /// from an earlier handshake.
+    BadChallengeResponse,
+    /// Challenge contains a different peer id than the one we wanted to connect to.
+    IdentityMismatch {
+        expected: AuthorityId,
+        got: AuthorityId,
+    },
+    /// The peer is a part of a different network than we are.
+    ChainMismatch {
+        ours: ChainIdentity,
+        theirs: ChainIdentity,
+    },
+    /// The peer is us, e.g. because we dialed our own announced address.
+    SelfConnection,
     /// Timeout.
     TimedOut,
 }
@@ -36,17 +129,73 @@ impl Display for HandshakeError {
         match self {
             SendError(e) => write!(f, "send error: {}", e),
             ReceiveError(e) => write!(f, "receive error: {}", e),
-            SignatureError => write!(f, "signature error"),
-            ChallengeError(expected, got) => write!(
+            BadChallengeResponse => write!(f, "response does not match the challenge"),
+            IdentityMismatch { expected, got } => write!(
                 f,
-                "challenge error, expected peer {}, received from {}",
+                "identity mismatch, expected peer {}, but {} answered",
                 expected, got
             ),
+            ChainMismatch { ours, theirs } => write!(
+                f,
+                "chain mismatch, we are on {}, but the peer is on {}",
+                ours, theirs
+            ),
+            SelfConnection => write!(f, "connected to ourselves"),
             TimedOut => write!(f, "timed out"),
         }
     }
 }
 
+impl std::error::Error for HandshakeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use HandshakeError::*;
+        match self {
+            SendError(e) => Some(e),
+            ReceiveError(e) => Some(e),
+            BadChallengeResponse
+            | IdentityMismatch { .. }
+            | ChainMismatch { .. }
+            | SelfConnection
+            | TimedOut => None,
+        }
+    }
+}
+
+impl HandshakeError {
+    /// Whether the peer broke the handshake, as opposed to the connection failing.
+    /// An identity mismatch is not the fault of the peer we expected, the address is likely
+    /// just outdated. Neither is a chain mismatch, the peer is honest, just on another network.
+    pub fn peer_misbehaved(&self) -> bool {
+        use HandshakeError::*;
+        match self {
+            ReceiveError(e) => e.peer_misbehaved(),
+            BadChallengeResponse => true,
+            SendError(_)
+            | IdentityMismatch { .. }
+            | ChainMismatch { .. }
+            | SelfConnection
+            | TimedOut => false,
+        }
+    }
+
+    /// Whether the handshake might succeed using an older protocol, i.e. whether the peer sent
+    /// something we could not make sense of, as it would if it got the protocol version wrong.
+    /// Broken or stalled connections would just as well break or stall with an older protocol,
+    /// and failures telling us the peer is not who we want to talk to will not go away with one.
+    pub fn recoverable(&self) -> bool {
+        use HandshakeError::*;
+        match self {
+            ReceiveError(e) => e.peer_misbehaved(),
+            SendError(_)
+            | BadChallengeResponse
+            | IdentityMismatch { .. }
+            | ChainMismatch { .. }
+            | SelfConnection
+            | TimedOut => false,
+        }
+    }
+}
+
 impl From<SendError> for HandshakeError {
     fn from(e: SendError) -> Self {
         HandshakeError::SendError(e)
@@ -59,27 +208,39 @@ impl From<ReceiveError> for HandshakeError {
     }
 }
 
-/// Handshake challenge. Contains public key of the creator, and a random nonce.
-#[derive(Debug, Clone, Encode, Decode)]
-struct Challenge {
-    id: AuthorityId,
-    nonce: [u8; 32],
+/// The part of the challenge that makes every handshake unique, so that responses cannot be
+/// replayed.
+pub type Nonce = [u8; 32];
+
+/// A fresh random nonce.
+pub fn random_nonce() -> Nonce {
+    rand::thread_rng().gen::<Nonce>()
+}
+
+/// Handshake challenge. Contains public key of the creator, and a nonce.
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct Challenge {
+    pub id: AuthorityId,
+    pub nonce: Nonce,
 }
 
 impl Challenge {
-    /// Prepare new challenge that contains ID of the creator.
+    /// Prepare new challenge that contains ID of the creator and a random nonce.
     fn new(id: AuthorityId) -> Self {
-        let nonce = rand::thread_rng().gen::<[u8; 32]>();
+        Self::with_nonce(id, random_nonce())
+    }
+
+    fn with_nonce(id: AuthorityId, nonce: Nonce) -> Self {
         Self { id, nonce }
     }
 }
 
 /// Handshake response. Contains public key of the creator, and signature
 /// related to the received challenge.
-#[derive(Debug, Clone, Encode, Decode)]
-struct Response {
-    id: AuthorityId,
-    signature: Signature,
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct Response {
+    pub id: AuthorityId,
+    pub signature: Signature,
 }
 
 impl Response {
@@ -97,6 +258,35 @@ impl Response {
     }
 }
 
+/// Sends our chain identity right after our part of the handshake, if the protocol carries it.
+async fn maybe_send_chain<S: AsyncWrite + Unpin>(
+    stream: S,
+    chain: Option<ChainIdentity>,
+    write_timeout: Option<Duration>,
+) -> Result<S, HandshakeError> {
+    Ok(match chain {
+        Some(chain) => send_data_with_timeout(stream, chain, write_timeout).await?,
+        None => stream,
+    })
+}
+
+/// Receives the chain identity of the peer and checks it against ours, if the protocol carries
+/// it. Peers using older protocols never send it, so their chain is unknown and not checked.
+async fn maybe_check_chain<S: AsyncRead + Unpin>(
+    stream: S,
+    chain: Option<ChainIdentity>,
+) -> Result<S, HandshakeError> {
+    let ours = match chain {
+        Some(ours) => ours,
+        None => return Ok(stream),
+    };
+    let (stream, theirs) = receive_data::<_, ChainIdentity>(stream).await?;
+    match ours == theirs {
+        true => Ok(stream),
+        false => Err(HandshakeError::ChainMismatch { ours, theirs }),
+    }
+}
+
 /// Performs the handshake with a peer that called us.
 /// The goal is to obtain the public key of the peer, and split
 /// the communication stream into two halves.
@@ -105,18 +295,48 @@ impl Response {
 /// will NOT be secured in any way. We assume that if the channel is
 /// compromised after the handshake, the peer will establish another connection,
 /// which will replace the current one.
+/// If `chain` is given, it is exchanged right after the challenge and the response, and peers
+/// from other networks are rejected. Connections to ourselves are rejected either way.
 pub async fn execute_v0_handshake_incoming<S: Splittable>(
     stream: S,
     authority_pen: AuthorityPen,
+    chain: Option<ChainIdentity>,
+    write_timeout: Option<Duration>,
+) -> Result<(S::Sender, S::Receiver, AuthorityId), HandshakeError> {
+    execute_handshake_incoming_with_nonce(
+        stream,
+        authority_pen,
+        chain,
+        random_nonce(),
+        write_timeout,
+    )
+    .await
+}
+
+/// Performs the incoming handshake, challenging the peer with the given nonce.
+/// The nonce has to be fresh for every handshake, otherwise a response captured from
+/// an earlier one could be replayed. Outside of tests, use `execute_v0_handshake_incoming`,
+/// which takes care of that.
+pub async fn execute_handshake_incoming_with_nonce<S: Splittable>(
+    stream: S,
+    authority_pen: AuthorityPen,
+    chain: Option<ChainIdentity>,
+    nonce: Nonce,
+    write_timeout: Option<Duration>,
 ) -> Result<(S::Sender, S::Receiver, AuthorityId), HandshakeError> {
     // send challenge
-    let our_challenge = Challenge::new(authority_pen.authority_id());
-    let stream = send_data(stream, our_challenge.clone()).await?;
+    let our_challenge = Challenge::with_nonce(authority_pen.authority_id(), nonce);
+    let stream = send_data_with_timeout(stream, our_challenge.clone(), write_timeout).await?;
+    let stream = maybe_send_chain(stream, chain, write_timeout).await?;
     // receive response
     let (stream, peer_response) = receive_data::<_, Response>(stream).await?;
+    let stream = maybe_check_chain(stream, chain).await?;
     // validate response
     if !peer_response.verify(&our_challenge) {
-        return Err(HandshakeError::SignatureError);
+        return Err(HandshakeError::BadChallengeResponse);
+    }
+    if peer_response.id == our_challenge.id {
+        return Err(HandshakeError::SelfConnection);
     }
     let (sender, receiver) = stream.split();
     let peer_id = peer_response.id;
@@ -132,67 +352,207 @@ pub async fn execute_v0_handshake_incoming<S: Splittable>(
 /// will NOT be secured in any way. We assume that if the channel is
 /// compromised after the handshake, we will establish another connection,
 /// which will replace the current one.
+/// If `chain` is given, it is exchanged right after the challenge and the response, and peers
+/// from other networks are rejected, but only after responding, so that they learn about the
+/// mismatch as well. Connections to ourselves are rejected immediately.
 pub async fn execute_v0_handshake_outgoing<S: Splittable>(
     stream: S,
     authority_pen: AuthorityPen,
     peer_id: AuthorityId,
+    chain: Option<ChainIdentity>,
+    write_timeout: Option<Duration>,
 ) -> Result<(S::Sender, S::Receiver), HandshakeError> {
     // receive challenge
     let (stream, peer_challenge) = receive_data::<_, Challenge>(stream).await?;
+    if peer_challenge.id == authority_pen.authority_id() {
+        return Err(HandshakeError::SelfConnection);
+    }
     if peer_id != peer_challenge.id {
-        return Err(HandshakeError::ChallengeError(peer_id, peer_challenge.id));
+        // The stream gets dropped here, so the connection is closed immediately.
+        return Err(HandshakeError::IdentityMismatch {
+            expected: peer_id,
+            got: peer_challenge.id,
+        });
     }
     // send response
     let our_response = Response::new(&authority_pen, &peer_challenge).await;
-    let stream = send_data(stream, our_response).await?;
+    let stream = send_data_with_timeout(stream, our_response, write_timeout).await?;
+    let stream = maybe_send_chain(stream, chain, write_timeout).await?;
+    let stream = maybe_check_chain(stream, chain).await?;
     let (sender, receiver) = stream.split();
     Ok((sender, receiver))
 }
 
 /// Wrapper that adds timeout to the function performing handshake.
+/// The stream is dropped if the handshake does not finish in time.
+/// The chain identity from the config is only exchanged if `with_chain` is set.
 pub async fn v0_handshake_incoming<S: Splittable>(
     stream: S,
     authority_pen: AuthorityPen,
+    with_chain: bool,
+    config: HandshakeConfig,
 ) -> Result<(S::Sender, S::Receiver, AuthorityId), HandshakeError> {
     timeout(
-        HANDSHAKE_TIMEOUT,
-        execute_v0_handshake_incoming(stream, authority_pen),
+        config.timeout,
+        execute_v0_handshake_incoming(
+            stream,
+            authority_pen,
+            with_chain.then_some(config.chain),
+            config.write_timeout,
+        ),
     )
     .await
     .map_err(|_| HandshakeError::TimedOut)?
 }
 
 /// Wrapper that adds timeout to the function performing handshake.
+/// The stream is dropped if the handshake does not finish in time.
+/// The chain identity from the config is only exchanged if `with_chain` is set.
 pub async fn v0_handshake_outgoing<S: Splittable>(
     stream: S,
     authority_pen: AuthorityPen,
     peer_id: AuthorityId,
+    with_chain: bool,
+    config: HandshakeConfig,
 ) -> Result<(S::Sender, S::Receiver), HandshakeError> {
     timeout(
-        HANDSHAKE_TIMEOUT,
-        execute_v0_handshake_outgoing(stream, authority_pen, peer_id),
+        config.timeout,
+        execute_v0_handshake_outgoing(
+            stream,
+            authority_pen,
+            peer_id,
+            with_chain.then_some(config.chain),
+            config.write_timeout,
+        ),
+    )
+    .await
+    .map_err(|_| HandshakeError::TimedOut)?
+}
+
+/// Announces the tags of all the codecs we are able to decompress. Should be called after the
+/// handshake by the side that will be receiving data.
+pub async fn announce_codecs<S: AsyncWrite + Unpin>(
+    stream: S,
+    config: HandshakeConfig,
+) -> Result<S, HandshakeError> {
+    let tags: Vec<u8> = Codec::SUPPORTED.iter().map(Codec::tag).collect();
+    timeout(
+        config.timeout,
+        send_data_with_timeout(stream, tags, config.write_timeout),
     )
     .await
     .map_err(|_| HandshakeError::TimedOut)?
+    .map_err(HandshakeError::from)
+}
+
+/// Receives the codecs announced by the peer and chooses the preferred one, if the peer is able
+/// to decompress it, falling back to no compression otherwise. Should be called after the
+/// handshake by the side that will be sending data.
+pub async fn choose_codec<S: AsyncRead + Unpin>(
+    stream: S,
+    preferred: Codec,
+    config: HandshakeConfig,
+) -> Result<(S, Codec), HandshakeError> {
+    let (stream, tags) = timeout(config.timeout, receive_data::<_, Vec<u8>>(stream))
+        .await
+        .map_err(|_| HandshakeError::TimedOut)??;
+    let codec = match tags.contains(&preferred.tag()) {
+        true => preferred,
+        false => Codec::Identity,
+    };
+    Ok((stream, codec))
+}
+
+/// Optional features of the protocol a peer implements, as a bitset. Bits we do not know about
+/// are ignored, so that newer peers can announce features we do not implement yet.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Encode, Decode)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// Compressing the data with any of the supported codecs.
+    pub const COMPRESSION: Capabilities = Capabilities(1);
+    /// Probing the round trip time of the connection.
+    pub const PROBES: Capabilities = Capabilities(1 << 1);
+    // The third bit used to announce carrying the data of many sessions over a single connection,
+    // which every version does anyway. It is left unused, so that it never means anything else.
+    /// Checksumming the data of a session, so that data delivered to the wrong session is caught.
+    pub const SESSION_CHECKSUM: Capabilities = Capabilities(1 << 3);
+
+    const KNOWN: Capabilities =
+        Capabilities(Self::COMPRESSION.0 | Self::PROBES.0 | Self::SESSION_CHECKSUM.0);
+
+    /// The capabilities this version of the node implements.
+    pub fn supported() -> Self {
+        Self::KNOWN
+    }
+
+    /// The capabilities both we and the peer have, i.e. what can be used over the connection.
+    pub fn intersection(self, other: Capabilities) -> Self {
+        Capabilities(self.0 & other.0 & Self::KNOWN.0)
+    }
+
+    /// Whether all of the other capabilities are among these.
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// Sends our capabilities to the peer and receives theirs, returning what both sides have.
+/// Should be called by both sides after the handshake, as both send before receiving.
+pub async fn exchange_capabilities<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(
+    sender: W,
+    receiver: R,
+    ours: Capabilities,
+    config: HandshakeConfig,
+) -> Result<(W, R, Capabilities), HandshakeError> {
+    let exchange = async {
+        let sender = send_data_with_timeout(sender, ours, config.write_timeout).await?;
+        let (receiver, theirs) = receive_data::<_, Capabilities>(receiver).await?;
+        Ok::<_, HandshakeError>((sender, receiver, ours.intersection(theirs)))
+    };
+    timeout(config.timeout, exchange)
+        .await
+        .map_err(|_| HandshakeError::TimedOut)?
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Instant;
+
+    use aleph_primitives::AuthorityId;
+    use codec::{Decode, Encode};
     use futures::{join, try_join};
+    use tokio::time::Duration;
 
     use super::{
-        execute_v0_handshake_incoming, execute_v0_handshake_outgoing, Challenge, HandshakeError,
-        Response,
+        announce_codecs, choose_codec, exchange_capabilities,
+        execute_handshake_incoming_with_nonce, execute_v0_handshake_incoming,
+        execute_v0_handshake_outgoing, v0_handshake_incoming, v0_handshake_outgoing, Capabilities,
+        ChainIdentity, Challenge, HandshakeConfig, HandshakeError, Response,
     };
     use crate::{
         crypto::AuthorityPen,
         validator_network::{
-            io::{receive_data, send_data},
+            io::{receive_data, send_data, Codec},
             mock::{keys, MockSplittable},
             Splittable,
         },
     };
 
+    const CHAIN: ChainIdentity = ChainIdentity {
+        genesis_hash: [1; 32],
+    };
+
+    fn assert_timed_out<T: std::fmt::Debug>(result: Result<T, HandshakeError>) {
+        match result {
+            Err(HandshakeError::TimedOut) => (),
+            x => panic!(
+                "should end with HandshakeError::TimedOut, but we got {:?}",
+                x
+            ),
+        }
+    }
+
     fn assert_send_error<T: std::fmt::Debug>(result: Result<T, HandshakeError>) {
         match result {
             Err(HandshakeError::SendError(_)) => (),
@@ -213,21 +573,28 @@ mod tests {
         };
     }
 
-    fn assert_signature_error<T: std::fmt::Debug>(result: Result<T, HandshakeError>) {
+    fn assert_bad_challenge_response<T: std::fmt::Debug>(result: Result<T, HandshakeError>) {
         match result {
-            Err(HandshakeError::SignatureError) => (),
+            Err(HandshakeError::BadChallengeResponse) => (),
             x => panic!(
-                "should end with HandshakeError::SignatureError, but we got {:?}",
+                "should end with HandshakeError::BadChallengeResponse, but we got {:?}",
                 x
             ),
         };
     }
 
-    fn assert_challenge_error<T: std::fmt::Debug>(result: Result<T, HandshakeError>) {
+    fn assert_identity_mismatch<T: std::fmt::Debug>(
+        result: Result<T, HandshakeError>,
+        expected_id: AuthorityId,
+        got_id: AuthorityId,
+    ) {
         match result {
-            Err(HandshakeError::ChallengeError(_, _)) => (),
+            Err(HandshakeError::IdentityMismatch { expected, got }) => {
+                assert_eq!(expected, expected_id);
+                assert_eq!(got, got_id);
+            }
             x => panic!(
-                "should end with HandshakeError::ChallengeError, but we got {:?}",
+                "should end with HandshakeError::IdentityMismatch, but we got {:?}",
                 x
             ),
         };
@@ -240,17 +607,135 @@ mod tests {
         let (id_b, pen_b) = keys().await;
         assert_ne!(id_a, id_b);
         let ((_, _, received_id_b), (_, _)) = try_join!(
-            execute_v0_handshake_incoming(stream_a, pen_a),
-            execute_v0_handshake_outgoing(stream_b, pen_b, id_a),
+            execute_v0_handshake_incoming(stream_a, pen_a, Some(CHAIN), None),
+            execute_v0_handshake_outgoing(stream_b, pen_b, id_a, Some(CHAIN), None),
+        )
+        .expect("handshake should work");
+        assert_eq!(id_b, received_id_b);
+    }
+
+    fn assert_chain_mismatch<T: std::fmt::Debug>(
+        result: Result<T, HandshakeError>,
+        expected_ours: ChainIdentity,
+        expected_theirs: ChainIdentity,
+    ) {
+        match result {
+            Err(HandshakeError::ChainMismatch { ours, theirs }) => {
+                assert_eq!(ours, expected_ours);
+                assert_eq!(theirs, expected_theirs);
+            }
+            x => panic!(
+                "should end with HandshakeError::ChainMismatch, but we got {:?}",
+                x
+            ),
+        };
+    }
+
+    fn assert_self_connection<T: std::fmt::Debug>(result: Result<T, HandshakeError>) {
+        match result {
+            Err(HandshakeError::SelfConnection) => (),
+            x => panic!(
+                "should end with HandshakeError::SelfConnection, but we got {:?}",
+                x
+            ),
+        };
+    }
+
+    #[tokio::test]
+    async fn handshake_with_ourselves() {
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        let (id, pen) = keys().await;
+        let (incoming_result, outgoing_result) = join!(
+            execute_v0_handshake_incoming(stream_a, pen.clone(), Some(CHAIN), None),
+            execute_v0_handshake_outgoing(stream_b, pen, id, Some(CHAIN), None),
+        );
+        assert_self_connection(outgoing_result);
+        // the outgoing side drops the connection as soon as it recognizes itself
+        assert_receive_error(incoming_result);
+    }
+
+    #[tokio::test]
+    async fn handshake_with_ourselves_not_checking() {
+        async fn execute_unchecking_v0_handshake_outgoing<S: Splittable>(
+            stream: S,
+            authority_pen: AuthorityPen,
+        ) {
+            // respond to any challenge, without looking at who sent it
+            let (stream, challenge) = receive_data::<_, Challenge>(stream)
+                .await
+                .expect("should receive");
+            let our_response = Response::new(&authority_pen, &challenge).await;
+            send_data(stream, our_response).await.expect("should send");
+            futures::future::pending::<()>().await;
+        }
+
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        let (_, pen) = keys().await;
+        tokio::select! {
+            result = execute_v0_handshake_incoming(stream_a, pen.clone(), None, None) => assert_self_connection(result),
+            _ = execute_unchecking_v0_handshake_outgoing(stream_b, pen) => panic!("should wait"),
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_on_matching_chain() {
+        let chain = ChainIdentity::new(&[2; 32]);
+        assert_eq!(chain, ChainIdentity::new(&[2; 32]));
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        let (id_a, pen_a) = keys().await;
+        let (id_b, pen_b) = keys().await;
+        let ((_, _, received_id_b), (_, _)) = try_join!(
+            execute_v0_handshake_incoming(stream_a, pen_a, Some(chain), None),
+            execute_v0_handshake_outgoing(stream_b, pen_b, id_a, Some(chain), None),
+        )
+        .expect("handshake should work");
+        assert_eq!(id_b, received_id_b);
+    }
+
+    #[tokio::test]
+    async fn handshake_on_mismatched_chains() {
+        let other_chain = ChainIdentity::new(&[2; 32]);
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        let (id_a, pen_a) = keys().await;
+        let (_, pen_b) = keys().await;
+        let (result_a, result_b) = join!(
+            execute_v0_handshake_incoming(stream_a, pen_a, Some(CHAIN), None),
+            execute_v0_handshake_outgoing(stream_b, pen_b, id_a, Some(other_chain), None),
+        );
+        // both sides learn about the mismatch
+        assert_chain_mismatch(result_a, CHAIN, other_chain);
+        assert_chain_mismatch(result_b, other_chain, CHAIN);
+    }
+
+    #[tokio::test]
+    async fn legacy_handshake_leaves_chain_unknown() {
+        // the legacy handshake carries no chain identity, so the peer is not rejected as being
+        // on another chain, nor as sending corrupt messages
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        let (id_a, pen_a) = keys().await;
+        let (id_b, pen_b) = keys().await;
+        let ((_, _, received_id_b), (_, _)) = try_join!(
+            execute_v0_handshake_incoming(stream_a, pen_a, None, None),
+            execute_v0_handshake_outgoing(stream_b, pen_b, id_a, None, None),
         )
         .expect("handshake should work");
         assert_eq!(id_b, received_id_b);
     }
 
+    #[test]
+    fn hashes_genesis_hashes_of_unusual_length() {
+        let chain = ChainIdentity::new(&[2; 64]);
+        assert_ne!(chain.genesis_hash, [2; 32]);
+        assert_eq!(chain, ChainIdentity::new(&[2; 64]));
+        assert_ne!(chain, ChainIdentity::new(&[2; 63]));
+    }
+
     #[tokio::test]
     async fn handshake_with_malicious_server_peer() {
-        async fn execute_malicious_v0_handshake_incoming<S: Splittable>(stream: S) {
-            let (fake_id, _) = keys().await;
+        async fn execute_malicious_v0_handshake_incoming<S: Splittable>(
+            stream: S,
+            fake_id: AuthorityId,
+        ) {
             // send challenge with incorrect id
             let our_challenge = Challenge::new(fake_id);
             send_data(stream, our_challenge.clone())
@@ -263,9 +748,10 @@ mod tests {
         let (stream_a, stream_b) = MockSplittable::new(4096);
         let (id_a, _) = keys().await;
         let (_, pen_b) = keys().await;
+        let (fake_id, _) = keys().await;
         tokio::select! {
-            _ = execute_malicious_v0_handshake_incoming(stream_a) => panic!("should wait"),
-            result = execute_v0_handshake_outgoing(stream_b, pen_b, id_a) => assert_challenge_error(result),
+            _ = execute_malicious_v0_handshake_incoming(stream_a, fake_id.clone()) => panic!("should wait"),
+            result = execute_v0_handshake_outgoing(stream_b, pen_b, id_a.clone(), None, None) => assert_identity_mismatch(result, id_a, fake_id),
         }
     }
 
@@ -292,7 +778,7 @@ mod tests {
         let (_, pen_a) = keys().await;
         let (_, pen_b) = keys().await;
         tokio::select! {
-            result = execute_v0_handshake_incoming(stream_a, pen_a) => assert_signature_error(result),
+            result = execute_v0_handshake_incoming(stream_a, pen_a, None, None) => assert_bad_challenge_response(result),
             _ = execute_malicious_v0_handshake_outgoing_fake_challenge(stream_b, pen_b) => panic!("should wait"),
         }
     }
@@ -320,17 +806,94 @@ mod tests {
         let (_, pen_a) = keys().await;
         let (_, pen_b) = keys().await;
         tokio::select! {
-            result = execute_v0_handshake_incoming(stream_a, pen_a) => assert_signature_error(result),
+            result = execute_v0_handshake_incoming(stream_a, pen_a, None, None) => assert_bad_challenge_response(result),
             _ = execute_malicious_v0_handshake_outgoing_fake_signature(stream_b, pen_b) => panic!("should wait"),
         }
     }
 
+    #[tokio::test]
+    async fn handshake_with_injected_nonce() {
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        let (id_a, pen_a) = keys().await;
+        let (id_b, pen_b) = keys().await;
+        let ((_, _, received_id_b), (_, _)) = try_join!(
+            execute_handshake_incoming_with_nonce(stream_a, pen_a, Some(CHAIN), [7; 32], None),
+            execute_v0_handshake_outgoing(stream_b, pen_b, id_a, Some(CHAIN), None),
+        )
+        .expect("handshake should work");
+        assert_eq!(id_b, received_id_b);
+    }
+
+    #[tokio::test]
+    async fn handshake_with_tampered_response() {
+        pub async fn execute_tampering_v0_handshake_outgoing<S: Splittable>(
+            stream: S,
+            authority_pen: AuthorityPen,
+        ) {
+            let (stream, challenge) = receive_data::<_, Challenge>(stream)
+                .await
+                .expect("should receive");
+            // flip a bit of the signature, which comes last in the encoding
+            let mut encoded = Response::new(&authority_pen, &challenge).await.encode();
+            *encoded.last_mut().expect("responses are not empty") ^= 1;
+            let tampered_response =
+                Response::decode(&mut encoded.as_slice()).expect("should still decode");
+            send_data(stream, tampered_response)
+                .await
+                .expect("should send");
+            futures::future::pending::<()>().await;
+        }
+
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        let (_, pen_a) = keys().await;
+        let (_, pen_b) = keys().await;
+        tokio::select! {
+            result = execute_v0_handshake_incoming(stream_a, pen_a, None, None) => assert_bad_challenge_response(result),
+            _ = execute_tampering_v0_handshake_outgoing(stream_b, pen_b) => panic!("should wait"),
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_with_replayed_response() {
+        async fn execute_replaying_v0_handshake_outgoing<S: Splittable>(
+            stream: S,
+            captured_response: Response,
+        ) {
+            // ignore the challenge, answer with a response to an earlier one
+            let (stream, _) = receive_data::<_, Challenge>(stream)
+                .await
+                .expect("should receive");
+            send_data(stream, captured_response)
+                .await
+                .expect("should send");
+            futures::future::pending::<()>().await;
+        }
+
+        let (id_a, pen_a) = keys().await;
+        let (_, pen_b) = keys().await;
+        let captured_response = Response::new(&pen_b, &Challenge::with_nonce(id_a, [7; 32])).await;
+
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        tokio::select! {
+            result = execute_handshake_incoming_with_nonce(stream_a, pen_a.clone(), None, [8; 32], None) => assert_bad_challenge_response(result),
+            _ = execute_replaying_v0_handshake_outgoing(stream_b, captured_response.clone()) => panic!("should wait"),
+        }
+        // the very same response is fine if the challenge is the same
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        tokio::select! {
+            result = execute_handshake_incoming_with_nonce(stream_a, pen_a, None, [7; 32], None) => {
+                result.expect("the response answers this challenge");
+            }
+            _ = execute_replaying_v0_handshake_outgoing(stream_b, captured_response) => panic!("should wait"),
+        }
+    }
+
     #[tokio::test]
     async fn broken_incoming_connection_step_one() {
         // break the connection even before the handshake starts by dropping the stream
         let (stream_a, _) = MockSplittable::new(4096);
         let (_, pen_a) = keys().await;
-        assert_send_error(execute_v0_handshake_incoming(stream_a, pen_a).await);
+        assert_send_error(execute_v0_handshake_incoming(stream_a, pen_a, Some(CHAIN), None).await);
     }
 
     #[tokio::test]
@@ -338,7 +901,7 @@ mod tests {
         let (stream_a, stream_b) = MockSplittable::new(4096);
         let (_, pen_a) = keys().await;
         let (result, _) = join!(
-            execute_v0_handshake_incoming(stream_a, pen_a),
+            execute_v0_handshake_incoming(stream_a, pen_a, None, None),
             // mock outgoing handshake: receive the first message and terminate
             async {
                 receive_data::<_, Challenge>(stream_b)
@@ -349,13 +912,27 @@ mod tests {
         assert_receive_error(result);
     }
 
+    #[tokio::test]
+    async fn stalled_incoming_connection_times_out_writing() {
+        // nobody reads from the other side, so the small buffer fills up and writing blocks
+        let (stream_a, _stream_b) = MockSplittable::new(16);
+        let (_, pen_a) = keys().await;
+        let start = Instant::now();
+        assert_send_error(
+            execute_v0_handshake_incoming(stream_a, pen_a, Some(CHAIN), Some(SHORT_TIMEOUT)).await,
+        );
+        assert_elapsed_about(start, SHORT_TIMEOUT);
+    }
+
     #[tokio::test]
     async fn broken_outgoing_connection_step_one() {
         // break the connection even before the handshake starts by dropping the stream
         let (stream_a, _) = MockSplittable::new(4096);
         let (_, pen_a) = keys().await;
         let (id_b, _) = keys().await;
-        assert_receive_error(execute_v0_handshake_outgoing(stream_a, pen_a, id_b).await);
+        assert_receive_error(
+            execute_v0_handshake_outgoing(stream_a, pen_a, id_b, Some(CHAIN), None).await,
+        );
     }
 
     #[tokio::test]
@@ -367,6 +944,140 @@ mod tests {
         send_data(stream_a, Challenge::new(pen_a.authority_id()))
             .await
             .expect("should send");
-        assert_send_error(execute_v0_handshake_outgoing(stream_b, pen_b, id_a).await);
+        assert_send_error(execute_v0_handshake_outgoing(stream_b, pen_b, id_a, None, None).await);
+    }
+
+    #[tokio::test]
+    async fn chooses_preferred_codec() {
+        for preferred in Codec::SUPPORTED {
+            let (stream_a, stream_b) = MockSplittable::new(4096);
+            let _stream_a = announce_codecs(stream_a, HandshakeConfig::default())
+                .await
+                .expect("should announce");
+            let (_, codec) = choose_codec(stream_b, preferred, HandshakeConfig::default())
+                .await
+                .expect("should choose");
+            assert_eq!(codec, preferred);
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_identity_codec() {
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        // mock a peer that only knows how to decompress lz4
+        let _stream_a = send_data(stream_a, vec![Codec::Lz4.tag()])
+            .await
+            .expect("should send");
+        let (_, codec) = choose_codec(stream_b, Codec::Zstd, HandshakeConfig::default())
+            .await
+            .expect("should choose");
+        assert_eq!(codec, Codec::Identity);
+    }
+
+    #[tokio::test]
+    async fn both_sides_agree_on_common_capabilities() {
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        let (sender_a, receiver_a) = stream_a.split();
+        let (sender_b, receiver_b) = stream_b.split();
+        let ((_, _, agreed_a), (_, _, agreed_b)) = try_join!(
+            exchange_capabilities(
+                sender_a,
+                receiver_a,
+                Capabilities::supported(),
+                HandshakeConfig::default(),
+            ),
+            exchange_capabilities(
+                sender_b,
+                receiver_b,
+                Capabilities::PROBES,
+                HandshakeConfig::default(),
+            ),
+        )
+        .expect("should exchange");
+        assert_eq!(agreed_a, Capabilities::PROBES);
+        assert_eq!(agreed_b, Capabilities::PROBES);
+    }
+
+    #[tokio::test]
+    async fn ignores_unknown_capabilities() {
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        let (sender_a, receiver_a) = stream_a.split();
+        // mock a newer peer announcing a capability we do not know about
+        let (sender_b, receiver_b) = stream_b.split();
+        let _sender_b = send_data(sender_b, (1u64 << 63) | 1)
+            .await
+            .expect("should send");
+        let (_, _, agreed) = exchange_capabilities(
+            sender_a,
+            receiver_a,
+            Capabilities::supported(),
+            HandshakeConfig::default(),
+        )
+        .await
+        .expect("should exchange");
+        assert_eq!(agreed, Capabilities::COMPRESSION);
+        let (_, theirs) = receive_data::<_, Capabilities>(receiver_b)
+            .await
+            .expect("should receive");
+        assert_eq!(theirs, Capabilities::supported());
+    }
+
+    const SHORT_TIMEOUT: Duration = Duration::from_millis(100);
+
+    fn assert_elapsed_about(start: Instant, expected: Duration) {
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= expected && elapsed < expected * 10,
+            "timed out after {:?}, expected about {:?}",
+            elapsed,
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn incoming_handshake_times_out_on_silent_peer() {
+        // keep the other end alive, but never send anything
+        let (stream_a, _stream_b) = MockSplittable::new(4096);
+        let (_, pen_a) = keys().await;
+        let config = HandshakeConfig {
+            timeout: SHORT_TIMEOUT,
+            ..HandshakeConfig::default()
+        };
+        let start = Instant::now();
+        assert_timed_out(v0_handshake_incoming(stream_a, pen_a, true, config).await);
+        assert_elapsed_about(start, SHORT_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn outgoing_handshake_times_out_on_silent_peer() {
+        // keep the other end alive, but never send anything
+        let (stream_a, _stream_b) = MockSplittable::new(4096);
+        let (_, pen_a) = keys().await;
+        let (id_b, _) = keys().await;
+        let config = HandshakeConfig {
+            timeout: SHORT_TIMEOUT,
+            ..HandshakeConfig::default()
+        };
+        let start = Instant::now();
+        assert_timed_out(v0_handshake_outgoing(stream_a, pen_a, id_b, true, config).await);
+        assert_elapsed_about(start, SHORT_TIMEOUT);
+    }
+
+    #[test]
+    fn outgoing_dial_limit_follows_peers() {
+        let config = HandshakeConfig::default();
+        assert_eq!(config.outgoing_dial_limit(0), 1);
+        assert_eq!(config.outgoing_dial_limit(10), 4);
+        assert_eq!(config.outgoing_dial_limit(100), 34);
+        let config = HandshakeConfig {
+            max_outgoing: Some(3),
+            ..config
+        };
+        assert_eq!(config.outgoing_dial_limit(100), 3);
+        let config = HandshakeConfig {
+            max_outgoing: Some(0),
+            ..config
+        };
+        assert_eq!(config.outgoing_dial_limit(100), 1);
     }
 }
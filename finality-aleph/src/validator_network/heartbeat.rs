@@ -1,22 +1,65 @@
 use codec::{Decode, Encode};
+use futures::{future::Either, pin_mut};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    time::{sleep, timeout, Duration},
+    time::Duration,
 };
 
-use crate::validator_network::io::{receive_data, send_data};
+use crate::validator_network::{
+    clock::Clock,
+    io::{receive_data, send_data},
+    metrics::Metrics,
+};
 
-const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const MAX_MISSED_HEARTBEATS: u32 = 4;
 
+/// Timing of the heartbeats sent over a single connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeartbeatConfig {
+    /// How often a heartbeat is sent.
+    pub interval: Duration,
+    /// How long we wait for a heartbeat before considering the connection dead.
+    pub timeout: Duration,
+    /// How often the round trip time to the peer is probed, if at all. Only the peers that
+    /// announced they understand probes ever get probed. Off by default.
+    pub probe_interval: Option<Duration>,
+    /// How much longer than the timeout we wait for the first heartbeat on a new connection,
+    /// while its timing is still settling. There is no grace by default.
+    pub initial_grace: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval: HEARTBEAT_INTERVAL,
+            timeout: HEARTBEAT_INTERVAL * MAX_MISSED_HEARTBEATS,
+            probe_interval: None,
+            initial_grace: Duration::ZERO,
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    /// How long we wait for the first heartbeat after the connection is established.
+    pub fn initial_timeout(&self) -> Duration {
+        self.timeout + self.initial_grace
+    }
+}
+
 /// Represents the heartbeat message. Holds a single integer, so that it encodes into a nonempty
 /// string of bytes.
 #[derive(Debug, Clone, Encode, Decode)]
 struct Heartbeat(u32);
 
-/// Sends heartbeat messages at regular intervals, indefinitely.
+/// Sends heartbeat messages at regular intervals, as measured by the clock, indefinitely.
 /// Fails if the communication channel is closed.
-pub async fn heartbeat_sender<S: AsyncWrite + Unpin + Send>(mut stream: S) {
+pub async fn heartbeat_sender<S: AsyncWrite + Unpin + Send, C: Clock>(
+    mut stream: S,
+    config: HeartbeatConfig,
+    clock: C,
+    metrics: Option<Metrics>,
+) {
     loop {
         // Random number so the message contains something.
         stream = match send_data(stream, Heartbeat(43)).await {
@@ -24,51 +67,209 @@ pub async fn heartbeat_sender<S: AsyncWrite + Unpin + Send>(mut stream: S) {
             // If anything at all went wrong, the heartbeat is dead.
             Err(_) => return,
         };
-        sleep(HEARTBEAT_TIMEOUT).await;
+        if let Some(metrics) = &metrics {
+            metrics.report_heartbeat_sent();
+        }
+        clock.sleep(config.interval).await;
     }
 }
 
 /// Receives heartbeat messages indefinitely.
 /// Fails if the communication channel is closed, or if no message is received
-/// for too long.
-pub async fn heartbeat_receiver<S: AsyncRead + Unpin + Send>(mut stream: S) {
+/// for too long, as measured by the clock. The first heartbeat gets the initial grace on top.
+pub async fn heartbeat_receiver<S: AsyncRead + Unpin + Send, C: Clock>(
+    mut stream: S,
+    config: HeartbeatConfig,
+    clock: C,
+    metrics: Option<Metrics>,
+) {
+    let mut wait = config.initial_timeout();
     loop {
-        stream = match timeout(
-            HEARTBEAT_TIMEOUT * MAX_MISSED_HEARTBEATS,
-            receive_data::<S, Heartbeat>(stream),
-        )
-        .await
-        {
-            Ok(Ok((stream, _))) => stream,
-            // If anything at all went wrong the heartbeat is dead.
+        let receive = receive_data::<S, Heartbeat>(stream);
+        let deadline = clock.sleep(wait);
+        pin_mut!(receive, deadline);
+        stream = match futures::future::select(receive, deadline).await {
+            Either::Left((Ok((stream, _)), _)) => stream,
+            // If anything at all went wrong, or it took too long, the heartbeat is dead.
             _ => return,
         };
+        wait = config.timeout;
+        if let Some(metrics) = &metrics {
+            metrics.report_heartbeat();
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use futures::{poll, task::Poll};
     use tokio::{
         self,
-        time::{timeout, Duration},
+        time::{sleep, timeout, Duration},
     };
 
-    use super::{heartbeat_receiver, heartbeat_sender};
-    use crate::validator_network::mock::MockSplittable;
+    use super::{heartbeat_receiver, heartbeat_sender, Heartbeat, HeartbeatConfig};
+    use crate::validator_network::{
+        clock::TokioClock,
+        io::send_data,
+        metrics::{ConnectionStats, Metrics},
+        mock::{MockClock, MockSplittable},
+    };
 
     #[tokio::test]
     async fn sender_closed_on_broken_connection() {
         let (stream, _) = MockSplittable::new(4096);
-        timeout(Duration::from_secs(10), heartbeat_sender(stream))
-            .await
-            .expect("should end immediately");
+        timeout(
+            Duration::from_secs(10),
+            heartbeat_sender(stream, HeartbeatConfig::default(), TokioClock, None),
+        )
+        .await
+        .expect("should end immediately");
     }
 
     #[tokio::test]
     async fn receiver_closed_on_broken_connection() {
         let (stream, _) = MockSplittable::new(4096);
-        timeout(Duration::from_secs(10), heartbeat_receiver(stream))
+        timeout(
+            Duration::from_secs(10),
+            heartbeat_receiver(stream, HeartbeatConfig::default(), TokioClock, None),
+        )
+        .await
+        .expect("should end immediately");
+    }
+
+    #[tokio::test]
+    async fn receiver_closed_on_stalled_sender() {
+        let config = HeartbeatConfig {
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(50),
+            ..HeartbeatConfig::default()
+        };
+        // keep the other side alive, but never send anything through it
+        let (stream, _stalled) = MockSplittable::new(4096);
+        timeout(
+            Duration::from_secs(1),
+            heartbeat_receiver(stream, config, TokioClock, None),
+        )
+        .await
+        .expect("should end after the heartbeat timeout");
+    }
+
+    #[tokio::test]
+    async fn receiver_alive_with_regular_heartbeats() {
+        let config = HeartbeatConfig {
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(200),
+            ..HeartbeatConfig::default()
+        };
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        tokio::select! {
+            _ = heartbeat_sender(stream_a, config, TokioClock, None) => panic!("sender unexpectedly finished"),
+            _ = heartbeat_receiver(stream_b, config, TokioClock, None) => panic!("receiver unexpectedly finished"),
+            _ = sleep(Duration::from_secs(1)) => (),
+        }
+    }
+
+    #[tokio::test]
+    async fn receiver_closed_after_mock_timeout() {
+        let config = HeartbeatConfig::default();
+        let clock = MockClock::new();
+        let (stream, _stalled) = MockSplittable::new(4096);
+        let receiver = heartbeat_receiver(stream, config, clock.clone(), None);
+        futures::pin_mut!(receiver);
+        assert_eq!(poll!(&mut receiver), Poll::Pending);
+        clock.advance(config.timeout - Duration::from_millis(1));
+        assert_eq!(poll!(&mut receiver), Poll::Pending);
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(poll!(&mut receiver), Poll::Ready(()));
+    }
+
+    #[tokio::test]
+    async fn receiver_closed_after_mock_grace() {
+        let config = HeartbeatConfig {
+            initial_grace: Duration::from_secs(30),
+            ..HeartbeatConfig::default()
+        };
+        let clock = MockClock::new();
+        let (stream, _stalled) = MockSplittable::new(4096);
+        let receiver = heartbeat_receiver(stream, config, clock.clone(), None);
+        futures::pin_mut!(receiver);
+        assert_eq!(poll!(&mut receiver), Poll::Pending);
+        clock.advance(config.timeout);
+        assert_eq!(poll!(&mut receiver), Poll::Pending);
+        clock.advance(config.initial_grace);
+        assert_eq!(poll!(&mut receiver), Poll::Ready(()));
+    }
+
+    #[tokio::test]
+    async fn receiver_grace_only_for_first_heartbeat() {
+        let config = HeartbeatConfig {
+            initial_grace: Duration::from_secs(30),
+            ..HeartbeatConfig::default()
+        };
+        let clock = MockClock::new();
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        let receiver = heartbeat_receiver(stream_b, config, clock.clone(), None);
+        futures::pin_mut!(receiver);
+        assert_eq!(poll!(&mut receiver), Poll::Pending);
+        // late, but still within the grace
+        clock.advance(config.initial_timeout() - Duration::from_millis(1));
+        assert_eq!(poll!(&mut receiver), Poll::Pending);
+        let _stalled = send_data(stream_a, Heartbeat(43))
             .await
-            .expect("should end immediately");
+            .expect("should send");
+        assert_eq!(poll!(&mut receiver), Poll::Pending);
+
+        // from now on the usual timeout applies
+        clock.advance(config.timeout - Duration::from_millis(1));
+        assert_eq!(poll!(&mut receiver), Poll::Pending);
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(poll!(&mut receiver), Poll::Ready(()));
+    }
+
+    #[tokio::test]
+    async fn receiver_alive_with_mock_heartbeats() {
+        let config = HeartbeatConfig::default();
+        let clock = MockClock::new();
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        let sender = heartbeat_sender(stream_a, config, clock.clone(), None);
+        let receiver = heartbeat_receiver(stream_b, config, clock.clone(), None);
+        futures::pin_mut!(sender, receiver);
+        for _ in 0..100 {
+            assert_eq!(poll!(&mut sender), Poll::Pending);
+            assert_eq!(poll!(&mut receiver), Poll::Pending);
+            clock.advance(config.interval);
+        }
+    }
+
+    #[tokio::test]
+    async fn receiver_records_last_heartbeat() {
+        let config = HeartbeatConfig::default();
+        let clock = MockClock::new();
+        let stats = ConnectionStats::new();
+        let metrics = Metrics::for_connection(&None, stats.clone());
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        let sender = heartbeat_sender(stream_a, config, clock.clone(), None);
+        let receiver = heartbeat_receiver(stream_b, config, clock.clone(), Some(metrics));
+        futures::pin_mut!(sender, receiver);
+        assert_eq!(poll!(&mut receiver), Poll::Pending);
+        assert_eq!(stats.snapshot().last_heartbeat, None);
+
+        assert_eq!(poll!(&mut sender), Poll::Pending);
+        assert_eq!(poll!(&mut receiver), Poll::Pending);
+        let first = stats
+            .snapshot()
+            .last_heartbeat
+            .expect("should record the heartbeat");
+
+        sleep(Duration::from_millis(10)).await;
+        clock.advance(config.interval);
+        assert_eq!(poll!(&mut sender), Poll::Pending);
+        assert_eq!(poll!(&mut receiver), Poll::Pending);
+        let second = stats
+            .snapshot()
+            .last_heartbeat
+            .expect("should record the heartbeat");
+        assert!(second > first);
     }
 }
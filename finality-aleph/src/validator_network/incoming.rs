@@ -1,21 +1,23 @@
 use std::fmt::{Display, Error as FmtError, Formatter};
 
-use aleph_primitives::AuthorityId;
-use futures::channel::{mpsc, oneshot};
+use futures::channel::mpsc;
 use log::{debug, info};
 
 use crate::{
     crypto::AuthorityPen,
     validator_network::{
+        handshake::HandshakeConfig,
+        heartbeat::HeartbeatConfig,
+        io::ReceiveConfig,
+        metrics::Metrics,
         protocol_negotiation::{protocol, ProtocolNegotiationError},
-        protocols::ProtocolError,
+        protocols::{CloseReason, IncomingResult},
         Data, Splittable,
     },
 };
 
 enum IncomingError {
     ProtocolNegotiationError(ProtocolNegotiationError),
-    ProtocolError(ProtocolError),
 }
 
 impl Display for IncomingError {
@@ -23,7 +25,6 @@ impl Display for IncomingError {
         use IncomingError::*;
         match self {
             ProtocolNegotiationError(e) => write!(f, "protocol negotiation error: {}", e),
-            ProtocolError(e) => write!(f, "protocol error: {}", e),
         }
     }
 }
@@ -34,38 +35,82 @@ impl From<ProtocolNegotiationError> for IncomingError {
     }
 }
 
-impl From<ProtocolError> for IncomingError {
-    fn from(e: ProtocolError) -> Self {
-        IncomingError::ProtocolError(e)
-    }
-}
-
+#[allow(clippy::too_many_arguments)]
 async fn manage_incoming<D: Data, S: Splittable>(
     authority_pen: AuthorityPen,
     stream: S,
-    result_for_parent: mpsc::UnboundedSender<(AuthorityId, oneshot::Sender<()>)>,
-    data_for_user: mpsc::UnboundedSender<D>,
-) -> Result<(), IncomingError> {
+    result_for_parent: mpsc::UnboundedSender<IncomingResult>,
+    data_for_user: mpsc::Sender<D>,
+    heartbeat_config: HeartbeatConfig,
+    handshake_config: HandshakeConfig,
+    receive_config: ReceiveConfig,
+    metrics: Option<Metrics>,
+) -> Result<CloseReason, IncomingError> {
     debug!(target: "validator-network", "Performing incoming protocol negotiation.");
     let (stream, protocol) = protocol(stream).await?;
     debug!(target: "validator-network", "Negotiated protocol, running.");
-    Ok(protocol
-        .manage_incoming(stream, authority_pen, result_for_parent, data_for_user)
-        .await?)
+    if let Some(metrics) = &metrics {
+        metrics.report_protocol(protocol);
+    }
+    let reason = CloseReason::from(
+        protocol
+            .manage_incoming(
+                stream,
+                authority_pen,
+                result_for_parent,
+                data_for_user,
+                heartbeat_config,
+                handshake_config,
+                receive_config,
+                metrics.clone(),
+            )
+            .await,
+    );
+    if let Some(metrics) = &metrics {
+        metrics.report_close(&reason);
+    }
+    Ok(reason)
 }
 
 /// Manage an incoming connection. After the handshake it will send the recognized AuthorityId to
-/// the parent, together with an exit channel for this process. When this channel is dropped the
-/// process ends. Whenever data arrives on this connection it will be passed to the user. Any
-/// failures in receiving data result in the process stopping, we assume the other side will
-/// reestablish it if necessary.
+/// the parent, together with the negotiated protocol and an exit channel for this process. When
+/// this channel is dropped the process ends. Whenever data arrives on this connection it will be
+/// passed to the user. Any failures in receiving data result in the process stopping, we assume
+/// the other side will reestablish it if necessary. Returns whether the process stopped because the peer misbehaved.
+#[allow(clippy::too_many_arguments)]
 pub async fn incoming<D: Data, S: Splittable>(
     authority_pen: AuthorityPen,
     stream: S,
-    result_for_parent: mpsc::UnboundedSender<(AuthorityId, oneshot::Sender<()>)>,
-    data_for_user: mpsc::UnboundedSender<D>,
-) {
-    if let Err(e) = manage_incoming(authority_pen, stream, result_for_parent, data_for_user).await {
-        info!(target: "validator-network", "Incoming connection failed: {}", e);
+    result_for_parent: mpsc::UnboundedSender<IncomingResult>,
+    data_for_user: mpsc::Sender<D>,
+    heartbeat_config: HeartbeatConfig,
+    handshake_config: HandshakeConfig,
+    receive_config: ReceiveConfig,
+    metrics: Option<Metrics>,
+) -> bool {
+    match manage_incoming(
+        authority_pen,
+        stream,
+        result_for_parent,
+        data_for_user,
+        heartbeat_config,
+        handshake_config,
+        receive_config,
+        metrics,
+    )
+    .await
+    {
+        Ok(CloseReason::ParentClosed) => {
+            debug!(target: "validator-network", "Incoming connection closed by the service.");
+            false
+        }
+        Ok(reason) => {
+            info!(target: "validator-network", "Incoming connection closed: {}", reason);
+            reason.peer_misbehaved()
+        }
+        Err(e) => {
+            info!(target: "validator-network", "Incoming connection failed: {}", e);
+            false
+        }
     }
 }
@@ -1,21 +1,142 @@
 use std::{
     fmt::{Display, Error as FmtError, Formatter},
-    io::Error as IoError,
+    io::{Error as IoError, ErrorKind},
 };
 
+use bytes::Bytes;
 use codec::DecodeAll;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::{timeout, Duration},
+};
 
-use crate::validator_network::Data;
+use crate::validator_network::{rate_limit::RateLimit, Data};
 
 // We allow sending up to 16MiB, that should be enough forever.
 pub const MAX_DATA_SIZE: u32 = 16 * 1024 * 1024;
 
+const ZSTD_COMPRESSION_LEVEL: i32 = 3;
+
+/// How many received messages can wait for the user by default.
+const USER_QUEUE_CAPACITY: usize = 8192;
+
+/// Limits on the data we are willing to receive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReceiveConfig {
+    /// Frames announcing a bigger length are rejected before any memory is allocated for them.
+    pub max_frame_size: u32,
+    /// How long a connection can go without any data, heartbeats not counting, before it is
+    /// dropped. Never dropped for idleness if not set.
+    pub idle_timeout: Option<Duration>,
+    /// How many transient errors in a row are retried before the connection is dropped.
+    pub transient_retries: u32,
+    /// How fast data can be read from the network, `None` means no limit. When the limit is hit
+    /// the reading is paced, slowing the peer down.
+    pub rate_limit: Option<RateLimit>,
+    /// How many received messages can wait for the user, both in the queue of every connection
+    /// and in the one they all share, every connection can put one more on top. When the queue of
+    /// a connection is full, reading from it pauses until the user takes some, slowing the peer
+    /// down, while the other connections go on filling their own queues. Memory use thus grows
+    /// with the number of connections, which is the price of one slow peer not holding up the
+    /// rest.
+    pub user_queue_capacity: usize,
+}
+
+impl Default for ReceiveConfig {
+    fn default() -> Self {
+        ReceiveConfig {
+            max_frame_size: MAX_DATA_SIZE,
+            idle_timeout: None,
+            transient_retries: 3,
+            rate_limit: None,
+            user_queue_capacity: USER_QUEUE_CAPACITY,
+        }
+    }
+}
+
+/// Compression applied to data sent with a codec tag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    /// No compression at all.
+    Identity,
+    /// LZ4 block compression, fast but compressing less.
+    Lz4,
+    /// Zstandard compression, compressing more but slower.
+    Zstd,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::Identity
+    }
+}
+
+impl Codec {
+    /// All the codecs we are able to decompress.
+    pub const SUPPORTED: [Codec; 3] = [Codec::Identity, Codec::Lz4, Codec::Zstd];
+
+    /// The byte identifying the codec on the wire.
+    pub fn tag(&self) -> u8 {
+        use Codec::*;
+        match self {
+            Identity => 0,
+            Lz4 => 1,
+            Zstd => 2,
+        }
+    }
+
+    /// The codec identified by the byte, if we know it.
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        Codec::SUPPORTED
+            .into_iter()
+            .find(|codec| codec.tag() == tag)
+    }
+
+    fn compress(&self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        use Codec::*;
+        match self {
+            Identity => Ok(data),
+            Lz4 => {
+                let len = u32::try_from(data.len()).map_err(|_| Error::DataTooLong(u32::MAX))?;
+                let mut result = len.to_le_bytes().to_vec();
+                result.extend(
+                    lz4::block::compress(&data, None, false).map_err(Error::CompressionFailed)?,
+                );
+                Ok(result)
+            }
+            Zstd => zstd::bulk::compress(&data, ZSTD_COMPRESSION_LEVEL)
+                .map_err(Error::CompressionFailed),
+        }
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, ReceiveError> {
+        use Codec::*;
+        match self {
+            Identity => Ok(data.to_vec()),
+            Lz4 => {
+                if data.len() < 4 {
+                    return Err(ReceiveError::DataCorrupted);
+                }
+                let (len, compressed) = data.split_at(4);
+                let len = u32::from_le_bytes(len.try_into().expect("we split at 4 bytes"));
+                if len > MAX_DATA_SIZE {
+                    return Err(Error::DataTooLong(len).into());
+                }
+                lz4::block::decompress(compressed, Some(len as i32))
+                    .map_err(|_| ReceiveError::DataCorrupted)
+            }
+            Zstd => zstd::bulk::decompress(data, MAX_DATA_SIZE as usize)
+                .map_err(|_| ReceiveError::DataCorrupted),
+        }
+    }
+}
+
 /// A general error when sending or receving data.
 #[derive(Debug)]
 pub enum Error {
     ConnectionClosed(IoError),
     DataTooLong(u32),
+    CompressionFailed(IoError),
 }
 
 impl Display for Error {
@@ -28,23 +149,51 @@ impl Display for Error {
                 "encoded data too long - {} bytes, the limit is {}",
                 length, MAX_DATA_SIZE
             ),
+            CompressionFailed(e) => write!(f, "compression failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            ConnectionClosed(e) | CompressionFailed(e) => Some(e),
+            DataTooLong(_) => None,
         }
     }
 }
 
 /// An error when sending data.
 #[derive(Debug)]
-pub struct SendError(Error);
+pub enum SendError {
+    Error(Error),
+    Timeout,
+}
 
 impl Display for SendError {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
-        write!(f, "{}", self.0)
+        use SendError::*;
+        match self {
+            Error(e) => write!(f, "{}", e),
+            Timeout => write!(f, "timed out while writing data"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use SendError::*;
+        match self {
+            Error(e) => Some(e),
+            Timeout => None,
+        }
     }
 }
 
 impl From<Error> for SendError {
     fn from(e: Error) -> Self {
-        SendError(e)
+        SendError::Error(e)
     }
 }
 
@@ -52,7 +201,14 @@ impl From<Error> for SendError {
 #[derive(Debug)]
 pub enum ReceiveError {
     Error(Error),
+    /// Reading failed before any part of the frame arrived, so the stream can still be read from.
+    Interrupted(IoError),
     DataCorrupted,
+    UnknownCodec(u8),
+    FrameTooLarge {
+        size: u32,
+        limit: u32,
+    },
 }
 
 impl Display for ReceiveError {
@@ -60,66 +216,293 @@ impl Display for ReceiveError {
         use ReceiveError::*;
         match self {
             Error(e) => write!(f, "{}", e),
+            Interrupted(e) => write!(f, "interrupted before receiving anything: {}", e),
             DataCorrupted => write!(f, "received corrupted data"),
+            UnknownCodec(tag) => write!(f, "received data with unknown codec tag {}", tag),
+            FrameTooLarge { size, limit } => write!(
+                f,
+                "peer announced a frame of {} bytes, the limit is {}",
+                size, limit
+            ),
         }
     }
 }
 
+impl std::error::Error for ReceiveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use ReceiveError::*;
+        match self {
+            Error(e) => Some(e),
+            Interrupted(e) => Some(e),
+            DataCorrupted | UnknownCodec(_) | FrameTooLarge { .. } => None,
+        }
+    }
+}
+
+impl ReceiveError {
+    /// Whether the peer sent something it should not have, as opposed to the connection failing.
+    pub fn peer_misbehaved(&self) -> bool {
+        use ReceiveError::*;
+        match self {
+            Error(_) | Interrupted(_) => false,
+            DataCorrupted | UnknownCodec(_) | FrameTooLarge { .. } => true,
+        }
+    }
+
+    /// Whether receiving can be attempted again on the same stream. Only errors that happened
+    /// before any part of a frame was read qualify, anything else could leave us in the middle of
+    /// a frame.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, ReceiveError::Interrupted(_))
+    }
+
+    /// Whether the peer closed the connection, as opposed to it breaking in some other way.
+    pub fn closed_by_peer(&self) -> bool {
+        matches!(self, ReceiveError::Error(Error::ConnectionClosed(e)) if e.kind() == ErrorKind::UnexpectedEof)
+    }
+}
+
 impl From<Error> for ReceiveError {
     fn from(e: Error) -> Self {
         ReceiveError::Error(e)
     }
 }
 
-/// Sends some data using the stream.
-pub async fn send_data<S: AsyncWriteExt + Unpin, D: Data>(
-    mut stream: S,
-    data: D,
-) -> Result<S, SendError> {
-    let encoded = data.encode();
-    let len = u32::try_from(encoded.len()).map_err(|_| Error::DataTooLong(u32::MAX))?;
-    if len > MAX_DATA_SIZE {
-        return Err(Error::DataTooLong(len).into());
-    }
-    let encoded_len = len.to_le_bytes().to_vec();
+async fn write_frame<S: AsyncWriteExt + Unpin>(
+    stream: &mut S,
+    encoded_len: &[u8],
+    encoded: &[u8],
+) -> Result<(), Error> {
     stream
-        .write_all(&encoded_len)
+        .write_all(encoded_len)
         .await
         .map_err(Error::ConnectionClosed)?;
     stream
-        .write_all(&encoded)
+        .write_all(encoded)
         .await
         .map_err(Error::ConnectionClosed)?;
+    stream.flush().await.map_err(Error::ConnectionClosed)
+}
+
+/// The length prefix of a frame holding the encoded data.
+fn frame_len(encoded: &[u8]) -> Result<[u8; 4], Error> {
+    let len = u32::try_from(encoded.len()).map_err(|_| Error::DataTooLong(u32::MAX))?;
+    if len > MAX_DATA_SIZE {
+        return Err(Error::DataTooLong(len));
+    }
+    Ok(len.to_le_bytes())
+}
+
+async fn send_bytes<S: AsyncWriteExt + Unpin>(
+    mut stream: S,
+    encoded: &[u8],
+    write_timeout: Option<Duration>,
+) -> Result<S, SendError> {
+    let encoded_len = frame_len(encoded)?;
+    let write = write_frame(&mut stream, &encoded_len, encoded);
+    match write_timeout {
+        Some(write_timeout) => timeout(write_timeout, write)
+            .await
+            .map_err(|_| SendError::Timeout)??,
+        None => write.await?,
+    }
     Ok(stream)
 }
 
-/// Attempts to receive some data using the stream.
-pub async fn receive_data<S: AsyncReadExt + Unpin, D: Data>(
+/// Sends an already encoded frame using the stream, without copying it. Whatever is sent with
+/// `send_data` can be passed on this way after receiving it with `receive_raw`.
+pub async fn send_raw<S: AsyncWriteExt + Unpin>(stream: S, frame: Bytes) -> Result<S, SendError> {
+    send_bytes(stream, &frame, None).await
+}
+
+/// Sends some data using the stream.
+pub async fn send_data<S: AsyncWriteExt + Unpin, D: Data>(
+    stream: S,
+    data: D,
+) -> Result<S, SendError> {
+    send_raw(stream, data.encode().into()).await
+}
+
+/// Sends some data using the stream. If `write_timeout` is set, fails with `SendError::Timeout`
+/// when the data cannot be written within it, e.g. because the peer stopped reading.
+pub async fn send_data_with_timeout<S: AsyncWriteExt + Unpin, D: Data>(
+    stream: S,
+    data: D,
+    write_timeout: Option<Duration>,
+) -> Result<S, SendError> {
+    send_bytes(stream, &data.encode(), write_timeout).await
+}
+
+/// Sends some data using the stream, compressed with the codec and preceded by the codec tag.
+/// If `write_timeout` is set, fails with `SendError::Timeout` when the data cannot be written
+/// within it, e.g. because the peer stopped reading.
+pub async fn send_data_with_codec<S: AsyncWriteExt + Unpin, D: Data>(
+    stream: S,
+    data: D,
+    codec: Codec,
+    write_timeout: Option<Duration>,
+) -> Result<S, SendError> {
+    let mut encoded = vec![codec.tag()];
+    encoded.extend(codec.compress(data.encode())?);
+    send_bytes(stream, &encoded, write_timeout).await
+}
+
+/// Frames gathered to be written to the network at once. Every frame keeps its own length prefix,
+/// so the peer receives them one by one, exactly as if they were sent separately.
+#[derive(Default)]
+pub struct CoalescedFrames {
+    buffer: Vec<u8>,
+    frames: usize,
+}
+
+impl CoalescedFrames {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a frame with the data compressed with the codec, the same as `send_data_with_codec`
+    /// would send.
+    pub fn push_data_with_codec<D: Data>(&mut self, data: D, codec: Codec) -> Result<(), Error> {
+        let mut encoded = vec![codec.tag()];
+        encoded.extend(codec.compress(data.encode())?);
+        self.buffer.extend(frame_len(&encoded)?);
+        self.buffer.extend(encoded);
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// How many frames were gathered.
+    pub fn len(&self) -> usize {
+        self.frames
+    }
+
+    /// How many bytes will be written, length prefixes included.
+    pub fn size(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+/// Sends all the gathered frames using the stream, with a single write and flush.
+/// If `write_timeout` is set, fails with `SendError::Timeout` when they cannot be written within
+/// it, e.g. because the peer stopped reading.
+pub async fn send_coalesced<S: AsyncWriteExt + Unpin>(
     mut stream: S,
-) -> Result<(S, D), ReceiveError> {
+    frames: CoalescedFrames,
+    write_timeout: Option<Duration>,
+) -> Result<S, SendError> {
+    let write = async {
+        stream
+            .write_all(&frames.buffer)
+            .await
+            .map_err(Error::ConnectionClosed)?;
+        stream.flush().await.map_err(Error::ConnectionClosed)
+    };
+    match write_timeout {
+        Some(write_timeout) => timeout(write_timeout, write)
+            .await
+            .map_err(|_| SendError::Timeout)??,
+        None => write.await?,
+    }
+    Ok(stream)
+}
+
+/// Reads the length of the next frame, telling apart the errors that happened before any of it was
+/// read.
+async fn receive_len<S: AsyncReadExt + Unpin>(stream: &mut S) -> Result<u32, ReceiveError> {
     let mut buf = [0; 4];
-    stream
-        .read_exact(&mut buf[..])
-        .await
-        .map_err(Error::ConnectionClosed)?;
-    let len = u32::from_le_bytes(buf);
-    if len > MAX_DATA_SIZE {
-        return Err(Error::DataTooLong(len).into());
+    let mut filled = 0;
+    while filled < buf.len() {
+        match stream.read(&mut buf[filled..]).await {
+            Ok(0) => {
+                return Err(Error::ConnectionClosed(IoError::from(ErrorKind::UnexpectedEof)).into())
+            }
+            Ok(read) => filled += read,
+            Err(e)
+                if filled == 0
+                    && matches!(e.kind(), ErrorKind::Interrupted | ErrorKind::WouldBlock) =>
+            {
+                return Err(ReceiveError::Interrupted(e))
+            }
+            Err(e) => return Err(Error::ConnectionClosed(e).into()),
+        }
+    }
+    Ok(u32::from_le_bytes(buf))
+}
+
+async fn receive_bytes<S: AsyncReadExt + Unpin>(
+    mut stream: S,
+    max_frame_size: u32,
+) -> Result<(S, Vec<u8>), ReceiveError> {
+    let len = receive_len(&mut stream).await?;
+    if len > max_frame_size {
+        return Err(ReceiveError::FrameTooLarge {
+            size: len,
+            limit: max_frame_size,
+        });
     }
     let mut buf: Vec<u8> = vec![0; len as usize];
     stream
         .read_exact(&mut buf[..])
         .await
         .map_err(Error::ConnectionClosed)?;
+    Ok((stream, buf))
+}
+
+/// Attempts to receive a frame using the stream without decoding it, accepting frames up to the
+/// given size.
+pub async fn receive_raw<S: AsyncReadExt + Unpin>(
+    stream: S,
+    max_frame_size: u32,
+) -> Result<(S, Bytes), ReceiveError> {
+    let (stream, buf) = receive_bytes(stream, max_frame_size).await?;
+    Ok((stream, buf.into()))
+}
+
+/// Attempts to receive some data using the stream, accepting frames up to the given size.
+pub async fn receive_data_with_limit<S: AsyncReadExt + Unpin, D: Data>(
+    stream: S,
+    max_frame_size: u32,
+) -> Result<(S, D), ReceiveError> {
+    let (stream, buf) = receive_raw(stream, max_frame_size).await?;
     let data = D::decode_all(&mut &buf[..]).map_err(|_| ReceiveError::DataCorrupted)?;
     Ok((stream, data))
 }
 
+/// Attempts to receive some data using the stream, with the default frame size limit.
+pub async fn receive_data<S: AsyncReadExt + Unpin, D: Data>(
+    stream: S,
+) -> Result<(S, D), ReceiveError> {
+    receive_data_with_limit(stream, ReceiveConfig::default().max_frame_size).await
+}
+
+/// Attempts to receive some data sent with `send_data_with_codec` using the stream, accepting
+/// frames up to the given size.
+pub async fn receive_data_with_codec<S: AsyncReadExt + Unpin, D: Data>(
+    stream: S,
+    max_frame_size: u32,
+) -> Result<(S, D), ReceiveError> {
+    let (stream, buf) = receive_bytes(stream, max_frame_size).await?;
+    let (tag, compressed) = buf.split_first().ok_or(ReceiveError::DataCorrupted)?;
+    let codec = Codec::from_tag(*tag).ok_or(ReceiveError::UnknownCodec(*tag))?;
+    let encoded = codec.decompress(compressed)?;
+    let data = D::decode_all(&mut &encoded[..]).map_err(|_| ReceiveError::DataCorrupted)?;
+    Ok((stream, data))
+}
+
 #[cfg(test)]
 mod tests {
-    use tokio::io::{duplex, AsyncWriteExt};
+    use codec::Encode;
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt},
+        time::Duration,
+    };
 
-    use super::{receive_data, send_data, Error, ReceiveError, SendError, MAX_DATA_SIZE};
+    use super::{
+        receive_data, receive_data_with_codec, receive_data_with_limit, receive_raw, send_data,
+        send_data_with_codec, send_data_with_timeout, send_raw, Codec, Error, ReceiveError,
+        SendError, MAX_DATA_SIZE,
+    };
+    use crate::validator_network::mock::MockSplittable;
 
     #[tokio::test]
     async fn sends_and_receives_correct_data() {
@@ -133,6 +516,25 @@ mod tests {
         assert_eq!(data, received_data);
     }
 
+    #[tokio::test]
+    async fn raw_frames_are_compatible_with_data() {
+        let (sender, receiver) = duplex(4096);
+        let data: Vec<i32> = vec![4, 3, 43];
+        let sender = send_data(sender, data.clone())
+            .await
+            .expect("data should send");
+        let (receiver, frame) = receive_raw(receiver, MAX_DATA_SIZE)
+            .await
+            .expect("should receive frame");
+        assert_eq!(frame, data.encode());
+
+        // relaying the frame as it is delivers the original data
+        let _sender = send_raw(sender, frame).await.expect("frame should send");
+        let (_receiver, received_data) = receive_data(receiver).await.expect("should receive data");
+        let received_data: Vec<i32> = received_data;
+        assert_eq!(data, received_data);
+    }
+
     #[tokio::test]
     async fn fails_to_receive_from_dropped_connection() {
         let (_, receiver) = duplex(4096);
@@ -151,7 +553,7 @@ mod tests {
         let data: Vec<i32> = vec![4, 3, 43];
         match send_data(sender, data.clone()).await {
             Err(e) => match e {
-                SendError(Error::ConnectionClosed(_)) => (),
+                SendError::Error(Error::ConnectionClosed(_)) => (),
                 e => panic!("unexpected error: {}", e),
             },
             _ => panic!("send data to a dropped stream!"),
@@ -169,7 +571,7 @@ mod tests {
         ];
         match send_data(sender, data.clone()).await {
             Err(e) => match e {
-                SendError(Error::DataTooLong(_)) => (),
+                SendError::Error(Error::DataTooLong(_)) => (),
                 e => panic!("unexpected error: {}", e),
             },
             _ => panic!("send data to a dropped stream!"),
@@ -187,13 +589,48 @@ mod tests {
             .expect("sending should work");
         match receive_data::<_, i32>(receiver).await {
             Err(e) => match e {
-                ReceiveError::Error(Error::DataTooLong(long)) => assert_eq!(long, too_long),
+                ReceiveError::FrameTooLarge { size, limit } => {
+                    assert_eq!(size, too_long);
+                    assert_eq!(limit, MAX_DATA_SIZE);
+                }
                 e => panic!("unexpected error: {}", e),
             },
             _ => panic!("received too long data!"),
         }
     }
 
+    #[tokio::test]
+    async fn rejects_frame_above_configured_limit() {
+        let (mut sender, receiver) = MockSplittable::new(4096);
+        // only the length prefix is ever sent, if we tried to allocate this much the test would
+        // most likely crash
+        sender
+            .write_all(&u32::MAX.to_le_bytes())
+            .await
+            .expect("sending should work");
+        match receive_data_with_codec::<_, Vec<u8>>(receiver, 1024).await {
+            Err(ReceiveError::FrameTooLarge { size, limit }) => {
+                assert_eq!(size, u32::MAX);
+                assert_eq!(limit, 1024);
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+            _ => panic!("received an oversized frame!"),
+        }
+        let (mut sender, receiver) = MockSplittable::new(4096);
+        sender
+            .write_all(&1025u32.to_le_bytes())
+            .await
+            .expect("sending should work");
+        match receive_data_with_limit::<_, Vec<u8>>(receiver, 1024).await {
+            Err(ReceiveError::FrameTooLarge {
+                size: 1025,
+                limit: 1024,
+            }) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            _ => panic!("received an oversized frame!"),
+        }
+    }
+
     #[tokio::test]
     async fn fails_to_decode_empty_data() {
         let (mut sender, receiver) = duplex(4096);
@@ -210,4 +647,134 @@ mod tests {
             _ => panic!("decoded no data into something?!"),
         }
     }
+
+    async fn round_trip_with_codec(data: Vec<u8>, codec: Codec) {
+        let (sender, receiver) = MockSplittable::new(1024 * 1024);
+        let _sender = send_data_with_codec(sender, data.clone(), codec, None)
+            .await
+            .expect("data should send");
+        let (_receiver, received_data) = receive_data_with_codec(receiver, MAX_DATA_SIZE)
+            .await
+            .expect("should receive data");
+        let received_data: Vec<u8> = received_data;
+        assert_eq!(data, received_data);
+    }
+
+    fn compressible_payload() -> Vec<u8> {
+        b"aleph".iter().cycle().take(64 * 1024).cloned().collect()
+    }
+
+    fn incompressible_payload() -> Vec<u8> {
+        (0..64 * 1024).map(|_| rand::random()).collect()
+    }
+
+    #[tokio::test]
+    async fn round_trips_with_every_codec() {
+        for codec in Codec::SUPPORTED {
+            round_trip_with_codec(compressible_payload(), codec).await;
+            round_trip_with_codec(incompressible_payload(), codec).await;
+            round_trip_with_codec(Vec::new(), codec).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn compression_reduces_size() {
+        for codec in [Codec::Lz4, Codec::Zstd] {
+            let (sender, mut receiver) = duplex(1024 * 1024);
+            let _sender = send_data_with_codec(sender, compressible_payload(), codec, None)
+                .await
+                .expect("data should send");
+            let mut len = [0; 4];
+            receiver
+                .read_exact(&mut len)
+                .await
+                .expect("should receive length");
+            assert!(u32::from_le_bytes(len) < 16 * 1024);
+        }
+    }
+
+    #[tokio::test]
+    async fn times_out_on_stalled_connection() {
+        // nobody reads from the other side, so the small buffer fills up and writing blocks
+        let (sender, _receiver) = MockSplittable::new(16);
+        match send_data_with_codec(
+            sender,
+            incompressible_payload(),
+            Codec::Identity,
+            Some(Duration::from_millis(50)),
+        )
+        .await
+        {
+            Err(SendError::Timeout) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            _ => panic!("sent data nobody received!"),
+        }
+    }
+
+    #[tokio::test]
+    async fn times_out_plain_data_on_stalled_connection() {
+        let (sender, _receiver) = MockSplittable::new(16);
+        match send_data_with_timeout(
+            sender,
+            incompressible_payload(),
+            Some(Duration::from_millis(50)),
+        )
+        .await
+        {
+            Err(SendError::Timeout) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            _ => panic!("sent data nobody received!"),
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_within_timeout() {
+        let (sender, receiver) = MockSplittable::new(1024 * 1024);
+        let data = compressible_payload();
+        let _sender = send_data_with_codec(
+            sender,
+            data.clone(),
+            Codec::Lz4,
+            Some(Duration::from_secs(5)),
+        )
+        .await
+        .expect("data should send");
+        let (_receiver, received_data) = receive_data_with_codec(receiver, MAX_DATA_SIZE)
+            .await
+            .expect("should receive data");
+        let received_data: Vec<u8> = received_data;
+        assert_eq!(data, received_data);
+    }
+
+    #[tokio::test]
+    async fn fails_to_receive_unknown_codec() {
+        let (mut sender, receiver) = duplex(4096);
+        let mut payload = 2u32.to_le_bytes().to_vec();
+        payload.extend([43, 0]);
+        sender
+            .write_all(&payload)
+            .await
+            .expect("sending should work");
+        match receive_data_with_codec::<_, u8>(receiver, MAX_DATA_SIZE).await {
+            Err(ReceiveError::UnknownCodec(43)) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            _ => panic!("decoded data with an unknown codec!"),
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_to_decompress_corrupted_data() {
+        let (mut sender, receiver) = duplex(4096);
+        let mut payload = 5u32.to_le_bytes().to_vec();
+        payload.extend([Codec::Zstd.tag(), 4, 3, 4, 3]);
+        sender
+            .write_all(&payload)
+            .await
+            .expect("sending should work");
+        match receive_data_with_codec::<_, Vec<u8>>(receiver, MAX_DATA_SIZE).await {
+            Err(ReceiveError::DataCorrupted) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            _ => panic!("decompressed garbage!"),
+        }
+    }
 }
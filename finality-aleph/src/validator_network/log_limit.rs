@@ -0,0 +1,128 @@
+use std::{collections::HashMap, fmt::Display, sync::Arc};
+
+use log::{log, Level};
+use parking_lot::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// How long identical messages are coalesced, before one of them gets logged again.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+/// Above this many remembered messages the ones not repeated recently are forgotten.
+const MAX_REMEMBERED: usize = 1024;
+
+struct Seen {
+    last_logged: Instant,
+    suppressed: usize,
+}
+
+/// Coalesces identical messages about the same peer, so that a peer that stays down does not
+/// flood the logs with the same error on every attempt to reach it. A message is logged the first
+/// time, and then at most once per interval, together with how many times it was repeated in the
+/// meantime. All the clones share what they have seen.
+#[derive(Clone)]
+pub struct LogLimiter {
+    interval: Duration,
+    seen: Arc<Mutex<HashMap<(String, String), Seen>>>,
+}
+
+impl Default for LogLimiter {
+    fn default() -> Self {
+        LogLimiter::new(DEFAULT_INTERVAL)
+    }
+}
+
+impl LogLimiter {
+    pub fn new(interval: Duration) -> Self {
+        LogLimiter {
+            interval,
+            seen: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Whether the message about the peer should be logged at the given moment, and if so, how
+    /// many identical ones were suppressed since it was last logged.
+    fn check_at(&self, peer: String, message: String, now: Instant) -> Option<usize> {
+        let mut seen = self.seen.lock();
+        if seen.len() >= MAX_REMEMBERED {
+            let interval = self.interval;
+            seen.retain(|_, seen| now.saturating_duration_since(seen.last_logged) < interval);
+        }
+        match seen.get_mut(&(peer.clone(), message.clone())) {
+            Some(seen) if now.saturating_duration_since(seen.last_logged) < self.interval => {
+                seen.suppressed += 1;
+                None
+            }
+            Some(seen) => {
+                let suppressed = seen.suppressed;
+                seen.last_logged = now;
+                seen.suppressed = 0;
+                Some(suppressed)
+            }
+            None => {
+                seen.insert(
+                    (peer, message),
+                    Seen {
+                        last_logged: now,
+                        suppressed: 0,
+                    },
+                );
+                Some(0)
+            }
+        }
+    }
+
+    /// Logs the message about the peer, unless an identical one was logged recently.
+    pub fn log(&self, level: Level, peer: &impl Display, message: String) {
+        match self.check_at(peer.to_string(), message.clone(), Instant::now()) {
+            Some(0) => log!(target: "validator-network", level, "{}", message),
+            Some(repeated) => {
+                log!(target: "validator-network", level, "{} (repeated {} times)", message, repeated)
+            }
+            None => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::{Duration, Instant};
+
+    use super::LogLimiter;
+
+    const INTERVAL: Duration = Duration::from_secs(60);
+
+    fn check(limiter: &LogLimiter, peer: &str, message: &str, now: Instant) -> Option<usize> {
+        limiter.check_at(peer.to_string(), message.to_string(), now)
+    }
+
+    #[test]
+    fn suppresses_repeated_messages() {
+        let limiter = LogLimiter::new(INTERVAL);
+        let start = Instant::now();
+        assert_eq!(check(&limiter, "alice", "dial failed", start), Some(0));
+        for second in 1..=5 {
+            let now = start + Duration::from_secs(second);
+            assert_eq!(check(&limiter, "alice", "dial failed", now), None);
+        }
+        // the summary comes with the first message after the interval
+        assert_eq!(
+            check(&limiter, "alice", "dial failed", start + INTERVAL),
+            Some(5)
+        );
+        assert_eq!(
+            check(&limiter, "alice", "dial failed", start + INTERVAL),
+            None
+        );
+    }
+
+    #[test]
+    fn keys_on_message_and_peer() {
+        let limiter = LogLimiter::new(INTERVAL);
+        let now = Instant::now();
+        assert_eq!(check(&limiter, "alice", "dial failed", now), Some(0));
+        assert_eq!(check(&limiter, "bob", "dial failed", now), Some(0));
+        assert_eq!(check(&limiter, "alice", "handshake failed", now), Some(0));
+        assert_eq!(check(&limiter, "alice", "dial failed", now), None);
+        // clones share what they have seen
+        assert_eq!(check(&limiter.clone(), "bob", "dial failed", now), None);
+    }
+}
@@ -1,19 +1,30 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::{Display, Error as FmtError, Formatter},
 };
 
 use aleph_primitives::AuthorityId;
-use futures::channel::{mpsc, oneshot};
+use futures::channel::oneshot;
 
-use crate::validator_network::Data;
+use crate::validator_network::{
+    send_channel::{DataSender, SendChannelError},
+    Data,
+};
 
 /// Network component responsible for holding the list of peers that we
 /// want to connect to, and managing the established connections.
+///
+/// Every connection carries data in one direction only, so having both an incoming and an
+/// outgoing connection with the same peer is expected rather than a duplicate, and neither
+/// should be dropped in favour of the other. Only a newer connection in the same direction
+/// replaces an older one.
 pub struct Manager<A: Data, D: Data> {
     addresses: HashMap<AuthorityId, Vec<A>>,
-    outgoing: HashMap<AuthorityId, mpsc::UnboundedSender<D>>,
+    outgoing: HashMap<AuthorityId, DataSender<D>>,
+    outgoing_exits: HashMap<AuthorityId, oneshot::Sender<()>>,
     incoming: HashMap<AuthorityId, oneshot::Sender<()>>,
+    /// Peers whose outgoing data is held back, also in connections established later.
+    paused: HashSet<AuthorityId>,
 }
 
 /// Error during sending data through the Manager
@@ -23,6 +34,8 @@ pub enum SendError {
     ConnectionClosed,
     /// Peer not added to the manager
     PeerNotFound,
+    /// Too much data waiting to be sent to the peer
+    BufferOverflow,
 }
 
 impl Display for SendError {
@@ -31,6 +44,7 @@ impl Display for SendError {
         match self {
             ConnectionClosed => write!(f, "worker dead"),
             PeerNotFound => write!(f, "peer not found"),
+            BufferOverflow => write!(f, "send buffer overflow"),
         }
     }
 }
@@ -57,6 +71,14 @@ impl Display for ManagerStatus {
     }
 }
 
+/// Which connections with a peer we want to stay connected to are alive.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PeerConnections {
+    pub peer_id: AuthorityId,
+    pub outgoing: bool,
+    pub incoming: bool,
+}
+
 /// Possible results of adding connections.
 #[derive(Debug, PartialEq, Eq)]
 pub enum AddResult {
@@ -74,7 +96,9 @@ impl<A: Data, D: Data> Manager<A, D> {
         Manager {
             addresses: HashMap::new(),
             outgoing: HashMap::new(),
+            outgoing_exits: HashMap::new(),
             incoming: HashMap::new(),
+            paused: HashSet::new(),
         }
     }
 
@@ -85,6 +109,11 @@ impl<A: Data, D: Data> Manager<A, D> {
         self.addresses.insert(peer_id, addresses).is_none()
     }
 
+    /// How many peers we want to stay connected to.
+    pub fn peer_count(&self) -> usize {
+        self.addresses.len()
+    }
+
     /// Return Option containing addresses of the given peer, or None if
     /// the peer is unknown.
     pub fn peer_addresses(&self, peer_id: &AuthorityId) -> Option<Vec<A>> {
@@ -96,18 +125,40 @@ impl<A: Data, D: Data> Manager<A, D> {
     pub fn add_outgoing(
         &mut self,
         peer_id: AuthorityId,
-        data_for_network: mpsc::UnboundedSender<D>,
+        data_for_network: DataSender<D>,
     ) -> AddResult {
         use AddResult::*;
         if !self.addresses.contains_key(&peer_id) {
             return Uninterested;
         }
+        data_for_network.set_paused(self.paused.contains(&peer_id));
         match self.outgoing.insert(peer_id, data_for_network) {
             Some(_) => Replaced,
             None => Added,
         }
     }
 
+    /// Set the exit channel of the worker managing the outgoing connection with a known peer,
+    /// replacing the channel of any previous worker.
+    pub fn set_outgoing_exit(&mut self, peer_id: AuthorityId, exit: oneshot::Sender<()>) {
+        if self.addresses.contains_key(&peer_id) {
+            self.outgoing_exits.insert(peer_id, exit);
+        }
+    }
+
+    /// Take the exit channel of the worker managing the outgoing connection with the peer, so that
+    /// the worker keeps running after a new one is spawned, until the channel is used or dropped.
+    pub fn take_outgoing_exit(&mut self, peer_id: &AuthorityId) -> Option<oneshot::Sender<()>> {
+        self.outgoing_exits.remove(peer_id)
+    }
+
+    /// Whether a worker managing the outgoing connection with the peer is still running.
+    pub fn has_outgoing_worker(&self, peer_id: &AuthorityId) -> bool {
+        self.outgoing_exits
+            .get(peer_id)
+            .map_or(false, |exit| !exit.is_canceled())
+    }
+
     /// Add an established incoming connection with a known peer,
     /// but only if the peer is on the list of peers that we want to stay connected with.
     pub fn add_incoming(&mut self, peer_id: AuthorityId, exit: oneshot::Sender<()>) -> AddResult {
@@ -122,22 +173,81 @@ impl<A: Data, D: Data> Manager<A, D> {
     }
 
     /// Remove a peer from the list of peers that we want to stay connected with.
-    /// Close any incoming and outgoing connections that were established.
+    /// Close any incoming and outgoing connections that were established, the outgoing ones
+    /// after sending the data already queued.
     pub fn remove_peer(&mut self, peer_id: &AuthorityId) {
         self.addresses.remove(peer_id);
+        self.paused.remove(peer_id);
+        self.incoming.remove(peer_id);
+        self.outgoing.remove(peer_id);
+        if let Some(exit) = self.outgoing_exits.remove(peer_id) {
+            // The worker might already be dead, nothing to do then.
+            let _ = exit.send(());
+        }
+    }
+
+    /// Close any incoming and outgoing connections with a peer, but keep it on the list of peers
+    /// that we want to stay connected with.
+    pub fn disconnect(&mut self, peer_id: &AuthorityId) {
         self.incoming.remove(peer_id);
         self.outgoing.remove(peer_id);
+        if let Some(exit) = self.outgoing_exits.remove(peer_id) {
+            // The worker might already be dead, nothing to do then.
+            let _ = exit.send(());
+        }
+    }
+
+    /// Hold back or release the data sent to a known peer, without closing the connection, so
+    /// that the heartbeats keep it alive. The held back data is buffered as usual and sent in
+    /// order once released. Returns whether the peer is known.
+    pub fn set_paused(&mut self, peer_id: &AuthorityId, paused: bool) -> bool {
+        if !self.addresses.contains_key(peer_id) {
+            return false;
+        }
+        match paused {
+            true => self.paused.insert(peer_id.clone()),
+            false => self.paused.remove(peer_id),
+        };
+        if let Some(data_for_network) = self.outgoing.get(peer_id) {
+            data_for_network.set_paused(paused);
+        }
+        true
     }
 
     /// Send data to a peer.
     /// Returns error if there is no outgoing connection to the peer,
-    /// or if the connection is dead.
+    /// if the connection is dead, or if too much data is waiting to be sent.
     pub fn send_to(&mut self, peer_id: &AuthorityId, data: D) -> Result<(), SendError> {
         self.outgoing
             .get(peer_id)
             .ok_or(SendError::PeerNotFound)?
-            .unbounded_send(data)
-            .map_err(|_| SendError::ConnectionClosed)
+            .send(data)
+            .map_err(|e| match e {
+                SendChannelError::Closed => SendError::ConnectionClosed,
+                SendChannelError::Overflow => SendError::BufferOverflow,
+            })
+    }
+
+    /// Whether there is a live outgoing connection with the peer.
+    pub fn is_connected(&self, peer_id: &AuthorityId) -> bool {
+        self.outgoing
+            .get(peer_id)
+            .map_or(false, |sender| !sender.is_closed())
+    }
+
+    /// The state of connections with all the peers we want to stay connected to.
+    pub fn peer_connections(&self) -> Vec<PeerConnections> {
+        self.addresses
+            .keys()
+            .map(|peer_id| PeerConnections {
+                peer_id: peer_id.clone(),
+                outgoing: self.is_connected(peer_id),
+                incoming: self
+                    .incoming
+                    .get(peer_id)
+                    .map_or(false, |exit| !exit.is_canceled()),
+            })
+            .collect()
     }
 
     /// A status of the manager, to be displayed somewhere.
@@ -160,13 +270,14 @@ impl<A: Data, D: Data> Manager<A, D> {
 
 #[cfg(test)]
 mod tests {
-    use futures::{
-        channel::{mpsc, oneshot},
-        StreamExt,
-    };
+    use futures::channel::oneshot;
+    use tokio::time::{timeout, Duration};
 
-    use super::{AddResult::*, Manager, SendError};
-    use crate::validator_network::mock::keys;
+    use super::{AddResult::*, Manager, PeerConnections, SendError};
+    use crate::validator_network::{
+        mock::keys,
+        send_channel::{send_channel, OverflowPolicy, SendChannelConfig},
+    };
 
     type Data = String;
     type Address = String;
@@ -209,7 +320,7 @@ mod tests {
             String::from("a/b/c"),
             String::from("43.43.43.43:43000"),
         ];
-        let (tx, _rx) = mpsc::unbounded();
+        let (tx, _rx) = send_channel(SendChannelConfig::default());
         // try add unknown peer
         manager.add_outgoing(peer_id.clone(), tx);
         // sending should fail
@@ -219,15 +330,84 @@ mod tests {
         );
         // add peer, this time for real
         assert!(manager.add_peer(peer_id.clone(), addresses.clone()));
-        let (tx, mut rx) = mpsc::unbounded();
+        let (tx, mut rx) = send_channel(SendChannelConfig::default());
         assert_eq!(manager.add_outgoing(peer_id.clone(), tx), Added);
         // send and receive
         assert!(manager.send_to(&peer_id, data.clone()).is_ok());
-        assert_eq!(data, rx.next().await.expect("should receive"));
+        assert_eq!(
+            data,
+            rx.next()
+                .await
+                .expect("should receive")
+                .expect("should receive")
+        );
         // remove peer
         manager.remove_peer(&peer_id);
         // receiving should fail
-        assert!(rx.next().await.is_none());
+        assert_eq!(rx.next().await, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn outgoing_buffer_overflow() {
+        let mut manager = Manager::<Address, Data>::new();
+        let (peer_id, _) = keys().await;
+        assert!(manager.add_peer(peer_id.clone(), vec![String::from("a/b/c")]));
+        let (tx, _rx) = send_channel(SendChannelConfig {
+            capacity: Some(2),
+            overflow_policy: OverflowPolicy::ReturnError,
+            ..SendChannelConfig::default()
+        });
+        assert_eq!(manager.add_outgoing(peer_id.clone(), tx), Added);
+        assert!(manager.send_to(&peer_id, String::from("1")).is_ok());
+        assert!(manager.send_to(&peer_id, String::from("2")).is_ok());
+        // the buffer is full now
+        assert_eq!(
+            manager.send_to(&peer_id, String::from("3")),
+            Err(SendError::BufferOverflow)
+        );
+        // and the connection is considered dead afterwards
+        assert_eq!(
+            manager.send_to(&peer_id, String::from("4")),
+            Err(SendError::ConnectionClosed)
+        );
+    }
+
+    #[tokio::test]
+    async fn outgoing_exit() {
+        let mut manager = Manager::<Address, Data>::new();
+        let (peer_id, _) = keys().await;
+        let (tx, mut rx) = oneshot::channel();
+        // unknown peer, the exit channel is dropped
+        manager.set_outgoing_exit(peer_id.clone(), tx);
+        assert!(rx.try_recv().is_err());
+        assert!(manager.add_peer(peer_id.clone(), vec![String::from("a/b/c")]));
+        let (tx, mut rx) = oneshot::channel();
+        manager.set_outgoing_exit(peer_id.clone(), tx);
+        assert_eq!(rx.try_recv(), Ok(None));
+        // removing the peer signals the worker to exit
+        manager.remove_peer(&peer_id);
+        assert_eq!(rx.try_recv(), Ok(Some(())));
+    }
+
+    #[tokio::test]
+    async fn pausing_survives_replaced_connections() {
+        let mut manager = Manager::<Address, Data>::new();
+        let (peer_id, _) = keys().await;
+        // unknown peers cannot be paused
+        assert!(!manager.set_paused(&peer_id, true));
+        assert!(manager.add_peer(peer_id.clone(), vec![String::from("a/b/c")]));
+        let (tx, mut rx) = send_channel(SendChannelConfig::default());
+        assert_eq!(manager.add_outgoing(peer_id.clone(), tx), Added);
+        assert!(manager.set_paused(&peer_id, true));
+        assert!(manager.send_to(&peer_id, String::from("1")).is_ok());
+        assert!(timeout(Duration::from_millis(50), rx.next()).await.is_err());
+        // the replacement connection starts paused as well
+        let (tx, mut rx) = send_channel(SendChannelConfig::default());
+        assert_eq!(manager.add_outgoing(peer_id.clone(), tx), Replaced);
+        assert!(manager.send_to(&peer_id, String::from("2")).is_ok());
+        assert!(timeout(Duration::from_millis(50), rx.next()).await.is_err());
+        assert!(manager.set_paused(&peer_id, false));
+        assert_eq!(rx.next().await, Ok(Some(String::from("2"))));
     }
 
     #[tokio::test]
@@ -262,4 +442,44 @@ mod tests {
         // receiving should fail
         assert!(rx2.try_recv().is_err());
     }
+
+    #[tokio::test]
+    async fn reports_peer_connections() {
+        let mut manager = Manager::<Address, Data>::new();
+        let (peer_id, _) = keys().await;
+        assert!(manager.peer_connections().is_empty());
+        assert!(manager.add_peer(peer_id.clone(), vec![String::from("a/b/c")]));
+        assert_eq!(
+            manager.peer_connections(),
+            vec![PeerConnections {
+                peer_id: peer_id.clone(),
+                outgoing: false,
+                incoming: false,
+            }]
+        );
+        let (tx, _rx) = send_channel(SendChannelConfig::default());
+        assert_eq!(manager.add_outgoing(peer_id.clone(), tx), Added);
+        let (exit, _exit_rx) = oneshot::channel();
+        assert_eq!(manager.add_incoming(peer_id.clone(), exit), Added);
+        assert_eq!(
+            manager.peer_connections(),
+            vec![PeerConnections {
+                peer_id: peer_id.clone(),
+                outgoing: true,
+                incoming: true,
+            }]
+        );
+        // dead connections do not count
+        let (tx, rx) = send_channel(SendChannelConfig::default());
+        drop(rx);
+        assert_eq!(manager.add_outgoing(peer_id.clone(), tx), Replaced);
+        assert_eq!(
+            manager.peer_connections(),
+            vec![PeerConnections {
+                peer_id,
+                outgoing: false,
+                incoming: true,
+            }]
+        );
+    }
 }
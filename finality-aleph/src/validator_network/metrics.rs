@@ -0,0 +1,378 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use prometheus_endpoint::{
+    register, Counter, CounterVec, Histogram, HistogramOpts, Opts, PrometheusError, Registry, U64,
+};
+
+use crate::validator_network::{
+    handshake::Capabilities,
+    protocols::{CloseReason, Protocol},
+};
+
+#[derive(Clone)]
+struct Counters {
+    bytes_sent: Counter<U64>,
+    bytes_received: Counter<U64>,
+    messages_sent: Counter<U64>,
+    messages_received: Counter<U64>,
+    heartbeats: Counter<U64>,
+    round_trips: Histogram,
+    connection_setups: Histogram,
+    closed_connections: CounterVec<U64>,
+    misrouted_data: Counter<U64>,
+    stalled_by_consumer: Counter<U64>,
+}
+
+impl Counters {
+    fn register(registry: &Registry) -> Result<Self, PrometheusError> {
+        Ok(Counters {
+            bytes_sent: register(
+                Counter::new(
+                    "aleph_validator_network_bytes_sent",
+                    "Bytes of encoded data sent, before compression",
+                )?,
+                registry,
+            )?,
+            bytes_received: register(
+                Counter::new(
+                    "aleph_validator_network_bytes_received",
+                    "Bytes of encoded data received, after decompression",
+                )?,
+                registry,
+            )?,
+            messages_sent: register(
+                Counter::new(
+                    "aleph_validator_network_messages_sent",
+                    "Data messages sent",
+                )?,
+                registry,
+            )?,
+            messages_received: register(
+                Counter::new(
+                    "aleph_validator_network_messages_received",
+                    "Data messages received",
+                )?,
+                registry,
+            )?,
+            heartbeats: register(
+                Counter::new(
+                    "aleph_validator_network_heartbeats",
+                    "Heartbeats sent or received together with the data",
+                )?,
+                registry,
+            )?,
+            round_trips: register(
+                Histogram::with_opts(HistogramOpts::new(
+                    "aleph_validator_network_round_trip_seconds",
+                    "Round trip times measured by probing the peers",
+                ))?,
+                registry,
+            )?,
+            connection_setups: register(
+                Histogram::with_opts(HistogramOpts::new(
+                    "aleph_validator_network_connection_setup_seconds",
+                    "Times from starting to dial a peer to finishing the handshake with them",
+                ))?,
+                registry,
+            )?,
+            closed_connections: register(
+                CounterVec::new(
+                    Opts::new(
+                        "aleph_validator_network_closed_connections",
+                        "Connections closed, by the reason of closing",
+                    ),
+                    &["reason"],
+                )?,
+                registry,
+            )?,
+            misrouted_data: register(
+                Counter::new(
+                    "aleph_validator_network_misrouted_data",
+                    "Data messages dropped, as their session checksums were missing or did not match",
+                )?,
+                registry,
+            )?,
+            stalled_by_consumer: register(
+                Counter::new(
+                    "aleph_validator_network_stalled_by_consumer",
+                    "Times reading from the network paused, as the user was not taking the data",
+                )?,
+                registry,
+            )?,
+        })
+    }
+}
+
+/// What we know about the traffic of a single connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionSnapshot {
+    pub protocol: Option<Protocol>,
+    pub capabilities: Option<Capabilities>,
+    pub last_heartbeat: Option<Instant>,
+    pub last_round_trip: Option<Duration>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+#[derive(Default)]
+struct ConnectionStatsInner {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    protocol: Mutex<Option<Protocol>>,
+    capabilities: Mutex<Option<Capabilities>>,
+    last_heartbeat: Mutex<Option<Instant>>,
+    last_round_trip: Mutex<Option<Duration>>,
+}
+
+/// Statistics of a single connection, shared between the worker managing it and the service.
+/// Cloning is cheap and all the clones report to the same statistics.
+#[derive(Clone, Default)]
+pub struct ConnectionStats {
+    inner: Arc<ConnectionStatsInner>,
+}
+
+impl ConnectionStats {
+    /// Fresh statistics of a connection that did not carry anything yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The current state of the statistics.
+    pub fn snapshot(&self) -> ConnectionSnapshot {
+        ConnectionSnapshot {
+            protocol: *self.inner.protocol.lock(),
+            capabilities: *self.inner.capabilities.lock(),
+            last_heartbeat: *self.inner.last_heartbeat.lock(),
+            last_round_trip: *self.inner.last_round_trip.lock(),
+            bytes_sent: self.inner.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.inner.bytes_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counters of the traffic going through the validator network connections. Cloning is cheap and
+/// all the clones report to the same counters.
+#[derive(Clone)]
+pub struct Metrics {
+    counters: Option<Counters>,
+    connection: Option<ConnectionStats>,
+    dial_started: Option<Instant>,
+}
+
+impl Metrics {
+    /// Create the counters and register them in the registry.
+    pub fn register(registry: &Registry) -> Result<Self, PrometheusError> {
+        Ok(Metrics {
+            counters: Some(Counters::register(registry)?),
+            connection: None,
+            dial_started: None,
+        })
+    }
+
+    /// Metrics reporting to the same counters as the provided ones, if any, and additionally
+    /// to the statistics of a single connection.
+    pub fn for_connection(metrics: &Option<Metrics>, stats: ConnectionStats) -> Self {
+        Metrics {
+            counters: metrics
+                .as_ref()
+                .and_then(|metrics| metrics.counters.clone()),
+            connection: Some(stats),
+            dial_started: None,
+        }
+    }
+
+    /// The same metrics, additionally remembering when we started dialing the connection, so that
+    /// the time it takes to set it up can be reported.
+    pub fn dialing_since(self, started: Instant) -> Self {
+        Metrics {
+            dial_started: Some(started),
+            ..self
+        }
+    }
+
+    /// Report a data message of the given encoded size being sent.
+    pub fn report_sent(&self, bytes: usize) {
+        if let Some(counters) = &self.counters {
+            counters.messages_sent.inc();
+            counters.bytes_sent.inc_by(bytes as u64);
+        }
+        if let Some(connection) = &self.connection {
+            connection
+                .inner
+                .bytes_sent
+                .fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Report a data message of the given encoded size being received.
+    pub fn report_received(&self, bytes: usize) {
+        if let Some(counters) = &self.counters {
+            counters.messages_received.inc();
+            counters.bytes_received.inc_by(bytes as u64);
+        }
+        if let Some(connection) = &self.connection {
+            connection
+                .inner
+                .bytes_received
+                .fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Report a heartbeat being received, remembering when it happened.
+    pub fn report_heartbeat(&self) {
+        if let Some(counters) = &self.counters {
+            counters.heartbeats.inc();
+        }
+        if let Some(connection) = &self.connection {
+            *connection.inner.last_heartbeat.lock() = Some(Instant::now());
+        }
+    }
+
+    /// Report a heartbeat being sent.
+    pub fn report_heartbeat_sent(&self) {
+        if let Some(counters) = &self.counters {
+            counters.heartbeats.inc();
+        }
+    }
+
+    /// Report the round trip time measured by a probe.
+    pub fn report_round_trip(&self, round_trip: Duration) {
+        if let Some(counters) = &self.counters {
+            counters.round_trips.observe(round_trip.as_secs_f64());
+        }
+        if let Some(connection) = &self.connection {
+            *connection.inner.last_round_trip.lock() = Some(round_trip);
+        }
+    }
+
+    /// Report the handshake being completed, observing how long it took since we started dialing,
+    /// if we know that.
+    pub fn report_established(&self) {
+        if let (Some(counters), Some(started)) = (&self.counters, self.dial_started) {
+            counters
+                .connection_setups
+                .observe(started.elapsed().as_secs_f64());
+        }
+    }
+
+    /// Report the connection being closed for the given reason.
+    pub fn report_close(&self, reason: &CloseReason) {
+        if let Some(counters) = &self.counters {
+            counters
+                .closed_connections
+                .with_label_values(&[reason.label()])
+                .inc();
+        }
+    }
+
+    /// Report data being dropped, as its session checksum was missing or did not match.
+    pub fn report_misrouted(&self) {
+        if let Some(counters) = &self.counters {
+            counters.misrouted_data.inc();
+        }
+    }
+
+    /// Report reading from the network being paused, as the user was not taking the data.
+    pub fn report_stalled_by_consumer(&self) {
+        if let Some(counters) = &self.counters {
+            counters.stalled_by_consumer.inc();
+        }
+    }
+
+    /// Report the protocol negotiated for the connection.
+    pub fn report_protocol(&self, protocol: Protocol) {
+        if let Some(connection) = &self.connection {
+            *connection.inner.protocol.lock() = Some(protocol);
+        }
+    }
+
+    /// Report the capabilities both sides of the connection have.
+    pub fn report_capabilities(&self, capabilities: Capabilities) {
+        if let Some(connection) = &self.connection {
+            *connection.inner.capabilities.lock() = Some(capabilities);
+        }
+    }
+
+    #[cfg(test)]
+    fn counter(&self, counter: impl Fn(&Counters) -> &Counter<U64>) -> u64 {
+        self.counters
+            .as_ref()
+            .map_or(0, |counters| counter(counters).get())
+    }
+
+    #[cfg(test)]
+    pub fn messages_sent(&self) -> u64 {
+        self.counter(|counters| &counters.messages_sent)
+    }
+
+    #[cfg(test)]
+    pub fn messages_received(&self) -> u64 {
+        self.counter(|counters| &counters.messages_received)
+    }
+
+    #[cfg(test)]
+    pub fn bytes_sent(&self) -> u64 {
+        self.counter(|counters| &counters.bytes_sent)
+    }
+
+    #[cfg(test)]
+    pub fn bytes_received(&self) -> u64 {
+        self.counter(|counters| &counters.bytes_received)
+    }
+
+    #[cfg(test)]
+    pub fn heartbeats(&self) -> u64 {
+        self.counter(|counters| &counters.heartbeats)
+    }
+
+    #[cfg(test)]
+    pub fn stalled_by_consumer(&self) -> u64 {
+        self.counter(|counters| &counters.stalled_by_consumer)
+    }
+
+    /// How many connection setups were observed, and how many seconds they took in total.
+    #[cfg(test)]
+    pub fn connection_setups(&self) -> (u64, f64) {
+        self.counters.as_ref().map_or((0, 0.0), |counters| {
+            (
+                counters.connection_setups.get_sample_count(),
+                counters.connection_setups.get_sample_sum(),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConnectionStats, Metrics};
+    use crate::validator_network::protocols::Protocol;
+
+    #[test]
+    fn connection_stats_track_traffic() {
+        let stats = ConnectionStats::new();
+        let metrics = Metrics::for_connection(&None, stats.clone());
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.protocol, None);
+        assert_eq!(snapshot.last_heartbeat, None);
+        assert_eq!(snapshot.bytes_sent, 0);
+        metrics.report_protocol(Protocol::V1);
+        metrics.report_sent(43);
+        metrics.report_sent(1);
+        metrics.report_received(7);
+        metrics.report_heartbeat_sent();
+        assert_eq!(stats.snapshot().last_heartbeat, None);
+        metrics.report_heartbeat();
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.protocol, Some(Protocol::V1));
+        assert!(snapshot.last_heartbeat.is_some());
+        assert_eq!(snapshot.bytes_sent, 44);
+        assert_eq!(snapshot.bytes_received, 7);
+    }
+}
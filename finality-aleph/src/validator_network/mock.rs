@@ -1,16 +1,31 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 #[cfg(test)]
 use std::{
-    io::Result as IoResult,
+    collections::VecDeque,
+    future::Future,
+    io::{Error as IoError, ErrorKind, Result as IoResult},
     pin::Pin,
     task::{Context, Poll},
+    time::Instant,
 };
 
 use aleph_primitives::{AuthorityId, KEY_TYPE};
+use futures::{
+    channel::{mpsc, oneshot},
+    ready, StreamExt,
+};
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use sp_keystore::{testing::KeyStore, CryptoStore};
-use tokio::io::{duplex, AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::{
+    io::{duplex, AsyncRead, AsyncWrite, DuplexStream, ReadBuf},
+    time::{sleep, Duration, Sleep},
+};
 
-use crate::{crypto::AuthorityPen, validator_network::Splittable};
+use crate::{
+    crypto::AuthorityPen,
+    validator_network::{clock::Clock, Dialer, Listener, Splittable},
+};
 
 /// Create a single authority id and pen of the same type, not related to each other.
 pub async fn keys() -> (AuthorityId, AuthorityPen) {
@@ -82,3 +97,340 @@ impl Splittable for MockSplittable {
         (self.outgoing_data, self.incoming_data)
     }
 }
+
+/// How a single direction of a lossy mock connection behaves.
+#[derive(Clone, Copy, Debug)]
+pub struct LinkConfig {
+    /// How long every write waits before reaching the other side.
+    pub latency: Duration,
+    /// The probability of a write being silently dropped, between 0 and 1.
+    pub drop_probability: f64,
+}
+
+impl LinkConfig {
+    /// A link delivering everything immediately.
+    pub fn clean() -> Self {
+        LinkConfig {
+            latency: Duration::ZERO,
+            drop_probability: 0.0,
+        }
+    }
+}
+
+/// Writes to an in-memory stream, delaying and dropping the writes according to the link config.
+/// Dropped writes are reported as successful, like data lost somewhere on the way.
+pub struct LossyWriter {
+    inner: DuplexStream,
+    config: LinkConfig,
+    rng: StdRng,
+    delay: Option<Pin<Box<Sleep>>>,
+    /// Whether the write that already waited out its latency gets delivered.
+    deliver: Option<bool>,
+}
+
+impl LossyWriter {
+    fn new(inner: DuplexStream, config: LinkConfig, seed: u64) -> Self {
+        LossyWriter {
+            inner,
+            config,
+            rng: StdRng::seed_from_u64(seed),
+            delay: None,
+            deliver: None,
+        }
+    }
+}
+
+impl AsyncWrite for LossyWriter {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        let this = self.get_mut();
+        let deliver = match this.deliver {
+            Some(deliver) => deliver,
+            None => {
+                let latency = this.config.latency;
+                let delay = this.delay.get_or_insert_with(|| Box::pin(sleep(latency)));
+                ready!(delay.as_mut().poll(cx));
+                this.delay = None;
+                let deliver = !this.rng.gen_bool(this.config.drop_probability);
+                this.deliver = Some(deliver);
+                deliver
+            }
+        };
+        let result = match deliver {
+            true => ready!(Pin::new(&mut this.inner).poll_write(cx, buf)),
+            false => Ok(buf.len()),
+        };
+        this.deliver = None;
+        Poll::Ready(result)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// A mock that can be split into two streams, with the data between them delayed and lost
+/// according to the configs of the two directions. The losses are random, but determined by the
+/// seed.
+pub struct LossyMockSplittable {
+    incoming_data: DuplexStream,
+    outgoing_data: LossyWriter,
+}
+
+impl LossyMockSplittable {
+    /// Create a pair of lossy mock splittables connected to each other, the first link config
+    /// applying to the data sent by the first one.
+    pub fn new(
+        max_buf_size: usize,
+        first_to_second: LinkConfig,
+        second_to_first: LinkConfig,
+        seed: u64,
+    ) -> (Self, Self) {
+        let (in_a, out_b) = duplex(max_buf_size);
+        let (in_b, out_a) = duplex(max_buf_size);
+        (
+            LossyMockSplittable {
+                incoming_data: in_a,
+                outgoing_data: LossyWriter::new(out_a, first_to_second, seed),
+            },
+            LossyMockSplittable {
+                incoming_data: in_b,
+                outgoing_data: LossyWriter::new(out_b, second_to_first, seed.wrapping_add(1)),
+            },
+        )
+    }
+}
+
+impl AsyncRead for LossyMockSplittable {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.get_mut().incoming_data).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for LossyMockSplittable {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        Pin::new(&mut self.get_mut().outgoing_data).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.get_mut().outgoing_data).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.get_mut().outgoing_data).poll_shutdown(cx)
+    }
+}
+
+impl Splittable for LossyMockSplittable {
+    type Sender = LossyWriter;
+    type Receiver = DuplexStream;
+
+    fn split(self) -> (Self::Sender, Self::Receiver) {
+        (self.outgoing_data, self.incoming_data)
+    }
+}
+
+/// A stream failing its first reads with errors of the given kinds, and then reading from the
+/// wrapped stream.
+#[cfg(test)]
+pub struct FailingReads<S> {
+    inner: S,
+    failures: VecDeque<ErrorKind>,
+}
+
+#[cfg(test)]
+impl<S> FailingReads<S> {
+    pub fn new(inner: S, failures: Vec<ErrorKind>) -> Self {
+        FailingReads {
+            inner,
+            failures: failures.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl<S: AsyncRead + Unpin> AsyncRead for FailingReads<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        let this = self.get_mut();
+        match this.failures.pop_front() {
+            Some(kind) => Poll::Ready(Err(IoError::from(kind))),
+            None => Pin::new(&mut this.inner).poll_read(cx, buf),
+        }
+    }
+}
+
+/// A stream counting how many times it was flushed, writing to the wrapped stream.
+#[cfg(test)]
+pub struct CountingFlushes<S> {
+    inner: S,
+    flushes: Arc<Mutex<usize>>,
+}
+
+#[cfg(test)]
+impl<S> CountingFlushes<S> {
+    pub fn new(inner: S) -> Self {
+        CountingFlushes {
+            inner,
+            flushes: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    /// The number of flushes so far, shared with the stream so that it can be checked after the
+    /// stream is gone.
+    pub fn flushes(&self) -> Arc<Mutex<usize>> {
+        self.flushes.clone()
+    }
+}
+
+#[cfg(test)]
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingFlushes<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        let this = self.get_mut();
+        let result = ready!(Pin::new(&mut this.inner).poll_flush(cx));
+        *this.flushes.lock() += 1;
+        Poll::Ready(result)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// A dialer connecting to mock listeners through in-memory streams.
+#[derive(Clone, Default)]
+pub struct MockDialer {
+    listeners: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<MockSplittable>>>>,
+}
+
+impl MockDialer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a listener that this dialer, and all its clones, can connect to using the address.
+    pub fn listener(&self, address: &str) -> MockListener {
+        let (connections_for_listener, connections) = mpsc::unbounded();
+        self.listeners
+            .lock()
+            .insert(address.to_string(), connections_for_listener);
+        MockListener { connections }
+    }
+}
+
+#[async_trait::async_trait]
+impl Dialer<String> for MockDialer {
+    type Connection = MockSplittable;
+    type Error = String;
+
+    async fn connect(&mut self, addresses: Vec<String>) -> Result<Self::Connection, Self::Error> {
+        let listeners = self.listeners.lock();
+        for address in &addresses {
+            if let Some(connections_for_listener) = listeners.get(address) {
+                let (ours, theirs) = MockSplittable::new(4096);
+                if connections_for_listener.unbounded_send(theirs).is_ok() {
+                    return Ok(ours);
+                }
+            }
+        }
+        Err(format!("no listener at any of {:?}", addresses))
+    }
+}
+
+/// A listener accepting connections from a mock dialer.
+pub struct MockListener {
+    connections: mpsc::UnboundedReceiver<MockSplittable>,
+}
+
+#[async_trait::async_trait]
+impl Listener for MockListener {
+    type Connection = MockSplittable;
+    type Error = String;
+
+    async fn accept(&mut self) -> Result<Self::Connection, Self::Error> {
+        self.connections
+            .next()
+            .await
+            .ok_or_else(|| String::from("all dialers gone"))
+    }
+}
+
+struct MockClockState {
+    now: Instant,
+    sleepers: Vec<(Instant, oneshot::Sender<()>)>,
+}
+
+/// A clock that only moves forward when explicitly advanced.
+#[derive(Clone)]
+pub struct MockClock {
+    state: Arc<Mutex<MockClockState>>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock {
+            state: Arc::new(Mutex::new(MockClockState {
+                now: Instant::now(),
+                sleepers: Vec::new(),
+            })),
+        }
+    }
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Moves the time forward, waking all the sleeps that finish by then.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock();
+        state.now += duration;
+        let now = state.now;
+        let (woken, sleeping): (Vec<_>, Vec<_>) = state
+            .sleepers
+            .drain(..)
+            .partition(|(deadline, _)| *deadline <= now);
+        state.sleepers = sleeping;
+        for (_, sleeper) in woken {
+            // The sleep might have been dropped already, this is fine.
+            let _ = sleeper.send(());
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.state.lock().now
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        let woken = {
+            let mut state = self.state.lock();
+            let deadline = state.now + duration;
+            if deadline <= state.now {
+                return;
+            }
+            let (sender, woken) = oneshot::channel();
+            state.sleepers.push((deadline, sender));
+            woken
+        };
+        // The clock holds the sender for as long as it exists.
+        let _ = woken.await;
+    }
+}
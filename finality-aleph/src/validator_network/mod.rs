@@ -5,19 +5,42 @@ use codec::Codec;
 use sp_core::crypto::KeyTypeId;
 use tokio::io::{AsyncRead, AsyncWrite};
 
+mod blacklist;
+mod clock;
 mod handshake;
 mod heartbeat;
 mod incoming;
 mod io;
+mod log_limit;
 mod manager;
+mod metrics;
 #[cfg(test)]
 mod mock;
 mod outgoing;
 mod protocol_negotiation;
 mod protocols;
+mod quality;
+mod rate_limit;
+mod reconnect;
+mod send_channel;
 mod service;
 
-pub use service::Service;
+pub use blacklist::BlacklistConfig;
+pub use handshake::{Capabilities, ChainIdentity, HandshakeConfig};
+pub use heartbeat::HeartbeatConfig;
+pub use io::{Codec, ReceiveConfig};
+pub use metrics::Metrics;
+pub use quality::ConnectionQuality;
+pub use rate_limit::RateLimit;
+pub use reconnect::ReconnectPolicy;
+pub use send_channel::{
+    OverflowPolicy, SendChannelConfig, SendChannelConfigBuilder, SendChannelConfigError,
+};
+pub use service::{ConnectedPeers, ConnectionState, PeerStatus, Service, StatusHandle};
+#[cfg(test)]
+pub mod testing {
+    pub use super::handshake::{Challenge, Response};
+}
 
 pub const KEY_TYPE: KeyTypeId = KeyTypeId(*b"a0vn");
 
@@ -41,12 +64,22 @@ pub trait Network<A: Data, D: Data>: Send + 'static {
     /// Remove the peer from the set of connected peers and close the connection.
     fn remove_connection(&mut self, peer: AuthorityId);
 
+    /// Hold back the data sent to the peer, without closing the connection.
+    fn pause(&mut self, peer: AuthorityId);
+
+    /// Send out the data held back for the peer, in order, and stop holding it back.
+    fn resume(&mut self, peer: AuthorityId);
+
+    /// The capabilities agreed on with the peer over the current outgoing connection, if any.
+    fn capabilities(&self, peer: &AuthorityId) -> Option<Capabilities>;
+
     /// Send a message to a single peer.
     /// This function should be implemented in a non-blocking manner.
     fn send(&self, data: D, recipient: AuthorityId);
 
-    /// Receive a message from the network.
-    async fn next(&mut self) -> Option<D>;
+    /// Receive a message from the network, together with the peer that sent it and the
+    /// capabilities agreed on over the connection it arrived on.
+    async fn next(&mut self) -> Option<(D, AuthorityId, Capabilities)>;
 }
 
 /// A stream that can be split into a sending and receiving part.
@@ -1,23 +1,35 @@
-use std::fmt::{Display, Error as FmtError, Formatter};
+use std::{
+    fmt::{Display, Error as FmtError, Formatter},
+    time::Instant,
+};
 
 use aleph_primitives::AuthorityId;
-use futures::channel::mpsc;
-use log::{debug, info};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{Fuse, FusedFuture},
+    FutureExt,
+};
+use log::{debug, Level};
 use tokio::time::{sleep, Duration};
 
 use crate::{
     crypto::AuthorityPen,
     validator_network::{
-        protocol_negotiation::{protocol, ProtocolNegotiationError},
-        protocols::ProtocolError,
-        Data, Dialer,
+        handshake::HandshakeConfig,
+        heartbeat::HeartbeatConfig,
+        io::Codec,
+        log_limit::LogLimiter,
+        metrics::Metrics,
+        protocol_negotiation::{fallback_protocol, protocol, ProtocolNegotiationError},
+        protocols::{CloseReason, OutgoingResult, Protocol, ProtocolError},
+        send_channel::SendChannelConfig,
+        Data, Dialer, Splittable,
     },
 };
 
 enum OutgoingError<A: Data, ND: Dialer<A>> {
     Dial(ND::Error),
     ProtocolNegotiation(ProtocolNegotiationError),
-    Protocol(ProtocolError),
 }
 
 impl<A: Data, ND: Dialer<A>> Display for OutgoingError<A, ND> {
@@ -26,7 +38,6 @@ impl<A: Data, ND: Dialer<A>> Display for OutgoingError<A, ND> {
         match self {
             Dial(e) => write!(f, "dial error: {}", e),
             ProtocolNegotiation(e) => write!(f, "protocol negotiation error: {}", e),
-            Protocol(e) => write!(f, "protocol error: {}", e),
         }
     }
 }
@@ -37,57 +48,415 @@ impl<A: Data, ND: Dialer<A>> From<ProtocolNegotiationError> for OutgoingError<A,
     }
 }
 
-impl<A: Data, ND: Dialer<A>> From<ProtocolError> for OutgoingError<A, ND> {
-    fn from(e: ProtocolError) -> Self {
-        OutgoingError::Protocol(e)
+/// Runs the protocol on the stream, passing the exit on to it. The exit stays usable for a later
+/// attempt if the protocol fails before it fires.
+#[allow(clippy::too_many_arguments)]
+async fn run_protocol<D: Data, S: Splittable>(
+    protocol: Protocol,
+    stream: S,
+    authority_pen: AuthorityPen,
+    peer_id: AuthorityId,
+    result_for_parent: mpsc::UnboundedSender<OutgoingResult<D>>,
+    exit: &mut Fuse<oneshot::Receiver<()>>,
+    heartbeat_config: HeartbeatConfig,
+    handshake_config: HandshakeConfig,
+    send_channel_config: SendChannelConfig,
+    codec: Codec,
+    metrics: Option<Metrics>,
+) -> Result<(), ProtocolError> {
+    if let Some(metrics) = &metrics {
+        metrics.report_protocol(protocol);
+    }
+    let (exit_for_protocol, protocol_exit) = oneshot::channel();
+    let running = protocol.manage_outgoing(
+        stream,
+        authority_pen,
+        peer_id,
+        result_for_parent,
+        protocol_exit,
+        heartbeat_config,
+        handshake_config,
+        send_channel_config,
+        codec,
+        metrics,
+    );
+    tokio::pin!(running);
+    tokio::select! {
+        result = &mut running => result,
+        _ = exit => {
+            let _ = exit_for_protocol.send(());
+            running.await
+        }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn manage_outgoing<D: Data, A: Data, ND: Dialer<A>>(
     authority_pen: AuthorityPen,
     peer_id: AuthorityId,
     mut dialer: ND,
     addresses: Vec<A>,
-    result_for_parent: mpsc::UnboundedSender<(AuthorityId, Option<mpsc::UnboundedSender<D>>)>,
-) -> Result<(), OutgoingError<A, ND>> {
-    debug!(target: "validator-network", "Trying to connect to {}.", peer_id);
+    result_for_parent: mpsc::UnboundedSender<OutgoingResult<D>>,
+    exit: oneshot::Receiver<()>,
+    heartbeat_config: HeartbeatConfig,
+    handshake_config: HandshakeConfig,
+    send_channel_config: SendChannelConfig,
+    codec: Codec,
+    metrics: Option<Metrics>,
+    log_limiter: &LogLimiter,
+) -> Result<CloseReason, OutgoingError<A, ND>> {
+    log_limiter.log(
+        Level::Debug,
+        &peer_id,
+        format!("Trying to connect to {}.", peer_id),
+    );
+    // A fallback counts from the first dial, as that is how long we actually waited.
+    let metrics = metrics.map(|metrics| metrics.dialing_since(Instant::now()));
     let stream = dialer
-        .connect(addresses)
+        .connect(addresses.clone())
         .await
         .map_err(OutgoingError::Dial)?;
     debug!(target: "validator-network", "Performing outgoing protocol negotiation.");
     let (stream, protocol) = protocol(stream).await?;
     debug!(target: "validator-network", "Negotiated protocol, running.");
-    Ok(protocol
-        .manage_outgoing(stream, authority_pen, peer_id, result_for_parent)
-        .await?)
+    let mut exit = exit.fuse();
+    let result = run_protocol(
+        protocol,
+        stream,
+        authority_pen.clone(),
+        peer_id.clone(),
+        result_for_parent.clone(),
+        &mut exit,
+        heartbeat_config,
+        handshake_config,
+        send_channel_config,
+        codec,
+        metrics.clone(),
+    )
+    .await;
+    // A peer might have a bug in a newer protocol, so we try the oldest one once, rather than
+    // not talking to the peer at all.
+    let result = match result {
+        Err(ProtocolError::HandshakeError(e))
+            if e.recoverable() && protocol != Protocol::V0 && !exit.is_terminated() =>
+        {
+            log_limiter.log(
+                Level::Info,
+                &peer_id,
+                format!(
+                    "Handshake with {} using protocol {:?} failed: {}, falling back to {:?}.",
+                    peer_id,
+                    protocol,
+                    e,
+                    Protocol::V0
+                ),
+            );
+            let stream = dialer
+                .connect(addresses)
+                .await
+                .map_err(OutgoingError::Dial)?;
+            let (stream, protocol) = fallback_protocol(stream).await?;
+            run_protocol(
+                protocol,
+                stream,
+                authority_pen,
+                peer_id,
+                result_for_parent,
+                &mut exit,
+                heartbeat_config,
+                handshake_config,
+                send_channel_config,
+                codec,
+                metrics.clone(),
+            )
+            .await
+        }
+        result => result,
+    };
+    let reason = CloseReason::from(result);
+    if let Some(metrics) = &metrics {
+        metrics.report_close(&reason);
+    }
+    Ok(reason)
 }
 
-const RETRY_DELAY: Duration = Duration::from_secs(10);
-
-/// Establish an outgoing connection to the provided peer using the dialer and then manage it.
-/// While this works it will send any data from the user to the peer. Any failures will be reported
-/// to the parent, so that connections can be reestablished if necessary.
+/// Establish an outgoing connection to the provided peer using the dialer after waiting for the
+/// given delay, and then manage it.
+/// While this works it will send any data from the user to the peer. When the exit channel fires,
+/// the data already queued is sent and the connection closed. Any failures will be reported
+/// to the parent, so that connections can be reestablished if necessary. Data queued when the
+/// connection fails is lost, a reestablished connection starts with an empty queue.
+/// Repeated failures to reach the same peer are logged through the limiter.
+/// Returns whether the connection failed because the peer misbehaved, in which case the closing
+/// is not reported, as the parent learns about it from the misbehaviour report instead.
+#[allow(clippy::too_many_arguments)]
 pub async fn outgoing<D: Data, A: Data, ND: Dialer<A>>(
     authority_pen: AuthorityPen,
     peer_id: AuthorityId,
     dialer: ND,
     addresses: Vec<A>,
-    result_for_parent: mpsc::UnboundedSender<(AuthorityId, Option<mpsc::UnboundedSender<D>>)>,
-) {
-    if let Err(e) = manage_outgoing(
+    result_for_parent: mpsc::UnboundedSender<OutgoingResult<D>>,
+    delay: Duration,
+    mut exit: oneshot::Receiver<()>,
+    heartbeat_config: HeartbeatConfig,
+    handshake_config: HandshakeConfig,
+    send_channel_config: SendChannelConfig,
+    codec: Codec,
+    metrics: Option<Metrics>,
+    log_limiter: LogLimiter,
+) -> bool {
+    tokio::select! {
+        _ = sleep(delay) => {},
+        _ = &mut exit => {
+            debug!(target: "validator-network", "Stopped waiting to connect to {}.", peer_id);
+            return false;
+        },
+    }
+    match manage_outgoing(
         authority_pen,
         peer_id.clone(),
         dialer,
         addresses,
         result_for_parent.clone(),
+        exit,
+        heartbeat_config,
+        handshake_config,
+        send_channel_config,
+        codec,
+        metrics,
+        &log_limiter,
     )
     .await
     {
-        info!(target: "validator-network", "Outgoing connection to {} failed: {}, will retry after {}s.", peer_id, e, RETRY_DELAY.as_secs());
-        sleep(RETRY_DELAY).await;
-        if result_for_parent.unbounded_send((peer_id, None)).is_err() {
-            debug!(target: "validator-network", "Could not send the closing message, we've probably been terminated by the parent service.");
+        Ok(CloseReason::ParentClosed) => {
+            debug!(target: "validator-network", "Outgoing connection to {} closed by the service.", peer_id);
+            false
         }
+        Ok(reason) => {
+            log_limiter.log(
+                Level::Info,
+                &peer_id,
+                format!("Outgoing connection to {} closed: {}.", peer_id, reason),
+            );
+            let misbehaved = reason.peer_misbehaved();
+            if !misbehaved {
+                report_closed(peer_id, result_for_parent);
+            }
+            misbehaved
+        }
+        Err(e) => {
+            log_limiter.log(
+                Level::Info,
+                &peer_id,
+                format!("Outgoing connection to {} failed: {}.", peer_id, e),
+            );
+            report_closed(peer_id, result_for_parent);
+            false
+        }
+    }
+}
+
+/// Lets the parent know the connection is gone, so that it can be reestablished.
+fn report_closed<D: Data>(
+    peer_id: AuthorityId,
+    result_for_parent: mpsc::UnboundedSender<OutgoingResult<D>>,
+) {
+    if result_for_parent.unbounded_send((peer_id, None)).is_err() {
+        debug!(target: "validator-network", "Could not send the closing message, we've probably been terminated by the parent service.");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{
+        channel::{mpsc, oneshot},
+        StreamExt,
+    };
+    use prometheus_endpoint::Registry;
+    use tokio::time::{timeout, Duration};
+
+    use super::outgoing;
+    use crate::validator_network::{
+        handshake::HandshakeConfig,
+        heartbeat::HeartbeatConfig,
+        incoming::incoming,
+        io::{send_data, Codec, ReceiveConfig},
+        log_limit::LogLimiter,
+        metrics::Metrics,
+        mock::{keys, MockDialer},
+        protocol_negotiation::protocol,
+        protocols::Protocol,
+        send_channel::SendChannelConfig,
+        Listener,
+    };
+
+    type Data = Vec<i32>;
+
+    #[tokio::test]
+    async fn falls_back_to_oldest_protocol_after_failed_handshake() {
+        let dialer = MockDialer::new();
+        let mut listener = dialer.listener("peer");
+        let (_, pen) = keys().await;
+        let (peer_id, peer_pen) = keys().await;
+        let peer = tokio::spawn(async move {
+            // the first connection gets garbage right after negotiating the newest protocol
+            let stream = listener.accept().await.expect("should accept");
+            let (stream, negotiated) = protocol(stream).await.expect("should negotiate");
+            assert_eq!(negotiated, Protocol::V3);
+            let _garbled = send_data(stream, vec![0u8; 3]).await.expect("should send");
+            let stream = listener.accept().await.expect("should accept");
+            let (result_for_parent, results) = mpsc::unbounded();
+            let (data_for_user, _data_from_network) =
+                mpsc::channel::<Data>(ReceiveConfig::default().user_queue_capacity);
+            tokio::spawn(incoming(
+                peer_pen,
+                stream,
+                result_for_parent,
+                data_for_user,
+                HeartbeatConfig::default(),
+                HandshakeConfig::default(),
+                ReceiveConfig::default(),
+                None,
+            ));
+            results
+        });
+
+        let (result_for_parent, mut results) = mpsc::unbounded();
+        let (exit_for_outgoing, exit) = oneshot::channel();
+        let outgoing = tokio::spawn(outgoing::<Data, _, _>(
+            pen,
+            peer_id.clone(),
+            dialer,
+            vec![String::from("peer")],
+            result_for_parent,
+            Duration::ZERO,
+            exit,
+            HeartbeatConfig::default(),
+            HandshakeConfig::default(),
+            SendChannelConfig::default(),
+            Codec::default(),
+            None,
+            LogLimiter::default(),
+        ));
+        let (connected_peer, connection) = timeout(Duration::from_secs(5), results.next())
+            .await
+            .expect("should connect in time")
+            .expect("should report the connection");
+        assert_eq!(connected_peer, peer_id);
+        let (protocol, _data_for_peer) = connection.expect("the fallback should succeed");
+        assert_eq!(protocol, Protocol::V0);
+        let mut peer_results = peer.await.expect("peer should not panic");
+        let (_, peer_protocol, _) = peer_results
+            .next()
+            .await
+            .expect("peer should accept the connection");
+        assert_eq!(peer_protocol, Protocol::V0);
+
+        exit_for_outgoing.send(()).expect("outgoing should listen");
+        assert!(!outgoing.await.expect("outgoing should not panic"));
+    }
+
+    #[tokio::test]
+    async fn does_not_fall_back_after_handshake_timeout() {
+        let dialer = MockDialer::new();
+        let mut listener = dialer.listener("peer");
+        let (_, pen) = keys().await;
+        let (peer_id, _) = keys().await;
+        let handshake_config = HandshakeConfig {
+            timeout: Duration::from_millis(100),
+            ..HandshakeConfig::default()
+        };
+
+        let (result_for_parent, mut results) = mpsc::unbounded();
+        let (_exit_for_outgoing, exit) = oneshot::channel();
+        let outgoing = tokio::spawn(outgoing::<Data, _, _>(
+            pen,
+            peer_id.clone(),
+            dialer,
+            vec![String::from("peer")],
+            result_for_parent,
+            Duration::ZERO,
+            exit,
+            HeartbeatConfig::default(),
+            handshake_config,
+            SendChannelConfig::default(),
+            Codec::default(),
+            None,
+            LogLimiter::default(),
+        ));
+        // the peer negotiates the newest protocol, but never answers the handshake
+        let stream = listener.accept().await.expect("should accept");
+        let (_stalled, negotiated) = protocol(stream).await.expect("should negotiate");
+        assert_eq!(negotiated, Protocol::V3);
+
+        let (closed_peer, connection) = timeout(Duration::from_secs(5), results.next())
+            .await
+            .expect("should give up in time")
+            .expect("should report the closing");
+        assert_eq!(closed_peer, peer_id);
+        assert!(connection.is_none());
+        assert!(!outgoing.await.expect("outgoing should not panic"));
+        // a stalled peer would stall with the oldest protocol as well, so it is not dialed again
+        assert!(!matches!(
+            timeout(Duration::from_millis(100), listener.accept()).await,
+            Ok(Ok(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reports_connection_setup_time() {
+        let dialer = MockDialer::new();
+        let mut listener = dialer.listener("peer");
+        let (_, pen) = keys().await;
+        let (peer_id, peer_pen) = keys().await;
+        let metrics = Metrics::register(&Registry::new()).expect("should register");
+        let (result_for_peer, _peer_results) = mpsc::unbounded();
+        let (data_for_user, _data_from_network) =
+            mpsc::channel::<Data>(ReceiveConfig::default().user_queue_capacity);
+        let peer = tokio::spawn(async move {
+            let stream = listener.accept().await.expect("should accept");
+            incoming(
+                peer_pen,
+                stream,
+                result_for_peer,
+                data_for_user,
+                HeartbeatConfig::default(),
+                HandshakeConfig::default(),
+                ReceiveConfig::default(),
+                None,
+            )
+            .await
+        });
+
+        let (result_for_parent, mut results) = mpsc::unbounded();
+        let (exit_for_outgoing, exit) = oneshot::channel();
+        let outgoing = tokio::spawn(outgoing::<Data, _, _>(
+            pen,
+            peer_id,
+            dialer,
+            vec![String::from("peer")],
+            result_for_parent,
+            Duration::ZERO,
+            exit,
+            HeartbeatConfig::default(),
+            HandshakeConfig::default(),
+            SendChannelConfig::default(),
+            Codec::default(),
+            Some(metrics.clone()),
+            LogLimiter::default(),
+        ));
+        let (_, connection) = timeout(Duration::from_secs(5), results.next())
+            .await
+            .expect("should connect in time")
+            .expect("should report the connection");
+        assert!(connection.is_some());
+        let (setups, seconds) = metrics.connection_setups();
+        assert_eq!(setups, 1);
+        assert!(seconds > 0.0);
+        assert!(seconds < 5.0);
+
+        exit_for_outgoing.send(()).expect("outgoing should listen");
+        assert!(!outgoing.await.expect("outgoing should not panic"));
+        peer.abort();
     }
 }
@@ -13,7 +13,9 @@ use crate::validator_network::protocols::Protocol;
 pub type ProtocolVersion = u32;
 
 const MIN_SUPPORTED_PROTOCOL: ProtocolVersion = 0;
-const MAX_SUPPORTED_PROTOCOL: ProtocolVersion = 0;
+const MAX_SUPPORTED_PROTOCOL: ProtocolVersion = 3;
+/// The protocol we fall back to when a handshake using a newer one fails.
+const FALLBACK_PROTOCOL: ProtocolVersion = 0;
 const PROTOCOL_NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// A range of supported protocols, will fail to decode if the range is empty.
@@ -30,6 +32,10 @@ const fn supported_protocol_range() -> ProtocolsRange {
     ProtocolsRange(MIN_SUPPORTED_PROTOCOL, MAX_SUPPORTED_PROTOCOL)
 }
 
+const fn fallback_protocol_range() -> ProtocolsRange {
+    ProtocolsRange(MIN_SUPPORTED_PROTOCOL, FALLBACK_PROTOCOL)
+}
+
 /// What went wrong when negotiating a protocol.
 #[derive(Debug, PartialEq, Eq)]
 pub enum ProtocolNegotiationError {
@@ -105,6 +111,9 @@ fn maximum_of_intersection(
 ) -> Result<Protocol, ProtocolNegotiationError> {
     intersection(range1, range2).map(|intersection| match intersection.1 {
         0 => Ok(Protocol::V0),
+        1 => Ok(Protocol::V1),
+        2 => Ok(Protocol::V2),
+        3 => Ok(Protocol::V3),
         unknown_version => Err(ProtocolNegotiationError::BadChoice(unknown_version)),
     })?
 }
@@ -141,17 +150,35 @@ pub async fn protocol<S: AsyncReadExt + AsyncWriteExt + Unpin>(
     .map_err(|_| ProtocolNegotiationError::TimedOut)?
 }
 
+/// Negotiate the oldest protocol version, for when a handshake using a newer one failed.
+pub async fn fallback_protocol<S: AsyncReadExt + AsyncWriteExt + Unpin>(
+    stream: S,
+) -> Result<(S, Protocol), ProtocolNegotiationError> {
+    timeout(
+        PROTOCOL_NEGOTIATION_TIMEOUT,
+        negotiate_protocol_version(stream, fallback_protocol_range()),
+    )
+    .await
+    .map_err(|_| ProtocolNegotiationError::TimedOut)?
+}
+
 #[cfg(test)]
 mod tests {
     use futures::{pin_mut, FutureExt};
     use tokio::io::duplex;
 
-    use super::{negotiate_protocol_version, supported_protocol_range, ProtocolNegotiationError};
+    use super::{
+        negotiate_protocol_version, supported_protocol_range, ProtocolNegotiationError,
+        ProtocolsRange,
+    };
     use crate::validator_network::protocols::Protocol;
 
-    fn correct_negotiation<S>(result: Result<(S, Protocol), ProtocolNegotiationError>) {
+    fn correct_negotiation<S>(
+        result: Result<(S, Protocol), ProtocolNegotiationError>,
+        expected_protocol: Protocol,
+    ) {
         match result {
-            Ok((_stream, protocol)) => assert_eq!(Protocol::V0, protocol),
+            Ok((_stream, protocol)) => assert_eq!(expected_protocol, protocol),
             Err(e) => panic!("Unexpected error: {:?}", e),
         }
     }
@@ -177,8 +204,8 @@ mod tests {
         pin_mut!(negotiation2);
         for _ in 0..2 {
             tokio::select! {
-                result = &mut negotiation1 => correct_negotiation(result),
-                result = &mut negotiation2 => correct_negotiation(result),
+                result = &mut negotiation1 => correct_negotiation(result, Protocol::V3),
+                result = &mut negotiation2 => correct_negotiation(result, Protocol::V3),
             }
         }
     }
@@ -194,8 +221,24 @@ mod tests {
         pin_mut!(negotiation2);
         for _ in 0..2 {
             tokio::select! {
-                result = &mut negotiation1 => correct_negotiation(result),
-                result = &mut negotiation2 => correct_negotiation(result),
+                result = &mut negotiation1 => correct_negotiation(result, Protocol::V3),
+                result = &mut negotiation2 => correct_negotiation(result, Protocol::V3),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn negotiates_v0_with_legacy_peer() {
+        let (stream1, stream2) = duplex(4096);
+        let legacy_protocol_range = ProtocolsRange(0, 0);
+        let negotiation1 = negotiate_protocol_version(stream1, supported_protocol_range()).fuse();
+        pin_mut!(negotiation1);
+        let negotiation2 = negotiate_protocol_version(stream2, legacy_protocol_range).fuse();
+        pin_mut!(negotiation2);
+        for _ in 0..2 {
+            tokio::select! {
+                result = &mut negotiation1 => correct_negotiation(result, Protocol::V0),
+                result = &mut negotiation2 => correct_negotiation(result, Protocol::V0),
             }
         }
     }
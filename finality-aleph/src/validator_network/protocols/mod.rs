@@ -0,0 +1,671 @@
+use std::fmt::{Display, Error as FmtError, Formatter};
+
+use aleph_primitives::AuthorityId;
+use futures::{
+    channel::{mpsc, oneshot},
+    SinkExt,
+};
+use tracing::{field, info_span, Instrument, Span};
+
+use crate::{
+    crypto::AuthorityPen,
+    validator_network::{
+        handshake::{Capabilities, HandshakeConfig, HandshakeError},
+        heartbeat::HeartbeatConfig,
+        io::{Codec, ReceiveConfig, ReceiveError, SendError},
+        metrics::Metrics,
+        send_channel::{DataSender, SendChannelConfig},
+        Data, Splittable,
+    },
+};
+
+mod v0;
+mod v1;
+
+/// Name of the span every protocol worker runs in.
+const WORKER_SPAN: &str = "validator_network_worker";
+
+/// Fills in the peer of the worker span once the handshake tells us who it is.
+fn record_peer_id(peer_id: &AuthorityId) {
+    Span::current().record("peer_id", &field::display(peer_id));
+}
+
+/// Passes received data to the user without waiting. If too much data is already waiting for
+/// them, gives the data back, to be passed once they take some.
+fn offer_to_user<D: Data>(
+    data_for_user: &mut mpsc::Sender<D>,
+    data: D,
+    metrics: &Option<Metrics>,
+) -> Result<Option<D>, ProtocolError> {
+    let data = match data_for_user.try_send(data) {
+        Ok(()) => return Ok(None),
+        Err(e) if e.is_full() => e.into_inner(),
+        Err(_) => return Err(ProtocolError::NoUserConnection),
+    };
+    if let Some(metrics) = metrics {
+        metrics.report_stalled_by_consumer();
+    }
+    Ok(Some(data))
+}
+
+/// Passes received data to the user. If too much data is already waiting for them, waits until
+/// they take some, so that nothing more is read from the network in the meantime.
+async fn pass_to_user<D: Data>(
+    data_for_user: &mut mpsc::Sender<D>,
+    data: D,
+    metrics: &Option<Metrics>,
+) -> Result<(), ProtocolError> {
+    match offer_to_user(data_for_user, data, metrics)? {
+        Some(data) => data_for_user
+            .send(data)
+            .await
+            .map_err(|_| ProtocolError::NoUserConnection),
+        None => Ok(()),
+    }
+}
+
+/// Defines the protocol for communication.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// The first version of the protocol, with heartbeats only flowing from the incoming side.
+    V0,
+    /// The second version of the protocol, with heartbeats interleaved with the data as well.
+    V1,
+    /// The third version of the protocol, with the capabilities of both sides exchanged after
+    /// the handshake.
+    V2,
+    /// The current version of the protocol, with the chain identities of both sides exchanged
+    /// during the handshake as well.
+    V3,
+}
+
+/// Reported to the service by an incoming connection once the handshake succeeds: the peer, the
+/// negotiated protocol, the capabilities agreed on over the connection and the exit channel of the
+/// connection.
+pub type IncomingResult = (AuthorityId, Protocol, Capabilities, oneshot::Sender<()>);
+
+/// Reported to the service by an outgoing connection: the peer and, if the handshake succeeded,
+/// the negotiated protocol together with the channel for sending data to the peer.
+pub type OutgoingResult<D> = (AuthorityId, Option<(Protocol, DataSender<D>)>);
+
+/// Protocol error.
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// Error during performing a handshake.
+    HandshakeError(HandshakeError),
+    /// Sending failed.
+    SendError(SendError),
+    /// Receiving failed.
+    ReceiveError(ReceiveError),
+    /// Heartbeat stopped.
+    CardiacArrest,
+    /// Channel to the parent service closed.
+    NoParentConnection,
+    /// Data channel closed.
+    NoUserConnection,
+    /// Too much data waiting to be sent to the peer.
+    SendBufferOverflow,
+    /// No data, as opposed to heartbeats, arrived for too long.
+    IdleTimeout,
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        use ProtocolError::*;
+        match self {
+            HandshakeError(e) => write!(f, "handshake error: {}", e),
+            SendError(e) => write!(f, "send error: {}", e),
+            ReceiveError(e) => write!(f, "receive error: {}", e),
+            CardiacArrest => write!(f, "heartbeat stopped"),
+            NoParentConnection => write!(f, "cannot send result to service"),
+            NoUserConnection => write!(f, "cannot send data to user"),
+            SendBufferOverflow => write!(f, "send buffer overflow"),
+            IdleTimeout => write!(f, "no data for too long"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use ProtocolError::*;
+        match self {
+            HandshakeError(e) => Some(e),
+            SendError(e) => Some(e),
+            ReceiveError(e) => Some(e),
+            CardiacArrest | NoParentConnection | NoUserConnection | SendBufferOverflow
+            | IdleTimeout => None,
+        }
+    }
+}
+
+impl ProtocolError {
+    /// Whether the peer broke the protocol, as opposed to the connection failing.
+    pub fn peer_misbehaved(&self) -> bool {
+        use ProtocolError::*;
+        match self {
+            HandshakeError(e) => e.peer_misbehaved(),
+            ReceiveError(e) => e.peer_misbehaved(),
+            SendError(_) | CardiacArrest | NoParentConnection | NoUserConnection
+            | SendBufferOverflow | IdleTimeout => false,
+        }
+    }
+}
+
+impl From<HandshakeError> for ProtocolError {
+    fn from(e: HandshakeError) -> Self {
+        ProtocolError::HandshakeError(e)
+    }
+}
+
+impl From<SendError> for ProtocolError {
+    fn from(e: SendError) -> Self {
+        ProtocolError::SendError(e)
+    }
+}
+
+impl From<ReceiveError> for ProtocolError {
+    fn from(e: ReceiveError) -> Self {
+        ProtocolError::ReceiveError(e)
+    }
+}
+
+/// Why a protocol worker stopped managing its connection.
+#[derive(Debug)]
+pub enum CloseReason {
+    /// The peer closed the connection.
+    PeerRequested,
+    /// The peer stopped responding in time.
+    Timeout,
+    /// The connection broke, or the peer broke the protocol.
+    Error(ProtocolError),
+    /// The parent service asked to close the connection, or stopped listening to it.
+    ParentClosed,
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        use CloseReason::*;
+        match self {
+            PeerRequested => write!(f, "closed by the peer"),
+            Timeout => write!(f, "peer timed out"),
+            Error(e) => write!(f, "{}", e),
+            ParentClosed => write!(f, "closed by the parent service"),
+        }
+    }
+}
+
+impl CloseReason {
+    /// A short name of the reason, suitable for labelling metrics.
+    pub fn label(&self) -> &'static str {
+        use CloseReason::*;
+        match self {
+            PeerRequested => "peer_requested",
+            Timeout => "timeout",
+            Error(_) => "error",
+            ParentClosed => "parent_closed",
+        }
+    }
+
+    /// Whether the peer broke the protocol, as opposed to the connection failing.
+    pub fn peer_misbehaved(&self) -> bool {
+        match self {
+            CloseReason::Error(e) => e.peer_misbehaved(),
+            _ => false,
+        }
+    }
+}
+
+impl From<Result<(), ProtocolError>> for CloseReason {
+    fn from(result: Result<(), ProtocolError>) -> Self {
+        match result {
+            Ok(()) | Err(ProtocolError::NoParentConnection | ProtocolError::NoUserConnection) => {
+                CloseReason::ParentClosed
+            }
+            Err(
+                ProtocolError::CardiacArrest
+                | ProtocolError::IdleTimeout
+                | ProtocolError::SendError(SendError::Timeout)
+                | ProtocolError::HandshakeError(HandshakeError::TimedOut),
+            ) => CloseReason::Timeout,
+            Err(ProtocolError::ReceiveError(e)) if e.closed_by_peer() => CloseReason::PeerRequested,
+            Err(e) => CloseReason::Error(e),
+        }
+    }
+}
+
+impl Protocol {
+    /// The capabilities we offer over this version of the protocol, if it exchanges any.
+    fn capabilities(&self) -> Option<Capabilities> {
+        use Protocol::*;
+        match self {
+            V0 | V1 => None,
+            V2 | V3 => Some(Capabilities::supported()),
+        }
+    }
+
+    /// Whether the handshake of this version of the protocol carries the chain identities. Peers
+    /// using older versions are on an unknown chain.
+    fn carries_chain(&self) -> bool {
+        use Protocol::*;
+        match self {
+            V0 | V1 | V2 => false,
+            V3 => true,
+        }
+    }
+
+    /// Launches the proper variant of the protocol (receiver half).
+    #[allow(clippy::too_many_arguments)]
+    pub async fn manage_incoming<D: Data, S: Splittable>(
+        &self,
+        stream: S,
+        authority_pen: AuthorityPen,
+        result_for_service: mpsc::UnboundedSender<IncomingResult>,
+        data_for_user: mpsc::Sender<D>,
+        heartbeat_config: HeartbeatConfig,
+        handshake_config: HandshakeConfig,
+        receive_config: ReceiveConfig,
+        metrics: Option<Metrics>,
+    ) -> Result<(), ProtocolError> {
+        use Protocol::*;
+        // the peer is only known after the handshake, the worker records it then
+        let span = info_span!(
+            WORKER_SPAN,
+            direction = "incoming",
+            protocol = ?self,
+            peer_id = field::Empty
+        );
+        async move {
+            match self {
+                V0 => {
+                    v0::incoming(
+                        stream,
+                        authority_pen,
+                        result_for_service,
+                        data_for_user,
+                        heartbeat_config,
+                        handshake_config,
+                        receive_config,
+                        metrics,
+                    )
+                    .await
+                }
+                V1 | V2 | V3 => {
+                    v1::incoming(
+                        stream,
+                        authority_pen,
+                        result_for_service,
+                        data_for_user,
+                        heartbeat_config,
+                        handshake_config,
+                        receive_config,
+                        *self,
+                        metrics,
+                    )
+                    .await
+                }
+            }
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Launches the proper variant of the protocol (sender half).
+    /// When the exit channel fires, the data already queued is sent before finishing.
+    /// The codec is only a preference, it is ignored by protocols not supporting compression.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn manage_outgoing<D: Data, S: Splittable>(
+        &self,
+        stream: S,
+        authority_pen: AuthorityPen,
+        peer_id: AuthorityId,
+        result_for_service: mpsc::UnboundedSender<OutgoingResult<D>>,
+        exit: oneshot::Receiver<()>,
+        heartbeat_config: HeartbeatConfig,
+        handshake_config: HandshakeConfig,
+        send_channel_config: SendChannelConfig,
+        codec: Codec,
+        metrics: Option<Metrics>,
+    ) -> Result<(), ProtocolError> {
+        use Protocol::*;
+        let span = info_span!(
+            WORKER_SPAN,
+            direction = "outgoing",
+            protocol = ?self,
+            peer_id = %peer_id
+        );
+        async move {
+            match self {
+                V0 => {
+                    v0::outgoing(
+                        stream,
+                        authority_pen,
+                        peer_id,
+                        result_for_service,
+                        exit,
+                        heartbeat_config,
+                        handshake_config,
+                        send_channel_config,
+                        metrics,
+                    )
+                    .await
+                }
+                V1 | V2 | V3 => {
+                    v1::outgoing(
+                        stream,
+                        authority_pen,
+                        peer_id,
+                        result_for_service,
+                        exit,
+                        heartbeat_config,
+                        handshake_config,
+                        send_channel_config,
+                        codec,
+                        *self,
+                        metrics,
+                    )
+                    .await
+                }
+            }
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, error::Error, fmt::Debug, sync::Arc};
+
+    use futures::{channel::mpsc, pin_mut, StreamExt};
+    use parking_lot::Mutex;
+    use tracing::{
+        field::{Field, Visit},
+        span::{Attributes, Id, Record},
+        Event, Metadata, Subscriber,
+    };
+    use tracing_core::span::Current;
+
+    use super::{CloseReason, Protocol, ProtocolError, WORKER_SPAN};
+    use crate::validator_network::{
+        handshake::{HandshakeConfig, HandshakeError},
+        heartbeat::HeartbeatConfig,
+        io::{Codec, Error as IoError, ReceiveConfig, ReceiveError, SendError},
+        mock::{keys, MockSplittable},
+        send_channel::SendChannelConfig,
+    };
+
+    type Fields = HashMap<&'static str, String>;
+
+    struct FieldVisitor<'a>(&'a mut Fields);
+
+    impl Visit for FieldVisitor<'_> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0.insert(field.name(), format!("{:?}", value));
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordedSpans {
+        spans: Vec<(&'static Metadata<'static>, Fields)>,
+        entered: Vec<Id>,
+    }
+
+    /// Remembers the fields of all the spans, so that they can be checked afterwards.
+    #[derive(Clone, Default)]
+    struct SpanRecorder {
+        state: Arc<Mutex<RecordedSpans>>,
+    }
+
+    impl SpanRecorder {
+        fn worker_spans(&self) -> Vec<Fields> {
+            self.state
+                .lock()
+                .spans
+                .iter()
+                .filter(|(metadata, _)| metadata.name() == WORKER_SPAN)
+                .map(|(_, fields)| fields.clone())
+                .collect()
+        }
+    }
+
+    impl Subscriber for SpanRecorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut fields = HashMap::new();
+            span.record(&mut FieldVisitor(&mut fields));
+            let mut state = self.state.lock();
+            state.spans.push((span.metadata(), fields));
+            // ids have to be nonzero
+            Id::from_u64(state.spans.len() as u64)
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            let mut state = self.state.lock();
+            let (_, fields) = &mut state.spans[span.into_u64() as usize - 1];
+            values.record(&mut FieldVisitor(fields));
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, _: &Event<'_>) {}
+
+        fn enter(&self, span: &Id) {
+            self.state.lock().entered.push(span.clone());
+        }
+
+        fn exit(&self, _: &Id) {
+            self.state.lock().entered.pop();
+        }
+
+        fn current_span(&self) -> Current {
+            let state = self.state.lock();
+            match state.entered.last() {
+                Some(id) => Current::new(id.clone(), state.spans[id.into_u64() as usize - 1].0),
+                None => Current::none(),
+            }
+        }
+    }
+
+    /// Runs both halves of the protocol until they report to the service, returning the protocols
+    /// they reported.
+    async fn reported_protocols(protocol: Protocol) -> (Protocol, Protocol) {
+        let (stream_incoming, stream_outgoing) = MockSplittable::new(4096);
+        let (id_incoming, pen_incoming) = keys().await;
+        let (_, pen_outgoing) = keys().await;
+        let (incoming_result_for_service, mut result_from_incoming) = mpsc::unbounded();
+        let (outgoing_result_for_service, mut result_from_outgoing) = mpsc::unbounded();
+        let (data_for_user, _data_from_incoming) =
+            mpsc::channel::<Vec<i32>>(ReceiveConfig::default().user_queue_capacity);
+        let (_exit_for_outgoing, exit) = futures::channel::oneshot::channel();
+        let incoming_handle = protocol.manage_incoming(
+            stream_incoming,
+            pen_incoming,
+            incoming_result_for_service,
+            data_for_user,
+            HeartbeatConfig::default(),
+            HandshakeConfig::default(),
+            ReceiveConfig::default(),
+            None,
+        );
+        let outgoing_handle = protocol.manage_outgoing(
+            stream_outgoing,
+            pen_outgoing,
+            id_incoming,
+            outgoing_result_for_service,
+            exit,
+            HeartbeatConfig::default(),
+            HandshakeConfig::default(),
+            SendChannelConfig::default(),
+            Codec::default(),
+            None,
+        );
+        pin_mut!(incoming_handle);
+        pin_mut!(outgoing_handle);
+        let reports = async {
+            let incoming = result_from_incoming.next().await.expect("should report");
+            let outgoing = result_from_outgoing.next().await.expect("should report");
+            (incoming, outgoing)
+        };
+        // the exit channel of the incoming worker is kept until we are done polling it
+        let ((_, incoming_protocol, _, _exit), (_, maybe_connection)) = tokio::select! {
+            e = &mut incoming_handle => panic!("incoming finished: {:?}", e),
+            e = &mut outgoing_handle => panic!("outgoing finished: {:?}", e),
+            reports = reports => reports,
+        };
+        let (outgoing_protocol, _) = maybe_connection.expect("successfully connected");
+        (incoming_protocol, outgoing_protocol)
+    }
+
+    #[test]
+    fn wrapping_errors_have_sources() {
+        let errors = [
+            (
+                ProtocolError::HandshakeError(HandshakeError::TimedOut),
+                "timed out",
+            ),
+            (
+                ProtocolError::SendError(SendError::Timeout),
+                "timed out while writing data",
+            ),
+            (
+                ProtocolError::ReceiveError(ReceiveError::DataCorrupted),
+                "received corrupted data",
+            ),
+        ];
+        for (error, source) in errors {
+            assert_eq!(
+                error.source().expect("should have a source").to_string(),
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn leaf_errors_have_no_source() {
+        let errors = [
+            ProtocolError::CardiacArrest,
+            ProtocolError::NoParentConnection,
+            ProtocolError::NoUserConnection,
+            ProtocolError::SendBufferOverflow,
+            ProtocolError::IdleTimeout,
+        ];
+        for error in errors {
+            assert!(error.source().is_none());
+        }
+    }
+
+    #[test]
+    fn classifies_close_reasons() {
+        let closed = || IoError::ConnectionClosed(std::io::ErrorKind::UnexpectedEof.into());
+        let reasons = [
+            (Ok(()), "parent_closed"),
+            (Err(ProtocolError::NoParentConnection), "parent_closed"),
+            (Err(ProtocolError::NoUserConnection), "parent_closed"),
+            (Err(ProtocolError::CardiacArrest), "timeout"),
+            (Err(ProtocolError::IdleTimeout), "timeout"),
+            (Err(ProtocolError::SendError(SendError::Timeout)), "timeout"),
+            (
+                Err(ProtocolError::HandshakeError(HandshakeError::TimedOut)),
+                "timeout",
+            ),
+            (
+                Err(ProtocolError::ReceiveError(ReceiveError::Error(closed()))),
+                "peer_requested",
+            ),
+            (
+                Err(ProtocolError::SendError(SendError::Error(closed()))),
+                "error",
+            ),
+            (Err(ProtocolError::SendBufferOverflow), "error"),
+        ];
+        for (result, label) in reasons {
+            let reason = CloseReason::from(result);
+            assert_eq!(reason.label(), label, "wrong reason {:?}", reason);
+            assert!(!reason.peer_misbehaved());
+        }
+        let reason = CloseReason::from(Err(ProtocolError::ReceiveError(
+            ReceiveError::DataCorrupted,
+        )));
+        assert!(matches!(
+            reason,
+            CloseReason::Error(ProtocolError::ReceiveError(ReceiveError::DataCorrupted))
+        ));
+        assert!(reason.peer_misbehaved());
+    }
+
+    #[tokio::test]
+    async fn worker_spans_name_the_peer() {
+        let recorder = SpanRecorder::default();
+        let _guard = tracing::subscriber::set_default(recorder.clone());
+        let (stream_incoming, stream_outgoing) = MockSplittable::new(4096);
+        let (id_incoming, pen_incoming) = keys().await;
+        let (id_outgoing, pen_outgoing) = keys().await;
+        let (incoming_result_for_service, mut result_from_incoming) = mpsc::unbounded();
+        let (outgoing_result_for_service, mut result_from_outgoing) = mpsc::unbounded();
+        let (data_for_user, _data_from_incoming) =
+            mpsc::channel::<Vec<i32>>(ReceiveConfig::default().user_queue_capacity);
+        let (_exit_for_outgoing, exit) = futures::channel::oneshot::channel();
+        let incoming_handle = Protocol::V1.manage_incoming(
+            stream_incoming,
+            pen_incoming,
+            incoming_result_for_service,
+            data_for_user,
+            HeartbeatConfig::default(),
+            HandshakeConfig::default(),
+            ReceiveConfig::default(),
+            None,
+        );
+        let outgoing_handle = Protocol::V1.manage_outgoing(
+            stream_outgoing,
+            pen_outgoing,
+            id_incoming.clone(),
+            outgoing_result_for_service,
+            exit,
+            HeartbeatConfig::default(),
+            HandshakeConfig::default(),
+            SendChannelConfig::default(),
+            Codec::default(),
+            None,
+        );
+        pin_mut!(incoming_handle);
+        pin_mut!(outgoing_handle);
+        let handshakes = async {
+            result_from_incoming.next().await.expect("should report");
+            result_from_outgoing.next().await.expect("should report");
+        };
+        tokio::select! {
+            e = &mut incoming_handle => panic!("incoming finished: {:?}", e),
+            e = &mut outgoing_handle => panic!("outgoing finished: {:?}", e),
+            _ = handshakes => (),
+        }
+
+        let spans = recorder.worker_spans();
+        assert_eq!(spans.len(), 2);
+        let span = |direction: &str| {
+            spans
+                .iter()
+                .find(|fields| fields.get("direction").map(String::as_str) == Some(direction))
+                .unwrap_or_else(|| panic!("no {} span", direction))
+        };
+        let incoming = span("incoming");
+        assert_eq!(incoming.get("peer_id"), Some(&id_outgoing.to_string()));
+        assert_eq!(incoming.get("protocol").map(String::as_str), Some("V1"));
+        let outgoing = span("outgoing");
+        assert_eq!(outgoing.get("peer_id"), Some(&id_incoming.to_string()));
+        assert_eq!(outgoing.get("protocol").map(String::as_str), Some("V1"));
+    }
+
+    #[tokio::test]
+    async fn reports_negotiated_protocol() {
+        for protocol in [Protocol::V0, Protocol::V1, Protocol::V2, Protocol::V3] {
+            assert_eq!(reported_protocols(protocol).await, (protocol, protocol));
+        }
+    }
+}
@@ -0,0 +1,797 @@
+use std::time::Duration;
+
+use aleph_primitives::AuthorityId;
+use codec::Encode;
+use futures::channel::{mpsc, oneshot};
+use log::{debug, info, trace};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    crypto::AuthorityPen,
+    validator_network::{
+        clock::TokioClock,
+        handshake::{v0_handshake_incoming, v0_handshake_outgoing, Capabilities, HandshakeConfig},
+        heartbeat::{heartbeat_receiver, heartbeat_sender, HeartbeatConfig},
+        io::{receive_data_with_limit, send_data_with_timeout, ReceiveConfig},
+        metrics::Metrics,
+        protocols::{
+            pass_to_user, record_peer_id, IncomingResult, OutgoingResult, Protocol, ProtocolError,
+        },
+        rate_limit::{RateLimit, RateLimiter},
+        send_channel::{send_channel, DataReceiver, SendChannelConfig},
+        Data, Splittable,
+    },
+};
+
+async fn send<D: Data, S: AsyncWrite + Unpin + Send>(
+    sender: S,
+    data: D,
+    write_timeout: Option<Duration>,
+    metrics: &Option<Metrics>,
+) -> Result<S, ProtocolError> {
+    if let Some(metrics) = metrics {
+        metrics.report_sent(data.encoded_size());
+    }
+    Ok(send_data_with_timeout(sender, data, write_timeout).await?)
+}
+
+/// Receives data from the parent service and sends it over the network.
+/// Exits when the parent channel is closed, or if the network connection is broken.
+/// On parent request flushes the data that was already queued and exits. The flushing is not
+/// paced, so that a rate limit cannot hold the exit up.
+async fn sending<D: Data, S: AsyncWrite + Unpin + Send>(
+    mut sender: S,
+    mut data_from_user: DataReceiver<D>,
+    mut exit: oneshot::Receiver<()>,
+    rate_limit: Option<RateLimit>,
+    write_timeout: Option<Duration>,
+    metrics: Option<Metrics>,
+) -> Result<(), ProtocolError> {
+    let mut rate_limiter = RateLimiter::new(rate_limit, TokioClock);
+    loop {
+        let next = tokio::select! {
+            next = data_from_user.next() => next.map_err(|_| ProtocolError::SendBufferOverflow)?,
+            _ = &mut exit => break,
+        };
+        let data = match next {
+            Some(data) => data,
+            // We have been closed by the parent service, all good.
+            None => return Ok(()),
+        };
+        let exiting = rate_limiter
+            .take_or_exit(data.encoded_size(), &mut exit)
+            .await;
+        sender = send(sender, data, write_timeout, &metrics).await?;
+        if exiting {
+            break;
+        }
+    }
+    while let Some(data) = data_from_user
+        .try_next()
+        .map_err(|_| ProtocolError::SendBufferOverflow)?
+    {
+        sender = send(sender, data, write_timeout, &metrics).await?;
+    }
+    Ok(())
+}
+
+/// Performs the handshake, and then keeps sending data received from the parent service.
+/// Exits on parent request, or in case of broken or dead network connection.
+#[allow(clippy::too_many_arguments)]
+pub async fn outgoing<D: Data, S: Splittable>(
+    stream: S,
+    authority_pen: AuthorityPen,
+    peer_id: AuthorityId,
+    result_for_parent: mpsc::UnboundedSender<OutgoingResult<D>>,
+    exit: oneshot::Receiver<()>,
+    heartbeat_config: HeartbeatConfig,
+    handshake_config: HandshakeConfig,
+    send_channel_config: SendChannelConfig,
+    metrics: Option<Metrics>,
+) -> Result<(), ProtocolError> {
+    trace!(target: "validator-network", "Extending hand to {}.", peer_id);
+    let (sender, receiver) = v0_handshake_outgoing(
+        stream,
+        authority_pen,
+        peer_id.clone(),
+        false,
+        handshake_config,
+    )
+    .await?;
+    info!(target: "validator-network", "Outgoing handshake with {} finished successfully.", peer_id);
+    if let Some(metrics) = &metrics {
+        metrics.report_established();
+    }
+    let (data_for_network, data_from_user) = send_channel::<D>(send_channel_config);
+    result_for_parent
+        .unbounded_send((peer_id.clone(), Some((Protocol::V0, data_for_network))))
+        .map_err(|_| ProtocolError::NoParentConnection)?;
+
+    let sending = sending(
+        sender,
+        data_from_user,
+        exit,
+        send_channel_config.rate_limit,
+        send_channel_config.write_timeout,
+        metrics.clone(),
+    );
+    let heartbeat = heartbeat_receiver(receiver, heartbeat_config, TokioClock, metrics);
+
+    debug!(target: "validator-network", "Starting worker for sending to {}.", peer_id);
+    loop {
+        tokio::select! {
+            _ = heartbeat => return Err(ProtocolError::CardiacArrest),
+            result = sending => return result,
+        }
+    }
+}
+
+/// Receives data from the network and sends it to the parent service. Stops reading while the
+/// parent service has no room for more data.
+/// Exits when the parent channel is closed, or if the network connection is broken.
+async fn receiving<D: Data, S: AsyncRead + Unpin + Send>(
+    mut stream: S,
+    mut data_for_user: mpsc::Sender<D>,
+    receive_config: ReceiveConfig,
+    metrics: Option<Metrics>,
+) -> Result<(), ProtocolError> {
+    let mut transient_errors = 0;
+    let mut rate_limiter = RateLimiter::new(receive_config.rate_limit, TokioClock);
+    loop {
+        let data = match receive_data_with_limit::<_, D>(&mut stream, receive_config.max_frame_size)
+            .await
+        {
+            Ok((_, data)) => data,
+            Err(e) if e.is_transient() && transient_errors < receive_config.transient_retries => {
+                transient_errors += 1;
+                debug!(target: "validator-network", "Retrying after a transient error when receiving: {}", e);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        transient_errors = 0;
+        let size = data.encoded_size();
+        if let Some(metrics) = &metrics {
+            metrics.report_received(size);
+        }
+        pass_to_user(&mut data_for_user, data, &metrics).await?;
+        // holding off with the next read slows the peer down
+        rate_limiter.take(size).await;
+    }
+}
+
+/// Performs the handshake, and then keeps sending data received from the network to the parent service.
+/// Exits on parent request, or in case of broken or dead network connection.
+#[allow(clippy::too_many_arguments)]
+pub async fn incoming<D: Data, S: Splittable>(
+    stream: S,
+    authority_pen: AuthorityPen,
+    result_for_parent: mpsc::UnboundedSender<IncomingResult>,
+    data_for_user: mpsc::Sender<D>,
+    heartbeat_config: HeartbeatConfig,
+    handshake_config: HandshakeConfig,
+    receive_config: ReceiveConfig,
+    metrics: Option<Metrics>,
+) -> Result<(), ProtocolError> {
+    trace!(target: "validator-network", "Waiting for extended hand...");
+    let (sender, receiver, peer_id) =
+        v0_handshake_incoming(stream, authority_pen, false, handshake_config).await?;
+    record_peer_id(&peer_id);
+    info!(target: "validator-network", "Incoming handshake with {} finished successfully.", peer_id);
+
+    let (tx_exit, exit) = oneshot::channel();
+    result_for_parent
+        .unbounded_send((
+            peer_id.clone(),
+            Protocol::V0,
+            Capabilities::default(),
+            tx_exit,
+        ))
+        .map_err(|_| ProtocolError::NoParentConnection)?;
+
+    let receiving = receiving(receiver, data_for_user, receive_config, metrics.clone());
+    let heartbeat = heartbeat_sender(sender, heartbeat_config, TokioClock, metrics);
+
+    debug!(target: "validator-network", "Starting worker for receiving from {}.", peer_id);
+    loop {
+        tokio::select! {
+            _ = heartbeat => return Err(ProtocolError::CardiacArrest),
+            result = receiving => return result,
+            _ = exit => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aleph_primitives::AuthorityId;
+    use futures::{
+        channel::{
+            mpsc,
+            mpsc::{Receiver, UnboundedReceiver},
+            oneshot,
+        },
+        join, pin_mut, FutureExt, StreamExt,
+    };
+
+    use super::{incoming, outgoing};
+    use crate::{
+        crypto::AuthorityPen,
+        validator_network::{
+            handshake::{HandshakeConfig, HandshakeError},
+            heartbeat::HeartbeatConfig,
+            io::ReceiveConfig,
+            mock::{keys, MockSplittable},
+            protocols::{CloseReason, IncomingResult, OutgoingResult, ProtocolError},
+            send_channel::SendChannelConfig,
+            Data,
+        },
+    };
+
+    async fn prepare<D: Data>() -> (
+        AuthorityId,
+        AuthorityPen,
+        AuthorityId,
+        AuthorityPen,
+        impl futures::Future<Output = Result<(), ProtocolError>>,
+        impl futures::Future<Output = Result<(), ProtocolError>>,
+        Receiver<D>,
+        UnboundedReceiver<IncomingResult>,
+        UnboundedReceiver<OutgoingResult<D>>,
+        oneshot::Sender<()>,
+    ) {
+        let (stream_incoming, stream_outgoing) = MockSplittable::new(4096);
+        let (id_incoming, pen_incoming) = keys().await;
+        let (id_outgoing, pen_outgoing) = keys().await;
+        assert_ne!(id_incoming, id_outgoing);
+        let (incoming_result_for_service, result_from_incoming) =
+            mpsc::unbounded::<IncomingResult>();
+        let (outgoing_result_for_service, result_from_outgoing) = mpsc::unbounded();
+        let (exit_for_outgoing, exit) = oneshot::channel();
+        let (data_for_user, data_from_incoming) =
+            mpsc::channel::<D>(ReceiveConfig::default().user_queue_capacity);
+        let incoming_handle = incoming(
+            stream_incoming,
+            pen_incoming.clone(),
+            incoming_result_for_service,
+            data_for_user,
+            HeartbeatConfig::default(),
+            HandshakeConfig::default(),
+            ReceiveConfig::default(),
+            None,
+        );
+        let outgoing_handle = outgoing(
+            stream_outgoing,
+            pen_outgoing.clone(),
+            id_incoming.clone(),
+            outgoing_result_for_service,
+            exit,
+            HeartbeatConfig::default(),
+            HandshakeConfig::default(),
+            SendChannelConfig::default(),
+            None,
+        );
+        (
+            id_incoming,
+            pen_incoming,
+            id_outgoing,
+            pen_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            data_from_incoming,
+            result_from_incoming,
+            result_from_outgoing,
+            exit_for_outgoing,
+        )
+    }
+
+    #[tokio::test]
+    async fn send_data() {
+        let (
+            _id_incoming,
+            _pen_incoming,
+            _id_outgoing,
+            _pen_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            mut data_from_incoming,
+            _result_from_incoming,
+            mut result_from_outgoing,
+            _exit_for_outgoing,
+        ) = prepare::<Vec<i32>>().await;
+        let incoming_handle = incoming_handle.fuse();
+        let outgoing_handle = outgoing_handle.fuse();
+        pin_mut!(incoming_handle);
+        pin_mut!(outgoing_handle);
+        let _data_for_outgoing = tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+            result = result_from_outgoing.next() => {
+                let (_, maybe_data_for_outgoing) = result.expect("outgoing should have resturned Some");
+                let (_, data_for_outgoing) = maybe_data_for_outgoing.expect("successfully connected");
+                data_for_outgoing
+                    .send(vec![4, 3, 43])
+                    .expect("should send");
+                data_for_outgoing
+                    .send(vec![2, 1, 3, 7])
+                    .expect("should send");
+                data_for_outgoing
+            },
+        };
+        tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+            v = data_from_incoming.next() => {
+                assert_eq!(v, Some(vec![4, 3, 43]));
+            },
+        };
+        tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+            v = data_from_incoming.next() => {
+                assert_eq!(v, Some(vec![2, 1, 3, 7]));
+            },
+        };
+    }
+
+    #[tokio::test]
+    async fn closed_by_parent_service() {
+        let (
+            _id_incoming,
+            _pen_incoming,
+            id_outgoing,
+            _pen_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            _data_from_incoming,
+            mut result_from_incoming,
+            _result_from_outgoing,
+            _exit_for_outgoing,
+        ) = prepare::<Vec<i32>>().await;
+        let incoming_handle = incoming_handle.fuse();
+        let outgoing_handle = outgoing_handle.fuse();
+        pin_mut!(incoming_handle);
+        pin_mut!(outgoing_handle);
+        tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+            received = result_from_incoming.next() => {
+                // we drop the exit oneshot channel, thus finishing incoming_handle
+                let (received_id, _, _, _) = received.expect("should receive");
+                assert_eq!(received_id, id_outgoing);
+            },
+        };
+        incoming_handle
+            .await
+            .expect("closed manually, should finish with no error");
+    }
+
+    #[tokio::test]
+    async fn outgoing_closed_by_parent_service() {
+        let (
+            _id_incoming,
+            _pen_incoming,
+            _id_outgoing,
+            _pen_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            mut data_from_incoming,
+            _result_from_incoming,
+            mut result_from_outgoing,
+            exit_for_outgoing,
+        ) = prepare::<Vec<i32>>().await;
+        let incoming_handle = incoming_handle.fuse();
+        let outgoing_handle = outgoing_handle.fuse();
+        pin_mut!(incoming_handle);
+        pin_mut!(outgoing_handle);
+        let _data_for_outgoing = tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+            result = result_from_outgoing.next() => {
+                let (_, maybe_data_for_outgoing) = result.expect("outgoing should have returned Some");
+                let (_, data_for_outgoing) = maybe_data_for_outgoing.expect("successfully connected");
+                data_for_outgoing
+                    .send(vec![4, 3, 43])
+                    .expect("should send");
+                data_for_outgoing
+                    .send(vec![2, 1, 3, 7])
+                    .expect("should send");
+                // the worker did not get a chance to send the data before being closed
+                exit_for_outgoing.send(()).expect("should send exit");
+                data_for_outgoing
+            },
+        };
+        tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            result = &mut outgoing_handle => result.expect("closed manually, should finish with no error"),
+        };
+        // the data queued before closing still arrives
+        for expected in [vec![4, 3, 43], vec![2, 1, 3, 7]] {
+            tokio::select! {
+                biased;
+                v = data_from_incoming.next() => assert_eq!(v, Some(expected)),
+                _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            };
+        }
+    }
+
+    #[tokio::test]
+    async fn parent_service_dead() {
+        let (
+            _id_incoming,
+            _pen_incoming,
+            _id_outgoing,
+            _pen_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            _data_from_incoming,
+            result_from_incoming,
+            _result_from_outgoing,
+            _exit_for_outgoing,
+        ) = prepare::<Vec<i32>>().await;
+        std::mem::drop(result_from_incoming);
+        let incoming_handle = incoming_handle.fuse();
+        let outgoing_handle = outgoing_handle.fuse();
+        pin_mut!(incoming_handle);
+        pin_mut!(outgoing_handle);
+        tokio::select! {
+            e = &mut incoming_handle => match e {
+                Err(ProtocolError::NoParentConnection) => (),
+                Err(e) => panic!("unexpected error: {}", e),
+                Ok(_) => panic!("successfully finished when parent dead"),
+            },
+            _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+        };
+    }
+
+    #[tokio::test]
+    async fn parent_user_dead() {
+        let (
+            _id_incoming,
+            _pen_incoming,
+            _id_outgoing,
+            _pen_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            data_from_incoming,
+            _result_from_incoming,
+            mut result_from_outgoing,
+            _exit_for_outgoing,
+        ) = prepare::<Vec<i32>>().await;
+        std::mem::drop(data_from_incoming);
+        let incoming_handle = incoming_handle.fuse();
+        let outgoing_handle = outgoing_handle.fuse();
+        pin_mut!(incoming_handle);
+        pin_mut!(outgoing_handle);
+        let _data_for_outgoing = tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+            result = result_from_outgoing.next() => {
+                let (_, maybe_data_for_outgoing) = result.expect("outgoing should have resturned Some");
+                let (_, data_for_outgoing) = maybe_data_for_outgoing.expect("successfully connected");
+                data_for_outgoing
+                    .send(vec![2, 1, 3, 7])
+                    .expect("should send");
+                data_for_outgoing
+            },
+        };
+        tokio::select! {
+            e = &mut incoming_handle => match e {
+                Err(ProtocolError::NoUserConnection) => (),
+                Err(e) => panic!("unexpected error: {}", e),
+                Ok(_) => panic!("successfully finished when user dead"),
+            },
+            _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+        };
+    }
+
+    #[tokio::test]
+    async fn sender_dead_before_handshake() {
+        let (
+            _id_incoming,
+            _pen_incoming,
+            _id_outgoing,
+            _pen_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            _data_from_incoming,
+            _result_from_incoming,
+            _result_from_outgoing,
+            _exit_for_outgoing,
+        ) = prepare::<Vec<i32>>().await;
+        std::mem::drop(outgoing_handle);
+        match incoming_handle.await {
+            Err(ProtocolError::HandshakeError(_)) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("successfully finished when connection dead"),
+        };
+    }
+
+    #[tokio::test]
+    async fn sender_dead_after_handshake() {
+        let (
+            _id_incoming,
+            _pen_incoming,
+            _id_outgoing,
+            _pen_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            _data_from_incoming,
+            mut result_from_incoming,
+            _result_from_outgoing,
+            _exit_for_outgoing,
+        ) = prepare::<Vec<i32>>().await;
+        let incoming_handle = incoming_handle.fuse();
+        pin_mut!(incoming_handle);
+        let (_, _, _, _exit) = tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            _ = outgoing_handle => panic!("outgoing process unexpectedly finished"),
+            out = result_from_incoming.next() => out.expect("should receive"),
+        };
+        // outgoing_handle got consumed by tokio::select!, the sender is dead
+        match incoming_handle.await {
+            Err(ProtocolError::ReceiveError(_)) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("successfully finished when connection dead"),
+        };
+    }
+
+    #[tokio::test]
+    async fn receiver_dead_before_handshake() {
+        let (
+            _id_incoming,
+            _pen_incoming,
+            _id_outgoing,
+            _pen_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            _data_from_incoming,
+            _result_from_incoming,
+            _result_from_outgoing,
+            _exit_for_outgoing,
+        ) = prepare::<Vec<i32>>().await;
+        std::mem::drop(incoming_handle);
+        match outgoing_handle.await {
+            Err(ProtocolError::HandshakeError(_)) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("successfully finished when connection dead"),
+        };
+    }
+
+    #[tokio::test]
+    async fn receiver_dead_after_handshake() {
+        let (
+            _id_incoming,
+            _pen_incoming,
+            _id_outgoing,
+            _pen_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            _data_from_incoming,
+            mut result_from_incoming,
+            _result_from_outgoing,
+            _exit_for_outgoing,
+        ) = prepare::<Vec<i32>>().await;
+        let outgoing_handle = outgoing_handle.fuse();
+        pin_mut!(outgoing_handle);
+        let (_, _, _, _exit) = tokio::select! {
+            _ = incoming_handle => panic!("incoming process unexpectedly finished"),
+            _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+            out = result_from_incoming.next() => out.expect("should receive"),
+        };
+        // incoming_handle got consumed by tokio::select!, the receiver is dead
+        match outgoing_handle.await {
+            // We never get the SendError variant here, because we did not send anything
+            // through data_for_outgoing.
+            Err(ProtocolError::CardiacArrest) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("successfully finished when connection dead"),
+        };
+    }
+
+    #[tokio::test]
+    async fn closed_by_parent_service_reason() {
+        let (
+            _id_incoming,
+            _pen_incoming,
+            _id_outgoing,
+            _pen_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            _data_from_incoming,
+            mut result_from_incoming,
+            _result_from_outgoing,
+            _exit_for_outgoing,
+        ) = prepare::<Vec<i32>>().await;
+        let incoming_handle = incoming_handle.fuse();
+        pin_mut!(incoming_handle);
+        tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            _ = outgoing_handle => panic!("outgoing process unexpectedly finished"),
+            // we drop the exit oneshot channel, thus finishing incoming_handle
+            received = result_from_incoming.next() => received.expect("should receive"),
+        };
+        assert!(matches!(
+            CloseReason::from(incoming_handle.await),
+            CloseReason::ParentClosed
+        ));
+    }
+
+    #[tokio::test]
+    async fn parent_service_dead_reason() {
+        let (
+            _id_incoming,
+            _pen_incoming,
+            _id_outgoing,
+            _pen_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            _data_from_incoming,
+            result_from_incoming,
+            _result_from_outgoing,
+            _exit_for_outgoing,
+        ) = prepare::<Vec<i32>>().await;
+        std::mem::drop(result_from_incoming);
+        let result = tokio::select! {
+            result = incoming_handle => result,
+            _ = outgoing_handle => panic!("outgoing process unexpectedly finished"),
+        };
+        assert!(matches!(
+            CloseReason::from(result),
+            CloseReason::ParentClosed
+        ));
+    }
+
+    #[tokio::test]
+    async fn handshake_failure_reason() {
+        let (
+            _id_incoming,
+            _pen_incoming,
+            _id_outgoing,
+            _pen_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            _data_from_incoming,
+            _result_from_incoming,
+            _result_from_outgoing,
+            _exit_for_outgoing,
+        ) = prepare::<Vec<i32>>().await;
+        std::mem::drop(outgoing_handle);
+        assert!(matches!(
+            CloseReason::from(incoming_handle.await),
+            CloseReason::Error(ProtocolError::HandshakeError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn sender_dead_after_handshake_reason() {
+        let (
+            _id_incoming,
+            _pen_incoming,
+            _id_outgoing,
+            _pen_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            _data_from_incoming,
+            mut result_from_incoming,
+            _result_from_outgoing,
+            _exit_for_outgoing,
+        ) = prepare::<Vec<i32>>().await;
+        let incoming_handle = incoming_handle.fuse();
+        pin_mut!(incoming_handle);
+        let (_, _, _, _exit) = tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            _ = outgoing_handle => panic!("outgoing process unexpectedly finished"),
+            out = result_from_incoming.next() => out.expect("should receive"),
+        };
+        // outgoing_handle got consumed by tokio::select!, the peer closed the connection
+        assert!(matches!(
+            CloseReason::from(incoming_handle.await),
+            CloseReason::PeerRequested
+        ));
+    }
+
+    #[tokio::test]
+    async fn receiver_dead_after_handshake_reason() {
+        let (
+            _id_incoming,
+            _pen_incoming,
+            _id_outgoing,
+            _pen_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            _data_from_incoming,
+            mut result_from_incoming,
+            _result_from_outgoing,
+            _exit_for_outgoing,
+        ) = prepare::<Vec<i32>>().await;
+        let outgoing_handle = outgoing_handle.fuse();
+        pin_mut!(outgoing_handle);
+        let (_, _, _, _exit) = tokio::select! {
+            _ = incoming_handle => panic!("incoming process unexpectedly finished"),
+            _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+            out = result_from_incoming.next() => out.expect("should receive"),
+        };
+        // incoming_handle got consumed by tokio::select!, no heartbeats arrive anymore
+        assert!(matches!(
+            CloseReason::from(outgoing_handle.await),
+            CloseReason::Timeout
+        ));
+    }
+
+    #[tokio::test]
+    async fn receiver_dead_after_handshake_try_send_error() {
+        let (
+            _id_incoming,
+            _pen_incoming,
+            _id_outgoing,
+            _pen_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            _data_from_incoming,
+            mut result_from_incoming,
+            _result_from_outgoing,
+            _exit_for_outgoing,
+        ) = prepare::<Vec<i32>>().await;
+        let outgoing_handle = outgoing_handle.fuse();
+        pin_mut!(outgoing_handle);
+        let (_, _, _, _exit) = tokio::select! {
+            _ = incoming_handle => panic!("incoming process unexpectedly finished"),
+            _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+            out = result_from_incoming.next() => out.expect("should receive"),
+        };
+        match outgoing_handle.await {
+            Err(ProtocolError::CardiacArrest) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("successfully finished when connection dead"),
+        };
+    }
+
+    #[tokio::test]
+    async fn connected_to_wrong_peer() {
+        let (stream_incoming, stream_outgoing) = MockSplittable::new(4096);
+        let (id_incoming, pen_incoming) = keys().await;
+        let (_, pen_outgoing) = keys().await;
+        let (id_expected, _) = keys().await;
+        let (incoming_result_for_service, _result_from_incoming) = mpsc::unbounded();
+        let (outgoing_result_for_service, _result_from_outgoing) = mpsc::unbounded();
+        let (_exit_for_outgoing, exit) = oneshot::channel();
+        let (data_for_user, _data_from_incoming) =
+            mpsc::channel::<Vec<i32>>(ReceiveConfig::default().user_queue_capacity);
+        let incoming_handle = incoming(
+            stream_incoming,
+            pen_incoming,
+            incoming_result_for_service,
+            data_for_user,
+            HeartbeatConfig::default(),
+            HandshakeConfig::default(),
+            ReceiveConfig::default(),
+            None,
+        );
+        let outgoing_handle = outgoing(
+            stream_outgoing,
+            pen_outgoing,
+            id_expected.clone(),
+            outgoing_result_for_service,
+            exit,
+            HeartbeatConfig::default(),
+            HandshakeConfig::default(),
+            SendChannelConfig::default(),
+            None,
+        );
+        let (incoming_result, outgoing_result) = join!(incoming_handle, outgoing_handle);
+        match outgoing_result {
+            Err(ProtocolError::HandshakeError(HandshakeError::IdentityMismatch {
+                expected,
+                got,
+            })) => {
+                assert_eq!(expected, id_expected);
+                assert_eq!(got, id_incoming);
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("successfully connected to the wrong peer"),
+        };
+        // the connection got closed immediately, so the incoming side fails as well
+        assert!(incoming_result.is_err());
+    }
+}
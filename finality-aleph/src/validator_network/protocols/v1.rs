@@ -0,0 +1,1784 @@
+use aleph_primitives::AuthorityId;
+use codec::{Decode, Encode, EncodeLike, Error as CodecError, Input, Output};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{pending, poll_fn},
+    pin_mut, stream, SinkExt, StreamExt,
+};
+use log::{debug, info, trace};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::Duration,
+};
+
+use crate::{
+    crypto::AuthorityPen,
+    validator_network::{
+        clock::{Clock, TokioClock},
+        handshake::{
+            announce_codecs, choose_codec, exchange_capabilities, v0_handshake_incoming,
+            v0_handshake_outgoing, Capabilities, HandshakeConfig,
+        },
+        heartbeat::HeartbeatConfig,
+        io::{
+            receive_data, receive_data_with_codec, send_coalesced, send_data, send_data_with_codec,
+            CoalescedFrames, Codec, ReceiveConfig, SendError,
+        },
+        metrics::Metrics,
+        protocols::{
+            offer_to_user, record_peer_id, IncomingResult, OutgoingResult, Protocol, ProtocolError,
+        },
+        rate_limit::{RateLimit, RateLimiter},
+        send_channel::{send_channel, DataReceiver, SendChannelConfig},
+        Data, Splittable,
+    },
+};
+
+/// Probes are never sent or answered more often than this, regardless of the configuration.
+const MIN_PROBE_INTERVAL: Duration = Duration::from_secs(1);
+/// Coalescing stops early once this many bytes were gathered, so that big bursts still get going.
+const MAX_COALESCED_SIZE: usize = 64 * 1024;
+
+/// A message sent over the data stream. Heartbeats are interleaved with the data, so that the
+/// receiving side can tell an idle peer from a dead one. Probes ask the receiving side for an ack
+/// over the heartbeat stream, to measure the round trip time.
+#[derive(Debug, Clone, Encode, Decode)]
+enum Message<D: Data> {
+    Data(D),
+    Heartbeat,
+    Probe(u64),
+}
+
+// The plain heartbeats hold this number, acks are told apart by holding a different one.
+const HEARTBEAT_MARKER: u32 = 43;
+const PROBE_ACK_MARKER: u32 = 44;
+
+/// A message sent back over the heartbeat stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Feedback {
+    Heartbeat,
+    ProbeAck(u64),
+}
+
+impl Encode for Feedback {
+    fn size_hint(&self) -> usize {
+        match self {
+            Feedback::Heartbeat => HEARTBEAT_MARKER.size_hint(),
+            Feedback::ProbeAck(nonce) => PROBE_ACK_MARKER.size_hint() + nonce.size_hint(),
+        }
+    }
+
+    /// Heartbeats are encoded the same way as the plain heartbeats, so that peers not knowing
+    /// about probes understand them.
+    fn encode_to<T: Output + ?Sized>(&self, dest: &mut T) {
+        match self {
+            Feedback::Heartbeat => HEARTBEAT_MARKER.encode_to(dest),
+            Feedback::ProbeAck(nonce) => {
+                PROBE_ACK_MARKER.encode_to(dest);
+                nonce.encode_to(dest);
+            }
+        }
+    }
+}
+
+impl EncodeLike for Feedback {}
+
+impl Decode for Feedback {
+    fn decode<I: Input>(input: &mut I) -> Result<Self, CodecError> {
+        match u32::decode(input)? {
+            PROBE_ACK_MARKER => Ok(Feedback::ProbeAck(u64::decode(input)?)),
+            _ => Ok(Feedback::Heartbeat),
+        }
+    }
+}
+
+/// Sends heartbeats at regular intervals, as measured by the clock, and acks for the probes as
+/// soon as they arrive, indefinitely. Fails if the communication channel is closed.
+async fn feedback_sender<S: AsyncWrite + Unpin + Send, C: Clock>(
+    mut stream: S,
+    config: HeartbeatConfig,
+    mut probes: mpsc::UnboundedReceiver<u64>,
+    clock: C,
+) {
+    loop {
+        let feedback = tokio::select! {
+            _ = clock.sleep(config.interval) => Feedback::Heartbeat,
+            Some(nonce) = probes.next() => Feedback::ProbeAck(nonce),
+        };
+        stream = match send_data(stream, feedback).await {
+            Ok(stream) => stream,
+            // If anything at all went wrong, the heartbeat is dead.
+            Err(_) => return,
+        };
+    }
+}
+
+/// Receives heartbeats and passes on the probe acks, indefinitely.
+/// Fails if the communication channel is closed, or if nothing is received for too long, as
+/// measured by the clock.
+async fn feedback_receiver<S: AsyncRead + Unpin + Send, C: Clock>(
+    mut stream: S,
+    config: HeartbeatConfig,
+    acks: mpsc::UnboundedSender<u64>,
+    clock: C,
+) {
+    let mut wait = config.initial_timeout();
+    loop {
+        stream = tokio::select! {
+            received = receive_data(stream) => match received {
+                Ok((stream, Feedback::Heartbeat)) => stream,
+                Ok((stream, Feedback::ProbeAck(nonce))) => {
+                    // Nobody waiting for acks only means we do not measure anything anymore.
+                    let _ = acks.unbounded_send(nonce);
+                    stream
+                }
+                // If anything at all went wrong, the heartbeat is dead.
+                Err(_) => return,
+            },
+            // It took too long, the heartbeat is dead.
+            _ = clock.sleep(wait) => return,
+        };
+        wait = config.timeout;
+    }
+}
+
+/// Waits until the given moment, as measured by the clock, forever if there is none.
+async fn wait_until<C: Clock>(clock: &C, moment: Option<std::time::Instant>) {
+    match moment {
+        Some(moment) => {
+            clock
+                .sleep(moment.saturating_duration_since(clock.now()))
+                .await
+        }
+        None => pending().await,
+    }
+}
+
+/// The heartbeat config to use over a connection with the agreed capabilities, if they were
+/// exchanged. Peers that did not announce they understand probes drop the connection when
+/// probed, so they are never probed.
+fn without_unsupported_probes(
+    heartbeat_config: HeartbeatConfig,
+    agreed: Option<Capabilities>,
+) -> HeartbeatConfig {
+    match agreed.map_or(false, |agreed| agreed.contains(Capabilities::PROBES)) {
+        true => heartbeat_config,
+        false => HeartbeatConfig {
+            probe_interval: None,
+            ..heartbeat_config
+        },
+    }
+}
+
+async fn send<D: Data, S: AsyncWrite + Unpin + Send>(
+    sender: S,
+    message: Message<D>,
+    codec: Codec,
+    write_timeout: Option<Duration>,
+    metrics: &Option<Metrics>,
+) -> Result<S, ProtocolError> {
+    if let Some(metrics) = metrics {
+        match &message {
+            Message::Data(data) => metrics.report_sent(data.encoded_size()),
+            Message::Heartbeat => metrics.report_heartbeat_sent(),
+            Message::Probe(_) => (),
+        }
+    }
+    Ok(send_data_with_codec(sender, message, codec, write_timeout).await?)
+}
+
+/// Clears the outstanding probe and reports its round trip, if the ack is for it.
+fn on_ack<C: Clock>(
+    acked: u64,
+    outstanding_probe: &mut Option<(u64, std::time::Instant)>,
+    clock: &C,
+    metrics: &Option<Metrics>,
+) {
+    match *outstanding_probe {
+        Some((probed, sent_at)) if probed == acked => {
+            *outstanding_probe = None;
+            if let Some(metrics) = metrics {
+                metrics.report_round_trip(clock.now().duration_since(sent_at));
+            }
+        }
+        _ => trace!(target: "validator-network", "Ignoring a stale probe ack."),
+    }
+}
+
+/// What ended coalescing before the window passed or enough data was gathered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Interruption {
+    /// The parent service closed the channel.
+    Closed,
+    /// The parent service requested the exit.
+    Exit,
+}
+
+/// Sends the data together with any more data arriving within the window, with a single write.
+/// The window never exceeds the heartbeat interval, as the written data stands in for the
+/// heartbeats meanwhile. Acks keep getting handled while waiting, and neither the window nor the
+/// rate limit hold up the exit, the data gathered so far is written right away then.
+/// Returns what interrupted the coalescing, if anything did.
+#[allow(clippy::too_many_arguments)]
+async fn send_coalescing<D: Data, S: AsyncWrite + Unpin + Send, C: Clock>(
+    sender: S,
+    data: D,
+    data_from_user: &mut DataReceiver<D>,
+    exit: &mut oneshot::Receiver<()>,
+    acks: &mut mpsc::UnboundedReceiver<u64>,
+    outstanding_probe: &mut Option<(u64, std::time::Instant)>,
+    window: Duration,
+    heartbeat_interval: Duration,
+    rate_limiter: &mut RateLimiter<C>,
+    codec: Codec,
+    write_timeout: Option<Duration>,
+    clock: &C,
+    metrics: &Option<Metrics>,
+) -> Result<(S, Option<Interruption>), ProtocolError> {
+    let deadline = clock.now() + window.min(heartbeat_interval);
+    let mut frames = CoalescedFrames::new();
+    let mut to_send = Message::Data(data);
+    let mut interruption = None;
+    'coalescing: loop {
+        if let (Some(metrics), Message::Data(data)) = (metrics, &to_send) {
+            metrics.report_sent(data.encoded_size());
+        }
+        frames
+            .push_data_with_codec(to_send, codec)
+            .map_err(SendError::from)?;
+        if interruption.is_some() || frames.size() >= MAX_COALESCED_SIZE {
+            break;
+        }
+        let next = loop {
+            tokio::select! {
+                next = data_from_user.next() => break next,
+                Some(acked) = acks.next() => on_ack(acked, outstanding_probe, clock, metrics),
+                _ = wait_until(clock, Some(deadline)) => break 'coalescing,
+                _ = &mut *exit => {
+                    interruption = Some(Interruption::Exit);
+                    break 'coalescing;
+                }
+            }
+        };
+        to_send = match next {
+            Ok(Some(data)) => Message::Data(data),
+            Ok(None) => {
+                interruption = Some(Interruption::Closed);
+                break;
+            }
+            Err(_) => return Err(ProtocolError::SendBufferOverflow),
+        };
+        if rate_limiter
+            .take_or_exit(to_send.encoded_size(), exit)
+            .await
+        {
+            // The data is already out of the queue, so it still goes out with the rest.
+            interruption = Some(Interruption::Exit);
+        }
+    }
+    trace!(target: "validator-network", "Writing {} coalesced messages at once.", frames.len());
+    let sender = send_coalesced(sender, frames, write_timeout).await?;
+    Ok((sender, interruption))
+}
+
+/// Receives data from the parent service and sends it over the network, sending a heartbeat
+/// whenever there was no data for a while. All messages are compressed with the codec.
+/// If configured, probes the peer every now and then, and reports the round trip time once the
+/// ack comes back. Only one probe is outstanding at a time, a new one replaces it.
+/// With a coalescing window, data arriving shortly after other data is written together with it.
+/// Exits when the parent channel is closed, or if the network connection is broken or stalled
+/// for longer than the write timeout.
+/// On parent request flushes the data that was already queued and exits. The flushing is not
+/// paced, so that a rate limit cannot hold the exit up.
+#[allow(clippy::too_many_arguments)]
+async fn sending<D: Data, S: AsyncWrite + Unpin + Send, C: Clock>(
+    mut sender: S,
+    mut data_from_user: DataReceiver<D>,
+    mut exit: oneshot::Receiver<()>,
+    mut acks: mpsc::UnboundedReceiver<u64>,
+    heartbeat_config: HeartbeatConfig,
+    codec: Codec,
+    write_timeout: Option<Duration>,
+    rate_limit: Option<RateLimit>,
+    coalesce_window: Option<Duration>,
+    clock: C,
+    metrics: Option<Metrics>,
+) -> Result<(), ProtocolError> {
+    use Message::*;
+    let mut rate_limiter = RateLimiter::new(rate_limit, clock.clone());
+    let probe_interval = heartbeat_config
+        .probe_interval
+        .map(|interval| interval.max(MIN_PROBE_INTERVAL));
+    let next_probe = || probe_interval.map(|interval| clock.now() + interval);
+    let mut maybe_next_probe = next_probe();
+    let mut outstanding_probe = None;
+    let mut nonce = 0;
+    loop {
+        let to_send = tokio::select! {
+            next = data_from_user.next() => match next {
+                Ok(Some(data)) => Data(data),
+                // We have been closed by the parent service, all good.
+                Ok(None) => return Ok(()),
+                Err(_) => return Err(ProtocolError::SendBufferOverflow),
+            },
+            _ = clock.sleep(heartbeat_config.interval) => Heartbeat,
+            _ = wait_until(&clock, maybe_next_probe) => {
+                maybe_next_probe = next_probe();
+                nonce += 1;
+                Probe(nonce)
+            },
+            Some(acked) = acks.next() => {
+                on_ack(acked, &mut outstanding_probe, &clock, &metrics);
+                continue;
+            },
+            _ = &mut exit => break,
+        };
+        let mut exiting = rate_limiter
+            .take_or_exit(to_send.encoded_size(), &mut exit)
+            .await;
+        if let Probe(probed) = to_send {
+            outstanding_probe = Some((probed, clock.now()));
+        }
+        sender = match (to_send, coalesce_window) {
+            (Data(data), Some(window)) if !exiting => {
+                let (sender, interruption) = send_coalescing(
+                    sender,
+                    data,
+                    &mut data_from_user,
+                    &mut exit,
+                    &mut acks,
+                    &mut outstanding_probe,
+                    window,
+                    heartbeat_config.interval,
+                    &mut rate_limiter,
+                    codec,
+                    write_timeout,
+                    &clock,
+                    &metrics,
+                )
+                .await?;
+                match interruption {
+                    Some(Interruption::Closed) => return Ok(()),
+                    Some(Interruption::Exit) => exiting = true,
+                    None => (),
+                }
+                sender
+            }
+            (to_send, _) => send(sender, to_send, codec, write_timeout, &metrics).await?,
+        };
+        if exiting {
+            break;
+        }
+    }
+    while let Some(data) = data_from_user
+        .try_next()
+        .map_err(|_| ProtocolError::SendBufferOverflow)?
+    {
+        sender = send(sender, Data(data), codec, write_timeout, &metrics).await?;
+    }
+    Ok(())
+}
+
+/// The codec to use over a connection with the agreed capabilities, if they were exchanged. The
+/// peer announced the codecs it supports either way, but a peer exchanging capabilities only
+/// gets compressed data if it agreed on compression.
+fn agreed_codec(codec: Codec, agreed: Option<Capabilities>) -> Codec {
+    match agreed {
+        Some(agreed) if !agreed.contains(Capabilities::COMPRESSION) => Codec::Identity,
+        _ => codec,
+    }
+}
+
+/// Exchanges the capabilities with the peer if the protocol in use does. Returns the capabilities
+/// both sides have, if they were exchanged.
+async fn maybe_exchange_capabilities<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(
+    sender: W,
+    receiver: R,
+    protocol: Protocol,
+    handshake_config: HandshakeConfig,
+    metrics: &Option<Metrics>,
+) -> Result<(W, R, Option<Capabilities>), ProtocolError> {
+    let ours = match protocol.capabilities() {
+        Some(ours) => ours,
+        None => return Ok((sender, receiver, None)),
+    };
+    let (sender, receiver, agreed) =
+        exchange_capabilities(sender, receiver, ours, handshake_config).await?;
+    debug!(target: "validator-network", "Agreed on capabilities {:?}.", agreed);
+    if let Some(metrics) = metrics {
+        metrics.report_capabilities(agreed);
+    }
+    Ok((sender, receiver, Some(agreed)))
+}
+
+/// Performs the handshake, chooses the codec, and then keeps sending data received from the parent
+/// service. Exits on parent request, or in case of broken or dead network connection.
+/// Serves the protocol versions starting from the second one, only using the optional features of
+/// the protocol the peer agreed on.
+#[allow(clippy::too_many_arguments)]
+pub async fn outgoing<D: Data, S: Splittable>(
+    stream: S,
+    authority_pen: AuthorityPen,
+    peer_id: AuthorityId,
+    result_for_parent: mpsc::UnboundedSender<OutgoingResult<D>>,
+    exit: oneshot::Receiver<()>,
+    heartbeat_config: HeartbeatConfig,
+    handshake_config: HandshakeConfig,
+    send_channel_config: SendChannelConfig,
+    codec: Codec,
+    protocol: Protocol,
+    metrics: Option<Metrics>,
+) -> Result<(), ProtocolError> {
+    trace!(target: "validator-network", "Extending hand to {}.", peer_id);
+    let (sender, receiver) = v0_handshake_outgoing(
+        stream,
+        authority_pen,
+        peer_id.clone(),
+        protocol.carries_chain(),
+        handshake_config,
+    )
+    .await?;
+    let (receiver, codec) = choose_codec(receiver, codec, handshake_config).await?;
+    let (sender, receiver, agreed) =
+        maybe_exchange_capabilities(sender, receiver, protocol, handshake_config, &metrics).await?;
+    let heartbeat_config = without_unsupported_probes(heartbeat_config, agreed);
+    let codec = agreed_codec(codec, agreed);
+    info!(target: "validator-network", "Outgoing handshake with {} finished successfully, using codec {:?}.", peer_id, codec);
+    if let Some(metrics) = &metrics {
+        metrics.report_established();
+    }
+    let (data_for_network, data_from_user) = send_channel::<D>(send_channel_config);
+    result_for_parent
+        .unbounded_send((peer_id.clone(), Some((protocol, data_for_network))))
+        .map_err(|_| ProtocolError::NoParentConnection)?;
+
+    let (acks_for_sending, acks) = mpsc::unbounded();
+    let sending = sending(
+        sender,
+        data_from_user,
+        exit,
+        acks,
+        heartbeat_config,
+        codec,
+        send_channel_config.write_timeout,
+        send_channel_config.rate_limit,
+        send_channel_config.coalesce_window,
+        TokioClock,
+        metrics,
+    );
+    let heartbeat = feedback_receiver(receiver, heartbeat_config, acks_for_sending, TokioClock);
+
+    debug!(target: "validator-network", "Starting worker for sending to {}.", peer_id);
+    tokio::select! {
+        _ = heartbeat => Err(ProtocolError::CardiacArrest),
+        result = sending => result,
+    }
+}
+
+/// Receives messages compressed with any supported codec from the network and sends the data to
+/// the parent service, not reading any further while it has no room for more. Passes on the
+/// probes to be acked, but not more often than allowed.
+/// While the parent has no room, one message of data waits here and reading goes on, so that the
+/// heartbeats and probes sent in the meantime still get handled and the peer does not take us for
+/// dead. Only once the next message of data arrives does reading stop until there is room, as
+/// buffering more here would defeat the backpressure. Heartbeats queued behind that data wait as
+/// well, a consumer stalled for longer than the peer's heartbeat timeout still loses the
+/// connection.
+/// Exits when the parent channel is closed, if the network connection is broken, if no message
+/// arrived for too long, or, if the idle timeout is set, if no data arrived for too long.
+/// No deduplication happens here, as nothing gets sent twice: data queued for a broken connection
+/// is dropped together with it, never resent over the next one.
+async fn receiving<D: Data, S: AsyncRead + Unpin + Send, C: Clock>(
+    mut stream: S,
+    mut data_for_user: mpsc::Sender<D>,
+    probes_for_feedback: mpsc::UnboundedSender<u64>,
+    heartbeat_config: HeartbeatConfig,
+    receive_config: ReceiveConfig,
+    clock: C,
+    metrics: Option<Metrics>,
+) -> Result<(), ProtocolError> {
+    use Message::*;
+    let mut last_acked_probe: Option<std::time::Instant> = None;
+    let idle_deadline = || {
+        receive_config
+            .idle_timeout
+            .map(|idle_timeout| clock.now() + idle_timeout)
+    };
+    let mut maybe_idle_deadline = idle_deadline();
+    let mut transient_errors = 0;
+    let mut rate_limiter = RateLimiter::new(receive_config.rate_limit, clock.clone());
+    let mut heartbeat_timeout = heartbeat_config.initial_timeout();
+    // the read in progress lives here, so that it survives making room for the waiting data
+    let max_frame_size = receive_config.max_frame_size;
+    let messages = stream::unfold(stream, move |mut stream| async move {
+        let received = receive_data_with_codec::<_, Message<D>>(&mut stream, max_frame_size)
+            .await
+            .map(|(_, message)| message);
+        Some((received, stream))
+    });
+    pin_mut!(messages);
+    let mut waiting_for_room = None;
+    loop {
+        let wait = match maybe_idle_deadline {
+            Some(deadline) => {
+                heartbeat_timeout.min(deadline.saturating_duration_since(clock.now()))
+            }
+            None => heartbeat_timeout,
+        };
+        let message = tokio::select! {
+            room = poll_fn(|cx| data_for_user.poll_ready(cx)), if waiting_for_room.is_some() => {
+                room.map_err(|_| ProtocolError::NoUserConnection)?;
+                let data = waiting_for_room.take().expect("we only wait for room with data");
+                data_for_user
+                    .start_send(data)
+                    .map_err(|_| ProtocolError::NoUserConnection)?;
+                continue;
+            },
+            received = messages.next() => match received.expect("the stream of messages never ends") {
+                Ok(message) => message,
+                Err(e)
+                    if e.is_transient() && transient_errors < receive_config.transient_retries =>
+                {
+                    transient_errors += 1;
+                    debug!(target: "validator-network", "Retrying after a transient error when receiving: {}", e);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            },
+            _ = clock.sleep(wait) => {
+                return Err(match maybe_idle_deadline {
+                    Some(deadline) if deadline <= clock.now() => ProtocolError::IdleTimeout,
+                    _ => ProtocolError::CardiacArrest,
+                })
+            }
+        };
+        transient_errors = 0;
+        heartbeat_timeout = heartbeat_config.timeout;
+        let size = message.encoded_size();
+        match message {
+            Data(data) => {
+                maybe_idle_deadline = idle_deadline();
+                if let Some(metrics) = &metrics {
+                    metrics.report_received(data.encoded_size());
+                }
+                // only one message of data waits, with more we stop reading until there is room
+                if let Some(waiting) = waiting_for_room.take() {
+                    data_for_user
+                        .send(waiting)
+                        .await
+                        .map_err(|_| ProtocolError::NoUserConnection)?;
+                }
+                waiting_for_room = offer_to_user(&mut data_for_user, data, &metrics)?;
+            }
+            Heartbeat => {
+                if let Some(metrics) = &metrics {
+                    metrics.report_heartbeat();
+                }
+            }
+            Probe(nonce) => {
+                let now = clock.now();
+                // some slack, as probes sent at the minimal interval can arrive a bit closer
+                let too_soon = last_acked_probe.map_or(false, |acked| {
+                    now.duration_since(acked) < MIN_PROBE_INTERVAL / 2
+                });
+                match too_soon {
+                    true => {
+                        debug!(target: "validator-network", "Ignoring a probe arriving too soon after the previous one.")
+                    }
+                    false => {
+                        last_acked_probe = Some(now);
+                        // Nobody sending feedback means the heartbeat is dead anyway.
+                        let _ = probes_for_feedback.unbounded_send(nonce);
+                    }
+                }
+            }
+        }
+        // holding off with the next read slows the peer down
+        rate_limiter.take(size).await;
+    }
+}
+
+/// Performs the handshake, announces the supported codecs, and then keeps sending data received
+/// from the network to the parent service.
+/// Exits on parent request, or in case of broken or dead network connection.
+/// Serves the protocol versions starting from the second one.
+#[allow(clippy::too_many_arguments)]
+pub async fn incoming<D: Data, S: Splittable>(
+    stream: S,
+    authority_pen: AuthorityPen,
+    result_for_parent: mpsc::UnboundedSender<IncomingResult>,
+    data_for_user: mpsc::Sender<D>,
+    heartbeat_config: HeartbeatConfig,
+    handshake_config: HandshakeConfig,
+    receive_config: ReceiveConfig,
+    protocol: Protocol,
+    metrics: Option<Metrics>,
+) -> Result<(), ProtocolError> {
+    trace!(target: "validator-network", "Waiting for extended hand...");
+    let (sender, receiver, peer_id) = v0_handshake_incoming(
+        stream,
+        authority_pen,
+        protocol.carries_chain(),
+        handshake_config,
+    )
+    .await?;
+    record_peer_id(&peer_id);
+    let sender = announce_codecs(sender, handshake_config).await?;
+    let (sender, receiver, agreed) =
+        maybe_exchange_capabilities(sender, receiver, protocol, handshake_config, &metrics).await?;
+    info!(target: "validator-network", "Incoming handshake with {} finished successfully.", peer_id);
+
+    let (tx_exit, exit) = oneshot::channel();
+    result_for_parent
+        .unbounded_send((
+            peer_id.clone(),
+            protocol,
+            agreed.unwrap_or_default(),
+            tx_exit,
+        ))
+        .map_err(|_| ProtocolError::NoParentConnection)?;
+
+    let (probes_for_feedback, probes) = mpsc::unbounded();
+    let receiving = receiving(
+        receiver,
+        data_for_user,
+        probes_for_feedback,
+        heartbeat_config,
+        receive_config,
+        TokioClock,
+        metrics,
+    );
+    let heartbeat = feedback_sender(sender, heartbeat_config, probes, TokioClock);
+
+    debug!(target: "validator-network", "Starting worker for receiving from {}.", peer_id);
+    tokio::select! {
+        _ = heartbeat => Err(ProtocolError::CardiacArrest),
+        result = receiving => result,
+        _ = exit => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::ErrorKind, time::Duration};
+
+    use aleph_primitives::AuthorityId;
+    use codec::{Decode, Encode};
+    use futures::{
+        channel::{
+            mpsc,
+            mpsc::{Receiver, UnboundedReceiver},
+            oneshot,
+        },
+        pin_mut, poll,
+        task::Poll,
+        FutureExt, StreamExt,
+    };
+    use prometheus_endpoint::Registry;
+    use tokio::{
+        io::{AsyncRead, AsyncWrite},
+        time::timeout,
+    };
+
+    use super::{
+        agreed_codec, feedback_receiver, incoming, outgoing, receiving, sending,
+        without_unsupported_probes, Feedback, Message, MIN_PROBE_INTERVAL,
+    };
+    use crate::validator_network::{
+        clock::TokioClock,
+        handshake::{v0_handshake_outgoing, Capabilities, HandshakeConfig},
+        heartbeat::HeartbeatConfig,
+        io::{receive_data_with_codec, send_data_with_codec, Codec, ReceiveConfig, ReceiveError},
+        metrics::{ConnectionStats, Metrics},
+        mock::{
+            keys, CountingFlushes, FailingReads, LinkConfig, LossyMockSplittable, MockClock,
+            MockSplittable,
+        },
+        protocols::{IncomingResult, OutgoingResult, Protocol, ProtocolError},
+        rate_limit::RateLimit,
+        send_channel::{send_channel, OverflowPolicy, SendChannelConfig, SendChannelError},
+        Data, Splittable,
+    };
+
+    async fn prepare<D: Data>(
+        codec: Codec,
+    ) -> (
+        AuthorityId,
+        impl futures::Future<Output = Result<(), ProtocolError>>,
+        impl futures::Future<Output = Result<(), ProtocolError>>,
+        Receiver<D>,
+        UnboundedReceiver<IncomingResult>,
+        UnboundedReceiver<OutgoingResult<D>>,
+        oneshot::Sender<()>,
+    ) {
+        let (stream_incoming, stream_outgoing) = MockSplittable::new(4096);
+        let (id_incoming, pen_incoming) = keys().await;
+        let (id_outgoing, pen_outgoing) = keys().await;
+        assert_ne!(id_incoming, id_outgoing);
+        let (incoming_result_for_service, result_from_incoming) = mpsc::unbounded();
+        let (outgoing_result_for_service, result_from_outgoing) = mpsc::unbounded();
+        let (exit_for_outgoing, exit) = oneshot::channel();
+        let (data_for_user, data_from_incoming) =
+            mpsc::channel::<D>(ReceiveConfig::default().user_queue_capacity);
+        let incoming_handle = incoming(
+            stream_incoming,
+            pen_incoming,
+            incoming_result_for_service,
+            data_for_user,
+            HeartbeatConfig::default(),
+            HandshakeConfig::default(),
+            ReceiveConfig::default(),
+            Protocol::V1,
+            None,
+        );
+        let outgoing_handle = outgoing(
+            stream_outgoing,
+            pen_outgoing,
+            id_incoming,
+            outgoing_result_for_service,
+            exit,
+            HeartbeatConfig::default(),
+            HandshakeConfig::default(),
+            SendChannelConfig::default(),
+            codec,
+            Protocol::V1,
+            None,
+        );
+        (
+            id_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            data_from_incoming,
+            result_from_incoming,
+            result_from_outgoing,
+            exit_for_outgoing,
+        )
+    }
+
+    async fn check_send_data(codec: Codec) {
+        let (
+            _id_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            mut data_from_incoming,
+            _result_from_incoming,
+            mut result_from_outgoing,
+            _exit_for_outgoing,
+        ) = prepare::<Vec<i32>>(codec).await;
+        let incoming_handle = incoming_handle.fuse();
+        let outgoing_handle = outgoing_handle.fuse();
+        pin_mut!(incoming_handle);
+        pin_mut!(outgoing_handle);
+        let _data_for_outgoing = tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+            result = result_from_outgoing.next() => {
+                let (_, maybe_data_for_outgoing) = result.expect("outgoing should have returned Some");
+                let (_, data_for_outgoing) = maybe_data_for_outgoing.expect("successfully connected");
+                data_for_outgoing
+                    .send(vec![4, 3, 43])
+                    .expect("should send");
+                data_for_outgoing
+                    .send(vec![2, 1, 3, 7])
+                    .expect("should send");
+                data_for_outgoing
+            },
+        };
+        for expected in [vec![4, 3, 43], vec![2, 1, 3, 7]] {
+            tokio::select! {
+                _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+                _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+                v = data_from_incoming.next() => {
+                    assert_eq!(v, Some(expected));
+                },
+            };
+        }
+    }
+
+    #[tokio::test]
+    async fn send_data() {
+        check_send_data(Codec::Identity).await;
+    }
+
+    #[tokio::test]
+    async fn send_compressed_data() {
+        check_send_data(Codec::Lz4).await;
+        check_send_data(Codec::Zstd).await;
+    }
+
+    #[tokio::test]
+    async fn closed_by_parent_service() {
+        let (
+            id_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            _data_from_incoming,
+            mut result_from_incoming,
+            _result_from_outgoing,
+            _exit_for_outgoing,
+        ) = prepare::<Vec<i32>>(Codec::default()).await;
+        let incoming_handle = incoming_handle.fuse();
+        let outgoing_handle = outgoing_handle.fuse();
+        pin_mut!(incoming_handle);
+        pin_mut!(outgoing_handle);
+        tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+            received = result_from_incoming.next() => {
+                // we drop the exit oneshot channel, thus finishing incoming_handle
+                let (received_id, _, _, _) = received.expect("should receive");
+                assert_eq!(received_id, id_outgoing);
+            },
+        };
+        incoming_handle
+            .await
+            .expect("closed manually, should finish with no error");
+    }
+
+    #[tokio::test]
+    async fn outgoing_closed_by_parent_service() {
+        let (
+            _id_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            mut data_from_incoming,
+            _result_from_incoming,
+            mut result_from_outgoing,
+            exit_for_outgoing,
+        ) = prepare::<Vec<i32>>(Codec::default()).await;
+        let incoming_handle = incoming_handle.fuse();
+        let outgoing_handle = outgoing_handle.fuse();
+        pin_mut!(incoming_handle);
+        pin_mut!(outgoing_handle);
+        let _data_for_outgoing = tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+            result = result_from_outgoing.next() => {
+                let (_, maybe_data_for_outgoing) = result.expect("outgoing should have returned Some");
+                let (_, data_for_outgoing) = maybe_data_for_outgoing.expect("successfully connected");
+                data_for_outgoing
+                    .send(vec![4, 3, 43])
+                    .expect("should send");
+                data_for_outgoing
+                    .send(vec![2, 1, 3, 7])
+                    .expect("should send");
+                // the worker did not get a chance to send the data before being closed
+                exit_for_outgoing.send(()).expect("should send exit");
+                data_for_outgoing
+            },
+        };
+        tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            result = &mut outgoing_handle => result.expect("closed manually, should finish with no error"),
+        };
+        // the data queued before closing still arrives
+        for expected in [vec![4, 3, 43], vec![2, 1, 3, 7]] {
+            tokio::select! {
+                biased;
+                v = data_from_incoming.next() => assert_eq!(v, Some(expected)),
+                _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            };
+        }
+    }
+
+    #[tokio::test]
+    async fn parent_user_dead() {
+        let (
+            _id_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            data_from_incoming,
+            _result_from_incoming,
+            mut result_from_outgoing,
+            _exit_for_outgoing,
+        ) = prepare::<Vec<i32>>(Codec::default()).await;
+        std::mem::drop(data_from_incoming);
+        let incoming_handle = incoming_handle.fuse();
+        let outgoing_handle = outgoing_handle.fuse();
+        pin_mut!(incoming_handle);
+        pin_mut!(outgoing_handle);
+        let _data_for_outgoing = tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+            result = result_from_outgoing.next() => {
+                let (_, maybe_data_for_outgoing) = result.expect("outgoing should have returned Some");
+                let (_, data_for_outgoing) = maybe_data_for_outgoing.expect("successfully connected");
+                data_for_outgoing
+                    .send(vec![2, 1, 3, 7])
+                    .expect("should send");
+                data_for_outgoing
+            },
+        };
+        tokio::select! {
+            e = &mut incoming_handle => match e {
+                Err(ProtocolError::NoUserConnection) => (),
+                Err(e) => panic!("unexpected error: {}", e),
+                Ok(_) => panic!("successfully finished when user dead"),
+            },
+            _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+        };
+    }
+
+    #[tokio::test]
+    async fn sender_dead_after_handshake() {
+        let (
+            _id_outgoing,
+            incoming_handle,
+            outgoing_handle,
+            _data_from_incoming,
+            mut result_from_incoming,
+            _result_from_outgoing,
+            _exit_for_outgoing,
+        ) = prepare::<Vec<i32>>(Codec::default()).await;
+        let incoming_handle = incoming_handle.fuse();
+        pin_mut!(incoming_handle);
+        let (_, _, _, _exit) = tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            _ = outgoing_handle => panic!("outgoing process unexpectedly finished"),
+            out = result_from_incoming.next() => out.expect("should receive"),
+        };
+        // outgoing_handle got consumed by tokio::select!, the sender is dead
+        match incoming_handle.await {
+            Err(ProtocolError::ReceiveError(_)) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("successfully finished when connection dead"),
+        };
+    }
+
+    #[tokio::test]
+    async fn stalled_sender_detected() {
+        let (stream_incoming, stream_outgoing) = MockSplittable::new(4096);
+        let (id_incoming, pen_incoming) = keys().await;
+        let (_, pen_outgoing) = keys().await;
+        let (incoming_result_for_service, _result_from_incoming) = mpsc::unbounded();
+        let (data_for_user, _data_from_incoming) =
+            mpsc::channel::<Vec<i32>>(ReceiveConfig::default().user_queue_capacity);
+        let config = HeartbeatConfig {
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(50),
+            ..HeartbeatConfig::default()
+        };
+        let incoming_handle = incoming(
+            stream_incoming,
+            pen_incoming,
+            incoming_result_for_service,
+            data_for_user,
+            config,
+            HandshakeConfig::default(),
+            ReceiveConfig::default(),
+            Protocol::V1,
+            None,
+        );
+        pin_mut!(incoming_handle);
+        // the peer completes the handshake and then goes silent, while keeping the connection open
+        let _stalled = tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            result = v0_handshake_outgoing(stream_outgoing, pen_outgoing, id_incoming, false, HandshakeConfig::default()) => {
+                result.expect("handshake should succeed")
+            },
+        };
+        match incoming_handle.await {
+            Err(ProtocolError::CardiacArrest) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("successfully finished when peer stalled"),
+        };
+    }
+
+    #[tokio::test]
+    async fn alive_under_intermittent_traffic() {
+        let (stream_incoming, stream_outgoing) = MockSplittable::new(4096);
+        let (id_incoming, pen_incoming) = keys().await;
+        let (_, pen_outgoing) = keys().await;
+        let (incoming_result_for_service, _result_from_incoming) = mpsc::unbounded();
+        let (outgoing_result_for_service, mut result_from_outgoing) = mpsc::unbounded();
+        let (_exit_for_outgoing, exit) = oneshot::channel();
+        let (data_for_user, mut data_from_incoming) =
+            mpsc::channel::<Vec<i32>>(ReceiveConfig::default().user_queue_capacity);
+        let config = HeartbeatConfig {
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(200),
+            ..HeartbeatConfig::default()
+        };
+        let incoming_handle = incoming(
+            stream_incoming,
+            pen_incoming,
+            incoming_result_for_service,
+            data_for_user,
+            config,
+            HandshakeConfig::default(),
+            ReceiveConfig::default(),
+            Protocol::V1,
+            None,
+        )
+        .fuse();
+        let outgoing_handle = outgoing(
+            stream_outgoing,
+            pen_outgoing,
+            id_incoming,
+            outgoing_result_for_service,
+            exit,
+            config,
+            HandshakeConfig::default(),
+            SendChannelConfig::default(),
+            Codec::default(),
+            Protocol::V1,
+            None,
+        )
+        .fuse();
+        pin_mut!(incoming_handle);
+        pin_mut!(outgoing_handle);
+        let data_for_outgoing = tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+            result = result_from_outgoing.next() => {
+                let (_, maybe_data_for_outgoing) = result.expect("outgoing should have returned Some");
+                let (_, data_for_outgoing) = maybe_data_for_outgoing.expect("successfully connected");
+                data_for_outgoing
+            },
+        };
+        // the pauses between data are longer than the timeout, only heartbeats keep us alive
+        for data in [vec![1], vec![2, 3], vec![4, 5, 6]] {
+            tokio::select! {
+                _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+                _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+                _ = tokio::time::sleep(Duration::from_millis(500)) => (),
+            };
+            data_for_outgoing.send(data.clone()).expect("should send");
+            tokio::select! {
+                _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+                _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+                v = data_from_incoming.next() => assert_eq!(v, Some(data)),
+            };
+        }
+    }
+
+    #[tokio::test]
+    async fn send_buffer_overflow() {
+        let (stream_incoming, stream_outgoing) = MockSplittable::new(4096);
+        let (id_incoming, pen_incoming) = keys().await;
+        let (_, pen_outgoing) = keys().await;
+        let (incoming_result_for_service, _result_from_incoming) = mpsc::unbounded();
+        let (outgoing_result_for_service, mut result_from_outgoing) = mpsc::unbounded();
+        let (_exit_for_outgoing, exit) = oneshot::channel();
+        let (data_for_user, _data_from_incoming) =
+            mpsc::channel::<Vec<i32>>(ReceiveConfig::default().user_queue_capacity);
+        let incoming_handle = incoming(
+            stream_incoming,
+            pen_incoming,
+            incoming_result_for_service,
+            data_for_user,
+            HeartbeatConfig::default(),
+            HandshakeConfig::default(),
+            ReceiveConfig::default(),
+            Protocol::V1,
+            None,
+        )
+        .fuse();
+        let outgoing_handle = outgoing(
+            stream_outgoing,
+            pen_outgoing,
+            id_incoming,
+            outgoing_result_for_service,
+            exit,
+            HeartbeatConfig::default(),
+            HandshakeConfig::default(),
+            SendChannelConfig {
+                capacity: Some(2),
+                overflow_policy: OverflowPolicy::ReturnError,
+                ..SendChannelConfig::default()
+            },
+            Codec::default(),
+            Protocol::V1,
+            None,
+        )
+        .fuse();
+        pin_mut!(incoming_handle);
+        pin_mut!(outgoing_handle);
+        let data_for_outgoing = tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+            result = result_from_outgoing.next() => {
+                let (_, maybe_data_for_outgoing) = result.expect("outgoing should have returned Some");
+                let (_, data_for_outgoing) = maybe_data_for_outgoing.expect("successfully connected");
+                data_for_outgoing
+            },
+        };
+        // the worker does not get a chance to run in between, so the buffer fills up
+        data_for_outgoing.send(vec![1]).expect("should send");
+        data_for_outgoing.send(vec![2]).expect("should send");
+        assert_eq!(
+            data_for_outgoing.send(vec![3]),
+            Err(SendChannelError::Overflow)
+        );
+        tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            e = &mut outgoing_handle => match e {
+                Err(ProtocolError::SendBufferOverflow) => (),
+                Err(e) => panic!("unexpected error: {}", e),
+                Ok(_) => panic!("successfully finished despite overflow"),
+            },
+        };
+    }
+
+    #[tokio::test]
+    async fn metrics_count_messages() {
+        let (stream_incoming, stream_outgoing) = MockSplittable::new(4096);
+        let (id_incoming, pen_incoming) = keys().await;
+        let (_, pen_outgoing) = keys().await;
+        let (incoming_result_for_service, _result_from_incoming) = mpsc::unbounded();
+        let (outgoing_result_for_service, mut result_from_outgoing) = mpsc::unbounded();
+        let (_exit_for_outgoing, exit) = oneshot::channel();
+        let (data_for_user, mut data_from_incoming) =
+            mpsc::channel::<Vec<i32>>(ReceiveConfig::default().user_queue_capacity);
+        let incoming_metrics =
+            Metrics::register(&Registry::new()).expect("should register metrics");
+        let outgoing_metrics =
+            Metrics::register(&Registry::new()).expect("should register metrics");
+        let incoming_handle = incoming(
+            stream_incoming,
+            pen_incoming,
+            incoming_result_for_service,
+            data_for_user,
+            HeartbeatConfig::default(),
+            HandshakeConfig::default(),
+            ReceiveConfig::default(),
+            Protocol::V1,
+            Some(incoming_metrics.clone()),
+        )
+        .fuse();
+        let outgoing_handle = outgoing(
+            stream_outgoing,
+            pen_outgoing,
+            id_incoming,
+            outgoing_result_for_service,
+            exit,
+            HeartbeatConfig::default(),
+            HandshakeConfig::default(),
+            SendChannelConfig::default(),
+            Codec::Lz4,
+            Protocol::V1,
+            Some(outgoing_metrics.clone()),
+        )
+        .fuse();
+        pin_mut!(incoming_handle);
+        pin_mut!(outgoing_handle);
+        let data: Vec<Vec<i32>> = (0..7).map(|i| (0..i).collect()).collect();
+        let _data_for_outgoing = tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+            result = result_from_outgoing.next() => {
+                let (_, maybe_data_for_outgoing) = result.expect("outgoing should have returned Some");
+                let (_, data_for_outgoing) = maybe_data_for_outgoing.expect("successfully connected");
+                for item in &data {
+                    data_for_outgoing.send(item.clone()).expect("should send");
+                }
+                data_for_outgoing
+            },
+        };
+        for expected in &data {
+            tokio::select! {
+                _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+                _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+                v = data_from_incoming.next() => assert_eq!(v.as_ref(), Some(expected)),
+            };
+        }
+        let total_bytes: u64 = data.iter().map(|item| item.encoded_size() as u64).sum();
+        assert_eq!(outgoing_metrics.messages_sent(), 7);
+        assert_eq!(outgoing_metrics.bytes_sent(), total_bytes);
+        assert_eq!(incoming_metrics.messages_received(), 7);
+        assert_eq!(incoming_metrics.bytes_received(), total_bytes);
+        assert_eq!(outgoing_metrics.messages_received(), 0);
+        assert_eq!(incoming_metrics.messages_sent(), 0);
+    }
+
+    #[tokio::test]
+    async fn probe_measures_round_trip() {
+        let (stream_incoming, stream_outgoing) = MockSplittable::new(4096);
+        let (id_incoming, pen_incoming) = keys().await;
+        let (_, pen_outgoing) = keys().await;
+        let (incoming_result_for_service, _result_from_incoming) = mpsc::unbounded();
+        let (outgoing_result_for_service, mut result_from_outgoing) = mpsc::unbounded();
+        let (_exit_for_outgoing, exit) = oneshot::channel();
+        let (data_for_user, mut data_from_incoming) =
+            mpsc::channel::<Vec<i32>>(ReceiveConfig::default().user_queue_capacity);
+        let config = HeartbeatConfig {
+            probe_interval: Some(MIN_PROBE_INTERVAL),
+            ..HeartbeatConfig::default()
+        };
+        let stats = ConnectionStats::new();
+        let incoming_handle = incoming(
+            stream_incoming,
+            pen_incoming,
+            incoming_result_for_service,
+            data_for_user,
+            config,
+            HandshakeConfig::default(),
+            ReceiveConfig::default(),
+            Protocol::V2,
+            None,
+        )
+        .fuse();
+        let outgoing_handle = outgoing(
+            stream_outgoing,
+            pen_outgoing,
+            id_incoming,
+            outgoing_result_for_service,
+            exit,
+            config,
+            HandshakeConfig::default(),
+            SendChannelConfig::default(),
+            Codec::default(),
+            Protocol::V2,
+            Some(Metrics::for_connection(&None, stats.clone())),
+        )
+        .fuse();
+        pin_mut!(incoming_handle);
+        pin_mut!(outgoing_handle);
+        let data_for_outgoing = tokio::select! {
+            _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+            _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+            result = result_from_outgoing.next() => {
+                let (_, maybe_data_for_outgoing) = result.expect("outgoing should have returned Some");
+                let (_, data_for_outgoing) = maybe_data_for_outgoing.expect("successfully connected");
+                data_for_outgoing
+            },
+        };
+        let start = std::time::Instant::now();
+        let round_trip = loop {
+            if let Some(round_trip) = stats.snapshot().last_round_trip {
+                break round_trip;
+            }
+            assert!(
+                start.elapsed() < 5 * MIN_PROBE_INTERVAL,
+                "no probe ack arrived"
+            );
+            // the probes do not get in the way of the data
+            data_for_outgoing.send(vec![4, 3]).expect("should send");
+            tokio::select! {
+                _ = &mut incoming_handle => panic!("incoming process unexpectedly finished"),
+                _ = &mut outgoing_handle => panic!("outgoing process unexpectedly finished"),
+                v = data_from_incoming.next() => assert_eq!(v, Some(vec![4, 3])),
+            };
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+        assert!(
+            round_trip < Duration::from_millis(500),
+            "implausible round trip over a local connection: {:?}",
+            round_trip
+        );
+        assert!(start.elapsed() >= round_trip);
+    }
+
+    #[test]
+    fn heartbeat_feedback_encoded_as_plain_heartbeat() {
+        assert_eq!(Feedback::Heartbeat.encode(), 43u32.encode());
+        let ack = Feedback::ProbeAck(43);
+        let decoded = Feedback::decode(&mut ack.encode().as_slice()).expect("should decode");
+        assert_eq!(decoded, ack);
+    }
+
+    /// Keeps sending only heartbeats, never any data.
+    async fn send_heartbeats<S: AsyncWrite + Unpin + Send>(mut sender: S) {
+        loop {
+            sender = match send_data_with_codec(
+                sender,
+                Message::<Vec<i32>>::Heartbeat,
+                Codec::Identity,
+                None,
+            )
+            .await
+            {
+                Ok(sender) => sender,
+                Err(_) => return,
+            };
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    async fn receive_heartbeats_only(
+        idle_timeout: Option<Duration>,
+        wait: Duration,
+    ) -> Option<Result<(), ProtocolError>> {
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        let (_, receiver) = stream_a.split();
+        let (sender, _receiver_b) = stream_b.split();
+        let (data_for_user, _data_from_receiving) =
+            mpsc::channel::<Vec<i32>>(ReceiveConfig::default().user_queue_capacity);
+        let receive_config = ReceiveConfig {
+            idle_timeout,
+            ..ReceiveConfig::default()
+        };
+        tokio::spawn(send_heartbeats(sender));
+        timeout(
+            wait,
+            receiving(
+                receiver,
+                data_for_user,
+                mpsc::unbounded().0,
+                HeartbeatConfig::default(),
+                receive_config,
+                TokioClock,
+                None,
+            ),
+        )
+        .await
+        .ok()
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_fires_despite_heartbeats() {
+        let result =
+            receive_heartbeats_only(Some(Duration::from_millis(200)), Duration::from_secs(5))
+                .await
+                .expect("should stop before the wait is over");
+        assert!(matches!(result, Err(ProtocolError::IdleTimeout)));
+    }
+
+    #[tokio::test]
+    async fn cardiac_arrest_under_sustained_loss() {
+        let lossy = LinkConfig {
+            latency: Duration::from_millis(5),
+            drop_probability: 1.0,
+        };
+        let (stream_a, stream_b) = LossyMockSplittable::new(4096, LinkConfig::clean(), lossy, 43);
+        let (_, receiver) = stream_a.split();
+        let (sender, _receiver_b) = stream_b.split();
+        let (data_for_user, _data_from_receiving) =
+            mpsc::channel::<Vec<i32>>(ReceiveConfig::default().user_queue_capacity);
+        let heartbeat_config = HeartbeatConfig {
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_millis(200),
+            ..HeartbeatConfig::default()
+        };
+        tokio::spawn(send_heartbeats(sender));
+        let result = timeout(
+            Duration::from_secs(5),
+            receiving(
+                receiver,
+                data_for_user,
+                mpsc::unbounded().0,
+                heartbeat_config,
+                ReceiveConfig::default(),
+                TokioClock,
+                None,
+            ),
+        )
+        .await
+        .expect("should stop before the wait is over");
+        assert!(matches!(result, Err(ProtocolError::CardiacArrest)));
+    }
+
+    #[tokio::test]
+    async fn cardiac_arrest_measured_by_clock() {
+        let (stream, _stalled) = MockSplittable::new(4096);
+        let (_, receiver) = stream.split();
+        let (data_for_user, _data_from_receiving) =
+            mpsc::channel::<Vec<i32>>(ReceiveConfig::default().user_queue_capacity);
+        let heartbeat_config = HeartbeatConfig::default();
+        let clock = MockClock::new();
+        let receiving = receiving(
+            receiver,
+            data_for_user,
+            mpsc::unbounded().0,
+            heartbeat_config,
+            ReceiveConfig::default(),
+            clock.clone(),
+            None,
+        );
+        pin_mut!(receiving);
+        assert!(poll!(&mut receiving).is_pending());
+        clock.advance(heartbeat_config.initial_timeout() - Duration::from_millis(1));
+        assert!(poll!(&mut receiving).is_pending());
+        clock.advance(Duration::from_millis(1));
+        assert!(matches!(
+            poll!(&mut receiving),
+            Poll::Ready(Err(ProtocolError::CardiacArrest))
+        ));
+    }
+
+    #[tokio::test]
+    async fn feedback_cardiac_arrest_measured_by_clock() {
+        let (stream, _stalled) = MockSplittable::new(4096);
+        let (_, receiver) = stream.split();
+        let heartbeat_config = HeartbeatConfig::default();
+        let clock = MockClock::new();
+        let feedback = feedback_receiver(
+            receiver,
+            heartbeat_config,
+            mpsc::unbounded().0,
+            clock.clone(),
+        );
+        pin_mut!(feedback);
+        assert!(poll!(&mut feedback).is_pending());
+        clock.advance(heartbeat_config.initial_timeout() - Duration::from_millis(1));
+        assert!(poll!(&mut feedback).is_pending());
+        clock.advance(Duration::from_millis(1));
+        assert!(poll!(&mut feedback).is_ready());
+    }
+
+    #[test]
+    fn probes_only_peers_understanding_them() {
+        let config = HeartbeatConfig {
+            probe_interval: Some(MIN_PROBE_INTERVAL),
+            ..HeartbeatConfig::default()
+        };
+        assert_eq!(
+            without_unsupported_probes(config, Some(Capabilities::supported())),
+            config
+        );
+        assert_eq!(
+            without_unsupported_probes(config, Some(Capabilities::COMPRESSION)).probe_interval,
+            None
+        );
+        assert_eq!(
+            without_unsupported_probes(config, None).probe_interval,
+            None
+        );
+    }
+
+    #[test]
+    fn compresses_only_for_peers_agreeing_on_it() {
+        let codec = Codec::Zstd;
+        assert_eq!(agreed_codec(codec, Some(Capabilities::supported())), codec);
+        assert_eq!(
+            agreed_codec(codec, Some(Capabilities::PROBES)),
+            Codec::Identity
+        );
+        // peers not exchanging capabilities only announce the codecs they support
+        assert_eq!(agreed_codec(codec, None), codec);
+    }
+
+    #[tokio::test]
+    async fn no_idle_timeout_by_default() {
+        assert!(
+            receive_heartbeats_only(None, Duration::from_millis(500))
+                .await
+                .is_none(),
+            "should keep receiving heartbeats"
+        );
+    }
+
+    /// Sends the data and then receives with the given reads failing first, returning what was
+    /// received and the result of receiving, if it finished.
+    async fn receive_with_failures(
+        failures: Vec<ErrorKind>,
+    ) -> (Option<Vec<i32>>, Option<Result<(), ProtocolError>>) {
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        let (_, receiver) = stream_a.split();
+        let (sender, _receiver_b) = stream_b.split();
+        let (data_for_user, mut data_from_receiving) =
+            mpsc::channel::<Vec<i32>>(ReceiveConfig::default().user_queue_capacity);
+        let _sender =
+            send_data_with_codec(sender, Message::Data(vec![4, 3]), Codec::Identity, None)
+                .await
+                .expect("should send");
+        let receiving = receiving(
+            FailingReads::new(receiver, failures),
+            data_for_user,
+            mpsc::unbounded().0,
+            HeartbeatConfig::default(),
+            ReceiveConfig::default(),
+            TokioClock,
+            None,
+        )
+        .fuse();
+        pin_mut!(receiving);
+        tokio::select! {
+            result = &mut receiving => (data_from_receiving.next().await, Some(result)),
+            received = data_from_receiving.next() => (received, None),
+        }
+    }
+
+    #[tokio::test]
+    async fn recovers_from_transient_receive_errors() {
+        let (received, result) =
+            receive_with_failures(vec![ErrorKind::Interrupted, ErrorKind::WouldBlock]).await;
+        assert_eq!(received, Some(vec![4, 3]));
+        assert!(result.is_none(), "should keep receiving");
+    }
+
+    #[tokio::test]
+    async fn fatal_receive_error_tears_down_immediately() {
+        let (received, result) = receive_with_failures(vec![ErrorKind::ConnectionReset]).await;
+        assert!(received.is_none());
+        assert!(matches!(
+            result,
+            Some(Err(ProtocolError::ReceiveError(ReceiveError::Error(_))))
+        ));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_too_many_transient_errors() {
+        let retries = ReceiveConfig::default().transient_retries as usize;
+        let (received, result) =
+            receive_with_failures(vec![ErrorKind::Interrupted; retries + 1]).await;
+        assert!(received.is_none());
+        assert!(matches!(
+            result,
+            Some(Err(ProtocolError::ReceiveError(ReceiveError::Interrupted(
+                _
+            ))))
+        ));
+    }
+
+    #[tokio::test]
+    async fn stops_reading_when_user_not_draining() {
+        const CAPACITY: usize = 2;
+        const MESSAGES: i32 = 10;
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        let (_, receiver) = stream_a.split();
+        let (mut sender, _receiver_b) = stream_b.split();
+        for i in 0..MESSAGES {
+            sender = send_data_with_codec(sender, Message::Data(vec![i]), Codec::Identity, None)
+                .await
+                .expect("should send");
+        }
+        let (data_for_user, mut data_from_receiving) = mpsc::channel::<Vec<i32>>(CAPACITY);
+        let metrics = Metrics::register(&Registry::new()).expect("should register metrics");
+        tokio::spawn(receiving(
+            receiver,
+            data_for_user,
+            mpsc::unbounded().0,
+            HeartbeatConfig::default(),
+            ReceiveConfig::default(),
+            TokioClock,
+            Some(metrics.clone()),
+        ));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        // the queue takes one message more for the single sender, the next one is waiting for room
+        // and the one after it stopped the reading
+        assert_eq!(metrics.messages_received(), CAPACITY as u64 + 3);
+        assert_eq!(metrics.stalled_by_consumer(), 1);
+
+        for i in 0..MESSAGES {
+            let data = timeout(Duration::from_secs(5), data_from_receiving.next())
+                .await
+                .expect("should resume reading");
+            assert_eq!(data, Some(vec![i]));
+        }
+        assert_eq!(metrics.messages_received(), MESSAGES as u64);
+    }
+
+    #[tokio::test]
+    async fn handles_probes_while_waiting_for_room() {
+        const CAPACITY: usize = 2;
+        const NONCE: u64 = 43;
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        let (_, receiver) = stream_a.split();
+        let (mut sender, _receiver_b) = stream_b.split();
+        // fills the queue and leaves one message waiting for room
+        for i in 0..CAPACITY as i32 + 2 {
+            sender = send_data_with_codec(sender, Message::Data(vec![i]), Codec::Identity, None)
+                .await
+                .expect("should send");
+        }
+        let _sender = send_data_with_codec(
+            sender,
+            Message::<Vec<i32>>::Probe(NONCE),
+            Codec::Identity,
+            None,
+        )
+        .await
+        .expect("should send");
+        let (data_for_user, mut data_from_receiving) = mpsc::channel::<Vec<i32>>(CAPACITY);
+        let (probes_for_feedback, mut probes) = mpsc::unbounded();
+        tokio::spawn(receiving(
+            receiver,
+            data_for_user,
+            probes_for_feedback,
+            HeartbeatConfig::default(),
+            ReceiveConfig::default(),
+            TokioClock,
+            None,
+        ));
+        let probe = timeout(Duration::from_secs(5), probes.next())
+            .await
+            .expect("should handle the probe without the user taking anything");
+        assert_eq!(probe, Some(NONCE));
+
+        for i in 0..CAPACITY as i32 + 2 {
+            let data = timeout(Duration::from_secs(5), data_from_receiving.next())
+                .await
+                .expect("should pass the waiting data");
+            assert_eq!(data, Some(vec![i]));
+        }
+    }
+
+    /// Counts the data messages arriving until none arrives for a while, or the stream closes.
+    async fn count_received<R: AsyncRead + Unpin>(receiver: &mut R) -> usize {
+        let mut received = 0;
+        while let Ok(Ok((_, message))) = timeout(
+            Duration::from_millis(100),
+            receive_data_with_codec::<_, Message<Vec<u8>>>(
+                &mut *receiver,
+                ReceiveConfig::default().max_frame_size,
+            ),
+        )
+        .await
+        {
+            if let Message::Data(_) = message {
+                received += 1;
+            }
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn sending_is_paced_by_rate_limit() {
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        let (sender, _) = stream_a.split();
+        let (_, mut receiver) = stream_b.split();
+        let (data_for_network, data_from_user) =
+            send_channel::<Vec<u8>>(SendChannelConfig::default());
+        let (_exit_for_sending, exit) = oneshot::channel();
+        let data = vec![43; 10_000];
+        for _ in 0..10 {
+            data_for_network.send(data.clone()).expect("should send");
+        }
+        // two and a half messages per second
+        let size = Message::Data(data).encoded_size() as u64;
+        let clock = MockClock::new();
+        let _sending = tokio::spawn(sending(
+            sender,
+            data_from_user,
+            exit,
+            mpsc::unbounded().1,
+            HeartbeatConfig::default(),
+            Codec::Identity,
+            None,
+            Some(RateLimit {
+                bytes_per_second: 2 * size + size / 2,
+            }),
+            None,
+            clock.clone(),
+            None,
+        ));
+
+        // a second worth of data goes through right away, the third message owes 200ms
+        assert_eq!(count_received(&mut receiver).await, 2);
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(count_received(&mut receiver).await, 0);
+        clock.advance(Duration::from_millis(200));
+        assert_eq!(count_received(&mut receiver).await, 1);
+    }
+
+    #[tokio::test]
+    async fn exits_without_waiting_for_rate_limit() {
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        let (sender, _) = stream_a.split();
+        let (_, mut receiver) = stream_b.split();
+        let (data_for_network, data_from_user) =
+            send_channel::<Vec<u8>>(SendChannelConfig::default());
+        let (exit_for_sending, exit) = oneshot::channel();
+        for _ in 0..5 {
+            data_for_network.send(vec![43; 100]).expect("should send");
+        }
+        let sending = tokio::spawn(sending(
+            sender,
+            data_from_user,
+            exit,
+            mpsc::unbounded().1,
+            HeartbeatConfig::default(),
+            Codec::Identity,
+            None,
+            Some(RateLimit {
+                bytes_per_second: 100,
+            }),
+            None,
+            MockClock::new(),
+            None,
+        ));
+        assert_eq!(count_received(&mut receiver).await, 0);
+
+        // the clock never moves, yet all the queued data gets flushed
+        exit_for_sending.send(()).expect("should send");
+        let result = timeout(Duration::from_secs(5), sending)
+            .await
+            .expect("should exit")
+            .expect("should not panic");
+        assert!(result.is_ok(), "sending failed: {:?}", result);
+        assert_eq!(count_received(&mut receiver).await, 5);
+    }
+
+    #[tokio::test]
+    async fn coalesces_queued_messages_into_one_write() {
+        const MESSAGES: u8 = 5;
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        let (sender, _) = stream_a.split();
+        let (_, mut receiver) = stream_b.split();
+        let sender = CountingFlushes::new(sender);
+        let flushes = sender.flushes();
+        let (data_for_network, data_from_user) =
+            send_channel::<Vec<u8>>(SendChannelConfig::default());
+        let (_exit_for_sending, exit) = oneshot::channel();
+        for i in 0..MESSAGES {
+            data_for_network.send(vec![i; 10]).expect("should send");
+        }
+        // closing the channel makes sending finish right after writing what was queued
+        drop(data_for_network);
+        let result = timeout(
+            Duration::from_secs(5),
+            sending(
+                sender,
+                data_from_user,
+                exit,
+                mpsc::unbounded().1,
+                HeartbeatConfig::default(),
+                Codec::Identity,
+                None,
+                None,
+                Some(Duration::from_millis(50)),
+                TokioClock,
+                None,
+            ),
+        )
+        .await
+        .expect("should finish");
+        assert!(result.is_ok(), "sending failed: {:?}", result);
+        assert_eq!(*flushes.lock(), 1);
+        for i in 0..MESSAGES {
+            let (_, message) = receive_data_with_codec::<_, Message<Vec<u8>>>(
+                &mut receiver,
+                ReceiveConfig::default().max_frame_size,
+            )
+            .await
+            .expect("should receive");
+            assert!(matches!(message, Message::Data(data) if data == vec![i; 10]));
+        }
+    }
+
+    #[tokio::test]
+    async fn exits_without_waiting_for_coalescing_window() {
+        let (stream_a, stream_b) = MockSplittable::new(4096);
+        let (sender, _) = stream_a.split();
+        let (_, mut receiver) = stream_b.split();
+        let (data_for_network, data_from_user) =
+            send_channel::<Vec<u8>>(SendChannelConfig::default());
+        let (exit_for_sending, exit) = oneshot::channel();
+        data_for_network.send(vec![43; 10]).expect("should send");
+        let sending = tokio::spawn(sending(
+            sender,
+            data_from_user,
+            exit,
+            mpsc::unbounded().1,
+            HeartbeatConfig::default(),
+            Codec::Identity,
+            None,
+            None,
+            Some(Duration::from_secs(60)),
+            MockClock::new(),
+            None,
+        ));
+        assert_eq!(count_received(&mut receiver).await, 0);
+
+        // the clock never moves, yet the data gathered so far gets written
+        exit_for_sending.send(()).expect("should send");
+        let result = timeout(Duration::from_secs(5), sending)
+            .await
+            .expect("should exit")
+            .expect("should not panic");
+        assert!(result.is_ok(), "sending failed: {:?}", result);
+        assert_eq!(count_received(&mut receiver).await, 1);
+        drop(data_for_network);
+    }
+}
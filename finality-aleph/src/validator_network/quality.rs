@@ -0,0 +1,171 @@
+use tokio::time::Duration;
+
+/// Round trips at least this long add nothing to the score.
+const MAX_ROUND_TRIP: Duration = Duration::from_secs(1);
+/// Heartbeats this many intervals late add nothing to the score.
+const MAX_HEARTBEAT_DELAY: u32 = 4;
+/// Carrying at least this many bytes per second adds all the throughput weight to the score.
+const GOOD_THROUGHPUT: f64 = 64.0 * 1024.0;
+
+// The weights of the parts of the score, summing up to the maximal score.
+const ROUND_TRIP_WEIGHT: f64 = 40.0;
+const HEARTBEAT_WEIGHT: f64 = 30.0;
+const FAILURES_WEIGHT: f64 = 20.0;
+const THROUGHPUT_WEIGHT: f64 = 10.0;
+
+/// What we measured about the connections with a peer, judging how healthy they are.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConnectionQuality {
+    /// The last round trip time measured by a probe, if any came back.
+    pub round_trip: Option<Duration>,
+    /// How long ago the last heartbeat was received, if there was any.
+    pub since_heartbeat: Option<Duration>,
+    /// How often the heartbeats should come.
+    pub heartbeat_interval: Duration,
+    /// Consecutive failed attempts to connect to the peer.
+    pub failures: u32,
+    /// Bytes per second recently carried in both directions.
+    pub throughput: f64,
+}
+
+impl ConnectionQuality {
+    /// A score between 0 and 100, higher for healthier connections. It is made up of:
+    /// 1. Up to 40 points for the round trip, linearly fewer the longer it is, none from a second
+    ///    on. An unknown round trip, as with peers that do not agree on probes or before the first
+    ///    probe comes back, is worth half of them.
+    /// 2. Up to 30 points for the heartbeats, all of them while they are at most an interval late,
+    ///    linearly fewer until they are four intervals late, that is five intervals since the
+    ///    last one. No heartbeats at all are worth none.
+    /// 3. Up to 20 points for the failures, halved with the first one and divided further by
+    ///    every next one.
+    /// 4. Up to 10 points for the throughput, linearly more up to 64KiB per second, so that busy
+    ///    connections are preferred only slightly over idle ones.
+    pub fn quality_score(&self) -> u8 {
+        let round_trip = match self.round_trip {
+            Some(round_trip) => 1.0 - ratio(round_trip, MAX_ROUND_TRIP),
+            None => 0.5,
+        };
+        let heartbeat = match self.since_heartbeat {
+            Some(since_heartbeat) => {
+                let late = since_heartbeat.saturating_sub(self.heartbeat_interval);
+                1.0 - ratio(late, self.heartbeat_interval * MAX_HEARTBEAT_DELAY)
+            }
+            None => 0.0,
+        };
+        let failures = 1.0 / (1.0 + self.failures as f64);
+        let throughput = (self.throughput / GOOD_THROUGHPUT).clamp(0.0, 1.0);
+        let score = ROUND_TRIP_WEIGHT * round_trip
+            + HEARTBEAT_WEIGHT * heartbeat
+            + FAILURES_WEIGHT * failures
+            + THROUGHPUT_WEIGHT * throughput;
+        score.round().clamp(0.0, 100.0) as u8
+    }
+}
+
+/// How big the part is compared to the whole, at most one.
+fn ratio(part: Duration, whole: Duration) -> f64 {
+    match whole.is_zero() {
+        true => 1.0,
+        false => (part.as_secs_f64() / whole.as_secs_f64()).min(1.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::Duration;
+
+    use super::ConnectionQuality;
+
+    fn healthy() -> ConnectionQuality {
+        ConnectionQuality {
+            round_trip: Some(Duration::from_millis(10)),
+            since_heartbeat: Some(Duration::from_secs(1)),
+            heartbeat_interval: Duration::from_secs(5),
+            failures: 0,
+            throughput: 1024.0 * 1024.0,
+        }
+    }
+
+    #[test]
+    fn healthy_connection_scores_almost_full() {
+        assert!(healthy().quality_score() >= 99);
+    }
+
+    #[test]
+    fn score_orders_connections_by_health() {
+        let score = healthy().quality_score();
+        let slow = ConnectionQuality {
+            round_trip: Some(Duration::from_millis(500)),
+            ..healthy()
+        };
+        let slower = ConnectionQuality {
+            round_trip: Some(Duration::from_secs(2)),
+            ..healthy()
+        };
+        assert!(slow.quality_score() < score);
+        assert!(slower.quality_score() < slow.quality_score());
+        let unprobed = ConnectionQuality {
+            round_trip: None,
+            ..healthy()
+        };
+        assert!(unprobed.quality_score() < score);
+        assert!(slower.quality_score() < unprobed.quality_score());
+        let late_heartbeats = ConnectionQuality {
+            since_heartbeat: Some(Duration::from_secs(12)),
+            ..healthy()
+        };
+        let dead_heartbeats = ConnectionQuality {
+            since_heartbeat: None,
+            ..healthy()
+        };
+        assert!(late_heartbeats.quality_score() < score);
+        assert!(dead_heartbeats.quality_score() < late_heartbeats.quality_score());
+        let failing = ConnectionQuality {
+            failures: 1,
+            ..healthy()
+        };
+        let failing_more = ConnectionQuality {
+            failures: 5,
+            ..healthy()
+        };
+        assert!(failing.quality_score() < score);
+        assert!(failing_more.quality_score() < failing.quality_score());
+        let idle = ConnectionQuality {
+            throughput: 0.0,
+            ..healthy()
+        };
+        assert!(idle.quality_score() < score);
+        // idling is not as bad as being slow
+        assert!(slow.quality_score() < idle.quality_score());
+    }
+
+    #[test]
+    fn heartbeats_four_intervals_late_score_nothing() {
+        let heartbeat_points = |since_heartbeat| {
+            let with_heartbeats = ConnectionQuality {
+                since_heartbeat: Some(since_heartbeat),
+                ..healthy()
+            };
+            let without_heartbeats = ConnectionQuality {
+                since_heartbeat: None,
+                ..healthy()
+            };
+            with_heartbeats.quality_score() - without_heartbeats.quality_score()
+        };
+        assert_eq!(heartbeat_points(Duration::from_secs(5)), 30);
+        assert!(heartbeat_points(Duration::from_secs(20)) > 0);
+        assert_eq!(heartbeat_points(Duration::from_secs(25)), 0);
+    }
+
+    #[test]
+    fn dead_connection_scores_nothing() {
+        let dead = ConnectionQuality {
+            round_trip: Some(Duration::from_secs(3)),
+            since_heartbeat: None,
+            heartbeat_interval: Duration::from_secs(5),
+            failures: u32::MAX,
+            throughput: 0.0,
+        };
+        assert_eq!(dead.quality_score(), 0);
+    }
+}
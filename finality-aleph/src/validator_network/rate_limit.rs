@@ -0,0 +1,145 @@
+use std::time::Instant;
+
+use futures::channel::oneshot;
+use tokio::time::Duration;
+
+use crate::validator_network::clock::Clock;
+
+/// A limit on how fast data can flow through a single connection in one direction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    /// How many bytes can flow per second on average. Up to a second worth of bytes can flow
+    /// at once after a quiet period.
+    pub bytes_per_second: u64,
+}
+
+/// A token bucket, refilled according to the limit, and holding at most a second worth of bytes.
+/// Transfers bigger than what is available are allowed, but put the bucket into debt that has to
+/// be waited out.
+struct TokenBucket {
+    rate: f64,
+    available: f64,
+    last_update: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        // A zero rate would stop all the traffic for good.
+        let rate = limit.bytes_per_second.max(1) as f64;
+        TokenBucket {
+            rate,
+            available: rate,
+            last_update: now,
+        }
+    }
+
+    /// Takes the bytes from the bucket, returning how long to wait before transferring them.
+    fn take_at(&mut self, bytes: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last_update);
+        self.last_update = now;
+        self.available = (self.available + elapsed.as_secs_f64() * self.rate).min(self.rate);
+        self.available -= bytes as f64;
+        match self.available < 0.0 {
+            true => Duration::from_secs_f64(-self.available / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+/// Paces the data flowing through a connection, does nothing if there is no limit.
+pub struct RateLimiter<C: Clock> {
+    bucket: Option<TokenBucket>,
+    clock: C,
+}
+
+impl<C: Clock> RateLimiter<C> {
+    pub fn new(limit: Option<RateLimit>, clock: C) -> Self {
+        RateLimiter {
+            bucket: limit.map(|limit| TokenBucket::new(limit, clock.now())),
+            clock,
+        }
+    }
+
+    /// Waits until the bytes can be transferred without exceeding the limit.
+    pub async fn take(&mut self, bytes: usize) {
+        if let Some(bucket) = &mut self.bucket {
+            let delay = bucket.take_at(bytes, self.clock.now());
+            if !delay.is_zero() {
+                self.clock.sleep(delay).await;
+            }
+        }
+    }
+
+    /// Waits like `take`, but stops waiting as soon as the exit fires. Returns whether it did.
+    pub async fn take_or_exit(&mut self, bytes: usize, exit: &mut oneshot::Receiver<()>) -> bool {
+        tokio::select! {
+            _ = self.take(bytes) => false,
+            _ = exit => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use futures::{channel::oneshot, pin_mut, poll};
+    use tokio::time::Duration;
+
+    use super::{RateLimit, RateLimiter, TokenBucket};
+    use crate::validator_network::mock::MockClock;
+
+    const LIMIT: RateLimit = RateLimit {
+        bytes_per_second: 1000,
+    };
+
+    #[test]
+    fn lets_burst_through_immediately() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(LIMIT, now);
+        assert_eq!(bucket.take_at(600, now), Duration::ZERO);
+        assert_eq!(bucket.take_at(400, now), Duration::ZERO);
+        assert_eq!(bucket.take_at(500, now), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn refills_at_rate_up_to_a_second_worth() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(LIMIT, now);
+        assert_eq!(bucket.take_at(1000, now), Duration::ZERO);
+        let later = now + Duration::from_millis(300);
+        assert_eq!(bucket.take_at(300, later), Duration::ZERO);
+        assert_eq!(bucket.take_at(100, later), Duration::from_millis(100));
+        // a long pause does not let more than a second worth through at once
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(bucket.take_at(1000, much_later), Duration::ZERO);
+        assert_eq!(bucket.take_at(1, much_later), Duration::from_millis(1));
+    }
+
+    #[tokio::test]
+    async fn waits_on_the_clock() {
+        let clock = MockClock::new();
+        let mut limiter = RateLimiter::new(Some(LIMIT), clock.clone());
+        limiter.take(1000).await;
+        let take = limiter.take(500);
+        pin_mut!(take);
+        assert!(poll!(&mut take).is_pending());
+        clock.advance(Duration::from_millis(499));
+        assert!(poll!(&mut take).is_pending());
+        clock.advance(Duration::from_millis(1));
+        assert!(poll!(&mut take).is_ready());
+    }
+
+    #[tokio::test]
+    async fn stops_waiting_on_exit() {
+        let clock = MockClock::new();
+        let mut limiter = RateLimiter::new(Some(LIMIT), clock);
+        let (exit_for_limiter, mut exit) = oneshot::channel();
+        assert!(!limiter.take_or_exit(1000, &mut exit).await);
+        let take = limiter.take_or_exit(500, &mut exit);
+        pin_mut!(take);
+        assert!(poll!(&mut take).is_pending());
+        exit_for_limiter.send(()).expect("should send");
+        assert_eq!(poll!(&mut take), std::task::Poll::Ready(true));
+    }
+}
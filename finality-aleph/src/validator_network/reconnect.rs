@@ -0,0 +1,269 @@
+use std::time::Instant;
+
+use rand::{thread_rng, Rng};
+use tokio::time::Duration;
+
+/// How long we wait before trying to reestablish a failed outgoing connection, and how long we
+/// keep even a healthy one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReconnectPolicy {
+    /// Delay after the first failure.
+    pub initial_delay: Duration,
+    /// The delay never grows above this.
+    pub max_delay: Duration,
+    /// By how much the delay grows after every consecutive failure.
+    pub multiplier: f64,
+    /// The delay is randomly changed by up to this fraction, so that peers do not reconnect all
+    /// at once after a network failure.
+    pub jitter: f64,
+    /// After a connection stayed up this long it is considered healthy, and its failure is
+    /// treated as the first one.
+    pub stable_duration: Duration,
+    /// After a connection failed before becoming healthy we wait at least this long before
+    /// dialing again, so that a flapping peer does not keep us reconnecting all the time.
+    pub short_connection_delay: Duration,
+    /// Connections older than this get replaced with fresh ones, which repeat the handshake. The
+    /// old connection is only closed once the new one is established. Kept forever if not set.
+    pub max_lifetime: Option<Duration>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.1,
+            stable_duration: Duration::from_secs(60),
+            short_connection_delay: Duration::from_secs(5),
+            max_lifetime: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// The delay after the given number of previous consecutive failures, before applying jitter.
+    fn base_delay(&self, failures: u32) -> Duration {
+        let delay = self.initial_delay.as_secs_f64()
+            * self.multiplier.powi(failures.min(i32::MAX as u32) as i32);
+        Duration::from_secs_f64(delay.min(self.max_delay.as_secs_f64()))
+    }
+
+    fn delay(&self, failures: u32) -> Duration {
+        let base = self.base_delay(failures).as_secs_f64();
+        let jitter = match self.jitter > 0.0 {
+            true => thread_rng().gen_range(-self.jitter..=self.jitter),
+            false => 0.0,
+        };
+        Duration::from_secs_f64((base * (1.0 + jitter)).clamp(0.0, self.max_delay.as_secs_f64()))
+    }
+}
+
+/// Keeps track of consecutive connection failures with a single peer.
+pub struct Backoff {
+    policy: ReconnectPolicy,
+    failures: u32,
+    connected_since: Option<Instant>,
+    retry_at: Option<Instant>,
+}
+
+impl Backoff {
+    /// Create a new backoff, as if there were no failures yet.
+    pub fn new(policy: ReconnectPolicy) -> Self {
+        Backoff {
+            policy,
+            failures: 0,
+            connected_since: None,
+            retry_at: None,
+        }
+    }
+
+    /// Mark the connection as established.
+    pub fn connected(&mut self) {
+        self.connected_at(Instant::now())
+    }
+
+    fn connected_at(&mut self, now: Instant) {
+        self.connected_since = Some(now);
+        self.retry_at = None;
+    }
+
+    /// Mark the connection as failed, returns how long to wait before reconnecting.
+    pub fn failed(&mut self) -> Duration {
+        self.failed_at(Instant::now())
+    }
+
+    fn failed_at(&mut self, now: Instant) -> Duration {
+        let mut short_lived = false;
+        if let Some(connected_since) = self.connected_since.take() {
+            match now.saturating_duration_since(connected_since) >= self.policy.stable_duration {
+                true => self.failures = 0,
+                false => short_lived = true,
+            }
+        }
+        let mut delay = self.policy.delay(self.failures);
+        if short_lived {
+            delay = delay.max(self.policy.short_connection_delay);
+        }
+        self.failures = self.failures.saturating_add(1);
+        self.retry_at = Some(now + delay);
+        delay
+    }
+
+    /// How many consecutive connections failed, those lasting long enough not counted.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Whether the connection has been up for longer than the maximum lifetime, if there is one.
+    pub fn expired(&self) -> bool {
+        self.expired_at(Instant::now())
+    }
+
+    fn expired_at(&self, now: Instant) -> bool {
+        match (self.policy.max_lifetime, self.connected_since) {
+            (Some(max_lifetime), Some(connected_since)) => {
+                now.saturating_duration_since(connected_since) >= max_lifetime
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether we are still waiting before the next connection attempt.
+    pub fn waiting(&self) -> bool {
+        self.waiting_at(Instant::now())
+    }
+
+    fn waiting_at(&self, now: Instant) -> bool {
+        self.retry_at.map_or(false, |retry_at| now < retry_at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use tokio::time::Duration;
+
+    use super::{Backoff, ReconnectPolicy};
+
+    fn policy(jitter: f64) -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter,
+            stable_duration: Duration::from_secs(30),
+            short_connection_delay: Duration::from_secs(5),
+            max_lifetime: None,
+        }
+    }
+
+    fn secs(delays: Vec<Duration>) -> Vec<u64> {
+        delays.into_iter().map(|delay| delay.as_secs()).collect()
+    }
+
+    #[test]
+    fn grows_until_cap() {
+        let mut backoff = Backoff::new(policy(0.0));
+        let now = Instant::now();
+        let delays = (0..7).map(|_| backoff.failed_at(now)).collect();
+        assert_eq!(secs(delays), vec![1, 2, 4, 8, 10, 10, 10]);
+    }
+
+    #[test]
+    fn resets_after_stable_connection() {
+        let mut backoff = Backoff::new(policy(0.0));
+        let now = Instant::now();
+        let delays = (0..3).map(|_| backoff.failed_at(now)).collect();
+        assert_eq!(secs(delays), vec![1, 2, 4]);
+        // a short lived connection does not reset the backoff
+        backoff.connected_at(now);
+        assert_eq!(
+            backoff.failed_at(now + Duration::from_secs(29)),
+            Duration::from_secs(8)
+        );
+        // but a stable one does
+        backoff.connected_at(now);
+        assert_eq!(
+            backoff.failed_at(now + Duration::from_secs(30)),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            backoff.failed_at(now + Duration::from_secs(30)),
+            Duration::from_secs(2)
+        );
+    }
+
+    #[test]
+    fn throttles_flapping_peer() {
+        let mut backoff = Backoff::new(policy(0.0));
+        let mut now = Instant::now();
+        let mut delays = Vec::new();
+        for _ in 0..5 {
+            // the peer accepts the connection, but drops it soon after
+            backoff.connected_at(now);
+            now += Duration::from_secs(1);
+            let delay = backoff.failed_at(now);
+            assert!(backoff.waiting_at(now + Duration::from_secs(4)));
+            delays.push(delay);
+            now += delay;
+        }
+        assert_eq!(secs(delays), vec![5, 5, 5, 8, 10]);
+        // failing to connect at all is not throttled more than usual
+        let mut backoff = Backoff::new(policy(0.0));
+        assert_eq!(backoff.failed_at(now), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn waits_only_until_retry() {
+        let mut backoff = Backoff::new(policy(0.0));
+        let now = Instant::now();
+        assert!(!backoff.waiting_at(now));
+        backoff.failed_at(now);
+        assert!(backoff.waiting_at(now + Duration::from_millis(999)));
+        assert!(!backoff.waiting_at(now + Duration::from_secs(1)));
+        backoff.failed_at(now);
+        backoff.connected_at(now);
+        assert!(!backoff.waiting_at(now));
+    }
+
+    #[test]
+    fn expires_only_connections_older_than_max_lifetime() {
+        let now = Instant::now();
+        let mut backoff = Backoff::new(policy(0.0));
+        backoff.connected_at(now);
+        assert!(!backoff.expired_at(now + Duration::from_secs(3600)));
+
+        let mut backoff = Backoff::new(ReconnectPolicy {
+            max_lifetime: Some(Duration::from_secs(10)),
+            ..policy(0.0)
+        });
+        assert!(!backoff.expired_at(now + Duration::from_secs(3600)));
+        backoff.connected_at(now);
+        assert!(!backoff.expired_at(now + Duration::from_secs(9)));
+        assert!(backoff.expired_at(now + Duration::from_secs(10)));
+        // a replacement starts a new lifetime
+        backoff.connected_at(now + Duration::from_secs(10));
+        assert!(!backoff.expired_at(now + Duration::from_secs(19)));
+        backoff.failed_at(now + Duration::from_secs(19));
+        assert!(!backoff.expired_at(now + Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let mut backoff = Backoff::new(policy(0.5));
+        let now = Instant::now();
+        let bounds = [(0.5, 1.5), (1.0, 3.0), (2.0, 6.0), (4.0, 10.0), (5.0, 10.0)];
+        for (min, max) in bounds {
+            let delay = backoff.failed_at(now).as_secs_f64();
+            assert!(
+                (min..=max).contains(&delay),
+                "delay {} not in [{}, {}]",
+                delay,
+                min,
+                max
+            );
+        }
+    }
+}
@@ -0,0 +1,385 @@
+use std::{
+    collections::VecDeque,
+    fmt::{Display, Error as FmtError, Formatter},
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+use tokio::{sync::Notify, time::Duration};
+
+use crate::validator_network::rate_limit::RateLimit;
+
+/// What to do when the buffer of data waiting to be sent to a peer is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Discard the oldest buffered data to make room for the new one.
+    DropOldest,
+    /// Reject the new data and close the connection, so that it can be reestablished.
+    ReturnError,
+}
+
+/// Configuration of the buffer of data waiting to be sent to a single peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SendChannelConfig {
+    /// Maximal number of buffered messages, `None` means the buffer is unbounded.
+    pub capacity: Option<usize>,
+    /// What happens when the buffer is full, irrelevant for unbounded buffers.
+    pub overflow_policy: OverflowPolicy,
+    /// How long writing a single message to the network may take before the connection is
+    /// considered stalled and closed, `None` means waiting indefinitely.
+    pub write_timeout: Option<Duration>,
+    /// How fast data can be written to the network, `None` means no limit. When the limit is hit
+    /// the sending is paced, heartbeats included, so a very tight limit might get the connection
+    /// dropped by the peer.
+    pub rate_limit: Option<RateLimit>,
+    /// How long to wait for more data after some arrives, so that all of it is written to the
+    /// network at once. Saves system calls when many small messages come in bursts, at the cost
+    /// of delaying them a bit. `None` means every message is written as soon as it arrives.
+    pub coalesce_window: Option<Duration>,
+}
+
+impl Default for SendChannelConfig {
+    fn default() -> Self {
+        SendChannelConfig {
+            capacity: None,
+            overflow_policy: OverflowPolicy::ReturnError,
+            write_timeout: None,
+            rate_limit: None,
+            coalesce_window: None,
+        }
+    }
+}
+
+/// Settings of the send buffer that cannot work at all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendChannelConfigError {
+    /// A buffer that cannot hold anything would never let any data through.
+    ZeroCapacity,
+}
+
+impl Display for SendChannelConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        use SendChannelConfigError::*;
+        match self {
+            ZeroCapacity => write!(f, "send buffer capacity cannot be zero"),
+        }
+    }
+}
+
+/// Builds a configuration of the send buffer, checking that the settings can work.
+pub struct SendChannelConfigBuilder {
+    config: SendChannelConfig,
+}
+
+impl SendChannelConfigBuilder {
+    /// Starts with the default, unbounded configuration.
+    pub fn new() -> Self {
+        SendChannelConfigBuilder {
+            config: SendChannelConfig::default(),
+        }
+    }
+
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.config.capacity = Some(capacity);
+        self
+    }
+
+    pub fn overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.config.overflow_policy = overflow_policy;
+        self
+    }
+
+    pub fn write_timeout(mut self, write_timeout: Duration) -> Self {
+        self.config.write_timeout = Some(write_timeout);
+        self
+    }
+
+    pub fn rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.config.rate_limit = Some(rate_limit);
+        self
+    }
+
+    pub fn coalesce_window(mut self, coalesce_window: Duration) -> Self {
+        self.config.coalesce_window = Some(coalesce_window);
+        self
+    }
+
+    /// Returns the configuration, unless some of the settings cannot work.
+    pub fn build(self) -> Result<SendChannelConfig, SendChannelConfigError> {
+        let config = self.config;
+        if config.capacity == Some(0) {
+            return Err(SendChannelConfigError::ZeroCapacity);
+        }
+        Ok(config)
+    }
+}
+
+impl Default for SendChannelConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Error returned when sending data through the channel.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SendChannelError {
+    /// The receiving side is gone.
+    Closed,
+    /// The buffer is full and the overflow policy does not allow dropping data.
+    Overflow,
+}
+
+impl Display for SendChannelError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), FmtError> {
+        use SendChannelError::*;
+        match self {
+            Closed => write!(f, "channel closed"),
+            Overflow => write!(f, "send buffer overflow"),
+        }
+    }
+}
+
+struct Shared<D> {
+    queue: VecDeque<D>,
+    config: SendChannelConfig,
+    overflowed: bool,
+    paused: bool,
+    sender_alive: bool,
+    receiver_alive: bool,
+}
+
+/// The sending half of a channel for data waiting to be sent to a peer.
+pub struct DataSender<D> {
+    shared: Arc<Mutex<Shared<D>>>,
+    notify: Arc<Notify>,
+}
+
+/// The receiving half of a channel for data waiting to be sent to a peer.
+pub struct DataReceiver<D> {
+    shared: Arc<Mutex<Shared<D>>>,
+    notify: Arc<Notify>,
+}
+
+/// Create a new channel for data waiting to be sent to a peer, buffering according to the config.
+pub fn send_channel<D>(config: SendChannelConfig) -> (DataSender<D>, DataReceiver<D>) {
+    let shared = Arc::new(Mutex::new(Shared {
+        queue: VecDeque::new(),
+        config,
+        overflowed: false,
+        paused: false,
+        sender_alive: true,
+        receiver_alive: true,
+    }));
+    let notify = Arc::new(Notify::new());
+    (
+        DataSender {
+            shared: shared.clone(),
+            notify: notify.clone(),
+        },
+        DataReceiver { shared, notify },
+    )
+}
+
+impl<D> DataSender<D> {
+    /// Put the data in the buffer, applying the overflow policy if the buffer is full.
+    pub fn send(&self, data: D) -> Result<(), SendChannelError> {
+        let mut shared = self.shared.lock();
+        if !shared.receiver_alive || shared.overflowed {
+            return Err(SendChannelError::Closed);
+        }
+        if let Some(capacity) = shared.config.capacity {
+            if shared.queue.len() >= capacity {
+                match shared.config.overflow_policy {
+                    OverflowPolicy::DropOldest => {
+                        shared.queue.pop_front();
+                    }
+                    OverflowPolicy::ReturnError => {
+                        shared.overflowed = true;
+                        shared.queue.clear();
+                        self.notify.notify_one();
+                        return Err(SendChannelError::Overflow);
+                    }
+                }
+            }
+        }
+        shared.queue.push_back(data);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Whether the receiving side is gone.
+    pub fn is_closed(&self) -> bool {
+        let shared = self.shared.lock();
+        !shared.receiver_alive || shared.overflowed
+    }
+
+    /// Stop or restart handing out the buffered data to the receiving side. While paused the data
+    /// keeps getting buffered, subject to the capacity as usual.
+    pub fn set_paused(&self, paused: bool) {
+        self.shared.lock().paused = paused;
+        if !paused {
+            self.notify.notify_one();
+        }
+    }
+}
+
+impl<D> Drop for DataSender<D> {
+    fn drop(&mut self) {
+        self.shared.lock().sender_alive = false;
+        self.notify.notify_one();
+    }
+}
+
+impl<D> DataReceiver<D> {
+    /// Receive the next buffered data. Returns `Ok(None)` if the sender is gone and the buffer
+    /// is empty, and an error if the buffer overflowed with the `ReturnError` policy.
+    /// Waits while the channel is paused, unless the sender is gone already.
+    pub async fn next(&mut self) -> Result<Option<D>, SendChannelError> {
+        loop {
+            {
+                let mut shared = self.shared.lock();
+                if shared.overflowed {
+                    return Err(SendChannelError::Overflow);
+                }
+                let paused = shared.paused && shared.sender_alive;
+                if !paused {
+                    if let Some(data) = shared.queue.pop_front() {
+                        return Ok(Some(data));
+                    }
+                }
+                if !shared.sender_alive {
+                    return Ok(None);
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Receive the next buffered data without waiting. Returns `Ok(None)` if the buffer is empty,
+    /// and an error if the buffer overflowed with the `ReturnError` policy. Ignores pausing, so
+    /// that a closing connection can still flush everything.
+    pub fn try_next(&mut self) -> Result<Option<D>, SendChannelError> {
+        let mut shared = self.shared.lock();
+        if shared.overflowed {
+            return Err(SendChannelError::Overflow);
+        }
+        Ok(shared.queue.pop_front())
+    }
+}
+
+impl<D> Drop for DataReceiver<D> {
+    fn drop(&mut self) {
+        self.shared.lock().receiver_alive = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::{timeout, Duration};
+
+    use super::{
+        send_channel, OverflowPolicy, SendChannelConfig, SendChannelConfigBuilder,
+        SendChannelConfigError, SendChannelError,
+    };
+
+    fn bounded(capacity: usize, overflow_policy: OverflowPolicy) -> SendChannelConfig {
+        SendChannelConfig {
+            capacity: Some(capacity),
+            overflow_policy,
+            ..SendChannelConfig::default()
+        }
+    }
+
+    #[test]
+    fn builder_rejects_zero_capacity() {
+        assert_eq!(
+            SendChannelConfigBuilder::new().capacity(0).build(),
+            Err(SendChannelConfigError::ZeroCapacity)
+        );
+        assert_eq!(
+            SendChannelConfigBuilder::new().capacity(1).build(),
+            Ok(bounded(1, OverflowPolicy::ReturnError))
+        );
+    }
+
+    #[tokio::test]
+    async fn unbounded_keeps_everything() {
+        let (sender, mut receiver) = send_channel(SendChannelConfig::default());
+        for i in 0..1000 {
+            sender.send(i).expect("should send");
+        }
+        for i in 0..1000 {
+            assert_eq!(receiver.next().await, Ok(Some(i)));
+        }
+        std::mem::drop(sender);
+        assert_eq!(receiver.next().await, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_when_full() {
+        let (sender, mut receiver) = send_channel(bounded(3, OverflowPolicy::DropOldest));
+        for i in 0..5 {
+            sender.send(i).expect("should send");
+        }
+        std::mem::drop(sender);
+        for i in 2..5 {
+            assert_eq!(receiver.next().await, Ok(Some(i)));
+        }
+        assert_eq!(receiver.next().await, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn return_error_when_full() {
+        let (sender, mut receiver) = send_channel(bounded(3, OverflowPolicy::ReturnError));
+        for i in 0..3 {
+            sender.send(i).expect("should send");
+        }
+        assert!(!sender.is_closed());
+        assert_eq!(sender.send(3), Err(SendChannelError::Overflow));
+        assert!(sender.is_closed());
+        assert_eq!(sender.send(4), Err(SendChannelError::Closed));
+        assert_eq!(receiver.next().await, Err(SendChannelError::Overflow));
+    }
+
+    #[tokio::test]
+    async fn try_next_does_not_wait() {
+        let (sender, mut receiver) = send_channel(SendChannelConfig::default());
+        assert_eq!(receiver.try_next(), Ok(None));
+        sender.send(43).expect("should send");
+        assert_eq!(receiver.try_next(), Ok(Some(43)));
+        assert_eq!(receiver.try_next(), Ok(None));
+    }
+
+    #[tokio::test]
+    async fn paused_channel_holds_data_until_resumed() {
+        let (sender, mut receiver) = send_channel(SendChannelConfig::default());
+        sender.set_paused(true);
+        for i in 0..3 {
+            sender.send(i).expect("should send");
+        }
+        assert!(
+            timeout(Duration::from_millis(50), receiver.next())
+                .await
+                .is_err(),
+            "paused channels should not hand out data"
+        );
+        sender.set_paused(false);
+        for i in 0..3 {
+            assert_eq!(receiver.next().await, Ok(Some(i)));
+        }
+        // the remaining data gets flushed even if the sender goes away while paused
+        sender.set_paused(true);
+        sender.send(3).expect("should send");
+        std::mem::drop(sender);
+        assert_eq!(receiver.next().await, Ok(Some(3)));
+        assert_eq!(receiver.next().await, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn closed_when_receiver_dropped() {
+        let (sender, receiver) = send_channel(SendChannelConfig::default());
+        std::mem::drop(receiver);
+        assert!(sender.is_closed());
+        assert_eq!(sender.send(43), Err(SendChannelError::Closed));
+    }
+}
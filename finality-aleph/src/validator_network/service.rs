@@ -1,17 +1,38 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Instant,
+};
+
 use aleph_primitives::AuthorityId;
 use futures::{
     channel::{mpsc, oneshot},
-    StreamExt,
+    future::join3,
+    SinkExt, StreamExt,
+};
+use log::{debug, info, trace, warn, Level};
+use parking_lot::Mutex;
+use tokio::{
+    sync::Semaphore,
+    time::{self, timeout, Duration},
 };
-use log::{info, trace, warn};
-use tokio::time;
 
 use crate::{
     crypto::AuthorityPen,
     validator_network::{
+        blacklist::{Blacklist, BlacklistConfig},
+        handshake::{Capabilities, HandshakeConfig},
+        heartbeat::HeartbeatConfig,
         incoming::incoming,
-        manager::{AddResult, Manager},
+        io::{Codec, ReceiveConfig},
+        log_limit::LogLimiter,
+        manager::{AddResult, Manager, PeerConnections},
+        metrics::{ConnectionStats, Metrics},
         outgoing::outgoing,
+        protocols::{OutgoingResult, Protocol},
+        quality::ConnectionQuality,
+        reconnect::{Backoff, ReconnectPolicy},
+        send_channel::SendChannelConfig,
         Data, Dialer, Listener, Network,
     },
     SpawnTaskHandle, STATUS_REPORT_INTERVAL,
@@ -21,11 +42,106 @@ enum ServiceCommand<D: Data, A: Data> {
     AddConnection(AuthorityId, Vec<A>),
     DelConnection(AuthorityId),
     SendData(D, AuthorityId),
+    Status(oneshot::Sender<Vec<PeerStatus>>),
+    WaitForPeer(AuthorityId, oneshot::Sender<()>),
+    Pause(AuthorityId),
+    Resume(AuthorityId),
+}
+
+/// The state of our outgoing connection with a peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The connection is established.
+    Connected,
+    /// We are trying to establish the connection.
+    Connecting,
+    /// The last attempt failed, we are waiting before trying again.
+    Backoff,
+    /// The peer misbehaved too often, we are not talking to it for a while.
+    Blacklisted,
+}
+
+/// A snapshot of what we know about a peer we want to stay connected to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerStatus {
+    pub peer_id: AuthorityId,
+    pub outgoing: ConnectionState,
+    /// Whether the peer is connected to us.
+    pub incoming: bool,
+    /// The protocol negotiated for the most recent connection.
+    pub protocol: Option<Protocol>,
+    /// The capabilities both sides of the most recent connection have, only exchanged by newer
+    /// protocols.
+    pub capabilities: Option<Capabilities>,
+    /// When the last heartbeat was received over any of the connections with the peer, `None` if
+    /// none ever came.
+    pub last_heartbeat: Option<Instant>,
+    /// Bytes of encoded data sent over the current outgoing connection.
+    pub bytes_sent: u64,
+    /// Bytes of encoded data received over the current incoming connection.
+    pub bytes_received: u64,
+    /// How healthy the connections with the peer are, from 0 to 100, as judged by
+    /// `ConnectionQuality`. Only known once the peer was scored, which happens periodically.
+    pub quality_score: Option<u8>,
+}
+
+/// Allows querying the status of a running service and waiting for peers to get connected.
+#[derive(Clone)]
+pub struct StatusHandle<D: Data, A: Data> {
+    commands_for_service: mpsc::UnboundedSender<ServiceCommand<D, A>>,
+}
+
+impl<D: Data, A: Data> StatusHandle<D, A> {
+    /// The status of all the peers we want to stay connected to, `None` if the service is dead.
+    pub async fn status(&self) -> Option<Vec<PeerStatus>> {
+        let (result_for_us, result) = oneshot::channel();
+        self.commands_for_service
+            .unbounded_send(ServiceCommand::Status(result_for_us))
+            .ok()?;
+        result.await.ok()
+    }
+
+    /// Resolves once we have an outgoing connection with the peer, right away if we already do.
+    /// `None` if the service dies before that.
+    pub async fn wait_for_peer(&self, peer_id: AuthorityId) -> Option<()> {
+        let (result_for_us, result) = oneshot::channel();
+        self.commands_for_service
+            .unbounded_send(ServiceCommand::WaitForPeer(peer_id, result_for_us))
+            .ok()?;
+        result.await.ok()
+    }
+}
+
+/// The capabilities agreed on over the current outgoing connections, kept up to date by the
+/// service.
+type AgreedCapabilities = Arc<Mutex<HashMap<AuthorityId, Capabilities>>>;
+
+/// The peers we currently have a live outgoing connection with, i.e. the ones data can actually
+/// be sent to, kept up to date by the service. Cloning is cheap and the clones share the set.
+#[derive(Clone, Default)]
+pub struct ConnectedPeers {
+    peers: Arc<Mutex<HashSet<AuthorityId>>>,
+}
+
+impl ConnectedPeers {
+    /// Whether we currently have a live outgoing connection with the peer.
+    pub fn contains(&self, peer_id: &AuthorityId) -> bool {
+        self.peers.lock().contains(peer_id)
+    }
+
+    fn set(&self, peer_id: &AuthorityId, connected: bool) {
+        let mut peers = self.peers.lock();
+        match connected {
+            true => peers.insert(peer_id.clone()),
+            false => peers.remove(peer_id),
+        };
+    }
 }
 
 struct ServiceInterface<D: Data, A: Data> {
     commands_for_service: mpsc::UnboundedSender<ServiceCommand<D, A>>,
-    next_from_service: mpsc::UnboundedReceiver<D>,
+    next_from_service: mpsc::Receiver<(D, AuthorityId, Capabilities)>,
+    agreed_capabilities: AgreedCapabilities,
 }
 
 #[async_trait::async_trait]
@@ -52,6 +168,33 @@ impl<D: Data, A: Data> Network<A, D> for ServiceInterface<D, A> {
         };
     }
 
+    /// Hold back the data sent to the peer, without closing the connection.
+    fn pause(&mut self, peer: AuthorityId) {
+        if self
+            .commands_for_service
+            .unbounded_send(ServiceCommand::Pause(peer))
+            .is_err()
+        {
+            info!(target: "validator-network", "Service is dead.");
+        };
+    }
+
+    /// Send out the data held back for the peer, in order, and stop holding it back.
+    fn resume(&mut self, peer: AuthorityId) {
+        if self
+            .commands_for_service
+            .unbounded_send(ServiceCommand::Resume(peer))
+            .is_err()
+        {
+            info!(target: "validator-network", "Service is dead.");
+        };
+    }
+
+    /// The capabilities agreed on with the peer over the current outgoing connection, if any.
+    fn capabilities(&self, peer: &AuthorityId) -> Option<Capabilities> {
+        self.agreed_capabilities.lock().get(peer).copied()
+    }
+
     /// Send a message to a single peer.
     /// This function should be implemented in a non-blocking manner.
     fn send(&self, data: D, recipient: AuthorityId) {
@@ -64,37 +207,109 @@ impl<D: Data, A: Data> Network<A, D> for ServiceInterface<D, A> {
         };
     }
 
-    /// Receive a message from the network.
-    async fn next(&mut self) -> Option<D> {
+    /// Receive a message from the network, together with the peer that sent it.
+    async fn next(&mut self) -> Option<(D, AuthorityId, Capabilities)> {
         self.next_from_service.next().await
     }
 }
 
+/// Dials using the wrapped dialer, no more connections at the same time than the semaphore allows.
+/// Further dials wait for their turn, in order.
+#[derive(Clone)]
+struct LimitedDialer<ND> {
+    dialer: ND,
+    dials: Arc<Semaphore>,
+}
+
+#[async_trait::async_trait]
+impl<A: Data, ND: Dialer<A>> Dialer<A> for LimitedDialer<ND> {
+    type Connection = ND::Connection;
+    type Error = ND::Error;
+
+    async fn connect(&mut self, addresses: Vec<A>) -> Result<Self::Connection, Self::Error> {
+        // the semaphore is never closed, and the permit is released once the dial is done
+        let _permit = self.dials.acquire().await;
+        self.dialer.connect(addresses).await
+    }
+}
+
+/// The latest quality score of a peer, with the bytes carried over its connections until then.
+struct Scored {
+    bytes: u64,
+    score: u8,
+}
+
 /// A service that has to be run for the validator network to work.
 pub struct Service<D: Data, A: Data, ND: Dialer<A>, NL: Listener> {
+    commands_for_service: mpsc::UnboundedSender<ServiceCommand<D, A>>,
     commands_from_interface: mpsc::UnboundedReceiver<ServiceCommand<D, A>>,
-    next_to_interface: mpsc::UnboundedSender<D>,
+    next_to_interface: mpsc::Sender<(D, AuthorityId, Capabilities)>,
     manager: Manager<A, D>,
     dialer: ND,
     listener: NL,
     spawn_handle: SpawnTaskHandle,
     authority_pen: AuthorityPen,
+    heartbeat_config: HeartbeatConfig,
+    handshake_config: HandshakeConfig,
+    receive_config: ReceiveConfig,
+    send_channel_config: SendChannelConfig,
+    codec: Codec,
+    reconnect_policy: ReconnectPolicy,
+    backoffs: HashMap<AuthorityId, Backoff>,
+    blacklist: Blacklist,
+    outgoing_stats: HashMap<AuthorityId, ConnectionStats>,
+    incoming_stats: HashMap<AuthorityId, ConnectionStats>,
+    agreed_capabilities: AgreedCapabilities,
+    connected_peers: ConnectedPeers,
+    quality: HashMap<AuthorityId, Scored>,
+    last_scored: Instant,
+    incoming_handshakes: Arc<Semaphore>,
+    outgoing_dials: Arc<Semaphore>,
+    /// How many outgoing connections can currently be dialed at once in total.
+    outgoing_dial_limit: usize,
+    /// Requesters waiting for an outgoing connection with the peer.
+    peer_waiters: HashMap<AuthorityId, Vec<oneshot::Sender<()>>>,
+    /// Exits of outgoing connections that outlived the maximum lifetime, kept until their
+    /// replacements are established.
+    recycling: HashMap<AuthorityId, oneshot::Sender<()>>,
+    /// Coalesces the logs repeated on every attempt to reach a peer that is down.
+    log_limiter: LogLimiter,
+    metrics: Option<Metrics>,
 }
 
 impl<D: Data, A: Data, ND: Dialer<A>, NL: Listener> Service<D, A, ND, NL> {
     /// Create a new validator network service plus an interface for interacting with it.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         dialer: ND,
         listener: NL,
         authority_pen: AuthorityPen,
         spawn_handle: SpawnTaskHandle,
+        heartbeat_config: HeartbeatConfig,
+        handshake_config: HandshakeConfig,
+        receive_config: ReceiveConfig,
+        send_channel_config: SendChannelConfig,
+        codec: Codec,
+        reconnect_policy: ReconnectPolicy,
+        blacklist_config: BlacklistConfig,
+        metrics: Option<Metrics>,
     ) -> (Self, impl Network<A, D>) {
         // Channel for sending commands between the service and interface
         let (commands_for_service, commands_from_interface) = mpsc::unbounded();
-        // Channel for receiving data from the network
-        let (next_to_interface, next_from_service) = mpsc::unbounded();
+        // Channel for receiving data from the network, bounded so that a slow user slows down the reading.
+        // Every connection forwards to it from a queue of its own, and the single slot each sender
+        // gets keeps the connections taking turns, so a busy one does not starve the others.
+        let (next_to_interface, next_from_service) =
+            mpsc::channel(receive_config.user_queue_capacity);
+        // A zero limit would never let anyone connect to us.
+        let incoming_handshakes = Arc::new(Semaphore::new(handshake_config.max_incoming.max(1)));
+        // Neither would a zero limit ever let us connect to anyone, which the limit ensures.
+        let outgoing_dial_limit = handshake_config.outgoing_dial_limit(0);
+        let outgoing_dials = Arc::new(Semaphore::new(outgoing_dial_limit));
+        let agreed_capabilities = AgreedCapabilities::default();
         (
             Self {
+                commands_for_service: commands_for_service.clone(),
                 commands_from_interface,
                 next_to_interface,
                 manager: Manager::new(),
@@ -102,44 +317,380 @@ impl<D: Data, A: Data, ND: Dialer<A>, NL: Listener> Service<D, A, ND, NL> {
                 listener,
                 spawn_handle,
                 authority_pen,
+                heartbeat_config,
+                handshake_config,
+                receive_config,
+                send_channel_config,
+                codec,
+                reconnect_policy,
+                backoffs: HashMap::new(),
+                blacklist: Blacklist::new(blacklist_config),
+                outgoing_stats: HashMap::new(),
+                incoming_stats: HashMap::new(),
+                agreed_capabilities: agreed_capabilities.clone(),
+                connected_peers: ConnectedPeers::default(),
+                quality: HashMap::new(),
+                last_scored: Instant::now(),
+                incoming_handshakes,
+                outgoing_dials,
+                outgoing_dial_limit,
+                peer_waiters: HashMap::new(),
+                recycling: HashMap::new(),
+                log_limiter: LogLimiter::default(),
+                metrics,
             },
             ServiceInterface {
                 commands_for_service,
                 next_from_service,
+                agreed_capabilities,
             },
         )
     }
 
+    /// A handle for querying the status of the service, also while it is running.
+    pub fn status_handle(&self) -> StatusHandle<D, A> {
+        StatusHandle {
+            commands_for_service: self.commands_for_service.clone(),
+        }
+    }
+
+    /// The set of peers we currently have a live outgoing connection with, kept up to date while
+    /// the service is running.
+    pub fn connected_peers(&self) -> ConnectedPeers {
+        self.connected_peers.clone()
+    }
+
+    /// The status of all the peers we want to stay connected to.
+    pub fn status(&self) -> Vec<PeerStatus> {
+        self.manager
+            .peer_connections()
+            .into_iter()
+            .map(
+                |PeerConnections {
+                     peer_id,
+                     outgoing,
+                     incoming,
+                 }| {
+                    let outgoing_stats = self.outgoing_stats.get(&peer_id).map(|s| s.snapshot());
+                    let incoming_stats = self.incoming_stats.get(&peer_id).map(|s| s.snapshot());
+                    let quality_score = self.quality.get(&peer_id).map(|scored| scored.score);
+                    let outgoing = match (outgoing, self.backoffs.get(&peer_id)) {
+                        (true, _) => ConnectionState::Connected,
+                        (false, _) if self.blacklist.banned_for(&peer_id).is_some() => {
+                            ConnectionState::Blacklisted
+                        }
+                        (false, Some(backoff)) if backoff.waiting() => ConnectionState::Backoff,
+                        (false, _) => ConnectionState::Connecting,
+                    };
+                    PeerStatus {
+                        peer_id,
+                        outgoing,
+                        incoming,
+                        protocol: outgoing_stats
+                            .and_then(|stats| stats.protocol)
+                            .or_else(|| incoming_stats.and_then(|stats| stats.protocol)),
+                        capabilities: outgoing_stats
+                            .and_then(|stats| stats.capabilities)
+                            .or_else(|| incoming_stats.and_then(|stats| stats.capabilities)),
+                        last_heartbeat: outgoing_stats
+                            .and_then(|stats| stats.last_heartbeat)
+                            .max(incoming_stats.and_then(|stats| stats.last_heartbeat)),
+                        bytes_sent: outgoing_stats.map_or(0, |stats| stats.bytes_sent),
+                        bytes_received: incoming_stats.map_or(0, |stats| stats.bytes_received),
+                        quality_score,
+                    }
+                },
+            )
+            .collect()
+    }
+
+    /// Scores the connections with all the peers, judging the throughput by the data carried
+    /// since the previous scoring.
+    fn score_peers(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(self.last_scored);
+        self.last_scored = now;
+        let mut quality = HashMap::new();
+        for PeerConnections { peer_id, .. } in self.manager.peer_connections() {
+            let outgoing_stats = self.outgoing_stats.get(&peer_id).map(|s| s.snapshot());
+            let incoming_stats = self.incoming_stats.get(&peer_id).map(|s| s.snapshot());
+            let bytes = outgoing_stats.map_or(0, |stats| stats.bytes_sent)
+                + incoming_stats.map_or(0, |stats| stats.bytes_received);
+            // replaced connections start counting from zero again
+            let previous = self
+                .quality
+                .get(&peer_id)
+                .map_or(bytes, |scored| scored.bytes.min(bytes));
+            let last_heartbeat = outgoing_stats
+                .and_then(|stats| stats.last_heartbeat)
+                .max(incoming_stats.and_then(|stats| stats.last_heartbeat));
+            let connection = ConnectionQuality {
+                round_trip: outgoing_stats.and_then(|stats| stats.last_round_trip),
+                since_heartbeat: last_heartbeat.map(|last| now.saturating_duration_since(last)),
+                heartbeat_interval: self.heartbeat_config.interval,
+                failures: self
+                    .backoffs
+                    .get(&peer_id)
+                    .map_or(0, |backoff| backoff.failures()),
+                throughput: match elapsed.is_zero() {
+                    true => 0.0,
+                    false => (bytes - previous) as f64 / elapsed.as_secs_f64(),
+                },
+            };
+            let score = connection.quality_score();
+            quality.insert(peer_id, Scored { bytes, score });
+        }
+        self.quality = quality;
+    }
+
+    fn wait_for_peer(&mut self, peer_id: AuthorityId, result_for_requester: oneshot::Sender<()>) {
+        if self.manager.is_connected(&peer_id) {
+            // the requester might have given up already, nothing to do then
+            let _ = result_for_requester.send(());
+            return;
+        }
+        let waiters = self.peer_waiters.entry(peer_id).or_default();
+        waiters.retain(|waiter| !waiter.is_canceled());
+        waiters.push(result_for_requester);
+    }
+
+    fn notify_peer_waiters(&mut self, peer_id: &AuthorityId) {
+        for waiter in self.peer_waiters.remove(peer_id).unwrap_or_default() {
+            let _ = waiter.send(());
+        }
+    }
+
+    /// Shares the capabilities agreed on over the current outgoing connection with the peer with
+    /// the interface, forgetting them if there is no such connection. Also shares whether the
+    /// connection is there at all.
+    fn share_capabilities(&self, peer_id: &AuthorityId) {
+        self.connected_peers
+            .set(peer_id, self.manager.is_connected(peer_id));
+        let capabilities = self
+            .outgoing_stats
+            .get(peer_id)
+            .and_then(|stats| stats.snapshot().capabilities);
+        let mut agreed_capabilities = self.agreed_capabilities.lock();
+        match capabilities {
+            Some(capabilities) => agreed_capabilities.insert(peer_id.clone(), capabilities),
+            None => agreed_capabilities.remove(peer_id),
+        };
+    }
+
+    /// Adjusts how many outgoing connections can be dialed at once to the number of peers we
+    /// stay connected to. The permits of ongoing dials cannot be taken back, so if they are
+    /// needed to lower the limit, it gets lowered on a later adjustment.
+    fn adjust_outgoing_dial_limit(&mut self) {
+        let limit = self
+            .handshake_config
+            .outgoing_dial_limit(self.manager.peer_count());
+        if limit > self.outgoing_dial_limit {
+            self.outgoing_dials
+                .add_permits(limit - self.outgoing_dial_limit);
+            self.outgoing_dial_limit = limit;
+        } else if limit < self.outgoing_dial_limit {
+            let excess = (self.outgoing_dial_limit - limit) as u32;
+            if let Ok(permits) = self.outgoing_dials.try_acquire_many(excess) {
+                permits.forget();
+                self.outgoing_dial_limit = limit;
+            }
+        }
+    }
+
     fn spawn_new_outgoing(
-        &self,
+        &mut self,
         peer_id: AuthorityId,
         addresses: Vec<A>,
-        result_for_parent: mpsc::UnboundedSender<(AuthorityId, Option<mpsc::UnboundedSender<D>>)>,
+        result_for_parent: mpsc::UnboundedSender<OutgoingResult<D>>,
+        misbehaviour_for_parent: mpsc::UnboundedSender<AuthorityId>,
+        delay: Duration,
     ) {
+        let (exit_for_manager, exit) = oneshot::channel();
+        self.manager
+            .set_outgoing_exit(peer_id.clone(), exit_for_manager);
         let authority_pen = self.authority_pen.clone();
-        let dialer = self.dialer.clone();
+        let dialer = LimitedDialer {
+            dialer: self.dialer.clone(),
+            dials: self.outgoing_dials.clone(),
+        };
+        let heartbeat_config = self.heartbeat_config;
+        let handshake_config = self.handshake_config;
+        let send_channel_config = self.send_channel_config;
+        let codec = self.codec;
+        let stats = ConnectionStats::new();
+        self.outgoing_stats.insert(peer_id.clone(), stats.clone());
+        let metrics = Some(Metrics::for_connection(&self.metrics, stats));
+        let log_limiter = self.log_limiter.clone();
         self.spawn_handle
             .spawn("aleph/validator_network_outgoing", None, async move {
-                outgoing(authority_pen, peer_id, dialer, addresses, result_for_parent).await;
+                let misbehaved = outgoing(
+                    authority_pen,
+                    peer_id.clone(),
+                    dialer,
+                    addresses,
+                    result_for_parent,
+                    delay,
+                    exit,
+                    heartbeat_config,
+                    handshake_config,
+                    send_channel_config,
+                    codec,
+                    metrics,
+                    log_limiter,
+                )
+                .await;
+                if misbehaved && misbehaviour_for_parent.unbounded_send(peer_id).is_err() {
+                    debug!(target: "validator-network", "Could not report a misbehaving peer, we've probably been terminated by the parent service.");
+                }
             });
     }
 
+    /// Starts replacing the outgoing connections that outlived the maximum lifetime. The old
+    /// connections keep carrying data until the new ones are established.
+    fn recycle_expired(
+        &mut self,
+        result_for_parent: &mpsc::UnboundedSender<OutgoingResult<D>>,
+        misbehaviour_for_parent: &mpsc::UnboundedSender<AuthorityId>,
+    ) {
+        let expired: Vec<_> = self
+            .backoffs
+            .iter()
+            .filter(|(peer_id, backoff)| {
+                backoff.expired() && !self.recycling.contains_key(*peer_id)
+            })
+            .map(|(peer_id, _)| peer_id.clone())
+            .collect();
+        for peer_id in expired {
+            let addresses = match self.manager.peer_addresses(&peer_id) {
+                Some(addresses) => addresses,
+                None => continue,
+            };
+            let old_exit = match self.manager.take_outgoing_exit(&peer_id) {
+                Some(old_exit) => old_exit,
+                None => continue,
+            };
+            info!(target: "validator-network", "Recycling outgoing connection to peer {}.", peer_id);
+            self.recycling.insert(peer_id.clone(), old_exit);
+            self.spawn_new_outgoing(
+                peer_id,
+                addresses,
+                result_for_parent.clone(),
+                misbehaviour_for_parent.clone(),
+                Duration::ZERO,
+            );
+        }
+    }
+
     fn spawn_new_incoming(
         &self,
         stream: NL::Connection,
-        result_for_parent: mpsc::UnboundedSender<(AuthorityId, oneshot::Sender<()>)>,
+        result_for_parent: mpsc::UnboundedSender<(
+            AuthorityId,
+            Protocol,
+            oneshot::Sender<()>,
+            ConnectionStats,
+        )>,
+        misbehaviour_for_parent: mpsc::UnboundedSender<AuthorityId>,
     ) {
         let authority_pen = self.authority_pen.clone();
-        let next_to_interface = self.next_to_interface.clone();
+        let mut next_to_interface = self.next_to_interface.clone();
+        let heartbeat_config = self.heartbeat_config;
+        let handshake_config = self.handshake_config;
+        let receive_config = self.receive_config;
+        let stats = ConnectionStats::new();
+        let metrics = Some(Metrics::for_connection(&self.metrics, stats.clone()));
+        let incoming_handshakes = self.incoming_handshakes.clone();
         self.spawn_handle
             .spawn("aleph/validator_network_incoming", None, async move {
-                incoming(authority_pen, stream, result_for_parent, next_to_interface).await;
+                // wait for our turn, so that a flood of connections cannot make us handshake
+                // with all of them at once
+                let permit = match timeout(
+                    handshake_config.incoming_queue_timeout,
+                    incoming_handshakes.acquire_owned(),
+                )
+                .await
+                {
+                    Ok(Ok(permit)) => permit,
+                    Ok(Err(_)) => return,
+                    Err(_) => {
+                        info!(target: "validator-network", "Dropping incoming connection, too many handshakes in progress.");
+                        return;
+                    }
+                };
+                // the peer is only known after the handshake, so we attach the statistics
+                // to the result of the worker, and the sender with the capabilities agreed on
+                // over this connection to the data it receives
+                let (result_for_worker, mut result_from_worker) = mpsc::unbounded();
+                let (peer_for_data, peer_known) = oneshot::channel();
+                let forward_result = async move {
+                    // the permit is released once the handshake is done, or the worker died
+                    let mut permit = Some(permit);
+                    let mut peer_for_data = Some(peer_for_data);
+                    let mut peer = None;
+                    while let Some((peer_id, protocol, capabilities, exit)) =
+                        result_from_worker.next().await
+                    {
+                        drop(permit.take());
+                        if let Some(peer_for_data) = peer_for_data.take() {
+                            // nobody waits for the peer only if the worker is already gone
+                            let _ = peer_for_data.send((peer_id.clone(), capabilities));
+                        }
+                        peer = Some(peer_id.clone());
+                        if result_for_parent
+                            .unbounded_send((peer_id, protocol, exit, stats.clone()))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    peer
+                };
+                let (data_for_worker, mut data_from_worker) =
+                    mpsc::channel(receive_config.user_queue_capacity);
+                let forward_data = async move {
+                    // the worker reports the peer before passing on any data
+                    let (peer_id, capabilities) = match peer_known.await {
+                        Ok(peer) => peer,
+                        Err(_) => return,
+                    };
+                    while let Some(data) = data_from_worker.next().await {
+                        if next_to_interface
+                            .send((data, peer_id.clone(), capabilities))
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                };
+                let worker = incoming(
+                    authority_pen,
+                    stream,
+                    result_for_worker,
+                    data_for_worker,
+                    heartbeat_config,
+                    handshake_config,
+                    receive_config,
+                    metrics,
+                );
+                // misbehaviour during the handshake cannot be blamed on anyone in particular
+                if let (true, Some(peer_id), _) = join3(worker, forward_result, forward_data).await {
+                    if misbehaviour_for_parent.unbounded_send(peer_id).is_err() {
+                        debug!(target: "validator-network", "Could not report a misbehaving peer, we've probably been terminated by the parent service.");
+                    }
+                }
             });
     }
 
     /// Run the service until a signal from exit.
     pub async fn run(mut self, mut exit: oneshot::Receiver<()>) {
         let mut status_ticker = time::interval(STATUS_REPORT_INTERVAL);
+        // checking a few times per lifetime keeps the connections from overstaying it much
+        let max_lifetime = self.reconnect_policy.max_lifetime;
+        let mut recycle_ticker =
+            time::interval(max_lifetime.map_or(STATUS_REPORT_INTERVAL, |max_lifetime| {
+                (max_lifetime / 4).max(Duration::from_millis(1))
+            }));
         // channel used to receive tuple (peer_id, exit_handle) from a spawned worker
         // that has just established an incoming connection
         // exit_handle may be used to kill the worker later
@@ -148,26 +699,41 @@ impl<D: Data, A: Data, ND: Dialer<A>, NL: Listener> Service<D, A, ND, NL> {
         // that managed an outgoing connection
         // the received peer_id can be used to spawn another worker
         let (outgoing_result_for_parent, mut outgoing_workers) = mpsc::unbounded();
+        // channel used to receive the ids of peers that broke the protocol in either direction
+        let (misbehaviour_for_parent, mut misbehaving_peers) = mpsc::unbounded();
         use ServiceCommand::*;
         loop {
             tokio::select! {
                 // got new incoming connection from the listener - spawn an incoming worker
                 maybe_stream = self.listener.accept() => match maybe_stream {
-                    Ok(stream) => self.spawn_new_incoming(stream, incoming_result_for_parent.clone()),
+                    Ok(stream) => self.spawn_new_incoming(stream, incoming_result_for_parent.clone(), misbehaviour_for_parent.clone()),
                     Err(e) => warn!(target: "validator-network", "Listener failed to accept connection: {}", e),
                 },
                 // got a new command from the interface
                 Some(command) = self.commands_from_interface.next() => match command {
                     // register new peer in manager or update its list of addresses if already there
                     // spawn a worker managing outgoing connection if the peer was not known
+                    // blacklisted peers only get dialed once their penalty is over, and we never dial ourselves
                     AddConnection(peer_id, addresses) => {
-                        if self.manager.add_peer(peer_id.clone(), addresses.clone()) {
-                            self.spawn_new_outgoing(peer_id, addresses, outgoing_result_for_parent.clone());
+                        if peer_id == self.authority_pen.authority_id() {
+                            warn!(target: "validator-network", "Not dialing our own addresses.");
+                        } else if self.manager.add_peer(peer_id.clone(), addresses.clone()) {
+                            self.adjust_outgoing_dial_limit();
+                            self.backoffs.insert(peer_id.clone(), Backoff::new(self.reconnect_policy));
+                            let delay = self.blacklist.banned_for(&peer_id).unwrap_or(Duration::ZERO);
+                            self.spawn_new_outgoing(peer_id, addresses, outgoing_result_for_parent.clone(), misbehaviour_for_parent.clone(), delay);
                         };
                     },
                     // remove the peer from the manager all workers will be killed automatically, due to closed channels
                     DelConnection(peer_id) => {
                         self.manager.remove_peer(&peer_id);
+                        self.adjust_outgoing_dial_limit();
+                        self.recycling.remove(&peer_id);
+                        self.backoffs.remove(&peer_id);
+                        self.outgoing_stats.remove(&peer_id);
+                        self.incoming_stats.remove(&peer_id);
+                        self.blacklist.forget(&peer_id);
+                        self.share_capabilities(&peer_id);
                     },
                     // pass the data to the manager
                     SendData(data, peer_id) => {
@@ -176,37 +742,112 @@ impl<D: Data, A: Data, ND: Dialer<A>, NL: Listener> Service<D, A, ND, NL> {
                             Err(e) => trace!(target: "validator-network", "Failed sending to {}: {}", peer_id, e),
                         }
                     },
+                    // the requester might have given up already, nothing to do then
+                    Status(result_for_requester) => {
+                        let _ = result_for_requester.send(self.status());
+                    },
+                    WaitForPeer(peer_id, result_for_requester) => self.wait_for_peer(peer_id, result_for_requester),
+                    // the heartbeats keep paused connections alive, only the data waits
+                    Pause(peer_id) => match self.manager.set_paused(&peer_id, true) {
+                        true => info!(target: "validator-network", "Pausing sending to {}.", peer_id),
+                        false => debug!(target: "validator-network", "Not pausing sending to unknown peer {}.", peer_id),
+                    },
+                    Resume(peer_id) => match self.manager.set_paused(&peer_id, false) {
+                        true => info!(target: "validator-network", "Resuming sending to {}.", peer_id),
+                        false => debug!(target: "validator-network", "Not resuming sending to unknown peer {}.", peer_id),
+                    },
                 },
-                // received tuple (peer_id, exit_handle) from a spawned worker
+                // received tuple (peer_id, protocol, exit_handle) from a spawned worker
                 // that has just established an incoming connection
                 // pass the tuple to the manager to register the connection
                 // the manager will be responsible for killing the worker if necessary
-                Some((peer_id, exit)) = incoming_workers.next() => {
+                // blacklisted peers get disconnected right away, by dropping the exit handle
+                Some((peer_id, protocol, exit, stats)) = incoming_workers.next() => {
                     use AddResult::*;
+                    if self.blacklist.banned_for(&peer_id).is_some() {
+                        self.log_limiter.log(Level::Info, &peer_id, format!("Rejecting incoming connection from blacklisted peer {}.", peer_id));
+                        continue;
+                    }
                     match self.manager.add_incoming(peer_id.clone(), exit) {
                         Uninterested => info!(target: "validator-network", "Peer {} connected to us despite out lack of interest.", peer_id),
-                        Added => info!(target: "validator-network", "New incoming connection for peer {}.", peer_id),
-                        Replaced => info!(target: "validator-network", "Replaced incoming connection for peer {}.", peer_id),
+                        Added => {
+                            info!(target: "validator-network", "New incoming connection for peer {} using protocol {:?}.", peer_id, protocol);
+                            self.incoming_stats.insert(peer_id, stats);
+                        },
+                        Replaced => {
+                            info!(target: "validator-network", "Replaced incoming connection for peer {} using protocol {:?}.", peer_id, protocol);
+                            self.incoming_stats.insert(peer_id, stats);
+                        },
                     }
                 },
                 // received information from a spawned worker managing an outgoing connection
-                // check if we still want to be connected to the peer, and if so, spawn a new worker or actually add proper connection
+                // check if we still want to be connected to the peer, and if so, spawn a new worker after a backoff or actually add proper connection
                 Some((peer_id, maybe_data_for_network)) = outgoing_workers.next() => {
                     use AddResult::*;
                     if let Some(addresses) = self.manager.peer_addresses(&peer_id) {
+                        let backoff = self.backoffs.entry(peer_id.clone()).or_insert_with(|| Backoff::new(self.reconnect_policy));
                         match maybe_data_for_network {
-                            Some(data_for_network) => match self.manager.add_outgoing(peer_id.clone(), data_for_network) {
-                                Uninterested => warn!(target: "validator-network", "We connected to peer {} for unknown reasons.", peer_id),
-                                Added => info!(target: "validator-network", "New outgoing connection to peer {}.", peer_id),
-                                Replaced => info!(target: "validator-network", "Replaced outgoing connection to peer {}.", peer_id),
+                            Some((protocol, data_for_network)) => {
+                                backoff.connected();
+                                match self.manager.add_outgoing(peer_id.clone(), data_for_network) {
+                                    Uninterested => warn!(target: "validator-network", "We connected to peer {} for unknown reasons.", peer_id),
+                                    Added => {
+                                        info!(target: "validator-network", "New outgoing connection to peer {} using protocol {:?}.", peer_id, protocol);
+                                        self.notify_peer_waiters(&peer_id);
+                                    },
+                                    Replaced => {
+                                        info!(target: "validator-network", "Replaced outgoing connection to peer {} using protocol {:?}.", peer_id, protocol);
+                                        self.notify_peer_waiters(&peer_id);
+                                    },
+                                }
+                                // the old connection sends out what it has queued and closes
+                                if let Some(old_exit) = self.recycling.remove(&peer_id) {
+                                    let _ = old_exit.send(());
+                                }
+                                self.share_capabilities(&peer_id);
+                            },
+                            None => {
+                                let delay = backoff.failed().max(self.blacklist.banned_for(&peer_id).unwrap_or(Duration::ZERO));
+                                info!(target: "validator-network", "Will retry connecting to peer {} after {}ms.", peer_id, delay.as_millis());
+                                self.spawn_new_outgoing(peer_id.clone(), addresses, outgoing_result_for_parent.clone(), misbehaviour_for_parent.clone(), delay);
+                                self.share_capabilities(&peer_id);
                             },
-                            None => self.spawn_new_outgoing(peer_id, addresses, outgoing_result_for_parent.clone()),
                         }
                     };
                 },
+                // a worker noticed the peer breaking the protocol, once it happens too often
+                // we drop all connections with the peer and only try again after the penalty
+                // an outgoing worker does not report its closing when the peer misbehaved, so
+                // this is the only place that spawns its replacement
+                Some(peer_id) = misbehaving_peers.next() => {
+                    let banned_for = self.blacklist.failed(&peer_id);
+                    if let Some(addresses) = self.manager.peer_addresses(&peer_id) {
+                        if let Some(ttl) = banned_for {
+                            warn!(target: "validator-network", "Blacklisting peer {} for {}s.", peer_id, ttl.as_secs());
+                            self.manager.disconnect(&peer_id);
+                            self.recycling.remove(&peer_id);
+                            self.incoming_stats.remove(&peer_id);
+                            self.spawn_new_outgoing(peer_id.clone(), addresses, outgoing_result_for_parent.clone(), misbehaviour_for_parent.clone(), ttl);
+                            self.share_capabilities(&peer_id);
+                        } else if !self.manager.has_outgoing_worker(&peer_id) {
+                            let backoff = self.backoffs.entry(peer_id.clone()).or_insert_with(|| Backoff::new(self.reconnect_policy));
+                            let delay = backoff.failed();
+                            info!(target: "validator-network", "Will retry connecting to misbehaving peer {} after {}ms.", peer_id, delay.as_millis());
+                            self.spawn_new_outgoing(peer_id.clone(), addresses, outgoing_result_for_parent.clone(), misbehaviour_for_parent.clone(), delay);
+                            self.share_capabilities(&peer_id);
+                        }
+                    }
+                },
+                // replace the connections that outlived the maximum lifetime
+                _ = recycle_ticker.tick(), if max_lifetime.is_some() => {
+                    self.recycle_expired(&outgoing_result_for_parent, &misbehaviour_for_parent);
+                },
                 // periodically reporting what we are trying to do
                 _ = status_ticker.tick() => {
-                    info!(target: "validator-network", "Manager status report: {}.", self.manager.status_report())
+                    self.score_peers();
+                    self.blacklist.prune();
+                    info!(target: "validator-network", "Manager status report: {}.", self.manager.status_report());
+                    debug!(target: "validator-network", "Peer statuses: {:?}.", self.status());
                 }
                 // received exit signal, stop the network
                 // all workers will be killed automatically after the manager gets dropped
@@ -215,3 +856,589 @@ impl<D: Data, A: Data, ND: Dialer<A>, NL: Listener> Service<D, A, ND, NL> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use futures::channel::oneshot;
+    use sc_service::{SpawnTaskHandle, TaskManager};
+    use tokio::{
+        io::AsyncReadExt,
+        runtime::Handle,
+        time::{sleep, timeout, Duration},
+    };
+
+    use super::{ConnectionState, PeerStatus, Service, StatusHandle};
+    use crate::validator_network::{
+        blacklist::BlacklistConfig,
+        handshake::{Capabilities, HandshakeConfig},
+        heartbeat::HeartbeatConfig,
+        io::{Codec, ReceiveConfig},
+        mock::{keys, MockDialer, MockSplittable},
+        protocols::Protocol,
+        reconnect::ReconnectPolicy,
+        send_channel::SendChannelConfig,
+        Dialer, Network,
+    };
+
+    type Data = Vec<i32>;
+
+    async fn wait_for_status(
+        status_handle: &StatusHandle<Data, String>,
+        condition: impl Fn(&PeerStatus) -> bool,
+    ) -> PeerStatus {
+        timeout(Duration::from_secs(5), async {
+            loop {
+                let status = status_handle
+                    .status()
+                    .await
+                    .expect("service should be alive");
+                if let [peer_status] = &status[..] {
+                    if condition(peer_status) {
+                        return peer_status.clone();
+                    }
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("status should reach the expected state")
+    }
+
+    #[tokio::test]
+    async fn reports_connected_peer() {
+        let task_manager = TaskManager::new(Handle::current(), None).unwrap();
+        let dialer = MockDialer::new();
+        let (id_a, pen_a) = keys().await;
+        let (id_b, pen_b) = keys().await;
+        let mut exits = Vec::new();
+        let mut networks = Vec::new();
+        let mut status_handles = Vec::new();
+        for (pen, address) in [(pen_a, "a"), (pen_b, "b")] {
+            let (service, network) = Service::<Data, String, _, _>::new(
+                dialer.clone(),
+                dialer.listener(address),
+                pen,
+                task_manager.spawn_handle(),
+                HeartbeatConfig::default(),
+                HandshakeConfig::default(),
+                ReceiveConfig::default(),
+                SendChannelConfig::default(),
+                Codec::default(),
+                ReconnectPolicy::default(),
+                BlacklistConfig::default(),
+                None,
+            );
+            status_handles.push(service.status_handle());
+            let (exit_for_service, exit) = oneshot::channel();
+            tokio::spawn(service.run(exit));
+            exits.push(exit_for_service);
+            networks.push(network);
+        }
+        assert_eq!(status_handles[0].status().await, Some(Vec::new()));
+
+        networks[0].add_connection(id_b.clone(), vec![String::from("b")]);
+        networks[1].add_connection(id_a.clone(), vec![String::from("a")]);
+        let status = wait_for_status(&status_handles[0], |status| {
+            status.outgoing == ConnectionState::Connected && status.incoming
+        })
+        .await;
+        assert_eq!(status.peer_id, id_b);
+        assert_eq!(status.protocol, Some(Protocol::V3));
+        assert_eq!(status.capabilities, Some(Capabilities::supported()));
+        assert_eq!(
+            networks[0].capabilities(&id_b),
+            Some(Capabilities::supported())
+        );
+
+        networks[0].send(vec![43], id_b.clone());
+        assert_eq!(
+            networks[1].next().await,
+            Some((vec![43], id_a, Capabilities::supported()))
+        );
+        wait_for_status(&status_handles[0], |status| status.bytes_sent > 0).await;
+
+        networks[0].remove_connection(id_b);
+        assert_eq!(status_handles[0].status().await, Some(Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn waits_for_peer_to_connect() {
+        let task_manager = TaskManager::new(Handle::current(), None).unwrap();
+        let dialer = MockDialer::new();
+        let (id_a, pen_a) = keys().await;
+        let (id_b, pen_b) = keys().await;
+        let mut exits = Vec::new();
+        let mut networks = Vec::new();
+        let mut status_handles = Vec::new();
+        for (pen, address) in [(pen_a, "a"), (pen_b, "b")] {
+            let (service, network) = Service::<Data, String, _, _>::new(
+                dialer.clone(),
+                dialer.listener(address),
+                pen,
+                task_manager.spawn_handle(),
+                HeartbeatConfig::default(),
+                HandshakeConfig::default(),
+                ReceiveConfig::default(),
+                SendChannelConfig::default(),
+                Codec::default(),
+                ReconnectPolicy::default(),
+                BlacklistConfig::default(),
+                None,
+            );
+            status_handles.push(service.status_handle());
+            let (exit_for_service, exit) = oneshot::channel();
+            tokio::spawn(service.run(exit));
+            exits.push(exit_for_service);
+            networks.push(network);
+        }
+        assert!(timeout(
+            Duration::from_millis(100),
+            status_handles[0].wait_for_peer(id_b.clone())
+        )
+        .await
+        .is_err());
+
+        let status_handle = status_handles[0].clone();
+        let peer_id = id_b.clone();
+        let waiting = tokio::spawn(async move { status_handle.wait_for_peer(peer_id).await });
+        networks[0].add_connection(id_b.clone(), vec![String::from("b")]);
+        networks[1].add_connection(id_a, vec![String::from("a")]);
+        let result = timeout(Duration::from_secs(5), waiting)
+            .await
+            .expect("the peer should connect")
+            .unwrap();
+        assert_eq!(result, Some(()));
+
+        // already connected peers resolve right away
+        assert_eq!(
+            timeout(
+                Duration::from_millis(100),
+                status_handles[0].wait_for_peer(id_b)
+            )
+            .await,
+            Ok(Some(()))
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnects_once_blacklisting_expires() {
+        let task_manager = TaskManager::new(Handle::current(), None).unwrap();
+        let dialer = MockDialer::new();
+        let (id_a, pen_a) = keys().await;
+        let (id_b, pen_b) = keys().await;
+        // the first service gets a single oversized frame and bans the sender right away
+        let receive_configs = [
+            ReceiveConfig {
+                max_frame_size: 1000,
+                ..ReceiveConfig::default()
+            },
+            ReceiveConfig::default(),
+        ];
+        let blacklist_config = BlacklistConfig {
+            threshold: 1,
+            ttl: Duration::from_millis(500),
+        };
+        let mut exits = Vec::new();
+        let mut networks = Vec::new();
+        let mut status_handles = Vec::new();
+        for ((pen, address), receive_config) in [(pen_a, "a"), (pen_b, "b")]
+            .into_iter()
+            .zip(receive_configs)
+        {
+            let (service, network) = Service::<Data, String, _, _>::new(
+                dialer.clone(),
+                dialer.listener(address),
+                pen,
+                task_manager.spawn_handle(),
+                HeartbeatConfig::default(),
+                HandshakeConfig::default(),
+                receive_config,
+                SendChannelConfig::default(),
+                Codec::default(),
+                ReconnectPolicy::default(),
+                blacklist_config,
+                None,
+            );
+            status_handles.push(service.status_handle());
+            let (exit_for_service, exit) = oneshot::channel();
+            tokio::spawn(service.run(exit));
+            exits.push(exit_for_service);
+            networks.push(network);
+        }
+        networks[0].add_connection(id_b.clone(), vec![String::from("b")]);
+        networks[1].add_connection(id_a.clone(), vec![String::from("a")]);
+        wait_for_status(&status_handles[0], |status| {
+            status.outgoing == ConnectionState::Connected && status.incoming
+        })
+        .await;
+
+        networks[1].send(vec![0; 1000], id_a.clone());
+        wait_for_status(&status_handles[0], |status| {
+            status.outgoing == ConnectionState::Blacklisted
+        })
+        .await;
+        let status = wait_for_status(&status_handles[0], |status| {
+            status.outgoing == ConnectionState::Connected
+        })
+        .await;
+        assert_eq!(status.peer_id, id_b);
+
+        networks[0].send(vec![43], id_b);
+        assert_eq!(
+            networks[1].next().await,
+            Some((vec![43], id_a, Capabilities::supported()))
+        );
+    }
+
+    /// Counts the connection attempts, so that recycled connections can be noticed.
+    #[derive(Clone)]
+    struct CountingDialer {
+        dialer: MockDialer,
+        dials: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl Dialer<String> for CountingDialer {
+        type Connection = MockSplittable;
+        type Error = String;
+
+        async fn connect(
+            &mut self,
+            addresses: Vec<String>,
+        ) -> Result<Self::Connection, Self::Error> {
+            self.dials.fetch_add(1, Ordering::SeqCst);
+            self.dialer.connect(addresses).await
+        }
+    }
+
+    /// Takes a while to fail every dial, remembering how many dials were in progress at once.
+    #[derive(Clone, Default)]
+    struct SlowDialer {
+        dials: Arc<AtomicUsize>,
+        dialing: Arc<AtomicUsize>,
+        max_dialing: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl Dialer<String> for SlowDialer {
+        type Connection = MockSplittable;
+        type Error = String;
+
+        async fn connect(&mut self, _: Vec<String>) -> Result<Self::Connection, Self::Error> {
+            self.dials.fetch_add(1, Ordering::SeqCst);
+            let dialing = self.dialing.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_dialing.fetch_max(dialing, Ordering::SeqCst);
+            sleep(Duration::from_millis(50)).await;
+            self.dialing.fetch_sub(1, Ordering::SeqCst);
+            Err(String::from("nobody there"))
+        }
+    }
+
+    #[tokio::test]
+    async fn limits_concurrent_outgoing_dials() {
+        const PEERS: usize = 10;
+        const MAX_DIALS: usize = 3;
+        let task_manager = TaskManager::new(Handle::current(), None).unwrap();
+        let dialer = SlowDialer::default();
+        let mock_dialer = MockDialer::new();
+        let (_, pen) = keys().await;
+        let (service, mut network) = Service::<Data, String, _, _>::new(
+            dialer.clone(),
+            mock_dialer.listener("a"),
+            pen,
+            task_manager.spawn_handle(),
+            HeartbeatConfig::default(),
+            HandshakeConfig {
+                max_outgoing: Some(MAX_DIALS),
+                ..HandshakeConfig::default()
+            },
+            ReceiveConfig::default(),
+            SendChannelConfig::default(),
+            Codec::default(),
+            ReconnectPolicy::default(),
+            BlacklistConfig::default(),
+            None,
+        );
+        let (_exit_for_service, exit) = oneshot::channel();
+        tokio::spawn(service.run(exit));
+        for _ in 0..PEERS {
+            let (peer_id, _) = keys().await;
+            network.add_connection(peer_id, vec![String::from("nowhere")]);
+        }
+
+        // all the peers get dialed a few at a time, well before anyone is retried
+        sleep(Duration::from_millis(500)).await;
+        assert_eq!(dialer.dials.load(Ordering::SeqCst), PEERS);
+        assert_eq!(dialer.max_dialing.load(Ordering::SeqCst), MAX_DIALS);
+    }
+
+    #[tokio::test]
+    async fn derives_outgoing_dial_limit_from_peers() {
+        const PEERS: usize = 10;
+        let task_manager = TaskManager::new(Handle::current(), None).unwrap();
+        let dialer = SlowDialer::default();
+        let mock_dialer = MockDialer::new();
+        let (_, pen) = keys().await;
+        let (service, mut network) = Service::<Data, String, _, _>::new(
+            dialer.clone(),
+            mock_dialer.listener("a"),
+            pen,
+            task_manager.spawn_handle(),
+            HeartbeatConfig::default(),
+            HandshakeConfig::default(),
+            ReceiveConfig::default(),
+            SendChannelConfig::default(),
+            Codec::default(),
+            ReconnectPolicy::default(),
+            BlacklistConfig::default(),
+            None,
+        );
+        let (_exit_for_service, exit) = oneshot::channel();
+        tokio::spawn(service.run(exit));
+        for _ in 0..PEERS {
+            let (peer_id, _) = keys().await;
+            network.add_connection(peer_id, vec![String::from("nowhere")]);
+        }
+
+        // all the peers get dialed in a few rounds
+        sleep(Duration::from_millis(500)).await;
+        assert_eq!(dialer.dials.load(Ordering::SeqCst), PEERS);
+        assert_eq!(
+            dialer.max_dialing.load(Ordering::SeqCst),
+            HandshakeConfig::default().outgoing_dial_limit(PEERS)
+        );
+    }
+
+    #[tokio::test]
+    async fn recycles_old_connections_without_losing_data() {
+        let task_manager = TaskManager::new(Handle::current(), None).unwrap();
+        let dialer = MockDialer::new();
+        let dials = Arc::new(AtomicUsize::new(0));
+        let (id_a, pen_a) = keys().await;
+        let (id_b, pen_b) = keys().await;
+        let reconnect_policy = ReconnectPolicy {
+            max_lifetime: Some(Duration::from_millis(200)),
+            ..ReconnectPolicy::default()
+        };
+        let mut exits = Vec::new();
+        let mut networks = Vec::new();
+        let mut status_handles = Vec::new();
+        for (pen, address) in [(pen_a, "a"), (pen_b, "b")] {
+            let (service, network) = Service::<Data, String, _, _>::new(
+                CountingDialer {
+                    dialer: dialer.clone(),
+                    dials: dials.clone(),
+                },
+                dialer.listener(address),
+                pen,
+                task_manager.spawn_handle(),
+                HeartbeatConfig::default(),
+                HandshakeConfig::default(),
+                ReceiveConfig::default(),
+                SendChannelConfig::default(),
+                Codec::default(),
+                reconnect_policy,
+                BlacklistConfig::default(),
+                None,
+            );
+            status_handles.push(service.status_handle());
+            let (exit_for_service, exit) = oneshot::channel();
+            tokio::spawn(service.run(exit));
+            exits.push(exit_for_service);
+            networks.push(network);
+        }
+        networks[0].add_connection(id_b.clone(), vec![String::from("b")]);
+        networks[1].add_connection(id_a, vec![String::from("a")]);
+        status_handles[0]
+            .wait_for_peer(id_b.clone())
+            .await
+            .expect("service should be alive");
+        let initial_dials = dials.load(Ordering::SeqCst);
+
+        // every message goes through, even the ones sent while connections get replaced
+        for i in 0..100 {
+            networks[0].send(vec![i], id_b.clone());
+            let received = timeout(Duration::from_millis(500), networks[1].next())
+                .await
+                .expect("data should not get lost");
+            assert_eq!(received.map(|(data, _, _)| data), Some(vec![i]));
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert!(dials.load(Ordering::SeqCst) >= initial_dials + 4);
+    }
+
+    #[tokio::test]
+    async fn delivers_data_held_back_while_paused_in_order() {
+        let task_manager = TaskManager::new(Handle::current(), None).unwrap();
+        let dialer = MockDialer::new();
+        let dials = Arc::new(AtomicUsize::new(0));
+        let (id_a, pen_a) = keys().await;
+        let (id_b, pen_b) = keys().await;
+        let heartbeat_config = HeartbeatConfig {
+            interval: Duration::from_millis(50),
+            timeout: Duration::from_millis(200),
+            ..HeartbeatConfig::default()
+        };
+        let mut exits = Vec::new();
+        let mut networks = Vec::new();
+        let mut status_handles = Vec::new();
+        for (pen, address) in [(pen_a, "a"), (pen_b, "b")] {
+            let (service, network) = Service::<Data, String, _, _>::new(
+                CountingDialer {
+                    dialer: dialer.clone(),
+                    dials: dials.clone(),
+                },
+                dialer.listener(address),
+                pen,
+                task_manager.spawn_handle(),
+                heartbeat_config,
+                HandshakeConfig::default(),
+                ReceiveConfig::default(),
+                SendChannelConfig::default(),
+                Codec::default(),
+                ReconnectPolicy::default(),
+                BlacklistConfig::default(),
+                None,
+            );
+            status_handles.push(service.status_handle());
+            let (exit_for_service, exit) = oneshot::channel();
+            tokio::spawn(service.run(exit));
+            exits.push(exit_for_service);
+            networks.push(network);
+        }
+        networks[0].add_connection(id_b.clone(), vec![String::from("b")]);
+        networks[1].add_connection(id_a, vec![String::from("a")]);
+        status_handles[0]
+            .wait_for_peer(id_b.clone())
+            .await
+            .expect("service should be alive");
+        let initial_dials = dials.load(Ordering::SeqCst);
+
+        networks[0].pause(id_b.clone());
+        for i in 0..10 {
+            networks[0].send(vec![i], id_b.clone());
+        }
+        // nothing gets through for several heartbeat timeouts, yet the connection survives
+        assert!(timeout(Duration::from_millis(600), networks[1].next())
+            .await
+            .is_err());
+        assert_eq!(dials.load(Ordering::SeqCst), initial_dials);
+
+        networks[0].resume(id_b);
+        for i in 0..10 {
+            let received = timeout(Duration::from_secs(5), networks[1].next())
+                .await
+                .expect("the held back data should be delivered");
+            assert_eq!(received.map(|(data, _, _)| data), Some(vec![i]));
+        }
+        assert_eq!(dials.load(Ordering::SeqCst), initial_dials);
+    }
+
+    async fn listening_service(
+        dialer: &MockDialer,
+        spawn_handle: SpawnTaskHandle,
+        handshake_config: HandshakeConfig,
+    ) -> oneshot::Sender<()> {
+        let (_, pen) = keys().await;
+        let (service, _network) = Service::<Data, String, _, _>::new(
+            dialer.clone(),
+            dialer.listener("a"),
+            pen,
+            spawn_handle,
+            HeartbeatConfig::default(),
+            handshake_config,
+            ReceiveConfig::default(),
+            SendChannelConfig::default(),
+            Codec::default(),
+            ReconnectPolicy::default(),
+            BlacklistConfig::default(),
+            None,
+        );
+        let (exit_for_service, exit) = oneshot::channel();
+        tokio::spawn(service.run(exit));
+        exit_for_service
+    }
+
+    async fn connect(dialer: &MockDialer, count: usize) -> Vec<MockSplittable> {
+        let mut dialer = dialer.clone();
+        let mut streams = Vec::new();
+        for _ in 0..count {
+            streams.push(
+                dialer
+                    .connect(vec![String::from("a")])
+                    .await
+                    .expect("should connect"),
+            );
+        }
+        streams
+    }
+
+    /// Whether the service started handshaking over the stream, it always speaks first.
+    async fn handshake_started(stream: &mut MockSplittable) -> bool {
+        let mut buf = [0; 1];
+        matches!(
+            timeout(Duration::from_millis(100), stream.read(&mut buf)).await,
+            Ok(Ok(1))
+        )
+    }
+
+    #[tokio::test]
+    async fn limits_concurrent_incoming_handshakes() {
+        let task_manager = TaskManager::new(Handle::current(), None).unwrap();
+        let dialer = MockDialer::new();
+        let config = HandshakeConfig {
+            max_incoming: 3,
+            ..HandshakeConfig::default()
+        };
+        let _exit = listening_service(&dialer, task_manager.spawn_handle(), config).await;
+        let streams = connect(&dialer, 10).await;
+        sleep(Duration::from_millis(200)).await;
+
+        let mut started = Vec::new();
+        let mut waiting = Vec::new();
+        for mut stream in streams {
+            match handshake_started(&mut stream).await {
+                true => started.push(stream),
+                false => waiting.push(stream),
+            }
+        }
+        assert_eq!(started.len(), 3);
+
+        // giving up on the handshakes makes room for the waiting ones
+        drop(started);
+        sleep(Duration::from_millis(200)).await;
+        let mut started = 0;
+        for stream in waiting.iter_mut() {
+            if handshake_started(stream).await {
+                started += 1;
+            }
+        }
+        assert_eq!(started, 3);
+    }
+
+    #[tokio::test]
+    async fn drops_connections_waiting_too_long() {
+        let task_manager = TaskManager::new(Handle::current(), None).unwrap();
+        let dialer = MockDialer::new();
+        let config = HandshakeConfig {
+            max_incoming: 1,
+            incoming_queue_timeout: Duration::from_millis(100),
+            ..HandshakeConfig::default()
+        };
+        let _exit = listening_service(&dialer, task_manager.spawn_handle(), config).await;
+        let mut streams = connect(&dialer, 2).await;
+        sleep(Duration::from_millis(50)).await;
+        assert!(handshake_started(&mut streams[0]).await);
+
+        let mut buf = [0; 1];
+        let closed = timeout(Duration::from_secs(1), streams[1].read(&mut buf))
+            .await
+            .expect("the waiting connection should be dropped");
+        assert_eq!(closed.expect("should read the end of the stream"), 0);
+    }
+}